@@ -0,0 +1,4 @@
+//! C API library. The implementation lives in gbrust::ffi; this crate
+//! only builds it as a dynamic library that C frontends can link.
+
+pub use gbrust::ffi::*;
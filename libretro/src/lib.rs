@@ -0,0 +1,4 @@
+//! libretro core. The implementation lives in gbrust::libretro; this
+//! crate only builds it as a dynamic library loadable by frontends.
+
+pub use gbrust::libretro::*;
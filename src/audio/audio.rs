@@ -0,0 +1,170 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+
+/// Type of an audio sample (signed 16-bit PCM)
+pub type Sample = i16;
+
+/// Output sample rate (in Hz)
+pub const SAMPLE_RATE: usize = 44100;
+
+/// Size of an audio hash (see hash_audio())
+pub const AUDIO_HASH_SIZE: usize = 256 / 8;
+
+/// Hashes audio: SHA-256 of the samples as little endian i16, like
+/// hash_frame() does for frames.
+pub fn hash_audio(samples: &[Sample]) -> [u8; AUDIO_HASH_SIZE] {
+    let mut hasher = Sha256::new();
+    for s in samples {
+        hasher.update(s.to_le_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Base trait for an audio output.
+/// Samples are interleaved stereo (left, right).
+pub trait AudioSink {
+    /// Amount of samples that can be pushed without blocking
+    fn free_space(&self) -> usize;
+
+    /// Queues samples for playback, blocks until all samples fit in
+    /// the buffer.
+    fn push_samples(&mut self, samples: &[Sample]);
+}
+
+/// An audio sink that discards all samples and never blocks.
+pub struct NullAudioSink {}
+
+impl NullAudioSink {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for NullAudioSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSink for NullAudioSink {
+    fn free_space(&self) -> usize {
+        usize::MAX
+    }
+
+    fn push_samples(&mut self, _samples: &[Sample]) {}
+}
+
+/// An audio sink that consumes samples at the sample rate in real
+/// time, without playing them. Paces emulation like a real audio
+/// device would when none is available.
+pub struct RealtimeSink {
+    /// Buffer size (in samples)
+    capacity: usize,
+
+    /// Samples in the buffer at 'since'
+    queued: usize,
+    since: Instant,
+}
+
+impl RealtimeSink {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queued: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Samples currently in the buffer
+    fn level(&self) -> usize {
+        let consumed = self.since.elapsed().as_micros() * (SAMPLE_RATE as u128 * 2) / 1_000_000;
+        self.queued.saturating_sub(consumed as usize)
+    }
+}
+
+impl AudioSink for RealtimeSink {
+    fn free_space(&self) -> usize {
+        self.capacity.saturating_sub(self.level())
+    }
+
+    fn push_samples(&mut self, samples: &[Sample]) {
+        // Wait until the samples fit. Pushes larger than the buffer
+        // only wait for the buffer to drain.
+        let wanted = samples.len().min(self.capacity);
+        let overflow = (self.level() + wanted).saturating_sub(self.capacity);
+        if overflow > 0 {
+            sleep(Duration::from_micros(
+                (overflow as u64 * 1_000_000).div_ceil(SAMPLE_RATE as u64 * 2),
+            ));
+        }
+        self.queued = self.level() + samples.len();
+        self.since = Instant::now();
+    }
+}
+
+/// Converts emulated CPU cycles into output sample frames
+/// (one sample for each channel).
+pub struct SampleClock {
+    /// Remainder of the previous conversion (in cycles * sample rate)
+    frac: u64,
+}
+
+impl SampleClock {
+    pub fn new() -> Self {
+        Self { frac: 0 }
+    }
+
+    /// Advances the clock by the specified amount of CPU cycles.
+    /// Returns the amount of sample frames that elapsed.
+    pub fn advance(&mut self, cycles: usize, double_speed: bool) -> usize {
+        // In double speed mode, the CPU runs twice as many cycles in
+        // the same amount of time.
+        let cycles = if double_speed { cycles / 2 } else { cycles };
+        // A frame of cycles times the sample rate overflows 32 bits
+        self.frac += cycles as u64 * SAMPLE_RATE as u64;
+        let frames = self.frac / CPU_CLOCK_HZ as u64;
+        self.frac %= CPU_CLOCK_HZ as u64;
+        frames as usize
+    }
+}
+
+impl Default for SampleClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_clock() {
+        let mut c = SampleClock::new();
+        let mut frames = 0;
+        for _ in 0..CPU_CLOCK_HZ / 4 {
+            frames += c.advance(4, false);
+        }
+        assert_eq!(frames, SAMPLE_RATE);
+
+        let mut c = SampleClock::new();
+        assert_eq!(c.advance(CPU_CLOCK_HZ * 2, true), SAMPLE_RATE);
+    }
+
+    #[test]
+    fn realtime_sink() {
+        // 10ms buffer
+        let mut s = RealtimeSink::new(SAMPLE_RATE / 100 * 2);
+        assert_eq!(s.free_space(), SAMPLE_RATE / 100 * 2);
+
+        let start = Instant::now();
+        s.push_samples(&[0; SAMPLE_RATE / 100 * 2]);
+        // Has to wait for the first push to be consumed
+        s.push_samples(&[0; SAMPLE_RATE / 100 * 2]);
+        assert!(start.elapsed() >= Duration::from_millis(8));
+    }
+}
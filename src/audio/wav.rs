@@ -0,0 +1,149 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::audio::audio::{AudioSink, Sample, SAMPLE_RATE};
+
+/// Output channels (interleaved stereo)
+const CHANNELS: u16 = 2;
+
+/// Bits per sample
+const BITS: u16 = 16;
+
+/// Size of the RIFF/WAVE header
+pub const WAV_HEADER_SIZE: usize = 44;
+
+/// An audio sink that keeps all samples, to be written to a WAV file.
+/// Never blocks, so emulation runs as fast as possible.
+pub struct WavSink {
+    samples: Vec<Sample>,
+}
+
+impl WavSink {
+    pub fn new() -> Self {
+        Self { samples: vec![] }
+    }
+
+    /// Samples pushed so far (interleaved stereo)
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Encodes the samples as a 16-bit stereo WAV file
+    pub fn to_wav(&self) -> Vec<u8> {
+        encode_wav(&self.samples)
+    }
+
+    /// Writes the samples to a WAV file
+    pub fn save(&self, filename: impl AsRef<Path>) -> Result<()> {
+        let filename = filename.as_ref();
+        fs::write(filename, self.to_wav())
+            .with_context(|| format!("Cannot write {}", filename.display()))
+    }
+}
+
+impl Default for WavSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn free_space(&self) -> usize {
+        usize::MAX
+    }
+
+    fn push_samples(&mut self, samples: &[Sample]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+/// Encodes interleaved stereo samples as a 16-bit PCM WAV file at
+/// SAMPLE_RATE. A trailing incomplete frame is dropped.
+pub fn encode_wav(samples: &[Sample]) -> Vec<u8> {
+    let samples = &samples[..samples.len() - samples.len() % CHANNELS as usize];
+    let block_align = CHANNELS * BITS / 8;
+    let data_size = (samples.len() * BITS as usize / 8) as u32;
+
+    let mut out = Vec::with_capacity(WAV_HEADER_SIZE + data_size as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(WAV_HEADER_SIZE as u32 - 8 + data_size).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&CHANNELS.to_le_bytes());
+    out.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+    out.extend_from_slice(&(SAMPLE_RATE as u32 * u32::from(block_align)).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&BITS.to_le_bytes());
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_size.to_le_bytes());
+    for s in samples {
+        out.extend_from_slice(&s.to_le_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(wav: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(wav[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(wav: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header() {
+        let wav = encode_wav(&[0; 200]);
+        assert_eq!(wav.len(), WAV_HEADER_SIZE + 400);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4), 36 + 400);
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(&wav[12..16], b"fmt ");
+        assert_eq!(u32_at(&wav, 16), 16);
+        // PCM, stereo
+        assert_eq!(u16_at(&wav, 20), 1);
+        assert_eq!(u16_at(&wav, 22), 2);
+        assert_eq!(u32_at(&wav, 24), 44100);
+        // Byte rate and block align
+        assert_eq!(u32_at(&wav, 28), 44100 * 4);
+        assert_eq!(u16_at(&wav, 32), 4);
+        assert_eq!(u16_at(&wav, 34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 400);
+
+        let empty = encode_wav(&[]);
+        assert_eq!(empty.len(), WAV_HEADER_SIZE);
+        assert_eq!(u32_at(&empty, 4), 36);
+        assert_eq!(u32_at(&empty, 40), 0);
+    }
+
+    #[test]
+    fn interleaving() {
+        let mut sink = WavSink::new();
+        // Left, right
+        sink.push_samples(&[1, -1, 0x1234]);
+        sink.push_samples(&[-0x1234, Sample::MAX, Sample::MIN]);
+        assert_eq!(sink.samples().len(), 6);
+
+        let wav = sink.to_wav();
+        assert_eq!(u32_at(&wav, 40), 12);
+        assert_eq!(
+            wav[WAV_HEADER_SIZE..],
+            [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xCC, 0xED, 0xFF, 0x7F, 0x00, 0x80]
+        );
+
+        // An incomplete frame is dropped
+        sink.push_samples(&[5]);
+        assert_eq!(sink.to_wav(), wav);
+    }
+}
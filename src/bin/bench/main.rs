@@ -0,0 +1,154 @@
+use std::hint::black_box;
+use std::time::Instant;
+
+use anyhow::Result;
+use clap::Parser;
+
+use gbrust::display::display::NullDisplay;
+use gbrust::gameboy::bus::bus::BusMember;
+use gbrust::gameboy::bus::gbbus::Gameboybus;
+use gbrust::gameboy::cartridge::cartridge;
+use gbrust::gameboy::cpu::cpu::CPU;
+use gbrust::gameboy::lcd::{LCDController, PpuMode};
+use gbrust::gameboy::model::Model;
+use gbrust::input::input::NullInput;
+
+#[derive(Parser)]
+#[command(about = "Micro-benchmarks for the Gameboy address bus and CPU")]
+struct Args {
+    /// Amount of accesses per benchmark
+    #[arg(short, long, default_value_t = 50_000_000)]
+    count: usize,
+
+    /// Amount of CPU steps (instructions)
+    #[arg(short, long, default_value_t = 10_000_000)]
+    steps: usize,
+
+    /// Emulate a CGB
+    #[arg(long)]
+    cgb: bool,
+}
+
+/// Simple xorshift PRNG, so runs are repeatable
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u16 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as u16
+    }
+}
+
+fn bus(cgb: bool) -> Gameboybus {
+    bus_with_rom(cgb, vec![0; 32 * 1024])
+}
+
+fn bus_with_rom(cgb: bool, mut rom: Vec<u8>) -> Gameboybus {
+    // MBC1 with RAM, so reads from external RAM hit the cartridge
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let model = Model::from_cgb(cgb);
+    let lcd = LCDController::new(Box::new(NullDisplay::new()), model);
+    let mut bus = Gameboybus::new(
+        cartridge::load(&rom).unwrap(),
+        None,
+        lcd,
+        Box::new(NullInput::new()),
+        model,
+    );
+    // Enable external RAM
+    bus.write(0x0000, 0x0A);
+    bus
+}
+
+/// KEY1 is handled by the CPU, the bus does not expect accesses to it
+fn readable(addr: u16) -> u16 {
+    if addr == 0xFF4D {
+        0xFF4C
+    } else {
+        addr
+    }
+}
+
+/// A loop of common instructions of various lengths, starting at 0x0100
+const CPU_LOOP: &[u8] = &[
+    0x21, 0x00, 0xC0, // LD HL,C000h
+    0x2A, // loop: LD A,(HL+)
+    0xC6, 0x12, // ADD A,12h
+    0x47, // LD B,A
+    0x0C, // INC C
+    0xCB, 0x30, // SWAP B
+    0xEA, 0x00, 0xC1, // LD (C100h),A
+    0xCD, 0x20, 0x01, // CALL sub
+    0x18, 0xF1, // JR loop
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xC9, // sub: RET
+];
+
+/// Runs a benchmark and prints the amount of operations per second
+fn run(name: &str, unit: &str, count: usize, mut f: impl FnMut(usize)) {
+    let start = Instant::now();
+    for i in 0..count {
+        f(i);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{:<20} {:>8.1} M {}/s ({:.3}s)",
+        name,
+        count as f64 / elapsed / 1_000_000.0,
+        unit,
+        elapsed
+    );
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut b = bus(args.cgb);
+    let count = args.count;
+
+    run("read sequential", "accesses", count, |i| {
+        black_box(b.read(readable(black_box(i as u16))));
+    });
+
+    let mut rng = XorShift(0x12345678);
+    let addrs: Vec<u16> = (0..0x10000).map(|_| readable(rng.next())).collect();
+    run("read random", "accesses", count, |i| {
+        black_box(b.read(addrs[i & 0xFFFF]));
+    });
+
+    // Only write to RAM, writes to I/O registers have side effects
+    // that would influence the results.
+    run("write sequential", "accesses", count, |i| {
+        b.write(0xC000 | (i as u16 & 0x1FFF), black_box(i as u8));
+    });
+
+    for (name, base, size) in [
+        ("write random VRAM", 0x8000, 0x2000),
+        ("write random ERAM", 0xA000, 0x2000),
+        ("write random WRAM", 0xC000, 0x2000),
+        ("write random mixed", 0x8000, 0x6000),
+    ] {
+        let ram_addrs: Vec<u16> = addrs.iter().map(|a| base + a % size).collect();
+        run(name, "accesses", count, |i| {
+            b.write(ram_addrs[i & 0xFFFF], black_box(i as u8));
+        });
+    }
+
+    let mut rom = vec![0; 32 * 1024];
+    rom[0x100..(0x100 + CPU_LOOP.len())].copy_from_slice(CPU_LOOP);
+    for (name, mode) in [
+        ("cpu step", PpuMode::Accurate),
+        ("cpu step (instant)", PpuMode::Instant),
+    ] {
+        let mut bus = bus_with_rom(args.cgb, rom.clone());
+        bus.get_lcd_mut().set_ppu_mode(mode);
+        let mut cpu = CPU::new(Box::new(bus), Model::from_cgb(args.cgb));
+        run(name, "steps", args.steps, |_| {
+            black_box(cpu.step().unwrap());
+        });
+    }
+
+    Ok(())
+}
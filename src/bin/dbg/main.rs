@@ -0,0 +1,462 @@
+use std::fs;
+use std::io::{stdin, stdout, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use terminal::Action;
+
+use gbrust::display::display::{Color, Display, FrameBuffer, NullDisplay};
+use gbrust::display::terminal::TerminalDisplay;
+use gbrust::gameboy::apu::APU_CHANNELS;
+use gbrust::gameboy::bootrom;
+use gbrust::gameboy::bus::bus::BusMember;
+use gbrust::gameboy::bus::coverage::CoverageBus;
+use gbrust::gameboy::cartridge::cartridge;
+use gbrust::gameboy::cheatfinder::Filter;
+use gbrust::gameboy::cpu::cpu::CPUError;
+use gbrust::gameboy::debugger::Debugger;
+use gbrust::gameboy::emulator::Emulator;
+use gbrust::gameboy::gdbstub::gdbstub::GdbStub;
+use gbrust::gameboy::lcd::{LCD_H, LCD_W};
+use gbrust::gameboy::lcd_debug::{self, Image};
+use gbrust::gameboy::model::Model;
+use gbrust::gameboy::raminit::RamInit;
+use gbrust::gameboy::serial::Serial;
+use gbrust::gameboy::symbols::SymbolTable;
+use gbrust::input::input::NullInput;
+use gbrust::misc::panic_message;
+
+#[derive(Parser)]
+#[command(about = "Interactive Gameboy debugger")]
+struct Args {
+    /// ROM filename to load.
+    filename: String,
+
+    /// ROM to load from a zip file containing several ROMs
+    #[arg(long, value_name = "NAME")]
+    rom_entry: Option<String>,
+
+    /// Boot ROM to optionally load ('builtin' for the built-in DMG boot
+    /// ROM)
+    #[arg(short, long)]
+    bootrom: Option<String>,
+
+    /// Force DMG mode for CGB cartridges
+    #[arg(long)]
+    dmg: bool,
+
+    /// Stop when the LCD is disabled outside VBlank, rather than only
+    /// warning about it
+    #[arg(long)]
+    strict_ppu: bool,
+
+    /// Contents of WRAM, VRAM and HRAM at power on: zero, pattern or
+    /// random[:seed]
+    #[arg(long, value_name = "INIT", default_value = "zero", value_parser = RamInit::parse)]
+    ram_init: RamInit,
+
+    /// Wait for a GDB connection on this port before starting
+    #[arg(long)]
+    gdb: Option<u16>,
+}
+
+const HELP: &str = "\
+Commands:
+  s [n]       step n instructions (default 1)
+  f [n]       run n frames (default 1)
+  c           continue until a breakpoint is hit
+  r           print CPU state
+  b [[bank:]addr]
+              set a breakpoint (only in a ROM bank if given) or list
+              breakpoints
+  bd <[bank:]addr>
+              delete a breakpoint
+  lbreak [line]
+              break when the PPU draws a scanline (no line: clear)
+  dis [addr] [n]
+              disassemble n instructions (default: 10 from PC)
+  m <addr> [len]
+              dump memory (annotates the cartridge header, OAM and IO
+              registers)
+  set <addr> <byte...>
+              write bytes to memory
+  patch <addr> <instruction>
+              assemble an instruction into ROM (e.g. 'patch 0150 ld a,$12')
+  patchfile <file>
+              patch ROM with a listing ('<addr>: <instruction>' or
+              <instruction> following the previous one, per line)
+  unpatch     remove all ROM patches
+  sym <file>  load an RGBDS symbol file
+  px <x> <y>  print debug information of a pixel in the last frame
+  tiles       show all tiles in VRAM
+  map         show the BG tile map and viewport
+  oam         list all objects in OAM
+  sound       print the state of the sound channels
+  mute [ch]   mute or unmute channel 1-4 in the audio output (no channel:
+              unmute all)
+  solo <ch>   mute all channels but one, or unmute all if it is soloed
+  cartinfo    print the cartridge, its bank state and out-of-range bank
+              selects
+  coverage <file>
+              write the execution coverage so far (code/data log for .cdl
+              files, executed addresses otherwise)
+  find start  start a search for an unknown value (cartridge RAM and WRAM)
+  find eq <n> keep addresses equal to n (starts a search if none is running)
+  find inc|dec|ch|unch
+              keep addresses that increased, decreased, changed or did not
+              change since the last search
+  find list   list the remaining addresses
+  find reset  stop searching
+  h           print this help
+  q           quit";
+
+/// Maximum amount of frames to run before giving up on a breakpoint
+const CONTINUE_MAX_FRAMES: u64 = 60 * 60;
+
+/// Parses an optional numeric argument
+fn parse_arg(arg: Option<&str>, default: usize) -> Result<usize> {
+    match arg {
+        Some(s) => Ok(s
+            .parse()
+            .with_context(|| format!("Invalid number: {}", s))?),
+        None => Ok(default),
+    }
+}
+
+/// Color of the viewport outline in the tile map view
+const VIEWPORT_COLOR: Color = 0x001F;
+
+/// Reads a line from stdin, returns None at end of input
+fn read_line() -> Result<Option<String>> {
+    let mut line = String::new();
+    if stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+/// Shows an image on the terminal until enter is pressed
+fn show_image(img: &Image) -> Result<()> {
+    let mut disp = TerminalDisplay::new(img.width, img.height, 60);
+    disp.blit_frame(&FrameBuffer::from_pixels(
+        img.width,
+        img.height,
+        img.pixels.clone(),
+    ));
+    disp.render();
+
+    let term = terminal::stdout();
+    term.act(Action::ResetColor)?;
+    term.act(Action::MoveCursorTo(0, disp.status_row()))?;
+    term.act(Action::ShowCursor)?;
+    print!("Press enter to continue");
+    stdout().flush()?;
+    read_line()?;
+    Ok(())
+}
+
+fn cmd_pixel(emu: &Emulator, x: usize, y: usize) -> Result<()> {
+    if x >= LCD_W || y >= LCD_H {
+        bail!("Pixel out of range ({}x{})", LCD_W, LCD_H);
+    }
+    let p = emu.get_lcd().get_debug_scanline(y)[x];
+    println!(
+        "({}, {}): {:?} palette {} index {} color {:04X} priority {:?}",
+        x, y, p.source, p.palette, p.idx, p.color, p.priority
+    );
+    Ok(())
+}
+
+/// Maximum amount of candidates listed by 'find list'
+const FIND_LIST_MAX: usize = 50;
+
+fn cmd_find(emu: &Emulator, dbg: &mut Debugger, args: &[&str]) -> Result<()> {
+    match args {
+        ["start"] => println!("{} candidates", dbg.find_start(emu)),
+        ["reset"] => dbg.cheat_finder_mut().reset(),
+        ["list"] => {
+            let candidates = dbg.cheat_finder().candidates();
+            for c in candidates.iter().take(FIND_LIST_MAX) {
+                println!("{}", c);
+            }
+            if candidates.len() > FIND_LIST_MAX {
+                println!("({} more)", candidates.len() - FIND_LIST_MAX);
+            }
+        }
+        [] => bail!("Syntax: find start|eq <n>|inc|dec|ch|unch|list|reset"),
+        _ => {
+            let filter = Filter::parse(args)?;
+            println!("{} candidates", dbg.find(emu, filter)?);
+        }
+    }
+    Ok(())
+}
+
+/// Parses a hexadecimal byte
+/// Parses a sound channel number (1-4) to its index
+fn parse_channel(s: &str) -> Result<usize> {
+    match s.parse::<usize>() {
+        Ok(ch @ 1..=APU_CHANNELS) => Ok(ch - 1),
+        _ => bail!("Invalid channel: {} (1-{})", s, APU_CHANNELS),
+    }
+}
+
+fn print_muted(mask: u8) {
+    let muted: Vec<String> = (0..APU_CHANNELS)
+        .filter(|ch| mask & (1 << ch) != 0)
+        .map(|ch| (ch + 1).to_string())
+        .collect();
+    if muted.is_empty() {
+        println!("No channels muted");
+    } else {
+        println!("Muted channels: {}", muted.join(" "));
+    }
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    u8::from_str_radix(s.trim_start_matches('$'), 16)
+        .with_context(|| format!("Invalid byte: {}", s))
+}
+
+/// Executes a single command, returns false to quit
+fn command(emu: &mut Emulator, dbg: &mut Debugger, line: &str) -> Result<bool> {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        None => (),
+        Some("s") => {
+            for _ in 0..parse_arg(args.next(), 1)? {
+                emu.step()?;
+            }
+            println!("{}", emu.cpu().dump_state());
+        }
+        Some("f") => {
+            for _ in 0..parse_arg(args.next(), 1)? {
+                if let Some(runaway) = emu.run_frame()? {
+                    println!("{}", runaway);
+                    break;
+                }
+            }
+            println!("{}", emu.cpu().dump_state());
+        }
+        Some("c") => {
+            if dbg.run(emu, CONTINUE_MAX_FRAMES * Emulator::FRAME_CYCLES)? {
+                if dbg.at_breakpoint(emu) {
+                    let pc = emu.cpu().regs.pc;
+                    println!("Breakpoint at {}", dbg.format_addr(emu, pc));
+                } else {
+                    println!("Line {} reached", emu.get_lcd().read(0xFF44));
+                }
+            } else {
+                println!("No breakpoint hit in {} frames", CONTINUE_MAX_FRAMES);
+            }
+            println!("{}", emu.cpu().dump_state());
+        }
+        Some("r") => println!("{}", emu.cpu().dump_state_verbose()),
+        Some("b") => match args.next() {
+            Some(spec) => {
+                let bp = dbg.add_breakpoint(spec)?;
+                println!("Breakpoint set at {}", dbg.format_breakpoint(emu, bp));
+            }
+            None => {
+                for &bp in dbg.breakpoints() {
+                    println!("{}", dbg.format_breakpoint(emu, bp));
+                }
+            }
+        },
+        Some("bd") => {
+            let bp = dbg.remove_breakpoint(args.next().context("Syntax: bd <addr>")?)?;
+            println!("Breakpoint at {} removed", bp);
+        }
+        Some("lbreak") => {
+            let line = match args.next() {
+                Some(l) => Some(l.parse().with_context(|| format!("Invalid line: {}", l))?),
+                None => None,
+            };
+            dbg.set_line_breakpoint(emu, line)?;
+            match line {
+                Some(l) => println!("Breaking on line {}", l),
+                None => println!("Line breakpoint cleared"),
+            }
+        }
+        Some("dis") => {
+            let addr = match args.next() {
+                Some(a) => dbg.parse_addr(a)?,
+                None => emu.cpu().regs.pc,
+            };
+            print!(
+                "{}",
+                dbg.disassemble(emu, addr, parse_arg(args.next(), 10)?)
+            );
+        }
+        Some("m") => {
+            let addr = dbg.parse_addr(args.next().context("Syntax: m <addr> [len]")?)?;
+            print!(
+                "{}",
+                dbg.dump_memory(emu, addr, parse_arg(args.next(), 64)?)
+            );
+        }
+        Some("set") => {
+            let addr = dbg.parse_addr(args.next().context("Syntax: set <addr> <byte...>")?)?;
+            let data = args.map(parse_byte).collect::<Result<Vec<u8>>>()?;
+            dbg.write_memory(emu, addr, &data);
+            print!("{}", dbg.dump_memory(emu, addr, data.len()));
+        }
+        Some("patch") => {
+            let addr =
+                dbg.parse_addr(args.next().context("Syntax: patch <addr> <instruction>")?)?;
+            let asm = args.collect::<Vec<_>>().join(" ");
+            dbg.patch(emu, addr, &asm)?;
+            print!("{}", dbg.disassemble(emu, addr, 1));
+        }
+        Some("patchfile") => {
+            let filename = args.next().context("Syntax: patchfile <file>")?;
+            let text = fs::read_to_string(filename)
+                .with_context(|| format!("Cannot read {}", filename))?;
+            println!("Patched {} instructions", dbg.patch_lines(emu, &text)?);
+        }
+        Some("unpatch") => {
+            emu.bus_mut().clear_rom_patches();
+            println!("ROM patches removed");
+        }
+        Some("sym") => {
+            let symbols = SymbolTable::load(Path::new(args.next().context("Syntax: sym <file>")?))?;
+            println!("Loaded {} symbols", symbols.len());
+            dbg.set_symbols(symbols);
+        }
+        Some("px") => {
+            let (Some(x), Some(y)) = (args.next(), args.next()) else {
+                bail!("Syntax: px <x> <y>");
+            };
+            cmd_pixel(emu, parse_arg(Some(x), 0)?, parse_arg(Some(y), 0)?)?;
+        }
+        Some("tiles") => show_image(&lcd_debug::render_tiles(emu.get_lcd()))?,
+        Some("map") => {
+            let lcd = emu.get_lcd();
+            let mut img = lcd_debug::render_bg_map(lcd);
+            lcd_debug::draw_viewport(&mut img, lcd.read(0xFF43), lcd.read(0xFF42), VIEWPORT_COLOR);
+            show_image(&img)?;
+        }
+        Some("oam") => print!("{}", lcd_debug::dump_oam(emu.get_lcd())),
+        Some("sound") => print!("{}", emu.get_apu().apu_state()),
+        Some("mute") => {
+            let apu = emu.get_apu_mut();
+            let mask = match args.next() {
+                Some(ch) => apu.get_channel_mask() ^ (1 << parse_channel(ch)?),
+                None => 0,
+            };
+            apu.set_channel_mask(mask);
+            print_muted(mask);
+        }
+        Some("solo") => {
+            let ch = parse_channel(args.next().context("Syntax: solo <ch>")?)?;
+            let apu = emu.get_apu_mut();
+            let others = ((1 << APU_CHANNELS) - 1) & !(1 << ch);
+            let mask = if apu.get_channel_mask() == others {
+                0
+            } else {
+                others
+            };
+            apu.set_channel_mask(mask);
+            print_muted(mask);
+        }
+        Some("cartinfo") => {
+            let cart = emu.bus().cartridge();
+            println!("Cartridge: {}", cart);
+            println!("{}", cart.dump_state());
+            println!("{}", cart.diagnostics());
+        }
+        Some("coverage") => {
+            let filename = args.next().context("Syntax: coverage <file>")?;
+            let coverage = emu
+                .cpu()
+                .bus
+                .find::<CoverageBus>()
+                .context("Coverage is not collected")?
+                .handle();
+            let coverage = coverage.lock().unwrap();
+            fs::write(filename, coverage.export(filename))?;
+            println!("{} bytes of code executed", coverage.code_bytes());
+        }
+        Some("find") => cmd_find(emu, dbg, &args.collect::<Vec<_>>())?,
+        Some("h") => println!("{}", HELP),
+        Some("q") => return Ok(false),
+        Some(c) => bail!("Unknown command '{}', 'h' for help", c),
+    }
+    Ok(true)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let rom = cartridge::read_rom(&args.filename, args.rom_entry.as_deref())?;
+    let cart = cartridge::load(&rom)?;
+    println!("Cartridge: {}", cart);
+    if let Some(warning) = cartridge::check_rom_size(&rom) {
+        println!("Warning: {}", warning);
+    }
+    let cgb = cart.is_cgb() && !args.dmg;
+    let bootrom = match args.bootrom {
+        Some(ref brfile) => Some(bootrom::load(brfile, Model::from_cgb(cgb))?),
+        None => None,
+    };
+
+    let mut emu = Emulator::new(
+        cart,
+        bootrom.as_deref(),
+        Box::new(NullDisplay::new()),
+        Box::new(NullInput::new()),
+        Model::from_cgb(cgb),
+        Serial::new_null(),
+    );
+    emu.get_lcd_mut().set_pixel_debug(true);
+    emu.wrap_bus(|bus| Box::new(CoverageBus::new(bus, rom.len())));
+    emu.get_lcd_mut().set_strict_disable(args.strict_ppu);
+    emu.init_ram(args.ram_init);
+    if args.ram_init != RamInit::Zeros {
+        println!("RAM initialization: {}", args.ram_init);
+    }
+    println!("{}", emu.cpu().dump_state());
+
+    let mut dbg = Debugger::new();
+    if let Some(port) = args.gdb {
+        println!("Waiting for GDB on port {}..", port);
+        GdbStub::listen(port)?.serve(&mut emu, &mut dbg)?;
+        println!("GDB detached");
+        println!("{}", emu.cpu().dump_state());
+    }
+    loop {
+        print!("> ");
+        stdout().flush()?;
+        let Some(line) = read_line()? else {
+            break;
+        };
+        let disables = emu.get_lcd().get_unsafe_disables();
+        // A panic in the emulator returns to the prompt, to allow
+        // inspecting the state it left the system in.
+        match panic::catch_unwind(AssertUnwindSafe(|| command(&mut emu, &mut dbg, &line))) {
+            Ok(Ok(true)) => (),
+            Ok(Ok(false)) => break,
+            // The CPU stops at the faulting instruction, so it can be
+            // inspected from the prompt.
+            Ok(Err(e)) if e.is::<CPUError>() => {
+                println!("Error: {:#}\n{}", e, emu.cpu().dump_state_verbose())
+            }
+            Ok(Err(e)) => println!("Error: {:#}", e),
+            Err(p) => print!(
+                "Emulator panicked: {}\n{}",
+                panic_message(&*p),
+                emu.cpu().dump_state_verbose()
+            ),
+        }
+        let disables = emu.get_lcd().get_unsafe_disables() - disables;
+        if disables > 0 {
+            println!(
+                "WARNING: LCD disabled outside VBlank {} time(s), use --strict-ppu to stop there",
+                disables
+            );
+        }
+    }
+    Ok(())
+}
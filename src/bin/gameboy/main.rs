@@ -1,41 +1,135 @@
 use std::fs;
 use std::fs::File;
-use std::io::{stdin, Read, Write};
+use std::io::{stdin, BufWriter, Read, Stdout, Write};
 use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
-use std::rc::Rc;
-use std::sync::mpsc;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::{Parser, ValueEnum};
-use terminal::{stdout, Action, Clear, Event, KeyCode, Retrieved, Value};
+use itertools::Itertools;
+use terminal::{stdout, Action, Clear, Event, KeyCode, KeyEvent, Retrieved, Terminal, Value};
 
 const DISPLAY_W: usize = 160;
 const DISPLAY_H: usize = 144;
 
+/// Audio buffer size in audio sync mode (50ms)
+const AUDIO_BUFFER: usize = SAMPLE_RATE / 20 * 2;
+
 #[cfg(not(feature = "sixel"))]
 use gbrust::display::terminal::TerminalDisplay;
 
 #[cfg(feature = "sixel")]
 use gbrust::display::sixel::SixelDisplay;
 
-use gbrust::display::display::{Display, NullDisplay};
+use gbrust::audio::audio::{RealtimeSink, SAMPLE_RATE};
+use gbrust::audio::wav::WavSink;
+use gbrust::display::bmp;
+use gbrust::display::display::{hash_frame, Display, FrameBuffer, NullDisplay};
+use gbrust::display::palette::{self, DmgPalette};
+use gbrust::display::terminal::RawModeGuard;
+use gbrust::gameboy::bootrom;
 use gbrust::gameboy::bus::bus::Bus;
+use gbrust::gameboy::bus::coverage::{Coverage, CoverageBus, CoverageHandle};
 use gbrust::gameboy::bus::gbbus::Gameboybus;
+use gbrust::gameboy::bus::profiler::{Profile, ProfileHandle, ProfilerBus, DEFAULT_BUCKET_SIZE};
 use gbrust::gameboy::bus::testbus::Testbus;
 use gbrust::gameboy::cartridge::cartridge;
+use gbrust::gameboy::cartridge::header::CartridgeHeader;
+use gbrust::gameboy::clock::EmuClock;
 use gbrust::gameboy::cpu::cpu::CPU;
+use gbrust::gameboy::emulator::{self, Emulator, SyncStrategy};
+use gbrust::gameboy::emuthread::{
+    Control, EmuThread, EmulationStopped, Frame, SystemBuilder, Warning,
+};
+use gbrust::gameboy::frameskip::{FrameSkip, FrameSkipMode};
 use gbrust::gameboy::lcd::LCDController;
-use gbrust::gameboy::serial::Serial;
-use gbrust::input::input::{Input, NullInput};
+use gbrust::gameboy::link::{LinkRole, LockstepLink};
+use gbrust::gameboy::model::Model;
+use gbrust::gameboy::movie::{Movie, MovieHandle, MoviePlayer, MovieRecorder};
+use gbrust::gameboy::raminit::RamInit;
+use gbrust::gameboy::serial::{self, LinkChannels, Serial};
+use gbrust::gameboy::stats::{Stats, StatsCollector};
+use gbrust::gameboy::testing;
+use gbrust::input::input::{Button, Input, NullInput};
+use gbrust::input::keymap::KeyMap;
+use gbrust::input::multiplexer::InputMultiplexer;
+use gbrust::input::turbo::{TurboButtons, TurboInput, DEFAULT_TURBO_RATE};
+
+#[cfg(not(feature = "sixel"))]
+use gbrust::input::terminal::{HoldTimeout, TerminalInput};
 
 /// Emulation mode/Gameboy model to emulate
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 enum EmulationMode {
     Auto,
     DMG,
+    /// Gameboy Pocket/Light
+    Mgb,
+    /// Super Gameboy (palettes and multiplayer only)
+    Sgb,
+    #[value(alias = "cgb")]
     Color,
+    /// Gameboy Advance (in Gameboy Color mode)
+    Agb,
+}
+
+/// Colors for DMG games
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum PaletteName {
+    /// Greyscale
+    Grey,
+    /// Original Gameboy pea-green
+    Green,
+    /// Gameboy Pocket
+    Pocket,
+    /// BGB emulator
+    Bgb,
+    /// Sepia tones
+    Sepia,
+}
+
+impl PaletteName {
+    fn colors(self) -> DmgPalette {
+        match self {
+            PaletteName::Grey => palette::DMG_GREY,
+            PaletteName::Green => palette::DMG_GREEN,
+            PaletteName::Pocket => palette::DMG_POCKET,
+            PaletteName::Bgb => palette::DMG_BGB,
+            PaletteName::Sepia => palette::DMG_SEPIA,
+        }
+    }
+}
+
+/// What to pace emulation on
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum SyncMode {
+    /// Limit to the framerate set with --fps
+    Video,
+    /// Consumption of the audio output
+    Audio,
+    /// Run as fast as possible
+    Off,
+}
+
+/// Time source of the cartridge's real time clock
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum RtcMode {
+    /// Follow emulated time (deterministic)
+    Emulated,
+    /// Follow the time of the host, also while the emulator is not running
+    Host,
+}
+
+impl From<SyncMode> for SyncStrategy {
+    fn from(mode: SyncMode) -> Self {
+        match mode {
+            SyncMode::Video => SyncStrategy::Video,
+            SyncMode::Audio => SyncStrategy::Audio,
+            SyncMode::Off => SyncStrategy::Off,
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -47,15 +141,48 @@ struct Args {
     /// ROM filename to load.
     filename: String,
 
+    /// ROM to load from a zip file containing several ROMs
+    #[arg(long, value_name = "NAME")]
+    rom_entry: Option<String>,
+
     /// Override the filename of the save.
     /// By default, this is the ROM filename with the .sav extension.
     #[arg(long)]
     save_filename: Option<String>,
 
-    /// Boot ROM to optionally load
+    /// Boot ROM to optionally load ('builtin' for the built-in DMG boot
+    /// ROM)
     #[arg(short, long)]
     bootrom: Option<String>,
 
+    /// Print the cartridge header, emulation mode and save file, then exit
+    #[arg(long)]
+    info: bool,
+
+    /// Like --info, as JSON
+    #[arg(long, conflicts_with = "info")]
+    info_json: bool,
+
+    /// Run N frames without display, input and pacing, print the
+    /// number and hash (SHA-256 of the RGB555 pixels, little endian,
+    /// row-major) of each frame, then exit. Useful with git bisect.
+    #[arg(long, value_name = "N", conflicts_with_all = ["info", "info_json", "testbus"])]
+    hash_frames: Option<u64>,
+
+    /// Run without display, input and pacing, write the audio output
+    /// of the first --dump-audio-seconds to a WAV file, then exit
+    #[arg(long, value_name = "OUT.WAV", conflicts_with_all = ["info", "info_json", "testbus", "hash_frames"])]
+    dump_audio: Option<PathBuf>,
+
+    /// Seconds of emulated time written by --dump-audio
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "dump_audio")]
+    dump_audio_seconds: u64,
+
+    /// Run the boot ROM as fast as possible before starting, so the
+    /// cartridge starts immediately in the state the boot ROM leaves.
+    #[arg(long, requires = "bootrom")]
+    skip_bootrom: bool,
+
     /// Wait for keystroke after each CPU step.
     #[arg(short, long)]
     pause: bool,
@@ -68,6 +195,32 @@ struct Args {
     #[arg(short, long)]
     verbose: bool,
 
+    /// Write a Gameboy Doctor compatible log to a file.
+    /// This starts in post-boot state and pins LY to 0x90.
+    #[arg(long, conflicts_with_all = ["bootrom", "testbus"])]
+    doctor: Option<String>,
+
+    /// Run without display, input and pacing, comparing each
+    /// instruction to a log written by --doctor (e.g. by another
+    /// build), then exit. Reports the first line that differs.
+    #[arg(long, value_name = "LOG", conflicts_with_all = ["bootrom", "testbus", "doctor", "hash_frames", "dump_audio"])]
+    compare_with: Option<String>,
+
+    /// Count memory accesses and write a report (CSV, hottest
+    /// addresses first) to a file on exit.
+    #[arg(long, value_name = "OUT.CSV", conflicts_with = "testbus")]
+    profile: Option<String>,
+
+    /// Size of the address buckets in the profile (power of two)
+    #[arg(long, default_value_t = DEFAULT_BUCKET_SIZE, value_parser = parse_bucket_size)]
+    profile_bucket: u16,
+
+    /// Collect the execution coverage of the ROM and write it to a file
+    /// on exit: a code/data log (flags per ROM byte) for .cdl files, a
+    /// list of executed addresses otherwise.
+    #[arg(long, value_name = "OUT.CDL", conflicts_with = "testbus")]
+    coverage: Option<String>,
+
     /// Disable display
     #[arg(long)]
     no_display: bool,
@@ -76,6 +229,43 @@ struct Args {
     #[arg(long, default_value = "80")]
     fps: u64,
 
+    /// Frames to skip presenting after each displayed frame (0-9), or
+    /// auto to skip as needed to keep up the --frameskip-speed. Skipped
+    /// frames are still emulated.
+    #[arg(long, value_name = "auto|0-9", default_value_t = FrameSkipMode::default(), value_parser = FrameSkipMode::parse)]
+    frameskip: FrameSkipMode,
+
+    /// Minimum emulation speed (in percent) kept up by --frameskip auto
+    #[arg(long, value_name = "PERCENT", default_value_t = 100.0, value_parser = parse_turbo_rate)]
+    frameskip_speed: f64,
+
+    /// Colors used for DMG games
+    #[arg(long, value_enum, default_value_t = PaletteName::Grey)]
+    palette: PaletteName,
+
+    /// Correct CGB colors to look like the real screen
+    #[arg(long)]
+    color_correction: bool,
+
+    /// Stop emulation when the LCD is disabled outside VBlank, which can
+    /// damage a real DMG's screen, rather than only warning about it
+    #[arg(long)]
+    strict_ppu: bool,
+
+    /// Contents of WRAM, VRAM and HRAM at power on: zero, pattern or
+    /// random[:seed]
+    #[arg(long, value_name = "INIT", default_value = "zero", value_parser = RamInit::parse)]
+    ram_init: RamInit,
+
+    /// Pace emulation on video, audio or nothing
+    #[arg(long, value_enum, default_value_t = SyncMode::Video)]
+    sync: SyncMode,
+
+    /// Mute an audio channel (1-4), can be repeated. Only the audio
+    /// output is affected, not the emulated sound registers.
+    #[arg(long, value_name = "1-4", value_parser = clap::value_parser!(u8).range(1..=4))]
+    mute_ch: Vec<u8>,
+
     /// Output serial output to terminal
     #[arg(short, long)]
     serial_out: bool,
@@ -99,160 +289,855 @@ struct Args {
     /// Enable link cable slave (TCP client)
     #[arg(short('l'))]
     link_slave: bool,
+
+    /// Pass link cable bytes on as they arrive, instead of keeping both
+    /// sides in lockstep (compatible with older versions)
+    #[arg(long)]
+    link_raw: bool,
+
+    /// Record input to a movie file
+    #[arg(long, conflicts_with = "playback")]
+    record: Option<String>,
+
+    /// Play back input from a movie file (ignores live input)
+    #[arg(long)]
+    playback: Option<String>,
+
+    /// Write frames as BMP images to the specified directory
+    #[arg(long)]
+    dump_frames: Option<PathBuf>,
+
+    /// Only write every Nth frame when dumping frames
+    #[arg(long, default_value = "1")]
+    dump_frames_interval: u64,
+
+    /// Key bindings file.
+    /// By default, ~/.config/gbrust/keymap is used if it exists.
+    #[arg(long)]
+    keymap: Option<PathBuf>,
+
+    /// Bind a key to a button (e.g. --bind a=z), overrides the keymap.
+    /// Buttons: up, down, left, right, a, b, start, select.
+    #[arg(long, value_name = "BUTTON=KEY")]
+    bind: Vec<String>,
+
+    /// Frames a button stays pressed after a key press, and after each
+    /// autorepeat of the key. Terminals do not report key releases.
+    #[arg(long, value_name = "FRAMES[,REPEAT]", default_value_t = HoldTimeout::default(), value_parser = HoldTimeout::parse)]
+    key_hold: HoldTimeout,
+
+    /// Start with autofire enabled for A (toggle with F5)
+    #[arg(long)]
+    turbo_a: bool,
+
+    /// Start with autofire enabled for B (toggle with F6)
+    #[arg(long)]
+    turbo_b: bool,
+
+    /// Autofire presses per second
+    #[arg(long, value_name = "HZ", default_value_t = DEFAULT_TURBO_RATE, value_parser = parse_turbo_rate)]
+    turbo_rate: f64,
+
+    /// Time source for cartridges with a real time clock. Recordings
+    /// only play back identically with the emulated clock.
+    #[arg(long, value_enum, default_value_t = RtcMode::Emulated)]
+    rtc: RtcMode,
+
+    /// Run a second instance of the ROM, connected to the first
+    /// through the link cable. The second player uses WASD, G (A),
+    /// F (B), R (start) and T (select).
+    #[arg(
+        long,
+        conflicts_with_all = ["link_master", "link_slave", "serial_out", "testbus", "record", "playback"]
+    )]
+    second_instance: bool,
 }
 
-fn main() -> Result<()> {
-    let mut args = Args::parse();
+/// Where the serial port of an emulated system is connected to
+enum SerialPort {
+    None,
+    Stdout,
+    Tcp(TcpStream, LinkRole),
+    /// In-memory link cable to another instance
+    Crossed(LinkChannels, LinkRole),
+}
 
-    let savefn = args.save_filename.unwrap_or_else(|| {
-        let mut p = PathBuf::from(&args.filename);
-        p.set_extension("sav");
-        p.into_os_string().into_string().unwrap()
+/// Configuration of an emulated system, which is constructed on its
+/// emulation thread.
+struct System {
+    rom: Vec<u8>,
+    sav: Vec<u8>,
+    bootrom: Option<Vec<u8>>,
+    /// Fast-forward through the boot ROM before handing over the system
+    skip_bootrom: bool,
+    model: Model,
+    testbus: bool,
+    serial: SerialPort,
+    /// Raw link cable instead of lockstep
+    link_raw: bool,
+    palette: DmgPalette,
+    color_correction: bool,
+    strict_ppu: bool,
+    ram_init: RamInit,
+    /// Audio channels muted in the output (see APU::set_channel_mask())
+    channel_mask: u8,
+    /// Gameboy Doctor log file
+    doctor: Option<PathBuf>,
+    /// Memory access profile to collect into
+    profile: Option<ProfileHandle>,
+    /// Execution coverage to collect into
+    coverage: Option<CoverageHandle>,
+    /// Key events for a terminal input, None disables input
+    keys: Option<(mpsc::Receiver<KeyEvent>, KeyMap)>,
+    key_hold: HoldTimeout,
+    /// Buttons in autofire mode
+    turbo: TurboButtons,
+    turbo_rate: f64,
+    rtc: RtcMode,
+    playback: Option<Movie>,
+    recording: Option<MovieHandle>,
+}
+
+impl System {
+    fn into_builder(self) -> SystemBuilder {
+        Box::new(move || {
+            let mut input: Box<dyn Input> = match self.keys {
+                None => Box::new(NullInput::new()),
+                #[cfg(not(feature = "sixel"))]
+                Some((key_rx, keymap)) => {
+                    Box::new(TerminalInput::with_timeout(key_rx, keymap, self.key_hold))
+                }
+                #[cfg(feature = "sixel")]
+                Some(_) => Box::new(NullInput::new()),
+            };
+            input = Box::new(TurboInput::new(input, self.turbo, self.turbo_rate));
+            if let Some(m) = self.playback {
+                input = Box::new(MoviePlayer::new(m));
+            } else if let Some(m) = self.recording {
+                input = MovieRecorder::with_handle(input, m);
+            }
+
+            let serial = match self.serial {
+                SerialPort::None => Serial::new_null(),
+                SerialPort::Stdout => Serial::on_byte(Box::new(|b| {
+                    let mut out = std::io::stdout();
+                    let _ = out.write_all(&[b]);
+                    let _ = out.flush();
+                })),
+                SerialPort::Tcp(stream, _) if self.link_raw => {
+                    Serial::new(Box::new(stream.try_clone()?), Box::new(stream))
+                }
+                SerialPort::Tcp(stream, role) => Serial::new_lockstep(LockstepLink::new(
+                    Box::new(stream.try_clone()?),
+                    Box::new(stream),
+                    role,
+                )),
+                SerialPort::Crossed((tx, rx), _) if self.link_raw => Serial::new_crossed(tx, rx),
+                SerialPort::Crossed((tx, rx), role) => {
+                    Serial::new_lockstep(LockstepLink::new_crossed(tx, rx, role))
+                }
+            };
+
+            // Frames are sent to the UI thread, which renders them.
+            let mut lcd = LCDController::new(Box::new(NullDisplay::new()), self.model);
+            lcd.set_dmg_palette(self.palette);
+            lcd.set_color_correction(self.color_correction);
+            lcd.set_strict_disable(self.strict_ppu);
+            let mut bus: Box<dyn Bus> = if self.testbus {
+                Box::new(Testbus::new())
+            } else {
+                let mut gbbus = Gameboybus::new_with_serial(
+                    cartridge::load_with_save(&self.rom, &self.sav)?,
+                    self.bootrom.as_deref(),
+                    lcd,
+                    input,
+                    self.model,
+                    serial,
+                );
+                if self.rtc == RtcMode::Host {
+                    gbbus.set_clock(EmuClock::host());
+                }
+                gbbus.init_ram(self.ram_init);
+                gbbus.get_apu_mut().set_channel_mask(self.channel_mask);
+                Box::new(gbbus)
+            };
+
+            if self.testbus {
+                bus.write_slice(&self.rom, 0);
+
+                // Indicate start of VBlank for testing purposes
+                bus.write(0xFF44, 0x90);
+            }
+
+            if let Some(profile) = self.profile {
+                bus = Box::new(ProfilerBus::with_handle(bus, profile));
+            }
+            if let Some(coverage) = self.coverage {
+                bus = Box::new(CoverageBus::with_handle(bus, coverage));
+            }
+
+            let mut cpu = CPU::new(bus, self.model);
+            if self.skip_bootrom {
+                emulator::skip_bootrom(&mut cpu)?;
+            }
+            if let Some(filename) = self.doctor {
+                let f = File::create(&filename)
+                    .with_context(|| format!("Cannot create {}", filename.display()))?;
+                cpu.set_doctor_log(Some(Box::new(BufWriter::new(f))));
+                if let Some(b) = Gameboybus::from_bus_mut(&mut *cpu.bus) {
+                    b.set_ly_override(Some(0x90));
+                }
+            }
+
+            Ok(cpu)
+        })
+    }
+}
+
+/// A running emulator instance
+struct Instance {
+    emu: EmuThread,
+    /// Filename suffix for files belonging to this instance
+    suffix: &'static str,
+    savefn: PathBuf,
+    profile: Option<ProfileHandle>,
+    coverage: Option<CoverageHandle>,
+    last_frame: Option<Frame>,
+    /// Warnings received while running, reported at exit
+    warnings: Vec<Warning>,
+}
+
+/// Loads the keymap from the keymap file and command line
+fn load_keymap(args: &Args) -> Result<KeyMap> {
+    let mut keymap = KeyMap::default();
+    if let Some(ref path) = args.keymap {
+        keymap.apply(&KeyMap::load(path)?);
+    } else if let Some(path) = KeyMap::default_path().filter(|p| p.exists()) {
+        keymap.apply(&KeyMap::load(path)?);
+    }
+
+    let mut bindings = KeyMap::empty();
+    for b in &args.bind {
+        let (button, key) = KeyMap::parse_binding(b)?;
+        bindings.add(button, key);
+    }
+    keymap.apply(&bindings);
+
+    Ok(keymap)
+}
+
+/// Parses a profile bucket size, which has to be a power of two
+fn parse_bucket_size(s: &str) -> Result<u16, String> {
+    match s.parse::<u16>() {
+        Ok(n) if n.is_power_of_two() => Ok(n),
+        _ => Err(format!("{} is not a power of two", s)),
+    }
+}
+
+/// Parses an autofire rate, which has to be positive
+fn parse_turbo_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n),
+        _ => Err(format!("{} is not a valid rate", s)),
+    }
+}
+
+/// Selects the model to emulate for a cartridge
+fn select_model(mode: EmulationMode, cgb: bool, sgb: bool) -> Model {
+    match mode {
+        EmulationMode::Auto if cgb => Model::Cgb,
+        EmulationMode::Auto if sgb => Model::Sgb,
+        EmulationMode::Auto | EmulationMode::DMG => Model::Dmg,
+        EmulationMode::Mgb => Model::Mgb,
+        EmulationMode::Sgb => Model::Sgb,
+        EmulationMode::Color => Model::Cgb,
+        EmulationMode::Agb => Model::Agb,
+    }
+}
+
+fn model_name(model: Model) -> &'static str {
+    match model {
+        Model::Dmg => "Gameboy (DMG)",
+        Model::Mgb => "Gameboy Pocket (MGB)",
+        Model::Sgb => "Super Gameboy (SGB)",
+        Model::Cgb => "Gameboy Color (CGB)",
+        Model::Agb => "Gameboy Advance (AGB)",
+    }
+}
+
+/// Prints the cartridge information for --info and --info-json
+fn print_info(args: &Args, rom: &[u8], savefn: &str) -> Result<()> {
+    let header = CartridgeHeader::parse(rom)?;
+    let model = select_model(args.mode, header.cgb, header.sgb);
+    if args.info_json {
+        let mut info = header.to_json();
+        info["mode"] = format!("{:?}", model).to_uppercase().into();
+        info["save_file"] = savefn.into();
+        println!("{}", info);
+    } else {
+        println!("{}", header);
+        println!("Mode: {}", model_name(model));
+        println!("Save file: {}", savefn);
+    }
+    Ok(())
+}
+
+/// Runs headless for --hash-frames, printing the hash of every frame
+fn print_frame_hashes(args: &Args, rom: &[u8], frames: u64) -> Result<()> {
+    let cart = cartridge::load(rom)?;
+    let model = select_model(args.mode, cart.is_cgb(), cart.is_sgb());
+    let bootrom = match args.bootrom {
+        Some(ref brfile) => Some(bootrom::load(brfile, model)?),
+        None => None,
+    };
+    let mut emu = Emulator::new(
+        cart,
+        bootrom.as_deref(),
+        Box::new(NullDisplay::new()),
+        Box::new(NullInput::new()),
+        model,
+        Serial::new_null(),
+    );
+    let lcd = emu.get_lcd_mut();
+    lcd.set_dmg_palette(args.palette.colors());
+    lcd.set_color_correction(args.color_correction);
+    emu.init_ram(args.ram_init);
+    if args.skip_bootrom {
+        emu.skip_bootrom()?;
+    }
+
+    // Frames of emulated time, the LCD may be disabled
+    for frame in 1..=frames {
+        emu.run_frame()?;
+        println!(
+            "{} {:02x}",
+            frame,
+            hash_frame(emu.get_framebuffer()).iter().format("")
+        );
+    }
+    Ok(())
+}
+
+/// Runs headless for --dump-audio, writing the audio output to a WAV file
+fn dump_audio(args: &Args, rom: &[u8], filename: &Path) -> Result<()> {
+    let cart = cartridge::load(rom)?;
+    let model = select_model(args.mode, cart.is_cgb(), cart.is_sgb());
+    let bootrom = match args.bootrom {
+        Some(ref brfile) => Some(bootrom::load(brfile, model)?),
+        None => None,
+    };
+    let mut emu = Emulator::new(
+        cart,
+        bootrom.as_deref(),
+        Box::new(NullDisplay::new()),
+        Box::new(NullInput::new()),
+        model,
+        Serial::new_null(),
+    );
+    emu.init_ram(args.ram_init);
+    emu.get_apu_mut()
+        .set_channel_mask(channel_mask(&args.mute_ch));
+    if args.skip_bootrom {
+        emu.skip_bootrom()?;
+    }
+
+    let mut sink = WavSink::new();
+    emu.render_audio(testing::secs(args.dump_audio_seconds), &mut sink)?;
+    sink.save(filename)?;
+    println!(
+        "Wrote {} seconds of audio to {}",
+        args.dump_audio_seconds,
+        filename.display()
+    );
+    Ok(())
+}
+
+/// Runs headless for --compare-with, in the same state as --doctor
+fn compare_with_trace(args: &Args, rom: &[u8], filename: &str) -> Result<()> {
+    let trace =
+        fs::read_to_string(filename).with_context(|| format!("Cannot read {}", filename))?;
+    let cart = cartridge::load(rom)?;
+    let model = select_model(args.mode, cart.is_cgb(), cart.is_sgb());
+    let mut emu = Emulator::new_headless(cart, model);
+    emu.init_ram(args.ram_init);
+    emu.bus_mut().set_ly_override(Some(0x90));
+
+    match testing::compare_trace(&mut emu, &trace)? {
+        None => {
+            println!("{} lines match", trace.lines().count());
+            Ok(())
+        }
+        Some(d) => {
+            println!("{}", d);
+            bail!("Trace differs from {}", filename);
+        }
+    }
+}
+
+/// Converts --mute-ch channels to an APU channel mask
+fn channel_mask(channels: &[u8]) -> u8 {
+    channels.iter().fold(0, |m, ch| m | (1 << (ch - 1)))
+}
+
+/// Builds the line shown below the screen
+fn status_line(paused: bool, stats: Option<(Stats, &FrameSkip)>) -> Option<String> {
+    let stats = stats.map(|(s, fs)| match fs.mode() {
+        FrameSkipMode::Fixed(0) => s.to_string(),
+        _ => format!("{} | Skip: {} ({})", s, fs.current(), fs.skipped()),
     });
+    match (paused, stats) {
+        (false, None) => None,
+        (true, None) => Some("Paused".to_string()),
+        (false, Some(s)) => Some(s),
+        (true, Some(s)) => Some(format!("Paused | {}", s)),
+    }
+}
 
-    let rom = fs::read(&args.filename)?;
-    let sav = fs::read(&savefn).unwrap_or(vec![]);
+/// Inserts a suffix before the extension of a filename
+/// (e.g. rom.sav -> rom-2.sav)
+fn with_suffix(filename: &str, suffix: &str) -> PathBuf {
+    let mut p = PathBuf::from(filename);
+    let stem = p
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = match p.extension() {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}{}", stem, suffix),
+    };
+    p.set_file_name(name);
+    p
+}
 
-    let display: Box<dyn Display>;
-    let input: Box<dyn Input>;
+/// Generates a screenshot filename (<rom>-<timestamp>.bmp)
+fn screenshot_filename(romfn: &str) -> PathBuf {
+    let mut p = PathBuf::from(romfn);
+    let stem = p
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    p.set_file_name(format!("{}-{}.bmp", stem, ts));
+    p
+}
 
-    let cartridge = cartridge::load_with_save(&rom, &sav);
-    println!("Cartridge: {}", cartridge.borrow());
+/// Generates the savestate filename (<rom><suffix>.state)
+fn savestate_filename(romfn: &str, suffix: &str) -> PathBuf {
+    let mut p = with_suffix(romfn, suffix);
+    p.set_extension("state");
+    p
+}
 
-    let cgb = match args.mode {
-        EmulationMode::Auto => cartridge.borrow().is_cgb(),
-        EmulationMode::DMG => false,
-        EmulationMode::Color => true,
+/// Restores the terminal from raw mode, if it is in raw mode
+fn restore_terminal(
+    raw_mode: &mut Option<RawModeGuard<Terminal<Stdout>>>,
+    clear: bool,
+) -> Result<()> {
+    let Some(guard) = raw_mode else {
+        return Ok(());
     };
+    guard.restore()?;
+    if clear {
+        guard.terminal().act(Action::MoveCursorTo(0, 0))?;
+        guard.terminal().act(Action::ClearTerminal(Clear::All))?;
+    }
+    Ok(())
+}
 
-    if cgb {
-        println!("Mode: Gameboy Color (CGB)");
-    } else {
-        println!("Mode: Gameboy (DMG)");
+/// Sends a control message to all instances
+fn broadcast(emus: &[Instance], msg: impl Fn() -> Control) -> Result<()> {
+    for i in emus {
+        i.emu.send(msg())?;
     }
+    Ok(())
+}
 
-    let mut serial = if args.serial_out {
-        Serial::new_out(Box::new(stdout()))
-    } else {
-        Serial::new_null()
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let savefn = args.save_filename.clone().unwrap_or_else(|| {
+        let mut p = PathBuf::from(&args.filename);
+        p.set_extension("sav");
+        p.into_os_string().into_string().unwrap()
+    });
+
+    let rom = cartridge::read_rom(&args.filename, args.rom_entry.as_deref())?;
+    if args.info || args.info_json {
+        return print_info(&args, &rom, &savefn);
+    }
+    if let Some(frames) = args.hash_frames {
+        return print_frame_hashes(&args, &rom, frames);
+    }
+    if let Some(ref filename) = args.compare_with {
+        return compare_with_trace(&args, &rom, filename);
+    }
+    if let Some(ref filename) = args.dump_audio {
+        return dump_audio(&args, &rom, filename);
+    }
+    let keymap = load_keymap(&args)?;
+    // Only used for the information below, the emulation thread loads
+    // its own instance.
+    let cartridge = cartridge::load(&rom)?;
+    println!("Cartridge: {}", cartridge);
+    if args.ram_init != RamInit::Zeros {
+        println!("RAM initialization: {}", args.ram_init);
+    }
+    if let Some(warning) = cartridge::check_rom_size(&rom) {
+        println!("Warning: {}", warning);
+    }
+    // A save that cannot be used is left alone, it is only written at
+    // exit if the cartridge RAM was written.
+    if let Ok(save) = fs::read(&savefn) {
+        let check = cartridge::check_save_size(cartridge.as_ref(), &save)
+            .with_context(|| format!("Cannot use save {}", savefn))?;
+        if let Some(warning) = check {
+            println!("Warning: {}", warning);
+        }
+    }
+
+    let model = select_model(args.mode, cartridge.is_cgb(), cartridge.is_sgb());
+    drop(cartridge);
+
+    println!("Mode: {}", model_name(model));
+
+    let bootrom = match args.bootrom {
+        Some(ref brfile) => Some(bootrom::load(brfile, model)?),
+        None => None,
     };
 
+    let mut serial_ports = vec![];
     if args.link_master {
         let listener = TcpListener::bind("127.0.0.1:4567").unwrap();
         println!("Link cable in master (server) mode");
         println!("Waiting for connection...");
         let stream = listener.incoming().next().unwrap()?;
         stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
         println!("Connection established!");
-        serial = Serial::new(Box::new(stream.try_clone()?), Box::new(stream));
+        serial_ports.push(SerialPort::Tcp(stream, LinkRole::Leader));
     } else if args.link_slave {
         println!("Link cable in slave (client) mode");
         println!("Connecting...");
         let stream = TcpStream::connect("127.0.0.1:4567")?;
         stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
         println!("Connection established!");
-        serial = Serial::new(Box::new(stream.try_clone()?), Box::new(stream));
+        serial_ports.push(SerialPort::Tcp(stream, LinkRole::Follower));
+    } else if args.second_instance {
+        println!("Running two instances connected by link cable");
+        let (a, b) = serial::crossed_channels();
+        serial_ports.push(SerialPort::Crossed(a, LinkRole::Leader));
+        serial_ports.push(SerialPort::Crossed(b, LinkRole::Follower));
+    } else if args.serial_out {
+        serial_ports.push(SerialPort::Stdout);
+    } else {
+        serial_ports.push(SerialPort::None);
     }
+    let instances = serial_ports.len();
+    let canvas_w = DISPLAY_W * instances;
 
-    let (key_tx, key_rx) = mpsc::channel();
     let terminal = stdout();
 
-    if !args.no_display {
-        terminal.act(Action::EnableRawMode).unwrap();
+    // Restores the terminal when dropped, also on errors and panics
+    let mut raw_mode = if args.no_display {
+        None
+    } else {
+        Some(RawModeGuard::new(stdout())?)
+    };
 
+    let mut display: Box<dyn Display> = if !args.no_display {
         #[cfg(not(feature = "sixel"))]
         {
-            let cdisplay = Box::new(TerminalDisplay::new(DISPLAY_W, DISPLAY_H, args.fps));
-            input = Box::new(cdisplay.create_input(key_rx));
-            display = cdisplay as Box<dyn Display>;
+            Box::new(TerminalDisplay::new(canvas_w, DISPLAY_H, args.fps))
         }
 
         #[cfg(feature = "sixel")]
         {
-            let sdisplay = Box::new(SixelDisplay::new(DISPLAY_W, DISPLAY_H, args.fps));
-            input = Box::new(NullInput::new());
-            display = sdisplay as Box<dyn Display>;
+            Box::new(SixelDisplay::new(canvas_w, DISPLAY_H, args.fps))
         }
     } else {
-        display = Box::new(NullDisplay::new());
-        input = Box::new(NullInput::new());
+        Box::new(NullDisplay::new())
+    };
+
+    let mut playback = None;
+    if let Some(ref moviefn) = args.playback {
+        let m = Movie::deserialize(&fs::read_to_string(moviefn)?)?;
+        m.verify(&rom)?;
+        println!("Playing back movie: {}", moviefn);
+        playback = Some(m);
     }
+    let movie = args
+        .record
+        .as_ref()
+        .map(|_| Arc::new(Mutex::new(Movie::new(&rom))));
 
-    let lcd = LCDController::new(display, cgb);
-    let mut bus: Box<dyn Bus> = if args.testbus {
-        Box::new(Testbus::new())
-    } else {
-        let b = if let Some(ref brfile) = args.bootrom {
-            let bootrom = fs::read(brfile)?;
-            Box::new(Gameboybus::new_with_serial(
-                Rc::clone(&cartridge),
-                Some(bootrom.as_slice()),
-                lcd,
-                input,
-                cgb,
-                serial,
-            ))
+    let turbo = TurboButtons::new();
+    turbo.set(Button::A, args.turbo_a);
+    turbo.set(Button::B, args.turbo_b);
+
+    let mut mux = InputMultiplexer::new();
+    let mut emus = vec![];
+    for (i, serial) in serial_ports.into_iter().enumerate() {
+        let (suffix, keymap) = if i == 0 {
+            ("", keymap.clone())
         } else {
-            Box::new(Gameboybus::new_with_serial(
-                Rc::clone(&cartridge),
-                None,
-                lcd,
-                input,
-                cgb,
-                serial,
-            ))
+            ("-2", KeyMap::player2())
         };
-        b
-    };
-
-    if args.testbus {
-        bus.write_slice(&rom, 0);
+        let key_rx = mux.add_player(keymap.clone());
+        let savefn = with_suffix(&savefn, suffix);
+        let profile = args
+            .profile
+            .as_ref()
+            .map(|_| Arc::new(Mutex::new(Profile::new(args.profile_bucket))));
+        let coverage = args
+            .coverage
+            .as_ref()
+            .map(|_| Arc::new(Mutex::new(Coverage::new(rom.len()))));
 
-        // Indicate start of VBlank for testing purposes
-        bus.write(0xFF44, 0x90);
+        let system = System {
+            rom: rom.clone(),
+            sav: fs::read(&savefn).unwrap_or(vec![]),
+            bootrom: bootrom.clone(),
+            skip_bootrom: args.skip_bootrom,
+            model,
+            testbus: args.testbus,
+            serial,
+            link_raw: args.link_raw,
+            palette: args.palette.colors(),
+            color_correction: args.color_correction,
+            strict_ppu: args.strict_ppu,
+            ram_init: args.ram_init,
+            channel_mask: channel_mask(&args.mute_ch),
+            doctor: args.doctor.as_ref().map(|f| with_suffix(f, suffix)),
+            profile: profile.clone(),
+            coverage: coverage.clone(),
+            keys: (!args.no_display).then_some((key_rx, keymap)),
+            key_hold: args.key_hold,
+            turbo: turbo.clone(),
+            turbo_rate: args.turbo_rate,
+            rtc: args.rtc,
+            playback: playback.take(),
+            recording: movie.clone(),
+        };
+        // There is no audio output device yet, the sink only consumes
+        // samples in real time.
+        let emu = EmuThread::spawn_synced(
+            system.into_builder(),
+            args.sync.into(),
+            args.fps,
+            Box::new(RealtimeSink::new(AUDIO_BUFFER)),
+        );
+        emu.send(Control::Verbose(args.verbose))?;
+        emu.send(Control::SingleStep(args.pause))?;
+        emus.push(Instance {
+            emu,
+            suffix,
+            savefn,
+            profile,
+            coverage,
+            last_frame: None,
+            warnings: vec![],
+        });
     }
+    let mut single_step = args.pause;
+    let mut paused = false;
+    let mut stats = StatsCollector::new();
+    let mut show_stats = false;
+    let mut frameskip = FrameSkip::with_min_speed(args.frameskip, args.frameskip_speed / 100.0);
 
-    let mut cpu = CPU::new(bus, cgb);
+    if let Some(ref dir) = args.dump_frames {
+        fs::create_dir_all(dir)?;
+    }
+    let mut canvas = FrameBuffer::new(canvas_w, DISPLAY_H);
 
     'mainloop: loop {
-        if let Retrieved::Event(Some(Event::Key(keyevent))) = terminal
-            .get(Value::Event(Some(Duration::from_millis(0))))
+        if emus.iter().any(|i| i.emu.is_finished()) {
+            // Emulation error, reported below
+            restore_terminal(&mut raw_mode, false)?;
+            break 'mainloop;
+        }
+
+        if single_step {
+            // Wait for keystroke after each CPU step
+            let _ = stdin().read(&mut [0u8]).unwrap();
+            broadcast(&emus, || Control::Step)?;
+        } else if let Retrieved::Event(Some(Event::Key(keyevent))) = terminal
+            .get(Value::Event(Some(Duration::from_millis(1))))
             .unwrap()
         {
             match keyevent.code {
                 KeyCode::Esc => {
-                    terminal.act(Action::DisableRawMode).unwrap();
-                    terminal.act(Action::ShowCursor).unwrap();
-                    terminal.act(Action::ResetColor).unwrap();
-                    if !args.verbose {
-                        terminal.act(Action::MoveCursorTo(0, 0)).unwrap();
-                        terminal.act(Action::ClearTerminal(Clear::All)).unwrap();
-                    }
-
+                    restore_terminal(&mut raw_mode, !args.verbose)?;
                     break 'mainloop;
                 }
+                // Bound keys take precedence over hotkeys
+                _ if mux.route(keyevent) => (),
+                KeyCode::F(12) => {
+                    bmp::save(
+                        screenshot_filename(&args.filename),
+                        canvas_w,
+                        DISPLAY_H,
+                        canvas.pixels(),
+                    )?;
+                }
+                KeyCode::F(2) => {
+                    for i in &emus {
+                        fs::write(
+                            savestate_filename(&args.filename, i.suffix),
+                            i.emu.save_state()?,
+                        )?;
+                    }
+                }
+                KeyCode::F(3) => {
+                    for i in &emus {
+                        if let Ok(state) = fs::read(savestate_filename(&args.filename, i.suffix)) {
+                            i.emu.send(Control::LoadState(state))?;
+                        }
+                    }
+                }
+                KeyCode::F(5) => {
+                    turbo.toggle(Button::A);
+                }
+                KeyCode::F(6) => {
+                    turbo.toggle(Button::B);
+                }
+                KeyCode::Char('p') => {
+                    paused = !paused;
+                    broadcast(&emus, || Control::Pause(paused))?;
+                    display.set_status_line(status_line(
+                        paused,
+                        show_stats.then(|| (stats.stats(), &frameskip)),
+                    ));
+                    display.render();
+                }
+                KeyCode::Char('o') => {
+                    show_stats = !show_stats;
+                    display.set_status_line(status_line(
+                        paused,
+                        show_stats.then(|| (stats.stats(), &frameskip)),
+                    ));
+                    display.render();
+                }
                 KeyCode::Char('d') => {
                     terminal.act(Action::DisableRawMode).unwrap();
-                    args.verbose = true;
-                    args.pause = true;
+                    broadcast(&emus, || Control::Verbose(true))?;
+                    broadcast(&emus, || Control::SingleStep(true))?;
+                    // Steps are ignored while paused
+                    broadcast(&emus, || Control::Pause(false))?;
+                    paused = false;
+                    single_step = true;
                 }
-                _ => key_tx.send(keyevent)?,
+                _ => (),
             }
         }
 
-        if args.verbose {
-            eprintln!("{}", cpu.dump_state());
+        // Render the most recent frames, dump all of them
+        let mut new_frame = false;
+        for (n, i) in emus.iter_mut().enumerate() {
+            while let Ok(frame) = i.emu.frames().try_recv() {
+                if let Some(ref dir) = args.dump_frames {
+                    if frame.number % args.dump_frames_interval.max(1) == 0 {
+                        bmp::save(
+                            dir.join(format!("{:06}{}.bmp", frame.number, i.suffix)),
+                            DISPLAY_W,
+                            DISPLAY_H,
+                            &frame.pixels,
+                        )?;
+                    }
+                }
+                i.last_frame = Some(frame);
+                new_frame = true;
+            }
+            if let Some(ref frame) = i.last_frame {
+                for (y, line) in frame.pixels.chunks(DISPLAY_W).enumerate() {
+                    let offset = y * canvas_w + n * DISPLAY_W;
+                    canvas.pixels_mut()[offset..(offset + DISPLAY_W)].copy_from_slice(line);
+                }
+            }
+        }
+        if new_frame && frameskip.present() {
+            let start = Instant::now();
+            display.blit_frame(&canvas);
+            if let Some(ref frame) = emus[0].last_frame {
+                stats.frame(frame.cycles);
+            }
+            display.set_status_line(status_line(
+                paused,
+                show_stats.then(|| (stats.stats(), &frameskip)),
+            ));
+            display.render();
+            frameskip.rendered(start.elapsed());
         }
 
-        if args.pause {
-            let _ = stdin().read(&mut [0u8]).unwrap();
+        // An instance pauses itself when its CPU ran away, pause the
+        // others as well and offer to debug it.
+        let mut runaway = None;
+        for i in emus.iter_mut() {
+            for warning in i.emu.warnings().try_iter() {
+                match warning {
+                    Warning::Runaway(r) => runaway = Some(r),
+                    _ => i.warnings.push(warning),
+                }
+            }
         }
+        if let Some(runaway) = runaway {
+            paused = true;
+            broadcast(&emus, || Control::Pause(true))?;
+            display.set_status_line(Some(format!("{} | p: continue, d: debug", runaway)));
+            display.render();
+        }
+    }
 
-        cpu.step()?;
+    // Stop all instances before joining them, the link cable partner
+    // of an instance may otherwise still be waiting on it.
+    let _ = broadcast(&emus, || Control::Quit);
+    let mut result = Ok(());
+    for i in emus {
+        // Ends when the emulation thread exits, which reports some
+        // warnings while stopping
+        for warning in i.warnings.iter().cloned().chain(i.emu.warnings().iter()) {
+            eprintln!("WARNING: {}", warning);
+        }
+        // Returns an error if the emulation thread failed
+        let save = match i.emu.quit() {
+            Ok(save) => save,
+            Err(e) => match e.downcast::<EmulationStopped>() {
+                Ok(mut stopped) => {
+                    eprint!("{}", stopped.state);
+                    let save = stopped.save.take();
+                    result = Err(stopped.into());
+                    save
+                }
+                Err(e) => {
+                    result = Err(e);
+                    None
+                }
+            },
+        };
+        if let Some(save) = save {
+            let mut save_file = File::create(i.savefn)?;
+            save_file.write_all(&save)?;
+        }
+        if let (Some(profilefn), Some(profile)) = (&args.profile, i.profile) {
+            fs::write(
+                with_suffix(profilefn, i.suffix),
+                profile.lock().unwrap().to_csv(),
+            )?;
+        }
+        if let (Some(coveragefn), Some(coverage)) = (&args.coverage, i.coverage) {
+            fs::write(
+                with_suffix(coveragefn, i.suffix),
+                coverage.lock().unwrap().export(coveragefn),
+            )?;
+        }
     }
 
-    let mut save = File::create(savefn)?;
-    save.write_all(&cartridge.borrow().get_save())?;
+    if let (Some(moviefn), Some(movie)) = (args.record, movie) {
+        fs::write(moviefn, movie.lock().unwrap().serialize())?;
+    }
 
-    Ok(())
+    result
 }
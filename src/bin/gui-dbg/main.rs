@@ -0,0 +1,438 @@
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use eframe::egui;
+
+use gbrust::display::display::{color_to_rgb888, Color, NullDisplay};
+use gbrust::gameboy::bootrom;
+use gbrust::gameboy::cartridge::cartridge;
+use gbrust::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+use gbrust::gameboy::debugger::{DebugSnapshot, Debugger};
+use gbrust::gameboy::emulator::Emulator;
+use gbrust::gameboy::lcd::{LCD_H, LCD_W};
+use gbrust::gameboy::model::Model;
+use gbrust::gameboy::serial::Serial;
+use gbrust::gameboy::symbols::SymbolTable;
+use gbrust::input::input::NullInput;
+
+#[derive(Parser)]
+#[command(about = "Graphical Gameboy debugger")]
+struct Args {
+    /// ROM filename to load.
+    filename: String,
+
+    /// ROM to load from a zip file containing several ROMs
+    #[arg(long, value_name = "NAME")]
+    rom_entry: Option<String>,
+
+    /// Boot ROM to optionally load ('builtin' for the built-in DMG boot
+    /// ROM)
+    #[arg(short, long)]
+    bootrom: Option<String>,
+
+    /// Force DMG mode for CGB cartridges
+    #[arg(long)]
+    dmg: bool,
+
+    /// RGBDS symbol file to load
+    #[arg(long)]
+    sym: Option<PathBuf>,
+}
+
+/// Scale of the screen in the screen panel
+const SCREEN_SCALE: f32 = 3.0;
+
+/// Requests from the UI to the emulation thread
+enum Command {
+    /// Continue until a breakpoint is hit
+    Run,
+    Pause,
+    /// Execute a single instruction
+    Step,
+    /// Run a single frame
+    Frame,
+    /// Set or remove a breakpoint ([bank:]address or label)
+    ToggleBreakpoint(String),
+    /// Show the memory page of an address (or label)
+    Memory(String),
+}
+
+/// State sent from the emulation thread to the UI, after every frame
+/// while running and after every command
+struct Update {
+    pixels: Vec<Color>,
+    snapshot: DebugSnapshot,
+    running: bool,
+    /// Outcome of the last command or run, if worth mentioning
+    message: Option<String>,
+}
+
+/// The emulator and debugger, owned by the emulation thread
+struct Session {
+    emu: Emulator,
+    dbg: Debugger,
+    running: bool,
+    mem_addr: u16,
+    message: Option<String>,
+}
+
+impl Session {
+    fn handle(&mut self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Run => self.running = true,
+            Command::Pause => self.running = false,
+            Command::Step => {
+                self.running = false;
+                self.emu.step()?;
+            }
+            Command::Frame => {
+                self.running = false;
+                self.emu.run_frame()?;
+            }
+            Command::ToggleBreakpoint(spec) => {
+                let bp = self.dbg.parse_breakpoint(&spec)?;
+                let desc = self.dbg.format_breakpoint(&self.emu, bp);
+                if self.dbg.clear_breakpoint(bp) {
+                    self.message = Some(format!("Breakpoint at {} removed", desc));
+                } else {
+                    self.dbg.set_breakpoint(bp);
+                    self.message = Some(format!("Breakpoint set at {}", desc));
+                }
+            }
+            Command::Memory(addr) => self.mem_addr = self.dbg.parse_addr(&addr)?,
+        }
+        Ok(())
+    }
+
+    /// Runs a frame worth of cycles, stops at breakpoints
+    fn run(&mut self) -> Result<()> {
+        if self.dbg.run(&mut self.emu, Emulator::FRAME_CYCLES)? {
+            self.running = false;
+            let pc = self.emu.cpu().regs.pc;
+            self.message = Some(format!(
+                "Breakpoint at {}",
+                self.dbg.format_addr(&self.emu, pc)
+            ));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self) -> Update {
+        Update {
+            pixels: self.emu.get_framebuffer().to_vec(),
+            snapshot: self.dbg.snapshot(&self.emu, self.mem_addr),
+            running: self.running,
+            message: self.message.take(),
+        }
+    }
+}
+
+/// Runs the session until the UI goes away. While running, frames are
+/// paced at the speed of the real hardware; while paused, this only
+/// wakes up for commands.
+fn emulation_thread(
+    mut session: Session,
+    commands: mpsc::Receiver<Command>,
+    updates: mpsc::Sender<Update>,
+    ctx: egui::Context,
+) {
+    let frame_time = Duration::from_secs_f64(Emulator::FRAME_CYCLES as f64 / CPU_CLOCK_HZ as f64);
+    let mut next_frame = Instant::now();
+    loop {
+        let cmd = if session.running {
+            match commands.try_recv() {
+                Ok(cmd) => Some(cmd),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => return,
+            }
+        } else {
+            match commands.recv() {
+                Ok(cmd) => {
+                    next_frame = Instant::now();
+                    Some(cmd)
+                }
+                Err(_) => return,
+            }
+        };
+
+        let mut result = cmd.map_or(Ok(()), |cmd| session.handle(cmd));
+        if result.is_ok() && session.running {
+            result = session.run();
+            next_frame += frame_time;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                next_frame = now;
+            }
+        }
+        if let Err(e) = result {
+            session.running = false;
+            session.message = Some(format!("Error: {:#}", e));
+        }
+
+        if updates.send(session.update()).is_err() {
+            return;
+        }
+        ctx.request_repaint();
+    }
+}
+
+struct GuiDebugger {
+    commands: mpsc::Sender<Command>,
+    updates: mpsc::Receiver<Update>,
+    last: Option<Update>,
+    message: String,
+    screen: Option<egui::TextureHandle>,
+    mem_input: String,
+    bp_input: String,
+}
+
+impl GuiDebugger {
+    fn send(&self, cmd: Command) {
+        // The emulation thread only stops when the UI goes away
+        self.commands.send(cmd).unwrap();
+    }
+
+    /// Takes the latest update from the emulation thread, if any
+    fn receive(&mut self, ctx: &egui::Context) {
+        let mut latest = None;
+        for update in self.updates.try_iter() {
+            if let Some(ref m) = update.message {
+                self.message = m.clone();
+            }
+            latest = Some(update);
+        }
+        let Some(update) = latest else {
+            return;
+        };
+        let rgb: Vec<u8> = update
+            .pixels
+            .iter()
+            .flat_map(|&c| {
+                let (r, g, b) = color_to_rgb888(c);
+                [r, g, b]
+            })
+            .collect();
+        let image = egui::ColorImage::from_rgb([LCD_W, LCD_H], &rgb);
+        match self.screen {
+            Some(ref mut t) => t.set(image, egui::TextureOptions::NEAREST),
+            None => {
+                self.screen = Some(ctx.load_texture("screen", image, egui::TextureOptions::NEAREST))
+            }
+        }
+        self.last = Some(update);
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui, running: bool) {
+        ui.horizontal(|ui| {
+            if running {
+                if ui.button("Pause").clicked() {
+                    self.send(Command::Pause);
+                }
+            } else if ui.button("Run").clicked() {
+                self.send(Command::Run);
+            }
+            if ui.button("Step").clicked() {
+                self.send(Command::Step);
+            }
+            if ui.button("Frame").clicked() {
+                self.send(Command::Frame);
+            }
+            ui.separator();
+            ui.add(egui::TextEdit::singleline(&mut self.bp_input).desired_width(100.0));
+            if ui.button("Breakpoint").clicked() && !self.bp_input.is_empty() {
+                self.send(Command::ToggleBreakpoint(self.bp_input.clone()));
+            }
+            ui.separator();
+            ui.label(self.message.as_str());
+        });
+    }
+}
+
+fn registers(ui: &mut egui::Ui, s: &DebugSnapshot) {
+    egui::Grid::new("registers").striped(true).show(ui, |ui| {
+        for (name, val) in ["AF", "BC", "DE", "HL", "SP", "PC"].iter().zip(s.regs) {
+            ui.label(*name);
+            ui.monospace(format!("{:04X}", val));
+            ui.end_row();
+        }
+        let f = s.regs[0] as u8;
+        let flags: String = [(0x80, 'Z'), (0x40, 'N'), (0x20, 'H'), (0x10, 'C')]
+            .iter()
+            .map(|&(mask, c)| if f & mask != 0 { c } else { '-' })
+            .collect();
+        ui.label("Flags");
+        ui.monospace(flags);
+        ui.end_row();
+        ui.label("IME");
+        ui.monospace(if s.ime { "1" } else { "0" });
+        ui.end_row();
+        ui.label("ROM bank");
+        ui.monospace(format!("{:02X}", s.rom_bank));
+        ui.end_row();
+        ui.label("Frame");
+        ui.monospace(s.frame.to_string());
+        ui.end_row();
+        ui.label("Cycles");
+        ui.monospace(s.cycles.to_string());
+        ui.end_row();
+    });
+}
+
+impl eframe::App for GuiDebugger {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.receive(ctx);
+        let Some(update) = self.last.take() else {
+            return;
+        };
+        let s = &update.snapshot;
+
+        egui::TopBottomPanel::top("controls").show(ctx, |ui| self.controls(ui, update.running));
+
+        egui::Window::new("Screen").show(ctx, |ui| {
+            if let Some(ref t) = self.screen {
+                ui.image((
+                    t.id(),
+                    egui::vec2(LCD_W as f32, LCD_H as f32) * SCREEN_SCALE,
+                ));
+            }
+        });
+
+        egui::Window::new("Registers").show(ctx, |ui| registers(ui, s));
+
+        egui::Window::new("Disassembly").show(ctx, |ui| {
+            for (i, line) in s.disasm.iter().enumerate() {
+                if let Some(ref l) = line.label {
+                    ui.monospace(format!("{}:", l));
+                }
+                let marker = match (i == s.pc_line, line.breakpoint) {
+                    (true, _) => "=>",
+                    (false, true) => " *",
+                    (false, false) => "  ",
+                };
+                let mut text =
+                    egui::RichText::new(format!("{} {:04X}: {}", marker, line.addr, line.text))
+                        .monospace();
+                if i == s.pc_line {
+                    text = text.strong();
+                }
+                if line.breakpoint {
+                    text = text.color(egui::Color32::RED);
+                }
+                // Clicking a line toggles a breakpoint
+                let label = ui.add(egui::Label::new(text).sense(egui::Sense::click()));
+                if label.clicked() {
+                    self.send(Command::ToggleBreakpoint(format!("{:04X}", line.addr)));
+                }
+            }
+        });
+
+        egui::Window::new("Memory").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("<").clicked() {
+                    self.send(Command::Memory(format!(
+                        "{:04X}",
+                        s.mem_addr.wrapping_sub(0x100)
+                    )));
+                }
+                if ui.button(">").clicked() {
+                    self.send(Command::Memory(format!(
+                        "{:04X}",
+                        s.mem_addr.wrapping_add(0x100)
+                    )));
+                }
+                ui.add(egui::TextEdit::singleline(&mut self.mem_input).desired_width(100.0));
+                if ui.button("Go").clicked() && !self.mem_input.is_empty() {
+                    self.send(Command::Memory(self.mem_input.clone()));
+                }
+            });
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (row, bytes) in s.memory.chunks(16).enumerate() {
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    ui.monospace(format!(
+                        "{:04X}: {}",
+                        s.mem_addr.wrapping_add(row as u16 * 16),
+                        hex.join(" ")
+                    ));
+                }
+            });
+        });
+
+        self.last = Some(update);
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let rom = cartridge::read_rom(&args.filename, args.rom_entry.as_deref())?;
+    let cart = cartridge::load(&rom);
+    println!("Cartridge: {}", cart);
+    if let Some(warning) = cartridge::check_rom_size(&rom) {
+        println!("Warning: {}", warning);
+    }
+    let model = Model::from_cgb(cart.is_cgb() && !args.dmg);
+    let bootrom = match args.bootrom {
+        Some(ref brfile) => Some(bootrom::load(brfile, model)?),
+        None => None,
+    };
+    let symbols = match args.sym {
+        Some(ref f) => Some(SymbolTable::load(f)?),
+        None => None,
+    };
+
+    let (cmd_tx, cmd_rx) = mpsc::channel();
+    let (update_tx, update_rx) = mpsc::channel();
+    eframe::run_native(
+        "gbrust debugger",
+        eframe::NativeOptions {
+            viewport: egui::ViewportBuilder::default().with_inner_size([1024.0, 768.0]),
+            ..Default::default()
+        },
+        Box::new(move |cc| {
+            let ctx = cc.egui_ctx.clone();
+            // The emulator is built on its own thread, as it cannot be
+            // sent between threads.
+            thread::spawn(move || {
+                let emu = Emulator::new(
+                    cartridge::load(&rom),
+                    bootrom.as_deref(),
+                    Box::new(NullDisplay::new()),
+                    Box::new(NullInput::new()),
+                    model,
+                    Serial::new_null(),
+                );
+                let mut dbg = Debugger::new();
+                if let Some(symbols) = symbols {
+                    dbg.set_symbols(symbols);
+                }
+                let mut session = Session {
+                    emu,
+                    dbg,
+                    running: false,
+                    mem_addr: 0xC000,
+                    message: None,
+                };
+                let first = session.update();
+                if update_tx.send(first).is_ok() {
+                    emulation_thread(session, cmd_rx, update_tx, ctx);
+                }
+            });
+            Box::new(GuiDebugger {
+                commands: cmd_tx,
+                updates: update_rx,
+                last: None,
+                message: String::new(),
+                screen: None,
+                mem_input: String::new(),
+                bp_input: String::new(),
+            })
+        }),
+    )
+    .map_err(|e| anyhow!("{}", e))
+}
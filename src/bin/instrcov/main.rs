@@ -3,6 +3,7 @@ use std::panic;
 use gbrust::gameboy::bus::testbus::Testbus;
 use gbrust::gameboy::cpu::cpu::CPU;
 use gbrust::gameboy::cpu::instructions::{INSTRUCTIONS, INSTRUCTIONS_CB};
+use gbrust::gameboy::model::Model;
 
 fn main() {
     println!(" --- Instruction set coverage --- ");
@@ -25,7 +26,7 @@ fn main() {
         panic::set_hook(Box::new(|_| {}));
         let result = panic::catch_unwind(|| {
             let bus = Testbus::from(&opcode);
-            let mut cpu = CPU::new(Box::new(bus), false);
+            let mut cpu = CPU::new(Box::new(bus), Model::Dmg);
             cpu.step().unwrap();
         });
         let _ = panic::take_hook();
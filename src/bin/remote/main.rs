@@ -0,0 +1,67 @@
+use anyhow::Result;
+use clap::Parser;
+
+use gbrust::display::display::NullDisplay;
+use gbrust::gameboy::cartridge::cartridge;
+use gbrust::gameboy::emulator::Emulator;
+use gbrust::gameboy::model::Model;
+use gbrust::gameboy::raminit::RamInit;
+use gbrust::gameboy::remote::RemoteServer;
+use gbrust::gameboy::serial::Serial;
+use gbrust::input::input::SharedInput;
+
+#[derive(Parser)]
+#[command(about = "Gameboy emulator controlled over JSON-RPC")]
+struct Args {
+    /// ROM filename to load.
+    filename: String,
+
+    /// ROM to load from a zip file containing several ROMs
+    #[arg(long, value_name = "NAME")]
+    rom_entry: Option<String>,
+
+    /// Force DMG mode for CGB cartridges
+    #[arg(long)]
+    dmg: bool,
+
+    /// Contents of WRAM, VRAM and HRAM at power on: zero, pattern or
+    /// random[:seed]
+    #[arg(long, value_name = "INIT", default_value = "zero", value_parser = RamInit::parse)]
+    ram_init: RamInit,
+
+    /// Port to listen on (localhost only)
+    #[arg(short, long, default_value_t = 8765)]
+    port: u16,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let rom = cartridge::read_rom(&args.filename, args.rom_entry.as_deref())?;
+    let cart = cartridge::load(&rom)?;
+    println!("Cartridge: {}", cart);
+    let cgb = cart.is_cgb() && !args.dmg;
+
+    let (input, buttons) = SharedInput::new();
+    let mut emu = Emulator::new(
+        cart,
+        None,
+        Box::new(NullDisplay::new()),
+        input,
+        Model::from_cgb(cgb),
+        Serial::new_null(),
+    );
+    emu.init_ram(args.ram_init);
+    if args.ram_init != RamInit::Zeros {
+        println!("RAM initialization: {}", args.ram_init);
+    }
+
+    let server = RemoteServer::listen(("127.0.0.1", args.port))?;
+    println!("Listening on port {}", server.port());
+
+    // The system only runs on request (run_frames), so every request
+    // is handled at a frame boundary.
+    loop {
+        server.handle_next(&mut emu, &buttons)?;
+    }
+}
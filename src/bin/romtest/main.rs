@@ -0,0 +1,168 @@
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+
+use gbrust::gameboy::cartridge::cartridge;
+use gbrust::gameboy::testing::{self, Expectation, Outcome, TestSpec, DEFAULT_BUDGET};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Expect {
+    /// Serial output ends with the pass text (--value, default "Passed")
+    SerialPassText,
+    /// Result in cartridge RAM
+    MemoryResult,
+}
+
+#[derive(Parser)]
+#[command(about = "Runs a set of test ROMs and reports regressions")]
+struct Args {
+    /// Manifest (.toml) listing the ROMs, or a directory of ROMs
+    path: PathBuf,
+
+    /// How the ROMs in a directory report their result
+    #[arg(long, value_enum, default_value = "serial-pass-text")]
+    expect: Expect,
+
+    /// Pass text for the ROMs in a directory
+    #[arg(long, default_value = "Passed")]
+    value: String,
+
+    /// Cycle budget per ROM for the ROMs in a directory
+    #[arg(long, default_value_t = DEFAULT_BUDGET)]
+    cycles: u64,
+
+    /// Amount of ROMs to run in parallel (default: amount of CPUs)
+    #[arg(short, long)]
+    jobs: Option<usize>,
+}
+
+/// Lists the ROMs (.gb and .gbc) in a directory and its subdirectories
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("Cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("gb" | "gbc")
+        ) {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Builds the list of tests, with ROM paths relative to the returned
+/// base directory
+fn load_tests(args: &Args) -> Result<(PathBuf, Vec<TestSpec>)> {
+    if args.path.is_dir() {
+        let mut roms = vec![];
+        find_roms(&args.path, &mut roms)?;
+        roms.sort();
+        let expect = match args.expect {
+            Expect::SerialPassText => Expectation::Serial {
+                pass: args.value.as_bytes().to_vec(),
+                fail: b"Failed".to_vec(),
+            },
+            Expect::MemoryResult => Expectation::MemoryResult,
+        };
+        let tests = roms
+            .into_iter()
+            .map(|path| TestSpec {
+                path: path.strip_prefix(&args.path).unwrap().to_path_buf(),
+                expect: expect.clone(),
+                max_cycles: args.cycles,
+                cgb: path.extension().is_some_and(|e| e == "gbc"),
+            })
+            .collect();
+        return Ok((args.path.clone(), tests));
+    }
+
+    let manifest = fs::read_to_string(&args.path)
+        .with_context(|| format!("Cannot read {}", args.path.display()))?;
+    let tests = testing::parse_manifest(&manifest)
+        .with_context(|| format!("Invalid manifest {}", args.path.display()))?;
+    let base = args.path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    Ok((base, tests))
+}
+
+fn run_test(base: &Path, test: &TestSpec) -> Outcome {
+    match cartridge::read_rom(base.join(&test.path), None) {
+        Ok(rom) => testing::run(&rom, &test.expect, test.max_cycles, test.cgb),
+        Err(e) => Outcome::Error(format!("{:#}", e)),
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let (base, tests) = load_tests(&args)?;
+    if tests.is_empty() {
+        bail!("No ROMs found in {}", args.path.display());
+    }
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, tests.len());
+
+    // Workers take the next test from the list until all have run
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<(Outcome, Duration)>>> = Mutex::new(vec![None; tests.len()]);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(test) = tests.get(i) else {
+                    break;
+                };
+                let test_start = Instant::now();
+                let outcome = run_test(&base, test);
+                results.lock().unwrap()[i] = Some((outcome, test_start.elapsed()));
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    let width = tests
+        .iter()
+        .map(|t| t.path.display().to_string().len())
+        .max()
+        .unwrap_or(0);
+    let mut failed = 0;
+    for (test, result) in tests.iter().zip(results) {
+        let (outcome, time) = result.expect("Test did not run");
+        if !outcome.is_pass() {
+            failed += 1;
+        }
+        println!(
+            "{:width$}  {:>4}  {:7.2}s  {}",
+            test.path.display(),
+            if outcome.is_pass() { "ok" } else { "FAIL" },
+            time.as_secs_f64(),
+            if outcome.is_pass() {
+                String::new()
+            } else {
+                outcome.to_string()
+            },
+            width = width
+        );
+    }
+    println!(
+        "\n{} passed, {} failed ({} ROMs in {:.2}s)",
+        tests.len() - failed,
+        failed,
+        tests.len(),
+        start.elapsed().as_secs_f64()
+    );
+
+    if failed > 0 {
+        bail!("{} of {} ROMs failed", failed, tests.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use super::display::{color_to_rgb888, Color};
+
+/// Decoded image: (width, height, row-major RGB888 pixels)
+pub type DecodedImage = (usize, usize, Vec<(u8, u8, u8)>);
+
+/// Size of the BITMAPFILEHEADER + BITMAPINFOHEADER
+const BMP_HEADER_SIZE: usize = 14 + 40;
+
+/// Bytes per row, padded to a multiple of 4 bytes
+fn row_size(width: usize) -> usize {
+    (width * 3 + 3) & !3
+}
+
+/// Encodes a framebuffer (row-major RGB555) into an uncompressed,
+/// 24-bit BMP image.
+pub fn encode(width: usize, height: usize, pixels: &[Color]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height);
+
+    let rowsize = row_size(width);
+    let datasize = rowsize * height;
+    let mut out = Vec::with_capacity(BMP_HEADER_SIZE + datasize);
+
+    // BITMAPFILEHEADER
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&((BMP_HEADER_SIZE + datasize) as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(BMP_HEADER_SIZE as u32).to_le_bytes());
+
+    // BITMAPINFOHEADER
+    out.extend_from_slice(&40u32.to_le_bytes());
+    out.extend_from_slice(&(width as i32).to_le_bytes());
+    out.extend_from_slice(&(height as i32).to_le_bytes());
+    // Planes
+    out.extend_from_slice(&1u16.to_le_bytes());
+    // Bits per pixel
+    out.extend_from_slice(&24u16.to_le_bytes());
+    // Compression (BI_RGB)
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(datasize as u32).to_le_bytes());
+    // Resolution (72 DPI), palette size, important colors
+    out.extend_from_slice(&2835u32.to_le_bytes());
+    out.extend_from_slice(&2835u32.to_le_bytes());
+    out.extend_from_slice(&[0; 8]);
+
+    // Pixel data, stored bottom-up in BGR order
+    for y in (0..height).rev() {
+        for &c in &pixels[(y * width)..((y + 1) * width)] {
+            let (r, g, b) = color_to_rgb888(c);
+            out.extend_from_slice(&[b, g, r]);
+        }
+        out.resize(out.len() + rowsize - width * 3, 0);
+    }
+
+    out
+}
+
+/// Decodes an uncompressed, 24-bit BMP image.
+pub fn decode(data: &[u8]) -> Result<DecodedImage> {
+    if data.len() < BMP_HEADER_SIZE || &data[0..2] != b"BM" {
+        bail!("Not a BMP file");
+    }
+
+    let rd32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let rd16 = |offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+
+    let dataoffset = rd32(10) as usize;
+    let width = rd32(18) as i32;
+    let height = rd32(22) as i32;
+    if rd16(28) != 24 || rd32(30) != 0 {
+        bail!("Unsupported BMP format");
+    }
+    if width <= 0 || height == 0 {
+        bail!("Invalid BMP dimensions");
+    }
+
+    let width = width as usize;
+    let bottom_up = height > 0;
+    let height = height.unsigned_abs() as usize;
+    let rowsize = row_size(width);
+    if data.len() < dataoffset + rowsize * height {
+        bail!("BMP file truncated");
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if bottom_up { height - 1 - y } else { y };
+        let rowdata = &data[(dataoffset + row * rowsize)..];
+        for x in 0..width {
+            pixels.push((rowdata[x * 3 + 2], rowdata[x * 3 + 1], rowdata[x * 3]));
+        }
+    }
+
+    Ok((width, height, pixels))
+}
+
+/// Writes a framebuffer (row-major RGB555) to a BMP file
+pub fn save(
+    filename: impl AsRef<Path>,
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+) -> Result<()> {
+    fs::write(filename, encode(width, height, pixels))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_header() {
+        let bmp = encode(3, 2, &[0; 6]);
+
+        // 3 pixels * 3 bytes = 9, padded to 12 bytes per row
+        assert_eq!(bmp.len(), BMP_HEADER_SIZE + 12 * 2);
+        assert_eq!(&bmp[0..2], b"BM");
+        assert_eq!(bmp[2..6], ((BMP_HEADER_SIZE + 24) as u32).to_le_bytes());
+        assert_eq!(bmp[18..22], 3u32.to_le_bytes());
+        assert_eq!(bmp[22..26], 2u32.to_le_bytes());
+        assert_eq!(bmp[28..30], 24u16.to_le_bytes());
+    }
+
+    #[test]
+    fn encode_pixels() {
+        let bmp = encode(2, 2, &[0x001F, 0x03E0, 0x7C00, 0x7FFF]);
+        let data = &bmp[BMP_HEADER_SIZE..];
+
+        // Bottom row first, BGR
+        assert_eq!(data[0..6], [255, 0, 0, 255, 255, 255]);
+        assert_eq!(data[8..14], [0, 0, 255, 0, 255, 0]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let pixels: Vec<Color> = (0..(5 * 3)).map(|i| i * 0x0421).collect();
+        let (w, h, decoded) = decode(&encode(5, 3, &pixels)).unwrap();
+        assert_eq!((w, h), (5, 3));
+        assert_eq!(
+            decoded,
+            pixels.into_iter().map(color_to_rgb888).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0; 100]).is_err());
+
+        let bmp = encode(4, 4, &[0; 16]);
+        assert!(decode(&bmp[..bmp.len() - 1]).is_err());
+    }
+}
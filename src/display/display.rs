@@ -1,11 +1,207 @@
+use sha2::{Digest, Sha256};
+
+use std::cell::{Cell, Ref, RefCell};
+
 /// Type of a color definition (RGB555)
 pub type Color = u16;
 
+/// Pixel formats frames can be converted to (see FrameBuffer)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// RGB555, the native format (Color)
+    Rgb555,
+    /// RGB565, as u16
+    Rgb565,
+    /// RGB888, three bytes (R, G, B) per pixel
+    Rgb888,
+    /// One of four shades by brightness, 0 (lightest) - 3 (darkest)
+    Indexed4,
+}
+
+/// Splits an RGB555 color into its 5-bit components
+pub fn unpack_rgb555(c: Color) -> (u8, u8, u8) {
+    (
+        (c & 0x1F) as u8,
+        ((c >> 5) & 0x1F) as u8,
+        ((c >> 10) & 0x1F) as u8,
+    )
+}
+
+/// Scales 5-bit color components to 8-bit color components
+pub fn rgb555_to_rgb888((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    (
+        (r as u16 * 255 / 31) as u8,
+        (g as u16 * 255 / 31) as u8,
+        (b as u16 * 255 / 31) as u8,
+    )
+}
+
+/// Converts an RGB555 color to RGB888 components
+pub fn color_to_rgb888(c: Color) -> (u8, u8, u8) {
+    rgb555_to_rgb888(unpack_rgb555(c))
+}
+
+/// Converts an RGB555 color to RGB565 (as used by e.g. libretro)
+pub fn color_to_rgb565(c: Color) -> u16 {
+    let (r, g, b) = unpack_rgb555(c);
+    let (r, g, b) = (r as u16, g as u16, b as u16);
+    (r << 11) | (((g << 1) | (g >> 4)) << 5) | b
+}
+
+/// Size of a frame hash, see hash_frame()
+pub const FRAME_HASH_SIZE: usize = 256 / 8;
+
+/// Hashes a frame: SHA-256 of the RGB555 colors as little endian u16,
+/// in row-major order. The hash only depends on the pixels, so it can
+/// be compared between versions (e.g. to bisect rendering changes).
+pub fn hash_frame(pixels: &[Color]) -> [u8; FRAME_HASH_SIZE] {
+    let mut hasher = Sha256::new();
+    for c in pixels {
+        hasher.update(c.to_le_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Converts an RGB555 color to one of four shades by brightness,
+/// 0 (lightest) - 3 (darkest)
+pub fn color_to_indexed4(c: Color) -> u8 {
+    let (r, g, b) = color_to_rgb888(c);
+    let luma = (r as u32 * 3 + g as u32 * 6 + b as u32) / 10;
+    (((255 - luma) * 3 + 127) / 255) as u8
+}
+
+/// A conversion of a FrameBuffer, kept until the frame changes
+struct Conversion<T> {
+    valid: Cell<bool>,
+    buf: RefCell<Vec<T>>,
+}
+
+impl<T> Conversion<T> {
+    fn new() -> Self {
+        Self {
+            valid: Cell::new(false),
+            buf: RefCell::new(vec![]),
+        }
+    }
+
+    /// Returns the converted frame, converting it with 'convert' first
+    /// if the frame changed. The buffer is reused between frames.
+    fn get(&self, convert: impl FnOnce(&mut Vec<T>)) -> Ref<'_, [T]> {
+        if !self.valid.replace(true) {
+            let mut buf = self.buf.borrow_mut();
+            buf.clear();
+            convert(&mut buf);
+        }
+        Ref::map(self.buf.borrow(), |b| b.as_slice())
+    }
+}
+
+/// A complete frame (row-major) in RGB555. Conversions to other pixel
+/// formats are done once per frame and cached.
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    rgb565: Conversion<u16>,
+    rgb888: Conversion<u8>,
+    indexed4: Conversion<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::from_pixels(width, height, vec![0; width * height])
+    }
+
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        assert_eq!(pixels.len(), width * height);
+        Self {
+            width,
+            height,
+            pixels,
+            rgb565: Conversion::new(),
+            rgb888: Conversion::new(),
+            indexed4: Conversion::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    /// Gives access to the pixels to change the frame
+    pub fn pixels_mut(&mut self) -> &mut [Color] {
+        self.rgb565.valid.set(false);
+        self.rgb888.valid.set(false);
+        self.indexed4.valid.set(false);
+        &mut self.pixels
+    }
+
+    pub fn rgb565(&self) -> Ref<'_, [u16]> {
+        self.rgb565
+            .get(|out| out.extend(self.pixels.iter().map(|&c| color_to_rgb565(c))))
+    }
+
+    /// Three bytes (R, G, B) per pixel
+    pub fn rgb888(&self) -> Ref<'_, [u8]> {
+        self.rgb888.get(|out| {
+            out.extend(self.pixels.iter().flat_map(|&c| {
+                let (r, g, b) = color_to_rgb888(c);
+                [r, g, b]
+            }))
+        })
+    }
+
+    /// Shade per pixel, see color_to_indexed4()
+    pub fn indexed4(&self) -> Ref<'_, [u8]> {
+        self.indexed4
+            .get(|out| out.extend(self.pixels.iter().map(|&c| color_to_indexed4(c))))
+    }
+}
+
+impl Clone for FrameBuffer {
+    fn clone(&self) -> Self {
+        Self::from_pixels(self.width, self.height, self.pixels.clone())
+    }
+}
+
 /// Base trait for a display output
 pub trait Display: std::any::Any {
-    fn set_pixel(&mut self, x: usize, y: usize, color: Color);
+    /// Sets a single pixel. Displays that implement blit_frame() do not
+    /// need this; the default blit_frame() sets all pixels one by one.
+    fn set_pixel(&mut self, _x: usize, _y: usize, _color: Color) {}
     fn clear(&mut self);
     fn render(&mut self);
+
+    /// Pixel format the display works with, so frames are converted
+    /// once (see FrameBuffer) instead of per pixel by the display.
+    fn preferred_format(&self) -> PixelFormat {
+        PixelFormat::Rgb555
+    }
+
+    /// Copies a complete frame to the display
+    fn blit_frame(&mut self, frame: &FrameBuffer) {
+        for (i, &c) in frame.pixels().iter().enumerate() {
+            self.set_pixel(i % frame.width(), i / frame.width(), c);
+        }
+    }
+
+    /// Sets a line of text shown outside of the screen area (e.g.
+    /// statistics or a pause indicator), None removes it.
+    fn set_status_line(&mut self, _line: Option<String>) {}
+
+    /// Whether frames sent to the display are seen by anyone. If not,
+    /// the PPU may skip drawing them (see lcd::PpuMode::Instant).
+    fn shows_frames(&self) -> bool {
+        true
+    }
 }
 
 /// A display thst doesn't do anything.
@@ -18,7 +214,125 @@ impl NullDisplay {
 }
 
 impl Display for NullDisplay {
-    fn set_pixel(&mut self, _x: usize, _y: usize, _color: Color) {}
     fn clear(&mut self) {}
     fn render(&mut self) {}
+    fn blit_frame(&mut self, _frame: &FrameBuffer) {}
+    fn shows_frames(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rgb555_to_rgb888() {
+        assert_eq!(rgb555_to_rgb888((0, 0, 0)), (0, 0, 0));
+        assert_eq!(rgb555_to_rgb888((0x1F, 0x1F, 0x1F)), (255, 255, 255));
+
+        assert_eq!(rgb555_to_rgb888((0b01000, 0b01000, 0b01000)), (65, 65, 65));
+        assert_eq!(
+            rgb555_to_rgb888((0b10000, 0b10000, 0b10000)),
+            (131, 131, 131)
+        );
+    }
+
+    #[test]
+    fn test_unpack_rgb555() {
+        assert_eq!(unpack_rgb555(0x7FFF), (0x1F, 0x1F, 0x1F));
+        assert_eq!(unpack_rgb555(0), (0, 0, 0));
+        assert_eq!(unpack_rgb555(0b01000_01000_01000), (8, 8, 8));
+        assert_eq!(unpack_rgb555(0b10000_10000_10000), (16, 16, 16));
+
+        assert_eq!(unpack_rgb555(0b11111_00000_00000), (0, 0, 0x1F));
+        assert_eq!(unpack_rgb555(0b00000_11111_00000), (0, 0x1F, 0));
+        assert_eq!(unpack_rgb555(0b00000_00000_11111), (0x1F, 0, 0));
+    }
+
+    #[test]
+    fn test_color_to_rgb888() {
+        assert_eq!(color_to_rgb888(0x7FFF), (255, 255, 255));
+        assert_eq!(color_to_rgb888(0), (0, 0, 0));
+        assert_eq!(color_to_rgb888(0b00000_00000_11111), (255, 0, 0));
+        assert_eq!(color_to_rgb888(0b00000_11111_00000), (0, 255, 0));
+        assert_eq!(color_to_rgb888(0b11111_00000_00000), (0, 0, 255));
+        assert_eq!(color_to_rgb888(0b10000_01000_00001), (8, 65, 131));
+    }
+
+    #[test]
+    fn test_color_to_rgb565() {
+        assert_eq!(color_to_rgb565(0), 0);
+        assert_eq!(color_to_rgb565(0x7FFF), 0xFFFF);
+        assert_eq!(color_to_rgb565(0x001F), 0xF800);
+        assert_eq!(color_to_rgb565(0x03E0), 0x07E0);
+        assert_eq!(color_to_rgb565(0x7C00), 0x001F);
+        assert_eq!(color_to_rgb565(0x0200), 0x0420);
+    }
+
+    #[test]
+    fn test_rgb888_expansion() {
+        // 5-bit levels spread over the full 8-bit range, ends exact
+        let levels: Vec<u8> = (0..32).map(|v| rgb555_to_rgb888((v, 0, 0)).0).collect();
+        assert_eq!(levels[0], 0);
+        assert_eq!(levels[31], 255);
+        assert!(levels.windows(2).all(|w| (8..=9).contains(&(w[1] - w[0]))));
+        // Rounded down
+        assert_eq!(levels[1], 8);
+        assert_eq!(levels[30], 246);
+    }
+
+    #[test]
+    fn test_color_to_indexed4() {
+        assert_eq!(color_to_indexed4(0x7FFF), 0);
+        assert_eq!(color_to_indexed4(0b11000_11000_11000), 1);
+        assert_eq!(color_to_indexed4(0b01000_01000_01000), 2);
+        assert_eq!(color_to_indexed4(0), 3);
+        // Green weighs most
+        assert_eq!(color_to_indexed4(0x03E0), 1);
+        assert_eq!(color_to_indexed4(0x7C00), 3);
+    }
+
+    #[test]
+    fn framebuffer_conversions() {
+        let fb = FrameBuffer::from_pixels(2, 1, vec![0x7FFF, 0x001F]);
+        assert_eq!(*fb.rgb565(), [0xFFFF, 0xF800]);
+        assert_eq!(*fb.rgb888(), [255, 255, 255, 255, 0, 0]);
+        assert_eq!(*fb.indexed4(), [0, 2]);
+    }
+
+    #[test]
+    fn framebuffer_cache() {
+        let mut fb = FrameBuffer::new(2, 2);
+        assert_eq!(*fb.rgb888(), [0; 12]);
+        let ptr = fb.rgb888().as_ptr();
+
+        // Converted again after a change, into the same buffer
+        fb.pixels_mut()[3] = 0x7FFF;
+        assert_eq!(fb.rgb888()[9..], [255, 255, 255]);
+        assert_eq!(fb.rgb888().as_ptr(), ptr);
+        assert_eq!(fb.rgb565()[3], 0xFFFF);
+
+        // Clones do not share the cache
+        let mut fb2 = fb.clone();
+        fb2.pixels_mut()[3] = 0;
+        assert_eq!(fb.rgb565()[3], 0xFFFF);
+        assert_eq!(fb2.rgb565()[3], 0);
+    }
+
+    #[test]
+    fn blit_frame_default() {
+        struct PixelDisplay(Vec<(usize, usize, Color)>);
+        impl Display for PixelDisplay {
+            fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+                self.0.push((x, y, color));
+            }
+            fn clear(&mut self) {}
+            fn render(&mut self) {}
+        }
+
+        let mut d = PixelDisplay(vec![]);
+        d.blit_frame(&FrameBuffer::from_pixels(2, 2, vec![1, 2, 3, 4]));
+        assert_eq!(d.0, [(0, 0, 1), (1, 0, 2), (0, 1, 3), (1, 1, 4)]);
+    }
 }
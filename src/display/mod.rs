@@ -1,9 +1,11 @@
+pub mod bmp;
 pub mod display;
+pub mod palette;
 
 #[cfg(feature = "sixel")]
 pub mod sixel;
 
+#[cfg(feature = "terminal")]
 pub mod terminal;
 
-#[cfg(test)]
 pub mod test;
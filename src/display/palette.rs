@@ -0,0 +1,102 @@
+use super::display::Color;
+
+/// Colors for the four DMG shades, lightest to darkest
+pub type DmgPalette = [Color; 4];
+
+/// Converts 8-bit color components to an RGB555 color
+pub const fn rgb888(r: u8, g: u8, b: u8) -> Color {
+    (r as Color >> 3) | ((g as Color >> 3) << 5) | ((b as Color >> 3) << 10)
+}
+
+/// Pure greyscale (default)
+pub const DMG_GREY: DmgPalette = [0x7FFF, 0b11000_11000_11000, 0b01000_01000_01000, 0];
+
+/// Pea-green screen of the original Gameboy
+pub const DMG_GREEN: DmgPalette = [
+    rgb888(0x9B, 0xBC, 0x0F),
+    rgb888(0x8B, 0xAC, 0x0F),
+    rgb888(0x30, 0x62, 0x30),
+    rgb888(0x0F, 0x38, 0x0F),
+];
+
+/// Grey-ish screen of the Gameboy Pocket
+pub const DMG_POCKET: DmgPalette = [
+    rgb888(0xC4, 0xCF, 0xA1),
+    rgb888(0x8B, 0x95, 0x6D),
+    rgb888(0x4D, 0x53, 0x3C),
+    rgb888(0x1F, 0x1F, 0x1F),
+];
+
+/// Green-blue palette of the BGB emulator
+pub const DMG_BGB: DmgPalette = [
+    rgb888(0xE0, 0xF8, 0xD0),
+    rgb888(0x88, 0xC0, 0x70),
+    rgb888(0x34, 0x68, 0x56),
+    rgb888(0x08, 0x18, 0x20),
+];
+
+/// Warm, sepia-toned palette
+pub const DMG_SEPIA: DmgPalette = [
+    rgb888(0xF8, 0xE8, 0xC8),
+    rgb888(0xC0, 0x98, 0x68),
+    rgb888(0x70, 0x50, 0x30),
+    rgb888(0x28, 0x18, 0x08),
+];
+
+/// Approximates the colors of a CGB screen by mixing the channels
+/// (as done by gambatte). The output is also slightly darker, like
+/// the real screen.
+pub const fn color_correct(c: Color) -> Color {
+    let r = (c & 0x1F) as u32;
+    let g = ((c >> 5) & 0x1F) as u32;
+    let b = ((c >> 10) & 0x1F) as u32;
+
+    // 8-bit results
+    let nr = (r * 13 + g * 2 + b) >> 1;
+    let ng = (g * 3 + b) << 1;
+    let nb = (r * 3 + g * 2 + b * 11) >> 1;
+
+    ((nr >> 3) | ((ng >> 3) << 5) | ((nb >> 3) << 10)) as Color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::display::unpack_rgb555;
+
+    #[test]
+    fn rgb888_to_555() {
+        assert_eq!(rgb888(0xFF, 0xFF, 0xFF), 0x7FFF);
+        assert_eq!(rgb888(0, 0, 0), 0);
+        assert_eq!(unpack_rgb555(rgb888(0xF8, 0x08, 0x80)), (0x1F, 0x01, 0x10));
+    }
+
+    #[test]
+    fn palettes_lightest_first() {
+        for p in [DMG_GREY, DMG_GREEN, DMG_POCKET, DMG_BGB, DMG_SEPIA] {
+            let luma = p.map(|c| {
+                let (r, g, b) = unpack_rgb555(c);
+                r as u32 + g as u32 + b as u32
+            });
+            assert!(luma.windows(2).all(|w| w[0] >= w[1]));
+        }
+    }
+
+    #[test]
+    fn color_correct_known() {
+        // White and black are preserved
+        assert_eq!(color_correct(0x7FFF), 0x7FFF);
+        assert_eq!(color_correct(0x0000), 0x0000);
+
+        // Primaries bleed into the other channels
+        assert_eq!(unpack_rgb555(color_correct(0x001F)), (25, 0, 5));
+        assert_eq!(unpack_rgb555(color_correct(0x03E0)), (3, 23, 3));
+        assert_eq!(unpack_rgb555(color_correct(0x7C00)), (1, 7, 21));
+
+        // Grey stays grey
+        assert_eq!(
+            unpack_rgb555(color_correct(0b10000_10000_10000)),
+            (16, 16, 16)
+        );
+    }
+}
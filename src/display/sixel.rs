@@ -3,10 +3,10 @@ use std::io::Write;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
-use super::display::{Color, Display};
+use super::display::{color_to_rgb888, Color, Display, FrameBuffer, PixelFormat};
 
 use sixel_rs::encoder::{Encoder, QuickFrameBuilder};
-use sixel_rs::sys::PixelFormat;
+use sixel_rs::sys::PixelFormat as SixelFormat;
 
 pub struct SixelDisplay {
     width: usize,
@@ -28,9 +28,9 @@ impl SixelDisplay {
         Self {
             width,
             height,
-            depth: 2,
+            depth: 3,
             scale: 4,
-            buffer: vec![0; width * height * 2 * 4 * 4],
+            buffer: vec![0; width * height * 3 * 4 * 4],
             encoder,
             updates: 0,
             last_frame: Instant::now(),
@@ -44,6 +44,16 @@ impl SixelDisplay {
         self.stdout.write_all(s.as_bytes()).unwrap();
     }
 
+    /// Sets a scaled up pixel in the buffer
+    fn put_pixel(&mut self, x: usize, y: usize, rgb: &[u8]) {
+        for px in (x * self.scale)..((x + 1) * self.scale) {
+            for py in (y * self.scale)..((y + 1) * self.scale) {
+                let offset = px * self.depth + py * self.depth * self.width * self.scale;
+                self.buffer[offset..(offset + self.depth)].copy_from_slice(rgb);
+            }
+        }
+    }
+
     fn clear_screen(&mut self) {
         let s = format!("\x1b[2J");
         self.stdout.write_all(s.as_bytes()).unwrap();
@@ -53,13 +63,18 @@ impl SixelDisplay {
 
 impl Display for SixelDisplay {
     fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
-        for px in (x * self.scale)..((x + 1) * self.scale) {
-            for py in (y * self.scale)..((y + 1) * self.scale) {
-                self.buffer[px * self.depth + py * self.depth * self.width * self.scale + 0] =
-                    (color >> 8) as u8;
-                self.buffer[px * self.depth + py * self.depth * self.width * self.scale + 1] =
-                    (color & 0xFF) as u8;
-            }
+        let (r, g, b) = color_to_rgb888(color);
+        self.put_pixel(x, y, &[r, g, b]);
+    }
+
+    fn preferred_format(&self) -> PixelFormat {
+        PixelFormat::Rgb888
+    }
+
+    fn blit_frame(&mut self, frame: &FrameBuffer) {
+        assert_eq!((frame.width(), frame.height()), (self.width, self.height));
+        for (i, rgb) in frame.rgb888().chunks_exact(3).enumerate() {
+            self.put_pixel(i % self.width, i / self.width, rgb);
         }
     }
 
@@ -75,7 +90,7 @@ impl Display for SixelDisplay {
             let frame = QuickFrameBuilder::new()
                 .width(self.width * self.scale)
                 .height(self.height * self.scale)
-                .format(PixelFormat::RGB555)
+                .format(SixelFormat::RGB888)
                 .pixels(self.buffer.clone());
 
             self.move_cursor(0, 0);
@@ -2,46 +2,267 @@ use std::sync::mpsc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
-use super::display::{Color, Display};
+use super::display::{color_to_rgb888, Color, Display, FrameBuffer, PixelFormat};
+use crate::input::keymap::KeyMap;
 use crate::input::terminal::TerminalInput;
 
 use anyhow::Result;
+use std::env;
+use std::fmt::Write as _;
 use std::io::{Stdout, Write};
-use terminal::{Action, Clear, Color as TerminalColor, KeyEvent, Terminal};
+use terminal::{Action, Clear, KeyEvent, Retrieved, Terminal, Value};
 
-const PX_BOT: char = '▄';
-//const PX_TOP: char = '▀';
-const PX_BOTH: char = '█';
-const PX_NONE: char = ' ';
+/// Upper half block: the foreground color is the top pixel, the
+/// background color the bottom pixel.
+const PX_TOP: char = '▀';
+
+/// Characters used in shaded mode, from light to dark
+const SHADES: [char; 5] = ['█', '▓', '▒', '░', ' '];
+
+/// Full redraw every this many frames
+const FULL_REDRAW_FRAMES: usize = 300;
+
+/// RGB888 color components
+type Rgb = (u8, u8, u8);
+
+/// How pixels are mapped to character cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RenderMode {
+    /// 1x2 pixels per cell using half blocks, with 256 colors
+    HalfBlock,
+    /// 2x4 pixels per cell, shaded by brightness without colors. Used
+    /// when the terminal has no color support or is too small.
+    Shaded,
+}
+
+impl RenderMode {
+    /// Size of the pixel block mapped to one cell
+    fn cell_size(&self) -> (usize, usize) {
+        match self {
+            Self::HalfBlock => (1, 2),
+            Self::Shaded => (2, 4),
+        }
+    }
+
+    /// Size of an image in cells
+    fn cells(&self, width: usize, height: usize) -> (usize, usize) {
+        let (cw, ch) = self.cell_size();
+        (width.div_ceil(cw), height.div_ceil(ch))
+    }
+
+    /// Selects the mode for an image and terminal size (in columns and
+    /// rows, one row is reserved for the status line).
+    fn select(width: usize, height: usize, term_size: Option<(u16, u16)>, color: bool) -> Self {
+        if !color {
+            return Self::Shaded;
+        }
+        let (w, h) = Self::HalfBlock.cells(width, height);
+        match term_size {
+            Some((cols, rows)) if (cols as usize) < w || (rows as usize) < h + 1 => Self::Shaded,
+            _ => Self::HalfBlock,
+        }
+    }
+}
+
+/// Returns true if the terminal is assumed to support 256 colors, given
+/// the TERM and NO_COLOR environment variables.
+fn color_supported(term: Option<&str>, no_color: bool) -> bool {
+    !no_color && !matches!(term, None | Some("") | Some("dumb"))
+}
+
+/// Where the image goes on the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// Render mode and (column, row) of the top left of the image
+    Image(RenderMode, (usize, usize)),
+    /// The terminal is too small for the image, (columns, rows) needed
+    TooSmall(usize, usize),
+}
+
+impl Layout {
+    /// Centers an image on a terminal of the specified size (in columns
+    /// and rows, one row is reserved for the status line). If the size
+    /// is unknown, the image is drawn at the top left.
+    fn new(width: usize, height: usize, term_size: Option<(u16, u16)>, color: bool) -> Self {
+        let mode = RenderMode::select(width, height, term_size, color);
+        let (w, h) = mode.cells(width, height);
+        match term_size {
+            Some((cols, rows)) if (cols as usize) < w || (rows as usize) < h + 1 => {
+                Self::TooSmall(w, h + 1)
+            }
+            Some((cols, rows)) => {
+                Self::Image(mode, ((cols as usize - w) / 2, (rows as usize - h - 1) / 2))
+            }
+            None => Self::Image(mode, (0, 0)),
+        }
+    }
+}
+
+/// A character cell on the terminal. Colors are ANSI color numbers,
+/// None is the default color of the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    fg: Option<u8>,
+    bg: Option<u8>,
+}
+
+/// Encodes two vertically adjacent pixels into a half block cell
+fn encode_pair(top: Rgb, bottom: Rgb) -> Cell {
+    let top = rgb888_to_ansi(top);
+    let bottom = rgb888_to_ansi(bottom);
+    if top == bottom {
+        // Only the background is needed
+        Cell {
+            ch: ' ',
+            fg: None,
+            bg: Some(top),
+        }
+    } else {
+        Cell {
+            ch: PX_TOP,
+            fg: Some(top),
+            bg: Some(bottom),
+        }
+    }
+}
+
+/// Encodes a block of pixels into a shaded cell, by average brightness
+fn encode_shade(pixels: &[Rgb]) -> Cell {
+    let sum: usize = pixels
+        .iter()
+        .map(|&(r, g, b)| (r as usize * 3 + g as usize * 6 + b as usize) / 10)
+        .sum();
+    let brightness = sum / pixels.len().max(1);
+    Cell {
+        ch: SHADES[(255 - brightness.min(255)) * SHADES.len() / 256],
+        fg: None,
+        bg: None,
+    }
+}
+
+/// Writes the escape sequence to set a color (SGR 38/48), or to
+/// reset it to the default (SGR 39/49).
+fn write_color(out: &mut String, base: u8, color: Option<u8>) {
+    match color {
+        Some(c) => write!(out, "\x1b[{};5;{}m", base, c).unwrap(),
+        None => write!(out, "\x1b[{}m", base + 1).unwrap(),
+    }
+}
+
+/// Encodes cells to escape sequences and characters. Only cells that
+/// differ from 'prev' are written (all cells if there is no previous
+/// frame). Cursor movement is skipped for consecutive cells and color
+/// changes are only emitted when the color differs from the previous
+/// cell written. 'origin' is the (column, row) of the top left cell.
+fn encode_cells(
+    prev: Option<&[Cell]>,
+    cells: &[Cell],
+    width: usize,
+    origin: (usize, usize),
+) -> String {
+    let mut out = String::new();
+    // Current state of the terminal, None if unknown
+    let mut cursor = None;
+    let mut fg = None;
+    let mut bg = None;
+
+    for (i, cell) in cells.iter().enumerate() {
+        if prev.is_some_and(|p| p[i] == *cell) {
+            continue;
+        }
+        let pos = (origin.0 + i % width, origin.1 + i / width);
+        if cursor != Some(pos) {
+            write!(out, "\x1b[{};{}H", pos.1 + 1, pos.0 + 1).unwrap();
+        }
+        if cell.ch != ' ' && fg != Some(cell.fg) {
+            write_color(&mut out, 38, cell.fg);
+            fg = Some(cell.fg);
+        }
+        if bg != Some(cell.bg) {
+            write_color(&mut out, 48, cell.bg);
+            bg = Some(cell.bg);
+        }
+        out.push(cell.ch);
+        cursor = Some((pos.0 + 1, pos.1));
+    }
+    out
+}
+
+/// Terminal operations to enter and leave raw mode
+pub trait RawTerminal {
+    fn enable_raw_mode(&self) -> Result<()>;
+
+    /// Leaves raw mode, shows the cursor and resets the colors
+    fn restore(&self) -> Result<()>;
+}
+
+impl RawTerminal for Terminal<Stdout> {
+    fn enable_raw_mode(&self) -> Result<()> {
+        Ok(self.act(Action::EnableRawMode)?)
+    }
+
+    fn restore(&self) -> Result<()> {
+        self.act(Action::DisableRawMode)?;
+        self.act(Action::ShowCursor)?;
+        self.act(Action::ResetColor)?;
+        Ok(())
+    }
+}
+
+/// Puts the terminal in raw mode and restores it when dropped, so the
+/// terminal is also usable again after an error or a panic.
+pub struct RawModeGuard<T: RawTerminal> {
+    terminal: T,
+    active: bool,
+}
+
+impl<T: RawTerminal> RawModeGuard<T> {
+    pub fn new(terminal: T) -> Result<Self> {
+        terminal.enable_raw_mode()?;
+        Ok(Self {
+            terminal,
+            active: true,
+        })
+    }
+
+    /// Restores the terminal now (e.g. before printing an error),
+    /// does nothing if it was already restored.
+    pub fn restore(&mut self) -> Result<()> {
+        if self.active {
+            self.active = false;
+            self.terminal.restore()?;
+        }
+        Ok(())
+    }
+
+    pub fn terminal(&self) -> &T {
+        &self.terminal
+    }
+}
+
+impl<T: RawTerminal> Drop for RawModeGuard<T> {
+    fn drop(&mut self) {
+        let _ = self.restore();
+    }
+}
 
 pub struct TerminalDisplay {
     width: usize,
     height: usize,
-    buffer: Vec<Vec<Color>>,
+    buffer: Vec<Rgb>,
     terminal: Terminal<Stdout>,
+    layout: Layout,
+    /// Last known terminal size
+    term_size: Option<(u16, u16)>,
+    /// Cells on the terminal, None to redraw everything
+    cells: Option<Vec<Cell>>,
     updates: usize,
     last_frame: Instant,
     frametime: u64,
-}
-
-/// Flag to mark a pixel for redrawing
-/// We use bit 15 as RGB555 only uses bits 0 - 14.
-const DISP_DIRTY: u16 = 1 << 15;
-
-fn unpack_rgb555(c: Color) -> (u8, u8, u8) {
-    (
-        (c & 0x1F) as u8,
-        ((c >> 5) & 0x1F) as u8,
-        ((c >> 10) & 0x1F) as u8,
-    )
-}
-
-fn rgb555_to_rgb888((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
-    (
-        (r as u16 * 255 / 31) as u8,
-        (g as u16 * 255 / 31) as u8,
-        (b as u16 * 255 / 31) as u8,
-    )
+    /// Line shown below the screen
+    status: Option<String>,
+    status_dirty: bool,
 }
 
 fn rgb888_to_ansi((r, g, b): (u8, u8, u8)) -> u8 {
@@ -60,96 +281,152 @@ fn rgb888_to_ansi((r, g, b): (u8, u8, u8)) -> u8 {
 
 impl TerminalDisplay {
     pub fn new(width: usize, height: usize, fps: u64) -> Self {
-        let mut vs: Vec<Vec<Color>> = Vec::with_capacity(height);
-        for _ in 0..height {
-            let mut vline = Vec::<Color>::with_capacity(width);
-            for _ in 0..width {
-                vline.push(0 | DISP_DIRTY);
-            }
-            vs.push(vline);
-        }
-
         let term = terminal::stdout();
         term.act(Action::ResetColor).unwrap();
         term.act(Action::HideCursor).unwrap();
         term.act(Action::DisableBlinking).unwrap();
-        term.act(Action::ClearTerminal(Clear::All)).unwrap();
-        term.act(Action::MoveCursorTo(0, 0)).unwrap();
 
-        Self {
+        let mut disp = Self {
             width,
             height,
-            buffer: vs,
+            buffer: vec![(0, 0, 0); width * height],
             terminal: term,
+            layout: Layout::Image(RenderMode::HalfBlock, (0, 0)),
+            term_size: None,
+            cells: None,
             updates: 0,
             last_frame: Instant::now(),
             frametime: (1000000 / fps),
-        }
+            status: None,
+            status_dirty: false,
+        };
+        disp.update_layout().unwrap();
+        disp
     }
 
-    pub fn create_input(&self, key_rx: mpsc::Receiver<KeyEvent>) -> TerminalInput {
-        TerminalInput::new(key_rx)
+    /// Size of the terminal in columns and rows, if it can be queried
+    fn terminal_size(&self) -> Option<(u16, u16)> {
+        match self.terminal.get(Value::TerminalSize) {
+            Ok(Retrieved::TerminalSize(cols, rows)) => Some((cols, rows)),
+            _ => None,
+        }
     }
 
-    /// Map a color from our internal color type to a terminal color
-    fn map_color(&self, c: Color) -> TerminalColor {
-        let ansi = rgb888_to_ansi(rgb555_to_rgb888(unpack_rgb555(c)));
-        TerminalColor::AnsiValue(ansi)
+    /// Selects the render mode and centers the image based on the
+    /// current terminal size. Clears the terminal if anything changed.
+    fn update_layout(&mut self) -> Result<()> {
+        let term_size = self.terminal_size();
+        let color = color_supported(
+            env::var("TERM").ok().as_deref(),
+            env::var_os("NO_COLOR").is_some(),
+        );
+        let layout = Layout::new(self.width, self.height, term_size, color);
+
+        if self.cells.is_none() || layout != self.layout || term_size != self.term_size {
+            self.layout = layout;
+            self.term_size = term_size;
+            self.cells = None;
+            self.terminal.act(Action::ResetColor)?;
+            self.terminal.act(Action::ClearTerminal(Clear::All))?;
+            self.status_dirty = self.status.is_some();
+        }
+        Ok(())
     }
 
-    /// Map a pair of two vertically adjacent pixels to a printable
-    /// character.
-    fn map_ch(&self, y1: Color, y2: Color) -> char {
-        if y1 == 3 && y2 == 3 {
-            // Both black
-            PX_NONE
-        } else if y1 == y2 {
-            // Same color
-            PX_BOTH
-        } else {
-            // Different colors
-            PX_BOT
+    /// Terminal row below the image, where the status line is shown
+    pub fn status_row(&self) -> u16 {
+        match self.layout {
+            Layout::Image(mode, origin) => {
+                (origin.1 + mode.cells(self.width, self.height).1) as u16
+            }
+            Layout::TooSmall(..) => 1,
         }
     }
 
-    /// Render a pair of two vertically adjacent pixels, specifying the cpordinates of the
-    /// top pixel.
-    fn render_pair(&mut self, x: usize, y: usize, top: Color, bottom: Color) -> Result<()> {
-        assert_eq!(y & 1, 0);
+    /// Renders the status line on the row below the screen, padded to
+    /// the screen width to overwrite the previous one.
+    fn render_status(&mut self, mode: RenderMode, origin: (usize, usize)) -> Result<()> {
+        let width = mode.cells(self.width, self.height).0;
+        let line: String = self
+            .status
+            .as_deref()
+            .unwrap_or("")
+            .chars()
+            .take(width)
+            .collect();
 
         self.terminal
-            .batch(Action::MoveCursorTo(x as u16, y as u16 / 2))?;
-
-        let ch = self.map_ch(top, bottom);
-        if ch == PX_BOT || ch == PX_BOTH {
-            self.terminal
-                .batch(Action::SetForegroundColor(self.map_color(bottom)))?;
-        }
-        if ch == PX_BOT || ch == PX_NONE {
-            self.terminal
-                .batch(Action::SetBackgroundColor(self.map_color(top)))?;
-        }
+            .batch(Action::MoveCursorTo(origin.0 as u16, self.status_row()))?;
+        self.terminal.batch(Action::ResetColor)?;
+        write!(self.terminal, "{:<1$}", line, width)?;
+        self.status_dirty = false;
+        Ok(())
+    }
 
-        write!(self.terminal, "{}", ch)?;
+    pub fn create_input(&self, key_rx: mpsc::Receiver<KeyEvent>, keymap: KeyMap) -> TerminalInput {
+        TerminalInput::new(key_rx, keymap)
+    }
 
-        Ok(())
+    /// Maps the image to character cells in the specified mode
+    fn build_cells(&self, mode: RenderMode) -> Vec<Cell> {
+        let (cw, ch) = mode.cell_size();
+        let (w, h) = mode.cells(self.width, self.height);
+        let mut cells = Vec::with_capacity(w * h);
+        let mut block = Vec::with_capacity(cw * ch);
+        for cy in 0..h {
+            for cx in 0..w {
+                block.clear();
+                for y in (cy * ch)..((cy + 1) * ch).min(self.height) {
+                    for x in (cx * cw)..((cx + 1) * cw).min(self.width) {
+                        block.push(self.buffer[y * self.width + x]);
+                    }
+                }
+                cells.push(match mode {
+                    // Odd heights repeat the last line
+                    RenderMode::HalfBlock => encode_pair(block[0], *block.last().unwrap()),
+                    RenderMode::Shaded => encode_shade(&block),
+                });
+            }
+        }
+        cells
     }
 
-    /// Render changed pixels since last redraw, or entire frame if
+    /// Render changed cells since last redraw, or the entire frame if
     /// 'full' is set.
     fn render_partial(&mut self, full: bool) -> Result<()> {
-        for y in (0..self.height).step_by(2) {
-            for x in 0..self.width {
-                if (self.buffer[y][x] | self.buffer[y + 1][x]) & DISP_DIRTY == DISP_DIRTY || full {
-                    self.buffer[y][x] &= !DISP_DIRTY;
-                    self.buffer[y + 1][x] &= !DISP_DIRTY;
-
-                    let y1 = self.buffer[y][x];
-                    let y2 = self.buffer[y + 1][x];
+        // Also picks up terminal resizes
+        self.update_layout()?;
+        if full {
+            self.cells = None;
+        }
 
-                    self.render_pair(x, y, y1, y2)?;
+        let (mode, origin) = match self.layout {
+            Layout::Image(mode, origin) => (mode, origin),
+            Layout::TooSmall(cols, rows) => {
+                if self.cells.is_none() {
+                    let cols_now = self.term_size.map_or(0, |(c, _)| c as usize);
+                    let msg: String = format!("Terminal too small, {}x{} needed", cols, rows)
+                        .chars()
+                        .take(cols_now)
+                        .collect();
+                    self.terminal.batch(Action::MoveCursorTo(0, 0))?;
+                    write!(self.terminal, "{}", msg)?;
+                    self.terminal.flush_batch()?;
+                    // Nothing to draw until the size changes
+                    self.cells = Some(vec![]);
                 }
+                return Ok(());
             }
+        };
+
+        let cells = self.build_cells(mode);
+        let width = mode.cells(self.width, self.height).0;
+        let out = encode_cells(self.cells.as_deref(), &cells, width, origin);
+        self.terminal.write_all(out.as_bytes())?;
+        self.cells = Some(cells);
+
+        if self.status_dirty || (full && self.status.is_some()) {
+            self.render_status(mode, origin)?;
         }
         self.terminal.flush_batch()?;
 
@@ -162,17 +439,32 @@ impl Display for TerminalDisplay {
         assert!(x < self.width);
         assert!(y < self.height);
 
-        if self.buffer[y][x] & !DISP_DIRTY != color {
-            self.buffer[y][x] = DISP_DIRTY | color;
-        }
+        self.buffer[y * self.width + x] = color_to_rgb888(color);
     }
 
     fn clear(&mut self) {}
 
+    fn preferred_format(&self) -> PixelFormat {
+        PixelFormat::Rgb888
+    }
+
+    fn blit_frame(&mut self, frame: &FrameBuffer) {
+        assert_eq!((frame.width(), frame.height()), (self.width, self.height));
+        for (px, c) in self.buffer.iter_mut().zip(frame.rgb888().chunks_exact(3)) {
+            *px = (c[0], c[1], c[2]);
+        }
+    }
+
+    fn set_status_line(&mut self, line: Option<String>) {
+        if line != self.status {
+            self.status = line;
+            self.status_dirty = true;
+        }
+    }
+
     fn render(&mut self) {
-        // Full redraw every 300 frames
         self.render_partial(self.updates == 0).unwrap();
-        self.updates = (self.updates + 1) % 300;
+        self.updates = (self.updates + 1) % FULL_REDRAW_FRAMES;
 
         // Limit the framerate
         let framelen = self.last_frame.elapsed().as_micros() as u64;
@@ -187,35 +479,200 @@ impl Display for TerminalDisplay {
 mod tests {
     use super::*;
 
+    use std::cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_rgb888_to_ansi() {
+        assert_eq!(rgb888_to_ansi((0, 0, 0)), 0);
+        assert_eq!(rgb888_to_ansi((65, 65, 65)), 237);
+        assert_eq!(rgb888_to_ansi((131, 131, 131)), 243);
+        assert_eq!(rgb888_to_ansi((255, 255, 255)), 231);
+    }
+
+    const WHITE: Rgb = (255, 255, 255);
+    const BLACK: Rgb = (0, 0, 0);
+
+    #[test]
+    fn test_encode_pair() {
+        assert_eq!(
+            encode_pair(WHITE, BLACK),
+            Cell {
+                ch: PX_TOP,
+                fg: Some(231),
+                bg: Some(0)
+            }
+        );
+        assert_eq!(
+            encode_pair(BLACK, BLACK),
+            Cell {
+                ch: ' ',
+                fg: None,
+                bg: Some(0)
+            }
+        );
+    }
+
+    #[test]
+    fn test_encode_shade() {
+        assert_eq!(encode_shade(&[WHITE; 8]).ch, '█');
+        assert_eq!(encode_shade(&[BLACK; 8]).ch, ' ');
+        assert_eq!(encode_shade(&[WHITE, BLACK]).ch, '▒');
+        assert_eq!(encode_shade(&[]).ch, ' ');
+    }
+
+    #[test]
+    fn test_encode_cells() {
+        let a = encode_pair(WHITE, BLACK);
+        let b = encode_pair(BLACK, BLACK);
+        let frame = [a, a, b, a];
+
+        // Full frame: one cursor move per row, colors only when changed
+        assert_eq!(
+            encode_cells(None, &frame, 2, (0, 0)),
+            "\x1b[1;1H\x1b[38;5;231m\x1b[48;5;0m▀▀\x1b[2;1H ▀"
+        );
+        assert_eq!(
+            encode_cells(None, &frame[..1], 2, (10, 5)),
+            "\x1b[6;11H\x1b[38;5;231m\x1b[48;5;0m▀"
+        );
+
+        // Only changed cells
+        assert_eq!(encode_cells(Some(&frame), &frame, 2, (0, 0)), "");
+        assert_eq!(
+            encode_cells(Some(&frame), &[a, b, b, a], 2, (0, 0)),
+            "\x1b[1;2H\x1b[48;5;0m "
+        );
+
+        // Default colors
+        let shade = encode_shade(&[WHITE]);
+        assert_eq!(
+            encode_cells(None, &[shade], 1, (0, 0)),
+            "\x1b[1;1H\x1b[39m\x1b[49m█"
+        );
+    }
+
     #[test]
-    fn test_rgb555_to_rgb888() {
-        assert_eq!(rgb555_to_rgb888((0, 0, 0)), (0, 0, 0));
-        assert_eq!(rgb555_to_rgb888((0x1F, 0x1F, 0x1F)), (255, 255, 255));
+    fn test_select_mode() {
+        let sel = RenderMode::select;
+        assert_eq!(sel(160, 144, Some((160, 73)), true), RenderMode::HalfBlock);
+        assert_eq!(sel(160, 144, None, true), RenderMode::HalfBlock);
+        assert_eq!(sel(160, 144, Some((160, 72)), true), RenderMode::Shaded);
+        assert_eq!(sel(160, 144, Some((80, 73)), true), RenderMode::Shaded);
+        assert_eq!(sel(160, 144, Some((200, 100)), false), RenderMode::Shaded);
+        assert_eq!(RenderMode::Shaded.cells(160, 144), (80, 36));
+        assert_eq!(RenderMode::HalfBlock.cells(5, 5), (5, 3));
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = |cols, rows, color| Layout::new(160, 144, Some((cols, rows)), color);
 
-        assert_eq!(rgb555_to_rgb888((0b01000, 0b01000, 0b01000)), (65, 65, 65));
+        // Exact fit and centered
+        assert_eq!(
+            layout(160, 73, true),
+            Layout::Image(RenderMode::HalfBlock, (0, 0))
+        );
+        assert_eq!(
+            layout(200, 83, true),
+            Layout::Image(RenderMode::HalfBlock, (20, 5))
+        );
+        assert_eq!(
+            Layout::new(160, 144, None, true),
+            Layout::Image(RenderMode::HalfBlock, (0, 0))
+        );
+
+        // Falls back to shaded mode
+        assert_eq!(
+            layout(100, 40, true),
+            Layout::Image(RenderMode::Shaded, (10, 1))
+        );
         assert_eq!(
-            rgb555_to_rgb888((0b10000, 0b10000, 0b10000)),
-            (131, 131, 131)
+            layout(200, 100, false),
+            Layout::Image(RenderMode::Shaded, (60, 31))
         );
+
+        // Too small for anything
+        assert_eq!(layout(79, 73, true), Layout::TooSmall(80, 37));
+        assert_eq!(layout(160, 36, true), Layout::TooSmall(80, 37));
+        assert_eq!(layout(0, 0, false), Layout::TooSmall(80, 37));
+    }
+
+    /// Records the raw mode state
+    #[derive(Clone, Default)]
+    struct MockTerminal {
+        raw: Rc<cell::Cell<bool>>,
+        restores: Rc<cell::Cell<usize>>,
+    }
+
+    impl RawTerminal for MockTerminal {
+        fn enable_raw_mode(&self) -> Result<()> {
+            self.raw.set(true);
+            Ok(())
+        }
+
+        fn restore(&self) -> Result<()> {
+            self.raw.set(false);
+            self.restores.set(self.restores.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn raw_mode_guard() {
+        let term = MockTerminal::default();
+        let mut guard = RawModeGuard::new(term.clone()).unwrap();
+        assert!(term.raw.get());
+        guard.restore().unwrap();
+        assert!(!term.raw.get());
+        drop(guard);
+        assert_eq!(term.restores.get(), 1);
     }
 
     #[test]
-    fn test_unpack_rgb555() {
-        assert_eq!(unpack_rgb555(0x7FFF), (0x1F, 0x1F, 0x1F));
-        assert_eq!(unpack_rgb555(0), (0, 0, 0));
-        assert_eq!(unpack_rgb555(0b01000_01000_01000), (8, 8, 8));
-        assert_eq!(unpack_rgb555(0b10000_10000_10000), (16, 16, 16));
+    fn raw_mode_guard_error() {
+        use crate::gameboy::cartridge::cartridge;
+        use crate::gameboy::emulator::Emulator;
+        use crate::gameboy::model::Model;
+
+        /// Runs a ROM in raw mode until it fails
+        fn run(term: MockTerminal) -> anyhow::Result<()> {
+            let _guard = RawModeGuard::new(term.clone())?;
+            let mut rom = vec![0; 32 * 1024];
+            // Invalid opcode
+            rom[0x100] = 0xD3;
+            let mut emu = Emulator::new_headless(cartridge::load(&rom).unwrap(), Model::Dmg);
+            loop {
+                assert!(term.raw.get());
+                emu.step()?;
+            }
+        }
 
-        assert_eq!(unpack_rgb555(0b11111_00000_00000), (0, 0, 0x1F));
-        assert_eq!(unpack_rgb555(0b00000_11111_00000), (0, 0x1F, 0));
-        assert_eq!(unpack_rgb555(0b00000_00000_11111), (0x1F, 0, 0));
+        let term = MockTerminal::default();
+        assert!(run(term.clone()).is_err());
+        assert!(!term.raw.get());
+        assert_eq!(term.restores.get(), 1);
     }
 
     #[test]
-    fn test_rgb888_to_ansi() {
-        assert_eq!(rgb888_to_ansi((0, 0, 0)), 0);
-        assert_eq!(rgb888_to_ansi((65, 65, 65)), 237);
-        assert_eq!(rgb888_to_ansi((131, 131, 131)), 243);
-        assert_eq!(rgb888_to_ansi((255, 255, 255)), 231);
+    fn raw_mode_guard_panic() {
+        let term = MockTerminal::default();
+        let result = crate::test::without_panic_output(|| {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                let _guard = RawModeGuard::new(term.clone()).unwrap();
+                panic!("Emulator bug");
+            }))
+        });
+        assert!(result.is_err());
+        assert!(!term.raw.get());
+    }
+
+    #[test]
+    fn test_color_supported() {
+        assert!(color_supported(Some("xterm-256color"), false));
+        assert!(!color_supported(Some("xterm-256color"), true));
+        assert!(!color_supported(Some("dumb"), false));
+        assert!(!color_supported(None, false));
     }
 }
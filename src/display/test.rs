@@ -1,12 +1,10 @@
-use super::display::{Color, Display};
-
-use sha2::{Digest, Sha256};
+use super::display::{hash_frame, Color, Display, FrameBuffer, FRAME_HASH_SIZE};
 
 use std::cell::Cell;
 use std::cmp;
 use std::rc::Rc;
 
-/// A display that hashes the contents using SHA256.
+/// A display that hashes the contents (see hash_frame()).
 pub struct TestDisplay {
     width: usize,
     height: usize,
@@ -17,7 +15,7 @@ pub struct TestDisplay {
 #[derive(Debug, Copy, Clone)]
 pub struct TestDisplayState {
     pub stable_frames: u16,
-    pub hash: [u8; 256 / 8],
+    pub hash: [u8; FRAME_HASH_SIZE],
 }
 
 pub type TDS = Rc<Cell<TestDisplayState>>;
@@ -35,7 +33,7 @@ impl TestDisplay {
 
         let state = Rc::new(Cell::new(TestDisplayState {
             stable_frames: 0,
-            hash: [0; 256 / 8],
+            hash: [0; FRAME_HASH_SIZE],
         }));
 
         (
@@ -60,26 +58,29 @@ impl Display for TestDisplay {
 
     fn clear(&mut self) {}
 
+    fn blit_frame(&mut self, frame: &FrameBuffer) {
+        assert_eq!(frame.width(), self.width);
+        for (line, src) in self
+            .buffer
+            .iter_mut()
+            .zip(frame.pixels().chunks(self.width))
+        {
+            line.copy_from_slice(src);
+        }
+    }
+
     fn render(&mut self) {
-        let mut hasher = Sha256::new();
-        hasher.update(
-            self.buffer
-                .iter()
-                .flat_map(|v| v.clone().into_iter())
-                .flat_map(|i| i.to_le_bytes().into_iter())
-                .collect::<Vec<u8>>(),
-        );
-        let hash = hasher.finalize();
+        let hash = hash_frame(&self.buffer.concat());
 
         let oldstate = self.state.get();
-        let stable_frames = if oldstate.hash == hash[..] {
+        let stable_frames = if oldstate.hash == hash {
             cmp::min(oldstate.stable_frames + 1, u16::MAX - 1)
         } else {
             1
         };
 
         self.state.set(TestDisplayState {
-            hash: hash.into(),
+            hash,
             stable_frames,
         });
     }
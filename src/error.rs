@@ -0,0 +1,11 @@
+//! Error type of the emulation core (CPU, PPU).
+//!
+//! The core only uses the names exported here, rather than depending on
+//! anyhow directly, so the implementation can be swapped out for targets
+//! where anyhow is not an option. Code that deals with the host OS
+//! (binaries, frontends, file loading) keeps using anyhow; the types are
+//! interchangeable.
+
+pub use anyhow::{bail, ensure, Error};
+
+pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,403 @@
+//! C-compatible interface for embedding the emulator in other
+//! frontends. Enabled by the 'capi' feature, the gbrust_capi crate
+//! builds it as a dynamic library.
+//!
+//! All functions are panic-safe; failures are reported through the
+//! GB_ERR_* return codes.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use num_traits::FromPrimitive;
+
+use crate::display::display::NullDisplay;
+use crate::gameboy::cartridge::cartridge::{self, CartridgeType, CARTTYPE_OFFSET};
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::model::Model;
+use crate::gameboy::serial::Serial;
+use crate::input::input::{Button, ButtonState, SharedInput};
+
+use strum::IntoEnumIterator;
+
+pub const GB_OK: i32 = 0;
+/// NULL pointer passed
+pub const GB_ERR_NULL: i32 = -1;
+/// Invalid argument passed
+pub const GB_ERR_INVALID: i32 = -2;
+/// Emulation error
+pub const GB_ERR_EMULATION: i32 = -3;
+/// Emulator panicked, the instance should be destroyed
+pub const GB_ERR_PANIC: i32 = -4;
+/// Provided buffer is too small, required size is written to len
+pub const GB_ERR_BUFFER: i32 = -5;
+
+/// Force DMG mode (default: auto-detect from cartridge)
+pub const GB_FLAG_DMG: u32 = 1 << 0;
+/// Force CGB mode (default: auto-detect from cartridge)
+pub const GB_FLAG_CGB: u32 = 1 << 1;
+
+/// Opaque emulator instance handed out to C
+pub struct GbEmulator {
+    emu: Emulator,
+    buttons: ButtonState,
+}
+
+/// Runs a closure on an emulator instance, catching panics.
+fn with_emu(emu: *mut GbEmulator, f: impl FnOnce(&mut GbEmulator) -> i32) -> i32 {
+    if emu.is_null() {
+        return GB_ERR_NULL;
+    }
+    // SAFETY: caller passes a pointer obtained from gb_create()
+    let emu = unsafe { &mut *emu };
+    panic::catch_unwind(AssertUnwindSafe(|| f(emu))).unwrap_or(GB_ERR_PANIC)
+}
+
+/// Copies data to a caller-provided buffer.
+/// If the buffer is NULL or too small, only the required length is
+/// written to len.
+fn copy_out(data: &[u8], buf: *mut u8, len: *mut usize) -> i32 {
+    if len.is_null() {
+        return GB_ERR_NULL;
+    }
+    // SAFETY: len checked for NULL above, caller guarantees validity
+    let buflen = unsafe { *len };
+    unsafe { *len = data.len() };
+    if buf.is_null() || buflen < data.len() {
+        return GB_ERR_BUFFER;
+    }
+    // SAFETY: caller guarantees buf points to at least *len bytes
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len()) };
+    GB_OK
+}
+
+/// Creates an emulator instance from a ROM image.
+/// The ROM is copied; the buffer may be released after this call.
+/// Returns NULL on failure.
+/// # Safety
+/// rom must point to rom_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gb_create(rom: *const u8, rom_len: usize, flags: u32) -> *mut GbEmulator {
+    if rom.is_null() || rom_len < 32 * 1024 {
+        return ptr::null_mut();
+    }
+    // SAFETY: caller guarantees rom points to rom_len bytes
+    let rom = unsafe { slice::from_raw_parts(rom, rom_len) };
+    if CartridgeType::from_u8(rom[CARTTYPE_OFFSET]).is_none() {
+        return ptr::null_mut();
+    }
+
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let cart = match cartridge::load(rom) {
+            Ok(cart) => cart,
+            Err(_) => return ptr::null_mut(),
+        };
+        let cgb = if flags & GB_FLAG_DMG != 0 {
+            false
+        } else if flags & GB_FLAG_CGB != 0 {
+            true
+        } else {
+            cart.is_cgb()
+        };
+        let (input, buttons) = SharedInput::new();
+        let emu = Emulator::new(
+            cart,
+            None,
+            Box::new(NullDisplay::new()),
+            input,
+            Model::from_cgb(cgb),
+            Serial::new_null(),
+        );
+        Box::into_raw(Box::new(GbEmulator { emu, buttons }))
+    }))
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Destroys an emulator instance created by gb_create().
+/// # Safety
+/// emu must be NULL or a pointer obtained from gb_create() that has
+/// not been destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn gb_destroy(emu: *mut GbEmulator) {
+    if emu.is_null() {
+        return;
+    }
+    // SAFETY: caller passes a pointer obtained from gb_create()
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(emu) })));
+}
+
+/// Runs the emulator until the next frame is completed.
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_run_frame(emu: *mut GbEmulator) -> i32 {
+    with_emu(emu, |e| match e.emu.run_frame() {
+        Ok(_) => GB_OK,
+        Err(_) => GB_ERR_EMULATION,
+    })
+}
+
+/// Returns a pointer to the framebuffer (160x144, row-major, RGB555)
+/// and writes the amount of pixels to len (if not NULL).
+/// The pointer is valid until the next call on this instance.
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_framebuffer(emu: *mut GbEmulator, len: *mut usize) -> *const u16 {
+    let mut fb = ptr::null();
+    with_emu(emu, |e| {
+        let buf = e.emu.get_framebuffer();
+        if !len.is_null() {
+            // SAFETY: checked for NULL, caller guarantees validity
+            unsafe { *len = buf.len() };
+        }
+        fb = buf.as_ptr();
+        GB_OK
+    });
+    fb
+}
+
+/// Sets the state of a button.
+/// Button IDs: 0 = up, 1 = down, 2 = left, 3 = right, 4 = A, 5 = B,
+/// 6 = start, 7 = select.
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_set_button(emu: *mut GbEmulator, button: u32, pressed: bool) -> i32 {
+    with_emu(emu, |e| match Button::iter().nth(button as usize) {
+        Some(b) => {
+            e.buttons.set(b, pressed);
+            GB_OK
+        }
+        None => GB_ERR_INVALID,
+    })
+}
+
+/// Copies the contents of cartridge RAM into buf.
+/// On input, len contains the size of buf. On output, len contains the
+/// size of the save. Pass NULL as buf to query the size.
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_save(emu: *mut GbEmulator, buf: *mut u8, len: *mut usize) -> i32 {
+    with_emu(emu, |e| copy_out(&e.emu.get_save(), buf, len))
+}
+
+/// Loads cartridge RAM contents from buf.
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_load_save(emu: *mut GbEmulator, buf: *const u8, len: usize) -> i32 {
+    if buf.is_null() {
+        return GB_ERR_NULL;
+    }
+    with_emu(emu, |e| {
+        // SAFETY: caller guarantees buf points to len bytes
+        let save = unsafe { slice::from_raw_parts(buf, len) };
+        match e.emu.load_save(save) {
+            Ok(_) => GB_OK,
+            Err(_) => GB_ERR_INVALID,
+        }
+    })
+}
+
+/// Creates a savestate into buf.
+/// On input, len contains the size of buf. On output, len contains the
+/// size of the savestate. Pass NULL as buf to query the size.
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_save_state(emu: *mut GbEmulator, buf: *mut u8, len: *mut usize) -> i32 {
+    with_emu(emu, |e| copy_out(&e.emu.save_state(), buf, len))
+}
+
+/// Restores a savestate created by gb_save_state().
+/// # Safety
+/// emu must be NULL or a live pointer obtained from gb_create(). Buffer
+/// pointers must be NULL or valid for the specified length.
+#[no_mangle]
+pub unsafe extern "C" fn gb_load_state(emu: *mut GbEmulator, buf: *const u8, len: usize) -> i32 {
+    if buf.is_null() {
+        return GB_ERR_NULL;
+    }
+    with_emu(emu, |e| {
+        // SAFETY: caller guarantees buf points to len bytes
+        let state = unsafe { slice::from_raw_parts(buf, len) };
+        match e.emu.load_state(state) {
+            Ok(_) => GB_OK,
+            Err(_) => GB_ERR_INVALID,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::bus::bus::BusMember;
+    use crate::test::without_panic_output;
+
+    // Function pointers with the C ABI signatures, so the tests
+    // exercise the functions the way a C caller would.
+    type CreateFn = unsafe extern "C" fn(*const u8, usize, u32) -> *mut GbEmulator;
+    type DestroyFn = unsafe extern "C" fn(*mut GbEmulator);
+    type RunFn = unsafe extern "C" fn(*mut GbEmulator) -> i32;
+    type FbFn = unsafe extern "C" fn(*mut GbEmulator, *mut usize) -> *const u16;
+    type ButtonFn = unsafe extern "C" fn(*mut GbEmulator, u32, bool) -> i32;
+    type GetFn = unsafe extern "C" fn(*mut GbEmulator, *mut u8, *mut usize) -> i32;
+    type LoadFn = unsafe extern "C" fn(*mut GbEmulator, *const u8, usize) -> i32;
+
+    const CREATE: CreateFn = gb_create;
+    const DESTROY: DestroyFn = gb_destroy;
+    const RUN_FRAME: RunFn = gb_run_frame;
+    const GET_FB: FbFn = gb_get_framebuffer;
+    const SET_BUTTON: ButtonFn = gb_set_button;
+    const GET_SAVE: GetFn = gb_get_save;
+    const LOAD_SAVE: LoadFn = gb_load_save;
+    const SAVE_STATE: GetFn = gb_save_state;
+    const LOAD_STATE: LoadFn = gb_load_state;
+
+    /// MBC1+RAM+BATTERY ROM that copies the joypad register to
+    /// WRAM (0xC000) and cartridge RAM (0xA000) in a loop.
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 64 * 1024];
+        rom[0x100..0x111].copy_from_slice(&[
+            0x3E, 0x0A, // LD A,0Ah
+            0xEA, 0x00, 0x00, // LD (0000h),A - enable RAM
+            0xF0, 0x00, // LDH A,(00h)
+            0xEA, 0x00, 0xC0, // LD (C000h),A
+            0xEA, 0x00, 0xA0, // LD (A000h),A
+            0xC3, 0x05, 0x01, // JP 0105h
+            0x00,
+        ]);
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1RamBat as u8;
+        rom[0x148] = 1;
+        rom[0x149] = 2;
+        rom
+    }
+
+    fn get_vec(f: GetFn, emu: *mut GbEmulator) -> Vec<u8> {
+        unsafe {
+            let mut len = 0;
+            assert_eq!(f(emu, ptr::null_mut(), &mut len), GB_ERR_BUFFER);
+            let mut buf = vec![0; len];
+            assert_eq!(f(emu, buf.as_mut_ptr(), &mut len), GB_OK);
+            assert_eq!(len, buf.len());
+            buf
+        }
+    }
+
+    #[test]
+    fn end_to_end() {
+        unsafe {
+            let rom = rom();
+            let emu = CREATE(rom.as_ptr(), rom.len(), GB_FLAG_DMG);
+            assert!(!emu.is_null());
+            // ROM is copied
+            drop(rom);
+
+            for _ in 0..10 {
+                assert_eq!(RUN_FRAME(emu), GB_OK);
+            }
+
+            let mut len = 0;
+            let fb = GET_FB(emu, &mut len);
+            assert!(!fb.is_null());
+            assert_eq!(len, 160 * 144);
+            let fb = slice::from_raw_parts(fb, len);
+            assert_eq!(fb.len(), 160 * 144);
+
+            // Select buttons and press start
+            assert_eq!(SET_BUTTON(emu, 6, true), GB_OK);
+            assert_eq!(SET_BUTTON(emu, 8, true), GB_ERR_INVALID);
+            (*emu).emu.bus_mut().write(0xFF00, 0x10);
+            assert_eq!(RUN_FRAME(emu), GB_OK);
+
+            // Start (bit 3) pressed is reflected in the save RAM
+            let save = get_vec(GET_SAVE, emu);
+            assert_eq!(save[0] & 0x0F, 0x07);
+
+            // Savestate roundtrip
+            let state = get_vec(SAVE_STATE, emu);
+            assert_eq!(SET_BUTTON(emu, 6, false), GB_OK);
+            assert_eq!(RUN_FRAME(emu), GB_OK);
+            assert_eq!(get_vec(GET_SAVE, emu)[0] & 0x0F, 0x0F);
+            assert_eq!(LOAD_STATE(emu, state.as_ptr(), state.len()), GB_OK);
+            assert_eq!(get_vec(GET_SAVE, emu)[0] & 0x0F, 0x07);
+            assert_eq!(LOAD_STATE(emu, state.as_ptr(), 10), GB_ERR_INVALID);
+
+            // Load save
+            let newsave = vec![0x55; 1024];
+            assert_eq!(LOAD_SAVE(emu, newsave.as_ptr(), newsave.len()), GB_OK);
+            assert_eq!(get_vec(GET_SAVE, emu)[0..1024], newsave);
+            let toolarge = vec![0; 1024 * 1024];
+            assert_eq!(
+                LOAD_SAVE(emu, toolarge.as_ptr(), toolarge.len()),
+                GB_ERR_INVALID
+            );
+
+            // Buffer too small
+            let mut small = [0u8; 4];
+            let mut len = small.len();
+            assert_eq!(GET_SAVE(emu, small.as_mut_ptr(), &mut len), GB_ERR_BUFFER);
+            assert_eq!(len, 32 * 1024);
+            assert_eq!(small, [0; 4]);
+
+            DESTROY(emu);
+        }
+    }
+
+    #[test]
+    fn invalid_args() {
+        unsafe {
+            assert!(CREATE(ptr::null(), 0, 0).is_null());
+            let rom = [0u8; 1024];
+            assert!(CREATE(rom.as_ptr(), rom.len(), 0).is_null());
+            let mut rom = vec![0u8; 32 * 1024];
+            rom[CARTTYPE_OFFSET] = 0xEE;
+            assert!(CREATE(rom.as_ptr(), rom.len(), 0).is_null());
+
+            let null = ptr::null_mut();
+            let mut len = 0;
+            assert_eq!(RUN_FRAME(null), GB_ERR_NULL);
+            assert!(GET_FB(null, &mut len).is_null());
+            assert_eq!(SET_BUTTON(null, 0, true), GB_ERR_NULL);
+            assert_eq!(GET_SAVE(null, ptr::null_mut(), &mut len), GB_ERR_NULL);
+            assert_eq!(LOAD_SAVE(null, ptr::null(), 0), GB_ERR_NULL);
+            assert_eq!(SAVE_STATE(null, ptr::null_mut(), &mut len), GB_ERR_NULL);
+            assert_eq!(LOAD_STATE(null, ptr::null(), 0), GB_ERR_NULL);
+            DESTROY(null);
+        }
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        unsafe {
+            let mut rom = vec![0u8; 32 * 1024];
+            rom[0x100] = 0xD3;
+            let emu = CREATE(rom.as_ptr(), rom.len(), 0);
+            assert!(!emu.is_null());
+            assert_eq!(RUN_FRAME(emu), GB_ERR_EMULATION);
+            DESTROY(emu);
+        }
+    }
+
+    #[test]
+    fn panic_safe() {
+        unsafe {
+            let rom = vec![0u8; 32 * 1024];
+            let emu = CREATE(rom.as_ptr(), rom.len(), 0);
+            assert!(!emu.is_null());
+
+            let result = without_panic_output(|| with_emu(emu, |_| panic!("Test panic")));
+
+            assert_eq!(result, GB_ERR_PANIC);
+            DESTROY(emu);
+        }
+    }
+}
@@ -1,17 +1,113 @@
+use std::fmt;
+
+use crate::audio::audio::Sample;
 use crate::gameboy::bus::bus::BusMember;
-use crate::tickable::{Tickable, Ticks};
 
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use anyhow::Result;
 
 /// Amount of APU channels
-const APU_CHANNELS: usize = 4;
+pub const APU_CHANNELS: usize = 4;
+
+/// Channel registers (NRx0 - NRx4) start at 0xFF10, 5 per channel
+const CH_REGS_START: u16 = 0xFF10;
+const CH_REGS_END: u16 = 0xFF23;
+const CH_REGS: usize = 5;
+
+/// Maximum length counter values per channel
+const LEN_MAX: [u16; APU_CHANNELS] = [64, 64, 256, 64];
+
+/// NRx4: Trigger
+const NRX4_TRIGGER: u8 = 1 << 7;
+/// NRx4: Length enable
+const NRX4_LEN_ENABLE: u8 = 1 << 6;
 
 /// Gameboy Audio Processing Unit
 pub struct APU {
     apu_enable: bool,
     dac_enable: u8,
-    len_timers: [u8; APU_CHANNELS],
+    /// Channels that are currently playing (bitmask)
+    ch_enable: u8,
+    /// Channels with the length counter enabled (bitmask)
+    len_enable: u8,
+    len_timers: [u16; APU_CHANNELS],
     nr50: u8,
+    /// Last written NRx0 - NRx4 values per channel
+    regs: [[u8; CH_REGS]; APU_CHANNELS],
+
+    /// Frame sequencer step (0 - 7)
+    frame_seq: u8,
+
+    /// Channels muted in the mixer output (bitmask, see
+    /// set_channel_mask())
+    channel_mask: u8,
+}
+
+/// Snapshot of an APU channel, for debugging (see APU::apu_state())
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelState {
+    /// Playing (as reported by NR52)
+    pub playing: bool,
+    /// Muted in the mixer output
+    pub muted: bool,
+    /// Frequency in Hz (for channel 4, of the LFSR clock)
+    pub frequency: f64,
+    /// Volume (0 - 15), as set by NRx2. For channel 3, derived from the
+    /// output level in NR32. The envelope is not emulated yet.
+    pub volume: u8,
+    /// Duty cycle (0 - 3: 12.5%, 25%, 50%, 75%), channel 1 and 2 only
+    pub duty: Option<u8>,
+    /// Length timer remaining
+    pub length: u16,
+    /// Length timer enabled
+    pub length_enabled: bool,
+    /// Width of the LFSR in bits (7 or 15), channel 4 only. The LFSR
+    /// contents are not emulated yet.
+    pub lfsr_width: Option<u8>,
+}
+
+/// Snapshot of the APU, for debugging (see APU::apu_state())
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApuState {
+    /// Powered on (NR52)
+    pub enabled: bool,
+    /// Master volume & VIN panning
+    pub nr50: u8,
+    pub channels: [ChannelState; APU_CHANNELS],
+}
+
+impl fmt::Display for ApuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "APU: {} NR50: {:02X}",
+            if self.enabled { "on" } else { "off" },
+            self.nr50
+        )?;
+        for (i, ch) in self.channels.iter().enumerate() {
+            write!(
+                f,
+                "CH{}: {:7} {:9.1} Hz vol {:2} len {:3}{}",
+                i + 1,
+                if ch.playing { "playing" } else { "off" },
+                ch.frequency,
+                ch.volume,
+                ch.length,
+                if ch.length_enabled { " (on)" } else { "" }
+            )?;
+            if let Some(duty) = ch.duty {
+                write!(f, " duty {}", duty)?;
+            }
+            if let Some(width) = ch.lfsr_width {
+                write!(f, " lfsr {}-bit", width)?;
+            }
+            if ch.muted {
+                write!(f, " [muted]")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
 }
 
 impl APU {
@@ -19,8 +115,140 @@ impl APU {
         Self {
             apu_enable: false,
             dac_enable: 0,
+            ch_enable: 0,
+            len_enable: 0,
             len_timers: [0; APU_CHANNELS],
             nr50: 0,
+            regs: [[0; CH_REGS]; APU_CHANNELS],
+            frame_seq: 0,
+            channel_mask: 0,
+        }
+    }
+
+    /// Mutes channels in the mixer output (bits 0 - 3 for channels 1 -
+    /// 4). This does not affect the emulated state: NR52 still reports
+    /// muted channels as playing.
+    pub fn set_channel_mask(&mut self, mask: u8) {
+        self.channel_mask = mask & ((1 << APU_CHANNELS) - 1);
+    }
+
+    /// Channels muted in the mixer output (see set_channel_mask())
+    pub fn get_channel_mask(&self) -> u8 {
+        self.channel_mask
+    }
+
+    /// Mixes the outputs of the channels into a sample. Channels that
+    /// are not playing or are muted do not contribute.
+    pub fn mix(&self, outputs: [Sample; APU_CHANNELS]) -> Sample {
+        let playing = self.ch_enable & self.dac_enable & !self.channel_mask;
+        outputs
+            .iter()
+            .enumerate()
+            .filter(|&(ch, _)| playing & (1 << ch) != 0)
+            .fold(0, |acc: Sample, (_, &s)| acc.saturating_add(s))
+    }
+
+    /// Frequency of a channel in Hz
+    fn frequency(&self, ch: usize) -> f64 {
+        let regs = &self.regs[ch];
+        let period = 2048 - ((u16::from(regs[4] & 0x07) << 8) | u16::from(regs[3]));
+        match ch {
+            0 | 1 => 131072.0 / f64::from(period),
+            2 => 65536.0 / f64::from(period),
+            _ => {
+                let divider = match regs[3] & 0x07 {
+                    0 => 0.5,
+                    r => f64::from(r),
+                };
+                524288.0 / divider / f64::from(2u32 << (regs[3] >> 4))
+            }
+        }
+    }
+
+    /// Returns a snapshot of the APU and its channels
+    pub fn apu_state(&self) -> ApuState {
+        let channels = std::array::from_fn(|ch| {
+            let regs = &self.regs[ch];
+            ChannelState {
+                playing: self.ch_enable & self.dac_enable & (1 << ch) != 0,
+                muted: self.channel_mask & (1 << ch) != 0,
+                frequency: self.frequency(ch),
+                volume: match ch {
+                    // NR32: mute, 100%, 50%, 25%
+                    2 => [0, 15, 7, 3][((regs[2] >> 5) & 3) as usize],
+                    _ => regs[2] >> 4,
+                },
+                duty: (ch < 2).then_some(regs[1] >> 6),
+                length: self.len_timers[ch],
+                length_enabled: self.len_enable & (1 << ch) != 0,
+                lfsr_width: (ch == 3).then_some(if regs[3] & 0x08 != 0 { 7 } else { 15 }),
+            }
+        });
+        ApuState {
+            enabled: self.apu_enable,
+            nr50: self.nr50,
+            channels,
+        }
+    }
+
+    /// Advances the frame sequencer by one step. This is clocked by
+    /// the falling edge of a bit of the DIV counter (see Timer), at 512Hz.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.apu_enable {
+            return;
+        }
+
+        // Length counters are clocked on even steps. Envelope (step 7)
+        // and sweep (steps 2 and 6) do not affect any state yet.
+        if self.frame_seq.is_multiple_of(2) {
+            for ch in 0..APU_CHANNELS {
+                if self.len_enable & (1 << ch) == 0 || self.len_timers[ch] == 0 {
+                    continue;
+                }
+                self.len_timers[ch] -= 1;
+                if self.len_timers[ch] == 0 {
+                    self.ch_enable &= !(1 << ch);
+                }
+            }
+        }
+
+        self.frame_seq = (self.frame_seq + 1) % 8;
+    }
+
+    /// Current frame sequencer step
+    pub fn get_frame_sequencer(&self) -> u8 {
+        self.frame_seq
+    }
+
+    fn set_dac(&mut self, ch: usize, enable: bool) {
+        if enable {
+            self.dac_enable |= 1 << ch;
+        } else {
+            self.dac_enable &= !(1 << ch);
+            self.ch_enable &= !(1 << ch);
+        }
+    }
+
+    /// NRx1 - Length timer
+    fn write_length(&mut self, ch: usize, len: u16) {
+        self.len_timers[ch] = LEN_MAX[ch] - len;
+    }
+
+    /// NRx4 - Trigger/length enable
+    fn write_control(&mut self, ch: usize, val: u8) {
+        if val & NRX4_LEN_ENABLE != 0 {
+            self.len_enable |= 1 << ch;
+        } else {
+            self.len_enable &= !(1 << ch);
+        }
+
+        if val & NRX4_TRIGGER != 0 {
+            if self.len_timers[ch] == 0 {
+                self.len_timers[ch] = LEN_MAX[ch];
+            }
+            if self.dac_enable & (1 << ch) != 0 {
+                self.ch_enable |= 1 << ch;
+            }
         }
     }
 }
@@ -45,15 +273,9 @@ impl BusMember for APU {
             // NR52: Sound on/off
             0xFF26 => {
                 if !self.apu_enable {
-                    0
+                    0x70
                 } else {
-                    self.len_timers
-                        .iter()
-                        .enumerate()
-                        .filter(|(_, &i)| i > 0)
-                        .fold(0, |a, (ch, _)| a | (1 << ch))
-                        & self.dac_enable
-                        | 0x87_u8
+                    self.ch_enable & self.dac_enable | 0xF0_u8
                 }
             }
 
@@ -67,37 +289,62 @@ impl BusMember for APU {
     fn write(&mut self, addr: u16, val: u8) {
         let addr = addr as usize;
 
+        // Registers are read-only while the APU is off
+        if !self.apu_enable && addr != 0xFF26 && !(0xFF30..=0xFF3F).contains(&addr) {
+            return;
+        }
+
+        if (CH_REGS_START..=CH_REGS_END).contains(&(addr as u16)) {
+            let offset = addr - CH_REGS_START as usize;
+            self.regs[offset / CH_REGS][offset % CH_REGS] = val;
+        }
+
         match addr {
+            // NR11, NR21, NR41: Length timer & duty cycle
+            0xFF11 => self.write_length(0, (val & 0x3F).into()),
+            0xFF16 => self.write_length(1, (val & 0x3F).into()),
+            0xFF20 => self.write_length(3, (val & 0x3F).into()),
+
             // FF12 — NR12: Channel 1 volume & envelope
-            0xFF12 => {
-                if val & 0x07 == 0 {
-                    self.dac_enable &= !(1 << 0);
-                } else {
-                    self.dac_enable |= 1 << 0;
-                }
-            }
+            0xFF12 => self.set_dac(0, val & 0x07 != 0),
 
             // FF17 — NR22: Channel 2 volume & envelope
-            0xFF17 => {
-                if val & 0x07 == 0 {
-                    self.dac_enable &= !(1 << 1);
-                } else {
-                    self.dac_enable |= 1 << 1;
-                }
-            }
+            0xFF17 => self.set_dac(1, val & 0x07 != 0),
 
             // NR30: Channel 3 DAC enable
-            0xFF1A => {
-                if (val & 0x80) == 0x80 {
-                    self.dac_enable |= 1 << 2;
-                } else {
-                    self.dac_enable &= !(1 << 2);
-                }
-            }
+            0xFF1A => self.set_dac(2, val & 0x80 != 0),
+
+            // NR31: Channel 3 length timer
+            0xFF1B => self.write_length(2, val.into()),
+
+            // FF21 — NR42: Channel 4 volume & envelope
+            0xFF21 => self.set_dac(3, val & 0x07 != 0),
+
+            // NR14, NR24, NR34, NR44: Trigger/length enable
+            0xFF14 => self.write_control(0, val),
+            0xFF19 => self.write_control(1, val),
+            0xFF1E => self.write_control(2, val),
+            0xFF23 => self.write_control(3, val),
 
             // NR50: Master volume & VIN panning
             0xFF24 => self.nr50 = val,
 
+            // NR52: Sound on/off
+            0xFF26 => {
+                let enable = val & 0x80 != 0;
+                if enable && !self.apu_enable {
+                    // Frame sequencer restarts at power on
+                    self.frame_seq = 0;
+                } else if !enable {
+                    self.ch_enable = 0;
+                    self.len_enable = 0;
+                    self.dac_enable = 0;
+                    self.nr50 = 0;
+                    self.regs = [[0; CH_REGS]; APU_CHANNELS];
+                }
+                self.apu_enable = enable;
+            }
+
             // Wave form RAM
             0xFF30..=0xFF3F => (),
 
@@ -106,25 +353,178 @@ impl BusMember for APU {
     }
 }
 
-impl Tickable for APU {
-    fn tick(&mut self, ticks: Ticks) -> Result<()> {
-        // APU is not affected by double speed
-        let ticks = ticks.get_t_no_ds();
-
-        let t = if ticks > u8::MAX.into() {
-            u8::MAX
-        } else {
-            ticks as u8
-        };
+impl Savestate for APU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_bool(self.apu_enable);
+        w.put_u8(self.dac_enable);
+        w.put_u8(self.ch_enable);
+        w.put_u8(self.len_enable);
+        for l in self.len_timers {
+            w.put_u16(l);
+        }
+        w.put_u8(self.nr50);
+        for regs in &self.regs {
+            w.put_slice(regs);
+        }
+        w.put_u8(self.frame_seq);
+    }
 
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.apu_enable = r.get_bool()?;
+        self.dac_enable = r.get_u8()?;
+        self.ch_enable = r.get_u8()?;
+        self.len_enable = r.get_u8()?;
         for l in self.len_timers.iter_mut() {
-            if *l > t {
-                *l -= t;
-            } else {
-                *l = 0;
-            }
+            *l = r.get_u16()?;
         }
-
+        self.nr50 = r.get_u8()?;
+        for regs in self.regs.iter_mut() {
+            r.get_slice(regs)?;
+        }
+        self.frame_seq = r.get_u8()? % 8;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apu() -> APU {
+        let mut apu = APU::new();
+        apu.write(0xFF26, 0x80);
+        apu
+    }
+
+    /// Starts channel 1 with the length counter enabled
+    fn trigger_ch1(apu: &mut APU, len: u8) {
+        apu.write(0xFF12, 0xF1);
+        apu.write(0xFF11, 64 - len);
+        apu.write(0xFF14, NRX4_TRIGGER | NRX4_LEN_ENABLE);
+    }
+
+    #[test]
+    fn length_even_steps() {
+        let mut a = apu();
+        trigger_ch1(&mut a, 2);
+        assert_eq!(a.read(0xFF26) & 0x01, 0x01);
+
+        // Step 0 clocks length
+        a.clock_frame_sequencer();
+        assert_eq!(a.len_timers[0], 1);
+        // Step 1 does not
+        a.clock_frame_sequencer();
+        assert_eq!(a.len_timers[0], 1);
+        assert_eq!(a.read(0xFF26) & 0x01, 0x01);
+        // Step 2 does
+        a.clock_frame_sequencer();
+        assert_eq!(a.len_timers[0], 0);
+        assert_eq!(a.read(0xFF26) & 0x01, 0x00);
+        assert_eq!(a.get_frame_sequencer(), 3);
+    }
+
+    #[test]
+    fn length_disabled() {
+        let mut a = apu();
+        trigger_ch1(&mut a, 1);
+        a.write(0xFF14, 0);
+        for _ in 0..16 {
+            a.clock_frame_sequencer();
+        }
+        assert_eq!(a.len_timers[0], 1);
+        assert_eq!(a.read(0xFF26) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn trigger_reloads_length() {
+        let mut a = apu();
+        a.write(0xFF1A, 0x80);
+        a.write(0xFF1E, NRX4_TRIGGER);
+        assert_eq!(a.len_timers[2], 256);
+        assert_eq!(a.read(0xFF26) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn mute_keeps_nr52() {
+        let mut a = apu();
+        trigger_ch1(&mut a, 10);
+        a.set_channel_mask(0x01);
+        assert_eq!(a.read(0xFF26) & 0x01, 0x01);
+        assert!(a.apu_state().channels[0].playing);
+        assert!(a.apu_state().channels[0].muted);
+
+        // The length counter still runs out
+        for _ in 0..20 {
+            a.clock_frame_sequencer();
+        }
+        assert_eq!(a.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn mix_muted() {
+        let mut a = apu();
+        trigger_ch1(&mut a, 10);
+        a.write(0xFF17, 0xF0 | 0x01);
+        a.write(0xFF19, NRX4_TRIGGER);
+        assert_eq!(a.mix([100, 20, 3, 3]), 120);
+
+        a.set_channel_mask(0x01);
+        assert_eq!(a.mix([100, 20, 3, 3]), 20);
+        a.set_channel_mask(0x0F);
+        assert_eq!(a.mix([100, 20, 3, 3]), 0);
+        // Upper bits are ignored
+        a.set_channel_mask(0xF2);
+        assert_eq!(a.get_channel_mask(), 0x02);
+        assert_eq!(a.mix([100, 20, 3, 3]), 100);
+    }
+
+    #[test]
+    fn state() {
+        let mut a = apu();
+        // Channel 1: 50% duty, volume 15, period 0x783 (1048.576 Hz)
+        trigger_ch1(&mut a, 10);
+        a.write(0xFF11, 0x80 | (64 - 10));
+        a.write(0xFF13, 0x83);
+        a.write(0xFF14, NRX4_TRIGGER | NRX4_LEN_ENABLE | 0x07);
+        // Channel 3: 50% output level
+        a.write(0xFF1C, 0x40);
+        // Channel 4: 7-bit LFSR, shift 1, divider 0
+        a.write(0xFF22, 0x18);
+
+        let s = a.apu_state();
+        assert!(s.enabled);
+        let ch1 = s.channels[0];
+        assert!(ch1.playing);
+        assert_eq!(ch1.frequency, 131072.0 / 125.0);
+        assert_eq!(ch1.volume, 15);
+        assert_eq!(ch1.duty, Some(2));
+        assert_eq!(ch1.length, 10);
+        assert!(ch1.length_enabled);
+        assert_eq!(ch1.lfsr_width, None);
+
+        assert!(!s.channels[2].playing);
+        assert_eq!(s.channels[2].volume, 7);
+        assert_eq!(s.channels[2].duty, None);
+        assert_eq!(s.channels[3].frequency, 524288.0 / 0.5 / 4.0);
+        assert_eq!(s.channels[3].lfsr_width, Some(7));
+
+        let out = s.to_string();
+        assert!(out.starts_with("APU: on"));
+        assert!(out.contains("CH1: playing    1048.6 Hz vol 15 len  10 (on) duty 2\n"));
+    }
+
+    #[test]
+    fn power_off() {
+        let mut a = apu();
+        trigger_ch1(&mut a, 1);
+        a.clock_frame_sequencer();
+        a.write(0xFF26, 0x00);
+        assert_eq!(a.read(0xFF26), 0x70);
+
+        // Sequencer does not run while off, restarts at step 0
+        a.clock_frame_sequencer();
+        assert_eq!(a.get_frame_sequencer(), 1);
+        a.write(0xFF26, 0x80);
+        assert_eq!(a.get_frame_sequencer(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Built-in DMG boot ROM
+//!
+//! A small open replacement for the DMG boot ROM, for users who do not
+//! have a dump of the original. Like the original, it scrolls the logo
+//! from the cartridge header into view, plays the ding and hands over
+//! to the cartridge at 0x0100 with the registers set up as the original
+//! leaves them. Unlike the original, it does not check the logo or the
+//! header checksum.
+
+use crate::error::{bail, Result};
+use crate::gameboy::model::Model;
+
+use std::fs;
+
+/// Boot ROM name that selects the built-in boot ROM
+pub const BUILTIN: &str = "builtin";
+
+/// Size of the built-in boot ROM
+pub const DMG_BOOTROM_SIZE: usize = 0x100;
+
+/// The built-in boot ROM, assembled by hand
+#[rustfmt::skip]
+pub const DMG_BOOTROM: [u8; DMG_BOOTROM_SIZE] = [
+    0x31, 0xFE, 0xFF,  // 0000: ld sp, $FFFE
+    0x18, 0x29,        // 0003: jr start
+    // Writes the upper nibble of A with every bit doubled to two tile rows,
+    // returns the lower nibble in the upper bits of A
+    0x4F,              // 0005: double: ld c, a
+    0x06, 0x04,        // 0006: ld b, 4
+    0xC5,              // 0008: dbl: push bc
+    0xCB, 0x11,        // 0009: rl c
+    0x17,              // 000B: rla
+    0xC1,              // 000C: pop bc
+    0xCB, 0x11,        // 000D: rl c
+    0x17,              // 000F: rla
+    0x05,              // 0010: dec b
+    0x20, 0xF5,        // 0011: jr nz, dbl
+    0x22,              // 0013: ld [hl+], a
+    0x23,              // 0014: inc hl
+    0x22,              // 0015: ld [hl+], a
+    0x23,              // 0016: inc hl
+    0x79,              // 0017: ld a, c
+    0xC9,              // 0018: ret
+    // Writes 12 consecutive tile numbers from A to [HL]
+    0x06, 0x0C,        // 0019: row: ld b, 12
+    0x22,              // 001B: rowl: ld [hl+], a
+    0x3C,              // 001C: inc a
+    0x05,              // 001D: dec b
+    0x20, 0xFB,        // 001E: jr nz, rowl
+    0xC9,              // 0020: ret
+    // Waits for the start of the next VBlank
+    0xF0, 0x44,        // 0021: frame: ldh a, [$44]
+    0xFE, 0x90,        // 0023: cp $90
+    0x28, 0xFA,        // 0025: jr z, frame
+    0xF0, 0x44,        // 0027: frame2: ldh a, [$44]
+    0xFE, 0x90,        // 0029: cp $90
+    0x20, 0xFA,        // 002B: jr nz, frame2
+    0xC9,              // 002D: ret
+    // Clear VRAM
+    0xAF,              // 002E: start: xor a
+    0x21, 0xFF, 0x9F,  // 002F: ld hl, $9FFF
+    0x32,              // 0032: clear: ld [hl-], a
+    0xCB, 0x7C,        // 0033: bit 7, h
+    0x20, 0xFB,        // 0035: jr nz, clear
+    // Sound on, channel 1 envelope, all channels to both outputs
+    0x21, 0x26, 0xFF,  // 0037: ld hl, $FF26
+    0x0E, 0x11,        // 003A: ld c, $11
+    0x3E, 0x80,        // 003C: ld a, $80
+    0x32,              // 003E: ld [hl-], a
+    0xE2,              // 003F: ldh [c], a
+    0x0C,              // 0040: inc c
+    0x3E, 0xF3,        // 0041: ld a, $F3
+    0xE2,              // 0043: ldh [c], a
+    0x32,              // 0044: ld [hl-], a
+    0x3E, 0x77,        // 0045: ld a, $77
+    0x77,              // 0047: ld [hl], a
+    // BGP
+    0x3E, 0xFC,        // 0048: ld a, $FC
+    0xE0, 0x47,        // 004A: ldh [$47], a
+    // Decompress the logo from the cartridge header into tiles 1-24
+    0x11, 0x04, 0x01,  // 004C: ld de, $0104
+    0x21, 0x10, 0x80,  // 004F: ld hl, $8010
+    0x1A,              // 0052: logo: ld a, [de]
+    0xCD, 0x05, 0x00,  // 0053: call double
+    0xCD, 0x05, 0x00,  // 0056: call double
+    0x13,              // 0059: inc de
+    0x7B,              // 005A: ld a, e
+    0xFE, 0x34,        // 005B: cp $34
+    0x20, 0xF3,        // 005D: jr nz, logo
+    // Tile map: two rows of 12 tiles
+    0x3E, 0x01,        // 005F: ld a, 1
+    0x21, 0x04, 0x99,  // 0061: ld hl, $9904
+    0xCD, 0x19, 0x00,  // 0064: call row
+    0x2E, 0x24,        // 0067: ld l, $24
+    0xCD, 0x19, 0x00,  // 0069: call row
+    // Scroll the logo down into view, one line per frame
+    0x3E, 0x64,        // 006C: ld a, $64
+    0xE0, 0x42,        // 006E: ldh [$42], a
+    0x3E, 0x91,        // 0070: ld a, $91
+    0xE0, 0x40,        // 0072: ldh [$40], a
+    0xCD, 0x21, 0x00,  // 0074: scroll: call frame
+    0xF0, 0x42,        // 0077: ldh a, [$42]
+    0x3D,              // 0079: dec a
+    0xE0, 0x42,        // 007A: ldh [$42], a
+    0x20, 0xF6,        // 007C: jr nz, scroll
+    // Ding, then show the logo for a second
+    0x3E, 0xC1,        // 007E: ld a, $C1
+    0xE0, 0x13,        // 0080: ldh [$13], a
+    0x3E, 0x87,        // 0082: ld a, $87
+    0xE0, 0x14,        // 0084: ldh [$14], a
+    0x06, 0x3C,        // 0086: ld b, 60
+    0xCD, 0x21, 0x00,  // 0088: hold: call frame
+    0x05,              // 008B: dec b
+    0x20, 0xFA,        // 008C: jr nz, hold
+    // Registers as the original boot ROM leaves them
+    0x01, 0xB0, 0x01,  // 008E: ld bc, $01B0
+    0xC5,              // 0091: push bc
+    0xF1,              // 0092: pop af
+    0x01, 0x13, 0x00,  // 0093: ld bc, $0013
+    0x11, 0xD8, 0x00,  // 0096: ld de, $00D8
+    0x21, 0x4D, 0x01,  // 0099: ld hl, $014D
+    // $009C-$00FB: NOPs up to the hand-off
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // Unmap the boot ROM, the next instruction is at $0100
+    0x3E, 0x01,        // 00FC: handoff: ld a, 1
+    0xE0, 0x50,        // 00FE: ldh [$50], a
+];
+
+/// Loads a boot ROM from a file, or the built-in boot ROM if the name
+/// is BUILTIN. The built-in boot ROM is DMG only.
+pub fn load(name: &str, model: Model) -> Result<Vec<u8>> {
+    if name != BUILTIN {
+        return Ok(fs::read(name)?);
+    }
+    if model.is_cgb() {
+        bail!("The built-in boot ROM only supports DMG mode");
+    }
+    Ok(DMG_BOOTROM.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::display::display::{Color, NullDisplay};
+    use crate::display::palette::DMG_GREY;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::cpu::regs::Register;
+    use crate::gameboy::emulator::Emulator;
+    use crate::gameboy::lcd::{LCD_H, LCD_W};
+    use crate::gameboy::serial::Serial;
+    use crate::input::input::NullInput;
+
+    /// Test ROM with a made-up logo, looping at 0x0100
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        for (i, b) in rom[0x104..0x134].iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(37) ^ 0x5A;
+        }
+        rom
+    }
+
+    /// The frame with the logo in its final position
+    fn logo_frame(rom: &[u8]) -> Vec<Color> {
+        let mut frame = vec![DMG_GREY[0]; LCD_W * LCD_H];
+        // Every header byte is two rows of 4 pixels, which are doubled
+        // in both directions. Two bytes form one tile of the 12x2 tiles
+        // logo, which is at tile 4,8.
+        for (i, &b) in rom[0x104..0x134].iter().enumerate() {
+            let (tile, half) = (i / 2, i % 2);
+            let tx = 4 + tile % 12;
+            let ty = 8 + tile / 12;
+            for (row, nibble) in [b >> 4, b & 0x0F].into_iter().enumerate() {
+                for px in 0..8 {
+                    if nibble & (0x08 >> (px / 2)) == 0 {
+                        continue;
+                    }
+                    for dy in 0..2 {
+                        let y = ty * 8 + half * 4 + row * 2 + dy;
+                        frame[y * LCD_W + tx * 8 + px] = DMG_GREY[3];
+                    }
+                }
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn builtin() {
+        let rom = rom();
+        let mut emu = Emulator::new(
+            cartridge::load(&rom).unwrap(),
+            Some(&DMG_BOOTROM),
+            Box::new(NullDisplay::new()),
+            Box::new(NullInput::new()),
+            Model::Dmg,
+            Serial::new_null(),
+        );
+
+        let logo = logo_frame(&rom);
+        let mut logo_seen = false;
+        for _ in 0..300 {
+            if emu.cpu().regs.pc == 0x0100 {
+                break;
+            }
+            emu.run_frame().unwrap();
+            logo_seen |= emu.get_framebuffer() == logo;
+        }
+        assert!(logo_seen);
+
+        // Handed over at 0x0100 in the post-boot state
+        let c = emu.cpu();
+        assert_eq!(c.regs.pc, 0x0100);
+        assert_eq!(c.bus.peek(0xFF50), 1);
+        for (reg, val) in [
+            (Register::AF, 0x01B0),
+            (Register::BC, 0x0013),
+            (Register::DE, 0x00D8),
+            (Register::HL, 0x014D),
+            (Register::SP, 0xFFFE),
+        ] {
+            assert_eq!(c.regs.read16(reg).unwrap(), val, "{:?}", reg);
+        }
+        assert_eq!(c.bus.peek(0xFF40), 0x91);
+        assert_eq!(c.bus.peek(0xFF47), 0xFC);
+    }
+
+    #[test]
+    fn load_builtin() {
+        assert_eq!(load(BUILTIN, Model::Dmg).unwrap(), DMG_BOOTROM);
+        assert!(load(BUILTIN, Model::Cgb).is_err());
+    }
+}
@@ -7,6 +7,18 @@ pub trait BusMember {
     fn read(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, val: u8);
 
+    /// Read for an instruction fetch by the CPU. Only differs from
+    /// read() for members that distinguish fetches (e.g. profiling).
+    fn fetch(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    /// Read without side effects and ignoring access restrictions,
+    /// for debuggers and other tools inspecting memory.
+    fn peek(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
     fn write_slice(&mut self, from: &[u8], offset: u16) {
         for (i, b) in from.into_iter().enumerate() {
             self.write(offset.wrapping_add(i as u16), *b);
@@ -46,17 +58,53 @@ pub trait BusMember {
         let h = self.read(addr.wrapping_add(1));
         l as u16 | (h as u16) << 8
     }
+
+    /// Peek 16-bits from addr and addr + 1,
+    /// from little endian.
+    fn peek16(&self, addr: u16) -> u16 {
+        let l = self.peek(addr);
+        let h = self.peek(addr.wrapping_add(1));
+        l as u16 | (h as u16) << 8
+    }
 }
 
-pub trait Bus: BusMember + fmt::Display + Tickable + Downcast {}
+pub trait Bus: BusMember + fmt::Display + Tickable + Downcast {
+    /// Wrapped bus, for buses that wrap another bus
+    fn inner(&self) -> Option<&dyn Bus> {
+        None
+    }
+
+    fn inner_mut(&mut self) -> Option<&mut dyn Bus> {
+        None
+    }
+}
 impl_downcast!(Bus);
 
+impl dyn Bus {
+    /// Finds a bus of the specified type, looking through wrappers
+    pub fn find<T: Bus>(&self) -> Option<&T> {
+        match self.downcast_ref::<T>() {
+            Some(b) => Some(b),
+            None => self.inner()?.find(),
+        }
+    }
+
+    /// Finds a bus of the specified type, looking through wrappers
+    pub fn find_mut<T: Bus>(&mut self) -> Option<&mut T> {
+        if self.is::<T>() {
+            return self.downcast_mut();
+        }
+        self.inner_mut()?.find_mut()
+    }
+}
+
 impl core::fmt::Debug for dyn Bus {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "Bus")
     }
 }
 
+/// Iterates over bus memory (using peek(), without side effects)
 pub struct BusIterator<'a> {
     bus: &'a dyn Bus,
     next: u16,
@@ -79,7 +127,7 @@ impl<'a> Iterator for BusIterator<'a> {
         let curr = self.next;
         self.next = self.next.wrapping_add(1);
 
-        Some(self.bus.read(curr))
+        Some(self.bus.peek(curr))
     }
 }
 
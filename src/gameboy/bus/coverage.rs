@@ -0,0 +1,279 @@
+use anyhow::Result;
+
+use super::bus::{Bus, BusMember};
+use super::gbbus::Gameboybus;
+use crate::gameboy::cpu::instructions::INSTRUCTIONS;
+use crate::tickable::{TickResult, Tickable, Ticks};
+
+use std::fmt;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Code/data log flag: executed as (the start of) an instruction
+pub const CDL_CODE: u8 = 1 << 0;
+/// Code/data log flag: read as data
+pub const CDL_DATA: u8 = 1 << 1;
+/// Code/data log flag: fetched as an operand, a byte of an instruction
+/// after the first (including the opcode after the 0xCB prefix)
+pub const CDL_OPERAND: u8 = 1 << 2;
+
+/// Size of a ROM bank
+const ROM_BANK_SIZE: usize = 0x4000;
+
+/// Execution coverage of a cartridge ROM, as code/data log flags per
+/// ROM byte. Code executed from RAM is not covered.
+pub struct Coverage {
+    flags: Vec<u8>,
+
+    /// Operand bytes left of the instruction being fetched
+    operands: usize,
+}
+
+impl Coverage {
+    pub fn new(rom_size: usize) -> Self {
+        Self {
+            flags: vec![0; rom_size],
+            operands: 0,
+        }
+    }
+
+    fn mark(&mut self, offset: Option<usize>, flag: u8) {
+        if let Some(f) = offset.and_then(|o| self.flags.get_mut(o)) {
+            *f |= flag;
+        }
+    }
+
+    /// Records an instruction fetch. The fetched bytes are followed to
+    /// tell opcodes from operands, also outside the ROM.
+    fn fetch(&mut self, offset: Option<usize>, val: u8) {
+        let flag = if self.operands > 0 {
+            self.operands -= 1;
+            CDL_OPERAND
+        } else {
+            self.operands = match val {
+                // CB-prefixed instructions have no immediate operands
+                0xCB => 1,
+                _ => INSTRUCTIONS[val as usize].len - 1,
+            };
+            CDL_CODE
+        };
+        self.mark(offset, flag);
+    }
+
+    /// Returns the flags of a ROM byte
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).copied().unwrap_or(0)
+    }
+
+    /// Amount of ROM bytes executed as code
+    pub fn code_bytes(&self) -> usize {
+        self.flags.iter().filter(|&&f| f & CDL_CODE != 0).count()
+    }
+
+    /// The code/data log: the flags of every ROM byte, as a file the
+    /// size of the ROM
+    pub fn to_cdl(&self) -> Vec<u8> {
+        self.flags.clone()
+    }
+
+    /// Lists the addresses of the executed instructions (bank:address),
+    /// one per line
+    pub fn to_address_list(&self) -> String {
+        let mut out = String::new();
+        for (offset, _) in self
+            .flags
+            .iter()
+            .enumerate()
+            .filter(|(_, &f)| f & CDL_CODE != 0)
+        {
+            let bank = offset / ROM_BANK_SIZE;
+            let addr = offset % ROM_BANK_SIZE + if bank > 0 { ROM_BANK_SIZE } else { 0 };
+            writeln!(out, "{:02X}:{:04X}", bank, addr).unwrap();
+        }
+        out
+    }
+
+    /// Exports as a code/data log if the filename ends in .cdl, as an
+    /// address list otherwise
+    pub fn export(&self, filename: &str) -> Vec<u8> {
+        if filename.to_lowercase().ends_with(".cdl") {
+            self.to_cdl()
+        } else {
+            self.to_address_list().into_bytes()
+        }
+    }
+}
+
+/// Shared handle to a coverage being collected
+pub type CoverageHandle = Arc<Mutex<Coverage>>;
+
+/// Bus wrapper that collects the execution coverage of the cartridge
+/// ROM.
+pub struct CoverageBus {
+    inner: Box<dyn Bus>,
+    coverage: CoverageHandle,
+}
+
+impl CoverageBus {
+    pub fn new(inner: Box<dyn Bus>, rom_size: usize) -> Self {
+        Self::with_handle(inner, Arc::new(Mutex::new(Coverage::new(rom_size))))
+    }
+
+    /// Collects into an existing coverage handle (e.g. when the bus is
+    /// created on the emulation thread)
+    pub fn with_handle(inner: Box<dyn Bus>, coverage: CoverageHandle) -> Self {
+        Self { inner, coverage }
+    }
+
+    pub fn handle(&self) -> CoverageHandle {
+        Arc::clone(&self.coverage)
+    }
+
+    /// Offset in the ROM of an address, None if the address does not
+    /// map to the cartridge ROM
+    fn rom_offset(&self, addr: u16) -> Option<usize> {
+        let bank = match Gameboybus::from_bus(&*self.inner) {
+            Some(b) if b.is_boot_rom_mapped(addr) => return None,
+            Some(b) => b.cartridge().current_rom_bank(),
+            // Flat memory without a cartridge
+            None => 1,
+        };
+        match addr {
+            0x0000..=0x3FFF => Some(addr as usize),
+            0x4000..=0x7FFF => Some(bank * ROM_BANK_SIZE + (addr as usize - ROM_BANK_SIZE)),
+            _ => None,
+        }
+    }
+}
+
+impl Bus for CoverageBus {
+    fn inner(&self) -> Option<&dyn Bus> {
+        Some(self.inner.as_ref())
+    }
+
+    fn inner_mut(&mut self) -> Option<&mut dyn Bus> {
+        Some(self.inner.as_mut())
+    }
+}
+
+impl BusMember for CoverageBus {
+    fn read(&self, addr: u16) -> u8 {
+        let offset = self.rom_offset(addr);
+        self.coverage.lock().unwrap().mark(offset, CDL_DATA);
+        self.inner.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.inner.write(addr, val)
+    }
+
+    fn fetch(&self, addr: u16) -> u8 {
+        let val = self.inner.fetch(addr);
+        let offset = self.rom_offset(addr);
+        self.coverage.lock().unwrap().fetch(offset, val);
+        val
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.inner.peek(addr)
+    }
+}
+
+impl Tickable for CoverageBus {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
+        self.inner.tick(ticks)
+    }
+}
+
+impl fmt::Display for CoverageBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testbus::Testbus;
+    use super::*;
+    use crate::display::display::NullDisplay;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::cpu::cpu::CPU;
+    use crate::gameboy::cpu::regs::Register;
+    use crate::gameboy::lcd::LCDController;
+    use crate::gameboy::model::Model;
+    use crate::input::input::NullInput;
+
+    #[test]
+    fn code_and_data() {
+        // 0000: LD A,(0010h)
+        // 0003: LD B,(HL)
+        // 0004: SWAP A
+        // 0006: JR -2
+        // 0010: data
+        let mut code = vec![0xFA, 0x10, 0x00, 0x46, 0xCB, 0x37, 0x18, 0xFE];
+        code.resize(0x12, 0);
+        let bus = Testbus::from(&code[..]);
+        let mut cpu = CPU::new(
+            Box::new(CoverageBus::new(Box::new(bus), 0x8000)),
+            Model::Dmg,
+        );
+        cpu.regs.write(Register::HL, 0x0011).unwrap();
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        let coverage = cpu.bus.downcast_ref::<CoverageBus>().unwrap().handle();
+        let coverage = coverage.lock().unwrap();
+        let expected = [
+            CDL_CODE,
+            CDL_OPERAND,
+            CDL_OPERAND,
+            CDL_CODE,
+            CDL_CODE,
+            CDL_OPERAND,
+            CDL_CODE,
+            CDL_OPERAND,
+        ];
+        for (offset, &flags) in expected.iter().enumerate() {
+            assert_eq!(coverage.flags(offset), flags, "{:04X}", offset);
+        }
+        assert_eq!(coverage.flags(0x0008), 0);
+        assert_eq!(coverage.flags(0x0010), CDL_DATA);
+        assert_eq!(coverage.flags(0x0011), CDL_DATA);
+        assert_eq!(coverage.code_bytes(), 4);
+        assert_eq!(
+            coverage.to_address_list(),
+            "00:0000\n00:0003\n00:0004\n00:0006\n"
+        );
+        assert_eq!(coverage.to_cdl().len(), 0x8000);
+    }
+
+    #[test]
+    fn rom_banks() {
+        // MBC1, 64KB
+        let mut rom = vec![0; 64 * 1024];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        let gbbus = Gameboybus::new(
+            cartridge::load(&rom).unwrap(),
+            None,
+            lcd,
+            Box::new(NullInput::new()),
+            Model::Dmg,
+        );
+        let mut bus: Box<dyn Bus> = Box::new(CoverageBus::new(Box::new(gbbus), rom.len()));
+        bus.read(0x4000);
+        bus.write(0x2000, 2);
+        bus.fetch(0x4001);
+        bus.peek(0x4002);
+        bus.read(0xC000);
+
+        let coverage = bus.downcast_ref::<CoverageBus>().unwrap().handle();
+        let coverage = coverage.lock().unwrap();
+        assert_eq!(coverage.flags(0x4000), CDL_DATA);
+        assert_eq!(coverage.flags(0x8001), CDL_CODE);
+        assert_eq!(coverage.flags(0x8002), 0);
+        assert_eq!(coverage.to_address_list(), "02:4001\n");
+    }
+}
@@ -1,20 +1,25 @@
 use super::super::apu::APU;
 use super::super::cartridge::cartridge::Cartridge;
+use super::super::clock::EmuClock;
 use super::super::cpu::cpu;
+use super::super::infrared::{InfraRed, IrTransport, NullTransport};
 use super::super::joypad::Joypad;
-use super::super::lcd::{LCDController, LCDStatMode};
+use super::super::lcd::{LCDController, LCDStatMode, LCDC_BGW_TILEDATA};
+use super::super::model::Model;
+use super::super::raminit::RamInit;
 use super::super::serial::Serial;
+use super::super::sgb::{Sgb, VRAM_TRANSFER_SIZE};
 use super::super::timer::Timer;
 use super::bus::{Bus, BusMember};
 use crate::input::input::Input;
-use crate::tickable::{Tickable, Ticks, ONE_MCYCLE};
+use crate::tickable::{TickResult, Tickable, Ticks, ONE_MCYCLE};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
-use std::cell::RefCell;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use std::cmp;
+use std::collections::BTreeMap;
 use std::fmt;
-use std::rc::Rc;
 
 #[allow(dead_code)]
 const BOOTROM_SIZE_DMG: usize = 0x100;
@@ -27,15 +32,43 @@ const VRAMDMA_LEN_MASK: u8 = 0x7F;
 const VRAMDMA_IDLE: u8 = 0xFF;
 const VRAMDMA_BLOCK_SIZE: usize = 0x10;
 
+/// Random sequences of WRAM and HRAM for RamInit::Random
+const RAM_STREAM_WRAM: u64 = 0;
+const RAM_STREAM_HRAM: u64 = 1;
+
+/// KEY0: DMG compatibility mode (set by the CGB boot ROM for DMG cartridges)
+const KEY0_DMG_COMPAT: u8 = 1 << 2;
+/// Writable bits of the undocumented register 0xFF75
+const FF75_MASK: u8 = 0x70;
+
+/// Memory region a page (256 bytes) of the address space maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    BootRom,
+    Cartridge,
+    /// Cartridge ROM with patched bytes in this page
+    PatchedRom,
+    Vram,
+    Wram,
+    Oam,
+    IO,
+}
+
 /// Multiplexer for the Gameboy address bus
 pub struct Gameboybus {
-    cgb: bool,
+    model: Model,
+
+    /// Memory map, indexed by the upper byte of the address
+    pages: [Region; 256],
 
-    cart: Rc<RefCell<dyn Cartridge>>,
+    cart: Box<dyn Cartridge>,
     boot_rom: [u8; BOOTROM_SIZE_CGB],
 
     boot_rom_enabled: bool,
 
+    /// Bytes overriding the cartridge ROM (0x0000 - 0x7FFF), by address
+    rom_patches: BTreeMap<u16, u8>,
+
     wram: [u8; Self::WRAM_SIZE * Self::WRAM_BANKS],
     hram: [u8; u16::MAX as usize + 1],
     ie: u8,
@@ -78,37 +111,74 @@ pub struct Gameboybus {
     /// Serial port controller
     serial: Serial,
 
+    /// CGB - Infrared port
+    ir: InfraRed,
+
+    /// SGB - Command packets, palettes and multiplayer
+    sgb: Option<Sgb>,
+
     /// Double speed mode
     double_speed: bool,
+
+    /// CGB - KEY0 - CPU mode select, writable while the boot ROM is mapped
+    key0: u8,
+
+    /// CGB - DMG compatibility mode, latched from KEY0 when the boot ROM
+    /// is disabled
+    dmg_compat: bool,
+
+    /// CGB - Undocumented registers 0xFF72 - 0xFF75
+    undocumented: [u8; 4],
+
+    /// Fixed value returned on reads from LY (for logging/testing)
+    ly_override: Option<u8>,
+
+    /// Time source for the cartridge's real time clock
+    clock: EmuClock,
 }
 
 impl Gameboybus {
     const WRAM_SIZE: usize = 0x1000;
+    const WRAM_MASK: usize = Self::WRAM_SIZE - 1;
     const WRAM_BANKS: usize = 8;
 
     pub fn new(
-        cart: Rc<RefCell<dyn Cartridge>>,
+        cart: Box<dyn Cartridge>,
         bootrom: Option<&[u8]>,
         lcd: LCDController,
         input: Box<dyn Input>,
-        cgb: bool,
+        model: Model,
     ) -> Self {
-        Self::new_with_serial(cart, bootrom, lcd, input, cgb, Serial::new_null())
+        Self::new_with_serial(cart, bootrom, lcd, input, model, Serial::new_null())
+    }
+
+    /// Returns the Gameboybus behind a bus, looking through wrappers
+    /// (e.g. ProfilerBus), for access to the peripherals. None for
+    /// other buses, like Testbus.
+    pub fn from_bus(bus: &dyn Bus) -> Option<&Self> {
+        bus.find()
+    }
+
+    /// Mutable version of from_bus()
+    pub fn from_bus_mut(bus: &mut dyn Bus) -> Option<&mut Self> {
+        bus.find_mut()
     }
 
     pub fn new_with_serial(
-        cart: Rc<RefCell<dyn Cartridge>>,
+        cart: Box<dyn Cartridge>,
         bootrom: Option<&[u8]>,
         lcd: LCDController,
         input: Box<dyn Input>,
-        cgb: bool,
+        model: Model,
         serial: Serial,
     ) -> Self {
         let mut bus = Gameboybus {
-            cgb,
+            model,
+            pages: [Region::IO; 256],
             cart,
             boot_rom: [0; BOOTROM_SIZE_CGB],
             boot_rom_enabled: false,
+            rom_patches: BTreeMap::new(),
 
             wram: [0; Self::WRAM_SIZE * Self::WRAM_BANKS],
             wram_banksel: 1,
@@ -122,6 +192,8 @@ impl Gameboybus {
 
             intflags: cpu::INT_VBLANK, // VBlank is set after boot ROM
             serial,
+            ir: InfraRed::new(Box::new(NullTransport)),
+            sgb: model.is_sgb().then(Sgb::new),
 
             vramdma_src: 0,
             vramdma_dest: 0,
@@ -131,28 +203,145 @@ impl Gameboybus {
             oamdma_ticks: 0,
             oamdma_addr: 0,
             double_speed: false,
+            key0: 0,
+            dmg_compat: false,
+            undocumented: [0; 4],
+            ly_override: None,
+            clock: EmuClock::emulated(),
         };
 
         if let Some(br) = bootrom {
             bus.boot_rom[0..br.len()].copy_from_slice(br);
             bus.boot_rom_enabled = true;
         }
+        bus.lcd.set_opri_writable(bus.boot_rom_enabled);
+        bus.update_pages();
 
         bus
     }
 
-    fn update_intflags(&mut self) {
-        if self.lcd.get_clr_intreq_vblank() {
-            self.intflags |= cpu::INT_VBLANK;
-        }
-        if self.lcd.get_clr_intreq_stat() {
-            self.intflags |= cpu::INT_LCDSTAT;
+    /// Runs in Gameboy Color mode: a CGB model that is not in DMG
+    /// compatibility mode
+    pub fn is_cgb_mode(&self) -> bool {
+        self.model.is_cgb() && !self.dmg_compat
+    }
+
+    /// Running a DMG cartridge on a CGB in compatibility mode
+    pub fn is_dmg_compat(&self) -> bool {
+        self.dmg_compat
+    }
+
+    /// An address currently maps to the boot ROM (rather than the
+    /// cartridge)
+    pub fn is_boot_rom_mapped(&self, addr: u16) -> bool {
+        self.pages[(addr >> 8) as usize] == Region::BootRom
+    }
+
+    /// Returns the LCD controller
+    pub fn get_lcd(&self) -> &LCDController {
+        &self.lcd
+    }
+
+    pub fn get_lcd_mut(&mut self) -> &mut LCDController {
+        &mut self.lcd
+    }
+
+    pub fn get_apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn get_apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    /// Makes reads from LY (0xFF44) return a fixed value, which some
+    /// logging tools (e.g. Gameboy Doctor) expect. None restores
+    /// normal behaviour.
+    pub fn set_ly_override(&mut self, ly: Option<u8>) {
+        self.ly_override = ly;
+    }
+
+    /// Replaces the clock, which is emulated by default. This should
+    /// happen before the system runs, as the real time clock jumps to
+    /// the time of the new clock.
+    pub fn set_clock(&mut self, clock: EmuClock) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &EmuClock {
+        &self.clock
+    }
+
+    /// Initializes WRAM, HRAM and VRAM (see RamInit). This should happen
+    /// before the system runs.
+    pub fn init_ram(&mut self, init: RamInit) {
+        init.fill(&mut self.wram, RAM_STREAM_WRAM);
+        init.fill(&mut self.hram[0xFF80..0xFFFF], RAM_STREAM_HRAM);
+        self.lcd.init_ram(init);
+    }
+
+    /// Completes a pending SGB VRAM transfer. The SGB takes the data
+    /// from the screen; this assumes the game displays the tile data
+    /// in order, as games do for transfers.
+    fn sgb_vram_transfer(&mut self) {
+        let Some(sgb) = self.sgb.as_mut().filter(|s| s.wants_vram_transfer()) else {
+            return;
+        };
+        let start = if self.lcd.read(0xFF40) & LCDC_BGW_TILEDATA != 0 {
+            0x0000
+        } else {
+            0x0800
+        };
+        sgb.vram_transfer(&self.lcd.dump_vram(0)[start..(start + VRAM_TRANSFER_SIZE)]);
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    /// Connects the infrared port (CGB only)
+    pub fn set_ir_transport(&mut self, transport: Box<dyn IrTransport>) {
+        self.ir.set_transport(transport);
+    }
+
+    /// Returns the inserted cartridge
+    pub fn cartridge(&self) -> &dyn Cartridge {
+        self.cart.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn Cartridge {
+        self.cart.as_mut()
+    }
+
+    /// Overrides cartridge ROM bytes at the given address. The patches
+    /// apply to whatever ROM bank is mapped at the address.
+    pub fn patch_rom(&mut self, addr: u16, data: &[u8]) -> Result<()> {
+        let end = addr as usize + data.len();
+        if end > 0x8000 {
+            bail!("Patch at {:04X} - {:04X} outside of ROM", addr, end - 1);
         }
-        if self.timer.get_clr_intreq() {
-            self.intflags |= cpu::INT_TIMER;
+        for (a, &b) in (addr..).zip(data) {
+            self.rom_patches.insert(a, b);
         }
-        if self.serial.get_clr_intreq() {
-            self.intflags |= cpu::INT_SERIAL;
+        self.update_pages();
+        Ok(())
+    }
+
+    /// Removes all ROM patches
+    pub fn clear_rom_patches(&mut self) {
+        self.rom_patches.clear();
+        self.update_pages();
+    }
+
+    /// Returns the ROM patches, by address
+    pub fn rom_patches(&self) -> &BTreeMap<u16, u8> {
+        &self.rom_patches
+    }
+
+    /// Clocks the APU frame sequencer from the timer's divider
+    fn clock_apu(&mut self) {
+        for _ in 0..self.timer.get_clr_apu_events() {
+            self.apu.clock_frame_sequencer();
         }
     }
 
@@ -245,70 +434,105 @@ impl Gameboybus {
             .oamdma_ticks
             .saturating_sub(ticks.saturating_sub(start_ticks));
         if self.oamdma_ticks == 0 {
-            // Transfer completed this tick, perform actual copy
+            // Transfer completed this tick, perform actual copy.
+            // The DMA unit is not subject to PPU access blocking.
             for i in 0..=0x9F {
-                self.write(0xFE00 | i, self.read(self.oamdma_addr | i));
+                self.write(0xFE00 | i, self.peek(self.oamdma_addr | i));
             }
         }
     }
-}
-
-impl Bus for Gameboybus {}
 
-impl BusMember for Gameboybus {
-    fn read(&self, addr: u16) -> u8 {
-        let addr = addr as usize;
+    /// Translates an address in WRAM (or its echo) to an offset in
+    /// the WRAM banks.
+    #[inline(always)]
+    fn wram_addr(&self, addr: usize) -> usize {
+        // Working RAM bank 0 (0xC000 - 0xCFFF),
+        // bank 1 (DMG) / bank 1-7 (CGB) (0xD000 - 0xDFFF)
+        let bank = if addr & Self::WRAM_SIZE != 0 {
+            self.wram_banksel as usize
+        } else {
+            0
+        };
+        (addr & Self::WRAM_MASK) + bank * Self::WRAM_SIZE
+    }
 
-        // About bus conflicts:
-        // https://reddit.com/r/EmuDev/s/EiuFVdz031
-        if self.oamdma_ticks > 0
-            && ((0x0000..=0x7FFF).contains(&addr)
-                || (0x8000..=0x9FFF).contains(&addr)
-                || (0xA000..=0xFDFF).contains(&addr)
-                || (0xFE00..=0xFE9F).contains(&addr))
-        {
-            // Bus blocked by OAM DMA
-            return 0xFF;
+    /// Rebuilds the page table, needs to be called when anything
+    /// changes that affects the memory map.
+    fn update_pages(&mut self) {
+        for (page, region) in self.pages.iter_mut().enumerate() {
+            *region = match page {
+                // DMG/CGB (lower part) Boot ROM
+                0x00 if self.boot_rom_enabled => Region::BootRom,
+                // CGB (upper part) Boot ROM
+                0x02..=0x08 if self.boot_rom_enabled && self.model.is_cgb() => Region::BootRom,
+                // Cartridge ROM with patches
+                0x00..=0x7F
+                    if self
+                        .rom_patches
+                        .range((page << 8) as u16..=(page << 8 | 0xFF) as u16)
+                        .next()
+                        .is_some() =>
+                {
+                    Region::PatchedRom
+                }
+                // Cartridge ROM
+                0x00..=0x7F => Region::Cartridge,
+                // Video RAM
+                0x80..=0x9F => Region::Vram,
+                // External (cartridge) RAM
+                0xA0..=0xBF => Region::Cartridge,
+                // Working RAM and its echo
+                0xC0..=0xFD => Region::Wram,
+                // OAM and unusable segment
+                0xFE => Region::Oam,
+                // I/O, HRAM and IE
+                _ => Region::IO,
+            };
         }
+    }
 
-        match addr {
-            // DMG/CGB (lower part) Boot ROM
-            0x0000..=0x00FF if self.boot_rom_enabled => self.boot_rom[addr],
-
-            // CGB (upper part) Boot ROM
-            0x0200..=0x08FF if self.boot_rom_enabled && self.cgb => self.boot_rom[addr],
-
-            // Cartridge ROM
-            0x0000..=0x7FFF => self.cart.borrow().read(addr as u16),
-
-            // Video RAM
-            0x8000..=0x9FFF => self.lcd.read(addr as u16),
-
-            // External (cartridge) RAM
-            0xA000..=0xBFFF => self.cart.borrow().read(addr as u16),
-
-            // Working RAM (bank 0)
-            0xC000..=0xCFFF => self.wram[addr - 0xC000],
-
-            // Working RAM (bank 1 (DMG) / bank 1-7 (CGB))
-            0xD000..=0xDFFF => {
-                self.wram[addr - 0xD000 + self.wram_banksel as usize * Self::WRAM_SIZE]
-            }
-
-            // Echo RAM
-            0xE000..=0xEFFF => self.wram[addr - 0xE000],
-            0xF000..=0xFDFF => {
-                self.wram[addr - 0xF000 + self.wram_banksel as usize * Self::WRAM_SIZE]
-            }
-
+    /// Reads from the memory map, without OAM DMA blocking
+    #[inline]
+    fn read_mapped(&self, addr: u16) -> u8 {
+        let addr = addr as usize;
+        match self.pages[addr >> 8] {
+            Region::BootRom => self.boot_rom[addr],
+            Region::Cartridge => self.cart.read(addr as u16),
+            Region::PatchedRom => match self.rom_patches.get(&(addr as u16)) {
+                Some(&val) => val,
+                None => self.cart.read(addr as u16),
+            },
+            Region::Vram => self.lcd.read(addr as u16),
+            Region::Wram => self.wram[self.wram_addr(addr)],
             // Object Attribute Table (OAM)
-            0xFE00..=0xFE9F => self.lcd.read(addr as u16),
-
+            Region::Oam if addr <= 0xFE9F => self.lcd.read(addr as u16),
             // Unusable segment
-            0xFEA0..=0xFEFF => 0,
+            Region::Oam => self.read_unusable(addr),
+            Region::IO => self.read_io(addr),
+        }
+    }
+
+    /// Reads from the unusable segment (0xFEA0 - 0xFEFF)
+    fn read_unusable(&self, addr: usize) -> u8 {
+        match self.model {
+            // Blocked like OAM during modes 2 and 3 (where the read would
+            // also corrupt OAM, which is not emulated).
+            Model::Dmg | Model::Mgb | Model::Sgb if self.lcd.oam_blocked() => 0xFF,
+            Model::Dmg | Model::Mgb | Model::Sgb => 0x00,
+            // CGB revision E: the upper nibble of the lower address byte,
+            // repeated in both nibbles (e.g. 0xFEA4 reads 0xAA).
+            Model::Cgb | Model::Agb => (addr as u8 & 0xF0) | (addr as u8 >> 4),
+        }
+    }
 
+    /// Reads from the I/O page (0xFF00 - 0xFFFF)
+    fn read_io(&self, addr: usize) -> u8 {
+        match addr {
             // I/O - Joypad
-            0xFF00 => self.joypad.read(),
+            0xFF00 => match self.sgb {
+                Some(ref sgb) => sgb.read_p1(self.joypad.read()),
+                None => self.joypad.read(),
+            },
 
             // I/O - Serial transfer
             0xFF01..=0xFF02 => self.serial.read(addr as u16),
@@ -326,36 +550,56 @@ impl BusMember for Gameboybus {
             // Handled here because we need to access source memory
             0xFF46 => 0,
 
+            // I/O - LY, if pinned
+            0xFF44 if self.ly_override.is_some() => self.ly_override.unwrap(),
+
             // I/O - LCD I/O
-            0xFF40..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => self.lcd.read(addr as u16),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.lcd.read(addr as u16)
+            }
 
             // CGB - KEY1 - Prepare speed switch
-            0xFF4D if self.cgb => unreachable!(), // Handled by CPU
+            0xFF4D if self.model.is_cgb() => unreachable!(), // Handled by CPU
+
+            // CGB - KEY0 - CPU mode select
+            0xFF4C if self.model.is_cgb() && self.boot_rom_enabled => self.key0,
 
             // I/O - Boot ROM disable
             0xFF50 if self.boot_rom_enabled => 0,
             0xFF50 => 1,
 
             // CGB - HDMA1 - VRAM DMA source (MSB)
-            0xFF51 if self.cgb => (self.vramdma_src >> 8) as u8,
+            0xFF51 if self.is_cgb_mode() => (self.vramdma_src >> 8) as u8,
 
             // CGB - HDMA2 - VRAM DMA source (LSB)
-            0xFF52 if self.cgb => (self.vramdma_src & 0xFF) as u8,
+            0xFF52 if self.is_cgb_mode() => (self.vramdma_src & 0xFF) as u8,
 
             // CGB - HDMA3 - VRAM DMA destination (MSB)
-            0xFF53 if self.cgb => (self.vramdma_dest >> 8) as u8,
+            0xFF53 if self.is_cgb_mode() => (self.vramdma_dest >> 8) as u8,
 
             // CGB - HDMA4 - VRAM DMA destination (LSB)
-            0xFF54 if self.cgb => (self.vramdma_dest & 0xFF) as u8,
+            0xFF54 if self.is_cgb_mode() => (self.vramdma_dest & 0xFF) as u8,
 
             // CGB - HDMA5 - VRAM DMA length/mode/start
-            0xFF55 if self.cgb => self.vramdma_len.unwrap_or(VRAMDMA_IDLE),
+            0xFF55 if self.is_cgb_mode() => self.vramdma_len.unwrap_or(VRAMDMA_IDLE),
+
+            // CGB - RP - Infrared communication port
+            0xFF56 if self.is_cgb_mode() => self.ir.read(addr as u16),
 
             // CGB - SVBK - WRAM bank select
-            0xFF70 if self.cgb => self.wram_banksel & 0x07,
+            0xFF70 if self.is_cgb_mode() => self.wram_banksel & 0x07,
 
-            // Other I/O registers
-            0xFF00..=0xFF7F => 0xFF,
+            // CGB - Undocumented, fully readable and writable
+            0xFF72 | 0xFF73 if self.model.is_cgb() => self.undocumented[addr - 0xFF72],
+
+            // CGB - Undocumented, locked in DMG compatibility mode
+            0xFF74 if self.is_cgb_mode() => self.undocumented[2],
+
+            // CGB - Undocumented, only bits 4-6 are used
+            0xFF75 if self.model.is_cgb() => self.undocumented[3] | !FF75_MASK,
+
+            // Other I/O registers, including the CGB registers above on DMG
+            0xFF03 | 0xFF08..=0xFF0E | 0xFF4C..=0xFF4E | 0xFF51..=0xFF67 | 0xFF6D..=0xFF7F => 0xFF,
 
             // High RAM
             0xFF80..=0xFFFE => self.hram[addr],
@@ -367,59 +611,28 @@ impl BusMember for Gameboybus {
         }
     }
 
-    fn write(&mut self, addr: u16, val: u8) {
-        let addr = addr as usize;
-
-        // About bus conflicts:
-        // https://reddit.com/r/EmuDev/s/EiuFVdz031
-        if self.oamdma_ticks > 0
-            && ((0x0000..=0x7FFF).contains(&addr)
-                || (0x8000..=0x9FFF).contains(&addr)
-                || (0xA000..=0xFDFF).contains(&addr)
-                || (0xFE00..=0xFE9F).contains(&addr))
-        {
-            // Bus blocked by OAM DMA
-            return ();
-        }
-
+    /// Writes to the I/O page (0xFF00 - 0xFFFF)
+    fn write_io(&mut self, addr: usize, val: u8) {
         match addr {
-            // Cartridge ROM
-            0x0000..=0x7FFF => self.cart.borrow_mut().write(addr as u16, val),
-
-            // Video RAM
-            0x8000..=0x9FFF => self.lcd.write(addr as u16, val),
-
-            // External (cartridge) RAM
-            0xA000..=0xBFFF => self.cart.borrow_mut().write(addr as u16, val),
-
-            // Working RAM (bank 0)
-            0xC000..=0xCFFF => self.wram[addr - 0xC000] = val,
-
-            // Working RAM (bank 1 (DMG) / bank 1-7 (CGB))
-            0xD000..=0xDFFF => {
-                self.wram[addr - 0xD000 + self.wram_banksel as usize * Self::WRAM_SIZE] = val
-            }
-
-            // Echo RAM
-            0xE000..=0xEFFF => self.wram[addr - 0xE000] = val,
-            0xF000..=0xFDFF => {
-                self.wram[addr - 0xF000 + self.wram_banksel as usize * Self::WRAM_SIZE] = val
-            }
-
-            // Object Attribute Table (OAM)
-            0xFE00..=0xFE9F => self.lcd.write(addr as u16, val),
-
-            // Unusable segment
-            0xFEA0..=0xFEFF => (),
-
             // I/O - Joypad
-            0xFF00 => self.joypad.write(val),
+            0xFF00 => {
+                self.joypad.write(val);
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_p1(val);
+                    if let Some(palette) = sgb.take_palette() {
+                        self.lcd.set_dmg_palette(palette);
+                    }
+                }
+            }
 
             // I/O - Serial transfer
             0xFF01..=0xFF02 => self.serial.write(addr as u16, val),
 
             // Timer
-            0xFF04..=0xFF07 => self.timer.write(addr as u16, val),
+            0xFF04..=0xFF07 => {
+                self.timer.write(addr as u16, val);
+                self.clock_apu();
+            }
 
             // IF - Interrupt Flags
             0xFF0F => self.intflags = val & IF_MASK,
@@ -431,6 +644,13 @@ impl BusMember for Gameboybus {
             0xFF50 => {
                 if val > 0 && self.boot_rom_enabled {
                     self.boot_rom_enabled = false;
+                    if self.model.is_cgb() && self.key0 & KEY0_DMG_COMPAT != 0 {
+                        self.dmg_compat = true;
+                        self.lcd.set_cgb_mode(false);
+                        self.lcd.set_dmg_on_cgb(true);
+                    }
+                    self.lcd.set_opri_writable(false);
+                    self.update_pages();
                 }
             }
 
@@ -445,33 +665,52 @@ impl BusMember for Gameboybus {
             }
 
             // I/O - LCD I/O
-            0xFF40..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => self.lcd.write(addr as u16, val),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.lcd.write(addr as u16, val)
+            }
+
+            // CGB - KEY0 - CPU mode select
+            0xFF4C if self.model.is_cgb() && self.boot_rom_enabled => self.key0 = val,
 
             // CGB - KEY1 - Prepare speed switch
-            0xFF4D if self.cgb => unreachable!(), // Handled by CPU
+            0xFF4D if self.model.is_cgb() => unreachable!(), // Handled by CPU
 
             // CGB - HDMA1 - VRAM DMA source (MSB)
-            0xFF51 if self.cgb => self.vramdma_src = ((val as u16) << 8) | self.vramdma_src & 0xFF,
+            0xFF51 if self.is_cgb_mode() => {
+                self.vramdma_src = ((val as u16) << 8) | self.vramdma_src & 0xFF
+            }
 
             // CGB - HDMA2 - VRAM DMA source (LSB)
-            0xFF52 if self.cgb => self.vramdma_src = self.vramdma_src & 0xFF00 | val as u16,
+            0xFF52 if self.is_cgb_mode() => {
+                self.vramdma_src = self.vramdma_src & 0xFF00 | val as u16
+            }
 
             // CGB - HDMA3 - VRAM DMA destination (MSB)
-            0xFF53 if self.cgb => {
+            0xFF53 if self.is_cgb_mode() => {
                 self.vramdma_dest = ((val as u16) << 8) | self.vramdma_dest & 0xFF
             }
 
             // CGB - HDMA4 - VRAM DMA destination (LSB)
-            0xFF54 if self.cgb => self.vramdma_dest = self.vramdma_dest & 0xFF00 | val as u16,
+            0xFF54 if self.is_cgb_mode() => {
+                self.vramdma_dest = self.vramdma_dest & 0xFF00 | val as u16
+            }
 
             // CGB - HDMA5 - VRAM DMA length/mode/start
-            0xFF55 if self.cgb => self.do_vramdma(Some(val)),
+            0xFF55 if self.is_cgb_mode() => self.do_vramdma(Some(val)),
+
+            // CGB - RP - Infrared communication port
+            0xFF56 if self.is_cgb_mode() => self.ir.write(addr as u16, val),
 
             // CGB - SVBK / WRAM bank select
-            0xFF70 if self.cgb => self.wram_banksel = cmp::max(1, val) & 0x07,
+            0xFF70 if self.is_cgb_mode() => self.wram_banksel = cmp::max(1, val) & 0x07,
 
-            // Other I/O registers
-            0xFF00..=0xFF7F => (),
+            // CGB - Undocumented registers
+            0xFF72 | 0xFF73 if self.model.is_cgb() => self.undocumented[addr - 0xFF72] = val,
+            0xFF74 if self.is_cgb_mode() => self.undocumented[2] = val,
+            0xFF75 if self.model.is_cgb() => self.undocumented[3] = val & FF75_MASK,
+
+            // Other I/O registers, including the CGB registers above on DMG
+            0xFF03 | 0xFF08..=0xFF0E | 0xFF4C..=0xFF4E | 0xFF51..=0xFF67 | 0xFF6D..=0xFF7F => (),
 
             // High RAM
             0xFF80..=0xFFFE => self.hram[addr] = val,
@@ -484,22 +723,86 @@ impl BusMember for Gameboybus {
     }
 }
 
+impl Bus for Gameboybus {}
+
+impl BusMember for Gameboybus {
+    #[inline]
+    fn read(&self, addr: u16) -> u8 {
+        // About bus conflicts:
+        // https://reddit.com/r/EmuDev/s/EiuFVdz031
+        if self.oamdma_ticks > 0 && addr < 0xFEA0 {
+            // Bus blocked by OAM DMA
+            return 0xFF;
+        }
+
+        self.read_mapped(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            // Video RAM, OAM and CGB palette data, without access blocking
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F | 0xFF69 | 0xFF6B => self.lcd.peek(addr),
+            // CGB - KEY1 lives in the CPU, see CPU::peek()
+            0xFF4D if self.model.is_cgb() => 0xFF,
+            // Not blocked by OAM DMA
+            _ => self.read_mapped(addr),
+        }
+    }
+
+    #[inline]
+    fn write(&mut self, addr: u16, val: u8) {
+        // About bus conflicts:
+        // https://reddit.com/r/EmuDev/s/EiuFVdz031
+        if self.oamdma_ticks > 0 && addr < 0xFEA0 {
+            // Bus blocked by OAM DMA
+            return;
+        }
+
+        let addr = addr as usize;
+        match self.pages[addr >> 8] {
+            // The boot ROM is read-only, writes go to the cartridge
+            // Patches are read-only as well
+            Region::BootRom | Region::Cartridge | Region::PatchedRom => {
+                self.cart.write(addr as u16, val)
+            }
+            Region::Vram => self.lcd.write(addr as u16, val),
+            Region::Wram => self.wram[self.wram_addr(addr)] = val,
+            // Object Attribute Table (OAM)
+            Region::Oam if addr <= 0xFE9F => self.lcd.write(addr as u16, val),
+            // Unusable segment
+            Region::Oam => (),
+            Region::IO => self.write_io(addr, val),
+        }
+    }
+}
+
 impl Tickable for Gameboybus {
-    fn tick(&mut self, ticks: Ticks) -> Result<()> {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
         self.oamdma_tick(ticks);
 
-        // Tick sub-peripherals
-        self.lcd.tick(ticks)?;
+        // Tick sub-peripherals, collecting their interrupt requests
+        let mut intreq = 0;
+        let frame = self.lcd.get_frame_count();
+        intreq |= self.lcd.tick(ticks)?;
+        if self.lcd.get_frame_count() != frame {
+            self.joypad.set_frame(self.lcd.get_frame_count());
+            self.sgb_vram_transfer();
+        }
         if ticks.is_double_speed() != self.double_speed {
             // Speed switch occured, do not tick timer
             // (timer is frozen during STOP)
             self.double_speed = ticks.is_double_speed();
         } else {
-            self.timer.tick(ticks)?;
+            intreq |= self.timer.tick(ticks)?;
+            self.clock_apu();
+        }
+        intreq |= self.serial.tick(ticks)?;
+        intreq |= self.ir.tick(ticks)?;
+        if let Some(now) = self.clock.tick(ticks) {
+            self.cart.set_time(now);
         }
-        self.serial.tick(ticks)?;
 
-        self.update_intflags();
+        self.intflags |= intreq;
 
         // Progress HBlank DMA if active
         let statmode = self.lcd.get_stat_mode();
@@ -511,13 +814,98 @@ impl Tickable for Gameboybus {
             self.vramdma_hb_seen = false;
         }
 
-        Ok(())
+        Ok(intreq)
     }
 }
 
 impl fmt::Display for Gameboybus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.cart.borrow().dump_state())
+        write!(
+            f,
+            "{} LCD:{:?} LY:{}",
+            self.cart.dump_state(),
+            self.lcd.get_stat_mode(),
+            self.lcd.read(0xFF44)
+        )
+    }
+}
+
+impl Savestate for Gameboybus {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.cart.save_state(w);
+        w.put_bool(self.boot_rom_enabled);
+        w.put_slice(&self.wram);
+        w.put_slice(&self.hram);
+        w.put_u8(self.ie);
+        self.lcd.save_state(w);
+        self.timer.save_state(w);
+        self.joypad.save_state(w);
+        self.apu.save_state(w);
+        w.put_u8(self.intflags);
+        w.put_u8(self.wram_banksel);
+        w.put_u16(self.vramdma_src);
+        w.put_u16(self.vramdma_dest);
+        w.put_bool(self.vramdma_len.is_some());
+        w.put_u8(self.vramdma_len.unwrap_or(0));
+        w.put_bool(self.vramdma_hb_seen);
+        w.put_usize(self.oamdma_start);
+        w.put_usize(self.oamdma_ticks);
+        w.put_u16(self.oamdma_addr);
+        self.serial.save_state(w);
+        self.ir.save_state(w);
+        w.put_bool(self.sgb.is_some());
+        if let Some(ref sgb) = self.sgb {
+            sgb.save_state(w);
+        }
+        w.put_bool(self.double_speed);
+        w.put_u8(self.key0);
+        w.put_bool(self.dmg_compat);
+        w.put_slice(&self.undocumented);
+        self.clock.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.cart.load_state(r)?;
+        self.boot_rom_enabled = r.get_bool()?;
+        self.update_pages();
+        r.get_slice(&mut self.wram)?;
+        r.get_slice(&mut self.hram)?;
+        self.ie = r.get_u8()?;
+        self.lcd.load_state(r)?;
+        self.lcd.set_opri_writable(self.boot_rom_enabled);
+        self.timer.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.intflags = r.get_u8()?;
+        self.wram_banksel = r.get_u8()?;
+        self.vramdma_src = r.get_u16()?;
+        self.vramdma_dest = r.get_u16()?;
+        let vramdma_active = r.get_bool()?;
+        let vramdma_len = r.get_u8()?;
+        self.vramdma_len = vramdma_active.then_some(vramdma_len);
+        self.vramdma_hb_seen = r.get_bool()?;
+        self.oamdma_start = r.get_usize()?;
+        self.oamdma_ticks = r.get_usize()?;
+        self.oamdma_addr = r.get_u16()?;
+        self.serial.load_state(r)?;
+        self.ir.load_state(r)?;
+        if r.get_bool()? != self.sgb.is_some() {
+            bail!("Savestate SGB mode does not match");
+        }
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.load_state(r)?;
+            if let Some(palette) = sgb.take_palette() {
+                self.lcd.set_dmg_palette(palette);
+            }
+        }
+        self.double_speed = r.get_bool()?;
+        self.key0 = r.get_u8()?;
+        self.dmg_compat = r.get_bool()?;
+        self.lcd.set_cgb_mode(self.is_cgb_mode());
+        self.lcd.set_dmg_on_cgb(self.dmg_compat);
+        r.get_slice(&mut self.undocumented)?;
+        self.clock.load_state(r)?;
+        Ok(())
     }
 }
 
@@ -525,35 +913,61 @@ impl fmt::Display for Gameboybus {
 mod tests {
     use super::*;
     use crate::display::display::NullDisplay;
+    use crate::gameboy::bus::profiler::ProfilerBus;
+    use crate::gameboy::bus::testbus::Testbus;
+    use crate::gameboy::cartridge::cartridge::{self, CartridgeType, CARTTYPE_OFFSET};
     use crate::gameboy::cartridge::romonly::RomOnly;
+    use crate::gameboy::cpu::cpu::CPU;
     use crate::gameboy::lcd::LCDController;
     use crate::input::input::NullInput;
 
     use num_traits::ToPrimitive;
 
-    fn gbbus() -> Gameboybus {
-        let cart: Rc<RefCell<dyn Cartridge>> =
-            Rc::new(RefCell::new(RomOnly::new(&[0xAA_u8; 32 * 1024])));
-        let lcd = LCDController::new(Box::new(NullDisplay::new()), false);
+    fn gbbus_model(model: Model) -> Gameboybus {
+        let cart = Box::new(RomOnly::new(&[0xAA_u8; 32 * 1024]));
+        let lcd = LCDController::new(Box::new(NullDisplay::new()), model);
         let input = Box::new(NullInput::new());
-        Gameboybus::new(Rc::clone(&cart), None, lcd, input, false)
+        Gameboybus::new(cart, None, lcd, input, model)
+    }
+
+    fn gbbus() -> Gameboybus {
+        gbbus_model(Model::Dmg)
     }
 
     fn gbbus_cgb() -> Gameboybus {
-        let cart: Rc<RefCell<dyn Cartridge>> =
-            Rc::new(RefCell::new(RomOnly::new(&[0xAA_u8; 32 * 1024])));
-        let lcd = LCDController::new(Box::new(NullDisplay::new()), false);
-        let input = Box::new(NullInput::new());
-        Gameboybus::new(Rc::clone(&cart), None, lcd, input, true)
+        gbbus_model(Model::Cgb)
+    }
+
+    fn gbbus_sgb() -> Gameboybus {
+        gbbus_model(Model::Sgb)
+    }
+
+    /// Sends an SGB packet through P1
+    fn send_sgb_packet(b: &mut Gameboybus, packet: &[u8; 16]) {
+        b.write(0xFF00, 0x00);
+        b.write(0xFF00, 0x30);
+        for bit in (0..128).map(|i| packet[i / 8] & (1 << (i % 8)) != 0) {
+            b.write(0xFF00, if bit { 0x10 } else { 0x20 });
+            b.write(0xFF00, 0x30);
+        }
+        b.write(0xFF00, 0x20);
+        b.write(0xFF00, 0x30);
     }
 
     fn gbbus_bootrom() -> Gameboybus {
-        let cart: Rc<RefCell<dyn Cartridge>> =
-            Rc::new(RefCell::new(RomOnly::new(&[0xAA_u8; 32 * 1024])));
-        let lcd = LCDController::new(Box::new(NullDisplay::new()), false);
+        let cart = Box::new(RomOnly::new(&[0xAA_u8; 32 * 1024]));
+        let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
         let bootrom = [0xBB_u8; 256];
         let input = Box::new(NullInput::new());
-        Gameboybus::new(Rc::clone(&cart), Some(&bootrom), lcd, input, false)
+        Gameboybus::new(cart, Some(&bootrom), lcd, input, Model::Dmg)
+    }
+
+    fn gbbus_cgb_bootrom() -> Gameboybus {
+        let cart = Box::new(RomOnly::new(&[0xAA_u8; 32 * 1024]));
+        let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        let bootrom = [0xBB_u8; BOOTROM_SIZE_CGB];
+        let input = Box::new(NullInput::new());
+        Gameboybus::new(cart, Some(&bootrom), lcd, input, Model::Cgb)
     }
 
     #[test]
@@ -601,6 +1015,23 @@ mod tests {
         assert_eq!(b.read(0x0100), 0xAA);
     }
 
+    #[test]
+    fn bootrom_savestate() {
+        let mut b = gbbus_bootrom();
+        let mut w = StateWriter::new();
+        b.save_state(&mut w);
+        let state = w.into_vec();
+
+        b.write(0xFF50, 1); // Boot ROM disable
+        assert_eq!(b.read(0x0000), 0xAA);
+
+        // Restoring the state maps the boot ROM again
+        let mut r = StateReader::new(&state).unwrap();
+        b.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(b.read(0x0000), 0xBB);
+    }
+
     #[test]
     fn wram() {
         for b in 0xC000..=0xDFFF {
@@ -703,6 +1134,198 @@ mod tests {
         assert_eq!(b.read(0xD001), 0xEF);
     }
 
+    #[test]
+    fn unusable_dmg() {
+        let mut b = gbbus();
+        b.write(0xFEA0, 0x12);
+        // LCD off
+        b.write(0xFF40, 0);
+        for addr in 0xFEA0..=0xFEFF {
+            assert_eq!(b.read(addr), 0x00);
+        }
+
+        // Blocked during OAM search
+        b.write(0xFF40, 0x80);
+        assert_eq!(b.get_lcd().get_stat_mode(), LCDStatMode::Search);
+        assert_eq!(b.read(0xFEA0), 0xFF);
+        assert_eq!(b.peek(0xFEFF), 0xFF);
+        while b.get_lcd().get_stat_mode() != LCDStatMode::HBlank {
+            b.tick(Ticks::from_t(4)).unwrap();
+        }
+        assert_eq!(b.read(0xFEA0), 0x00);
+    }
+
+    #[test]
+    fn dmg_ignores_cgb_registers() {
+        let mut b = gbbus();
+        b.write(0xD000, 0x12);
+        for addr in [
+            0xFF4D, 0xFF4F, 0xFF51, 0xFF52, 0xFF53, 0xFF54, 0xFF55, 0xFF56, 0xFF68, 0xFF69, 0xFF6A,
+            0xFF6B, 0xFF70,
+        ] {
+            b.write(addr, 0x02);
+            assert_eq!(b.read(addr), 0xFF, "{:04X}", addr);
+        }
+
+        // No WRAM bank switch, no VRAM DMA
+        assert_eq!(b.read(0xD000), 0x12);
+        assert_eq!(b.vramdma_len, None);
+
+        let mut b = gbbus_cgb();
+        b.write(0xD000, 0x12);
+        b.write(0xFF70, 0x02);
+        assert_eq!(b.read(0xFF70), 0x02);
+        assert_ne!(b.read(0xD000), 0x12);
+        b.write(0xFF4F, 0x01);
+        assert_eq!(b.read(0xFF4F), 0x01);
+    }
+
+    #[test]
+    fn rom_patches() {
+        let mut b = gbbus();
+        b.patch_rom(0x4FFF, &[0x12, 0x34]).unwrap();
+        assert_eq!(b.read(0x4FFE), 0xAA);
+        assert_eq!(b.read(0x4FFF), 0x12);
+        assert_eq!(b.read(0x5000), 0x34);
+        assert_eq!(b.read(0x5001), 0xAA);
+        assert_eq!(b.rom_patches().len(), 2);
+
+        // Writes still go to the cartridge
+        b.write(0x4FFF, 0x00);
+        assert_eq!(b.read(0x4FFF), 0x12);
+
+        assert!(b.patch_rom(0x7FFF, &[0x00, 0x00]).is_err());
+        assert!(b.patch_rom(0x7FFF, &[0x00]).is_ok());
+
+        b.clear_rom_patches();
+        assert_eq!(b.read(0x4FFF), 0xAA);
+        assert_eq!(b.read(0x7FFF), 0xAA);
+        assert!(b.pages.iter().all(|&r| r != Region::PatchedRom));
+    }
+
+    #[test]
+    fn unusable_cgb() {
+        let mut b = gbbus_cgb();
+        b.write(0xFEA0, 0x12);
+        for addr in 0xFEA0..=0xFEFF {
+            let hi = (addr & 0xF0) as u8;
+            assert_eq!(b.read(addr), hi | (hi >> 4));
+        }
+        assert_eq!(b.read(0xFEA4), 0xAA);
+        assert_eq!(b.read(0xFEFF), 0xFF);
+        assert_eq!(b.peek(0xFEC7), 0xCC);
+    }
+
+    #[test]
+    fn unusable_models() {
+        for model in [Model::Dmg, Model::Mgb, Model::Sgb, Model::Cgb, Model::Agb] {
+            let mut b = gbbus_model(model);
+            // LCD off
+            b.write(0xFF40, 0);
+            let expected = if model.is_cgb() { 0xAA } else { 0x00 };
+            assert_eq!(b.read(0xFEA4), expected, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn undocumented_ff72_ff73() {
+        for model in [Model::Dmg, Model::Mgb, Model::Sgb, Model::Cgb, Model::Agb] {
+            let mut b = gbbus_model(model);
+            for addr in [0xFF72, 0xFF73] {
+                assert_eq!(b.read(addr), if model.is_cgb() { 0 } else { 0xFF });
+                b.write(addr, 0xA5);
+                let expected = if model.is_cgb() { 0xA5 } else { 0xFF };
+                assert_eq!(b.read(addr), expected, "{:?} {:04X}", model, addr);
+            }
+        }
+    }
+
+    #[test]
+    fn undocumented_ff74() {
+        let mut b = gbbus_cgb();
+        b.write(0xFF74, 0x5A);
+        assert_eq!(b.read(0xFF74), 0x5A);
+
+        let mut b = gbbus();
+        b.write(0xFF74, 0x5A);
+        assert_eq!(b.read(0xFF74), 0xFF);
+
+        // Locked in DMG compatibility mode
+        let mut b = gbbus_cgb_bootrom();
+        b.write(0xFF4C, KEY0_DMG_COMPAT);
+        b.write(0xFF50, 1);
+        b.write(0xFF74, 0x5A);
+        assert_eq!(b.read(0xFF74), 0xFF);
+    }
+
+    #[test]
+    fn undocumented_ff75() {
+        let mut b = gbbus_cgb();
+        assert_eq!(b.read(0xFF75), 0x8F);
+        b.write(0xFF75, 0xFF);
+        assert_eq!(b.read(0xFF75), 0xFF);
+        b.write(0xFF75, 0x50);
+        assert_eq!(b.read(0xFF75), 0xDF);
+
+        let mut b = gbbus();
+        b.write(0xFF75, 0x00);
+        assert_eq!(b.read(0xFF75), 0xFF);
+    }
+
+    #[test]
+    fn key0_dmg_compat() {
+        let mut b = gbbus_cgb_bootrom();
+        b.write(0xFF4C, KEY0_DMG_COMPAT);
+        assert_eq!(b.read(0xFF4C), KEY0_DMG_COMPAT);
+        assert!(!b.is_dmg_compat());
+
+        // Latched when the boot ROM is disabled
+        b.write(0xFF50, 1);
+        assert!(b.is_dmg_compat());
+        assert!(!b.is_cgb_mode());
+        assert!(!b.get_lcd().is_cgb());
+        assert!(b.get_lcd().is_dmg_on_cgb());
+
+        // CGB registers are no longer accessible
+        b.write(0xFF70, 4);
+        assert_eq!(b.read(0xFF70), 0xFF);
+        b.write(0xFF4F, 1);
+        assert_eq!(b.read(0xFF4F), 0xFF);
+
+        // KEY0 is locked
+        b.write(0xFF4C, 0x80);
+        assert_eq!(b.read(0xFF4C), 0xFF);
+        assert!(b.is_dmg_compat());
+
+        // The latched mode survives a savestate
+        let mut w = StateWriter::new();
+        b.save_state(&mut w);
+        let state = w.into_vec();
+        let mut b2 = gbbus_cgb_bootrom();
+        let mut r = StateReader::new(&state).unwrap();
+        b2.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert!(b2.is_dmg_compat());
+        assert!(!b2.get_lcd().is_cgb());
+        assert!(b2.get_lcd().is_dmg_on_cgb());
+    }
+
+    #[test]
+    fn key0_cgb_mode() {
+        let mut b = gbbus_cgb_bootrom();
+        b.write(0xFF4C, 0x80);
+        b.write(0xFF50, 1);
+        assert!(!b.is_dmg_compat());
+        assert!(b.is_cgb_mode());
+        assert!(b.get_lcd().is_cgb());
+
+        // Ignored on DMG
+        let mut b = gbbus_bootrom();
+        b.write(0xFF4C, KEY0_DMG_COMPAT);
+        b.write(0xFF50, 1);
+        assert!(!b.is_dmg_compat());
+    }
+
     #[test]
     fn cgb_echo_ram_read() {
         let mut b = gbbus_cgb();
@@ -1028,4 +1651,312 @@ mod tests {
         test(0x7F, 0x9000, 0x97FF);
         test(0x7F, 0x9800, 0x9FFF);
     }
+
+    #[test]
+    fn cartridge_save_mid_emulation() {
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x10D].copy_from_slice(&[
+            0x3E, 0x0A, // LD A,0Ah
+            0xEA, 0x00, 0x00, // LD (0000h),A - enable RAM
+            0x21, 0x00, 0xA0, // LD HL,A000h
+            0x3C, // INC A
+            0x22, // LD (HL+),A
+            0xC3, 0x08, 0x01, // JP 0108h
+        ]);
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1RamBat as u8;
+        rom[0x149] = 2;
+
+        let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        let bus = Box::new(Gameboybus::new(
+            cartridge::load(&rom).unwrap(),
+            None,
+            lcd,
+            Box::new(NullInput::new()),
+            Model::Dmg,
+        ));
+        let mut cpu = CPU::new(bus, Model::Dmg);
+
+        // 3 setup instructions + 3 instructions per byte
+        for _ in 0..(3 + 3 * 16) {
+            cpu.step().unwrap();
+        }
+        let save = Gameboybus::from_bus(&*cpu.bus)
+            .unwrap()
+            .cartridge()
+            .get_save();
+        assert_eq!(save[0..16], (0x0B..0x1B).collect::<Vec<u8>>());
+        assert_eq!(save[16], 0);
+
+        // Emulation continues after saving
+        for _ in 0..(3 * 16) {
+            cpu.step().unwrap();
+        }
+        let bus = Gameboybus::from_bus_mut(&mut *cpu.bus).unwrap();
+        assert_eq!(
+            bus.cartridge().get_save()[16..32],
+            (0x1B..0x2B).collect::<Vec<u8>>()
+        );
+
+        // Load a save through the accessor
+        bus.cartridge_mut().load_save(&[0x55; 4]);
+        assert_eq!(bus.read(0xA000), 0x55);
+        assert_eq!(bus.read(0xA004), 0x00);
+    }
+
+    fn tick_t(b: &mut Gameboybus, t: usize) {
+        for _ in 0..(t / ONE_MCYCLE) {
+            b.tick(Ticks::from_t(ONE_MCYCLE)).unwrap();
+        }
+    }
+
+    #[test]
+    fn div_write_clocks_apu_length() {
+        let mut b = gbbus();
+        b.write(0xFF04, 0);
+        tick_t(&mut b, 0x1000);
+
+        // Channel 1 with a length of 1
+        b.write(0xFF26, 0x80);
+        b.write(0xFF12, 0xF1);
+        b.write(0xFF11, 0x3F);
+        b.write(0xFF14, 0xC0);
+        assert_eq!(b.read(0xFF26) & 0x01, 0x01);
+
+        // DIV bit 12 is set, resetting DIV clocks the frame sequencer
+        b.write(0xFF04, 0);
+        assert_eq!(b.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn div_write_no_apu_clock() {
+        let mut b = gbbus();
+        b.write(0xFF04, 0);
+        tick_t(&mut b, 0x0FFC);
+
+        b.write(0xFF26, 0x80);
+        b.write(0xFF12, 0xF1);
+        b.write(0xFF11, 0x3F);
+        b.write(0xFF14, 0xC0);
+
+        // DIV bit 12 is not set yet
+        b.write(0xFF04, 0);
+        assert_eq!(b.read(0xFF26) & 0x01, 0x01);
+
+        // Falling edge of bit 12
+        tick_t(&mut b, 0x2000);
+        assert_eq!(b.read(0xFF26) & 0x01, 0x00);
+    }
+
+    /// Ticks the bus per M-cycle until an interrupt is raised in IF,
+    /// returns the interrupt requests returned by tick().
+    fn tick_until_int(b: &mut Gameboybus, int: u8) -> TickResult {
+        for _ in 0..(80000 / ONE_MCYCLE) {
+            let intreq = b.tick(Ticks::from_t(ONE_MCYCLE)).unwrap();
+            if b.read(0xFF0F) & int != 0 {
+                return intreq;
+            }
+        }
+        panic!("Interrupt {:02X} not raised", int);
+    }
+
+    #[test]
+    fn int_vblank() {
+        let mut b = gbbus();
+        b.write(0xFF40, 0x80);
+        b.write(0xFF0F, 0);
+        assert_eq!(tick_until_int(&mut b, cpu::INT_VBLANK), cpu::INT_VBLANK);
+    }
+
+    #[test]
+    fn int_stat() {
+        let mut b = gbbus_cgb();
+        b.write(0xFF40, 0x80);
+        b.write(0xFF41, 0x08); // HBlank
+        b.write(0xFF0F, 0);
+        assert_eq!(tick_until_int(&mut b, cpu::INT_LCDSTAT), cpu::INT_LCDSTAT);
+    }
+
+    #[test]
+    fn int_stat_write() {
+        // STAT write quirk on DMG raises an interrupt on write,
+        // which ends up in IF on the next tick.
+        let mut b = gbbus();
+        b.write(0xFF40, 0x80);
+        b.write(0xFF0F, 0);
+        b.write(0xFF41, 0);
+        assert_eq!(b.read(0xFF0F) & cpu::INT_LCDSTAT, 0);
+        let intreq = b.tick(Ticks::from_t(ONE_MCYCLE)).unwrap();
+        assert_eq!(intreq & cpu::INT_LCDSTAT, cpu::INT_LCDSTAT);
+        assert_eq!(b.read(0xFF0F) & cpu::INT_LCDSTAT, cpu::INT_LCDSTAT);
+    }
+
+    #[test]
+    fn int_timer() {
+        let mut b = gbbus();
+        b.write(0xFF07, 0x05);
+        b.write(0xFF05, 0xFF);
+        b.write(0xFF0F, 0);
+        assert_eq!(tick_until_int(&mut b, cpu::INT_TIMER), cpu::INT_TIMER);
+    }
+
+    #[test]
+    fn int_serial() {
+        let mut b = gbbus();
+        b.write(0xFF0F, 0);
+        b.write(0xFF01, 0x12);
+        b.write(0xFF02, 0x81);
+        assert_eq!(tick_until_int(&mut b, cpu::INT_SERIAL), cpu::INT_SERIAL);
+        assert_eq!(b.read(0xFF01), 0xFF);
+    }
+
+    #[test]
+    fn display_lcd_state() {
+        let mut b = gbbus();
+        b.write(0xFF40, 0x80);
+        let s = b.to_string();
+        assert!(s.contains("LCD:Search LY:0"), "{}", s);
+    }
+
+    #[test]
+    fn ly_override() {
+        let mut b = gbbus();
+        b.write(0xFF40, 0x80);
+        assert_eq!(b.read(0xFF44), 0);
+        b.set_ly_override(Some(0x90));
+        assert_eq!(b.read(0xFF44), 0x90);
+        assert_eq!(b.get_lcd().read(0xFF44), 0);
+        b.set_ly_override(None);
+        assert_eq!(b.read(0xFF44), 0);
+    }
+
+    #[test]
+    fn peek_during_oam_dma() {
+        let mut b = gbbus();
+        b.write(0xC000, 0x42);
+        b.write(0xC001, 0x43);
+        b.write(0xFF46, 0xC0);
+        b.tick(Ticks::from_t(2 * ONE_MCYCLE)).unwrap();
+        assert_eq!(b.read(0xC000), 0xFF);
+        assert_eq!(b.peek(0xC000), 0x42);
+        assert_eq!(b.peek16(0xC000), 0x4342);
+        // HRAM is not blocked
+        b.write(0xFF80, 0x12);
+        assert_eq!(b.read(0xFF80), b.peek(0xFF80));
+    }
+
+    #[test]
+    fn peek_io_cgb() {
+        let b = gbbus_cgb();
+        for addr in 0xFF00..=0xFFFF {
+            b.peek(addr);
+        }
+        assert_eq!(b.peek(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn ir_dmg() {
+        let mut b = gbbus();
+        b.write(0xFF56, 0xC1);
+        assert_eq!(b.read(0xFF56), 0xFF);
+    }
+
+    #[test]
+    fn ir_loopback() {
+        let (ta, tb) = crate::gameboy::infrared::crossed_transports();
+        let mut a = gbbus_cgb();
+        let mut b = gbbus_cgb();
+        a.set_ir_transport(Box::new(ta));
+        b.set_ir_transport(Box::new(tb));
+
+        // LED on, but receiver of B not enabled
+        a.write(0xFF56, 0x01);
+        assert_eq!(b.read(0xFF56) & 0x02, 0x02);
+        b.write(0xFF56, 0x80);
+        assert_eq!(b.read(0xFF56) & 0x02, 0x02);
+        b.write(0xFF56, 0xC0);
+        assert_eq!(b.read(0xFF56) & 0x02, 0x00);
+
+        // LED off
+        a.write(0xFF56, 0x00);
+        assert_eq!(b.read(0xFF56) & 0x02, 0x02);
+
+        // And the other way around
+        assert_eq!(a.read(0xFF56) & 0x02, 0x02);
+        a.write(0xFF56, 0xC0);
+        b.write(0xFF56, 0xC1);
+        assert_eq!(a.read(0xFF56) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn sgb_multiplayer() {
+        let mut mlt_req = [0; 16];
+        mlt_req[0] = 0x89;
+        mlt_req[1] = 0x01;
+
+        let mut b = gbbus_sgb();
+        send_sgb_packet(&mut b, &mlt_req);
+        assert_eq!(b.read(0xFF00), 0xFF);
+        b.write(0xFF00, 0x10);
+        b.write(0xFF00, 0x30);
+        assert_eq!(b.read(0xFF00), 0xFE);
+
+        // Not on DMG
+        let mut b = gbbus();
+        send_sgb_packet(&mut b, &mlt_req);
+        b.write(0xFF00, 0x10);
+        b.write(0xFF00, 0x30);
+        assert_eq!(b.read(0xFF00), 0xFF);
+    }
+
+    #[test]
+    fn sgb_palette() {
+        let mut pal01 = [0; 16];
+        pal01[0] = 0x01;
+        pal01[1..9].copy_from_slice(&[0x11, 0x00, 0x22, 0x00, 0x33, 0x00, 0x44, 0x00]);
+
+        let mut b = gbbus_sgb();
+        b.write(0xFF47, 0xE4);
+        send_sgb_packet(&mut b, &pal01);
+        assert_eq!(b.get_lcd().get_bg_palette(0), [0x11, 0x22, 0x33, 0x44]);
+
+        // Restored from a savestate
+        let mut w = StateWriter::new();
+        b.save_state(&mut w);
+        let state = w.into_vec();
+        let mut b = gbbus_sgb();
+        b.write(0xFF47, 0xE4);
+        b.load_state(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert_eq!(b.get_lcd().get_bg_palette(0), [0x11, 0x22, 0x33, 0x44]);
+
+        // Not into a DMG
+        assert!(gbbus()
+            .load_state(&mut StateReader::new(&state).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn from_bus() {
+        let mut bus: Box<dyn Bus> = Box::new(gbbus());
+        bus.write(0xFF0F, 0x01);
+        let b = Gameboybus::from_bus(&*bus).unwrap();
+        assert_eq!(b.cartridge().current_rom_bank(), 1);
+        assert_eq!(b.get_lcd().get_frame_count(), 0);
+        Gameboybus::from_bus_mut(&mut *bus)
+            .unwrap()
+            .write(0xFFFF, 0x1F);
+        assert_eq!(bus.read(0xFFFF), 0x1F);
+
+        // Through a wrapper
+        let mut bus: Box<dyn Bus> = Box::new(ProfilerBus::new(bus, 1));
+        assert_eq!(
+            Gameboybus::from_bus(&*bus).unwrap().read(0xFF0F) & 0x1F,
+            0x01
+        );
+        assert!(Gameboybus::from_bus_mut(&mut *bus).is_some());
+
+        let mut bus: Box<dyn Bus> = Box::new(Testbus::new());
+        assert!(Gameboybus::from_bus(&*bus).is_none());
+        assert!(Gameboybus::from_bus_mut(&mut *bus).is_none());
+    }
 }
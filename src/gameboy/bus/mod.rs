@@ -1,3 +1,5 @@
 pub mod bus;
+pub mod coverage;
 pub mod gbbus;
+pub mod profiler;
 pub mod testbus;
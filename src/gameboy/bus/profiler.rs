@@ -0,0 +1,315 @@
+use anyhow::Result;
+
+use super::bus::{Bus, BusMember};
+use super::gbbus::Gameboybus;
+use crate::tickable::{TickResult, Tickable, Ticks};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Default size of a bucket of addresses (in bytes)
+pub const DEFAULT_BUCKET_SIZE: u16 = 16;
+
+/// Access counters of a bucket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    pub reads: u64,
+    pub writes: u64,
+    /// Instruction fetches (not counted as reads)
+    pub fetches: u64,
+}
+
+impl Counters {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes + self.fetches
+    }
+}
+
+/// Counters of a single bucket in a report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileEntry {
+    /// ROM bank, for the switchable ROM bank (0x4000 - 0x7FFF) only
+    pub bank: Option<usize>,
+    /// First address of the bucket
+    pub addr: u16,
+    pub counters: Counters,
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+    Fetch,
+}
+
+/// Memory access counters per bucket of addresses
+pub struct Profile {
+    bucket_size: u16,
+    counters: HashMap<(Option<usize>, u16), Counters>,
+}
+
+impl Profile {
+    /// Creates an empty profile. The bucket size has to be a power
+    /// of two, use 1 for per-byte counters.
+    pub fn new(bucket_size: u16) -> Self {
+        assert!(bucket_size.is_power_of_two());
+        Self {
+            bucket_size,
+            counters: HashMap::new(),
+        }
+    }
+
+    fn count(&mut self, bank: Option<usize>, addr: u16, access: Access) {
+        let bucket = addr & !(self.bucket_size - 1);
+        let c = self.counters.entry((bank, bucket)).or_default();
+        match access {
+            Access::Read => c.reads += 1,
+            Access::Write => c.writes += 1,
+            Access::Fetch => c.fetches += 1,
+        }
+    }
+
+    /// Returns the counters of all accessed buckets, hottest first
+    pub fn report(&self) -> Vec<ProfileEntry> {
+        let mut report: Vec<ProfileEntry> = self
+            .counters
+            .iter()
+            .map(|(&(bank, addr), &counters)| ProfileEntry {
+                bank,
+                addr,
+                counters,
+            })
+            .collect();
+        report.sort_by(|a, b| {
+            b.counters
+                .total()
+                .cmp(&a.counters.total())
+                .then((a.addr, a.bank).cmp(&(b.addr, b.bank)))
+        });
+        report
+    }
+
+    /// Writes the report as CSV, hottest first
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("bank,address,reads,writes,fetches\n");
+        for e in self.report() {
+            let bank = e.bank.map(|b| b.to_string()).unwrap_or_default();
+            writeln!(
+                out,
+                "{},{:04X},{},{},{}",
+                bank, e.addr, e.counters.reads, e.counters.writes, e.counters.fetches
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Shared handle to a profile being collected
+pub type ProfileHandle = Arc<Mutex<Profile>>;
+
+/// Bus wrapper that counts reads, writes and instruction fetches
+/// per bucket of addresses.
+pub struct ProfilerBus {
+    inner: Box<dyn Bus>,
+    profile: ProfileHandle,
+}
+
+impl ProfilerBus {
+    pub fn new(inner: Box<dyn Bus>, bucket_size: u16) -> Self {
+        Self::with_handle(inner, Arc::new(Mutex::new(Profile::new(bucket_size))))
+    }
+
+    /// Collects into an existing profile handle (e.g. when the bus is
+    /// created on the emulation thread)
+    pub fn with_handle(inner: Box<dyn Bus>, profile: ProfileHandle) -> Self {
+        Self { inner, profile }
+    }
+
+    pub fn handle(&self) -> ProfileHandle {
+        Arc::clone(&self.profile)
+    }
+
+    pub fn report(&self) -> Vec<ProfileEntry> {
+        self.profile.lock().unwrap().report()
+    }
+
+    fn count(&self, addr: u16, access: Access) {
+        // Switchable ROM banks are told apart, if there is a cartridge
+        let bank = if (0x4000..0x8000).contains(&addr) {
+            Gameboybus::from_bus(&*self.inner).map(|b| b.cartridge().current_rom_bank())
+        } else {
+            None
+        };
+        self.profile.lock().unwrap().count(bank, addr, access);
+    }
+}
+
+impl Bus for ProfilerBus {
+    fn inner(&self) -> Option<&dyn Bus> {
+        Some(self.inner.as_ref())
+    }
+
+    fn inner_mut(&mut self) -> Option<&mut dyn Bus> {
+        Some(self.inner.as_mut())
+    }
+}
+
+impl BusMember for ProfilerBus {
+    fn read(&self, addr: u16) -> u8 {
+        self.count(addr, Access::Read);
+        self.inner.read(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.count(addr, Access::Write);
+        self.inner.write(addr, val)
+    }
+
+    fn fetch(&self, addr: u16) -> u8 {
+        self.count(addr, Access::Fetch);
+        self.inner.fetch(addr)
+    }
+
+    /// Not counted
+    fn peek(&self, addr: u16) -> u8 {
+        self.inner.peek(addr)
+    }
+}
+
+impl Tickable for ProfilerBus {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
+        self.inner.tick(ticks)
+    }
+}
+
+impl fmt::Display for ProfilerBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testbus::Testbus;
+    use super::*;
+    use crate::display::display::NullDisplay;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::cpu::cpu::CPU;
+    use crate::gameboy::cpu::regs::Register;
+    use crate::gameboy::lcd::LCDController;
+    use crate::gameboy::model::Model;
+    use crate::input::input::NullInput;
+
+    fn counters(report: &[ProfileEntry], bank: Option<usize>, addr: u16) -> Counters {
+        report
+            .iter()
+            .find(|e| e.bank == bank && e.addr == addr)
+            .map(|e| e.counters)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn loop_fetches() {
+        // 0000: LD B,10
+        // 0002: DEC B
+        // 0003: JR NZ,-3
+        // 0005: LD (HL),B
+        // 0006: JR -2
+        let bus = Testbus::from(&[0x06, 10, 0x05, 0x20, 0xFD, 0x70, 0x18, 0xFE]);
+        let mut cpu = CPU::new(Box::new(ProfilerBus::new(Box::new(bus), 1)), Model::Dmg);
+        cpu.regs.write(Register::HL, 0xC000).unwrap();
+        for _ in 0..(1 + 10 * 2 + 1) {
+            cpu.step().unwrap();
+        }
+
+        let report = cpu.bus.downcast_ref::<ProfilerBus>().unwrap().report();
+        assert_eq!(counters(&report, None, 0x0000).fetches, 1);
+        for addr in 0x0002..=0x0004 {
+            assert_eq!(
+                counters(&report, None, addr),
+                Counters {
+                    reads: 0,
+                    writes: 0,
+                    fetches: 10
+                }
+            );
+        }
+        assert_eq!(counters(&report, None, 0x0005).fetches, 1);
+        assert_eq!(counters(&report, None, 0xC000).writes, 1);
+        // Hottest first
+        assert_eq!(report[0].counters.fetches, 10);
+        assert!(report
+            .windows(2)
+            .all(|w| w[0].counters.total() >= w[1].counters.total()));
+    }
+
+    #[test]
+    fn buckets() {
+        let mut bus = ProfilerBus::new(Box::new(Testbus::new()), DEFAULT_BUCKET_SIZE);
+        bus.read(0xC000);
+        bus.read(0xC00F);
+        bus.peek(0xC000);
+        bus.write(0xC010, 1);
+        assert_eq!(
+            bus.report(),
+            vec![
+                ProfileEntry {
+                    bank: None,
+                    addr: 0xC000,
+                    counters: Counters {
+                        reads: 2,
+                        writes: 0,
+                        fetches: 0
+                    }
+                },
+                ProfileEntry {
+                    bank: None,
+                    addr: 0xC010,
+                    counters: Counters {
+                        reads: 0,
+                        writes: 1,
+                        fetches: 0
+                    }
+                },
+            ]
+        );
+        assert_eq!(
+            bus.handle().lock().unwrap().to_csv(),
+            "bank,address,reads,writes,fetches\n,C000,2,0,0\n,C010,0,1,0\n"
+        );
+    }
+
+    #[test]
+    fn rom_banks() {
+        // MBC1, 64KB
+        let mut rom = vec![0; 64 * 1024];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        let gbbus = Gameboybus::new(
+            cartridge::load(&rom).unwrap(),
+            None,
+            lcd,
+            Box::new(NullInput::new()),
+            Model::Dmg,
+        );
+        let mut bus: Box<dyn Bus> = Box::new(ProfilerBus::new(Box::new(gbbus), 1));
+        bus.read(0x4000);
+        bus.write(0x2000, 2);
+        bus.read(0x4000);
+        bus.read(0x4000);
+
+        let report = bus.downcast_ref::<ProfilerBus>().unwrap().report();
+        assert_eq!(counters(&report, Some(1), 0x4000).reads, 1);
+        assert_eq!(counters(&report, Some(2), 0x4000).reads, 2);
+        assert_eq!(counters(&report, None, 0x2000).writes, 1);
+
+        // The wrapped bus can still be found
+        assert!(bus.find::<Gameboybus>().is_some());
+        assert!(bus.find_mut::<Gameboybus>().is_some());
+        assert!(bus.find::<Testbus>().is_none());
+    }
+}
@@ -1,10 +1,11 @@
 use anyhow::Result;
 
 use super::bus::{Bus, BusMember};
-use crate::tickable::{Tickable, Ticks};
+use crate::tickable::{TickResult, Tickable, Ticks};
 
 use std::cell::RefCell;
 use std::fmt;
+use std::ops::RangeInclusive;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Access {
@@ -20,21 +21,58 @@ pub struct TraceEntry {
     pub cycle: usize,
 }
 
+/// Initial contents of memory
+#[derive(Copy, Clone, Debug)]
+pub enum Fill {
+    /// All bytes set to a value
+    Value(u8),
+    /// Pseudo-random, repeatable for the same seed
+    Random(u32),
+}
+
 pub struct Testbus {
     mem: [u8; u16::MAX as usize + 1],
     trace: RefCell<Vec<TraceEntry>>,
     cycles: usize,
     trace_enabled: bool,
+
+    /// Read-only address ranges
+    read_only: Vec<RangeInclusive<u16>>,
+    /// Panic on writes to read-only ranges, rather than recording them
+    panic_on_violation: bool,
+    /// Writes to read-only ranges
+    violations: Vec<TraceEntry>,
 }
 
 impl Testbus {
     pub fn new() -> Self {
-        Testbus {
+        Self::new_filled(Fill::Value(0))
+    }
+
+    pub fn new_filled(fill: Fill) -> Self {
+        let mut ret = Testbus {
             mem: [0; u16::MAX as usize + 1],
             trace: RefCell::new(vec![]),
             cycles: 0,
             trace_enabled: false,
+            read_only: vec![],
+            panic_on_violation: false,
+            violations: vec![],
+        };
+        match fill {
+            Fill::Value(val) => ret.mem.fill(val),
+            Fill::Random(seed) => {
+                // xorshift32, which gets stuck at 0
+                let mut state = seed.max(1);
+                for b in ret.mem.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *b = state as u8;
+                }
+            }
         }
+        ret
     }
 
     pub fn from(data: &[u8]) -> Self {
@@ -43,6 +81,31 @@ impl Testbus {
         ret
     }
 
+    /// Creates a bus with data at the specified addresses,
+    /// the rest of memory is zero.
+    pub fn from_segments(segments: &[(u16, &[u8])]) -> Self {
+        let mut ret = Testbus::new();
+        for (addr, data) in segments {
+            ret.write_slice(data, *addr);
+        }
+        ret
+    }
+
+    /// Marks an address range read-only. Writes to it are ignored and
+    /// recorded as violations (see get_violations()), or panic if
+    /// set_panic_on_violation() is enabled.
+    pub fn set_read_only(&mut self, range: RangeInclusive<u16>) {
+        self.read_only.push(range);
+    }
+
+    pub fn set_panic_on_violation(&mut self, panic: bool) {
+        self.panic_on_violation = panic;
+    }
+
+    pub fn get_violations(&self) -> &[TraceEntry] {
+        &self.violations
+    }
+
     pub fn reset_trace(&mut self) {
         self.trace.borrow_mut().clear();
         self.trace_enabled = true;
@@ -78,14 +141,26 @@ impl BusMember for Testbus {
                 cycle: self.cycles,
             });
         }
+        if self.read_only.iter().any(|r| r.contains(&addr)) {
+            if self.panic_on_violation {
+                panic!("Write to read-only address {:04X}: {:02X}", addr, val);
+            }
+            self.violations.push(TraceEntry {
+                addr,
+                access: Access::Write,
+                val,
+                cycle: self.cycles,
+            });
+            return;
+        }
         self.mem[addr as usize] = val;
     }
 }
 
 impl Tickable for Testbus {
-    fn tick(&mut self, ticks: Ticks) -> Result<()> {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
         self.cycles += ticks.get_t_ds();
-        Ok(())
+        Ok(0)
     }
 }
 
@@ -113,4 +188,55 @@ mod tests {
             assert_eq!(b.read(a), a as u8);
         }
     }
+
+    #[test]
+    fn fill() {
+        let b = Testbus::new_filled(Fill::Value(0xAA));
+        assert!((0..=u16::MAX).all(|a| b.read(a) == 0xAA));
+
+        let b = Testbus::new_filled(Fill::Random(1234));
+        let b2 = Testbus::new_filled(Fill::Random(1234));
+        let b3 = Testbus::new_filled(Fill::Random(4321));
+        assert!((0..=u16::MAX).all(|a| b.read(a) == b2.read(a)));
+        assert!((0..=u16::MAX).any(|a| b.read(a) != b3.read(a)));
+        // Every value appears
+        for v in 0..=u8::MAX {
+            assert!((0..=u16::MAX).any(|a| b.read(a) == v));
+        }
+    }
+
+    #[test]
+    fn segments() {
+        let b = Testbus::from_segments(&[(0x0100, &[1, 2]), (0xC000, &[3])]);
+        assert_eq!(b.read16(0x0100), 0x0201);
+        assert_eq!(b.read(0xC000), 3);
+        assert_eq!(b.read(0x0000), 0);
+        assert_eq!(b.read(0xC001), 0);
+    }
+
+    #[test]
+    fn read_only() {
+        let mut b = Testbus::from(&[0x12]);
+        b.set_read_only(0x0000..=0x7FFF);
+        b.write(0x0000, 0x34);
+        b.write(0x8000, 0x56);
+        assert_eq!(b.read(0x0000), 0x12);
+        assert_eq!(b.read(0x8000), 0x56);
+
+        let v = b.get_violations();
+        assert_eq!(v.len(), 1);
+        assert_eq!(
+            (v[0].addr, v[0].access, v[0].val),
+            (0x0000, Access::Write, 0x34)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Write to read-only address 7FFF: 34")]
+    fn read_only_panic() {
+        let mut b = Testbus::new();
+        b.set_read_only(0x0000..=0x7FFF);
+        b.set_panic_on_violation(true);
+        b.write(0x7FFF, 0x34);
+    }
 }
@@ -0,0 +1,345 @@
+use anyhow::{bail, Context, Result};
+
+use super::inflate::{crc32, inflate};
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+const ZIP_LOCAL_HEADER: u32 = 0x04034B50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014B50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x06054B50;
+
+const GZIP_FHCRC: u8 = 1 << 1;
+const GZIP_FEXTRA: u8 = 1 << 2;
+const GZIP_FNAME: u8 = 1 << 3;
+const GZIP_FCOMMENT: u8 = 1 << 4;
+
+/// File extensions of ROMs in archives
+const ROM_EXTENSIONS: [&str; 2] = [".gb", ".gbc"];
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(&ZIP_MAGIC)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    let b = data
+        .get(offset..offset + 2)
+        .context("Unexpected end of archive")?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    let b = data
+        .get(offset..offset + 4)
+        .context("Unexpected end of archive")?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Decompresses a gzip file
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    if !is_gzip(data) {
+        bail!("Not a gzip file");
+    }
+    if data.get(2) != Some(&8) {
+        bail!("Unsupported gzip compression method");
+    }
+    let flags = *data.get(3).context("Unexpected end of archive")?;
+
+    let mut pos = 10;
+    if flags & GZIP_FEXTRA != 0 {
+        pos += 2 + u16_at(data, pos)? as usize;
+    }
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            // Zero terminated string
+            let len = data
+                .get(pos..)
+                .and_then(|d| d.iter().position(|&c| c == 0))
+                .context("Unexpected end of archive")?;
+            pos += len + 1;
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        pos += 2;
+    }
+
+    let (out, len) = inflate(data.get(pos..).context("Unexpected end of archive")?)?;
+    let crc = u32_at(data, pos + len)?;
+    let size = u32_at(data, pos + len + 4)?;
+    if crc != crc32(&out) || size != out.len() as u32 {
+        bail!("gzip checksum mismatch");
+    }
+    Ok(out)
+}
+
+/// Entry in the central directory of a zip file
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    size: usize,
+    offset: usize,
+}
+
+impl ZipEntry {
+    fn is_rom(&self) -> bool {
+        let name = self.name.to_lowercase();
+        ROM_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+    }
+
+    fn extract(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if u32_at(data, self.offset)? != ZIP_LOCAL_HEADER {
+            bail!("Invalid local header for {}", self.name);
+        }
+        let start = self.offset
+            + 30
+            + u16_at(data, self.offset + 26)? as usize
+            + u16_at(data, self.offset + 28)? as usize;
+        let compressed = data
+            .get(start..start + self.compressed_size)
+            .context("Unexpected end of archive")?;
+
+        let out = match self.method {
+            0 => compressed.to_vec(),
+            8 => inflate(compressed)?.0,
+            m => bail!("Unsupported compression method {} for {}", m, self.name),
+        };
+        if out.len() != self.size || crc32(&out) != self.crc {
+            bail!("Checksum mismatch for {}", self.name);
+        }
+        Ok(out)
+    }
+}
+
+/// Reads the central directory of a zip file
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>> {
+    // The end of central directory record is at the end of the file,
+    // followed by a comment of up to 64K.
+    let eocd = (0..data.len().saturating_sub(21))
+        .rev()
+        .take(22 + u16::MAX as usize)
+        .find(|&i| u32_at(data, i).ok() == Some(ZIP_END_OF_DIRECTORY))
+        .context("Zip end of central directory not found")?;
+    let count = u16_at(data, eocd + 10)? as usize;
+    let mut pos = u32_at(data, eocd + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(data, pos)? != ZIP_CENTRAL_HEADER {
+            bail!("Invalid zip central directory");
+        }
+        let name_len = u16_at(data, pos + 28)? as usize;
+        let name = data
+            .get(pos + 46..pos + 46 + name_len)
+            .context("Unexpected end of archive")?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            method: u16_at(data, pos + 10)?,
+            crc: u32_at(data, pos + 16)?,
+            compressed_size: u32_at(data, pos + 20)? as usize,
+            size: u32_at(data, pos + 24)? as usize,
+            offset: u32_at(data, pos + 42)? as usize,
+        });
+        pos += 46 + name_len + u16_at(data, pos + 30)? as usize + u16_at(data, pos + 32)? as usize;
+    }
+    Ok(entries)
+}
+
+/// Lists the names of the entries in a zip file
+pub fn zip_list(data: &[u8]) -> Result<Vec<String>> {
+    Ok(zip_entries(data)?.into_iter().map(|e| e.name).collect())
+}
+
+/// Extracts a ROM from a zip file. Without an entry name, the archive
+/// has to contain exactly one .gb/.gbc file.
+pub fn unzip_rom(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>> {
+    let entries = zip_entries(data)?;
+    let names = || {
+        entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    if let Some(name) = entry {
+        let Some(e) = entries.iter().find(|e| e.name == name) else {
+            bail!("Entry {} not found in archive, entries: {}", name, names());
+        };
+        return e.extract(data);
+    }
+
+    let roms: Vec<&ZipEntry> = entries.iter().filter(|e| e.is_rom()).collect();
+    match roms[..] {
+        [rom] => rom.extract(data),
+        [] => bail!("No ROM found in archive, entries: {}", names()),
+        _ => bail!(
+            "Multiple ROMs in archive, select one with --rom-entry: {}",
+            roms.iter()
+                .map(|e| e.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Decompresses a ROM if it is a gzip or zip file, returns other data as-is
+pub fn decompress(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>> {
+    if is_gzip(&data) {
+        gunzip(&data)
+    } else if is_zip(&data) {
+        unzip_rom(&data, entry)
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cartridge::cartridge;
+
+    use hex_literal::hex;
+
+    fn rom(title: &str) -> Vec<u8> {
+        let mut rom = vec![0; 32 * 1024];
+        rom[cartridge::TITLE_OFFSET..][..title.len()].copy_from_slice(title.as_bytes());
+        rom
+    }
+
+    /// DEFLATE stream of stored blocks
+    fn deflate_stored(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let chunks: Vec<&[u8]> = data.chunks(u16::MAX as usize).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            out.push(if i == chunks.len() - 1 { 1 } else { 0 });
+            out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1F, 0x8B, 8, GZIP_FNAME, 0, 0, 0, 0, 0, 0xFF];
+        out.extend_from_slice(b"rom.gb\0");
+        out.extend(deflate_stored(data));
+        out.extend_from_slice(&crc32(data).to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut directory = vec![];
+        for (name, data) in files {
+            let compressed = deflate_stored(data);
+            let mut header = vec![];
+            header.extend_from_slice(&20u16.to_le_bytes()); // version
+            header.extend_from_slice(&0u16.to_le_bytes()); // flags
+            header.extend_from_slice(&8u16.to_le_bytes()); // method
+            header.extend_from_slice(&0u32.to_le_bytes()); // time, date
+            header.extend_from_slice(&crc32(data).to_le_bytes());
+            header.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes()); // extra
+
+            directory.extend_from_slice(&ZIP_CENTRAL_HEADER.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes()); // made by
+            directory.extend_from_slice(&header);
+            directory.extend_from_slice(&[0; 10]); // comment, disk, attributes
+            directory.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(&ZIP_LOCAL_HEADER.to_le_bytes());
+            out.extend_from_slice(&header);
+            out.extend_from_slice(name.as_bytes());
+            out.extend(compressed);
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&directory);
+        out.extend_from_slice(&ZIP_END_OF_DIRECTORY.to_le_bytes());
+        out.extend_from_slice(&[0; 4]); // disks
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes()); // comment
+        out
+    }
+
+    fn banner(rom: &[u8]) -> String {
+        cartridge::load(rom).unwrap().to_string()
+    }
+
+    #[test]
+    fn gzip_stored() {
+        let rom = rom("GZTEST");
+        let out = decompress(gzip(&rom), None).unwrap();
+        assert_eq!(out, rom);
+        assert_eq!(banner(&out), banner(&rom));
+    }
+
+    #[test]
+    fn gzip_compressed() {
+        // gzip.compress() of rom("GZTEST")
+        let gz = hex!(
+            "1f8b0800000000000203edd0310d0030080030cc9109800b"
+            "ff42a68197b4121ac1d69bce6a0f00000000000000000000"
+            "000000000000000000000000000000000000000000977c08"
+            "1d3a8200800000"
+        );
+        let out = decompress(gz.to_vec(), None).unwrap();
+        assert_eq!(out, rom("GZTEST"));
+        assert!(banner(&out).starts_with("\"GZTEST\""));
+    }
+
+    #[test]
+    fn gzip_corrupt() {
+        let mut gz = gzip(&rom("GZTEST"));
+        let len = gz.len();
+        gz[len - 8] ^= 1;
+        assert!(decompress(gz, None).is_err());
+    }
+
+    #[test]
+    fn zip_single() {
+        let rom = rom("ZIPTEST");
+        let out = decompress(zip(&[("readme.txt", b"hi"), ("Game.GBC", &rom)]), None).unwrap();
+        assert_eq!(out, rom);
+        assert_eq!(banner(&out), banner(&rom));
+    }
+
+    #[test]
+    fn zip_multiple() {
+        let a = rom("A");
+        let b = rom("B");
+        let z = zip(&[("a.gb", &a), ("b.gb", &b)]);
+        assert_eq!(zip_list(&z).unwrap(), vec!["a.gb", "b.gb"]);
+
+        let err = decompress(z.clone(), None).unwrap_err().to_string();
+        assert!(err.contains("a.gb, b.gb"), "{}", err);
+        assert_eq!(decompress(z.clone(), Some("b.gb")).unwrap(), b);
+        assert!(decompress(z, Some("c.gb")).is_err());
+    }
+
+    #[test]
+    fn zip_no_rom() {
+        let err = decompress(zip(&[("readme.txt", b"hi")]), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("readme.txt"), "{}", err);
+    }
+
+    #[test]
+    fn plain() {
+        let rom = rom("PLAIN");
+        assert_eq!(decompress(rom.clone(), None).unwrap(), rom);
+    }
+}
@@ -1,25 +1,32 @@
 use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::savestate::Savestate;
 
 use super::mbc1::Mbc1;
-use super::mbc3::Mbc3;
+use super::mbc3::{Mbc3, RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_SHORT};
 use super::mbc5::Mbc5;
 use super::romonly::RomOnly;
 
+use anyhow::{bail, Context, Result};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
-use std::cell::RefCell;
 use std::fmt;
-use std::rc::Rc;
+use std::fs;
+use std::path::Path;
 
 pub const TITLE_OFFSET: usize = 0x134;
 pub const TITLE_SIZE: usize = 16;
 pub const CGB_OFFSET: usize = 0x143;
+pub const SGB_OFFSET: usize = 0x146;
 pub const CARTTYPE_OFFSET: usize = 0x147;
 pub const ROMSIZE_OFFSET: usize = 0x148;
 pub const RAMSIZE_OFFSET: usize = 0x149;
+pub const OLD_LICENSEE_OFFSET: usize = 0x14B;
 pub const CARTHEADER_END: usize = 0x150;
 
+/// Largest save accepted: 128KB of RAM (MBC5) plus an RTC footer
+pub const MAX_SAVE_SIZE: usize = 128 * 1024 + RTC_FOOTER_SIZE;
+
 #[derive(Debug, FromPrimitive)]
 pub enum CartridgeType {
     Rom = 0x00,
@@ -52,7 +59,126 @@ pub enum CartridgeType {
     Huc1RamBat = 0xFF,
 }
 
-pub trait Cartridge: BusMember {
+/// Kind of bank a bank select applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankKind {
+    Rom,
+    Ram,
+}
+
+impl fmt::Display for BankKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BankKind::Rom => write!(f, "ROM"),
+            BankKind::Ram => write!(f, "RAM"),
+        }
+    }
+}
+
+/// A bank select beyond the banks declared in the cartridge header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BankSelect {
+    pub kind: BankKind,
+    /// Bank number written by the game
+    pub requested: usize,
+    /// Bank actually mapped after masking
+    pub mapped: usize,
+    /// Number of times this bank was selected
+    pub count: u64,
+}
+
+impl fmt::Display for BankSelect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "out-of-range {} bank {:02X} selected, mapped to {:02X} ({} time(s))",
+            self.kind, self.requested, self.mapped, self.count
+        )
+    }
+}
+
+/// A read beyond the end of a trimmed ROM file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingRom {
+    /// ROM offset of the first such read
+    pub offset: usize,
+    /// Size of the ROM file
+    pub file_size: usize,
+}
+
+impl fmt::Display for MissingRom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ROM read at {:06X}, beyond the end of the ROM file ({} bytes), read as FF",
+            self.offset, self.file_size
+        )
+    }
+}
+
+/// Warnings collected by the memory bank controller while running
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    bank_selects: Vec<BankSelect>,
+    missing_rom: Option<MissingRom>,
+}
+
+impl Diagnostics {
+    /// Records an out-of-range bank select, repeated selects of the
+    /// same bank are counted.
+    pub fn record(&mut self, kind: BankKind, requested: usize, mapped: usize) {
+        match self
+            .bank_selects
+            .iter_mut()
+            .find(|s| s.kind == kind && s.requested == requested && s.mapped == mapped)
+        {
+            Some(select) => select.count += 1,
+            None => self.bank_selects.push(BankSelect {
+                kind,
+                requested,
+                mapped,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Records a read beyond the end of the ROM file, only the first
+    /// one is kept.
+    pub fn record_missing_rom(&mut self, offset: usize, file_size: usize) {
+        self.missing_rom
+            .get_or_insert(MissingRom { offset, file_size });
+    }
+
+    pub fn bank_selects(&self) -> &[BankSelect] {
+        &self.bank_selects
+    }
+
+    pub fn missing_rom(&self) -> Option<MissingRom> {
+        self.missing_rom
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bank_selects.is_empty() && self.missing_rom.is_none()
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "No out-of-range bank selects");
+        }
+        let mut lines = vec![];
+        for s in &self.bank_selects {
+            lines.push(format!("WARNING: {}", s));
+        }
+        if let Some(missing) = self.missing_rom {
+            lines.push(format!("WARNING: {}", missing));
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+pub trait Cartridge: BusMember + Savestate {
     fn get_title(&self) -> String {
         String::from_utf8(
             self.read_vec(TITLE_OFFSET as u16, TITLE_SIZE)
@@ -98,52 +224,232 @@ pub trait Cartridge: BusMember {
         }
     }
 
+    /// Supports Super Gameboy functions (the SGB flag is only
+    /// honoured with the old licensee code set to 0x33)
+    fn is_sgb(&self) -> bool {
+        self.read(SGB_OFFSET as u16) == 0x03 && self.read(OLD_LICENSEE_OFFSET as u16) == 0x33
+    }
+
     fn get_ram_banks(&self) -> usize {
         self.get_ram_size() / (8 * 1024)
     }
 
+    /// Returns the ROM bank currently mapped at 0x4000 - 0x7FFF
+    fn current_rom_bank(&self) -> usize {
+        1
+    }
+
+    /// Cartridge has a real time clock, which is part of the save
+    fn has_rtc(&self) -> bool {
+        false
+    }
+
+    /// Runs the real time clock (if any) up to 'now', the time of the
+    /// system's EmuClock in seconds
+    fn set_time(&mut self, _now: u64) {}
+
+    /// Returns the warnings collected by the memory bank controller,
+    /// such as selects of banks the header does not declare
+    fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    /// Cartridge RAM (or the clock) was written since the cartridge
+    /// was loaded, so the save on disk is out of date
+    fn ram_written(&self) -> bool {
+        false
+    }
+
     fn dump_state(&self) -> String;
 
+    /// Returns the contents of a save (.sav) file: cartridge RAM,
+    /// followed by the RTC footer for cartridges with a clock. All RAM
+    /// banks are included in ascending order, independent of the
+    /// currently selected bank and banking mode.
     fn get_save(&self) -> Vec<u8>;
+
+    /// Replaces the contents of cartridge RAM with a save
+    fn load_save(&mut self, save: &[u8]);
 }
 
-impl fmt::Display for dyn Cartridge {
+impl fmt::Display for dyn Cartridge + '_ {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "\"{}\" - {:?} - {} KB ROM ({} bank(s), 00-{:02X}), {} KB RAM ({} bank(s))",
             self.get_title(),
             self.get_type(),
-            self.get_rom_size(),
+            self.get_rom_size() / 1024,
             self.get_rom_banks(),
             self.get_rom_banks() - 1,
-            self.get_ram_size(),
+            self.get_ram_size() / 1024,
             self.get_ram_banks()
-        )
+        )?;
+        if self.is_cgb() {
+            write!(f, " - CGB")?;
+        }
+        if self.is_sgb() {
+            write!(f, " - SGB")?;
+        }
+        Ok(())
     }
 }
 
-pub fn load(rom: &[u8]) -> Rc<RefCell<dyn Cartridge>> {
+pub fn load(rom: &[u8]) -> Result<Box<dyn Cartridge>> {
     load_with_save(rom, &[])
 }
 
-pub fn load_with_save(rom: &[u8], save: &[u8]) -> Rc<RefCell<dyn Cartridge>> {
-    assert!(rom.len() >= 32 * 1024);
-
-    match CartridgeType::from_u8(rom[CARTTYPE_OFFSET]) {
-        Some(CartridgeType::Rom) => Rc::new(RefCell::new(RomOnly::new(rom))),
-        Some(CartridgeType::Mbc1) => Rc::new(RefCell::new(Mbc1::new(rom, save))),
-        Some(CartridgeType::Mbc1Ram) => Rc::new(RefCell::new(Mbc1::new(rom, save))),
-        Some(CartridgeType::Mbc1RamBat) => Rc::new(RefCell::new(Mbc1::new(rom, save))),
-        Some(CartridgeType::Mbc3) => Rc::new(RefCell::new(Mbc3::new(rom, save))),
-        Some(CartridgeType::Mbc3Ram) => Rc::new(RefCell::new(Mbc3::new(rom, save))),
-        Some(CartridgeType::Mbc3RamBat) => Rc::new(RefCell::new(Mbc3::new(rom, save))),
-        Some(CartridgeType::Mbc3RtcRamBat) => Rc::new(RefCell::new(Mbc3::new(rom, save))),
-        Some(CartridgeType::Mbc5) => Rc::new(RefCell::new(Mbc5::new(rom, save))),
-        Some(CartridgeType::Mbc5Ram) => Rc::new(RefCell::new(Mbc5::new(rom, save))),
-        Some(CartridgeType::Mbc5RamBat) => Rc::new(RefCell::new(Mbc5::new(rom, save))),
-        Some(CartridgeType::Mbc5RumbleRamBat) => Rc::new(RefCell::new(Mbc5::new(rom, save))),
-        Some(unknown) => panic!("Unknown cartridge type {:?}", unknown),
-        _ => panic!("Unknown cartridge type {:02X}", rom[CARTTYPE_OFFSET]),
+/// Reads a ROM from a file, decompressing gzip and zip files.
+/// A zip file with several ROMs needs the entry to load.
+pub fn read_rom(path: impl AsRef<Path>, entry: Option<&str>) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    let rom = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+
+    #[cfg(feature = "archives")]
+    let rom = super::archive::decompress(rom, entry)
+        .with_context(|| format!("Cannot load ROM from {}", path.display()))?;
+
+    #[cfg(not(feature = "archives"))]
+    {
+        let _ = entry;
+        if rom.starts_with(&[0x1F, 0x8B]) || rom.starts_with(b"PK\x03\x04") {
+            anyhow::bail!(
+                "{} is an archive, but archive support is not enabled",
+                path.display()
+            );
+        }
+    }
+
+    Ok(rom)
+}
+
+/// Loads a cartridge from a (possibly compressed) ROM file
+pub fn load_file(path: impl AsRef<Path>) -> Result<Box<dyn Cartridge>> {
+    load(&read_rom(path, None)?)
+}
+
+/// Decodes the ROM size value in the header, None if unknown
+fn decode_rom_size(val: u8) -> Option<usize> {
+    (val <= 8).then(|| (32 * 1024) << val)
+}
+
+/// Size of the ROM declared in the header. For an unknown size value,
+/// the size of the ROM file rounded up to a power of two.
+pub fn declared_rom_size(rom: &[u8]) -> usize {
+    let size = rom
+        .get(ROMSIZE_OFFSET)
+        .and_then(|&val| decode_rom_size(val));
+    size.unwrap_or_else(|| rom.len().next_power_of_two().max(32 * 1024))
+}
+
+/// Checks the ROM size against the size declared in the header,
+/// returns a warning if they do not match.
+pub fn check_rom_size(rom: &[u8]) -> Option<String> {
+    match rom.get(ROMSIZE_OFFSET) {
+        Some(&val) if decode_rom_size(val).is_none() => {
+            return Some(format!("Unknown ROM size value {:02X}", val))
+        }
+        Some(_) => (),
+        None => {
+            return Some(format!(
+                "ROM is too small for a header ({} bytes)",
+                rom.len()
+            ))
+        }
+    }
+    let declared = declared_rom_size(rom);
+    if rom.len() != declared {
+        return Some(format!(
+            "ROM is {} bytes, but the header declares {} bytes",
+            rom.len(),
+            declared
+        ));
+    }
+    None
+}
+
+/// Checks a save against the cartridge, returns a warning if the save
+/// does not have the expected size and is truncated or padded. Saves
+/// larger than any cartridge can hold are an error.
+pub fn check_save_size(cart: &dyn Cartridge, save: &[u8]) -> Result<Option<String>> {
+    check_save_limit(save)?;
+    let expected = cart.get_save().len();
+    if save.is_empty() || save.len() == expected {
+        return Ok(None);
+    }
+    if cart.has_rtc() {
+        // Saves with the short footer or without a footer are fine too
+        let ram = expected - RTC_FOOTER_SIZE;
+        if save.len() == ram || save.len() == ram + RTC_FOOTER_SIZE_SHORT {
+            return Ok(None);
+        }
+    }
+    Ok(Some(format!(
+        "Save is {} bytes, but {} bytes were expected; {}",
+        save.len(),
+        expected,
+        if save.len() > expected {
+            "truncating"
+        } else {
+            "padding"
+        }
+    )))
+}
+
+fn check_save_limit(save: &[u8]) -> Result<()> {
+    if save.len() > MAX_SAVE_SIZE {
+        bail!(
+            "Save is {} bytes, larger than any cartridge RAM ({} bytes)",
+            save.len(),
+            MAX_SAVE_SIZE
+        );
+    }
+    Ok(())
+}
+
+/// Loads a cartridge with the contents of a save. Saves that do not
+/// match the cartridge RAM size are truncated or padded with zeroes
+/// (see check_save_size()). Fails on cartridge types that are not
+/// supported and on saves larger than MAX_SAVE_SIZE.
+pub fn load_with_save(rom: &[u8], save: &[u8]) -> Result<Box<dyn Cartridge>> {
+    check_save_limit(save)?;
+
+    // ROMs too small to contain a header are treated as ROM-only
+    let carttype = rom.get(CARTTYPE_OFFSET).copied().unwrap_or(0);
+
+    Ok(match CartridgeType::from_u8(carttype) {
+        Some(CartridgeType::Rom) => Box::new(RomOnly::new(rom)),
+        Some(CartridgeType::RomRam) => Box::new(RomOnly::new_with_ram(rom, save)),
+        Some(CartridgeType::RomRamBat) => Box::new(RomOnly::new_with_ram(rom, save)),
+        Some(CartridgeType::Mbc1) => Box::new(Mbc1::new(rom, save)),
+        Some(CartridgeType::Mbc1Ram) => Box::new(Mbc1::new(rom, save)),
+        Some(CartridgeType::Mbc1RamBat) => Box::new(Mbc1::new(rom, save)),
+        Some(CartridgeType::Mbc3) => Box::new(Mbc3::new(rom, save)),
+        Some(CartridgeType::Mbc3Ram) => Box::new(Mbc3::new(rom, save)),
+        Some(CartridgeType::Mbc3RamBat) => Box::new(Mbc3::new(rom, save)),
+        Some(CartridgeType::Mbc3RtcBat) => Box::new(Mbc3::new(rom, save)),
+        Some(CartridgeType::Mbc3RtcRamBat) => Box::new(Mbc3::new(rom, save)),
+        Some(CartridgeType::Mbc5) => Box::new(Mbc5::new(rom, save)),
+        Some(CartridgeType::Mbc5Ram) => Box::new(Mbc5::new(rom, save)),
+        Some(CartridgeType::Mbc5RamBat) => Box::new(Mbc5::new(rom, save)),
+        Some(CartridgeType::Mbc5RumbleRamBat) => Box::new(Mbc5::new(rom, save)),
+        Some(unsupported) => bail!("Unsupported cartridge type {:?}", unsupported),
+        None => bail!("Unknown cartridge type {:02X}", carttype),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_type() {
+        let mut rom = vec![0; 32 * 1024];
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc2 as u8;
+        assert!(load(&rom).is_err());
+        rom[CARTTYPE_OFFSET] = 0xF0;
+        assert!(load(&rom).is_err());
+        rom[CARTTYPE_OFFSET] = CartridgeType::Rom as u8;
+        assert!(load(&rom).is_ok());
     }
 }
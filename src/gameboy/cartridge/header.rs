@@ -0,0 +1,207 @@
+use super::cartridge::{
+    CartridgeType, CARTHEADER_END, CARTTYPE_OFFSET, CGB_OFFSET, OLD_LICENSEE_OFFSET,
+    RAMSIZE_OFFSET, ROMSIZE_OFFSET, SGB_OFFSET, TITLE_OFFSET, TITLE_SIZE,
+};
+
+use anyhow::{bail, Result};
+use num_traits::FromPrimitive;
+use serde_json::json;
+
+use std::fmt;
+
+pub const NEW_LICENSEE_OFFSET: usize = 0x144;
+pub const VERSION_OFFSET: usize = 0x14C;
+pub const HEADER_CHECKSUM_OFFSET: usize = 0x14D;
+pub const GLOBAL_CHECKSUM_OFFSET: usize = 0x14E;
+
+/// Metadata from the cartridge header, parsed without loading the
+/// cartridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    /// Raw cartridge type (mapper and peripherals)
+    pub cart_type: u8,
+    /// Declared ROM size in bytes
+    pub rom_size: usize,
+    /// Declared RAM size in bytes
+    pub ram_size: usize,
+    pub cgb: bool,
+    pub sgb: bool,
+    /// Old licensee code, or the two characters of the new licensee code
+    /// if the old one is 0x33
+    pub licensee: String,
+    pub version: u8,
+    pub header_checksum_valid: bool,
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self> {
+        if rom.len() < CARTHEADER_END {
+            bail!("ROM is too small for a header ({} bytes)", rom.len());
+        }
+
+        let title = rom[TITLE_OFFSET..(TITLE_OFFSET + TITLE_SIZE)]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect();
+        let rom_size = match rom[ROMSIZE_OFFSET] {
+            val @ 0..=8 => (32 * 1024) << val,
+            val => bail!("Unknown ROM size value {:02X}", val),
+        };
+        let ram_size = match rom[RAMSIZE_OFFSET] {
+            0 => 0,
+            2 => 8 * 1024,
+            3 => 32 * 1024,
+            4 => 128 * 1024,
+            5 => 64 * 1024,
+            val => bail!("Unknown RAM size value {:02X}", val),
+        };
+        let licensee = match rom[OLD_LICENSEE_OFFSET] {
+            0x33 => String::from_utf8_lossy(&rom[NEW_LICENSEE_OFFSET..(NEW_LICENSEE_OFFSET + 2)])
+                .into_owned(),
+            old => format!("{:02X}", old),
+        };
+
+        let header_checksum = rom[TITLE_OFFSET..HEADER_CHECKSUM_OFFSET]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+        let global_checksum = rom
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != GLOBAL_CHECKSUM_OFFSET && i != GLOBAL_CHECKSUM_OFFSET + 1)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+
+        Ok(Self {
+            title,
+            cart_type: rom[CARTTYPE_OFFSET],
+            rom_size,
+            ram_size,
+            cgb: matches!(rom[CGB_OFFSET], 0x80 | 0xC0),
+            sgb: rom[SGB_OFFSET] == 0x03 && rom[OLD_LICENSEE_OFFSET] == 0x33,
+            licensee,
+            version: rom[VERSION_OFFSET],
+            header_checksum_valid: header_checksum == rom[HEADER_CHECKSUM_OFFSET],
+            global_checksum_valid: global_checksum
+                == u16::from_be_bytes([
+                    rom[GLOBAL_CHECKSUM_OFFSET],
+                    rom[GLOBAL_CHECKSUM_OFFSET + 1],
+                ]),
+        })
+    }
+
+    /// Name of the cartridge type, 'Unknown' for unknown types
+    pub fn mapper(&self) -> String {
+        match CartridgeType::from_u8(self.cart_type) {
+            Some(t) => format!("{:?}", t),
+            None => "Unknown".to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "title": self.title,
+            "cart_type": self.cart_type,
+            "mapper": self.mapper(),
+            "rom_size": self.rom_size,
+            "ram_size": self.ram_size,
+            "cgb": self.cgb,
+            "sgb": self.sgb,
+            "licensee": self.licensee,
+            "version": self.version,
+            "header_checksum_valid": self.header_checksum_valid,
+            "global_checksum_valid": self.global_checksum_valid,
+        })
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let valid = |v| if v { "valid" } else { "invalid" };
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Mapper: {} ({:02X})", self.mapper(), self.cart_type)?;
+        writeln!(f, "ROM size: {} KB", self.rom_size / 1024)?;
+        writeln!(f, "RAM size: {} KB", self.ram_size / 1024)?;
+        writeln!(f, "CGB: {}", if self.cgb { "yes" } else { "no" })?;
+        writeln!(f, "SGB: {}", if self.sgb { "yes" } else { "no" })?;
+        writeln!(f, "Licensee: {}", self.licensee)?;
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Header checksum: {}", valid(self.header_checksum_valid))?;
+        write!(f, "Global checksum: {}", valid(self.global_checksum_valid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 64 * 1024];
+        rom[TITLE_OFFSET..(TITLE_OFFSET + 4)].copy_from_slice(b"TEST");
+        rom[NEW_LICENSEE_OFFSET..(NEW_LICENSEE_OFFSET + 2)].copy_from_slice(b"01");
+        rom[CGB_OFFSET] = 0x80;
+        rom[SGB_OFFSET] = 0x03;
+        rom[CARTTYPE_OFFSET] = 0x13;
+        rom[ROMSIZE_OFFSET] = 1;
+        rom[RAMSIZE_OFFSET] = 3;
+        rom[OLD_LICENSEE_OFFSET] = 0x33;
+        rom[VERSION_OFFSET] = 2;
+        rom
+    }
+
+    #[test]
+    fn parse() {
+        let h = CartridgeHeader::parse(&rom()).unwrap();
+        assert_eq!(h.title, "TEST");
+        assert_eq!(h.mapper(), "Mbc3RamBat");
+        assert_eq!(h.rom_size, 64 * 1024);
+        assert_eq!(h.ram_size, 32 * 1024);
+        assert!(h.cgb && h.sgb);
+        assert_eq!(h.licensee, "01");
+        assert_eq!(h.version, 2);
+        assert!(!h.header_checksum_valid);
+        assert!(!h.global_checksum_valid);
+
+        assert!(CartridgeHeader::parse(&rom()[..0x100]).is_err());
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom = rom();
+        rom[HEADER_CHECKSUM_OFFSET] = 0x77;
+        let sum = rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        rom[GLOBAL_CHECKSUM_OFFSET..(GLOBAL_CHECKSUM_OFFSET + 2)]
+            .copy_from_slice(&sum.to_be_bytes());
+
+        let h = CartridgeHeader::parse(&rom).unwrap();
+        assert!(h.header_checksum_valid);
+        assert!(h.global_checksum_valid);
+    }
+
+    #[test]
+    fn json() {
+        let h = CartridgeHeader::parse(&rom()).unwrap();
+        assert_eq!(
+            h.to_json(),
+            json!({
+                "title": "TEST",
+                "cart_type": 0x13,
+                "mapper": "Mbc3RamBat",
+                "rom_size": 65536,
+                "ram_size": 32768,
+                "cgb": true,
+                "sgb": true,
+                "licensee": "01",
+                "version": 2,
+                "header_checksum_valid": false,
+                "global_checksum_valid": false,
+            })
+        );
+        let text = h.to_json().to_string();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            h.to_json()
+        );
+    }
+}
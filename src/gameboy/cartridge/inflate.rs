@@ -0,0 +1,320 @@
+use anyhow::{bail, Result};
+
+/// Maximum length of a Huffman code
+const MAX_BITS: usize = 15;
+
+/// Base lengths of length symbols 257 - 285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// Extra bits of length symbols 257 - 285
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances of distance symbols 0 - 29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Extra bits of distance symbols 0 - 29
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which the code length code lengths are stored
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Reads a stream of bits, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut val = 0;
+        for i in 0..count {
+            let Some(&byte) = self.data.get(self.pos) else {
+                bail!("Unexpected end of compressed data");
+            };
+            val |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(val)
+    }
+
+    /// Skips to the next byte boundary
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        debug_assert_eq!(self.bit, 0);
+        let Some(bytes) = self.data.get(self.pos..self.pos + len) else {
+            bail!("Unexpected end of compressed data");
+        };
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Number of bytes consumed (including a partial byte)
+    fn consumed(&self) -> usize {
+        self.pos + if self.bit != 0 { 1 } else { 0 }
+    }
+}
+
+/// Canonical Huffman code
+struct Huffman {
+    /// Number of codes per length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols, ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Check for an over-subscribed code
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                bail!("Invalid Huffman code");
+            }
+        }
+
+        let mut offsets = [0; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        // Codes are stored most significant bit first
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        bail!("Invalid Huffman code in compressed data")
+    }
+}
+
+/// Fixed Huffman codes of block type 1
+fn fixed_codes() -> Result<(Huffman, Huffman)> {
+    let mut lengths = [0; 288];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+/// Reads the dynamic Huffman codes of block type 2
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let nlen = reader.bits(5)? as usize + 257;
+    let ndist = reader.bits(5)? as usize + 1;
+    let ncode = reader.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        bail!("Invalid dynamic block header");
+    }
+
+    let mut clens = [0; 19];
+    for &i in &CLEN_ORDER[..ncode] {
+        clens[i] = reader.bits(3)? as u8;
+    }
+    let clencode = Huffman::new(&clens)?;
+
+    let mut lengths = vec![0; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let (len, repeat) = match clencode.decode(reader)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => {
+                if i == 0 {
+                    bail!("Repeat without previous code length");
+                }
+                (lengths[i - 1], 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            bail!("Too many code lengths");
+        }
+        lengths[i..i + repeat].fill(len);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        bail!("Missing end of block code");
+    }
+
+    Ok((
+        Huffman::new(&lengths[..nlen])?,
+        Huffman::new(&lengths[nlen..])?,
+    ))
+}
+
+/// Decodes the symbols of a compressed block
+fn codes(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    lencode: &Huffman,
+    distcode: &Huffman,
+) -> Result<()> {
+    loop {
+        let sym = lencode.decode(reader)? as usize;
+        match sym {
+            0..=255 => out.push(sym as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let sym = sym - 257;
+                let len =
+                    LENGTH_BASE[sym] as usize + reader.bits(LENGTH_EXTRA[sym] as u32)? as usize;
+                let dsym = distcode.decode(reader)? as usize;
+                if dsym >= DIST_BASE.len() {
+                    bail!("Invalid distance code");
+                }
+                let dist =
+                    DIST_BASE[dsym] as usize + reader.bits(DIST_EXTRA[dsym] as u32)? as usize;
+                if dist > out.len() {
+                    bail!("Distance too far back");
+                }
+                // Copies may overlap themselves
+                let start = out.len() - dist;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => bail!("Invalid length code"),
+        }
+    }
+}
+
+/// Decompresses a raw DEFLATE (RFC 1951) stream. Returns the decompressed
+/// data and the number of compressed bytes consumed.
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let mut reader = BitReader::new(data);
+    let mut out = vec![];
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                // Stored
+                reader.align();
+                let hdr = reader.bytes(4)?;
+                let len = u16::from_le_bytes([hdr[0], hdr[1]]);
+                let nlen = u16::from_le_bytes([hdr[2], hdr[3]]);
+                if len != !nlen {
+                    bail!("Invalid stored block length");
+                }
+                out.extend_from_slice(reader.bytes(len as usize)?);
+            }
+            1 => {
+                let (lencode, distcode) = fixed_codes()?;
+                codes(&mut reader, &mut out, &lencode, &distcode)?;
+            }
+            2 => {
+                let (lencode, distcode) = dynamic_codes(&mut reader)?;
+                codes(&mut reader, &mut out, &lencode, &distcode)?;
+            }
+            _ => bail!("Invalid block type"),
+        }
+        if last {
+            break;
+        }
+    }
+
+    Ok((out, reader.consumed()))
+}
+
+/// CRC-32 as used by gzip and zip
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored() {
+        let data = [0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'];
+        assert_eq!(inflate(&data).unwrap(), (b"abc".to_vec(), data.len()));
+    }
+
+    #[test]
+    fn fixed() {
+        // zlib.compressobj(wbits=-15), "hello hello hello"
+        let data = [0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x90, 0x00];
+        assert_eq!(inflate(&data).unwrap().0, b"hello hello hello");
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(inflate(&[0xCB, 0x48, 0xCD]).is_err());
+        assert!(inflate(&[]).is_err());
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+}
@@ -1,18 +1,20 @@
-use super::cartridge::Cartridge;
+use super::cartridge::{declared_rom_size, BankKind, Cartridge, Diagnostics};
+use super::rom::Rom;
 use crate::gameboy::bus::bus::BusMember;
 
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use std::cmp;
 
+use anyhow::{bail, Result};
+
 const ROM_BANK_SIZE: usize = 16 * 1024;
-const ROM_BANK_COUNT: usize = ROM_BANKS_MAX + 1;
-const ROM_BANKS_MAX: usize = 0x7F;
 
 const RAM_BANK_SIZE: usize = 8 * 1024;
 const RAM_BANK_COUNT: usize = RAM_BANKS_MAX + 1;
 const RAM_BANKS_MAX: usize = 0x03;
 
 pub struct Mbc1 {
-    rom: Vec<u8>,
+    rom: Rom,
     bank1: u8,
     ram: Vec<u8>,
     bank2: u8,
@@ -21,12 +23,14 @@ pub struct Mbc1 {
     bank_advanced: bool,
     rom_banks: usize,
     ram_banks: usize,
+    ram_written: bool,
+    diagnostics: Diagnostics,
 }
 
 impl Mbc1 {
     pub fn new(rom: &[u8], save: &[u8]) -> Self {
         let mut cart = Self {
-            rom: vec![0; ROM_BANK_COUNT * ROM_BANK_SIZE],
+            rom: Rom::new(rom, declared_rom_size(rom)),
             ram: vec![0; RAM_BANK_COUNT * RAM_BANK_SIZE],
             bank1: 1,
             bank2: 0,
@@ -34,11 +38,13 @@ impl Mbc1 {
             bank_advanced: false,
             rom_banks: 0,
             ram_banks: 0,
+            ram_written: false,
+            diagnostics: Diagnostics::default(),
         };
-        cart.rom[0..rom.len()].copy_from_slice(rom);
+        // Bank numbers are masked to the size in the header
         cart.rom_banks = cart.get_rom_banks();
         cart.ram_banks = cart.get_ram_banks();
-        cart.ram[0..save.len()].copy_from_slice(save);
+        cart.load_save(save);
         cart
     }
 
@@ -75,9 +81,52 @@ impl Mbc1 {
         };
         bankaddr + (addr as usize - 0xA000)
     }
+
+    /// Checks a write to bank register 1 against the ROM size
+    fn check_bank1(&mut self, val: u8) {
+        if val as usize >= cmp::min(self.rom_banks, 0x20) {
+            let mapped = self.current_rom_bank();
+            self.diagnostics.record(BankKind::Rom, val as usize, mapped);
+        }
+    }
+
+    /// Checks a write to bank register 2, which holds the upper ROM
+    /// bits on large cartridges and the RAM bank otherwise
+    fn check_bank2(&mut self, val: u8) {
+        if self.rom_banks > 0x20 {
+            if val as usize >= self.rom_banks >> 5 {
+                let requested = (val as usize) << 5 | cmp::max(self.bank1, 1) as usize;
+                let mapped = self.current_rom_bank();
+                self.diagnostics.record(BankKind::Rom, requested, mapped);
+            }
+        } else if val != 0 && val as usize >= self.ram_banks {
+            let mapped = if self.ram_banks > 0 {
+                (self.bank2 as usize) & (self.ram_banks - 1)
+            } else {
+                0
+            };
+            self.diagnostics.record(BankKind::Ram, val as usize, mapped);
+        }
+    }
 }
 
 impl Cartridge for Mbc1 {
+    fn get_rom_size(&self) -> usize {
+        self.rom.size()
+    }
+
+    fn current_rom_bank(&self) -> usize {
+        self.rom_translate_1(0x4000) / ROM_BANK_SIZE
+    }
+
+    fn diagnostics(&self) -> Diagnostics {
+        self.rom.add_diagnostics(self.diagnostics.clone())
+    }
+
+    fn ram_written(&self) -> bool {
+        self.ram_written
+    }
+
     fn dump_state(&self) -> String {
         format!(
             "ROM: {:02X} - RAM : {:02X} - Mode: {}",
@@ -90,15 +139,21 @@ impl Cartridge for Mbc1 {
     fn get_save(&self) -> Vec<u8> {
         self.ram.to_owned()
     }
+
+    fn load_save(&mut self, save: &[u8]) {
+        self.ram.fill(0);
+        let len = save.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&save[..len]);
+    }
 }
 
 impl BusMember for Mbc1 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             // ROM - Always bank 0
-            0x0000..=0x3FFF => self.rom[self.rom_translate_0(addr)],
+            0x0000..=0x3FFF => self.rom.read(self.rom_translate_0(addr)),
             // ROM - Bank 1..n
-            0x4000..=0x7FFF => self.rom[self.rom_translate_1(addr)],
+            0x4000..=0x7FFF => self.rom.read(self.rom_translate_1(addr)),
             // RAM - Bank 0..=3
             0xA000..=0xBFFF => {
                 if self.ram_enable {
@@ -117,9 +172,15 @@ impl BusMember for Mbc1 {
             // RAM enable
             0x0000..=0x1FFF => self.ram_enable = val & 0x0F == 0x0A,
             // Bank register 1
-            0x2000..=0x3FFF => self.bank1 = val & 0x1F,
+            0x2000..=0x3FFF => {
+                self.bank1 = val & 0x1F;
+                self.check_bank1(val);
+            }
             // Bank register 2
-            0x4000..=0x5FFF => self.bank2 = val & 0x03,
+            0x4000..=0x5FFF => {
+                self.bank2 = val & 0x03;
+                self.check_bank2(val);
+            }
             // Banking mode select
             0x6000..=0x7FFF => self.bank_advanced = val & 1 == 1,
             // RAM - Bank 0..=3
@@ -127,6 +188,7 @@ impl BusMember for Mbc1 {
                 if self.ram_enable {
                     let tr_addr = self.ram_translate(addr);
                     self.ram[tr_addr] = val;
+                    self.ram_written = true;
                 }
             }
 
@@ -135,6 +197,30 @@ impl BusMember for Mbc1 {
     }
 }
 
+impl Savestate for Mbc1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u8(self.bank1);
+        w.put_u8(self.bank2);
+        w.put_bool(self.ram_enable);
+        w.put_bool(self.bank_advanced);
+        w.put_block(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.bank1 = r.get_u8()?;
+        self.bank2 = r.get_u8()?;
+        self.ram_enable = r.get_bool()?;
+        self.bank_advanced = r.get_bool()?;
+        let ram = r.get_block()?;
+        if ram.len() != self.ram.len() {
+            bail!("Cartridge RAM size mismatch");
+        }
+        self.ram.copy_from_slice(ram);
+        self.ram_written = true;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::cartridge::*;
@@ -142,6 +228,9 @@ mod tests {
 
     use itertools::repeat_n;
 
+    const ROM_BANK_COUNT: usize = ROM_BANKS_MAX + 1;
+    const ROM_BANKS_MAX: usize = 0x7F;
+
     #[test]
     fn rom_bank_switching_mode0() {
         let mut rom: Vec<u8> = (0u8..=(ROM_BANKS_MAX as u8))
@@ -181,6 +270,7 @@ mod tests {
             }
             // Bank n (with addressing quirk)
             let expected = cmp::max(b & 0x1F, 1) | (b & !0x1F);
+            assert_eq!(c.current_rom_bank(), expected as usize);
             for i in 0u16..(ROM_BANK_SIZE as u16) {
                 assert_eq!(c.read(0x4000 + i), expected);
             }
@@ -300,6 +390,126 @@ mod tests {
         assert_eq!(c.read(0xA000 as u16), 1);
     }
 
+    #[test]
+    fn save_ram_banks() {
+        let mut rom: [u8; CARTHEADER_END] = [0; CARTHEADER_END];
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1RamBat as u8;
+        rom[RAMSIZE_OFFSET] = 0x03; // 32kb RAM
+        let mut c = Mbc1::new(&rom, &[]);
+
+        c.write(0x0000, 0x0A); // RAM enable
+        c.write(0x6000, 1); // Banking mode 1
+        c.write(0x4000, 3);
+        c.write(0xA123, 0x5A);
+        c.write(0x4000, 0);
+
+        // All banks in order, regardless of the selected bank and mode
+        let save = c.get_save();
+        assert_eq!(save.len(), RAM_BANK_COUNT * RAM_BANK_SIZE);
+        assert_eq!(save[3 * RAM_BANK_SIZE + 0x123], 0x5A);
+        assert_eq!(save.iter().filter(|&&b| b != 0).count(), 1);
+        c.write(0x6000, 0);
+        assert_eq!(c.get_save(), save);
+    }
+
+    #[test]
+    fn load_ram_banks() {
+        let mut rom: [u8; CARTHEADER_END] = [0; CARTHEADER_END];
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1RamBat as u8;
+        rom[RAMSIZE_OFFSET] = 0x03; // 32kb RAM
+        let mut save = vec![0; RAM_BANK_COUNT * RAM_BANK_SIZE];
+        for b in 0..RAM_BANK_COUNT {
+            save[b * RAM_BANK_SIZE + 0x123] = b as u8 + 1;
+        }
+
+        let mut c = load_with_save(&rom, &save).unwrap();
+        c.write(0x0000, 0x0A); // RAM enable
+        c.write(0x6000, 1); // Banking mode 1
+        for b in [3u8, 0, 2, 1] {
+            c.write(0x4000, b);
+            assert_eq!(c.read(0xA123), b + 1, "bank {}", b);
+        }
+        assert_eq!(c.get_save(), save);
+    }
+
+    #[test]
+    fn out_of_range_bank() {
+        let mut rom = vec![0; 512 * 1024];
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1 as u8;
+        rom[ROMSIZE_OFFSET] = 0x04; // 512KB ROM
+        let mut c = Mbc1::new(&rom, &[]);
+        assert!(c.diagnostics().is_empty());
+
+        c.write(0x2000, 0x1F);
+        assert!(c.diagnostics().is_empty());
+
+        c.write(0x2000, 0x7F);
+        c.write(0x2000, 0x7F);
+        assert_eq!(c.current_rom_bank(), 0x1F);
+        assert_eq!(
+            c.diagnostics().bank_selects(),
+            &[BankSelect {
+                kind: BankKind::Rom,
+                requested: 0x7F,
+                mapped: 0x1F,
+                count: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn trimmed_rom() {
+        // 96KB of a 128KB ROM
+        let mut rom: Vec<u8> = (0u8..6).flat_map(|i| repeat_n(i, ROM_BANK_SIZE)).collect();
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1 as u8;
+        rom[ROMSIZE_OFFSET] = 0x02; // 128KB ROM
+        let mut c = Mbc1::new(&rom, &[]);
+        assert_eq!(c.get_rom_banks(), 8);
+
+        // Last bank in the file
+        c.write(0x2000, 5);
+        assert_eq!(c.read(0x4000), 5);
+        assert_eq!(c.read(0x7FFF), 5);
+        assert!(c.diagnostics().is_empty());
+
+        // Last declared bank, beyond the end of the file
+        c.write(0x2000, 7);
+        assert_eq!(c.current_rom_bank(), 7);
+        assert_eq!(c.read(0x4000), 0xFF);
+        assert_eq!(c.read(0x7FFF), 0xFF);
+        assert!(c.diagnostics().bank_selects().is_empty());
+        assert_eq!(
+            c.diagnostics().missing_rom(),
+            Some(MissingRom {
+                offset: 7 * ROM_BANK_SIZE,
+                file_size: 6 * ROM_BANK_SIZE
+            })
+        );
+
+        // Masked to the declared size
+        c.write(0x2000, 0x0D);
+        assert_eq!(c.current_rom_bank(), 5);
+        assert_eq!(c.read(0x4000), 5);
+    }
+
+    #[test]
+    fn overdumped_rom() {
+        // 64KB ROM, doubled by trailing data
+        let mut rom: Vec<u8> = (0u8..8).flat_map(|i| repeat_n(i, ROM_BANK_SIZE)).collect();
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1 as u8;
+        rom[ROMSIZE_OFFSET] = 0x01; // 64KB ROM
+        let mut c = Mbc1::new(&rom, &[]);
+        assert_eq!(c.get_rom_size(), 64 * 1024);
+
+        c.write(0x2000, 3);
+        assert_eq!(c.read(0x4000), 3);
+        // The trailing data is not mapped
+        c.write(0x2000, 5);
+        assert_eq!(c.current_rom_bank(), 1);
+        assert_eq!(c.read(0x4000), 1);
+        assert!(c.diagnostics().missing_rom().is_none());
+    }
+
     #[test]
     fn ram_enable() {
         let mut rom: [u8; CARTHEADER_END] = [0; CARTHEADER_END];
@@ -310,12 +520,14 @@ mod tests {
         assert_eq!(c.read(0xA000), 0xFF);
         c.write(0xA000, 0xAB);
         assert_eq!(c.read(0xA000), 0xFF);
+        assert!(!c.ram_written());
 
         c.write(0x0000, 0x0A); // RAM enable
                                // Earlier write should not have had any effect.
         assert_eq!(c.read(0xA000), 0x00);
         c.write(0xA000, 0xAB);
         assert_eq!(c.read(0xA000), 0xAB);
+        assert!(c.ram_written());
 
         // Disabling and enabling should reveal the original
         // data and writing while disabled shouldn't overwrite it.
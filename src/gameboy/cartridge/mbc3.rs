@@ -1,54 +1,239 @@
-use super::cartridge::Cartridge;
+use super::cartridge::{
+    declared_rom_size, BankKind, Cartridge, CartridgeType, Diagnostics, CARTTYPE_OFFSET,
+    RAMSIZE_OFFSET, ROMSIZE_OFFSET,
+};
+use super::rom::Rom;
 use crate::gameboy::bus::bus::BusMember;
 
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use num_traits::FromPrimitive;
 use std::cmp;
 
+use anyhow::{bail, Result};
+
 const ROM_BANK_SIZE: usize = 16 * 1024;
-const ROM_BANK_COUNT: usize = ROM_BANKS_MAX + 1;
 const ROM_BANKS_MAX: usize = 127;
+/// MBC30 (Pokemon Crystal JP): 8-bit ROM bank select, up to 4MB ROM
+const MBC30_ROM_BANKS_MAX: usize = 255;
 
 const RAM_BANK_SIZE: usize = 8 * 1024;
 const RAM_BANK_COUNT: usize = RAM_BANKS_MAX + 1;
 const RAM_BANKS_MAX: usize = 0x03;
+/// MBC30: 3-bit RAM bank select, up to 64KB RAM
+const MBC30_RAM_BANK_COUNT: usize = MBC30_RAM_BANKS_MAX + 1;
+const MBC30_RAM_BANKS_MAX: usize = 0x07;
 const RAM_BANK_MASK: u8 = 0x0F;
 
+/// First RAM bank select value that selects an RTC register
+const RTC_BANKSEL: u8 = 0x08;
+const RTC_REGS: usize = 5;
+/// Writable bits of the seconds, minutes, hours, day low and day high registers
+const RTC_MASKS: [u8; RTC_REGS] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+/// Day high register: bit 8 of the day counter
+const RTC_DAY_HIGH: u8 = 1 << 0;
+/// Day high register: clock stopped
+const RTC_HALT: u8 = 1 << 6;
+/// Day high register: day counter overflowed (sticky)
+const RTC_CARRY: u8 = 1 << 7;
+/// Days before the day counter overflows
+const RTC_DAYS: u64 = 512;
+
+/// Size of the RTC footer of a save, as used by BGB and VBA-M: the clock
+/// registers and the latched registers as 32-bit values, followed by a
+/// 64-bit timestamp (the time of the EmuClock, which is the UNIX time when
+/// synced with the host).
+pub const RTC_FOOTER_SIZE: usize = 48;
+/// Older variant of the RTC footer, with a 32-bit timestamp
+pub const RTC_FOOTER_SIZE_SHORT: usize = 44;
+
+/// Clock registers of cartridges with a real time clock
+#[derive(Default)]
+struct Rtc {
+    regs: [u8; RTC_REGS],
+    latched: [u8; RTC_REGS],
+    /// Last value written to the latch register
+    latch: u8,
+    /// Clock time the registers were last updated to
+    last: Option<u64>,
+}
+
+impl Rtc {
+    /// Runs the clock up to 'now'. The time only moves forward, the
+    /// registers stay as they are if 'now' lies before the last update.
+    fn set_time(&mut self, now: u64) {
+        if let Some(last) = self.last {
+            self.advance(now.saturating_sub(last));
+        }
+        self.last = Some(now);
+    }
+
+    fn advance(&mut self, secs: u64) {
+        let [s, m, h, dl, dh] = self.regs.map(u64::from);
+        if secs == 0 || dh & RTC_HALT as u64 != 0 {
+            return;
+        }
+
+        let days = ((dh & RTC_DAY_HIGH as u64) << 8) | dl;
+        let total = ((days * 24 + h) * 60 + m) * 60 + s + secs;
+        let days = total / (24 * 60 * 60);
+        let mut day_high = self.regs[4] & !RTC_DAY_HIGH;
+        if days >= RTC_DAYS {
+            day_high |= RTC_CARRY;
+        }
+        let days = days % RTC_DAYS;
+
+        self.regs = [
+            (total % 60) as u8,
+            (total / 60 % 60) as u8,
+            (total / (60 * 60) % 24) as u8,
+            days as u8,
+            day_high | (days >> 8) as u8,
+        ];
+    }
+
+    fn footer(&self) -> Vec<u8> {
+        self.regs
+            .iter()
+            .chain(self.latched.iter())
+            .flat_map(|&r| (r as u32).to_le_bytes())
+            .chain(self.last.unwrap_or(0).to_le_bytes())
+            .collect()
+    }
+
+    /// Restores the registers from a save footer. The clock catches up
+    /// with the time passed since the timestamp on the next update.
+    fn load_footer(&mut self, footer: &[u8]) {
+        let mut values = footer
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) as u8);
+        for regs in [&mut self.regs, &mut self.latched] {
+            for (reg, mask) in regs.iter_mut().zip(RTC_MASKS) {
+                *reg = values.next().unwrap_or(0) & mask;
+            }
+        }
+        let timestamp = &footer[footer.len().min(RTC_REGS * 2 * 4)..];
+        self.last = match timestamp.len() {
+            8 => Some(u64::from_le_bytes(timestamp.try_into().unwrap())),
+            4 => Some(u32::from_le_bytes(timestamp.try_into().unwrap()) as u64),
+            _ => None,
+        };
+    }
+}
+
+/// MBC3, optionally with a real time clock. Cartridges with 64KB RAM or
+/// 4MB ROM use the MBC30, which has an extra RAM and ROM bank bit.
+///
+/// Saves are the contents of cartridge RAM (the size declared in the
+/// header). Cartridges with a clock append the RTC footer of BGB/VBA-M.
+/// Saves are loaded with or without a footer (either size), RAM that
+/// does not match the declared size is truncated or padded.
 pub struct Mbc3 {
-    rom: Vec<u8>,
+    rom: Rom,
     rom_banksel: u8,
+    rom_banks: usize,
     ram: Vec<u8>,
     ram_banksel: u8,
+    rtc: Option<Rtc>,
+    mbc30: bool,
+    ram_written: bool,
+    diagnostics: Diagnostics,
 }
 
 impl Mbc3 {
     pub fn new(rom: &[u8], save: &[u8]) -> Self {
+        let rtc = matches!(
+            rom.get(CARTTYPE_OFFSET)
+                .copied()
+                .and_then(CartridgeType::from_u8),
+            Some(CartridgeType::Mbc3RtcBat | CartridgeType::Mbc3RtcRamBat)
+        );
+        let mbc30 =
+            rom.get(RAMSIZE_OFFSET) == Some(&0x05) || rom.get(ROMSIZE_OFFSET) == Some(&0x07);
+        let rom = Rom::new(rom, declared_rom_size(rom));
         let mut cart = Self {
-            // Too large for the stack..
-            rom: vec![0; ROM_BANK_COUNT * ROM_BANK_SIZE],
-            ram: vec![0; RAM_BANK_COUNT * RAM_BANK_SIZE],
+            rom_banks: rom.size() / ROM_BANK_SIZE,
+            rom,
+            ram: vec![],
             rom_banksel: 1,
             ram_banksel: 0,
+            rtc: rtc.then(Rtc::default),
+            mbc30,
+            ram_written: false,
+            diagnostics: Diagnostics::default(),
         };
-        cart.rom[0..rom.len()].copy_from_slice(rom);
-        cart.ram[0..save.len()].copy_from_slice(save);
+        // RAM as declared in the header, up to what the MBC can address
+        let ram_size = cmp::min(cart.get_ram_size(), cart.ram_bank_count() * RAM_BANK_SIZE);
+        cart.ram = vec![0; ram_size];
+        cart.load_save(save);
         cart
     }
 
+    fn rom_banks_max(&self) -> u8 {
+        if self.mbc30 {
+            MBC30_ROM_BANKS_MAX as u8
+        } else {
+            ROM_BANKS_MAX as u8
+        }
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        if self.mbc30 {
+            MBC30_RAM_BANK_COUNT
+        } else {
+            RAM_BANK_COUNT
+        }
+    }
+
     fn rom_translate(&self, addr: u16) -> usize {
         assert!(addr >= 0x4000);
 
-        let bankaddr: usize = ROM_BANK_SIZE * (self.rom_banksel as usize);
+        // Bank selects beyond the ROM size in the header wrap around
+        let bank = self.rom_banksel as usize & (self.rom_banks - 1);
+        let bankaddr: usize = ROM_BANK_SIZE * bank;
         bankaddr + (addr as usize - 0x4000)
     }
 
     fn ram_translate(&self, addr: u16) -> usize {
         assert!(addr >= 0xA000);
 
-        let bankaddr: usize = RAM_BANK_SIZE * (self.ram_banksel as usize);
+        // Bank selects beyond the RAM on the cartridge wrap around
+        let bank = (self.ram_banksel as usize & (self.ram_bank_count() - 1))
+            % (self.ram.len() / RAM_BANK_SIZE);
+        let bankaddr: usize = RAM_BANK_SIZE * bank;
         bankaddr + (addr as usize - 0xA000)
     }
+
+    /// Checks a bank select against the sizes in the header
+    fn check_banksel(&mut self, kind: BankKind, val: u8) {
+        let (banks, mapped) = match kind {
+            BankKind::Rom => (self.get_rom_banks(), self.current_rom_bank()),
+            // Bank 0 is always fine, RTC register selects are not RAM banks
+            BankKind::Ram if val == 0 || val >= RTC_BANKSEL => return,
+            BankKind::Ram => (self.get_ram_banks(), self.ram_banksel as usize),
+        };
+        if val as usize >= banks {
+            self.diagnostics.record(kind, val as usize, mapped);
+        }
+    }
 }
 
 impl Cartridge for Mbc3 {
+    fn get_rom_size(&self) -> usize {
+        self.rom.size()
+    }
+
+    fn current_rom_bank(&self) -> usize {
+        self.rom_translate(0x4000) / ROM_BANK_SIZE
+    }
+
+    fn diagnostics(&self) -> Diagnostics {
+        self.rom.add_diagnostics(self.diagnostics.clone())
+    }
+
+    fn ram_written(&self) -> bool {
+        self.ram_written
+    }
+
     fn dump_state(&self) -> String {
         format!(
             "ROM bank: {:02X} - RAM bank: {:02X}",
@@ -56,8 +241,42 @@ impl Cartridge for Mbc3 {
         )
     }
 
+    fn has_rtc(&self) -> bool {
+        self.rtc.is_some()
+    }
+
+    fn set_time(&mut self, now: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_time(now);
+        }
+    }
+
     fn get_save(&self) -> Vec<u8> {
-        self.ram.to_owned()
+        let mut save = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            save.extend(rtc.footer());
+        }
+        save
+    }
+
+    fn load_save(&mut self, save: &[u8]) {
+        // RAM sizes are a multiple of the bank size, so a footer shows
+        // as a remainder.
+        let footer = match save.len() % RAM_BANK_SIZE {
+            RTC_FOOTER_SIZE | RTC_FOOTER_SIZE_SHORT if self.rtc.is_some() => {
+                save.len() % RAM_BANK_SIZE
+            }
+            _ => 0,
+        };
+        let (ram, footer) = save.split_at(save.len() - footer);
+
+        self.ram.fill(0);
+        let len = cmp::min(ram.len(), self.ram.len());
+        self.ram[..len].copy_from_slice(&ram[..len]);
+        if let Some(rtc) = &mut self.rtc {
+            *rtc = Rtc::default();
+            rtc.load_footer(footer);
+        }
     }
 }
 
@@ -65,15 +284,26 @@ impl BusMember for Mbc3 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             // ROM - Always bank 0
-            0x0000..=0x3FFF => self.rom[addr as usize],
-            // ROM - Bank 1..=127
-            0x4000..=0x7FFF => self.rom[self.rom_translate(addr)],
-            // RAM - Bank 0..=3
-            0xA000..=0xBFFF if self.ram_banksel < RAM_BANK_COUNT as u8 => {
-                self.ram[self.ram_translate(addr)]
+            0x0000..=0x3FFF => self.rom.read(addr as usize),
+            // ROM - Bank 1..=127 (MBC30: 1..=255)
+            0x4000..=0x7FFF => self.rom.read(self.rom_translate(addr)),
+            // RAM - Bank 0..=3 (MBC30: 0..=7)
+            0xA000..=0xBFFF if self.ram_banksel < RTC_BANKSEL => {
+                if self.ram.is_empty() {
+                    0xFF
+                } else {
+                    self.ram[self.ram_translate(addr)]
+                }
             }
             // RTC registers
-            0xA000..=0xBFFF => 0, // TODO
+            0xA000..=0xBFFF => match &self.rtc {
+                Some(rtc) if self.ram_banksel >= RTC_BANKSEL => rtc
+                    .latched
+                    .get((self.ram_banksel - RTC_BANKSEL) as usize)
+                    .copied()
+                    .unwrap_or(0xFF),
+                _ => 0,
+            },
 
             _ => unreachable!(),
         }
@@ -84,42 +314,112 @@ impl BusMember for Mbc3 {
             // RAM + RTC enable
             0x0000..=0x1FFF => (),
             // ROM bank select
-            0x2000..=0x3FFF => self.rom_banksel = cmp::max(val, 1) & ROM_BANKS_MAX as u8,
+            0x2000..=0x3FFF => {
+                self.rom_banksel = cmp::max(val, 1) & self.rom_banks_max();
+                self.check_banksel(BankKind::Rom, val);
+            }
             // RAM/upper ROM bank select
-            0x4000..=0x5FFF => self.ram_banksel = val & RAM_BANK_MASK as u8,
-            // RTC Latch clock data
-            0x6000..=0x7FFF => (),
-            // RAM - Bank 0..=3
-            0xA000..=0xBFFF if self.ram_banksel < RAM_BANK_COUNT as u8 => {
-                let tr_addr = self.ram_translate(addr);
-                self.ram[tr_addr] = val
+            0x4000..=0x5FFF => {
+                self.ram_banksel = val & RAM_BANK_MASK;
+                self.check_banksel(BankKind::Ram, val);
+            }
+            // RTC Latch clock data, on a write of 0 followed by 1
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    if rtc.latch == 0 && val == 1 {
+                        rtc.latched = rtc.regs;
+                    }
+                    rtc.latch = val;
+                }
+            }
+            // RAM - Bank 0..=3 (MBC30: 0..=7)
+            0xA000..=0xBFFF if self.ram_banksel < RTC_BANKSEL => {
+                if !self.ram.is_empty() {
+                    let tr_addr = self.ram_translate(addr);
+                    self.ram[tr_addr] = val;
+                    self.ram_written = true;
+                }
             }
             // RTC registers
-            0xA000..=0xBFFF => (), // TODO
+            0xA000..=0xBFFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    let reg = self.ram_banksel.wrapping_sub(RTC_BANKSEL) as usize;
+                    if reg < RTC_REGS {
+                        // Writes go to the clock and the latched value
+                        rtc.regs[reg] = val & RTC_MASKS[reg];
+                        rtc.latched[reg] = val & RTC_MASKS[reg];
+                        self.ram_written = true;
+                    }
+                }
+            }
             _ => unreachable!(),
         }
     }
 }
 
+impl Savestate for Mbc3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u8(self.rom_banksel);
+        w.put_u8(self.ram_banksel);
+        w.put_block(&self.ram);
+        if let Some(rtc) = &self.rtc {
+            w.put_slice(&rtc.regs);
+            w.put_slice(&rtc.latched);
+            w.put_u8(rtc.latch);
+            w.put_bool(rtc.last.is_some());
+            w.put_u64(rtc.last.unwrap_or(0));
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom_banksel = r.get_u8()?;
+        self.ram_banksel = r.get_u8()?;
+        let ram = r.get_block()?;
+        if ram.len() != self.ram.len() {
+            bail!("Cartridge RAM size mismatch");
+        }
+        self.ram.copy_from_slice(ram);
+        self.ram_written = true;
+        if let Some(rtc) = &mut self.rtc {
+            r.get_slice(&mut rtc.regs)?;
+            r.get_slice(&mut rtc.latched)?;
+            rtc.latch = r.get_u8()?;
+            let synced = r.get_bool()?;
+            let last = r.get_u64()?;
+            rtc.last = synced.then_some(last);
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gameboy::cartridge::cartridge;
+
+    use hex_literal::hex;
 
     use itertools::repeat_n;
 
+    const ROM_BANK_COUNT: usize = ROM_BANKS_MAX + 1;
+
     #[test]
     fn rom_bank_switching() {
-        let rom: Box<Vec<u8>> = Box::new(
+        let mut rom: Box<Vec<u8>> = Box::new(
             (0u8..=(ROM_BANKS_MAX as u8))
                 .flat_map(|i| repeat_n(i, ROM_BANK_SIZE))
                 .collect(),
         );
         assert_eq!(rom.len(), ROM_BANK_COUNT * ROM_BANK_SIZE);
+        rom[ROMSIZE_OFFSET] = 0x06; // 2MB ROM
 
         let mut c = Mbc3::new(&rom, &[]);
 
         // Bank 0
         for i in 0u16..(ROM_BANK_SIZE as u16) {
+            if i == ROMSIZE_OFFSET as u16 {
+                continue;
+            }
             assert_eq!(c.read(i), 0);
         }
         // Bank n default (1)
@@ -132,9 +432,13 @@ mod tests {
             c.write(0x2000, b);
             // Bank 0
             for i in 0..(ROM_BANK_SIZE as u16) {
+                if i == ROMSIZE_OFFSET as u16 {
+                    continue;
+                }
                 assert_eq!(c.read(i), 0);
             }
             // Bank n
+            assert_eq!(c.current_rom_bank(), b as usize);
             for i in 0u16..(ROM_BANK_SIZE as u16) {
                 assert_eq!(c.read(0x4000 + i), b);
             }
@@ -153,9 +457,17 @@ mod tests {
         }
     }
 
+    /// MBC3+RAM+BATTERY with the specified RAM size code
+    fn ram_rom(ramsize: u8) -> Vec<u8> {
+        let mut rom = vec![0; 32 * 1024];
+        rom[CARTTYPE_OFFSET] = 0x13;
+        rom[RAMSIZE_OFFSET] = ramsize;
+        rom
+    }
+
     #[test]
     fn ram_bank_switching() {
-        let mut c = Mbc3::new(&[], &[]);
+        let mut c = Mbc3::new(&ram_rom(0x03), &[]);
 
         for b in 0u8..(RAM_BANK_COUNT as u8) {
             c.write(0x4000, b);
@@ -175,11 +487,80 @@ mod tests {
         // Masking
         c.write(0x4000, 0x80);
         assert_eq!(c.read(0xA000 as u16), 1);
+
+        // Banks 4-7 mirror 0-3
+        for b in 4u8..8 {
+            c.write(0x4000, b);
+            assert_eq!(c.read(0xA000), b - 4 + 1);
+        }
+    }
+
+    #[test]
+    fn ram_size_from_header() {
+        // No RAM
+        let mut c = Mbc3::new(&ram_rom(0x00), &[]);
+        c.write(0xA000, 0x12);
+        assert_eq!(c.read(0xA000), 0xFF);
+        assert!(c.get_save().is_empty());
+
+        // 8KB, every bank select maps the same bank
+        let mut c = Mbc3::new(&ram_rom(0x02), &[]);
+        c.write(0x4000, 3);
+        c.write(0xA000, 0x12);
+        c.write(0x4000, 0);
+        assert_eq!(c.read(0xA000), 0x12);
+        assert_eq!(c.get_save().len(), RAM_BANK_SIZE);
+    }
+
+    #[test]
+    fn mbc30_ram_banks() {
+        let mut c = Mbc3::new(&ram_rom(0x05), &[]);
+        assert!(c.mbc30);
+
+        for b in 0u8..(MBC30_RAM_BANK_COUNT as u8) {
+            c.write(0x4000, b);
+            for n in 0u16..(RAM_BANK_SIZE as u16) {
+                assert_eq!(c.read(0xA000 + n), 0);
+                c.write(0xA000 + n, b + 1);
+            }
+        }
+        // Banks 4-7 do not overwrite banks 0-3
+        for b in 0u8..(MBC30_RAM_BANK_COUNT as u8) {
+            c.write(0x4000, b);
+            for n in 0u16..(RAM_BANK_SIZE as u16) {
+                assert_eq!(c.read(0xA000 + n), b + 1);
+            }
+        }
+
+        // 64KB save
+        let save = c.get_save();
+        assert_eq!(save.len(), MBC30_RAM_BANK_COUNT * RAM_BANK_SIZE);
+        assert_eq!(save[7 * RAM_BANK_SIZE], 8);
+        let c = cartridge::load_with_save(&ram_rom(0x05), &save).unwrap();
+        assert_eq!(cartridge::check_save_size(c.as_ref(), &save).unwrap(), None);
+        assert_eq!(c.get_save(), save);
+    }
+
+    #[test]
+    fn mbc30_rom_banks() {
+        let mut rom: Vec<u8> = (0..=(MBC30_ROM_BANKS_MAX as u8))
+            .flat_map(|i| repeat_n(i, ROM_BANK_SIZE))
+            .collect();
+        rom[CARTTYPE_OFFSET] = 0x13;
+        rom[ROMSIZE_OFFSET] = 0x07; // 4MB ROM
+        let mut c = Mbc3::new(&rom, &[]);
+
+        c.write(0x2000, 0xFF);
+        assert_eq!(c.current_rom_bank(), 0xFF);
+        assert_eq!(c.read(0x4000), 0xFF);
+        c.write(0x2000, 0x80);
+        assert_eq!(c.read(0x4000), 0x80);
+        assert!(c.diagnostics().is_empty());
     }
 
     #[test]
     fn rtc_ignored() {
-        let mut c = Mbc3::new(&[], &[]);
+        let mut c = Mbc3::new(&ram_rom(0x03), &[]);
 
         for b in 0x08..=0x0C {
             c.write(0x4000, b);
@@ -196,4 +577,206 @@ mod tests {
             }
         }
     }
+
+    /// MBC3+TIMER+RAM+BATTERY, 8KB RAM
+    fn rtc_rom() -> Vec<u8> {
+        let mut rom = vec![0; 32 * 1024];
+        rom[CARTTYPE_OFFSET] = 0x10;
+        rom[0x149] = 2;
+        rom
+    }
+
+    /// Reads the latched RTC registers
+    fn read_rtc(c: &mut Mbc3) -> Vec<u8> {
+        (0..RTC_REGS as u8)
+            .map(|r| {
+                c.write(0x4000, RTC_BANKSEL + r);
+                c.read(0xA000)
+            })
+            .collect()
+    }
+
+    fn write_rtc(c: &mut Mbc3, regs: &[u8]) {
+        for (r, &val) in regs.iter().enumerate() {
+            c.write(0x4000, RTC_BANKSEL + r as u8);
+            c.write(0xA000, val);
+        }
+    }
+
+    fn latch(c: &mut Mbc3) {
+        c.write(0x6000, 0);
+        c.write(0x6000, 1);
+    }
+
+    #[test]
+    fn rtc_registers() {
+        let mut c = Mbc3::new(&rtc_rom(), &[]);
+        assert!(c.has_rtc());
+        assert_eq!(read_rtc(&mut c), [0; RTC_REGS]);
+
+        write_rtc(&mut c, &[0xFF; RTC_REGS]);
+        assert_eq!(read_rtc(&mut c), RTC_MASKS);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), RTC_MASKS);
+    }
+
+    #[test]
+    fn save_rtc_roundtrip() {
+        let mut c = Mbc3::new(&rtc_rom(), &[]);
+        c.write(0x4000, 0);
+        c.write(0xA000, 0x12);
+        c.write(0xBFFF, 0x34);
+        write_rtc(&mut c, &[59, 30, 23, 0x12, 0x01]);
+        latch(&mut c);
+
+        let save = c.get_save();
+        assert_eq!(save.len(), RAM_BANK_SIZE + RTC_FOOTER_SIZE);
+        assert_eq!(save[0], 0x12);
+        assert_eq!(save[RAM_BANK_SIZE - 1], 0x34);
+
+        let mut c = Mbc3::new(&rtc_rom(), &save);
+        assert_eq!(c.get_save()[..RAM_BANK_SIZE], save[..RAM_BANK_SIZE]);
+        assert_eq!(read_rtc(&mut c), [59, 30, 23, 0x12, 0x01]);
+    }
+
+    #[test]
+    fn save_rtc_footer() {
+        // Save as written by BGB: RAM, then the clock and latched
+        // registers as 32-bit values and a 64-bit timestamp.
+        let mut save = vec![0xAA; RAM_BANK_SIZE];
+        save.extend_from_slice(&hex!(
+            "0a000000 14000000 05000000 2c010000 01000000"
+            "0b000000 15000000 06000000 2d000000 00000000"
+            "00e1f50500000000"
+        ));
+        let mut c = Mbc3::new(&rtc_rom(), &save);
+        assert_eq!(c.read(0xA000), 0xAA);
+        // The latched registers are visible until the next latch
+        assert_eq!(read_rtc(&mut c), [11, 21, 6, 0x2D, 0x00]);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [10, 20, 5, 0x2C, 0x01]);
+
+        // Short (32-bit timestamp) footer
+        let mut c = Mbc3::new(&rtc_rom(), &save[..save.len() - 4]);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [10, 20, 5, 0x2C, 0x01]);
+
+        // No footer
+        let mut c = Mbc3::new(&rtc_rom(), &save[..RAM_BANK_SIZE]);
+        assert_eq!(c.read(0xA000), 0xAA);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [0; RTC_REGS]);
+    }
+
+    #[test]
+    fn save_size() {
+        let c = cartridge::load(&rtc_rom()).unwrap();
+        let save = c.get_save();
+        assert_eq!(cartridge::check_save_size(c.as_ref(), &save).unwrap(), None);
+        assert_eq!(cartridge::check_save_size(c.as_ref(), &[]).unwrap(), None);
+        assert_eq!(
+            cartridge::check_save_size(c.as_ref(), &save[..RAM_BANK_SIZE]).unwrap(),
+            None
+        );
+        assert_eq!(
+            cartridge::check_save_size(c.as_ref(), &save[..save.len() - 4]).unwrap(),
+            None
+        );
+        assert!(cartridge::check_save_size(c.as_ref(), &[0; 100])
+            .unwrap()
+            .is_some());
+
+        // RAM larger than declared in the header is truncated
+        let mut save = vec![0x55; 4 * RAM_BANK_SIZE];
+        save[RAM_BANK_SIZE] = 0x66;
+        let c = cartridge::load_with_save(&rtc_rom(), &save).unwrap();
+        assert!(cartridge::check_save_size(c.as_ref(), &save)
+            .unwrap()
+            .is_some());
+        assert_eq!(c.get_save().len(), RAM_BANK_SIZE + RTC_FOOTER_SIZE);
+        assert!(c.get_save()[..RAM_BANK_SIZE].iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn ram_written() {
+        // Reading and latching the clock leaves the save untouched
+        let mut c = Mbc3::new(&rtc_rom(), &[]);
+        latch(&mut c);
+        read_rtc(&mut c);
+        assert!(!c.ram_written());
+        write_rtc(&mut c, &[10]);
+        assert!(c.ram_written());
+
+        let mut c = Mbc3::new(&rtc_rom(), &[]);
+        c.write(0x4000, 0);
+        c.write(0xA000, 0x12);
+        assert!(c.ram_written());
+    }
+
+    #[test]
+    fn save_without_rtc() {
+        // MBC3+RAM+BATTERY, 32KB RAM
+        let mut rom = vec![0; 32 * 1024];
+        rom[CARTTYPE_OFFSET] = 0x13;
+        rom[0x149] = 3;
+        let mut c = Mbc3::new(&rom, &[0x42; 4 * RAM_BANK_SIZE + RTC_FOOTER_SIZE]);
+        assert!(!c.has_rtc());
+        assert_eq!(c.get_save(), vec![0x42; 4 * RAM_BANK_SIZE]);
+        assert_eq!(read_rtc(&mut c), [0; RTC_REGS]);
+    }
+
+    #[test]
+    fn rtc_running() {
+        let mut c = Mbc3::new(&rtc_rom(), &[]);
+        c.set_time(100);
+        c.set_time(100 + 3661);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [1, 1, 1, 0, 0]);
+
+        // Time does not go back
+        c.set_time(50);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [1, 1, 1, 0, 0]);
+
+        // Day counter overflow
+        write_rtc(&mut c, &[59, 59, 23, 0xFF, 0x00]);
+        c.set_time(51);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [0, 0, 0, 0x00, 0x01]);
+        write_rtc(&mut c, &[59, 59, 23, 0xFF, 0x01]);
+        c.set_time(52);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [0, 0, 0, 0x00, RTC_CARRY]);
+
+        // Carry is sticky, halt stops the clock
+        c.set_time(53);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [1, 0, 0, 0x00, RTC_CARRY]);
+        write_rtc(&mut c, &[1, 0, 0, 0x00, RTC_CARRY | RTC_HALT]);
+        c.set_time(1000);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [1, 0, 0, 0x00, RTC_CARRY | RTC_HALT]);
+    }
+
+    #[test]
+    fn rtc_catch_up() {
+        let mut c = Mbc3::new(&rtc_rom(), &[]);
+        c.set_time(1000);
+        write_rtc(&mut c, &[0, 30, 0, 0, 0]);
+        let save = c.get_save();
+        assert_eq!(save[save.len() - 8..], 1000u64.to_le_bytes());
+
+        // Time passed since the save was made
+        let mut c = Mbc3::new(&rtc_rom(), &save);
+        c.set_time(1000 + 120);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [0, 32, 0, 0, 0]);
+
+        // A timestamp in the future is ignored
+        let mut c = Mbc3::new(&rtc_rom(), &save);
+        c.set_time(0);
+        c.set_time(120);
+        latch(&mut c);
+        assert_eq!(read_rtc(&mut c), [0, 32, 0, 0, 0]);
+    }
 }
@@ -1,33 +1,38 @@
-use super::cartridge::Cartridge;
+use super::cartridge::{declared_rom_size, BankKind, Cartridge, Diagnostics};
+use super::rom::Rom;
 use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+
+use anyhow::{bail, Result};
 
 const ROM_BANK_SIZE: usize = 16 * 1024;
-const ROM_BANK_COUNT: usize = ROM_BANKS_MAX + 1;
-const ROM_BANKS_MAX: usize = 0x1FF;
 
 const RAM_BANK_SIZE: usize = 8 * 1024;
 const RAM_BANK_COUNT: usize = RAM_BANKS_MAX + 1;
 const RAM_BANKS_MAX: usize = 0x0F;
 
 pub struct Mbc5 {
-    rom: Vec<u8>,
+    rom: Rom,
     rom_banksel: u16,
     ram: Vec<u8>,
     ram_banksel: u8,
     rom_banks: usize,
+    ram_written: bool,
+    diagnostics: Diagnostics,
 }
 
 impl Mbc5 {
     pub fn new(rom: &[u8], save: &[u8]) -> Self {
         let mut cart = Self {
-            rom: vec![0; ROM_BANK_COUNT * ROM_BANK_SIZE],
+            rom: Rom::new(rom, declared_rom_size(rom)),
             ram: vec![0; RAM_BANK_COUNT * RAM_BANK_SIZE],
             rom_banksel: 1,
             ram_banksel: 0,
             rom_banks: 0,
+            ram_written: false,
+            diagnostics: Diagnostics::default(),
         };
-        cart.rom[0..rom.len()].copy_from_slice(rom);
-        cart.ram[0..save.len()].copy_from_slice(save);
+        cart.load_save(save);
 
         // Keep this calculated in RAM because it gets looked up a lot.
         cart.rom_banks = cart.get_rom_banks();
@@ -37,8 +42,8 @@ impl Mbc5 {
     fn rom_translate(&self, addr: u16) -> usize {
         assert!(addr >= 0x4000);
 
-        let bankaddr: usize =
-            ROM_BANK_SIZE * (self.rom_banksel as usize).rem_euclid(self.rom_banks);
+        // Bank numbers are masked to the size in the header
+        let bankaddr: usize = ROM_BANK_SIZE * (self.rom_banksel as usize & (self.rom_banks - 1));
         bankaddr + (addr as usize - 0x4000)
     }
 
@@ -48,9 +53,34 @@ impl Mbc5 {
         let bankaddr: usize = RAM_BANK_SIZE * (self.ram_banksel as usize);
         bankaddr + (addr as usize - 0xA000)
     }
+
+    /// Checks the selected ROM bank against the size in the header
+    fn check_rom_banksel(&mut self) {
+        if self.rom_banksel as usize >= self.rom_banks {
+            let mapped = self.current_rom_bank();
+            self.diagnostics
+                .record(BankKind::Rom, self.rom_banksel as usize, mapped);
+        }
+    }
 }
 
 impl Cartridge for Mbc5 {
+    fn get_rom_size(&self) -> usize {
+        self.rom.size()
+    }
+
+    fn current_rom_bank(&self) -> usize {
+        self.rom_translate(0x4000) / ROM_BANK_SIZE
+    }
+
+    fn diagnostics(&self) -> Diagnostics {
+        self.rom.add_diagnostics(self.diagnostics.clone())
+    }
+
+    fn ram_written(&self) -> bool {
+        self.ram_written
+    }
+
     fn dump_state(&self) -> String {
         format!(
             "ROM bank: {:02X} - RAM bank: {:02X}",
@@ -61,15 +91,21 @@ impl Cartridge for Mbc5 {
     fn get_save(&self) -> Vec<u8> {
         self.ram.to_owned()
     }
+
+    fn load_save(&mut self, save: &[u8]) {
+        self.ram.fill(0);
+        let len = save.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&save[..len]);
+    }
 }
 
 impl BusMember for Mbc5 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             // ROM - Always bank 0
-            0x0000..=0x3FFF => self.rom[addr as usize],
+            0x0000..=0x3FFF => self.rom.read(addr as usize),
             // ROM - Bank 1..=511
-            0x4000..=0x7FFF => self.rom[self.rom_translate(addr)],
+            0x4000..=0x7FFF => self.rom.read(self.rom_translate(addr)),
             // RAM - Bank 0..=15
             0xA000..=0xBFFF => self.ram[self.ram_translate(addr)],
 
@@ -82,15 +118,28 @@ impl BusMember for Mbc5 {
             // RAM enable
             0x0000..=0x1FFF => (),
             // ROM bank select (lower 8-bits)
-            0x2000..=0x2FFF => self.rom_banksel = val as u16 | (self.rom_banksel & 0x100),
+            0x2000..=0x2FFF => {
+                self.rom_banksel = val as u16 | (self.rom_banksel & 0x100);
+                self.check_rom_banksel();
+            }
             // ROM bank select (bit 9)
-            0x3000..=0x3FFF => self.rom_banksel = (self.rom_banksel & 0xFF) | (val as u16 & 1) << 8,
+            0x3000..=0x3FFF => {
+                self.rom_banksel = (self.rom_banksel & 0xFF) | (val as u16 & 1) << 8;
+                self.check_rom_banksel();
+            }
             // RAM bank select
-            0x4000..=0x5FFF => self.ram_banksel = val & RAM_BANKS_MAX as u8,
+            0x4000..=0x5FFF => {
+                self.ram_banksel = val & RAM_BANKS_MAX as u8;
+                if val != 0 && val as usize >= self.get_ram_banks() {
+                    self.diagnostics
+                        .record(BankKind::Ram, val as usize, self.ram_banksel as usize);
+                }
+            }
             // RAM
             0xA000..=0xBFFF => {
                 let tr_addr = self.ram_translate(addr);
-                self.ram[tr_addr] = val
+                self.ram[tr_addr] = val;
+                self.ram_written = true;
             }
 
             _ => (), //panic!("write to {:04X}", addr),
@@ -98,6 +147,26 @@ impl BusMember for Mbc5 {
     }
 }
 
+impl Savestate for Mbc5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u16(self.rom_banksel);
+        w.put_u8(self.ram_banksel);
+        w.put_block(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rom_banksel = r.get_u16()?;
+        self.ram_banksel = r.get_u8()?;
+        let ram = r.get_block()?;
+        if ram.len() != self.ram.len() {
+            bail!("Cartridge RAM size mismatch");
+        }
+        self.ram.copy_from_slice(ram);
+        self.ram_written = true;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::cartridge::*;
@@ -105,6 +174,9 @@ mod tests {
 
     use itertools::repeat_n;
 
+    const ROM_BANK_COUNT: usize = ROM_BANKS_MAX + 1;
+    const ROM_BANKS_MAX: usize = 0x1FF;
+
     #[test]
     fn rom_bank_switching() {
         let mut rom: Vec<u8> = (0u16..=(ROM_BANKS_MAX as u16))
@@ -142,6 +214,7 @@ mod tests {
                 assert_eq!(c.read(i), 0);
             }
             // Bank n
+            assert_eq!(c.current_rom_bank(), b as usize);
             for i in (0..ROM_BANK_SIZE).step_by(2) {
                 assert_eq!(c.read16(0x4000 + i as u16), b);
             }
@@ -164,7 +237,7 @@ mod tests {
 
     #[test]
     fn ram_bank_switching() {
-        let mut c = Mbc5::new(&[], &[]);
+        let mut c = Mbc5::new(&[0; CARTHEADER_END], &[]);
 
         for b in 0u8..(RAM_BANK_COUNT as u8) {
             c.write(0x4000, b);
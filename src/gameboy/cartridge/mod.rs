@@ -1,5 +1,11 @@
+#[cfg(feature = "archives")]
+pub mod archive;
 pub mod cartridge;
+pub mod header;
+#[cfg(feature = "archives")]
+pub mod inflate;
 pub mod mbc1;
 pub mod mbc3;
 pub mod mbc5;
+pub mod rom;
 pub mod romonly;
@@ -0,0 +1,87 @@
+use std::cell::Cell;
+
+use super::cartridge::Diagnostics;
+
+/// ROM of a cartridge, of the size declared in the header. ROM files
+/// do not always match that size: data beyond it (an overdump) is
+/// ignored, and trimmed ROM files read as 0xFF past their end, like
+/// the padding that was stripped.
+pub struct Rom {
+    data: Vec<u8>,
+    /// Declared size
+    size: usize,
+    /// First offset read beyond the end of the ROM file
+    missing_read: Cell<Option<usize>>,
+}
+
+impl Rom {
+    pub fn new(rom: &[u8], size: usize) -> Self {
+        Self {
+            data: rom[..rom.len().min(size)].to_vec(),
+            size,
+            missing_read: Cell::new(None),
+        }
+    }
+
+    /// Declared size of the ROM (in bytes)
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads a byte at an offset in the ROM. Offsets beyond the end of
+    /// the ROM file read as 0xFF.
+    pub fn read(&self, offset: usize) -> u8 {
+        match self.data.get(offset) {
+            Some(&b) => b,
+            None => {
+                if self.missing_read.get().is_none() {
+                    self.missing_read.set(Some(offset));
+                }
+                0xFF
+            }
+        }
+    }
+
+    /// Adds a warning for reads beyond the end of the ROM file (if any)
+    /// to the diagnostics of the memory bank controller
+    pub fn add_diagnostics(&self, mut diagnostics: Diagnostics) -> Diagnostics {
+        if let Some(offset) = self.missing_read.get() {
+            diagnostics.record_missing_rom(offset, self.data.len());
+        }
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cartridge::MissingRom;
+    use super::*;
+
+    #[test]
+    fn trimmed() {
+        let r = Rom::new(&[1, 2, 3], 8);
+        assert_eq!(r.size(), 8);
+        assert_eq!(r.read(2), 3);
+        assert!(r.add_diagnostics(Diagnostics::default()).is_empty());
+
+        assert_eq!(r.read(5), 0xFF);
+        assert_eq!(r.read(3), 0xFF);
+        // Only the first read is reported
+        let d = r.add_diagnostics(Diagnostics::default());
+        assert_eq!(
+            d.missing_rom(),
+            Some(MissingRom {
+                offset: 5,
+                file_size: 3
+            })
+        );
+    }
+
+    #[test]
+    fn overdumped() {
+        let r = Rom::new(&[1, 2, 3, 4], 2);
+        assert_eq!(r.size(), 2);
+        assert_eq!(r.read(1), 2);
+        assert_eq!(r.read(2), 0xFF);
+    }
+}
@@ -1,18 +1,38 @@
 use super::cartridge::Cartridge;
 use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+
+use anyhow::{bail, Result};
 
 pub struct RomOnly {
-    rom: [u8; 32 * 1024],
+    rom: Vec<u8>,
+
+    /// External RAM (ROM+RAM cartridges), empty if not present
+    ram: Vec<u8>,
+
+    /// RAM was written since loading
+    ram_written: bool,
 }
 
 impl RomOnly {
     const ROM_SIZE: usize = 32 * 1024;
+    const RAM_SIZE: usize = 8 * 1024;
 
+    /// Creates a cartridge without RAM. ROMs smaller than 32KB read
+    /// as 0xFF beyond their end.
     pub fn new(rom: &[u8]) -> Self {
-        let mut cart = Self {
-            rom: [0; Self::ROM_SIZE],
-        };
-        cart.rom.copy_from_slice(rom);
+        Self {
+            rom: rom[..rom.len().min(Self::ROM_SIZE)].to_vec(),
+            ram: vec![],
+            ram_written: false,
+        }
+    }
+
+    /// Creates a cartridge with 8KB RAM (type 0x08/0x09)
+    pub fn new_with_ram(rom: &[u8], save: &[u8]) -> Self {
+        let mut cart = Self::new(rom);
+        cart.ram = vec![0; Self::RAM_SIZE];
+        cart.load_save(save);
         cart
     }
 }
@@ -23,18 +43,193 @@ impl Cartridge for RomOnly {
     }
 
     fn get_save(&self) -> Vec<u8> {
-        vec![]
+        self.ram.to_owned()
+    }
+
+    fn load_save(&mut self, save: &[u8]) {
+        let len = save.len().min(self.ram.len());
+        self.ram.fill(0);
+        self.ram[..len].copy_from_slice(&save[..len]);
+    }
+
+    fn ram_written(&self) -> bool {
+        self.ram_written
     }
 }
 
 impl BusMember for RomOnly {
     fn read(&self, addr: u16) -> u8 {
-        if addr as usize > self.rom.len() {
-            0xFF
-        } else {
-            self.rom[addr as usize]
+        match addr {
+            0x0000..=0x7FFF => self.rom.get(addr as usize).copied().unwrap_or(0xFF),
+            0xA000..=0xBFFF if !self.ram.is_empty() => self.ram[addr as usize - 0xA000],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        if let 0xA000..=0xBFFF = addr {
+            if !self.ram.is_empty() {
+                self.ram[addr as usize - 0xA000] = val;
+                self.ram_written = true;
+            }
+        }
+    }
+}
+
+impl Savestate for RomOnly {
+    fn save_state(&self, w: &mut StateWriter) {
+        if !self.ram.is_empty() {
+            w.put_block(&self.ram);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        if !self.ram.is_empty() {
+            let ram = r.get_block()?;
+            if ram.len() != self.ram.len() {
+                bail!("Cartridge RAM size mismatch");
+            }
+            self.ram.copy_from_slice(ram);
+            self.ram_written = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cartridge::cartridge::{
+        self, CARTTYPE_OFFSET, MAX_SAVE_SIZE, ROMSIZE_OFFSET,
+    };
+
+    #[test]
+    fn small_rom() {
+        let mut rom: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        rom[CARTTYPE_OFFSET] = 0;
+        let c = cartridge::load(&rom).unwrap();
+        assert_eq!(c.read(0x0000), 0x00);
+        assert_eq!(c.read(0x03FF), 0xFF);
+        assert_eq!(c.read(0x0101), 0x01);
+        assert_eq!(c.read(0x0400), 0xFF);
+        assert_eq!(c.read(0x3FFF), 0xFF);
+        assert_eq!(c.read(0x4000), 0xFF);
+        assert_eq!(c.read(0x7FFF), 0xFF);
+        // No RAM
+        assert_eq!(c.read(0xA000), 0xFF);
+        assert!(c.get_save().is_empty());
+    }
+
+    #[test]
+    fn size_warning() {
+        let mut rom = vec![0; 32 * 1024];
+        assert_eq!(cartridge::check_rom_size(&rom), None);
+        rom[ROMSIZE_OFFSET] = 1;
+        assert_eq!(
+            cartridge::check_rom_size(&rom).unwrap(),
+            "ROM is 32768 bytes, but the header declares 65536 bytes"
+        );
+        assert!(cartridge::check_rom_size(&rom[..1024]).is_some());
+        assert!(cartridge::check_rom_size(&rom[..0x100]).is_some());
+    }
+
+    #[test]
+    fn full_rom() {
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x3FFF] = 1;
+        rom[0x4000] = 2;
+        rom[0x7FFF] = 3;
+        let c = cartridge::load(&rom).unwrap();
+        assert_eq!(c.read(0x3FFF), 1);
+        assert_eq!(c.read(0x4000), 2);
+        assert_eq!(c.read(0x7FFF), 3);
+    }
+
+    #[test]
+    fn rom_ram() {
+        for carttype in [0x08, 0x09] {
+            let mut rom = vec![0; 32 * 1024];
+            rom[CARTTYPE_OFFSET] = carttype;
+            let mut c = cartridge::load_with_save(&rom, &[0x12, 0x34]).unwrap();
+            assert_eq!(c.read(0xA000), 0x12);
+            assert_eq!(c.read(0xA001), 0x34);
+            c.write(0xBFFF, 0x56);
+            assert_eq!(c.read(0xBFFF), 0x56);
+            // ROM is read-only
+            c.write(0x0100, 0x56);
+            assert_eq!(c.read(0x0100), 0x00);
+
+            let save = c.get_save();
+            assert_eq!(save.len(), 8 * 1024);
+            assert_eq!(save[0x1FFF], 0x56);
         }
     }
 
-    fn write(&mut self, _addr: u16, _val: u8) {}
+    #[test]
+    fn rom_ram_savestate() {
+        let mut c = RomOnly::new_with_ram(&[], &[0x12]);
+        let mut w = StateWriter::new();
+        c.save_state(&mut w);
+        c.write(0xA000, 0x34);
+        let state = w.into_vec();
+        c.load_state(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert_eq!(c.read(0xA000), 0x12);
+        assert!(c.ram_written());
+    }
+
+    #[test]
+    fn save_size() {
+        let mut rom = vec![0; 32 * 1024];
+        rom[CARTTYPE_OFFSET] = 0x09;
+        let c = cartridge::load(&rom).unwrap();
+
+        // Exact size
+        let save = vec![0x12; 8 * 1024];
+        assert_eq!(cartridge::check_save_size(c.as_ref(), &save).unwrap(), None);
+        let c = cartridge::load_with_save(&rom, &save).unwrap();
+        assert_eq!(c.get_save(), save);
+
+        // Larger, truncated
+        let mut save = vec![0x12; 8 * 1024];
+        save.extend([0x34; 100]);
+        assert!(cartridge::check_save_size(c.as_ref(), &save)
+            .unwrap()
+            .unwrap()
+            .ends_with("truncating"));
+        let c = cartridge::load_with_save(&rom, &save).unwrap();
+        assert_eq!(c.get_save(), save[..8 * 1024]);
+
+        // Smaller, padded with zeroes
+        let save = vec![0x12; 100];
+        assert!(cartridge::check_save_size(c.as_ref(), &save)
+            .unwrap()
+            .unwrap()
+            .ends_with("padding"));
+        let c = cartridge::load_with_save(&rom, &save).unwrap();
+        assert_eq!(c.get_save()[..100], save);
+        assert!(c.get_save()[100..].iter().all(|&b| b == 0));
+
+        // Larger than any cartridge
+        let save = vec![0; MAX_SAVE_SIZE + 1];
+        assert!(cartridge::check_save_size(c.as_ref(), &save).is_err());
+        assert!(cartridge::load_with_save(&rom, &save).is_err());
+        assert!(cartridge::load_with_save(&rom, &save[..MAX_SAVE_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn ram_written() {
+        let mut c = RomOnly::new_with_ram(&[], &[0x12]);
+        assert!(!c.ram_written());
+        let _ = c.read(0xA000);
+        c.write(0x2000, 0x01);
+        assert!(!c.ram_written());
+        c.write(0xA000, 0x12);
+        assert!(c.ram_written());
+
+        // No RAM to write
+        let mut c = RomOnly::new(&[]);
+        c.write(0xA000, 0x12);
+        assert!(!c.ram_written());
+    }
 }
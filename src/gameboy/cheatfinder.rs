@@ -0,0 +1,305 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, Result};
+
+use crate::gameboy::bus::bus::BusMember;
+
+/// Memory searched for cheats: cartridge RAM and WRAM, as currently
+/// mapped (so only the selected cartridge RAM/WRAM bank).
+pub const SEARCH_REGIONS: [RangeInclusive<u16>; 2] = [0xA000..=0xBFFF, 0xC000..=0xDFFF];
+
+/// Copy of searchable memory at a point in time
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    regions: Vec<(u16, Vec<u8>)>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a snapshot of SEARCH_REGIONS, without side effects
+    pub fn capture(bus: &dyn BusMember) -> Self {
+        let mut snapshot = Self::new();
+        for region in SEARCH_REGIONS {
+            let start = *region.start();
+            snapshot.add_region(start, region.map(|addr| bus.peek(addr)).collect());
+        }
+        snapshot
+    }
+
+    /// Adds a block of memory starting at 'start'
+    pub fn add_region(&mut self, start: u16, data: Vec<u8>) {
+        self.regions.push((start, data));
+    }
+
+    pub fn get(&self, addr: u16) -> Option<u8> {
+        self.regions
+            .iter()
+            .find_map(|(start, data)| data.get(addr.checked_sub(*start)? as usize).copied())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.regions.iter().flat_map(|(start, data)| {
+            data.iter()
+                .enumerate()
+                .map(move |(i, &v)| (start.wrapping_add(i as u16), v))
+        })
+    }
+}
+
+/// Search filter, applied to the value of an address in a new snapshot
+/// compared to its value in the previous snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Equal(u8),
+    Increased,
+    Decreased,
+    Changed,
+    Unchanged,
+}
+
+impl Filter {
+    /// Parses a filter from debugger command arguments (e.g. "eq 99")
+    pub fn parse(args: &[&str]) -> Result<Self> {
+        Ok(match args {
+            ["eq", val] => Self::Equal(parse_value(val)?),
+            ["inc"] => Self::Increased,
+            ["dec"] => Self::Decreased,
+            ["ch"] => Self::Changed,
+            ["unch"] => Self::Unchanged,
+            _ => bail!("Invalid filter: {}", args.join(" ")),
+        })
+    }
+
+    fn matches(&self, previous: u8, value: u8) -> bool {
+        match *self {
+            Self::Equal(v) => value == v,
+            Self::Increased => value > previous,
+            Self::Decreased => value < previous,
+            Self::Changed => value != previous,
+            Self::Unchanged => value == previous,
+        }
+    }
+}
+
+/// Parses a decimal value, or hexadecimal if prefixed with '$' or '0x'
+fn parse_value(s: &str) -> Result<u8> {
+    let val = match s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    match val {
+        Ok(v) => Ok(v),
+        Err(_) => bail!("Invalid value: {}", s),
+    }
+}
+
+/// An address still matching all filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub addr: u16,
+    /// Value in the last snapshot
+    pub value: u8,
+    /// Value in the snapshot before that
+    pub previous: u8,
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}: {:3} (${:02X}), was {:3} (${:02X})",
+            self.addr, self.value, self.value, self.previous, self.previous
+        )
+    }
+}
+
+/// Narrows down the address of a value by repeatedly searching memory
+/// ("search for value, change it in game, search again").
+#[derive(Debug, Default)]
+pub struct CheatFinder {
+    /// None if no search is in progress
+    candidates: Option<Vec<Candidate>>,
+}
+
+impl CheatFinder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new search with every address in the snapshot as candidate
+    pub fn start(&mut self, snapshot: &Snapshot) {
+        self.candidates = Some(
+            snapshot
+                .iter()
+                .map(|(addr, value)| Candidate {
+                    addr,
+                    value,
+                    previous: value,
+                })
+                .collect(),
+        );
+    }
+
+    /// Stops the current search
+    pub fn reset(&mut self) {
+        self.candidates = None;
+    }
+
+    /// Keeps the candidates matching the filter in a new snapshot and
+    /// returns the amount of candidates left. Without a search in
+    /// progress, a new search is started from the snapshot, which only
+    /// works for Filter::Equal.
+    pub fn filter(&mut self, snapshot: &Snapshot, filter: Filter) -> Result<usize> {
+        let Some(ref mut candidates) = self.candidates else {
+            if !matches!(filter, Filter::Equal(_)) {
+                bail!("No search in progress, start by searching for a value");
+            }
+            self.start(snapshot);
+            return self.filter(snapshot, filter);
+        };
+
+        candidates.retain_mut(|c| {
+            let Some(value) = snapshot.get(c.addr) else {
+                return false;
+            };
+            (c.previous, c.value) = (c.value, value);
+            filter.matches(c.previous, c.value)
+        });
+        Ok(candidates.len())
+    }
+
+    /// Returns the current candidates, or an empty slice if no search is
+    /// in progress.
+    pub fn candidates(&self) -> &[Candidate] {
+        self.candidates.as_deref().unwrap_or_default()
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.candidates.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gameboy::bus::testbus::Testbus;
+
+    /// Snapshot of two regions, 0xC000 - 0xC003 and 0xD000 - 0xD001
+    fn image(low: [u8; 4], high: [u8; 2]) -> Snapshot {
+        let mut s = Snapshot::new();
+        s.add_region(0xC000, low.to_vec());
+        s.add_region(0xD000, high.to_vec());
+        s
+    }
+
+    fn addrs(f: &CheatFinder) -> Vec<u16> {
+        f.candidates().iter().map(|c| c.addr).collect()
+    }
+
+    #[test]
+    fn snapshot_get() {
+        let s = image([1, 2, 3, 4], [5, 6]);
+        assert_eq!(s.get(0xC000), Some(1));
+        assert_eq!(s.get(0xC003), Some(4));
+        assert_eq!(s.get(0xC004), None);
+        assert_eq!(s.get(0xD001), Some(6));
+        assert_eq!(s.get(0x0000), None);
+        assert_eq!(s.iter().count(), 6);
+    }
+
+    #[test]
+    fn equal() {
+        let mut f = CheatFinder::new();
+        assert!(!f.in_progress());
+        assert_eq!(
+            f.filter(&image([99, 1, 99, 2], [99, 0]), Filter::Equal(99))
+                .unwrap(),
+            3
+        );
+        assert_eq!(addrs(&f), [0xC000, 0xC002, 0xD000]);
+        assert_eq!(
+            f.filter(&image([98, 1, 98, 2], [98, 0]), Filter::Equal(98))
+                .unwrap(),
+            3
+        );
+        // Only the candidates are searched
+        assert_eq!(
+            f.filter(&image([97, 97, 0, 0], [0, 0]), Filter::Equal(97))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            f.candidates(),
+            [Candidate {
+                addr: 0xC000,
+                value: 97,
+                previous: 98
+            }]
+        );
+    }
+
+    #[test]
+    fn relative() {
+        let mut f = CheatFinder::new();
+        let s = image([10, 10, 10, 10], [10, 10]);
+        assert!(f.filter(&s, Filter::Decreased).is_err());
+        f.start(&s);
+        assert_eq!(f.candidates().len(), 6);
+
+        let s = image([9, 11, 10, 0], [200, 10]);
+        assert_eq!(f.filter(&s, Filter::Changed).unwrap(), 4);
+        assert_eq!(addrs(&f), [0xC000, 0xC001, 0xC003, 0xD000]);
+
+        let s = image([8, 12, 10, 0], [201, 10]);
+        assert_eq!(f.filter(&s, Filter::Decreased).unwrap(), 1);
+        assert_eq!(addrs(&f), [0xC000]);
+
+        let s = image([8, 0, 0, 0], [0, 0]);
+        assert_eq!(f.filter(&s, Filter::Unchanged).unwrap(), 1);
+        let s = image([9, 0, 0, 0], [0, 0]);
+        assert_eq!(f.filter(&s, Filter::Increased).unwrap(), 1);
+        assert_eq!(f.filter(&s, Filter::Increased).unwrap(), 0);
+
+        f.reset();
+        assert!(!f.in_progress());
+        assert!(f.candidates().is_empty());
+    }
+
+    #[test]
+    fn increased() {
+        let mut f = CheatFinder::new();
+        f.start(&image([0, 5, 255, 7], [1, 2]));
+        f.filter(&image([1, 4, 0, 7], [3, 2]), Filter::Increased)
+            .unwrap();
+        assert_eq!(addrs(&f), [0xC000, 0xD000]);
+    }
+
+    #[test]
+    fn parse_filter() {
+        assert_eq!(Filter::parse(&["eq", "99"]).unwrap(), Filter::Equal(99));
+        assert_eq!(Filter::parse(&["eq", "$63"]).unwrap(), Filter::Equal(99));
+        assert_eq!(Filter::parse(&["eq", "0xff"]).unwrap(), Filter::Equal(255));
+        assert_eq!(Filter::parse(&["dec"]).unwrap(), Filter::Decreased);
+        assert_eq!(Filter::parse(&["unch"]).unwrap(), Filter::Unchanged);
+        assert!(Filter::parse(&["eq", "256"]).is_err());
+        assert!(Filter::parse(&["eq"]).is_err());
+        assert!(Filter::parse(&["foo"]).is_err());
+    }
+
+    #[test]
+    fn capture() {
+        let mut bus = Testbus::new();
+        bus.write(0xA000, 0x12);
+        bus.write(0xDFFF, 0x34);
+        let s = Snapshot::capture(&bus);
+        assert_eq!(s.get(0xA000), Some(0x12));
+        assert_eq!(s.get(0xDFFF), Some(0x34));
+        assert_eq!(s.get(0xE000), None);
+        assert_eq!(s.iter().count(), 0x4000);
+    }
+}
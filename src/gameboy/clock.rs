@@ -0,0 +1,159 @@
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use crate::tickable::Ticks;
+use crate::time::{HostClock, SystemClock};
+
+use anyhow::Result;
+
+/// How often a host-synced clock reads the host time, in cycles
+const HOST_POLL_CYCLES: u64 = CPU_CLOCK_HZ as u64 / 16;
+
+/// The time source of an emulated system, for everything that needs
+/// wall clock time (e.g. the real time clock of MBC3 cartridges).
+///
+/// An emulated clock only advances with emulated cycles, so runs, movies
+/// and savestates are deterministic. A host-synced clock follows the time
+/// of the host, like the battery-backed clock of a real cartridge.
+pub struct EmuClock {
+    /// Emulated cycles (at normal speed) since power on
+    cycles: u64,
+    host: Option<Box<dyn HostClock>>,
+    /// Last read host time
+    host_now: u64,
+    /// Cycle count at which the host time is read next
+    next_poll: u64,
+    /// Time last returned by tick()
+    reported: Option<u64>,
+}
+
+impl EmuClock {
+    /// Clock advancing with emulated time, starting at 0
+    pub fn emulated() -> Self {
+        Self {
+            cycles: 0,
+            host: None,
+            host_now: 0,
+            next_poll: 0,
+            reported: None,
+        }
+    }
+
+    /// Clock following the host clock
+    pub fn host() -> Self {
+        Self::host_with(Box::new(SystemClock))
+    }
+
+    /// Clock following the specified host clock
+    pub fn host_with(host: Box<dyn HostClock>) -> Self {
+        Self {
+            host: Some(host),
+            ..Self::emulated()
+        }
+    }
+
+    pub fn is_host_synced(&self) -> bool {
+        self.host.is_some()
+    }
+
+    /// Current time, in seconds
+    pub fn now(&self) -> u64 {
+        if self.host.is_some() {
+            self.host_now
+        } else {
+            self.cycles / CPU_CLOCK_HZ as u64
+        }
+    }
+
+    /// Advances the clock, returns the new time if it changed since the
+    /// last call (or on the first call).
+    pub fn tick(&mut self, ticks: Ticks) -> Option<u64> {
+        self.cycles += ticks.get_t_no_ds() as u64;
+        if let Some(host) = &self.host {
+            if self.cycles >= self.next_poll {
+                self.host_now = host.now();
+                self.next_poll = self.cycles + HOST_POLL_CYCLES;
+            }
+        }
+
+        let now = self.now();
+        if self.reported == Some(now) {
+            return None;
+        }
+        self.reported = Some(now);
+        Some(now)
+    }
+}
+
+impl Default for EmuClock {
+    fn default() -> Self {
+        Self::emulated()
+    }
+}
+
+impl Savestate for EmuClock {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.cycles = r.get_u64()?;
+        // Report (and read the host time) again on the next tick
+        self.next_poll = 0;
+        self.reported = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::MockClock;
+
+    #[test]
+    fn emulated() {
+        let mut c = EmuClock::emulated();
+        assert!(!c.is_host_synced());
+        assert_eq!(c.tick(Ticks::from_t(4)), Some(0));
+        assert_eq!(c.tick(Ticks::from_t(4)), None);
+        assert_eq!(c.tick(Ticks::from_t(CPU_CLOCK_HZ - 8)), Some(1));
+        assert_eq!(c.now(), 1);
+
+        // Double speed cycles take half the time
+        assert_eq!(c.tick(Ticks::from_t_ds(CPU_CLOCK_HZ)), None);
+        assert_eq!(c.tick(Ticks::from_t_ds(CPU_CLOCK_HZ)), Some(2));
+    }
+
+    #[test]
+    fn host() {
+        let mock = MockClock::default();
+        mock.set(1000);
+        let mut c = EmuClock::host_with(Box::new(mock.clone()));
+        assert!(c.is_host_synced());
+        assert_eq!(c.tick(Ticks::from_t(4)), Some(1000));
+
+        // Host time is only read every HOST_POLL_CYCLES
+        mock.set(1005);
+        assert_eq!(c.tick(Ticks::from_t(4)), None);
+        assert_eq!(c.tick(Ticks::from_t(HOST_POLL_CYCLES as usize)), Some(1005));
+        assert_eq!(c.now(), 1005);
+
+        // Emulated time does not matter
+        assert_eq!(c.tick(Ticks::from_t(CPU_CLOCK_HZ * 10)), None);
+    }
+
+    #[test]
+    fn savestate() {
+        let mut c = EmuClock::emulated();
+        c.tick(Ticks::from_t(CPU_CLOCK_HZ * 3));
+        let mut w = StateWriter::new();
+        c.save_state(&mut w);
+
+        let mut c2 = EmuClock::emulated();
+        c2.tick(Ticks::from_t(4));
+        let state = w.into_vec();
+        let mut r = StateReader::new(&state).unwrap();
+        c2.load_state(&mut r).unwrap();
+        assert_eq!(c2.now(), 3);
+        assert_eq!(c2.tick(Ticks::from_t(4)), Some(3));
+    }
+}
@@ -0,0 +1,247 @@
+//! Assembler for single SM83 instructions, the inverse of the
+//! disassembly of Instruction. The syntax is that of the mnemonics in
+//! the instruction tables, with the placeholders (d8, a16, r8, ...)
+//! replaced by numbers: $12, 0x12, 12h or decimal (also negative).
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::error::{bail, Result};
+
+use super::instruction::{InstructionDef, Operand};
+use super::instructions::{INSTRUCTIONS, INSTRUCTIONS_CB};
+
+/// An instruction of the tables, with its opcode bytes
+struct Entry {
+    opcode: Vec<u8>,
+    def: &'static InstructionDef,
+}
+
+/// Mnemonics of the instruction tables, with the placeholders replaced
+/// by '#' (see key()), mapped to their instructions
+fn lookup() -> &'static HashMap<String, Entry> {
+    static LOOKUP: OnceLock<HashMap<String, Entry>> = OnceLock::new();
+    LOOKUP.get_or_init(|| {
+        let base: &'static [InstructionDef; 256] = &INSTRUCTIONS;
+        let cb: &'static [InstructionDef; 256] = &INSTRUCTIONS_CB;
+        let mut lookup = HashMap::new();
+        for (prefix, defs) in [(None, base), (Some(0xCB), cb)] {
+            for (op, def) in defs.iter().enumerate() {
+                if matches!(def.mnemonic, "INVALID" | "PREFIX CB") {
+                    continue;
+                }
+                let opcode = prefix.into_iter().chain([op as u8]).collect();
+                let (key, _) = key(def.mnemonic, |operand| {
+                    matches!(operand, "D8" | "D16" | "A8" | "A16" | "R8").then_some(0)
+                });
+                lookup.insert(key, Entry { opcode, def });
+            }
+        }
+        lookup
+    })
+}
+
+/// Normalizes an instruction to uppercase, with a single space after
+/// the mnemonic and no spaces between the operands.
+fn normalize(text: &str) -> String {
+    let text = text.trim().to_ascii_uppercase();
+    match text.split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => {
+            let operands: String = operands.split_whitespace().collect();
+            format!("{} {}", mnemonic, operands)
+        }
+        None => text,
+    }
+}
+
+/// Builds the lookup key of an instruction: the normalized text with the
+/// values in the operands, as recognized by 'value', replaced by '#'.
+/// Returns the key and the values.
+fn key(text: &str, value: impl Fn(&str) -> Option<i64>) -> (String, Vec<i64>) {
+    let text = normalize(text);
+    let Some((mnemonic, operands)) = text.split_once(' ') else {
+        return (text, vec![]);
+    };
+    let mut values = vec![];
+    let operands: Vec<String> = operands
+        .split(',')
+        .map(|operand| {
+            let (pre, inner, post) =
+                if let Some(inner) = operand.strip_prefix('(').and_then(|o| o.strip_suffix(')')) {
+                    ("(", inner, ")")
+                } else if let Some(offset) = operand.strip_prefix("SP+") {
+                    ("SP+", offset, "")
+                } else {
+                    ("", operand, "")
+                };
+            match value(inner) {
+                Some(v) => {
+                    values.push(v);
+                    format!("{}#{}", pre, post)
+                }
+                None => operand.to_string(),
+            }
+        })
+        .collect();
+    (format!("{} {}", mnemonic, operands.join(",")), values)
+}
+
+/// Parses a number: $12, 0x12, 12h or decimal, optionally negative.
+/// Expects uppercase.
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let hex = s
+        .strip_prefix('$')
+        .or_else(|| s.strip_prefix("0X"))
+        .or_else(|| {
+            s.strip_suffix('H')
+                .filter(|h| h.starts_with(|c: char| c.is_ascii_digit()))
+        });
+    let val = match hex {
+        Some(h) if h.chars().all(|c| c.is_ascii_hexdigit()) => i64::from_str_radix(h, 16).ok()?,
+        Some(_) => return None,
+        None if s.chars().all(|c| c.is_ascii_digit()) => s.parse().ok()?,
+        None => return None,
+    };
+    Some(if negative { -val } else { val })
+}
+
+/// Encodes an immediate value of an operand, little endian
+fn encode(operand: &Operand, val: i64) -> Result<Vec<u8>> {
+    let bytes = match operand {
+        Operand::Immediate8 if (-0x80..=0xFF).contains(&val) => vec![val as u8],
+        // LDH also takes the full address
+        Operand::ImmediateIndirect8 if (0..=0xFF).contains(&val) => vec![val as u8],
+        Operand::ImmediateIndirect8 if (0xFF00..=0xFFFF).contains(&val) => vec![val as u8],
+        // Relative values are the offset byte, not a target address
+        Operand::Relative8 | Operand::SPRelative8 if (-0x80..=0xFF).contains(&val) => {
+            vec![val as u8]
+        }
+        Operand::Immediate16 | Operand::ImmediateIndirect16
+            if (-0x8000..=0xFFFF).contains(&val) =>
+        {
+            (val as u16).to_le_bytes().to_vec()
+        }
+        _ => bail!("Value {} out of range", val),
+    };
+    Ok(bytes)
+}
+
+/// Assembles a single instruction (e.g. 'ld a, $12') into its bytes
+pub fn assemble(text: &str) -> Result<Vec<u8>> {
+    let lookup = lookup();
+
+    // Operands that look like numbers can also be part of the
+    // mnemonic (e.g. BIT 0,A or RST 38H)
+    let literal = normalize(text);
+    let (entry, values) = match lookup.get(&literal) {
+        Some(entry) => (entry, vec![]),
+        None => {
+            let (key, values) = key(text, parse_number);
+            match lookup.get(&key) {
+                Some(entry) => (entry, values),
+                None => bail!("Unknown instruction '{}'", literal),
+            }
+        }
+    };
+
+    let mut bytes = entry.opcode.clone();
+    let mut values = values.into_iter();
+    for operand in entry.def.operands.iter() {
+        if operand.immediate_len() == 0 {
+            continue;
+        }
+        let Some(val) = values.next() else {
+            bail!("Missing operand value in '{}'", literal);
+        };
+        bytes.extend(encode(operand, val)?);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gameboy::cpu::instruction::Instruction;
+
+    #[test]
+    fn round_trip() {
+        for (prefix, defs) in [(None, &INSTRUCTIONS), (Some(0xCB), &INSTRUCTIONS_CB)] {
+            for (op, def) in defs.iter().enumerate() {
+                if matches!(def.mnemonic, "INVALID" | "PREFIX CB") {
+                    continue;
+                }
+                let text = def
+                    .mnemonic
+                    .replacen("d16", "$1234", 1)
+                    .replacen("a16", "$1234", 1)
+                    .replacen("d8", "$12", 1)
+                    .replacen("a8", "$12", 1)
+                    .replacen("r8", "$12", 1);
+                let bytes = assemble(&text).unwrap();
+                let opcode: Vec<u8> = prefix.into_iter().chain([op as u8]).collect();
+                assert_eq!(bytes[..opcode.len()], opcode, "{}", text);
+                assert_eq!(bytes.len(), def.len, "{}", text);
+
+                let i = Instruction::decode(&mut bytes.into_iter()).unwrap();
+                assert_eq!(i.to_asm(), text);
+            }
+        }
+    }
+
+    #[test]
+    fn unique_mnemonics() {
+        let count = [&INSTRUCTIONS, &INSTRUCTIONS_CB]
+            .iter()
+            .flat_map(|defs| defs.iter())
+            .filter(|d| !matches!(d.mnemonic, "INVALID" | "PREFIX CB"))
+            .count();
+        assert_eq!(lookup().len(), count);
+    }
+
+    #[test]
+    fn syntax() {
+        assert_eq!(assemble("ld a, $12").unwrap(), [0x3E, 0x12]);
+        assert_eq!(assemble("  LD   A,0x12 ").unwrap(), [0x3E, 0x12]);
+        assert_eq!(assemble("ld a,12h").unwrap(), [0x3E, 0x12]);
+        assert_eq!(assemble("ld a,18").unwrap(), [0x3E, 0x12]);
+        assert_eq!(assemble("call 1234h").unwrap(), [0xCD, 0x34, 0x12]);
+        assert_eq!(assemble("jr -2").unwrap(), [0x18, 0xFE]);
+        assert_eq!(assemble("ld hl,sp+-1").unwrap(), [0xF8, 0xFF]);
+        assert_eq!(assemble("ldh ($ff80),a").unwrap(), [0xE0, 0x80]);
+        assert_eq!(assemble("ldh ($80),a").unwrap(), [0xE0, 0x80]);
+        assert_eq!(assemble("ld ($c000),sp").unwrap(), [0x08, 0x00, 0xC0]);
+        assert_eq!(assemble("ld a,(c)").unwrap(), [0xF2]);
+        assert_eq!(assemble("ld (hl+),a").unwrap(), [0x22]);
+        assert_eq!(assemble("bit 7,h").unwrap(), [0xCB, 0x7C]);
+        assert_eq!(assemble("rst 38h").unwrap(), [0xFF]);
+        assert_eq!(assemble("stop 0").unwrap(), [0x10, 0x00]);
+        assert_eq!(assemble("nop").unwrap(), [0x00]);
+    }
+
+    #[test]
+    fn errors() {
+        for text in [
+            "",
+            "foo",
+            "ld a",
+            "ld a,$100",
+            "ld a,-129",
+            "ld bc,$10000",
+            "ld a,(bc",
+            "ld a,$",
+            "ld a,1g",
+            "ldh ($ff00+c),a",
+            "ldh ($1234),a",
+            "bit 8,a",
+            "invalid",
+            "prefix cb",
+        ] {
+            assert!(assemble(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -1,11 +1,17 @@
-use anyhow::{bail, Result};
+use crate::error::{bail, ensure, Result};
 use std::borrow::Borrow;
+use std::io::Write;
+use thiserror::Error;
 
 use super::super::bus::bus::{Bus, BusIterator, BusMember};
 use super::alu;
+use super::history::PcHistory;
 use super::instruction::{Instruction, Operand};
 use super::regs::{Flag, Register, RegisterFile, RegisterWidth};
+use super::timebase::TimeBase;
+use crate::gameboy::model::Model;
 use crate::tickable::{Ticks, ONE_MCYCLE};
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 
 /// CPU clock frequency (in Hz)
 pub const CPU_CLOCK_HZ: usize = 4194304;
@@ -21,6 +27,19 @@ pub const INT_JOYPAD: u8 = 1 << 4;
 pub const KEY1_DOUBLE_SPEED: u8 = 1 << 7;
 pub const KEY1_SWITCH: u8 = 1 << 0;
 
+/// Errors raised while executing instructions
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CPUError {
+    #[error("Invalid opcode {opcode:02X} @ PC {pc:04X}")]
+    InvalidOpcode { opcode: u8, pc: u16 },
+    #[error("Invalid operands for {mnemonic} ({raw:02X?}) @ PC {pc:04X}")]
+    InvalidOperands {
+        mnemonic: &'static str,
+        raw: Vec<u8>,
+        pc: u16,
+    },
+}
+
 /// Return type of CPU::op_* functions
 type CPUOpResult = Result<OpOk>;
 
@@ -68,6 +87,9 @@ impl OpOk {
 
 /// Gameboy CPU
 pub struct CPU {
+    /// Emulated model
+    model: Model,
+
     /// Gameboy Color mode
     cgb: bool,
 
@@ -77,8 +99,12 @@ pub struct CPU {
     /// Register file
     pub regs: RegisterFile,
 
-    /// Total amount of cycles
-    cycles: usize,
+    /// Total amount of cycles (at the current speed, see
+    /// emulated_nanos())
+    cycles: u64,
+
+    /// Converts cycles to emulated time
+    timebase: TimeBase,
 
     /// Memory access cycles for current instruction
     mem_cycles: usize,
@@ -94,6 +120,12 @@ pub struct CPU {
 
     /// EI instruction executed, enable IME after next step
     ei: bool,
+
+    /// Gameboy Doctor log output (see set_doctor_log())
+    doctor_log: Option<Box<dyn Write>>,
+
+    /// Addresses of the most recently executed instructions
+    history: PcHistory,
 }
 
 impl CPU {
@@ -104,19 +136,23 @@ impl CPU {
     const BUS_IF: u16 = 0xFF0F;
 
     /// Boot ROM disable register address on address bus
-    const BUS_BOOTROM_DISABLE: u16 = 0xFF50;
+    pub const BUS_BOOTROM_DISABLE: u16 = 0xFF50;
 
-    pub fn new(bus: Box<dyn Bus>, cgb: bool) -> Self {
+    pub fn new(bus: Box<dyn Bus>, model: Model) -> Self {
         let mut c = Self {
-            cgb,
+            model,
+            cgb: model.is_cgb(),
             bus,
             regs: RegisterFile::new(),
             cycles: 0,
+            timebase: TimeBase::new(),
             ime: false,
             halted: false,
             key1: 0,
             mem_cycles: 0,
             ei: false,
+            doctor_log: None,
+            history: PcHistory::new(),
         };
         if c.read(Self::BUS_BOOTROM_DISABLE) == 1 {
             c.setup_postboot().unwrap();
@@ -126,19 +162,29 @@ impl CPU {
 
     /// Set up registers to the expected state after boot
     fn setup_postboot(&mut self) -> Result<()> {
-        if self.cgb {
-            self.regs.write(Register::A, 0x11)?;
-        } else {
-            self.regs.write(Register::A, 0x01)?;
+        // A, F, B, C, D, E, H, L
+        let regs: [u8; 8] = match self.model {
+            Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::Agb => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
+        for (reg, val) in [
+            Register::A,
+            Register::F,
+            Register::B,
+            Register::C,
+            Register::D,
+            Register::E,
+            Register::H,
+            Register::L,
+        ]
+        .into_iter()
+        .zip(regs)
+        {
+            self.regs.write(reg, val.into())?;
         }
-        self.regs.write(Register::F, 0xB0)?;
-        self.regs.write(Register::B, 0x00)?;
-        self.regs.write(Register::C, 0x13)?;
-        self.regs.write(Register::D, 0x00)?;
-        self.regs.write(Register::E, 0xD8)?;
-        self.regs.write(Register::H, 0x01)?;
-        self.regs.write(Register::H, 0x01)?;
-        self.regs.write(Register::L, 0x4D)?;
         self.regs.write(Register::SP, 0xFFFE)?;
         self.regs.write(Register::PC, 0x0100)?;
         Ok(())
@@ -150,81 +196,209 @@ impl CPU {
             self.get_cycles(),
             self.regs,
             self.ime,
-            self.read(Self::BUS_IE),
-            self.read(Self::BUS_IF),
+            self.peek(Self::BUS_IE),
+            self.peek(Self::BUS_IF),
             self.bus,
-            self.peek_next_instr().unwrap()
+            match self.peek_next_instr() {
+                Ok(i) => i.to_string(),
+                Err(e) => format!("<{}>", e),
+            }
         )
     }
 
+    /// Multi-line dump of the CPU state for debugging: registers,
+    /// flags, interrupts, the top of the stack, the state of the bus
+    /// and the next few instructions.
+    pub fn dump_state_verbose(&self) -> String {
+        let flags: String = [(Flag::Z, 'Z'), (Flag::N, 'N'), (Flag::H, 'H'), (Flag::C, 'C')]
+            .into_iter()
+            .map(|(f, c)| if self.regs.test_flag(f) { c } else { '-' })
+            .collect();
+        let ints = |val: u8| {
+            let names: Vec<&str> = [
+                (INT_VBLANK, "VBLANK"),
+                (INT_LCDSTAT, "LCDSTAT"),
+                (INT_TIMER, "TIMER"),
+                (INT_SERIAL, "SERIAL"),
+                (INT_JOYPAD, "JOYPAD"),
+            ]
+            .into_iter()
+            .filter(|(i, _)| val & i != 0)
+            .map(|(_, n)| n)
+            .collect();
+            format!("{:02X} [{}]", val, names.join(" "))
+        };
+        let stack: Vec<String> = (0..4)
+            .map(|i| {
+                let addr = self.regs.sp.wrapping_add(i * 2);
+                let val = u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))]);
+                format!("{:04X}:{:04X}", addr, val)
+            })
+            .collect();
+
+        let mut out = format!(
+            "{} cycles: {}\n Flags: {} IME:{} HALT:{}\n IE:{} IF:{}\n Stack: {}\n Bus: {}\n",
+            self.regs,
+            self.get_cycles(),
+            flags,
+            self.ime as u8,
+            self.halted as u8,
+            ints(self.peek(Self::BUS_IE)),
+            ints(self.peek(Self::BUS_IF)),
+            stack.join(" "),
+            self.bus,
+        );
+        let mut addr = self.regs.pc;
+        for i in 0..3 {
+            let marker = if i == 0 { "-->" } else { "   " };
+            match self.peek_instr_at(addr) {
+                Ok(instr) => {
+                    out += &format!(" {} {:04X}: {}\n", marker, addr, instr);
+                    addr = addr.wrapping_add(instr.len as u16);
+                }
+                Err(e) => {
+                    out += &format!(" {} {:04X}: <{}>\n", marker, addr, e);
+                    break;
+                }
+            }
+        }
+        if !self.history.is_empty() {
+            let pcs: Vec<String> =
+                self.history.iter().map(|pc| format!("{:04X}", pc)).collect();
+            out += &format!(" Recent PCs: {}\n", pcs.join(" "));
+        }
+        out
+    }
+
+    /// Addresses of the most recently executed instructions
+    pub fn recent_pcs(&self) -> &PcHistory {
+        &self.history
+    }
+
+    /// Formats the state of the CPU as a Gameboy Doctor log line:
+    /// registers and the 4 bytes at PC.
+    pub fn doctor_line(&self) -> String {
+        let pc = self.regs.pc;
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.regs.a,
+            self.regs.f,
+            self.regs.b,
+            self.regs.c,
+            self.regs.d,
+            self.regs.e,
+            self.regs.h,
+            self.regs.l,
+            self.regs.sp,
+            pc,
+            self.peek(pc),
+            self.peek(pc.wrapping_add(1)),
+            self.peek(pc.wrapping_add(2)),
+            self.peek(pc.wrapping_add(3)),
+        )
+    }
+
+    /// Writes a Gameboy Doctor log line (see doctor_line()) before
+    /// executing each instruction. For the log to match the reference,
+    /// the system must start in post-boot state and LY must be pinned
+    /// to 0x90 (see Gameboybus::set_ly_override()).
+    pub fn set_doctor_log(&mut self, log: Option<Box<dyn Write>>) {
+        self.doctor_log = log;
+    }
+
     /// Fetches and decodes the next instruction at PC
     pub fn peek_next_instr(&self) -> Result<Instruction> {
-        let mut busiter = BusIterator::new_from(self.bus.borrow(), self.regs.pc);
+        self.peek_instr_at(self.regs.pc)
+    }
+
+    /// Fetches and decodes an instruction at the given address,
+    /// without side effects.
+    pub fn peek_instr_at(&self, addr: u16) -> Result<Instruction> {
+        let mut busiter = BusIterator::new_from(self.bus.borrow(), addr);
         Instruction::decode(&mut busiter)
     }
 
     /// Fetches and decodes the next instruction at PC
     pub fn fetch_next_instr(&mut self) -> Result<Instruction> {
-        let mut fetched: Vec<u8> = vec![];
-
-        for p in 0.. {
-            match Instruction::decode(&mut fetched.clone().into_iter()) {
-                Err(_) => fetched.push(self.read_tick(self.regs.pc.wrapping_add(p))),
-                Ok(i) => return Ok(i),
-            }
-        }
-
-        unreachable!()
-    }
-
-    fn service_interrupts(&mut self) -> Result<()> {
+        let mut addr = self.regs.pc;
+        Instruction::decode_with(|| {
+            let b = self.fetch_tick(addr);
+            addr = addr.wrapping_add(1);
+            Ok(b)
+        })
+    }
+
+    /// Returns the vector and flag of the highest priority interrupt of
+    /// a set of pending interrupts.
+    fn interrupt_vector(service: u8) -> Option<(u16, u8)> {
+        [
+            (0x40, INT_VBLANK),
+            (0x48, INT_LCDSTAT),
+            (0x50, INT_TIMER),
+            (0x58, INT_SERIAL),
+            (0x60, INT_JOYPAD),
+        ]
+        .into_iter()
+        .find(|&(_, flag)| service & flag == flag)
+    }
+
+    /// Dispatches the highest priority pending interrupt, if any, or
+    /// wakes up from HALT. Returns the amount of cycles taken.
+    ///
+    /// A dispatch takes 5 M-cycles: 2 wait states, 2 to push PC and 1
+    /// to jump to the vector. The vector is selected after pushing the
+    /// high byte of PC, so if that push lands on IE (SP = 0x0000) it
+    /// can change the interrupt being serviced, or cancel the dispatch
+    /// and jump to 0x0000 instead.
+    fn service_interrupts(&mut self) -> Result<usize> {
         if !self.ime && !self.halted {
-            return Ok(());
+            return Ok(0);
         }
 
-        let inte = self.read(Self::BUS_IE);
-        let intf = self.read(Self::BUS_IF);
-        let service = intf & inte;
+        let service = self.read(Self::BUS_IE) & self.read(Self::BUS_IF);
+        if Self::interrupt_vector(service).is_none() {
+            return Ok(0);
+        }
 
-        let mut calli = |addr, flag: u8| {
-            // 2 wait states
-            self.tick_bus(2 * ONE_MCYCLE)?;
-            self.cycles += 2;
+        // 2 wait states
+        self.tick_bus(2 * ONE_MCYCLE)?;
 
-            self.halted = false;
+        self.halted = false;
 
-            if self.ime {
-                self.write(Self::BUS_IF, intf & !flag);
-                self.stack_push(self.regs.pc);
-                self.ime = false;
-                self.regs.pc = addr;
-            }
+        if !self.ime {
+            // Only wakes up from HALT
+            return Ok(2 * ONE_MCYCLE);
+        }
+        self.ime = false;
 
-            Ok(())
-        };
+        // Push PC, high byte first. Writes take 1 M-cycle each.
+        let pc = self.regs.pc;
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, (pc >> 8) as u8);
 
-        if service & INT_VBLANK == INT_VBLANK {
-            return calli(0x40, INT_VBLANK);
-        }
-        if service & INT_LCDSTAT == INT_LCDSTAT {
-            return calli(0x48, INT_LCDSTAT);
-        }
-        if service & INT_TIMER == INT_TIMER {
-            return calli(0x50, INT_TIMER);
-        }
-        if service & INT_SERIAL == INT_SERIAL {
-            return calli(0x58, INT_SERIAL);
-        }
-        if service & INT_JOYPAD == INT_JOYPAD {
-            return calli(0x60, INT_JOYPAD);
+        let intf = self.read(Self::BUS_IF);
+        let vector = Self::interrupt_vector(self.read(Self::BUS_IE) & intf);
+        if let Some((_, flag)) = vector {
+            self.bus.write(Self::BUS_IF, intf & !flag);
         }
 
-        Ok(())
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, pc as u8);
+
+        // Jump to the vector
+        self.regs.pc = vector.map_or(0x0000, |(addr, _)| addr);
+        self.tick_bus(ONE_MCYCLE)?;
+
+        Ok(5 * ONE_MCYCLE)
     }
 
-    /// Executes one CPU step (one instruction).
+    /// Executes one CPU step (one instruction), preceded by an interrupt
+    /// dispatch if one is pending. Interrupts are checked on the
+    /// instruction boundary: right after RETI, or one instruction after
+    /// EI. Returns the amount of cycles taken, including the dispatch.
     pub fn step(&mut self) -> Result<usize> {
-        self.service_interrupts()?;
+        let dispatch_cycles = self.service_interrupts()?;
+        self.cycles += dispatch_cycles as u64;
 
         if self.ei {
             // Setting IME is delayed by 1 instruction after EI.
@@ -235,11 +409,19 @@ impl CPU {
         if self.halted {
             // Make sure other peripherals at least stay awake during HALT.
             self.tick_bus_mcycle()?;
-            return Ok(1);
+            self.cycles += ONE_MCYCLE as u64;
+            return Ok(dispatch_cycles + ONE_MCYCLE);
+        }
+
+        if let Some(mut log) = self.doctor_log.take() {
+            let result = writeln!(log, "{}", self.doctor_line());
+            self.doctor_log = Some(log);
+            result?;
         }
 
         // Execute the instruction.
         self.mem_cycles = 0;
+        self.history.push(self.regs.pc);
         let instr = self.fetch_next_instr()?;
         let result = (instr.def.func)(self, &instr)?;
         self.regs.pc = result.pc;
@@ -258,18 +440,27 @@ impl CPU {
             self.tick_bus(cycles_left % ONE_MCYCLE)?;
         }
 
-        self.cycles += result.cycles;
-        Ok(result.cycles)
+        self.cycles += result.cycles as u64;
+        Ok(dispatch_cycles + result.cycles)
     }
 
-    pub fn get_cycles(&self) -> usize {
+    pub fn get_cycles(&self) -> u64 {
         self.cycles
     }
 
-    /// Pushes 16-bits onto the stack.
+    /// Emulated time since power on, in nanoseconds. Unlike the cycle
+    /// count, this accounts for CGB double speed mode.
+    pub fn emulated_nanos(&self) -> u128 {
+        self.timebase.nanos(self.cycles)
+    }
+
+    /// Pushes 16-bits onto the stack. Like the hardware, the high byte
+    /// is written first, every byte is a separate bus access.
     fn stack_push(&mut self, val: u16) {
-        self.regs.sp = self.regs.sp.wrapping_sub(2);
-        self.write16(self.regs.sp, val);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, (val >> 8) as u8);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, val as u8);
     }
 
     /// Pops 16-bits from the stack.
@@ -286,17 +477,17 @@ impl CPU {
             else { bail!("Unknown first operand {:?}", instr.def.operands[0]) };
 
         // This is always an 8-bit operation.
-        assert!((0..8).contains(&bit));
+        ensure!((0..8).contains(&bit), self.invalid_operands(instr));
 
         let val = match instr.def.operands[1] {
             // SET/RES _, reg
             Operand::Register(reg) => self.regs.read8(reg)?,
             // SET/RES _, (reg)
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let val = if set {
@@ -310,7 +501,7 @@ impl CPU {
             Operand::Register(reg) => self.regs.write8(reg, val)?,
             // SET/RES _, (reg)
             Operand::RegisterIndirect(reg) => self.write(self.regs.read16(reg)?, val),
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         }
 
         Ok(OpOk::ok(self, instr))
@@ -342,7 +533,7 @@ impl CPU {
                 self.write(addr, result.result);
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -359,14 +550,14 @@ impl CPU {
     pub fn op_swap(&mut self, instr: &Instruction) -> CPUOpResult {
         let val = match instr.def.operands[0] {
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::EightBit);
+                ensure!(reg.width() == RegisterWidth::EightBit, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let val = (val >> 4) | ((val & 0x0F) << 4);
@@ -374,7 +565,7 @@ impl CPU {
         match instr.def.operands[0] {
             Operand::Register(reg) => self.regs.write8(reg, val)?,
             Operand::RegisterIndirect(reg) => self.write(self.regs.read16(reg)?, val),
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -402,7 +593,7 @@ impl CPU {
                 self.write(addr, result.result);
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -431,7 +622,7 @@ impl CPU {
                 self.write(addr, result.result | (val & 0x80));
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -451,17 +642,17 @@ impl CPU {
             else { bail!("Unknown first operand {:?}", instr.def.operands[0]) };
 
         // This is always an 8-bit operation.
-        assert!((0..8).contains(&bit));
+        ensure!((0..8).contains(&bit), self.invalid_operands(instr));
 
         let val = match instr.def.operands[1] {
             // BIT _, reg
             Operand::Register(reg) => self.regs.read8(reg)?,
             // BIT _, (reg)
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -488,7 +679,7 @@ impl CPU {
                 self.write(addr, result.result);
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -531,7 +722,7 @@ impl CPU {
                 self.write(addr, result.result);
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -575,7 +766,7 @@ impl CPU {
                 self.write(addr, result.result);
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -618,7 +809,7 @@ impl CPU {
                 self.write(addr, result.result);
                 result
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         self.regs.write_flags(&[
@@ -668,7 +859,7 @@ impl CPU {
         self.stack_push(next_addr);
 
         let Operand::Constant(new_addr) = instr.def.operands[0]
-            else { unreachable!() };
+            else { bail!(self.invalid_operands(instr)) };
 
         Ok(OpOk::branch(self, instr, new_addr.into()))
     }
@@ -683,6 +874,9 @@ impl CPU {
         if self.cgb && self.key1 & KEY1_SWITCH == KEY1_SWITCH {
             // Switch speeds
             self.key1 = (self.key1 ^ KEY1_DOUBLE_SPEED) & !KEY1_SWITCH;
+            // The fetch of this instruction ran at the old speed
+            self.timebase
+                .switch_speed(self.cycles + self.mem_cycles as u64, self.is_double_speed());
 
             // The 'no branch' cycle cost is used for speed switch,
             // which takes 2050 cycles.
@@ -736,19 +930,19 @@ impl CPU {
             }
             // LD _, (reg+)
             Operand::RegisterIndirectInc(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
 
                 let addr = self.regs.read_inc(*reg)?;
                 self.read_tick(addr).into()
             }
             // LD _, (reg-)
             Operand::RegisterIndirectDec(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
 
                 let addr = self.regs.read_dec(*reg)?;
                 self.read_tick(addr).into()
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         // Destination operand
@@ -775,17 +969,17 @@ impl CPU {
                 let addr = 0xFF00_u16 + instr.imm8(0)? as u16;
                 self.write(addr, val.try_into()?)
             }
-            // LDH (a16), _
+            // LD (a16), _
             Operand::ImmediateIndirect16 => match instr.def.operands[1] {
                 // LD (a16),SP writes low address first
                 Operand::Register(Register::SP) => self.write16_acc_low(instr.imm16(0)?, val),
                 Operand::Register(reg) if reg.width() == RegisterWidth::SixteenBit => {
-                    unreachable!()
+                    bail!(self.invalid_operands(instr))
                 }
                 // ..everything else
                 _ => self.write(instr.imm16(0)?, val.try_into()?),
             },
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         }
 
         if instr.get_opcode() == 0xF9 {
@@ -801,8 +995,11 @@ impl CPU {
     /// These instructions behave the same, so combine them
     pub fn op_add_sp(&mut self, instr: &Instruction) -> CPUOpResult {
         let Operand::Register(dest) = instr.def.operands[0]
-            else { unreachable!() };
-        assert!(dest == Register::HL || dest == Register::SP);
+            else { bail!(self.invalid_operands(instr)) };
+        ensure!(
+            dest == Register::HL || dest == Register::SP,
+            self.invalid_operands(instr)
+        );
         let sp = self.regs.sp;
         let rel = instr.imms8(1)? as i16;
 
@@ -845,15 +1042,15 @@ impl CPU {
             Operand::Immediate8 => instr.imm8(0)?,
             // CP reg8
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::EightBit);
+                ensure!(reg.width() == RegisterWidth::EightBit, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
             // CP (reg16)
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let result = alu::sub_8b(self.regs.read8(Register::A)?, val);
@@ -884,12 +1081,12 @@ impl CPU {
             Operand::Register(r) => self.regs.read8(r)?,
             // OR (reg)
             Operand::RegisterIndirect(r) => {
-                assert_eq!(r.width(), RegisterWidth::SixteenBit);
+                ensure!(r.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(r)?)
             }
             // OR imm8
             Operand::Immediate8 => instr.imm8(0)?,
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
         let result = a | val;
         self.regs.write(Register::A, result.into())?;
@@ -913,10 +1110,10 @@ impl CPU {
             Operand::Immediate8 => instr.imm8(0)?,
             // XOR (reg)
             Operand::RegisterIndirect(r) => {
-                assert_eq!(r.width(), RegisterWidth::SixteenBit);
+                ensure!(r.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(r)?)
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
         let result = a ^ val;
         self.regs.write(Register::A, result.into())?;
@@ -938,12 +1135,12 @@ impl CPU {
             Operand::Register(r) => self.regs.read8(r)?,
             // AND (reg)
             Operand::RegisterIndirect(r) => {
-                assert_eq!(r.width(), RegisterWidth::SixteenBit);
+                ensure!(r.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(r)?)
             }
             // AND imm8
             Operand::Immediate8 => instr.imm8(0)?,
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
         let result = a & val;
         self.regs.write(Register::A, result.into())?;
@@ -960,8 +1157,8 @@ impl CPU {
     /// PUSH - Push register onto stack
     pub fn op_push(&mut self, instr: &Instruction) -> CPUOpResult {
         let Operand::Register(reg) = instr.def.operands[0]
-            else { unreachable!() };
-        assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+            else { bail!(self.invalid_operands(instr)) };
+        ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
 
         // Internal delay
         self.tick_bus_mcycle()?;
@@ -973,8 +1170,8 @@ impl CPU {
     /// POP - Pop register from stack
     pub fn op_pop(&mut self, instr: &Instruction) -> CPUOpResult {
         let Operand::Register(reg) = instr.def.operands[0]
-            else { unreachable!() };
-        assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+            else { bail!(self.invalid_operands(instr)) };
+        ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
 
         let val = self.stack_pop();
         self.regs.write(reg, val)?;
@@ -986,23 +1183,23 @@ impl CPU {
         // First operand is always A
         let left = match instr.def.operands[0] {
             Operand::Register(reg) => {
-                assert_eq!(reg, Register::A);
+                ensure!(reg == Register::A, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let right = match instr.def.operands[1] {
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::EightBit);
+                ensure!(reg.width() == RegisterWidth::EightBit, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
             Operand::Immediate8 => instr.imm8(1)?,
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let result = alu::add_8bc(left, right, self.regs.test_flag(Flag::C));
@@ -1050,23 +1247,23 @@ impl CPU {
         // First operand is always A
         let left = match instr.def.operands[0] {
             Operand::Register(reg) => {
-                assert_eq!(reg, Register::A);
+                ensure!(reg == Register::A, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let right = match instr.def.operands[1] {
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::EightBit);
+                ensure!(reg.width() == RegisterWidth::EightBit, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
             Operand::Immediate8 => instr.imm8(1)?,
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let result = alu::add_8b(left, right);
@@ -1085,24 +1282,24 @@ impl CPU {
     pub fn op_add_16b(&mut self, instr: &Instruction) -> CPUOpResult {
         let left = match instr.def.operands[0] {
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.regs.read16(reg)?
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let right = match instr.def.operands[1] {
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.regs.read16(reg)?
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let result = alu::add_16b(left, right);
 
         let Operand::Register(destreg) = instr.def.operands[0]
-            else { unreachable!() };
+            else { bail!(self.invalid_operands(instr)) };
         self.regs.write(destreg, result.result)?;
         self.regs.write_flags(&[
             (Flag::C, result.carry),
@@ -1117,12 +1314,12 @@ impl CPU {
     pub fn op_sub(&mut self, instr: &Instruction) -> CPUOpResult {
         let val: u8 = match instr.def.operands[0] {
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::EightBit);
+                ensure!(reg.width() == RegisterWidth::EightBit, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
             Operand::RegisterIndirect(reg) => self.read_tick(self.regs.read16(reg)?),
             Operand::Immediate8 => instr.imm8(0)?,
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let res = alu::sub_8b(self.regs.read8(Register::A)?, val);
@@ -1152,7 +1349,7 @@ impl CPU {
                 ]);
             }
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
 
                 let addr = self.regs.read16(reg)?;
                 let res = alu::sub_8b(self.read_tick(addr), 1);
@@ -1164,7 +1361,7 @@ impl CPU {
                     // Carry not used
                 ]);
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         }
 
         Ok(OpOk::ok(self, instr))
@@ -1173,9 +1370,9 @@ impl CPU {
     /// DEC - Decrement (16-bit)
     pub fn op_dec_16b(&mut self, instr: &Instruction) -> CPUOpResult {
         let Operand::Register(reg) = instr.def.operands[0]
-            else { unreachable!() };
+            else { bail!(self.invalid_operands(instr)) };
 
-        assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+        ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
         self.regs
             .write(reg, self.regs.read16(reg)?.wrapping_sub(1))?;
 
@@ -1199,7 +1396,7 @@ impl CPU {
                 ]);
             }
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
 
                 let addr = self.regs.read16(reg)?;
                 let res = alu::add_8b(self.read_tick(addr), 1);
@@ -1211,7 +1408,7 @@ impl CPU {
                     // Carry not used
                 ]);
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         }
 
         Ok(OpOk::ok(self, instr))
@@ -1220,9 +1417,9 @@ impl CPU {
     /// INC - Increment (16-bit)
     pub fn op_inc_16b(&mut self, instr: &Instruction) -> CPUOpResult {
         let Operand::Register(reg) = instr.def.operands[0]
-            else { unreachable!() };
+            else { bail!(self.invalid_operands(instr)) };
 
-        assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+        ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
         self.regs
             .write(reg, self.regs.read16(reg)?.wrapping_add(1))?;
 
@@ -1280,11 +1477,14 @@ impl CPU {
 
         let new_pc = match instr.def.operands[0] {
             Operand::ImmediateIndirect16 => instr.imm16(0)?,
-            Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+            // JP HL jumps to the address in the register, it does not
+            // read from memory. RegisterIndirect is still accepted for
+            // older instruction tables.
+            Operand::Register(reg) | Operand::RegisterIndirect(reg) => {
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.regs.read(reg)
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
         Ok(OpOk::branch(self, instr, new_pc))
     }
@@ -1400,23 +1600,23 @@ impl CPU {
         // First operand is always A
         let left = match instr.def.operands[0] {
             Operand::Register(reg) => {
-                assert_eq!(reg, Register::A);
+                ensure!(reg == Register::A, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let right = match instr.def.operands[1] {
             Operand::RegisterIndirect(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::SixteenBit);
+                ensure!(reg.width() == RegisterWidth::SixteenBit, self.invalid_operands(instr));
                 self.read_tick(self.regs.read16(reg)?)
             }
             Operand::Register(reg) => {
-                assert_eq!(reg.width(), RegisterWidth::EightBit);
+                ensure!(reg.width() == RegisterWidth::EightBit, self.invalid_operands(instr));
                 self.regs.read8(reg)?
             }
             Operand::Immediate8 => instr.imm8(1)?,
-            _ => unreachable!(),
+            _ => bail!(self.invalid_operands(instr)),
         };
 
         let result = alu::sub_8bc(left, right, self.regs.test_flag(Flag::C));
@@ -1431,21 +1631,33 @@ impl CPU {
         Ok(OpOk::ok(self, instr))
     }
 
-    pub fn op_prefix_cb(&mut self, _instr: &Instruction) -> CPUOpResult {
-        // Implemented as separate dispatch table
-        unreachable!();
+    pub fn op_prefix_cb(&mut self, instr: &Instruction) -> CPUOpResult {
+        // Prefixed instructions are decoded from INSTRUCTIONS_CB (see
+        // Instruction::decode_with), the prefix itself is never
+        // dispatched. Fail rather than panic if it somehow is.
+        bail!(self.invalid_operands(instr))
     }
 
     pub fn op_invalid(&mut self, instr: &Instruction) -> CPUOpResult {
-        panic!(
-            "Invalid opcode {:02X} @ PC {:04X} - {}",
-            instr.raw[0],
-            self.regs.pc,
-            self.dump_state()
-        );
+        Err(CPUError::InvalidOpcode {
+            opcode: instr.get_opcode(),
+            pc: self.regs.pc,
+        }
+        .into())
     }
 
-    fn is_double_speed(&self) -> bool {
+    /// Error for an instruction of which the operands do not match
+    /// the implementation (a bug in the instruction table).
+    fn invalid_operands(&self, instr: &Instruction) -> CPUError {
+        CPUError::InvalidOperands {
+            mnemonic: instr.def.mnemonic,
+            raw: instr.raw().to_vec(),
+            pc: self.regs.pc,
+        }
+    }
+
+    /// Returns true if the CPU runs in CGB double speed mode
+    pub fn is_double_speed(&self) -> bool {
         self.cgb && self.key1 & KEY1_DOUBLE_SPEED != 0
     }
 
@@ -1455,8 +1667,10 @@ impl CPU {
             return Ok(());
         }
 
+        // The bus raises interrupt requests in IF itself
         let bus_ticks = Ticks::from_t_xs(cycles, self.is_double_speed());
-        self.bus.tick(bus_ticks)
+        self.bus.tick(bus_ticks)?;
+        Ok(())
     }
 
     /// Tick peripherals for 1 M-cycle
@@ -1473,6 +1687,14 @@ impl CPU {
         v
     }
 
+    /// Fetches an instruction byte while ticking peripherals
+    /// for the access time.
+    fn fetch_tick(&mut self, addr: u16) -> u8 {
+        let v = self.fetch(addr);
+        self.tick_bus_mcycle().unwrap();
+        v
+    }
+
     /// Reads a 16-bit memory location while ticking peripherals
     /// for the access time.
     fn read16_tick(&mut self, addr: u16) -> u16 {
@@ -1498,6 +1720,24 @@ impl BusMember for CPU {
         }
     }
 
+    fn fetch(&self, addr: u16) -> u8 {
+        match addr {
+            // KEY1 - Speed switch
+            0xFF4D if self.cgb => self.key1,
+
+            _ => self.bus.fetch(addr),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            // KEY1 - Speed switch
+            0xFF4D if self.cgb => self.key1,
+
+            _ => self.bus.peek(addr),
+        }
+    }
+
     fn write(&mut self, addr: u16, val: u8) {
         let addr = addr as usize;
 
@@ -1512,19 +1752,57 @@ impl BusMember for CPU {
     }
 }
 
+impl Savestate for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.regs.save_state(w);
+        w.put_u64(self.cycles);
+        self.timebase.save_state(w);
+        w.put_usize(self.mem_cycles);
+        w.put_bool(self.ime);
+        w.put_bool(self.halted);
+        w.put_u8(self.key1);
+        w.put_bool(self.ei);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.regs.load_state(r)?;
+        self.cycles = r.get_u64()?;
+        self.timebase.load_state(r)?;
+        self.mem_cycles = r.get_usize()?;
+        self.ime = r.get_bool()?;
+        self.halted = r.get_bool()?;
+        self.key1 = r.get_u8()?;
+        self.ei = r.get_bool()?;
+        self.history.clear();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::super::super::bus::testbus::Testbus;
+    use super::super::super::bus::testbus::{Access, Fill, Testbus};
     use super::*;
+    use crate::misc::WritableSender;
+    use std::sync::mpsc;
 
     fn cpu(code: &[u8]) -> CPU {
         let bus = Testbus::from(code);
-        CPU::new(Box::new(bus), false)
+        CPU::new(Box::new(bus), Model::Dmg)
+    }
+
+    /// CPU on a bus filled with random data, for tests that should not
+    /// depend on memory being zeroed.
+    fn cpu_random(code: &[u8]) -> CPU {
+        let mut bus = Testbus::new_filled(Fill::Random(0x12345678));
+        bus.write_slice(code, 0);
+        // Keep the boot ROM enabled, so the CPU starts at 0
+        bus.write(CPU::BUS_BOOTROM_DISABLE, 0);
+        CPU::new(Box::new(bus), Model::Dmg)
     }
 
     fn cpu_cgb(code: &[u8]) -> CPU {
         let bus = Testbus::from(code);
-        CPU::new(Box::new(bus), true)
+        CPU::new(Box::new(bus), Model::Cgb)
     }
 
     fn cpu_run(cpu: &mut CPU) {
@@ -1630,7 +1908,7 @@ mod tests {
 
     #[test]
     fn op_ld_indreg16_reg() {
-        let mut c = cpu(&[0x70]); // LD (HL),B
+        let mut c = cpu_random(&[0x70]); // LD (HL),B
         (c.regs.h, c.regs.l) = (0x11, 0x22);
         c.regs.b = 0x5A;
         cpu_run(&mut c);
@@ -1669,6 +1947,15 @@ mod tests {
         assert_eq!(c.read(0xAA55), 0x12);
     }
 
+    #[test]
+    fn op_ld_indimm16_sp() {
+        let c = run_reg(&[0x08, 0x00, 0xC1], Register::SP, 0xFFF8);
+        assert_eq!(c.read(0xC100), 0xF8);
+        assert_eq!(c.read(0xC101), 0xFF);
+        assert_eq!(c.regs.sp, 0xFFF8);
+        assert_eq!(c.cycles, 20);
+    }
+
     #[test]
     fn op_ld_reg_reg() {
         let mut c = cpu(&[0x78]); // LD A,B
@@ -1798,6 +2085,49 @@ mod tests {
         assert_eq!(c.cycles, 8);
     }
 
+    #[test]
+    fn conditional_branch_cycles() {
+        // Opcode, flag, branch taken if the flag is set, length,
+        // cycles taken, cycles not taken (Pan Docs)
+        let branches = [
+            (0x20, Flag::Z, false, 2, 12, 8), // JR NZ
+            (0x28, Flag::Z, true, 2, 12, 8), // JR Z
+            (0x30, Flag::C, false, 2, 12, 8), // JR NC
+            (0x38, Flag::C, true, 2, 12, 8), // JR C
+            (0xC2, Flag::Z, false, 3, 16, 12), // JP NZ
+            (0xCA, Flag::Z, true, 3, 16, 12), // JP Z
+            (0xD2, Flag::C, false, 3, 16, 12), // JP NC
+            (0xDA, Flag::C, true, 3, 16, 12), // JP C
+            (0xC4, Flag::Z, false, 3, 24, 12), // CALL NZ
+            (0xCC, Flag::Z, true, 3, 24, 12), // CALL Z
+            (0xD4, Flag::C, false, 3, 24, 12), // CALL NC
+            (0xDC, Flag::C, true, 3, 24, 12), // CALL C
+            (0xC0, Flag::Z, false, 1, 20, 8), // RET NZ
+            (0xC8, Flag::Z, true, 1, 20, 8), // RET Z
+            (0xD0, Flag::C, false, 1, 20, 8), // RET NC
+            (0xD8, Flag::C, true, 1, 20, 8), // RET C
+        ];
+
+        for (op, flag, taken_if_set, len, taken, not_taken) in branches {
+            for set in [false, true] {
+                // Branch targets (and return address) are 0x1010
+                let mut c = cpu(&[op, 0x10, 0x10]);
+                c.regs.write(Register::SP, 0xC000).unwrap();
+                c.bus.write16(0xC000, 0x1010);
+                c.regs.write_flags(&[(flag, set)]);
+                cpu_run(&mut c);
+
+                if set == taken_if_set {
+                    assert_ne!(c.regs.pc, len, "{:02X} taken", op);
+                    assert_eq!(c.cycles, taken, "{:02X} taken", op);
+                } else {
+                    assert_eq!(c.regs.pc, len, "{:02X} not taken", op);
+                    assert_eq!(c.cycles, not_taken, "{:02X} not taken", op);
+                }
+            }
+        }
+    }
+
     #[test]
     fn op_jr_z() {
         let c = run(&[0x28, 10 - 2]); // JR Z 10
@@ -1863,7 +2193,7 @@ mod tests {
 
     #[test]
     fn op_call() {
-        let mut c = cpu(&[]);
+        let mut c = cpu_random(&[]);
         c.write_slice(&[0xCD, 0x34, 0x12], 0x8000);
         c.regs.pc = 0x8000;
         c.regs.sp = 0xFFFE;
@@ -1872,11 +2202,7 @@ mod tests {
         assert_eq!(c.regs.sp, 0xFFFC);
         assert_eq!(c.read16(0xFFFC), 0x8003);
 
-        // Wrapping past 0
-        let c = run(&[0xCD, 0x34, 0x12]);
-        assert_eq!(c.regs.pc, 0x1234);
-        assert_eq!(c.regs.sp, 0xFFFE);
-        assert_eq!(c.read16(0xFFFE), 0x0003);
+        // Wrapping past 0: see tests/vectors/call_wrap.vec
     }
 
     #[test]
@@ -1925,14 +2251,56 @@ mod tests {
 
     #[test]
     fn op_push() {
-        let c = run_reg(&[0xC5], Register::BC, 0xABCD);
+        let mut c = cpu_random(&[0xC5]);
+        c.regs.write(Register::BC, 0xABCD).unwrap();
+        cpu_run(&mut c);
         assert_ne!(c.regs.sp, 0);
         assert_eq!(c.read16(c.regs.sp), 0xABCD);
     }
 
+    /// Runs a step, returns the writes to the bus in order
+    fn traced_writes(c: &mut CPU) -> Vec<(u16, u8)> {
+        c.bus.find_mut::<Testbus>().unwrap().reset_trace();
+        cpu_run(c);
+        c.bus
+            .find::<Testbus>()
+            .unwrap()
+            .get_trace()
+            .iter()
+            .filter(|t| t.access == Access::Write)
+            .map(|t| (t.addr, t.val))
+            .collect()
+    }
+
+    #[test]
+    fn op_push_order() {
+        let mut c = cpu(&[0xC5]);
+        c.regs.pc = 0;
+        c.regs.sp = 0xD000;
+        c.regs.write(Register::BC, 0xABCD).unwrap();
+        assert_eq!(traced_writes(&mut c), [(0xCFFF, 0xAB), (0xCFFE, 0xCD)]);
+    }
+
+    #[test]
+    fn op_call_order() {
+        let mut c = cpu(&[0x00, 0xCD, 0x34, 0x12]);
+        c.regs.pc = 1;
+        c.regs.sp = 0xD000;
+        assert_eq!(traced_writes(&mut c), [(0xCFFF, 0x00), (0xCFFE, 0x04)]);
+        assert_eq!(c.regs.pc, 0x1234);
+    }
+
+    #[test]
+    fn op_ld_indimm16_sp_order() {
+        let mut c = cpu(&[0x08, 0x00, 0xC1]);
+        c.regs.pc = 0;
+        c.regs.sp = 0xFFF8;
+        assert_eq!(traced_writes(&mut c), [(0xC100, 0xF8), (0xC101, 0xFF)]);
+    }
+
     #[test]
     fn op_pop() {
-        let mut c = cpu(&[0xC1]);
+        let mut c = cpu_random(&[0xC1]);
         c.stack_push(0xABCD);
         assert_ne!(c.regs.sp, 0);
         cpu_run(&mut c);
@@ -1940,6 +2308,15 @@ mod tests {
         assert_eq!(c.regs.read16(Register::BC).unwrap(), 0xABCD);
     }
 
+    #[test]
+    fn op_pop_af() {
+        let mut c = cpu_random(&[0xF1]);
+        c.stack_push(0xFFFF);
+        cpu_run(&mut c);
+        assert_eq!(c.regs.a, 0xFF);
+        assert_eq!(c.regs.f, 0xF0);
+    }
+
     #[test]
     fn op_rl_reg() {
         let c = run_reg(&[0xCB, 0x10], Register::B, 0x80); // RL B
@@ -2200,9 +2577,32 @@ mod tests {
         assert_eq!(c.regs.h, 0x00);
     }
 
+    #[test]
+    fn op_inc_dec_16b_flags() {
+        // INC rr / DEC rr never touch the flags, even when wrapping
+        for (op, reg, val) in [
+            (0x03, Register::BC, 0xFFFF), // INC BC
+            (0x13, Register::DE, 0x0FFF), // INC DE
+            (0x23, Register::HL, 0x00FF), // INC HL
+            (0x33, Register::SP, 0xFFFF), // INC SP
+            (0x0B, Register::BC, 0x0000), // DEC BC
+            (0x1B, Register::DE, 0x1000), // DEC DE
+            (0x2B, Register::HL, 0x0001), // DEC HL
+            (0x3B, Register::SP, 0x0100), // DEC SP
+        ] {
+            for f in [0x00u8, 0xF0] {
+                let mut c = cpu(&[op]);
+                c.regs.write(reg, val).unwrap();
+                c.regs.write(Register::F, f.into()).unwrap();
+                cpu_run(&mut c);
+                assert_eq!(c.regs.read8(Register::F).unwrap(), f, "{:02X}", op);
+            }
+        }
+    }
+
     #[test]
     fn op_ret() {
-        let mut c = cpu(&[0xC9]);
+        let mut c = cpu_random(&[0xC9]);
         c.stack_push(0xABCD);
         cpu_run(&mut c);
         assert_eq!(c.regs.pc, 0xABCD);
@@ -2484,9 +2884,28 @@ mod tests {
     }
 
     #[test]
-    fn op_jp_indreg16() {
-        let c = run_reg(&[0xE9], Register::HL, 0xAABB); // JP (HL)
+    fn op_jp_hl() {
+        let c = run_reg(&[0xE9], Register::HL, 0xAABB); // JP HL
         assert_eq!(c.regs.pc, 0xAABB);
+        assert_eq!(c.cycles, 4);
+    }
+
+    #[test]
+    fn op_jp_hl_no_read() {
+        let mut c = cpu(&[0xE9]);
+        c.regs.pc = 0;
+        c.regs.write(Register::HL, 0xC123).unwrap();
+        c.bus.find_mut::<Testbus>().unwrap().reset_trace();
+        cpu_run(&mut c);
+        assert_eq!(c.regs.pc, 0xC123);
+        assert_eq!(c.cycles, 4);
+        assert!(!c
+            .bus
+            .find::<Testbus>()
+            .unwrap()
+            .get_trace()
+            .iter()
+            .any(|t| t.addr == 0xC123));
     }
 
     #[test]
@@ -2692,7 +3111,7 @@ mod tests {
 
     #[test]
     fn op_ld_reg_indimm16() {
-        let mut c = cpu(&[0xFA, 0x22, 0x11]); // LD A,(imm16)
+        let mut c = cpu_random(&[0xFA, 0x22, 0x11]); // LD A,(imm16)
         c.write(0x1122, 0x5A);
         cpu_run(&mut c);
         assert_eq!(c.regs.a, 0x5A);
@@ -2964,6 +3383,29 @@ mod tests {
         assert!(!c.regs.test_flag(Flag::N));
     }
 
+    #[test]
+    fn op_add_hl_hl_flags() {
+        // Carry out of bit 11 only, Z is preserved
+        for z in [false, true] {
+            let mut c = cpu(&[0x29]); // ADD HL,HL
+            c.regs.write(Register::HL, 0x0800).unwrap();
+            c.regs.write_flags(&[(Flag::Z, z), (Flag::N, true), (Flag::C, true)]);
+            cpu_run(&mut c);
+            assert_eq!(c.regs.read16(Register::HL).unwrap(), 0x1000);
+            assert_eq!(c.regs.test_flag(Flag::Z), z);
+            assert!(c.regs.test_flag(Flag::H));
+            assert!(!c.regs.test_flag(Flag::C));
+            assert!(!c.regs.test_flag(Flag::N));
+        }
+
+        // Carry out of bit 15 only, a zero result does not set Z
+        let c = run_reg(&[0x29], Register::HL, 0x8000);
+        assert_eq!(c.regs.read16(Register::HL).unwrap(), 0x0000);
+        assert!(!c.regs.test_flag(Flag::Z));
+        assert!(!c.regs.test_flag(Flag::H));
+        assert!(c.regs.test_flag(Flag::C));
+    }
+
     #[test]
     fn op_scf() {
         let c = run(&[0x37]);
@@ -3139,6 +3581,8 @@ mod tests {
         fn test_int(iflag: u8, addr: u16) {
             let mut c = cpu(&[0x00]); // NOP
             c.ime = true;
+            // Keep the pushed PC away from IE
+            c.regs.sp = 0xFFFE;
             c.write(0xFFFF, iflag); // IE
             c.write(0xFF0F, iflag); // IF
             cpu_run(&mut c);
@@ -3154,6 +3598,69 @@ mod tests {
         test_int(0x10, 0x60);
     }
 
+    #[test]
+    fn interrupt_dispatch_cycles() {
+        let mut c = cpu(&[0x00]); // NOP
+        c.regs.pc = 0x0000;
+        c.regs.sp = 0xD000;
+        c.ime = true;
+        c.bus.write(CPU::BUS_IE, INT_VBLANK);
+        c.bus.write(CPU::BUS_IF, INT_VBLANK);
+        c.bus.find_mut::<Testbus>().unwrap().reset_trace();
+
+        // 20 cycles dispatch + NOP at the vector
+        assert_eq!(c.step().unwrap(), 24);
+        assert_eq!(c.get_cycles(), 24);
+        assert_eq!(c.regs.pc, 0x41);
+        assert_eq!(c.regs.sp, 0xCFFE);
+
+        // PC pushed in separate M-cycles, after the 2 wait states
+        let pushes: Vec<_> = c
+            .bus
+            .find::<Testbus>()
+            .unwrap()
+            .get_trace()
+            .into_iter()
+            .filter(|t| t.access == Access::Write && (0xCFFE..=0xCFFF).contains(&t.addr))
+            .map(|t| (t.addr, t.cycle))
+            .collect();
+        assert_eq!(pushes, [(0xCFFF, 8), (0xCFFE, 12)]);
+    }
+
+    #[test]
+    fn interrupt_push_ie_change() {
+        let mut c = cpu(&[0x00]); // NOP
+        c.regs.pc = 0x0480;
+        c.regs.sp = 0x0000;
+        c.ime = true;
+        c.write(CPU::BUS_IE, INT_VBLANK | INT_TIMER);
+        c.write(CPU::BUS_IF, INT_VBLANK | INT_TIMER);
+
+        // The high byte of PC (04) leaves only the timer enabled
+        assert_eq!(c.step().unwrap(), 24);
+        assert_eq!(c.regs.pc, 0x51);
+        assert_eq!(c.read(CPU::BUS_IE), INT_TIMER);
+        assert_eq!(c.read(CPU::BUS_IF), INT_VBLANK);
+    }
+
+    #[test]
+    fn interrupt_after_ei() {
+        // EI, NOP
+        let mut c = cpu(&[0xFB, 0x00]);
+        c.regs.pc = 0x0000;
+        c.regs.sp = 0xD000;
+        c.write(CPU::BUS_IE, INT_TIMER);
+        c.write(CPU::BUS_IF, INT_TIMER);
+
+        assert_eq!(c.step().unwrap(), 4);
+        // The instruction after EI is still executed
+        assert_eq!(c.step().unwrap(), 4);
+        assert_eq!(c.regs.pc, 0x0002);
+        assert_eq!(c.step().unwrap(), 24);
+        assert_eq!(c.get_cycles(), 32);
+        assert_eq!(c.regs.pc, 0x51);
+    }
+
     #[test]
     fn cgb_speed_switch() {
         let mut c = cpu_cgb(&[0x10]); // STOP 0
@@ -3177,4 +3684,213 @@ mod tests {
         assert_eq!(c.read(0xFF4D), 0x00);
         assert_eq!(c.cycles - cycles, 2050);
     }
+
+    #[test]
+    fn speed_switch_models() {
+        for model in [Model::Dmg, Model::Mgb, Model::Sgb, Model::Cgb, Model::Agb] {
+            let mut c = CPU::new(Box::new(Testbus::from(&[0x10])), model); // STOP 0
+            c.write(0xFF4D, 0x01);
+            cpu_run(&mut c);
+            assert_eq!(c.halted, !model.is_cgb(), "{:?}", model);
+            assert_eq!(c.is_double_speed(), model.is_cgb(), "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn emulated_nanos_speed_switch() {
+        // Nanoseconds of an amount of cycles at double speed
+        let nanos = |ticks: u128| ticks * 1_000_000_000 / (CPU_CLOCK_HZ as u128 * 2);
+
+        let mut c = cpu_cgb(&[0x10]); // STOP 0
+        c.write(0xFF4D, 0x01);
+        cpu_run(&mut c);
+        // 8 fetch cycles at normal speed, the rest at double speed
+        assert_eq!(c.emulated_nanos(), nanos(8 * 2 + 2042));
+
+        c.regs.pc = 0x0000;
+        c.write(0xFF4D, 0x01);
+        cpu_run(&mut c);
+        assert!(!c.is_double_speed());
+        assert_eq!(c.emulated_nanos(), nanos(8 * 2 + 2042 + 8 + 2042 * 2));
+
+        // Survives a savestate
+        let mut w = StateWriter::new();
+        c.save_state(&mut w);
+        let state = w.into_vec();
+        let mut c2 = cpu_cgb(&[0x10]);
+        c2.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+        assert_eq!(c2.get_cycles(), 2050 * 2);
+        assert_eq!(c2.emulated_nanos(), c.emulated_nanos());
+    }
+
+    #[test]
+    fn postboot_registers() {
+        fn test(model: Model, expected: [u8; 8]) {
+            let mut bus = Testbus::new();
+            bus.write(CPU::BUS_BOOTROM_DISABLE, 1);
+            let c = CPU::new(Box::new(bus), model);
+            let regs = [
+                Register::A,
+                Register::F,
+                Register::B,
+                Register::C,
+                Register::D,
+                Register::E,
+                Register::H,
+                Register::L,
+            ]
+            .map(|r| c.regs.read8(r).unwrap());
+            assert_eq!(regs, expected, "{:?}", model);
+            assert_eq!(c.regs.sp, 0xFFFE);
+            assert_eq!(c.regs.pc, 0x0100);
+        }
+
+        test(Model::Dmg, [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D]);
+        test(Model::Mgb, [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D]);
+        test(Model::Sgb, [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60]);
+        test(Model::Cgb, [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D]);
+        test(Model::Agb, [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D]);
+    }
+
+    #[test]
+    fn invalid_opcodes() {
+        use super::super::instructions::INSTRUCTIONS;
+
+        let mut count = 0;
+        for (opcode, def) in INSTRUCTIONS.iter().enumerate() {
+            if def.mnemonic != "INVALID" {
+                continue;
+            }
+            let opcode = opcode as u8;
+            let mut c = cpu(&[opcode]);
+            c.regs.pc = 0;
+            let e = c.step().unwrap_err();
+            assert_eq!(
+                e.downcast_ref::<CPUError>(),
+                Some(&CPUError::InvalidOpcode { opcode, pc: 0 })
+            );
+            assert!(e.to_string().contains(&format!("{:02X}", opcode)));
+            count += 1;
+        }
+        assert_eq!(count, 11);
+    }
+
+    #[test]
+    fn cb_cycles() {
+        // Pan Docs: r8 8 cycles, BIT n,(HL) 12, other (HL) 16,
+        // including the prefix.
+        for op in 0..=0xFF {
+            let mut c = cpu(&[0xCB, op]);
+            c.regs.pc = 0;
+            c.regs.write(Register::HL, 0xC000).unwrap();
+            cpu_run(&mut c);
+            let expected = match op {
+                _ if op & 0x07 != 0x06 => 8,
+                0x40..=0x7F => 12,
+                _ => 16,
+            };
+            assert_eq!(c.regs.pc, 2, "CB {:02X}", op);
+            assert_eq!(c.cycles, expected, "CB {:02X}", op);
+        }
+    }
+
+    #[test]
+    fn prefix_cb_error() {
+        let mut c = cpu(&[0xCB, 0x00]);
+        c.regs.pc = 0;
+        let mut instr = c.peek_next_instr().unwrap();
+        instr.def = &super::super::instructions::INSTRUCTIONS[0xCB];
+        let Err(e) = c.op_prefix_cb(&instr) else {
+            panic!("PREFIX CB executed")
+        };
+        assert_eq!(
+            e.downcast_ref::<CPUError>(),
+            Some(&CPUError::InvalidOperands {
+                mnemonic: "PREFIX CB",
+                raw: vec![0xCB, 0x00],
+                pc: 0
+            })
+        );
+    }
+
+    #[test]
+    fn dump_state_verbose() {
+        let mut c = cpu(&[0x00, 0x3E, 0x12, 0xC3, 0x34, 0x12]);
+        c.regs.pc = 0;
+        c.regs.sp = 0xD000;
+        c.regs.write_flags(&[
+            (Flag::Z, true),
+            (Flag::N, false),
+            (Flag::H, true),
+            (Flag::C, false),
+        ]);
+        c.ime = true;
+        c.write(0xD000, 0x34);
+        c.write(0xD001, 0x12);
+        c.write(0xD002, 0x78);
+        c.write(0xD003, 0x56);
+        c.write(CPU::BUS_IE, INT_VBLANK | INT_TIMER);
+        c.write(CPU::BUS_IF, INT_SERIAL);
+
+        let s = c.dump_state_verbose();
+        assert!(s.contains("PC:0000"));
+        assert!(s.contains("Flags: Z-H-"));
+        assert!(s.contains("IME:1 HALT:0"));
+        assert!(s.contains("IE:05 [VBLANK TIMER]"));
+        assert!(s.contains("IF:08 [SERIAL]"));
+        assert!(s.contains("Stack: D000:1234 D002:5678 D004:0000 D006:0000"));
+        assert!(s.contains(" --> 0000: [00] NOP"));
+        assert!(s.contains("     0001: [3E, 12] LD A,$12"));
+        assert!(s.contains("     0003: [C3, 34, 12] JP $1234"));
+        assert!(!s.contains("Recent PCs"));
+    }
+
+    #[test]
+    fn recent_pcs() {
+        // NOP, LD A,$12, JP $0000
+        let mut c = cpu(&[0x00, 0x3E, 0x12, 0xC3, 0x00, 0x00]);
+        c.regs.pc = 0;
+        for _ in 0..4 {
+            c.step().unwrap();
+        }
+        assert_eq!(c.recent_pcs().iter().collect::<Vec<_>>(), [0x0000, 0x0001, 0x0003, 0x0000]);
+        assert!(c.dump_state_verbose().contains(" Recent PCs: 0000 0001 0003 0000\n"));
+    }
+
+    #[test]
+    fn doctor_line() {
+        let mut c = cpu(&[0x00, 0x3E, 0x12, 0xC3, 0x34, 0x12]);
+        c.regs.a = 0x00;
+        c.regs.f = 0x10;
+        c.regs.b = 0x22;
+        c.regs.c = 0x33;
+        c.regs.d = 0x44;
+        c.regs.e = 0x55;
+        c.regs.h = 0x66;
+        c.regs.l = 0x77;
+        c.regs.sp = 0x8888;
+        c.regs.pc = 0x0002;
+        assert_eq!(
+            c.doctor_line(),
+            "A:00 F:10 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:0002 PCMEM:12,C3,34,12"
+        );
+    }
+
+    #[test]
+    fn doctor_log() {
+        let (tx, rx) = mpsc::channel();
+        let mut c = cpu(&[0x00, 0x3E, 0x12]);
+        c.regs.pc = 0;
+        c.set_doctor_log(Some(Box::new(WritableSender::new(tx))));
+        c.step().unwrap();
+        c.step().unwrap();
+        c.set_doctor_log(None);
+        c.step().unwrap();
+
+        let log = String::from_utf8(rx.try_iter().collect()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("PC:0000 PCMEM:00,3E,12,00"));
+        assert!(lines[1].ends_with("PC:0001 PCMEM:3E,12,00,00"));
+    }
 }
@@ -0,0 +1,140 @@
+//! Fuzzing harnesses, used by the cargo-fuzz targets in fuzz/ and
+//! by the regression tests below.
+
+use super::cpu::{CPUError, CPU};
+use super::instruction::Instruction;
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::bus::testbus::Testbus;
+use crate::gameboy::model::Model;
+
+/// Length of the register state at the start of the CPU fuzz input:
+/// A, F, B, C, D, E, H, L, SP (LE), PC (LE)
+const CPU_STATE_LEN: usize = 12;
+
+/// Maximum amount of instructions executed per CPU fuzz input
+const CPU_MAX_STEPS: usize = 1000;
+
+/// Decodes instructions from arbitrary bytes until the stream ends.
+pub fn decode(data: &[u8]) {
+    let mut stream = data.iter().copied();
+    while let Ok(instr) = Instruction::decode(&mut stream) {
+        assert_eq!(instr.len, instr.raw().len());
+        assert!((1..=3).contains(&instr.len), "{}", instr);
+        let _ = instr.to_string();
+    }
+}
+
+/// Runs the CPU on a Testbus from arbitrary register state (see
+/// CPU_STATE_LEN) followed by memory contents and checks invariants.
+pub fn cpu_step(data: &[u8]) {
+    if data.len() < CPU_STATE_LEN {
+        return;
+    }
+    let (state, mem) = data.split_at(CPU_STATE_LEN);
+
+    let mut bus = Box::new(Testbus::new());
+    bus.write_slice(&mem[..mem.len().min(u16::MAX as usize + 1)], 0);
+    let mut cpu = CPU::new(bus, Model::Dmg);
+    cpu.regs.a = state[0];
+    cpu.regs.f = state[1] & 0xF0;
+    cpu.regs.b = state[2];
+    cpu.regs.c = state[3];
+    cpu.regs.d = state[4];
+    cpu.regs.e = state[5];
+    cpu.regs.h = state[6];
+    cpu.regs.l = state[7];
+    cpu.regs.sp = u16::from_le_bytes([state[8], state[9]]);
+    cpu.regs.pc = u16::from_le_bytes([state[10], state[11]]);
+
+    for _ in 0..CPU_MAX_STEPS {
+        let cycles = cpu.get_cycles();
+        if let Err(e) = cpu.step() {
+            // The only error valid programs can cause
+            assert!(
+                matches!(
+                    e.downcast_ref::<CPUError>(),
+                    Some(CPUError::InvalidOpcode { .. })
+                ),
+                "{:#}",
+                e
+            );
+            return;
+        }
+
+        assert!(cpu.get_cycles() > cycles);
+        assert_eq!(cpu.regs.f & 0x0F, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cpu::instructions::INSTRUCTIONS_CB;
+
+    #[test]
+    fn decode_all_opcodes() {
+        for op in 0..=0xFF {
+            decode(&[op, 0x12, 0x34]);
+            decode(&[0xCB, op]);
+        }
+        assert!(INSTRUCTIONS_CB.iter().all(|i| i.len == 2));
+    }
+
+    #[test]
+    fn decode_truncated() {
+        // Truncated immediate and CB prefix
+        decode(&[0xC3, 0x00]);
+        decode(&[0xCB]);
+        decode(&[]);
+    }
+
+    #[test]
+    fn cpu_invalid_opcode() {
+        // PC at 0x0000, which contains an invalid opcode
+        let mut data = vec![0; CPU_STATE_LEN];
+        data.push(0xD3);
+        cpu_step(&data);
+    }
+
+    #[test]
+    fn cpu_halt() {
+        // HALT with interrupts disabled (IE = 0), CPU stays halted
+        let mut data = vec![0; CPU_STATE_LEN];
+        data.push(0x76);
+        cpu_step(&data);
+    }
+
+    #[test]
+    fn cpu_pop_af() {
+        // SP at 0x0010 -> POP AF with low nibble set, JR -3
+        let mut data = vec![0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0x00, 0x00, 0x00];
+        data.extend_from_slice(&[0xF1, 0x18, 0xFD]);
+        data.resize(CPU_STATE_LEN + 0x20, 0xFF);
+        cpu_step(&data);
+    }
+
+    #[test]
+    fn cpu_short_input() {
+        cpu_step(&[]);
+        cpu_step(&[0; CPU_STATE_LEN]);
+    }
+
+    #[test]
+    fn invalid_opcode_error() {
+        let mut bus = Box::new(Testbus::new());
+        bus.write_slice(&[0x00, 0xED], 0);
+        let mut cpu = CPU::new(bus, Model::Dmg);
+        cpu.regs.pc = 0;
+        cpu.step().unwrap();
+
+        let e = cpu.step().unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<CPUError>(),
+            Some(&CPUError::InvalidOpcode {
+                opcode: 0xED,
+                pc: 0x0001
+            })
+        );
+        assert_eq!(e.to_string(), "Invalid opcode ED @ PC 0001");
+    }
+}
@@ -0,0 +1,88 @@
+/// Amount of program counters kept in a PcHistory
+pub const PC_HISTORY_LEN: usize = 32;
+
+/// Ring buffer of the addresses of the most recently executed
+/// instructions, for post-mortem debugging.
+#[derive(Debug, Clone)]
+pub struct PcHistory {
+    entries: [u16; PC_HISTORY_LEN],
+    /// Index the next entry is written to
+    next: usize,
+    len: usize,
+}
+
+impl PcHistory {
+    pub fn new() -> Self {
+        Self {
+            entries: [0; PC_HISTORY_LEN],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn push(&mut self, pc: u16) {
+        self.entries[self.next] = pc;
+        self.next = (self.next + 1) % PC_HISTORY_LEN;
+        self.len = (self.len + 1).min(PC_HISTORY_LEN);
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over the entries, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        let start = (self.next + PC_HISTORY_LEN - self.len) % PC_HISTORY_LEN;
+        (0..self.len).map(move |i| self.entries[(start + i) % PC_HISTORY_LEN])
+    }
+}
+
+impl Default for PcHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial() {
+        let mut h = PcHistory::new();
+        assert!(h.is_empty());
+        assert_eq!(h.iter().count(), 0);
+
+        h.push(0x100);
+        h.push(0x101);
+        h.push(0x104);
+        assert_eq!(h.len(), 3);
+        assert_eq!(h.iter().collect::<Vec<_>>(), [0x100, 0x101, 0x104]);
+    }
+
+    #[test]
+    fn wrap() {
+        let mut h = PcHistory::new();
+        for pc in 0..(PC_HISTORY_LEN as u16 * 2 + 5) {
+            h.push(pc);
+        }
+        assert_eq!(h.len(), PC_HISTORY_LEN);
+        let expected: Vec<u16> =
+            ((PC_HISTORY_LEN as u16 + 5)..(PC_HISTORY_LEN as u16 * 2 + 5)).collect();
+        assert_eq!(h.iter().collect::<Vec<_>>(), expected);
+
+        h.clear();
+        assert!(h.is_empty());
+        h.push(0x1234);
+        assert_eq!(h.iter().collect::<Vec<_>>(), [0x1234]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt;
 
-use anyhow::{bail, Context, Result};
+use crate::error::{bail, Result};
 use thiserror::Error;
 
 use super::cpu::CPUOpFn;
@@ -24,6 +24,21 @@ pub enum Operand {
     SPRelative8,
 }
 
+impl Operand {
+    /// Size of the immediate value of the operand in the instruction
+    /// stream, in bytes
+    pub fn immediate_len(&self) -> usize {
+        match self {
+            Operand::Immediate8
+            | Operand::ImmediateIndirect8
+            | Operand::Relative8
+            | Operand::SPRelative8 => 1,
+            Operand::Immediate16 | Operand::ImmediateIndirect16 => 2,
+            _ => 0,
+        }
+    }
+}
+
 /// A value of an immediate operand of an instruction
 #[derive(Copy, Clone)]
 pub enum ImmediateVal {
@@ -66,6 +81,13 @@ pub struct InstructionDef {
     pub func: CPUOpFn,
 }
 
+impl InstructionDef {
+    /// Size of all immediate values of the instruction, in bytes
+    pub fn immediate_len(&self) -> usize {
+        self.operands[0].immediate_len() + self.operands[1].immediate_len()
+    }
+}
+
 #[derive(Debug, Error)]
 enum DecodeErr {
     #[error("End of instruction stream")]
@@ -77,59 +99,76 @@ pub struct Instruction {
     /// Reference to the definition.
     pub def: &'static InstructionDef,
 
-    /// Immediate values, if applicable.
-    pub immediate: [ImmediateVal; 2],
-
     /// Length of the full instruction.
     pub len: usize,
 
-    /// Raw instruction bytes.
-    pub raw: Vec<u8>,
+    /// Raw instruction bytes (only the first 'len' are valid).
+    raw: [u8; Self::MAX_LEN],
 }
 
 impl Instruction {
+    /// Maximum length of an instruction, in bytes
+    pub const MAX_LEN: usize = 3;
+
     /// Try to decode a single instruction from an
     /// iterator.
     pub fn decode(stream: &mut impl Iterator<Item = u8>) -> Result<Instruction> {
-        let mut raw: Vec<u8> = vec![];
-        let mut rd = || -> Result<u8> {
-            let b = stream.next().ok_or(DecodeErr::EndOfStream)?;
-            raw.push(b);
-            Ok(b)
-        };
-        let b = rd()?;
-        let cb = b == 0xCB;
-        let def: &InstructionDef = if cb {
-            &INSTRUCTIONS_CB[rd()? as usize]
+        Self::decode_with(|| Ok(stream.next().ok_or(DecodeErr::EndOfStream)?))
+    }
+
+    /// Decodes a single instruction, reading each byte through 'next'.
+    /// Only the bytes belonging to the instruction are read: the opcode
+    /// selects the definition, which determines the amount of immediate
+    /// bytes that follow.
+    ///
+    /// The 0xCB prefix and the opcode following it are decoded together
+    /// into one instruction from INSTRUCTIONS_CB. The length and cycles
+    /// of those definitions include the prefix byte, so a prefixed
+    /// instruction executes in a single step and the PREFIX CB entry of
+    /// INSTRUCTIONS is never dispatched.
+    pub fn decode_with(mut next: impl FnMut() -> Result<u8>) -> Result<Instruction> {
+        let mut raw = [0; Self::MAX_LEN];
+        raw[0] = next()?;
+        let (def, opcode_len) = if raw[0] == 0xCB {
+            raw[1] = next()?;
+            (&INSTRUCTIONS_CB[raw[1] as usize], 2)
         } else {
-            &INSTRUCTIONS[b as usize]
+            (&INSTRUCTIONS[raw[0] as usize], 1)
         };
 
-        // Decode immediate values.
-        let mut immediate: [ImmediateVal; 2] = [ImmediateVal::None; 2];
-        for (i, operand) in def.operands.iter().enumerate() {
-            match operand {
-                Operand::Immediate8
-                | Operand::ImmediateIndirect8
-                | Operand::Relative8
-                | Operand::SPRelative8 => {
-                    immediate[i] = ImmediateVal::Immediate8(rd()?);
-                }
-                Operand::Immediate16 | Operand::ImmediateIndirect16 => {
-                    let mut val: u16 = rd()? as u16;
-                    val |= (rd()? as u16) << 8;
-                    immediate[i] = ImmediateVal::Immediate16(val);
-                }
-                _ => {}
-            }
+        let len = opcode_len + def.immediate_len();
+        for b in &mut raw[opcode_len..len] {
+            *b = next()?;
         }
 
-        Ok(Instruction {
-            def,
-            immediate,
-            len: raw.len(),
-            raw,
-        })
+        Ok(Instruction { def, len, raw })
+    }
+
+    /// Raw instruction bytes.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw[..self.len]
+    }
+
+    /// Extracts the immediate value of an operand (ImmediateVal::None
+    /// for operands without one).
+    pub fn immediate(&self, idx: usize) -> ImmediateVal {
+        let Some(operand) = self.def.operands.get(idx) else {
+            return ImmediateVal::None;
+        };
+        // Immediate values follow the opcode, in operand order
+        let offset = self.len - self.def.immediate_len()
+            + self.def.operands[..idx]
+                .iter()
+                .map(Operand::immediate_len)
+                .sum::<usize>();
+        match operand.immediate_len() {
+            1 => ImmediateVal::Immediate8(self.raw[offset]),
+            2 => ImmediateVal::Immediate16(u16::from_le_bytes([
+                self.raw[offset],
+                self.raw[offset + 1],
+            ])),
+            _ => ImmediateVal::None,
+        }
     }
 
     /// Read 8-bit immediate value.
@@ -137,9 +176,11 @@ impl Instruction {
     /// Returns an error if index is out of bounds or
     /// the requested value is not 8-bit.
     pub fn imm8(&self, idx: usize) -> Result<u8> {
-        let immval = self.immediate.get(idx).context("Index out of bounds")?;
-        if let ImmediateVal::Immediate8(val) = immval {
-            Ok(*val)
+        if idx >= self.def.operands.len() {
+            bail!("Index out of bounds");
+        }
+        if let ImmediateVal::Immediate8(val) = self.immediate(idx) {
+            Ok(val)
         } else {
             bail!("Value not 8-bit")
         }
@@ -152,9 +193,11 @@ impl Instruction {
     pub fn imms8(&self, idx: usize) -> Result<i8> {
         // TODO fix representation in instruction table and disassembly.
 
-        let immval = self.immediate.get(idx).context("Index out of bounds")?;
-        if let ImmediateVal::Immediate8(val) = immval {
-            Ok(*val as i8)
+        if idx >= self.def.operands.len() {
+            bail!("Index out of bounds");
+        }
+        if let ImmediateVal::Immediate8(val) = self.immediate(idx) {
+            Ok(val as i8)
         } else {
             bail!("Value not 8-bit")
         }
@@ -165,46 +208,74 @@ impl Instruction {
     /// Returns an error if index is out of bounds or
     /// the requested value is not 16-bit.
     pub fn imm16(&self, idx: usize) -> Result<u16> {
-        let immval = self.immediate.get(idx).context("Index out of bounds")?;
-        if let ImmediateVal::Immediate16(val) = immval {
-            Ok(*val)
+        if idx >= self.def.operands.len() {
+            bail!("Index out of bounds");
+        }
+        if let ImmediateVal::Immediate16(val) = self.immediate(idx) {
+            Ok(val)
         } else {
             bail!("Value not 16-bit")
         }
     }
 
+    /// Returns the address referred to by a 16-bit immediate or relative
+    /// operand, with 'addr' being the address of the instruction itself.
+    pub fn target(&self, addr: u16) -> Option<u16> {
+        self.def
+            .operands
+            .iter()
+            .enumerate()
+            .find_map(|(i, operand)| match (operand, self.immediate(i)) {
+                (
+                    Operand::Immediate16 | Operand::ImmediateIndirect16,
+                    ImmediateVal::Immediate16(val),
+                ) => Some(val),
+                (Operand::Relative8, ImmediateVal::Immediate8(val)) => Some(
+                    addr.wrapping_add(self.len as u16)
+                        .wrapping_add(val as i8 as u16),
+                ),
+                _ => None,
+            })
+    }
+
     /// Returns the (first) opcode of the instruction.
     pub fn get_opcode(&self) -> u8 {
         self.raw[0]
     }
-}
 
-impl fmt::Display for Instruction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    /// The instruction in assembly, with the immediate values filled in
+    /// (e.g. 'LD A,$12')
+    pub fn to_asm(&self) -> String {
         let mut s = self.def.mnemonic.to_string();
 
         // Fill in immediate values.
         for (i, operand) in self.def.operands.iter().enumerate() {
             s = match operand {
                 Operand::Immediate8 => {
-                    s.replacen("d8", format!("{}", self.immediate[i]).as_str(), 1)
+                    s.replacen("d8", format!("{}", self.immediate(i)).as_str(), 1)
                 }
                 Operand::ImmediateIndirect8 => {
-                    s.replacen("a8", format!("{}", self.immediate[i]).as_str(), 1)
+                    s.replacen("a8", format!("{}", self.immediate(i)).as_str(), 1)
                 }
                 Operand::Immediate16 => {
-                    s.replacen("d16", format!("{}", self.immediate[i]).as_str(), 1)
+                    s.replacen("d16", format!("{}", self.immediate(i)).as_str(), 1)
                 }
                 Operand::ImmediateIndirect16 => {
-                    s.replacen("a16", format!("{}", self.immediate[i]).as_str(), 1)
+                    s.replacen("a16", format!("{}", self.immediate(i)).as_str(), 1)
                 }
                 Operand::Relative8 | Operand::SPRelative8 => {
-                    s.replacen("r8", format!("{}", self.immediate[i]).as_str(), 1)
+                    s.replacen("r8", format!("{}", self.immediate(i)).as_str(), 1)
                 }
                 _ => s,
             }
         }
-        write!(f, "{:02X?} {}", self.raw, s.as_str())
+        s
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X?} {}", self.raw(), self.to_asm())
     }
 }
 
@@ -225,4 +296,78 @@ mod tests {
         let i = Instruction::decode(&mut test.into_iter()).unwrap();
         assert!(i.def.mnemonic == INSTRUCTIONS_CB[0].mnemonic);
     }
+
+    #[test]
+    fn instruction_target() {
+        let decode = |b: &[u8]| Instruction::decode(&mut b.iter().copied()).unwrap();
+        // JP a16
+        assert_eq!(decode(&[0xC3, 0x50, 0x01]).target(0x100), Some(0x0150));
+        // LD (a16),A
+        assert_eq!(decode(&[0xEA, 0x00, 0xC0]).target(0x100), Some(0xC000));
+        // JR r8 (backwards)
+        assert_eq!(decode(&[0x18, 0xFE]).target(0x150), Some(0x0150));
+        // JR NZ,r8
+        assert_eq!(decode(&[0x20, 0x05]).target(0x150), Some(0x0157));
+        // LD A,d8
+        assert_eq!(decode(&[0x3E, 0x12]).target(0x100), None);
+        assert_eq!(decode(&[0x00]).target(0x100), None);
+    }
+
+    #[test]
+    fn instruction_len() {
+        for (opcode_len, defs) in [(1, &INSTRUCTIONS), (2, &INSTRUCTIONS_CB)] {
+            for def in defs.iter().filter(|d| d.mnemonic != "PREFIX CB") {
+                assert_eq!(
+                    opcode_len + def.immediate_len(),
+                    def.len,
+                    "{}",
+                    def.mnemonic
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn instruction_immediates() {
+        let decode = |b: &[u8]| Instruction::decode(&mut b.iter().copied()).unwrap();
+
+        // LD (a16),SP
+        let i = decode(&[0x08, 0x34, 0x12, 0xFF]);
+        assert_eq!(i.raw(), [0x08, 0x34, 0x12]);
+        assert_eq!(i.imm16(0).unwrap(), 0x1234);
+        assert!(i.imm8(0).is_err());
+        assert!(i.imm16(1).is_err());
+        assert!(i.imm16(2).is_err());
+
+        // LD B,d8
+        let i = decode(&[0x06, 0xAB]);
+        assert_eq!(i.imm8(1).unwrap(), 0xAB);
+        assert!(i.imm8(0).is_err());
+
+        // LD HL,SP+r8
+        let i = decode(&[0xF8, 0xFE]);
+        assert_eq!(i.imms8(1).unwrap(), -2);
+
+        // SWAP B
+        let i = decode(&[0xCB, 0x30, 0x00]);
+        assert_eq!(i.len, 2);
+        assert_eq!(i.get_opcode(), 0xCB);
+        assert_eq!(i.to_string(), "[CB, 30] SWAP B");
+    }
+
+    #[test]
+    fn instruction_decode_reads_only_instruction() {
+        let mut reads = 0;
+        let i = Instruction::decode_with(|| {
+            reads += 1;
+            Ok(0xC3)
+        })
+        .unwrap();
+        assert_eq!(i.len, 3);
+        assert_eq!(reads, 3);
+
+        // Truncated
+        let mut stream = [0xC3, 0x50].into_iter();
+        assert!(Instruction::decode(&mut stream).is_err());
+    }
 }
@@ -146,7 +146,7 @@ pub const INSTRUCTIONS: [InstructionDef; 256] = [
     },
     // STOP 0 (2), - - - -
     InstructionDef {
-        mnemonic: "STOP 0",
+        mnemonic: "STOP d8",
         operands: [Operand::Immediate8, Operand::None],
         len: 2,
         // 'Condition not met' cycle cost is for CGB speed switch
@@ -2113,14 +2113,14 @@ pub const INSTRUCTIONS: [InstructionDef; 256] = [
         cycles: [12, 12],
         func: CPU::op_pop,
     },
-    // LD (C),A (2), - - - -
+    // LD (C),A (1), - - - -
     InstructionDef {
         mnemonic: "LD (C),A",
         operands: [
             Operand::RegisterIndirect(Register::C),
             Operand::Register(Register::A),
         ],
-        len: 2,
+        len: 1,
         cycles: [8, 8],
         func: CPU::op_ld,
     },
@@ -2172,10 +2172,10 @@ pub const INSTRUCTIONS: [InstructionDef; 256] = [
         cycles: [16, 16],
         func: CPU::op_add_sp,
     },
-    // JP (HL) (1), - - - -
+    // JP HL (1), - - - -
     InstructionDef {
-        mnemonic: "JP (HL)",
-        operands: [Operand::RegisterIndirect(Register::HL), Operand::None],
+        mnemonic: "JP HL",
+        operands: [Operand::Register(Register::HL), Operand::None],
         len: 1,
         cycles: [4, 4],
         func: CPU::op_jp,
@@ -2244,14 +2244,14 @@ pub const INSTRUCTIONS: [InstructionDef; 256] = [
         cycles: [12, 12],
         func: CPU::op_pop,
     },
-    // LD A,(C) (2), - - - -
+    // LD A,(C) (1), - - - -
     InstructionDef {
         mnemonic: "LD A,(C)",
         operands: [
             Operand::Register(Register::A),
             Operand::RegisterIndirect(Register::C),
         ],
-        len: 2,
+        len: 1,
         cycles: [8, 8],
         func: CPU::op_ld,
     },
@@ -4487,3 +4487,59 @@ pub const INSTRUCTIONS_CB: [InstructionDef; 256] = [
         func: CPU::op_set,
     },
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instruction length derived from the operand kinds
+    fn canonical_len(def: &InstructionDef, cb: bool) -> usize {
+        if cb {
+            return 2;
+        }
+        1 + def
+            .operands
+            .iter()
+            .map(|op| match op {
+                Operand::Immediate8
+                | Operand::ImmediateIndirect8
+                | Operand::Relative8
+                | Operand::SPRelative8 => 1,
+                Operand::Immediate16 | Operand::ImmediateIndirect16 => 2,
+                _ => 0,
+            })
+            .sum::<usize>()
+    }
+
+    fn mismatches(table: &[InstructionDef], cb: bool) -> Vec<String> {
+        table
+            .iter()
+            .enumerate()
+            .filter(|(_, def)| def.len != canonical_len(def, cb))
+            .map(|(op, def)| {
+                format!(
+                    "{}{:02X} {} (len {}, expected {})",
+                    if cb { "CB " } else { "" },
+                    op,
+                    def.mnemonic,
+                    def.len,
+                    canonical_len(def, cb)
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn instruction_lengths() {
+        let mismatches = [
+            mismatches(&INSTRUCTIONS, false),
+            mismatches(&INSTRUCTIONS_CB, true),
+        ]
+        .concat();
+        assert!(
+            mismatches.is_empty(),
+            "Length mismatches:\n{}",
+            mismatches.join("\n")
+        );
+    }
+}
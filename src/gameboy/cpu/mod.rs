@@ -1,5 +1,9 @@
 mod alu;
+pub mod assembler;
 pub mod cpu;
+pub mod fuzz;
+pub mod history;
 pub mod instruction;
 pub mod instructions;
 pub mod regs;
+pub mod timebase;
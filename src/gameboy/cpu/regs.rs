@@ -1,6 +1,7 @@
 use std::fmt;
 
-use anyhow::{bail, Result};
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use crate::error::{bail, Result};
 use num_derive::ToPrimitive;
 use num_traits::ToPrimitive;
 
@@ -294,6 +295,29 @@ impl fmt::Display for RegisterFile {
     }
 }
 
+impl Savestate for RegisterFile {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_slice(&[
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ]);
+        w.put_u16(self.sp);
+        w.put_u16(self.pc);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let mut regs = [0; 8];
+        r.get_slice(&mut regs)?;
+        [
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ] = regs;
+        // Bottom 4 bits of F always read 0
+        self.f &= 0xF0;
+        self.sp = r.get_u16()?;
+        self.pc = r.get_u16()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -345,6 +369,30 @@ mod tests {
         assert_eq!(r.a, 0);
     }
 
+    #[test]
+    fn write_f_low_nibble() {
+        let mut r = RegisterFile::new();
+        r.write(Register::F, 0x01).unwrap();
+        assert_eq!(r.read8(Register::F).unwrap(), 0x00);
+        assert_eq!(r.read16(Register::AF).unwrap(), 0x0000);
+
+        r.write(Register::AF, 0xFFFF).unwrap();
+        assert_eq!(r.read8(Register::F).unwrap(), 0xF0);
+    }
+
+    #[test]
+    fn load_state_f_low_nibble() {
+        let mut r = RegisterFile::new();
+        r.f = 0xFF;
+        let mut w = StateWriter::new();
+        r.save_state(&mut w);
+
+        let mut r = RegisterFile::new();
+        let state = w.into_vec();
+        r.load_state(&mut StateReader::new(&state).unwrap()).unwrap();
+        assert_eq!(r.f, 0xF0);
+    }
+
     #[test]
     fn write_comb16bit() {
         let mut r = RegisterFile::new();
@@ -399,8 +447,8 @@ mod tests {
         assert!(matches!(r.read8(Register::E), Ok(0x12)));
 
         let mut r = RegisterFile::new();
-        r.f = 0x12;
-        assert!(matches!(r.read8(Register::F), Ok(0x12)));
+        r.f = 0x50;
+        assert!(matches!(r.read8(Register::F), Ok(0x50)));
 
         let mut r = RegisterFile::new();
         r.h = 0x12;
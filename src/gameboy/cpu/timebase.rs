@@ -0,0 +1,148 @@
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+
+use anyhow::Result;
+
+/// Time base ticks per second (cycles at double speed)
+const TICKS_PER_SEC: u128 = CPU_CLOCK_HZ as u128 * 2;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Converts the CPU cycle counter to emulated time. A cycle takes half
+/// as long in CGB double speed mode, so the counter is split into
+/// epochs at each speed switch, which each run at a single speed. The
+/// conversion is exact and does not drift over speed switches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeBase {
+    /// Cycle count at the start of the current epoch
+    epoch_cycles: u64,
+    /// Time at the start of the current epoch, in cycles at double
+    /// speed
+    epoch_ticks: u128,
+    /// The current epoch runs at double speed
+    double_speed: bool,
+}
+
+impl TimeBase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new epoch at a speed switch, at the specified cycle count
+    pub fn switch_speed(&mut self, cycles: u64, double_speed: bool) {
+        self.epoch_ticks = self.ticks(cycles);
+        self.epoch_cycles = cycles;
+        self.double_speed = double_speed;
+    }
+
+    /// Time at a cycle count in the current epoch, in cycles at double
+    /// speed
+    fn ticks(&self, cycles: u64) -> u128 {
+        let elapsed = u128::from(cycles.saturating_sub(self.epoch_cycles));
+        let per_cycle = if self.double_speed { 1 } else { 2 };
+        self.epoch_ticks + elapsed * per_cycle
+    }
+
+    /// Emulated time at a cycle count in the current epoch, in
+    /// nanoseconds (rounded down)
+    pub fn nanos(&self, cycles: u64) -> u128 {
+        self.ticks(cycles) * NANOS_PER_SEC / TICKS_PER_SEC
+    }
+}
+
+impl Savestate for TimeBase {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u64(self.epoch_cycles);
+        w.put_u128(self.epoch_ticks);
+        w.put_bool(self.double_speed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.epoch_cycles = r.get_u64()?;
+        self.epoch_ticks = r.get_u128()?;
+        self.double_speed = r.get_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HZ: u64 = CPU_CLOCK_HZ as u64;
+
+    #[test]
+    fn normal_speed() {
+        let t = TimeBase::new();
+        assert_eq!(t.nanos(0), 0);
+        assert_eq!(t.nanos(HZ), 1_000_000_000);
+        assert_eq!(t.nanos(HZ * 3600), 3600 * 1_000_000_000);
+        // 238.4185791015625 ns
+        assert_eq!(t.nanos(1), 238);
+    }
+
+    #[test]
+    fn speed_switch() {
+        let mut t = TimeBase::new();
+        t.switch_speed(HZ, true);
+        assert_eq!(t.nanos(HZ), 1_000_000_000);
+        // A second at double speed
+        assert_eq!(t.nanos(HZ * 3), 2_000_000_000);
+        t.switch_speed(HZ * 3, false);
+        assert_eq!(t.nanos(HZ * 4), 3_000_000_000);
+
+        // Switching back and forth on odd cycles does not drift
+        let mut t = TimeBase::new();
+        let mut cycles = 0;
+        for i in 0..1000 {
+            cycles += 2051;
+            t.switch_speed(cycles, i % 2 == 0);
+        }
+        // 500 * 2051 cycles at each speed
+        assert_eq!(
+            t.nanos(cycles),
+            500 * 2051 * 3 * 1_000_000_000 / (HZ as u128 * 2)
+        );
+    }
+
+    #[test]
+    fn monotonic() {
+        let mut t = TimeBase::new();
+        let mut last = 0;
+        for cycles in 0..10_000 {
+            if cycles % 1000 == 0 {
+                t.switch_speed(cycles, !t.double_speed);
+            }
+            let nanos = t.nanos(cycles);
+            assert!(nanos >= last);
+            last = nanos;
+        }
+    }
+
+    #[test]
+    fn near_overflow() {
+        let mut t = TimeBase::new();
+        t.switch_speed(u64::MAX - HZ * 2, true);
+        let start = t.nanos(u64::MAX - HZ * 2);
+        assert_eq!(
+            start,
+            (u64::MAX - HZ * 2) as u128 * 1_000_000_000 / HZ as u128
+        );
+        assert_eq!(t.nanos(u64::MAX) - start, 1_000_000_000);
+    }
+
+    #[test]
+    fn savestate() {
+        let mut t = TimeBase::new();
+        t.switch_speed(12345, true);
+        let mut w = StateWriter::new();
+        t.save_state(&mut w);
+        let data = w.into_vec();
+
+        let mut t2 = TimeBase::new();
+        let mut r = StateReader::new(&data).unwrap();
+        t2.load_state(&mut r).unwrap();
+        assert_eq!(t2, t);
+        assert_eq!(t2.nanos(99999), t.nanos(99999));
+    }
+}
@@ -0,0 +1,807 @@
+use std::cell::Cell;
+use std::fmt::{self, Write};
+use std::ops::Range;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::cheatfinder::{CheatFinder, Filter, Snapshot};
+use crate::gameboy::cpu::assembler::assemble;
+use crate::gameboy::cpu::regs::Register;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::hexdump;
+use crate::gameboy::lcd::{ScanlineHook, LCD_H};
+use crate::gameboy::symbols::SymbolTable;
+
+/// Switchable ROM bank region, where breakpoints can be banked
+const ROM_BANKED: Range<u16> = 0x4000..0x8000;
+
+/// A PC breakpoint, optionally only in a specific ROM bank
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    /// ROM bank, None matches any bank (always None outside of the
+    /// switchable bank)
+    pub bank: Option<usize>,
+}
+
+impl Breakpoint {
+    /// Breakpoint in any bank
+    pub fn new(addr: u16) -> Self {
+        Self { addr, bank: None }
+    }
+
+    /// Breakpoint in a specific bank, the bank is ignored for addresses
+    /// outside of the switchable ROM bank.
+    pub fn banked(bank: usize, addr: u16) -> Self {
+        Self {
+            addr,
+            bank: ROM_BANKED.contains(&addr).then_some(bank),
+        }
+    }
+
+    /// Checks if the breakpoint triggers at 'pc' with 'rom_bank' mapped
+    pub fn matches(&self, pc: u16, rom_bank: usize) -> bool {
+        self.addr == pc && self.bank.is_none_or(|b| b == rom_bank)
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr),
+            None => write!(f, "{:04X}", self.addr),
+        }
+    }
+}
+
+/// Longest instruction, in bytes
+const MAX_INSTR_LEN: usize = 3;
+
+/// Instructions before PC in the disassembly of a DebugSnapshot
+pub const SNAPSHOT_DISASM_BEFORE: usize = 8;
+
+/// Instructions after PC in the disassembly of a DebugSnapshot
+pub const SNAPSHOT_DISASM_AFTER: usize = 16;
+
+/// One line of a disassembly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    /// Label at the address, if any
+    pub label: Option<String>,
+    /// Instruction with labels substituted, or the decoding error
+    pub text: String,
+    /// Length of the instruction, 0 if it could not be decoded
+    pub len: usize,
+    /// A breakpoint (in any bank) is set at the address
+    pub breakpoint: bool,
+}
+
+/// State of an Emulator as shown by a debugger frontend, at one point
+/// in time. It owns all of its data, so it can be sent to a frontend
+/// running on another thread than the emulator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugSnapshot {
+    /// AF, BC, DE, HL, SP and PC
+    pub regs: [u16; 6],
+    pub ime: bool,
+    pub rom_bank: usize,
+    pub cycles: u64,
+    pub frame: u64,
+    /// PC is at a breakpoint
+    pub at_breakpoint: bool,
+    /// Disassembly around PC
+    pub disasm: Vec<DisasmLine>,
+    /// Index of the line of PC in 'disasm'
+    pub pc_line: usize,
+    /// Start address of 'memory'
+    pub mem_addr: u16,
+    /// A 256 byte page of memory, read without side effects
+    pub memory: Vec<u8>,
+}
+
+/// Debugging state (breakpoints, symbols, cheat search) for an Emulator
+#[derive(Default)]
+pub struct Debugger {
+    symbols: SymbolTable,
+    breakpoints: Vec<Breakpoint>,
+    cheats: CheatFinder,
+
+    /// Scanline to break on (see set_line_breakpoint())
+    line_breakpoint: Option<u8>,
+    /// Set by the scanline hook when the line is drawn
+    line_hit: Rc<Cell<bool>>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Parses an address, which is either a label or a hexadecimal
+    /// number (optionally prefixed with '$' or '0x').
+    pub fn parse_addr(&self, s: &str) -> Result<u16> {
+        if let Some(sym) = self.symbols.get(s) {
+            return Ok(sym.addr);
+        }
+        let hex = s
+            .strip_prefix('$')
+            .or_else(|| s.strip_prefix("0x"))
+            .unwrap_or(s);
+        u16::from_str_radix(hex, 16).with_context(|| format!("Invalid address or label: {}", s))
+    }
+
+    /// Parses a breakpoint, which is an address (see parse_addr()),
+    /// optionally prefixed by a hexadecimal ROM bank ('bank:addr').
+    /// Labels in the switchable ROM bank give a breakpoint in the bank
+    /// of the label.
+    pub fn parse_breakpoint(&self, s: &str) -> Result<Breakpoint> {
+        if let Some(sym) = self.symbols.get(s) {
+            return Ok(Breakpoint::banked(sym.bank, sym.addr));
+        }
+        match s.split_once(':') {
+            Some((bank, addr)) => Ok(Breakpoint::banked(
+                usize::from_str_radix(bank, 16)
+                    .with_context(|| format!("Invalid bank: {}", bank))?,
+                self.parse_addr(addr)?,
+            )),
+            None => Ok(Breakpoint::new(self.parse_addr(s)?)),
+        }
+    }
+
+    /// Adds a breakpoint (see parse_breakpoint()), returns the breakpoint
+    pub fn add_breakpoint(&mut self, spec: &str) -> Result<Breakpoint> {
+        let bp = self.parse_breakpoint(spec)?;
+        self.set_breakpoint(bp);
+        Ok(bp)
+    }
+
+    /// Removes a breakpoint (see parse_breakpoint()), returns the breakpoint
+    pub fn remove_breakpoint(&mut self, spec: &str) -> Result<Breakpoint> {
+        let bp = self.parse_breakpoint(spec)?;
+        if !self.clear_breakpoint(bp) {
+            bail!("No breakpoint at {}", bp);
+        }
+        Ok(bp)
+    }
+
+    pub fn set_breakpoint(&mut self, bp: Breakpoint) {
+        if !self.breakpoints.contains(&bp) {
+            self.breakpoints.push(bp);
+        }
+    }
+
+    /// Removes a breakpoint, returns false if there was none
+    pub fn clear_breakpoint(&mut self, bp: Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|&b| b != bp);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Checks if the emulator is at a breakpoint, taking the currently
+    /// mapped ROM bank into account.
+    pub fn at_breakpoint(&self, emu: &Emulator) -> bool {
+        let pc = emu.cpu().regs.pc;
+        if !self.breakpoints.iter().any(|bp| bp.addr == pc) {
+            return false;
+        }
+        let bank = emu.bus().cartridge().current_rom_bank();
+        self.breakpoints.iter().any(|bp| bp.matches(pc, bank))
+    }
+
+    /// Sets (or clears, with None) a breakpoint on the PPU drawing a
+    /// scanline. This installs a scanline hook on the LCD of the emulator.
+    pub fn set_line_breakpoint(&mut self, emu: &mut Emulator, line: Option<u8>) -> Result<()> {
+        let hook: Option<ScanlineHook> = match line {
+            Some(l) if l as usize >= LCD_H => bail!("Line out of range (0-{})", LCD_H - 1),
+            Some(l) => {
+                let hit = Rc::clone(&self.line_hit);
+                Some(Box::new(move |ly, _| {
+                    if ly == l {
+                        hit.set(true);
+                    }
+                }))
+            }
+            None => None,
+        };
+        emu.get_lcd_mut().set_scanline_hook(hook);
+        self.line_breakpoint = line;
+        self.line_hit.set(false);
+        Ok(())
+    }
+
+    pub fn line_breakpoint(&self) -> Option<u8> {
+        self.line_breakpoint
+    }
+
+    /// Runs until PC reaches a breakpoint, the PPU reaches the line
+    /// breakpoint or for (at least) 'max_cycles'. Always executes at
+    /// least one instruction, so this can be used to continue from a
+    /// breakpoint. Returns true if a breakpoint was hit.
+    pub fn run(&self, emu: &mut Emulator, max_cycles: u64) -> Result<bool> {
+        let mut cycles = 0;
+        loop {
+            cycles += emu.step()? as u64;
+            if self.line_hit.take() || self.at_breakpoint(emu) {
+                return Ok(true);
+            }
+            if cycles >= max_cycles {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Returns the label at an address, taking the currently mapped
+    /// ROM bank into account.
+    pub fn label(&self, emu: &Emulator, addr: u16) -> Option<&str> {
+        self.symbols
+            .lookup(addr, emu.bus().cartridge().current_rom_bank())
+    }
+
+    /// Formats an address with its label, if there is one
+    pub fn format_addr(&self, emu: &Emulator, addr: u16) -> String {
+        match self.label(emu, addr) {
+            Some(l) => format!("{:04X} ({})", addr, l),
+            None => format!("{:04X}", addr),
+        }
+    }
+
+    /// Formats a breakpoint with its label, if there is one
+    pub fn format_breakpoint(&self, emu: &Emulator, bp: Breakpoint) -> String {
+        let Some(bank) = bp.bank else {
+            return self.format_addr(emu, bp.addr);
+        };
+        match self.symbols.lookup(bp.addr, bank) {
+            Some(l) => format!("{} ({})", bp, l),
+            None => bp.to_string(),
+        }
+    }
+
+    /// Disassembles 'count' instructions from 'addr', with labels
+    /// substituted for the addresses they refer to.
+    pub fn disassemble(&self, emu: &Emulator, addr: u16, count: usize) -> String {
+        let mut out = String::new();
+        let mut addr = addr;
+        for _ in 0..count {
+            if let Some(l) = self.label(emu, addr) {
+                writeln!(out, "{}:", l).unwrap();
+            }
+            let (s, len) = match self.disassemble_one(emu, addr) {
+                Ok(i) => i,
+                Err(e) => {
+                    writeln!(out, "  {:04X}: <{}>", addr, e).unwrap();
+                    break;
+                }
+            };
+            writeln!(out, "  {:04X}: {}", addr, s).unwrap();
+            addr = addr.wrapping_add(len as u16);
+        }
+        out
+    }
+
+    /// Disassembles the instruction at 'addr', with labels substituted
+    /// for the addresses it refers to. Returns the text and the length
+    /// of the instruction.
+    fn disassemble_one(&self, emu: &Emulator, addr: u16) -> Result<(String, usize)> {
+        let instr = emu.cpu().peek_instr_at(addr)?;
+        let mut s = instr.to_string();
+        if let Some(target) = instr.target(addr) {
+            if let Some(l) = self.label(emu, target) {
+                // The operand is shown as the raw immediate value
+                let imm = (0..instr.def.operands.len())
+                    .map(|i| instr.immediate(i).to_string())
+                    .find(|i| s.contains(i.as_str()))
+                    .unwrap();
+                s = s.replacen(&imm, l, 1);
+            }
+        }
+        Ok((s, instr.len))
+    }
+
+    /// Checks if decoding instructions from 'start' lands exactly on
+    /// 'addr'
+    fn decodes_to(&self, emu: &Emulator, start: u16, addr: u16) -> bool {
+        let distance = addr.wrapping_sub(start) as usize;
+        let mut offset = 0;
+        while offset < distance {
+            match emu.cpu().peek_instr_at(start.wrapping_add(offset as u16)) {
+                Ok(instr) => offset += instr.len,
+                Err(_) => return false,
+            }
+        }
+        offset == distance
+    }
+
+    /// Disassembles a window of instructions around PC: up to 'before'
+    /// instructions before it, PC itself and 'after' instructions after
+    /// it. Returns the lines and the index of the line of PC.
+    /// Instructions have different lengths, so the instructions before
+    /// PC are decoded from the furthest address that lines up with PC.
+    pub fn disassemble_window(
+        &self,
+        emu: &Emulator,
+        before: usize,
+        after: usize,
+    ) -> (Vec<DisasmLine>, usize) {
+        let pc = emu.cpu().regs.pc;
+        let start = (1..=before * MAX_INSTR_LEN)
+            .rev()
+            .map(|back| pc.wrapping_sub(back as u16))
+            .find(|&start| self.decodes_to(emu, start, pc))
+            .unwrap_or(pc);
+
+        let mut lines = vec![];
+        let mut addr = start;
+        while addr != pc {
+            let line = self.disasm_line(emu, addr);
+            addr = addr.wrapping_add(line.len as u16);
+            lines.push(line);
+        }
+        let lines_before = lines.len().min(before);
+        lines.drain(..lines.len() - lines_before);
+
+        for _ in 0..=after {
+            let line = self.disasm_line(emu, addr);
+            if line.len == 0 {
+                lines.push(line);
+                break;
+            }
+            addr = addr.wrapping_add(line.len as u16);
+            lines.push(line);
+        }
+        (lines, lines_before)
+    }
+
+    fn disasm_line(&self, emu: &Emulator, addr: u16) -> DisasmLine {
+        let (text, len) = self
+            .disassemble_one(emu, addr)
+            .unwrap_or_else(|e| (format!("<{}>", e), 0));
+        DisasmLine {
+            addr,
+            label: self.label(emu, addr).map(str::to_string),
+            text,
+            len,
+            breakpoint: self.breakpoints.iter().any(|bp| bp.addr == addr),
+        }
+    }
+
+    /// Captures the state shown by a debugger frontend, with the 256
+    /// byte memory page containing 'mem_addr'.
+    pub fn snapshot(&self, emu: &Emulator, mem_addr: u16) -> DebugSnapshot {
+        let regs = &emu.cpu().regs;
+        let (disasm, pc_line) =
+            self.disassemble_window(emu, SNAPSHOT_DISASM_BEFORE, SNAPSHOT_DISASM_AFTER);
+        let mem_addr = mem_addr & !0xFF;
+        DebugSnapshot {
+            regs: [
+                Register::AF,
+                Register::BC,
+                Register::DE,
+                Register::HL,
+                Register::SP,
+                Register::PC,
+            ]
+            .map(|r| regs.read16(r).unwrap()),
+            ime: emu.cpu().ime,
+            rom_bank: emu.bus().cartridge().current_rom_bank(),
+            cycles: emu.cpu().get_cycles(),
+            frame: emu.get_frame_count(),
+            at_breakpoint: self.at_breakpoint(emu),
+            disasm,
+            pc_line,
+            mem_addr,
+            memory: (0..=0xFF).map(|i| emu.cpu().peek(mem_addr | i)).collect(),
+        }
+    }
+
+    /// Hexdump of memory, as seen by the CPU (without side effects).
+    /// The cartridge header, OAM and IO registers are annotated, see
+    /// hexdump::format_memory().
+    pub fn dump_memory(&self, emu: &Emulator, addr: u16, len: usize) -> String {
+        let data: Vec<u8> = (0..len)
+            .map(|i| emu.cpu().peek(addr.wrapping_add(i as u16)))
+            .collect();
+        hexdump::format_memory(addr, &data)
+    }
+
+    /// Writes bytes to memory, through the bus
+    pub fn write_memory(&self, emu: &mut Emulator, addr: u16, data: &[u8]) {
+        for (i, &b) in data.iter().enumerate() {
+            emu.cpu_mut().write(addr.wrapping_add(i as u16), b);
+        }
+    }
+
+    /// Assembles a single instruction and patches it into ROM at the
+    /// given address (see Gameboybus::patch_rom()). Returns the bytes.
+    pub fn patch(&self, emu: &mut Emulator, addr: u16, asm: &str) -> Result<Vec<u8>> {
+        let bytes = assemble(asm)?;
+        emu.bus_mut().patch_rom(addr, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// Patches ROM with a listing of instructions, one per line. A line
+    /// is either '<addr>: <instruction>' or an instruction following the
+    /// previous one. Comments start with ';'. Returns the amount of
+    /// patched instructions.
+    pub fn patch_lines(&self, emu: &mut Emulator, text: &str) -> Result<usize> {
+        let mut addr = None;
+        let mut count = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let asm = match line.split_once(':') {
+                Some((a, asm)) => {
+                    addr = Some(
+                        self.parse_addr(a.trim())
+                            .with_context(|| format!("Line {}", i + 1))?,
+                    );
+                    asm
+                }
+                None => line,
+            };
+            let Some(a) = addr else {
+                bail!("Line {}: No address", i + 1);
+            };
+            let bytes = self
+                .patch(emu, a, asm)
+                .with_context(|| format!("Line {}", i + 1))?;
+            addr = Some(a.wrapping_add(bytes.len() as u16));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Starts a new cheat search, with all of searchable memory as
+    /// candidates. Returns the amount of candidates.
+    pub fn find_start(&mut self, emu: &Emulator) -> usize {
+        self.cheats.start(&Snapshot::capture(emu.bus()));
+        self.cheats.candidates().len()
+    }
+
+    /// Narrows down the cheat search, returns the amount of candidates left
+    pub fn find(&mut self, emu: &Emulator, filter: Filter) -> Result<usize> {
+        self.cheats.filter(&Snapshot::capture(emu.bus()), filter)
+    }
+
+    pub fn cheat_finder(&self) -> &CheatFinder {
+        &self.cheats
+    }
+
+    pub fn cheat_finder_mut(&mut self) -> &mut CheatFinder {
+        &mut self.cheats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cartridge::cartridge::{self, Cartridge};
+    use crate::gameboy::cartridge::romonly::RomOnly;
+    use crate::gameboy::model::Model;
+    use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Cartridge reporting a ROM bank set by the test (the contents
+    /// of the ROM do not change)
+    struct MockCart {
+        rom: RomOnly,
+        bank: Arc<AtomicUsize>,
+    }
+
+    impl Cartridge for MockCart {
+        fn current_rom_bank(&self) -> usize {
+            self.bank.load(Ordering::Relaxed)
+        }
+
+        fn dump_state(&self) -> String {
+            "".to_string()
+        }
+
+        fn get_save(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn load_save(&mut self, _save: &[u8]) {}
+    }
+
+    impl BusMember for MockCart {
+        fn read(&self, addr: u16) -> u8 {
+            self.rom.read(addr)
+        }
+
+        fn write(&mut self, _addr: u16, _val: u8) {}
+    }
+
+    impl Savestate for MockCart {
+        fn save_state(&self, _w: &mut StateWriter) {}
+
+        fn load_state(&mut self, _r: &mut StateReader) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// 0x0100: JP main
+    /// 0x0150: NOP, NOP
+    /// 0x0152: main_loop: JR main_loop
+    /// 0x4000: banked: NOP (bank 1)
+    fn emulator() -> Emulator {
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x154].copy_from_slice(&[0x00, 0x00, 0x18, 0xFE]);
+        Emulator::new_headless(cartridge::load(&rom).unwrap(), Model::Dmg)
+    }
+
+    fn debugger() -> Debugger {
+        let mut dbg = Debugger::new();
+        dbg.set_symbols(
+            SymbolTable::parse(
+                "00:0150 main\n00:0152 main_loop\n01:4000 banked\n02:4000 other\n00:c000 wVar\n",
+            )
+            .unwrap(),
+        );
+        dbg
+    }
+
+    #[test]
+    fn parse_addr() {
+        let dbg = debugger();
+        assert_eq!(dbg.parse_addr("main_loop").unwrap(), 0x0152);
+        assert_eq!(dbg.parse_addr("0150").unwrap(), 0x0150);
+        assert_eq!(dbg.parse_addr("$C000").unwrap(), 0xC000);
+        assert_eq!(dbg.parse_addr("0xff80").unwrap(), 0xFF80);
+        assert!(dbg.parse_addr("nope").is_err());
+        assert!(dbg.parse_addr("10000").is_err());
+    }
+
+    #[test]
+    fn breakpoint_by_name() {
+        let mut emu = emulator();
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.add_breakpoint("main_loop").unwrap(),
+            Breakpoint::new(0x0152)
+        );
+        assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        assert_eq!(emu.cpu().regs.pc, 0x0152);
+
+        // Continuing hits the breakpoint again on the next iteration
+        let cycles = emu.cpu().get_cycles();
+        assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        assert_eq!(emu.cpu().regs.pc, 0x0152);
+        assert_eq!(emu.cpu().get_cycles() - cycles, 12);
+
+        dbg.remove_breakpoint("main_loop").unwrap();
+        assert!(dbg.remove_breakpoint("main_loop").is_err());
+        assert!(!dbg.run(&mut emu, 1000).unwrap());
+    }
+
+    #[test]
+    fn disassemble_labels() {
+        let emu = emulator();
+        let dbg = debugger();
+        assert_eq!(
+            dbg.disassemble(&emu, 0x0100, 1),
+            "  0100: [C3, 50, 01] JP main\n"
+        );
+        assert_eq!(
+            dbg.disassemble(&emu, 0x0151, 2),
+            "  0151: [00] NOP\nmain_loop:\n  0152: [18, FE] JR main_loop\n"
+        );
+        // Banked label, bank 1 is mapped
+        assert_eq!(dbg.format_addr(&emu, 0x4000), "4000 (banked)");
+        assert_eq!(dbg.format_addr(&emu, 0x4001), "4001");
+    }
+
+    #[test]
+    fn memory_editor() {
+        let mut emu = emulator();
+        let dbg = debugger();
+        let addr = dbg.parse_addr("wVar").unwrap();
+        dbg.write_memory(&mut emu, addr, &[0x12, 0x34, 0x56]);
+        assert_eq!(dbg.dump_memory(&emu, addr, 4), "C000: 12 34 56 00\n");
+        assert_eq!(dbg.dump_memory(&emu, 0xC000, 20).lines().count(), 2);
+    }
+
+    #[test]
+    fn disassemble_window() {
+        let mut emu = emulator();
+        let dbg = debugger();
+
+        // Lined up with the 3 byte JP before PC
+        emu.cpu_mut().regs.pc = 0x0103;
+        let (lines, pc_line) = dbg.disassemble_window(&emu, 2, 1);
+        let addrs: Vec<u16> = lines.iter().map(|l| l.addr).collect();
+        assert_eq!(addrs, [0x00FF, 0x0100, 0x0103, 0x0104]);
+        assert_eq!(pc_line, 2);
+        assert_eq!(lines[1].text, "[C3, 50, 01] JP main");
+        assert_eq!(lines[1].len, 3);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut emu = emulator();
+        let mut dbg = debugger();
+        dbg.add_breakpoint("main_loop").unwrap();
+        dbg.write_memory(&mut emu, 0xC010, &[0x12, 0x34]);
+
+        let snap = dbg.snapshot(&emu, 0xC010);
+        assert_eq!(snap.regs[5], 0x0100);
+        assert_eq!(snap.pc_line, SNAPSHOT_DISASM_BEFORE);
+        assert_eq!(
+            snap.disasm.len(),
+            SNAPSHOT_DISASM_BEFORE + 1 + SNAPSHOT_DISASM_AFTER
+        );
+        assert_eq!(snap.disasm[0].addr, 0x00F8);
+        assert_eq!(snap.disasm[snap.pc_line].addr, 0x0100);
+        assert_eq!(snap.disasm[snap.pc_line + 1].addr, 0x0103);
+        assert!(!snap.at_breakpoint);
+        assert_eq!(snap.mem_addr, 0xC000);
+        assert_eq!(snap.memory.len(), 0x100);
+        assert_eq!(snap.memory[0x10..0x12], [0x12, 0x34]);
+
+        assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        let snap = dbg.snapshot(&emu, 0xC000);
+        assert!(snap.at_breakpoint);
+        let pc = &snap.disasm[snap.pc_line];
+        assert_eq!(pc.label.as_deref(), Some("main_loop"));
+        assert_eq!(pc.text, "[18, FE] JR main_loop");
+        assert!(pc.breakpoint);
+        assert_eq!(snap.disasm[snap.pc_line - 1].addr, 0x0151);
+        assert_eq!(snap.disasm[snap.pc_line - 2].label.as_deref(), Some("main"));
+        assert!(!snap.disasm[snap.pc_line - 2].breakpoint);
+    }
+
+    #[test]
+    fn find() {
+        let mut emu = emulator();
+        let mut dbg = debugger();
+        dbg.write_memory(&mut emu, 0xC123, &[99]);
+        assert!(dbg.find(&emu, Filter::Equal(99)).unwrap() >= 1);
+        dbg.write_memory(&mut emu, 0xC123, &[98]);
+        assert_eq!(dbg.find(&emu, Filter::Decreased).unwrap(), 1);
+        assert_eq!(dbg.cheat_finder().candidates()[0].addr, 0xC123);
+
+        assert_eq!(dbg.find_start(&emu), 0x4000);
+        dbg.cheat_finder_mut().reset();
+        assert!(dbg.find(&emu, Filter::Changed).is_err());
+    }
+
+    #[test]
+    fn line_breakpoint() {
+        let mut emu = emulator();
+        let mut dbg = debugger();
+        assert!(dbg.set_line_breakpoint(&mut emu, Some(144)).is_err());
+        dbg.set_line_breakpoint(&mut emu, Some(100)).unwrap();
+        assert_eq!(dbg.line_breakpoint(), Some(100));
+
+        for _ in 0..2 {
+            assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES * 2).unwrap());
+            assert_eq!(emu.get_lcd().read(0xFF44), 100);
+        }
+
+        dbg.set_line_breakpoint(&mut emu, None).unwrap();
+        assert!(!dbg.run(&mut emu, Emulator::FRAME_CYCLES * 2).unwrap());
+    }
+
+    #[test]
+    fn breakpoint_matches() {
+        let bp = Breakpoint::banked(2, 0x4123);
+        assert!(bp.matches(0x4123, 2));
+        assert!(!bp.matches(0x4123, 1));
+        assert!(!bp.matches(0x4124, 2));
+        assert!(Breakpoint::new(0x4123).matches(0x4123, 1));
+
+        // Banks only apply to the switchable bank
+        assert_eq!(Breakpoint::banked(1, 0x0150), Breakpoint::new(0x0150));
+        assert_eq!(Breakpoint::banked(1, 0x8000), Breakpoint::new(0x8000));
+        assert_eq!(bp.to_string(), "02:4123");
+    }
+
+    #[test]
+    fn parse_breakpoint() {
+        let dbg = debugger();
+        assert_eq!(
+            dbg.parse_breakpoint("12:4123").unwrap(),
+            Breakpoint::banked(0x12, 0x4123)
+        );
+        assert_eq!(
+            dbg.parse_breakpoint("4123").unwrap(),
+            Breakpoint::new(0x4123)
+        );
+        // Banked labels give banked breakpoints
+        assert_eq!(
+            dbg.parse_breakpoint("other").unwrap(),
+            Breakpoint::banked(2, 0x4000)
+        );
+        assert_eq!(
+            dbg.parse_breakpoint("main").unwrap(),
+            Breakpoint::new(0x0150)
+        );
+        assert!(dbg.parse_breakpoint("xx:4000").is_err());
+        assert!(dbg.parse_breakpoint("1:nope").is_err());
+    }
+
+    #[test]
+    fn banked_breakpoint() {
+        // 0x0100: JP 4000h
+        // 0x4000: JR 4000h
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        rom[0x4000..0x4002].copy_from_slice(&[0x18, 0xFE]);
+        let bank = Arc::new(AtomicUsize::new(1));
+        let cart = MockCart {
+            rom: RomOnly::new(&rom),
+            bank: bank.clone(),
+        };
+        let mut emu = Emulator::new_headless(Box::new(cart), Model::Dmg);
+        let mut dbg = debugger();
+
+        let bp = dbg.add_breakpoint("other").unwrap();
+        assert_eq!(dbg.format_breakpoint(&emu, bp), "02:4000 (other)");
+        assert!(!dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        assert_eq!(emu.cpu().regs.pc, 0x4000);
+
+        bank.store(2, Ordering::Relaxed);
+        assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        assert_eq!(emu.cpu().regs.pc, 0x4000);
+
+        // Any bank
+        bank.store(3, Ordering::Relaxed);
+        assert!(!dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        dbg.add_breakpoint("4000").unwrap();
+        assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+
+        dbg.remove_breakpoint("02:4000").unwrap();
+        assert_eq!(dbg.breakpoints(), [Breakpoint::new(0x4000)]);
+    }
+
+    #[test]
+    fn patch() {
+        let mut emu = emulator();
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.patch(&mut emu, 0x0150, "ld a,$42").unwrap(),
+            [0x3E, 0x42]
+        );
+        assert_eq!(dbg.dump_memory(&emu, 0x0150, 4), "0150: 3E 42 18 FE\n");
+        dbg.add_breakpoint("main_loop").unwrap();
+        assert!(dbg.run(&mut emu, Emulator::FRAME_CYCLES).unwrap());
+        assert_eq!(emu.cpu().regs.read8(Register::A).unwrap(), 0x42);
+
+        assert!(dbg.patch(&mut emu, 0x8000, "nop").is_err());
+        assert!(dbg.patch(&mut emu, 0x0150, "ld a,").is_err());
+    }
+
+    #[test]
+    fn patch_lines() {
+        let mut emu = emulator();
+        let dbg = debugger();
+        let text = "; Patches\nmain: xor a ; comment\n  inc a\n\n0200: jp $0150\n";
+        assert_eq!(dbg.patch_lines(&mut emu, text).unwrap(), 3);
+        assert_eq!(dbg.dump_memory(&emu, 0x0150, 2), "0150: AF 3C\n");
+        assert_eq!(dbg.dump_memory(&emu, 0x0200, 3), "0200: C3 50 01\n");
+
+        let err = dbg.patch_lines(&mut emu, "nop\n").unwrap_err();
+        assert_eq!(format!("{:#}", err), "Line 1: No address");
+        let err = dbg.patch_lines(&mut emu, "0150: nop\nfoo\n").unwrap_err();
+        assert!(format!("{:#}", err).starts_with("Line 2: "));
+    }
+}
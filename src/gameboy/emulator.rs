@@ -0,0 +1,855 @@
+use anyhow::{bail, Result};
+
+use crate::audio::audio::{AudioSink, Sample, SampleClock, SAMPLE_RATE};
+use crate::display::display::{hash_frame, Color, Display, NullDisplay, FRAME_HASH_SIZE};
+use crate::gameboy::apu::APU;
+use crate::gameboy::bus::bus::Bus;
+use crate::gameboy::bus::gbbus::Gameboybus;
+use crate::gameboy::bus::testbus::Testbus;
+use crate::gameboy::cartridge::cartridge::Cartridge;
+use crate::gameboy::clock::EmuClock;
+use crate::gameboy::cpu::cpu::{CPU, CPU_CLOCK_HZ};
+use crate::gameboy::lcd::LCDController;
+use crate::gameboy::model::Model;
+use crate::gameboy::raminit::RamInit;
+use crate::gameboy::runaway::{Runaway, RunawayDetector};
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use crate::gameboy::serial::{Serial, SerialBuffer};
+use crate::input::input::{Input, NullInput};
+
+use std::mem;
+
+/// Result of Emulator::run_for_cycles()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunReport {
+    /// CPU cycles actually executed (the last instruction may exceed
+    /// the budget)
+    pub cycles: u64,
+    /// CPU registers after the run
+    pub registers: String,
+    /// Hash of the framebuffer after the run (see hash_frame())
+    pub framebuffer_hash: [u8; FRAME_HASH_SIZE],
+    /// Serial output collected during the run (headless only)
+    pub serial: Vec<u8>,
+    /// Amount of frames since power on
+    pub frames: u64,
+}
+
+/// How a frontend paces emulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStrategy {
+    /// Run a frame at a time, the frontend waits for the next frame
+    Video,
+    /// Run until the audio sink is filled, the sink blocks until it
+    /// has room for more samples.
+    Audio,
+    /// Run a frame at a time, as fast as possible
+    Off,
+}
+
+/// Least amount of sample frames produced per slice in audio sync mode
+const AUDIO_SLICE_MIN: usize = 64;
+
+/// Most amount of sample frames produced per slice in audio sync mode
+/// (about a frame worth of samples)
+const AUDIO_SLICE_MAX: usize = SAMPLE_RATE / 60;
+
+/// Runs the system until it produced enough samples to fill the free
+/// space of the audio sink, then pushes them (blocking).
+/// step() executes one instruction and returns the cycles taken and
+/// whether the CPU runs in double speed mode.
+pub fn run_audio_slice(
+    clock: &mut SampleClock,
+    sink: &mut dyn AudioSink,
+    mut step: impl FnMut() -> Result<(usize, bool)>,
+) -> Result<()> {
+    let wanted = (sink.free_space() / 2).clamp(AUDIO_SLICE_MIN, AUDIO_SLICE_MAX);
+    let mut frames = 0;
+    while frames < wanted {
+        let (cycles, double_speed) = step()?;
+        frames += clock.advance(cycles, double_speed);
+    }
+    push_silence(sink, frames * 2);
+    Ok(())
+}
+
+/// Pushes the samples produced in the specified amount of cycles to the
+/// audio sink, dropping what does not fit so this never blocks.
+pub fn feed_audio(
+    clock: &mut SampleClock,
+    sink: &mut dyn AudioSink,
+    cycles: usize,
+    double_speed: bool,
+) {
+    let samples = (clock.advance(cycles, double_speed) * 2).min(sink.free_space());
+    push_silence(sink, samples);
+}
+
+/// Most amount of cycles the boot ROM may run for in skip_bootrom()
+/// (the boot ROMs take a few seconds at most).
+pub const BOOTROM_MAX_CYCLES: u64 = CPU_CLOCK_HZ as u64 * 10;
+
+/// Executes the boot ROM as fast as possible, until it unmaps itself
+/// (or BOOTROM_MAX_CYCLES have passed). This leaves the system in the
+/// state the boot ROM hands it over to the cartridge in, at 0x0100.
+/// Does nothing if the boot ROM is not mapped. The CGB boot ROM selects
+/// DMG compatibility mode (KEY0) for DMG cartridges, which is latched
+/// when it unmaps itself, so the system continues in that mode.
+pub fn skip_bootrom(cpu: &mut CPU) -> Result<()> {
+    let start = cpu.get_cycles();
+    while cpu.bus.peek(CPU::BUS_BOOTROM_DISABLE) != 1 {
+        if cpu.get_cycles() - start >= BOOTROM_MAX_CYCLES {
+            bail!(
+                "Boot ROM did not finish in {} cycles (PC: {:04X})",
+                BOOTROM_MAX_CYCLES,
+                cpu.regs.pc
+            );
+        }
+        cpu.step()?;
+    }
+    Ok(())
+}
+
+fn push_silence(sink: &mut dyn AudioSink, len: usize) {
+    // The APU does not generate its channel outputs yet, so the output is
+    // silent.
+    let samples: Vec<Sample> = vec![0; len];
+    sink.push_samples(&samples);
+}
+
+/// A complete Gameboy system (CPU + Gameboy bus and peripherals).
+/// This is the entry point for frontends embedding the emulator.
+pub struct Emulator {
+    cpu: CPU,
+
+    /// Serial output (headless only)
+    serial_out: Option<SerialBuffer>,
+
+    /// Converts emulated time to audio samples
+    sample_clock: SampleClock,
+
+    /// Detects the CPU running away after a crash
+    runaway: RunawayDetector,
+}
+
+impl Emulator {
+    /// CPU cycles in a frame (at normal speed)
+    pub const FRAME_CYCLES: u64 = 70224;
+
+    pub fn new(
+        cart: Box<dyn Cartridge>,
+        bootrom: Option<&[u8]>,
+        display: Box<dyn Display>,
+        input: Box<dyn Input>,
+        model: Model,
+        serial: Serial,
+    ) -> Self {
+        let lcd = LCDController::new(display, model);
+        let bus = Box::new(Gameboybus::new_with_serial(
+            cart, bootrom, lcd, input, model, serial,
+        ));
+
+        Self {
+            cpu: CPU::new(bus, model),
+            serial_out: None,
+            sample_clock: SampleClock::new(),
+            runaway: RunawayDetector::new(),
+        }
+    }
+
+    /// Creates a system without display or input, collecting serial
+    /// output for run_for_cycles().
+    pub fn new_headless(cart: Box<dyn Cartridge>, model: Model) -> Self {
+        let (serial, serial_out) = Serial::new_buffered();
+        let mut emu = Self::new(
+            cart,
+            None,
+            Box::new(NullDisplay::new()),
+            Box::new(NullInput::new()),
+            model,
+            serial,
+        );
+        emu.serial_out = Some(serial_out);
+        emu
+    }
+
+    /// Output collected from the serial port (headless only). Note that
+    /// run_for_cycles() takes the output collected during the run.
+    pub fn serial_output(&self) -> Option<&SerialBuffer> {
+        self.serial_out.as_ref()
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    /// Wraps the bus in another bus (e.g. a CoverageBus). The
+    /// Gameboybus is found through wrappers, so the emulator works
+    /// as before.
+    pub fn wrap_bus(&mut self, wrap: impl FnOnce(Box<dyn Bus>) -> Box<dyn Bus>) {
+        let bus = mem::replace(&mut self.cpu.bus, Box::new(Testbus::new()));
+        self.cpu.bus = wrap(bus);
+    }
+
+    pub fn bus(&self) -> &Gameboybus {
+        Gameboybus::from_bus(&*self.cpu.bus).unwrap()
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Gameboybus {
+        Gameboybus::from_bus_mut(&mut *self.cpu.bus).unwrap()
+    }
+
+    /// Replaces the clock, see Gameboybus::set_clock()
+    pub fn set_clock(&mut self, clock: EmuClock) {
+        self.bus_mut().set_clock(clock);
+    }
+
+    /// Initializes RAM, see Gameboybus::init_ram()
+    pub fn init_ram(&mut self, init: RamInit) {
+        self.bus_mut().init_ram(init);
+    }
+
+    /// Runs the boot ROM (if one was loaded) to completion, without
+    /// waiting for the display. See skip_bootrom().
+    pub fn skip_bootrom(&mut self) -> Result<()> {
+        skip_bootrom(&mut self.cpu)
+    }
+
+    /// Executes one CPU step (one instruction).
+    pub fn step(&mut self) -> Result<usize> {
+        self.cpu.step()
+    }
+
+    /// Runs until the next frame has been completed (start of VBlank).
+    /// If the LCD is disabled, runs for the duration of a frame.
+    /// Stops early and returns a warning if the CPU runs away (see
+    /// Runaway); the emulator can continue afterwards.
+    pub fn run_frame(&mut self) -> Result<Option<Runaway>> {
+        let frame = self.get_frame_count();
+        let max_cycles = if self.cpu.is_double_speed() {
+            Self::FRAME_CYCLES * 2
+        } else {
+            Self::FRAME_CYCLES
+        };
+        let mut cycles = 0;
+
+        while self.get_frame_count() == frame && cycles < max_cycles {
+            let pc = self.cpu.regs.pc;
+            cycles += self.cpu.step()? as u64;
+            if let Some(runaway) = self.runaway.observe(pc, &self.cpu) {
+                return Ok(Some(runaway));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs a slice of emulation paced by the specified strategy and
+    /// feeds the produced audio to the sink. In video sync mode, the
+    /// caller has to wait until the next frame is due.
+    pub fn run_synced(&mut self, sync: SyncStrategy, sink: &mut dyn AudioSink) -> Result<()> {
+        match sync {
+            SyncStrategy::Audio => {
+                let cpu = &mut self.cpu;
+                run_audio_slice(&mut self.sample_clock, sink, || {
+                    Ok((cpu.step()?, cpu.is_double_speed()))
+                })
+            }
+            SyncStrategy::Video | SyncStrategy::Off => {
+                let start = self.cpu.get_cycles();
+                self.run_frame()?;
+                // At most two frames
+                let cycles = (self.cpu.get_cycles() - start) as usize;
+                let double_speed = self.cpu.is_double_speed();
+                feed_audio(&mut self.sample_clock, sink, cycles, double_speed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs for (at least) the specified amount of CPU cycles. Unlike
+    /// wall-clock based limits, this is deterministic.
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<RunReport> {
+        let start = self.cpu.get_cycles();
+        while self.cpu.get_cycles() - start < cycles {
+            self.cpu.step()?;
+        }
+
+        Ok(RunReport {
+            cycles: self.cpu.get_cycles() - start,
+            registers: self.cpu.regs.to_string(),
+            framebuffer_hash: hash_frame(self.get_framebuffer()),
+            serial: self
+                .serial_out
+                .as_ref()
+                .map(|buf| buf.take())
+                .unwrap_or_default(),
+            frames: self.get_frame_count(),
+        })
+    }
+
+    /// Runs for (at least) the specified amount of CPU cycles, pushing
+    /// all audio produced to the sink. Like run_for_cycles(), this is
+    /// deterministic if the sink has room for all samples.
+    pub fn render_audio(&mut self, cycles: u64, sink: &mut dyn AudioSink) -> Result<()> {
+        let start = self.cpu.get_cycles();
+        while self.cpu.get_cycles() - start < cycles {
+            let step = self.cpu.step()?;
+            let double_speed = self.cpu.is_double_speed();
+            feed_audio(&mut self.sample_clock, sink, step, double_speed);
+        }
+        Ok(())
+    }
+
+    /// Returns the current contents of the screen (row-major)
+    pub fn get_framebuffer(&self) -> &[Color] {
+        self.get_lcd().get_framebuffer()
+    }
+
+    /// Returns the amount of frames since power on
+    pub fn get_frame_count(&self) -> u64 {
+        self.get_lcd().get_frame_count()
+    }
+
+    pub fn get_lcd(&self) -> &LCDController {
+        self.bus().get_lcd()
+    }
+
+    pub fn get_lcd_mut(&mut self) -> &mut LCDController {
+        self.bus_mut().get_lcd_mut()
+    }
+
+    pub fn get_apu(&self) -> &APU {
+        self.bus().get_apu()
+    }
+
+    pub fn get_apu_mut(&mut self) -> &mut APU {
+        self.bus_mut().get_apu_mut()
+    }
+
+    /// Returns the contents of cartridge RAM
+    pub fn get_save(&self) -> Vec<u8> {
+        self.bus().cartridge().get_save()
+    }
+
+    /// Replaces the contents of cartridge RAM
+    pub fn load_save(&mut self, save: &[u8]) -> Result<()> {
+        let ramsize = self.bus().cartridge().get_save().len();
+        if save.len() > ramsize {
+            bail!(
+                "Save size ({} bytes) exceeds cartridge RAM ({} bytes)",
+                save.len(),
+                ramsize
+            );
+        }
+        self.bus_mut().cartridge_mut().load_save(save);
+        Ok(())
+    }
+
+    /// Creates a savestate of the complete system
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.cpu.save_state(&mut w);
+        self.bus().save_state(&mut w);
+        w.into_vec()
+    }
+
+    /// Restores a savestate created by save_state()
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = StateReader::new(state)?;
+        self.cpu.load_state(&mut r)?;
+        self.bus_mut().load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::display::NullDisplay;
+    use crate::display::test::TestDisplay;
+    use crate::gameboy::bus::bus::BusMember;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::lcd::{LCD_H, LCD_W};
+    use crate::gameboy::runaway::RUNAWAY_STEPS;
+    use crate::input::input::NullInput;
+    use crate::test::MockClock;
+
+    fn emulator(cart_type: u8) -> Emulator {
+        let mut rom = vec![0; 32 * 1024];
+        // Increment A and store in WRAM at 0xC000, loop
+        rom[0x100..0x108].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0xC3, 0x00, 0x01, 0x00]);
+        rom[0x147] = cart_type;
+        rom[0x148] = 0;
+        rom[0x149] = 2;
+
+        Emulator::new(
+            cartridge::load(&rom).unwrap(),
+            None,
+            Box::new(NullDisplay::new()),
+            Box::new(NullInput::new()),
+            Model::Dmg,
+            Serial::new_null(),
+        )
+    }
+
+    /// Emulator with a 256 byte boot ROM that sets BGP and then runs into
+    /// the boot ROM disable write at 0x00FE. If 'hang' is set, the boot
+    /// ROM never gets there.
+    fn emulator_bootrom(hang: bool) -> Emulator {
+        let mut bootrom = vec![0; 0x100];
+        bootrom[0..7].copy_from_slice(&[
+            0x31, 0xFE, 0xFF, // LD SP,FFFEh
+            0x3E, 0xFC, // LD A,FCh
+            0xE0, 0x47, // LDH (47h),A
+        ]);
+        if hang {
+            bootrom[7..9].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        }
+        bootrom[0xFC..].copy_from_slice(&[
+            0x3E, 0x01, // LD A,01h
+            0xE0, 0x50, // LDH (50h),A
+        ]);
+
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        Emulator::new(
+            cartridge::load(&rom).unwrap(),
+            Some(&bootrom),
+            Box::new(NullDisplay::new()),
+            Box::new(NullInput::new()),
+            Model::Dmg,
+            Serial::new_null(),
+        )
+    }
+
+    /// Sends an incrementing counter over serial
+    fn headless() -> Emulator {
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x10F].copy_from_slice(&[
+            0x3C, // INC A
+            0xE0, 0x01, // LDH (01h),A
+            0x47, // LD B,A
+            0x3E, 0x81, // LD A,81h
+            0xE0, 0x02, // LDH (02h),A
+            0x78, // LD A,B
+            0xEA, 0x00, 0xC0, // LD (C000h),A
+            0xC3, 0x00, 0x01, // JP 0100h
+        ]);
+        Emulator::new_headless(cartridge::load(&rom).unwrap(), Model::Dmg)
+    }
+
+    #[test]
+    fn run_for_cycles() {
+        let mut e = headless();
+        let report = e.run_for_cycles(1000).unwrap();
+        assert!(report.cycles >= 1000);
+        assert!(report.cycles < 1000 + 24);
+        assert_eq!(e.cpu().get_cycles(), report.cycles);
+        assert!(!report.serial.is_empty());
+        assert!(report.serial.windows(2).all(|w| w[1] == w[0] + 1));
+        assert_eq!(report.registers, e.cpu().regs.to_string());
+
+        // Serial output is only reported once
+        let report2 = e.run_for_cycles(1000).unwrap();
+        assert_eq!(report2.serial[0], *report.serial.last().unwrap() + 1);
+    }
+
+    #[test]
+    fn run_for_cycles_deterministic() {
+        let mut a = headless();
+        let mut b = headless();
+        for _ in 0..3 {
+            let report = a.run_for_cycles(Emulator::FRAME_CYCLES).unwrap();
+            assert_eq!(report, b.run_for_cycles(Emulator::FRAME_CYCLES).unwrap());
+        }
+        assert_eq!(a.get_frame_count(), 3);
+    }
+
+    #[test]
+    fn ram_init_random_deterministic() {
+        let run = |init| {
+            let mut e = headless();
+            e.init_ram(init);
+            // Without a boot ROM, the background is disabled
+            e.bus_mut().write(0xFF40, 0x91); // LCDC
+            e.bus_mut().write(0xFF47, 0xE4); // BGP
+            e.run_for_cycles(Emulator::FRAME_CYCLES * 2).unwrap()
+        };
+        // Same seed, same display
+        let report = run(RamInit::Random(1234));
+        assert_eq!(report, run(RamInit::Random(1234)));
+        // VRAM contents show on the display
+        assert_ne!(
+            report.framebuffer_hash,
+            run(RamInit::Random(4321)).framebuffer_hash
+        );
+        assert_ne!(
+            report.framebuffer_hash,
+            run(RamInit::Zeros).framebuffer_hash
+        );
+    }
+
+    #[test]
+    fn run_for_cycles_no_serial() {
+        let mut e = emulator(0);
+        let report = e.run_for_cycles(Emulator::FRAME_CYCLES).unwrap();
+        assert!(report.serial.is_empty());
+        assert_eq!(report.frames, 1);
+    }
+
+    #[test]
+    fn skip_bootrom() {
+        let mut emu = emulator_bootrom(false);
+        assert_eq!(emu.cpu().regs.pc, 0x0000);
+        assert_eq!(emu.bus().read(0xFF50), 0);
+
+        emu.skip_bootrom().unwrap();
+        assert_eq!(emu.cpu().regs.pc, 0x0100);
+        assert_eq!(emu.bus().read(0xFF50), 1);
+        assert_eq!(emu.cpu().regs.sp, 0xFFFE);
+        assert_eq!(emu.bus().read(0xFF47), 0xFC);
+        assert_eq!(emu.bus().read(0x0000), 0x00);
+
+        // Nothing left to skip
+        let cycles = emu.cpu().get_cycles();
+        emu.skip_bootrom().unwrap();
+        assert_eq!(emu.cpu().get_cycles(), cycles);
+    }
+
+    #[test]
+    fn skip_bootrom_hangs() {
+        let mut emu = emulator_bootrom(true);
+        assert!(emu.skip_bootrom().is_err());
+        assert!(emu.cpu().get_cycles() >= BOOTROM_MAX_CYCLES);
+        assert_eq!(emu.cpu().regs.pc, 0x0007);
+    }
+
+    #[test]
+    fn run_frame() {
+        let mut e = emulator(0);
+        assert_eq!(e.get_frame_count(), 0);
+        e.run_frame().unwrap();
+        assert_eq!(e.get_frame_count(), 1);
+        e.run_frame().unwrap();
+        assert_eq!(e.get_frame_count(), 2);
+
+        // Exactly a frame apart
+        let cycles = e.cpu().get_cycles();
+        e.run_frame().unwrap();
+        assert!((e.cpu().get_cycles() - cycles).abs_diff(Emulator::FRAME_CYCLES) < 24);
+    }
+
+    /// Headless system running the given code at 0x0100 (and 0x0038),
+    /// the rest of the ROM is filled with 'fill'
+    fn emulator_code(fill: u8, entry: &[u8], rst38: &[u8]) -> Emulator {
+        let mut rom = vec![fill; 32 * 1024];
+        rom[0x100..0x100 + entry.len()].copy_from_slice(entry);
+        rom[0x38..0x38 + rst38.len()].copy_from_slice(rst38);
+        rom[0x147..=0x149].fill(0);
+        Emulator::new_headless(cartridge::load(&rom).unwrap(), Model::Dmg)
+    }
+
+    #[test]
+    fn runaway_rst38() {
+        // Crashed into 0xFF-filled ROM
+        let mut e = emulator_code(0xFF, &[], &[]);
+        let runaway = (0..10).find_map(|_| e.run_frame().unwrap()).unwrap();
+        assert_eq!(
+            runaway,
+            Runaway {
+                pc: 0x0038,
+                opcode: 0xFF,
+                sp: 0xFFFC - 2 * RUNAWAY_STEPS as u16,
+            }
+        );
+        assert_eq!(
+            runaway.to_string(),
+            "Likely crashed: executing 0xFF at 0x0038 repeatedly, SP=F7FC"
+        );
+
+        // Reported once, emulation continues
+        let cycles = e.cpu().get_cycles();
+        assert_eq!(e.run_frame().unwrap(), None);
+        assert!(e.cpu().get_cycles() > cycles);
+    }
+
+    #[test]
+    fn runaway_legitimate_loops() {
+        for (entry, rst38) in [
+            // Wait for LY 144, forever
+            (
+                &[
+                    0xF0, 0x44, // LDH A,(44h)
+                    0xFE, 0x90, // CP 90h
+                    0x20, 0xFA, // JR NZ,-6
+                    0x18, 0xF8, // JR -8
+                ][..],
+                &[][..],
+            ),
+            // HALT (without interrupts) and JR to itself at 0x0038
+            (
+                &[0xC3, 0x38, 0x00][..], // JP 0038h
+                &[
+                    0x76, // HALT
+                    0x18, 0xFE, // JR -2
+                ][..],
+            ),
+        ] {
+            let mut e = emulator_code(0xFF, entry, rst38);
+            for _ in 0..60 {
+                assert_eq!(e.run_frame().unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_hashes() {
+        let rom = include_bytes!("../../tests/dmg-acid2/dmg-acid2.gb");
+        let run = || {
+            let (display, state) = TestDisplay::new(LCD_W, LCD_H);
+            let mut e = Emulator::new(
+                cartridge::load(rom).unwrap(),
+                None,
+                display,
+                Box::new(NullInput::new()),
+                Model::Dmg,
+                Serial::new_null(),
+            );
+            let hashes: Vec<_> = (0..30)
+                .map(|_| {
+                    e.run_frame().unwrap();
+                    hash_frame(e.get_framebuffer())
+                })
+                .collect();
+            // The same hash as the display computed for the last frame
+            assert_eq!(hashes.last(), Some(&state.get().hash));
+            hashes
+        };
+
+        let hashes = run();
+        assert_eq!(hashes, run());
+        assert_ne!(hashes.first(), hashes.last());
+    }
+
+    #[test]
+    fn run_frame_lcd_off() {
+        let mut e = emulator(0);
+        e.bus_mut().write(0xFF40, 0);
+
+        let cycles = e.cpu().get_cycles();
+        e.run_frame().unwrap();
+        assert_eq!(e.get_frame_count(), 0);
+        assert!(e.cpu().get_cycles() - cycles >= Emulator::FRAME_CYCLES);
+    }
+
+    #[test]
+    fn savestate() {
+        let mut e = emulator(0);
+        e.run_frame().unwrap();
+        let state = e.save_state();
+        let regs = e.cpu().regs.to_string();
+        let cycles = e.cpu().get_cycles();
+
+        e.run_frame().unwrap();
+        let wram = e.bus().read(0xC000);
+        assert_ne!(e.cpu().get_cycles(), cycles);
+
+        e.load_state(&state).unwrap();
+        assert_eq!(e.cpu().regs.to_string(), regs);
+        assert_eq!(e.cpu().get_cycles(), cycles);
+        assert_eq!(e.get_frame_count(), 1);
+
+        // Execution is deterministic after restore
+        e.run_frame().unwrap();
+        assert_eq!(e.bus().read(0xC000), wram);
+    }
+
+    #[test]
+    fn savestate_invalid() {
+        let mut e = emulator(0);
+        let state = e.save_state();
+        assert!(e.load_state(&state[..state.len() - 1]).is_err());
+        assert!(e.load_state(&[0; 16]).is_err());
+
+        let mut state = state;
+        state.push(0);
+        assert!(e.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn save() {
+        // MBC1+RAM+BATTERY
+        let mut e = emulator(0x03);
+        assert_eq!(e.get_save().len(), 32 * 1024);
+
+        e.load_save(&[0xAB; 16]).unwrap();
+        e.bus_mut().write(0x0000, 0x0A);
+        assert_eq!(e.bus().read(0xA000), 0xAB);
+        assert_eq!(e.bus().read(0xA010), 0x00);
+        assert_eq!(e.get_save()[0..17], [[0xAB; 16].as_slice(), &[0]].concat());
+
+        assert!(e.load_save(&[0; 64 * 1024]).is_err());
+    }
+
+    /// Audio sink that records the pushes and drains completely
+    /// between them.
+    struct MockSink {
+        capacity: usize,
+        pushes: Vec<usize>,
+    }
+
+    impl AudioSink for MockSink {
+        fn free_space(&self) -> usize {
+            self.capacity
+        }
+
+        fn push_samples(&mut self, samples: &[Sample]) {
+            assert!(samples.len() <= self.capacity);
+            assert!(samples.iter().all(|&s| s == 0));
+            self.pushes.push(samples.len());
+        }
+    }
+
+    #[test]
+    fn sync_audio_cadence() {
+        let mut e = headless();
+        let mut sink = MockSink {
+            capacity: 512 * 2,
+            pushes: vec![],
+        };
+        let mut cycles = vec![];
+        for _ in 0..100 {
+            let start = e.cpu().get_cycles();
+            e.run_synced(SyncStrategy::Audio, &mut sink).unwrap();
+            cycles.push(e.cpu().get_cycles() - start);
+        }
+
+        // An instruction takes less time than a sample, so every slice
+        // produces exactly the requested amount of samples.
+        assert!(sink.pushes.iter().all(|&p| p == 512 * 2));
+
+        // ..and runs for the same amount of emulated time, give or take
+        // an instruction.
+        let expected = (512 * CPU_CLOCK_HZ / SAMPLE_RATE) as u64;
+        assert!(cycles.iter().all(|&c| c.abs_diff(expected) < 100));
+        let total: u64 = cycles.iter().sum();
+        assert!(total.abs_diff(expected * 100) < 100);
+    }
+
+    #[test]
+    fn sync_video_audio() {
+        let mut e = headless();
+        let mut sink = MockSink {
+            capacity: 4096,
+            pushes: vec![],
+        };
+        // The first frame after power on is incomplete
+        e.run_synced(SyncStrategy::Off, &mut sink).unwrap();
+        sink.pushes.clear();
+
+        e.run_synced(SyncStrategy::Video, &mut sink).unwrap();
+        e.run_synced(SyncStrategy::Off, &mut sink).unwrap();
+        assert_eq!(sink.pushes.len(), 2);
+        let frame = Emulator::FRAME_CYCLES as usize * SAMPLE_RATE / CPU_CLOCK_HZ * 2;
+        assert!(sink.pushes.iter().all(|&p| p.abs_diff(frame) <= 2));
+
+        // Never blocks, excess samples are dropped
+        sink.capacity = 100;
+        e.run_synced(SyncStrategy::Video, &mut sink).unwrap();
+        assert_eq!(*sink.pushes.last().unwrap(), 100);
+    }
+
+    /// Writes the RTC registers of an MBC3 cartridge
+    fn write_rtc(e: &mut Emulator, regs: &[u8]) {
+        let bus = e.bus_mut();
+        for (r, &val) in regs.iter().enumerate() {
+            bus.write(0x4000, 0x08 + r as u8);
+            bus.write(0xA000, val);
+        }
+    }
+
+    /// Latches and reads the RTC registers of an MBC3 cartridge
+    fn read_rtc(e: &mut Emulator) -> Vec<u8> {
+        let bus = e.bus_mut();
+        bus.write(0x6000, 0);
+        bus.write(0x6000, 1);
+        (0x08..=0x0C)
+            .map(|r| {
+                bus.write(0x4000, r);
+                bus.read(0xA000)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rtc_emulated() {
+        let mut a = emulator(0x10);
+        let mut b = emulator(0x10);
+        // 23:59:58 on the last day before the day counter overflows
+        write_rtc(&mut a, &[58, 59, 23, 0xFF, 0x01]);
+        write_rtc(&mut b, &[58, 59, 23, 0xFF, 0x01]);
+
+        let mut reads = vec![];
+        for _ in 0..4 {
+            a.run_for_cycles(CPU_CLOCK_HZ as u64 / 2).unwrap();
+            b.run_for_cycles(CPU_CLOCK_HZ as u64 / 2).unwrap();
+            assert_eq!(a.cpu().get_cycles(), b.cpu().get_cycles());
+            let rtc = read_rtc(&mut a);
+            assert_eq!(rtc, read_rtc(&mut b));
+            reads.push(rtc);
+        }
+        assert_eq!(reads[0], [58, 59, 23, 0xFF, 0x01]);
+        assert_eq!(reads[1], [59, 59, 23, 0xFF, 0x01]);
+        assert_eq!(reads[2], [59, 59, 23, 0xFF, 0x01]);
+        // Overflow sets the carry bit
+        assert_eq!(reads[3], [0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn rtc_savestate() {
+        let mut e = emulator(0x10);
+        write_rtc(&mut e, &[10, 0, 0, 0, 0]);
+        e.run_for_cycles(CPU_CLOCK_HZ as u64 / 2).unwrap();
+        let state = e.save_state();
+
+        e.run_for_cycles(CPU_CLOCK_HZ as u64).unwrap();
+        let rtc = read_rtc(&mut e);
+        assert_eq!(rtc, [11, 0, 0, 0, 0]);
+
+        let mut e = emulator(0x10);
+        e.run_for_cycles(CPU_CLOCK_HZ as u64 / 4).unwrap();
+        e.load_state(&state).unwrap();
+        e.run_for_cycles(CPU_CLOCK_HZ as u64).unwrap();
+        assert_eq!(read_rtc(&mut e), rtc);
+    }
+
+    #[test]
+    fn rtc_host() {
+        let mock = MockClock::default();
+        mock.set(1_000_000);
+        let mut e = emulator(0x10);
+        e.set_clock(EmuClock::host_with(Box::new(mock.clone())));
+        assert!(e.bus().clock().is_host_synced());
+        e.run_for_cycles(1000).unwrap();
+        assert_eq!(read_rtc(&mut e), [0; 5]);
+
+        // One day, one hour, one minute and one second later
+        mock.set(1_000_000 + 90061);
+        e.run_for_cycles(Emulator::FRAME_CYCLES * 4).unwrap();
+        assert_eq!(read_rtc(&mut e), [1, 1, 1, 1, 0]);
+
+        // The save footer holds the time of the clock
+        let save = e.get_save();
+        assert_eq!(save[save.len() - 8..], (1_000_000u64 + 90061).to_le_bytes());
+
+        // Halted
+        write_rtc(&mut e, &[1, 1, 1, 1, 0x40]);
+        mock.set(1_000_000 + 100_000);
+        e.run_for_cycles(Emulator::FRAME_CYCLES * 4).unwrap();
+        assert_eq!(read_rtc(&mut e), [1, 1, 1, 1, 0x40]);
+    }
+}
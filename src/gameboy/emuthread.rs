@@ -0,0 +1,891 @@
+use anyhow::{anyhow, Context, Result};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::audio::audio::{AudioSink, NullAudioSink, SampleClock};
+use crate::display::display::Color;
+use crate::gameboy::bus::gbbus::Gameboybus;
+use crate::gameboy::cartridge::cartridge::{BankSelect, MissingRom};
+use crate::gameboy::cpu::cpu::CPU;
+use crate::gameboy::emulator::{self, Emulator, SyncStrategy};
+use crate::gameboy::runaway::{Runaway, RunawayDetector};
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use crate::misc::panic_message;
+
+/// Control messages to the emulation thread.
+/// Messages are handled at frame boundaries (or per instruction in
+/// single-step mode).
+pub enum Control {
+    /// Pause or resume emulation
+    Pause(bool),
+    /// Print the CPU state after each instruction
+    Verbose(bool),
+    /// Only execute an instruction on a Step message
+    SingleStep(bool),
+    /// Execute a single instruction (in single-step mode)
+    Step,
+    /// Create a savestate, which is sent back through the channel
+    SaveState(mpsc::Sender<Result<Vec<u8>>>),
+    /// Restore a savestate
+    LoadState(Vec<u8>),
+    /// Stop emulation
+    Quit,
+}
+
+/// Error returned by EmuThread::quit() when emulation stopped because
+/// of an error (or panic) while running the system.
+#[derive(Debug, Error)]
+#[error("{error:#}")]
+pub struct EmulationStopped {
+    /// Error that stopped emulation
+    pub error: anyhow::Error,
+    /// CPU state at the time of the error (see CPU::dump_state_verbose())
+    pub state: String,
+    /// Contents of cartridge RAM, if a cartridge is inserted and its
+    /// RAM was written (see Runner::get_save())
+    pub save: Option<Vec<u8>>,
+}
+
+/// A completed frame
+pub struct Frame {
+    /// Frame number (amount of frames since power on)
+    pub number: u64,
+    /// Emulated time since the thread started, in cycles at normal speed
+    pub cycles: u64,
+    /// Screen contents (row-major)
+    pub pixels: Vec<Color>,
+}
+
+/// Warnings about the running system, for the frontend to report
+/// (see EmuThread::warnings())
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// The LCD was disabled outside VBlank, which can damage a real
+    /// DMG's screen. Sent once per instruction address.
+    UnsafeLcdDisable { pc: u16 },
+    /// A bank beyond the banks declared in the cartridge header was
+    /// selected, which usually points at an emulation bug or a bad ROM
+    /// dump. Sent when emulation stops.
+    BankSelect(BankSelect),
+    /// The game read beyond the end of a trimmed ROM file. Sent when
+    /// emulation stops.
+    MissingRom(MissingRom),
+    /// The CPU ran away (see Runaway). Emulation is paused when this
+    /// is sent, resume it with Control::Pause.
+    Runaway(Runaway),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UnsafeLcdDisable { pc } => {
+                write!(f, "LCD disabled outside VBlank at PC {:04X}", pc)
+            }
+            Warning::BankSelect(select) => write!(f, "{}", select),
+            Warning::MissingRom(missing) => write!(f, "{}", missing),
+            Warning::Runaway(runaway) => write!(f, "{}", runaway),
+        }
+    }
+}
+
+/// Builds the emulated system on the emulation thread.
+pub type SystemBuilder = Box<dyn FnOnce() -> Result<CPU> + Send>;
+
+/// Capacity of the control channel; senders block when it is full.
+const CONTROL_QUEUE: usize = 16;
+
+/// Runs the emulator on a dedicated thread.
+/// The system is constructed on the emulation thread by a builder,
+/// so it does not need to be Send.
+pub struct EmuThread {
+    control: mpsc::SyncSender<Control>,
+    frames: mpsc::Receiver<Frame>,
+    warnings: mpsc::Receiver<Warning>,
+    thread: JoinHandle<Result<Option<Vec<u8>>>>,
+}
+
+impl EmuThread {
+    /// Spawns the emulation thread. If fps is set, emulation is
+    /// limited to that framerate.
+    pub fn spawn(build: SystemBuilder, fps: Option<u64>) -> Self {
+        match fps {
+            Some(fps) => Self::spawn_synced(
+                build,
+                SyncStrategy::Video,
+                fps,
+                Box::new(NullAudioSink::new()),
+            ),
+            None => Self::spawn_synced(build, SyncStrategy::Off, 0, Box::new(NullAudioSink::new())),
+        }
+    }
+
+    /// Spawns the emulation thread, paced by the specified strategy.
+    /// fps is the framerate limit in video sync mode.
+    pub fn spawn_synced(
+        build: SystemBuilder,
+        sync: SyncStrategy,
+        fps: u64,
+        audio: Box<dyn AudioSink + Send>,
+    ) -> Self {
+        let frametime =
+            (sync == SyncStrategy::Video).then(|| Duration::from_micros(1000000 / fps.max(1)));
+        let (control_tx, control_rx) = mpsc::sync_channel(CONTROL_QUEUE);
+        let (frame_tx, frame_rx) = mpsc::channel();
+        let (warning_tx, warning_rx) = mpsc::channel();
+
+        let thread = thread::Builder::new()
+            .name("emulation".to_string())
+            .spawn(move || {
+                let cpu = build()?;
+                let mut runner = Runner {
+                    cpu,
+                    control: control_rx,
+                    frames: frame_tx,
+                    warnings: warning_tx,
+                    frametime,
+                    sync,
+                    audio,
+                    sample_clock: SampleClock::new(),
+                    last_frame: Instant::now(),
+                    last_sent: 0,
+                    emulated: 0,
+                    paused: false,
+                    verbose: false,
+                    single_step: false,
+                    lcd_disables: LcdDisableWatch::default(),
+                    runaway: RunawayDetector::new(),
+                };
+                // A panic in the emulator is reported like an error, so
+                // the state is still dumped and the save is not lost.
+                // The runner is not used after this other than to read
+                // the CPU state and cartridge RAM.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| runner.run()))
+                    .unwrap_or_else(|p| Err(anyhow!("Emulator panicked: {}", panic_message(&*p))));
+                if let Some(diagnostics) = runner.get_bus().map(|b| b.cartridge().diagnostics()) {
+                    for select in diagnostics.bank_selects() {
+                        let _ = runner.warnings.send(Warning::BankSelect(select.clone()));
+                    }
+                    if let Some(missing) = diagnostics.missing_rom() {
+                        let _ = runner.warnings.send(Warning::MissingRom(missing));
+                    }
+                }
+                if let Err(error) = result {
+                    return Err(EmulationStopped {
+                        error,
+                        state: runner.cpu.dump_state_verbose(),
+                        save: runner.get_save(),
+                    }
+                    .into());
+                }
+
+                Ok(runner.get_save())
+            })
+            .expect("Failed to spawn emulation thread");
+
+        Self {
+            control: control_tx,
+            frames: frame_rx,
+            warnings: warning_rx,
+            thread,
+        }
+    }
+
+    /// Sends a control message to the emulation thread.
+    /// Blocks if the control queue is full.
+    pub fn send(&self, msg: Control) -> Result<()> {
+        self.control
+            .send(msg)
+            .map_err(|_| anyhow!("Emulation thread stopped"))
+    }
+
+    /// Receiver for completed frames
+    pub fn frames(&self) -> &mpsc::Receiver<Frame> {
+        &self.frames
+    }
+
+    /// Receiver for warnings about the running system. The channel is
+    /// closed when the emulation thread exits.
+    pub fn warnings(&self) -> &mpsc::Receiver<Warning> {
+        &self.warnings
+    }
+
+    /// Creates a savestate of the running system
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Control::SaveState(tx))?;
+        rx.recv().context("Emulation thread stopped")?
+    }
+
+    /// Returns true if the emulation thread stopped (e.g. because of an
+    /// error). Call quit() to retrieve the result.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Stops emulation and waits for the emulation thread to exit.
+    /// Returns the contents of cartridge RAM, if a cartridge is
+    /// inserted and its RAM was written (an untouched save on disk is
+    /// not overwritten). If emulation stopped because of an error, the
+    /// error can be downcast to EmulationStopped.
+    pub fn quit(self) -> Result<Option<Vec<u8>>> {
+        // Fails if the thread already exited, which join() will report.
+        let _ = self.control.send(Control::Quit);
+        match self.thread.join() {
+            Ok(r) => r,
+            Err(_) => Err(anyhow!("Emulation thread panicked")),
+        }
+    }
+}
+
+/// State of the emulation thread
+struct Runner {
+    cpu: CPU,
+    control: mpsc::Receiver<Control>,
+    frames: mpsc::Sender<Frame>,
+    warnings: mpsc::Sender<Warning>,
+    frametime: Option<Duration>,
+    last_frame: Instant,
+    sync: SyncStrategy,
+    audio: Box<dyn AudioSink + Send>,
+    sample_clock: SampleClock,
+    /// Number of the last frame sent out
+    last_sent: u64,
+    /// Emulated time, in cycles at normal speed
+    emulated: u64,
+    paused: bool,
+    verbose: bool,
+    single_step: bool,
+    lcd_disables: LcdDisableWatch,
+    runaway: RunawayDetector,
+}
+
+/// Watches for the LCD being disabled outside VBlank
+#[derive(Default)]
+struct LcdDisableWatch {
+    /// Last seen count (see LCDController::get_unsafe_disables())
+    count: u64,
+    /// Instruction addresses already warned about
+    reported: HashSet<u16>,
+}
+
+impl LcdDisableWatch {
+    /// Checks the instruction at 'pc' that was just executed
+    fn observe(&mut self, pc: u16, cpu: &CPU) -> Option<Warning> {
+        let count =
+            Gameboybus::from_bus(&*cpu.bus).map_or(0, |b| b.get_lcd().get_unsafe_disables());
+        if count == self.count {
+            return None;
+        }
+        self.count = count;
+        self.reported
+            .insert(pc)
+            .then_some(Warning::UnsafeLcdDisable { pc })
+    }
+}
+
+impl Runner {
+    fn run(&mut self) -> Result<()> {
+        loop {
+            // Block for control messages while not running freely,
+            // otherwise handle pending messages before the next frame.
+            let msg = if self.paused || self.single_step {
+                match self.control.recv() {
+                    Ok(m) => Some(m),
+                    Err(_) => return Ok(()),
+                }
+            } else {
+                match self.control.try_recv() {
+                    Ok(m) => Some(m),
+                    Err(mpsc::TryRecvError::Empty) => None,
+                    Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                }
+            };
+
+            match msg {
+                Some(Control::Quit) => return Ok(()),
+                Some(Control::Pause(p)) => self.paused = p,
+                Some(Control::Verbose(v)) => self.verbose = v,
+                Some(Control::SingleStep(s)) => self.single_step = s,
+                Some(Control::Step) => {
+                    if !self.paused {
+                        self.step()?;
+                        self.check_frame();
+                    }
+                }
+                Some(Control::SaveState(reply)) => {
+                    // Requester may have given up waiting
+                    let _ = reply.send(self.save_state());
+                }
+                Some(Control::LoadState(state)) => self.load_state(&state)?,
+                None => {
+                    self.wait_for_link();
+                    if self.sync == SyncStrategy::Audio {
+                        self.run_audio()?
+                    } else {
+                        self.run_frame()?
+                    }
+                }
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<usize> {
+        let pc = self.cpu.regs.pc;
+        let cycles = Self::step_cpu(&mut self.cpu, self.verbose, &mut self.emulated)?;
+        if let Some(warning) = self.lcd_disables.observe(pc, &self.cpu) {
+            // Receiver may be gone during shutdown
+            let _ = self.warnings.send(warning);
+        }
+        if let Some(runaway) = self.runaway.observe(pc, &self.cpu) {
+            self.stop_runaway(runaway);
+        }
+        Ok(cycles)
+    }
+
+    /// Pauses emulation and reports a runaway CPU to the frontend
+    fn stop_runaway(&mut self, runaway: Runaway) {
+        self.paused = true;
+        // Receiver may be gone during shutdown
+        let _ = self.warnings.send(Warning::Runaway(runaway));
+    }
+
+    fn step_cpu(cpu: &mut CPU, verbose: bool, emulated: &mut u64) -> Result<usize> {
+        if verbose {
+            eprint!("{}", cpu.dump_state_verbose());
+        }
+        let cycles = cpu.step()?;
+        *emulated += if cpu.is_double_speed() {
+            cycles / 2
+        } else {
+            cycles
+        } as u64;
+        Ok(cycles)
+    }
+
+    fn get_bus(&self) -> Option<&Gameboybus> {
+        Gameboybus::from_bus(&*self.cpu.bus)
+    }
+
+    /// Contents of cartridge RAM, if the cartridge RAM was written
+    /// since it was loaded
+    fn get_save(&self) -> Option<Vec<u8>> {
+        self.get_bus()
+            .map(|b| b.cartridge())
+            .filter(|c| c.ram_written())
+            .map(|c| c.get_save())
+    }
+
+    /// Back-pressure from a lockstep link cable partner
+    fn wait_for_link(&mut self) {
+        if let Some(bus) = Gameboybus::from_bus_mut(&mut *self.cpu.bus) {
+            bus.serial_mut().wait_for_link();
+        }
+    }
+
+    fn frame_count(&self) -> u64 {
+        self.get_bus().map_or(0, |b| b.get_lcd().get_frame_count())
+    }
+
+    /// Sends out the current frame, if a new frame was completed
+    fn check_frame(&mut self) {
+        let Some(bus) = self.get_bus() else {
+            return;
+        };
+        let lcd = bus.get_lcd();
+        let number = lcd.get_frame_count();
+        if number == self.last_sent {
+            return;
+        }
+
+        // Receiver may be gone during shutdown
+        let _ = self.frames.send(Frame {
+            number,
+            cycles: self.emulated,
+            pixels: lcd.get_framebuffer().to_vec(),
+        });
+        self.last_sent = number;
+    }
+
+    /// Runs until the next frame has been completed. If there is no
+    /// LCD or it is disabled, runs for the duration of a frame.
+    fn run_frame(&mut self) -> Result<()> {
+        let frame = self.frame_count();
+        let max_cycles = if self.cpu.is_double_speed() {
+            Emulator::FRAME_CYCLES as usize * 2
+        } else {
+            Emulator::FRAME_CYCLES as usize
+        };
+        let mut cycles = 0;
+
+        while self.frame_count() == frame && cycles < max_cycles && !self.paused {
+            cycles += self.step()?;
+        }
+        self.check_frame();
+        emulator::feed_audio(
+            &mut self.sample_clock,
+            self.audio.as_mut(),
+            cycles,
+            self.cpu.is_double_speed(),
+        );
+
+        // Limit the framerate
+        if let Some(frametime) = self.frametime {
+            let elapsed = self.last_frame.elapsed();
+            if elapsed < frametime {
+                sleep(frametime - elapsed);
+            }
+            self.last_frame = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Runs until the audio sink is filled, which blocks until the
+    /// sink has room.
+    fn run_audio(&mut self) -> Result<()> {
+        let cpu = &mut self.cpu;
+        let verbose = self.verbose;
+        let emulated = &mut self.emulated;
+        let lcd_disables = &mut self.lcd_disables;
+        let warnings = &self.warnings;
+        let detector = &mut self.runaway;
+        let mut runaway = None;
+        emulator::run_audio_slice(&mut self.sample_clock, self.audio.as_mut(), || {
+            let pc = cpu.regs.pc;
+            let cycles = Self::step_cpu(cpu, verbose, emulated)?;
+            if let Some(warning) = lcd_disables.observe(pc, cpu) {
+                let _ = warnings.send(warning);
+            }
+            runaway = runaway.or(detector.observe(pc, cpu));
+            Ok((cycles, cpu.is_double_speed()))
+        })?;
+        if let Some(runaway) = runaway {
+            self.stop_runaway(runaway);
+        }
+        self.check_frame();
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        let bus = self
+            .get_bus()
+            .context("Savestates are not supported on this bus")?;
+        let mut w = StateWriter::new();
+        self.cpu.save_state(&mut w);
+        bus.save_state(&mut w);
+        Ok(w.into_vec())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut r = StateReader::new(state)?;
+        self.cpu.load_state(&mut r)?;
+        Gameboybus::from_bus_mut(&mut *self.cpu.bus)
+            .context("Savestates are not supported on this bus")?
+            .load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::audio::{RealtimeSink, SAMPLE_RATE};
+    use crate::display::display::NullDisplay;
+    use crate::gameboy::bus::testbus::Testbus;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::cpu::cpu::CPUError;
+    use crate::gameboy::lcd::{LCDController, LCD_H, LCD_W};
+    use crate::gameboy::model::Model;
+    use crate::input::input::NullInput;
+
+    fn builder() -> SystemBuilder {
+        Box::new(|| {
+            let cart = cartridge::load(include_bytes!("../../tests/dmg-acid2/dmg-acid2.gb"))?;
+            let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+            let bus = Box::new(Gameboybus::new(
+                cart,
+                None,
+                lcd,
+                Box::new(NullInput::new()),
+                Model::Dmg,
+            ));
+            Ok(CPU::new(bus, Model::Dmg))
+        })
+    }
+
+    /// Runs a closure on a separate thread, failing if it does not
+    /// complete in time (deadlock).
+    fn with_timeout(secs: u64, f: impl FnOnce() + Send + 'static) {
+        let (tx, rx) = mpsc::channel();
+        let t = thread::spawn(move || {
+            f();
+            tx.send(()).unwrap();
+        });
+        if rx.recv_timeout(Duration::from_secs(secs)).is_err() {
+            if t.is_finished() {
+                // Propagate the panic
+                t.join().unwrap();
+            }
+            panic!("Timeout (deadlock?)");
+        }
+    }
+
+    #[test]
+    fn frames() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(builder(), None);
+            let mut last = 0;
+            while last < 10 {
+                let frame = emu.frames().recv().unwrap();
+                assert_eq!(frame.pixels.len(), LCD_W * LCD_H);
+                assert!(frame.number > last);
+                last = frame.number;
+            }
+            // ROM only, no cartridge RAM to save
+            assert!(emu.quit().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn sync_audio() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn_synced(
+                builder(),
+                SyncStrategy::Audio,
+                0,
+                Box::new(RealtimeSink::new(SAMPLE_RATE / 20 * 2)),
+            );
+            // Paced by the sink, so about 60 frames per second
+            let start = Instant::now();
+            let first = emu.frames().recv().unwrap().number;
+            let mut last = first;
+            while last < first + 12 {
+                last = emu.frames().recv().unwrap().number;
+            }
+            assert!(start.elapsed() >= Duration::from_millis(100));
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn pause() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(builder(), None);
+            emu.frames().recv().unwrap();
+
+            emu.send(Control::Pause(true)).unwrap();
+            // Synchronize; the pause has been handled after this.
+            let state = emu.save_state().unwrap();
+            while emu.frames().try_recv().is_ok() {}
+            thread::sleep(Duration::from_millis(50));
+            assert!(emu.frames().try_recv().is_err());
+            assert_eq!(emu.save_state().unwrap(), state);
+
+            emu.send(Control::Pause(false)).unwrap();
+            emu.frames().recv().unwrap();
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn single_step() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(builder(), None);
+            emu.send(Control::SingleStep(true)).unwrap();
+            let state = emu.save_state().unwrap();
+            assert_eq!(emu.save_state().unwrap(), state);
+            emu.send(Control::Step).unwrap();
+            assert_ne!(emu.save_state().unwrap(), state);
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn savestate() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(builder(), None);
+            emu.frames().recv().unwrap();
+            emu.send(Control::Pause(true)).unwrap();
+            let state = emu.save_state().unwrap();
+
+            emu.send(Control::Pause(false)).unwrap();
+            emu.frames().recv().unwrap();
+            emu.send(Control::Pause(true)).unwrap();
+            assert_ne!(emu.save_state().unwrap(), state);
+
+            emu.send(Control::LoadState(state.clone())).unwrap();
+            assert_eq!(emu.save_state().unwrap(), state);
+
+            // Invalid savestate stops emulation
+            emu.send(Control::LoadState(vec![0; 10])).unwrap();
+            assert!(emu.quit().is_err());
+        });
+    }
+
+    #[test]
+    fn error() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(Box::new(|| Err(anyhow!("Build failed"))), None);
+            assert!(emu.quit().is_err());
+        });
+    }
+
+    #[test]
+    fn unsafe_lcd_disable() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(
+                Box::new(|| {
+                    let mut rom = vec![0; 32 * 1024];
+                    rom[0x100..0x109].copy_from_slice(&[
+                        0xAF, // loop: XOR A
+                        0xE0, 0x40, // LDH (LCDC), A
+                        0x3E, 0x91, // LD A, 0x91
+                        0xE0, 0x40, // LDH (LCDC), A
+                        0x18, 0xF7, // JR loop
+                    ]);
+                    let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+                    let bus = Box::new(Gameboybus::new(
+                        cartridge::load(&rom)?,
+                        None,
+                        lcd,
+                        Box::new(NullInput::new()),
+                        Model::Dmg,
+                    ));
+                    Ok(CPU::new(bus, Model::Dmg))
+                }),
+                None,
+            );
+            assert_eq!(
+                emu.warnings().recv().unwrap(),
+                Warning::UnsafeLcdDisable { pc: 0x0101 }
+            );
+            // Once per address
+            assert!(emu
+                .warnings()
+                .recv_timeout(Duration::from_millis(100))
+                .is_err());
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn runaway() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(
+                Box::new(|| {
+                    let bus = Testbus::from_segments(&[(0x0000, &[0xFF; 0x100])]);
+                    let mut cpu = CPU::new(Box::new(bus), Model::Dmg);
+                    cpu.regs.pc = 0x0038;
+                    cpu.regs.sp = 0xFFFE;
+                    Ok(cpu)
+                }),
+                None,
+            );
+            let Warning::Runaway(runaway) = emu.warnings().recv().unwrap() else {
+                panic!("Expected a runaway warning");
+            };
+            assert_eq!(runaway.pc, 0x0038);
+            assert_eq!(runaway.opcode, 0xFF);
+
+            // Paused, the warning is sent once
+            assert!(emu
+                .warnings()
+                .recv_timeout(Duration::from_millis(100))
+                .is_err());
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn bank_select_warning() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(
+                Box::new(|| {
+                    // MBC1, 32 KB ROM
+                    let mut rom = vec![0; 32 * 1024];
+                    rom[0x147] = 0x01;
+                    rom[0x100..0x107].copy_from_slice(&[
+                        0x3E, 0x05, // LD A, 0x05
+                        0xEA, 0x00, 0x20, // LD (0x2000), A
+                        0x18, 0xFE, // JR -2
+                    ]);
+                    let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+                    let bus = Box::new(Gameboybus::new(
+                        cartridge::load(&rom)?,
+                        None,
+                        lcd,
+                        Box::new(NullInput::new()),
+                        Model::Dmg,
+                    ));
+                    Ok(CPU::new(bus, Model::Dmg))
+                }),
+                None,
+            );
+            thread::sleep(Duration::from_millis(10));
+            emu.send(Control::Quit).unwrap();
+            let warnings: Vec<_> = emu.warnings().iter().collect();
+            assert!(matches!(
+                warnings.as_slice(),
+                [Warning::BankSelect(BankSelect {
+                    requested: 5,
+                    count: 1,
+                    ..
+                })]
+            ));
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(
+                Box::new(|| {
+                    let bus = Testbus::from([0x00, 0xD3].as_slice());
+                    let mut cpu = CPU::new(Box::new(bus), Model::Dmg);
+                    cpu.regs.pc = 0;
+                    Ok(cpu)
+                }),
+                None,
+            );
+            while !emu.is_finished() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let e = emu.quit().unwrap_err();
+            let stopped = e.downcast::<EmulationStopped>().unwrap();
+            assert_eq!(
+                stopped.error.downcast_ref::<CPUError>(),
+                Some(&CPUError::InvalidOpcode {
+                    opcode: 0xD3,
+                    pc: 0x0001
+                })
+            );
+            assert!(stopped.state.contains("INVALID"));
+            assert_eq!(stopped.to_string(), "Invalid opcode D3 @ PC 0001");
+            // Not a Gameboybus, so no cartridge
+            assert!(stopped.save.is_none());
+        });
+    }
+
+    #[test]
+    fn panic() {
+        with_timeout(30, || {
+            crate::test::without_panic_output(|| {
+                let emu = EmuThread::spawn(
+                    Box::new(|| {
+                        // NOP, LD ($C000),A to a read-only address
+                        let mut bus = Testbus::from([0x00, 0xEA, 0x00, 0xC0].as_slice());
+                        bus.set_read_only(0xC000..=0xC000);
+                        bus.set_panic_on_violation(true);
+                        let mut cpu = CPU::new(Box::new(bus), Model::Dmg);
+                        cpu.regs.pc = 0;
+                        Ok(cpu)
+                    }),
+                    None,
+                );
+                while !emu.is_finished() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                let e = emu.quit().unwrap_err();
+                let stopped = e.downcast::<EmulationStopped>().unwrap();
+                assert!(stopped.to_string().starts_with("Emulator panicked: "));
+                assert!(stopped.state.contains("Recent PCs: 0000 0001"));
+            });
+        });
+    }
+
+    #[test]
+    fn stress_control() {
+        with_timeout(60, || {
+            let emu = EmuThread::spawn(builder(), Some(1000));
+            let mut frames = 0;
+            let mut state = None;
+
+            for i in 0..5000 {
+                let msg = match i % 7 {
+                    0 => Control::Pause(true),
+                    1 => Control::Pause(false),
+                    2 => Control::Verbose(false),
+                    3 => Control::SingleStep(i % 2 == 0),
+                    4 => Control::Step,
+                    5 => match state.take() {
+                        Some(s) => Control::LoadState(s),
+                        None => Control::Step,
+                    },
+                    _ => Control::SingleStep(false),
+                };
+                emu.send(msg).unwrap();
+                if i % 100 == 0 {
+                    state = Some(emu.save_state().unwrap());
+                }
+                while emu.frames().try_recv().is_ok() {
+                    frames += 1;
+                }
+            }
+
+            // Frames are still produced after the storm
+            emu.send(Control::Pause(false)).unwrap();
+            emu.send(Control::SingleStep(false)).unwrap();
+            emu.frames().recv().unwrap();
+            frames += 1;
+            assert!(frames > 0);
+
+            emu.quit().unwrap();
+        });
+    }
+
+    #[test]
+    fn quit_with_pending_frames() {
+        with_timeout(30, || {
+            let emu = EmuThread::spawn(builder(), None);
+            // Never drain frames
+            thread::sleep(Duration::from_millis(100));
+            emu.quit().unwrap();
+        });
+    }
+
+    /// ROM+RAM+BATTERY cartridge running 'code' at 0x0100, with a save
+    fn ram_builder(code: &'static [u8]) -> SystemBuilder {
+        Box::new(move || {
+            let mut rom = vec![0; 32 * 1024];
+            rom[cartridge::CARTTYPE_OFFSET] = 0x09;
+            rom[0x100..0x100 + code.len()].copy_from_slice(code);
+            let cart = cartridge::load_with_save(&rom, &[0xAA; 8 * 1024])?;
+            let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+            let bus = Box::new(Gameboybus::new(
+                cart,
+                None,
+                lcd,
+                Box::new(NullInput::new()),
+                Model::Dmg,
+            ));
+            let mut cpu = CPU::new(bus, Model::Dmg);
+            cpu.regs.pc = 0x100;
+            Ok(cpu)
+        })
+    }
+
+    #[test]
+    fn save_only_if_written() {
+        with_timeout(30, || {
+            // JR -2, never touches RAM: the save on disk is kept
+            let emu = EmuThread::spawn(ram_builder(&[0x18, 0xFE]), None);
+            emu.frames().recv().unwrap();
+            assert!(emu.quit().unwrap().is_none());
+
+            // LD A,$12; LD ($A000),A; JR -2
+            let code = &[0x3E, 0x12, 0xEA, 0x00, 0xA0, 0x18, 0xFE];
+            let emu = EmuThread::spawn(ram_builder(code), None);
+            emu.frames().recv().unwrap();
+            let save = emu.quit().unwrap().unwrap();
+            assert_eq!(save.len(), 8 * 1024);
+            assert_eq!(save[0], 0x12);
+            assert_eq!(save[1], 0xAA);
+        });
+    }
+}
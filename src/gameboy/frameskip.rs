@@ -0,0 +1,249 @@
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+
+use core::time::Duration;
+use std::fmt;
+
+/// Cycles per frame at normal speed (see Emulator::FRAME_CYCLES)
+const FRAME_CYCLES: usize = 70224;
+
+/// Maximum amount of frames skipped after a presented frame
+pub const MAX_FRAMESKIP: usize = 9;
+
+/// Default minimum emulation speed in auto mode (1.0 is real time)
+pub const DEFAULT_MIN_SPEED: f64 = 1.0;
+
+/// Frame skipping mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameSkipMode {
+    /// Skip as many frames as needed to keep up the minimum speed
+    Auto,
+    /// Always skip this amount of frames after a presented frame
+    Fixed(usize),
+}
+
+impl FrameSkipMode {
+    /// Parses 'auto' or an amount of frames (0-9)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        match s.parse::<usize>() {
+            Ok(n) if n <= MAX_FRAMESKIP => Ok(Self::Fixed(n)),
+            _ => Err(format!(
+                "Invalid frame skip '{}' (expected auto or 0-{})",
+                s, MAX_FRAMESKIP
+            )),
+        }
+    }
+}
+
+impl Default for FrameSkipMode {
+    fn default() -> Self {
+        Self::Fixed(0)
+    }
+}
+
+impl fmt::Display for FrameSkipMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Fixed(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Decides which emulated frames are presented on the display. Skipped
+/// frames are still fully emulated, only presenting them (which can be
+/// slow, e.g. on a terminal over SSH) is left out.
+///
+/// In auto mode, the time it took to present a frame decides how many
+/// frames are skipped after it: presenting may take up to the time of
+/// a frame at the minimum speed, per frame. A longer presentation
+/// raises the skip immediately, a shorter one lowers it by one frame at
+/// a time to not oscillate. The presentation time is measured by the
+/// frontend and reported through rendered().
+pub struct FrameSkip {
+    mode: FrameSkipMode,
+
+    /// Time available for presenting per frame
+    budget: Duration,
+
+    /// Frames to skip after each presented frame
+    skip: usize,
+
+    /// Frames left to skip before the next presented frame
+    pending: usize,
+
+    /// Total amount of skipped frames
+    skipped: u64,
+}
+
+impl FrameSkip {
+    pub fn new(mode: FrameSkipMode) -> Self {
+        Self::with_min_speed(mode, DEFAULT_MIN_SPEED)
+    }
+
+    /// Auto mode targets the given minimum emulation speed (relative
+    /// to real hardware)
+    pub fn with_min_speed(mode: FrameSkipMode, min_speed: f64) -> Self {
+        let frame_time = FRAME_CYCLES as f64 / CPU_CLOCK_HZ as f64;
+        Self {
+            mode,
+            budget: Duration::from_secs_f64(frame_time / min_speed.max(0.01)),
+            skip: match mode {
+                FrameSkipMode::Auto => 0,
+                FrameSkipMode::Fixed(n) => n.min(MAX_FRAMESKIP),
+            },
+            pending: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Called for every emulated frame, returns whether to present it
+    pub fn present(&mut self) -> bool {
+        if self.pending > 0 {
+            self.pending -= 1;
+            self.skipped += 1;
+            false
+        } else {
+            self.pending = self.skip;
+            true
+        }
+    }
+
+    /// Reports how long presenting the last presented frame took
+    pub fn rendered(&mut self, duration: Duration) {
+        if self.mode != FrameSkipMode::Auto {
+            return;
+        }
+
+        // Frames the presentation overran the budget by
+        let needed = (duration.as_secs_f64() / self.budget.as_secs_f64()).ceil() as usize;
+        let needed = needed.saturating_sub(1).min(MAX_FRAMESKIP);
+        if needed > self.skip {
+            self.skip = needed;
+        } else if needed < self.skip {
+            self.skip -= 1;
+        }
+        self.pending = self.skip;
+    }
+
+    pub fn mode(&self) -> FrameSkipMode {
+        self.mode
+    }
+
+    /// Frames currently skipped after each presented frame
+    pub fn current(&self) -> usize {
+        self.skip
+    }
+
+    /// Total amount of skipped frames
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+impl Default for FrameSkip {
+    fn default() -> Self {
+        Self::new(FrameSkipMode::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Presents frames, rendering each presented frame in the given
+    /// time, and returns which frames were presented
+    fn run(fs: &mut FrameSkip, render_ms: &[u64]) -> Vec<bool> {
+        render_ms
+            .iter()
+            .map(|&ms| {
+                let present = fs.present();
+                if present {
+                    fs.rendered(Duration::from_millis(ms));
+                }
+                present
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(FrameSkipMode::parse("auto"), Ok(FrameSkipMode::Auto));
+        assert_eq!(FrameSkipMode::parse("0"), Ok(FrameSkipMode::Fixed(0)));
+        assert_eq!(FrameSkipMode::parse("9"), Ok(FrameSkipMode::Fixed(9)));
+        assert!(FrameSkipMode::parse("10").is_err());
+        assert!(FrameSkipMode::parse("-1").is_err());
+        assert!(FrameSkipMode::parse("x").is_err());
+        for mode in [FrameSkipMode::Auto, FrameSkipMode::Fixed(3)] {
+            assert_eq!(FrameSkipMode::parse(&mode.to_string()), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let mut fs = FrameSkip::default();
+        assert!(run(&mut fs, &[100; 10]).iter().all(|&p| p));
+        assert_eq!(fs.skipped(), 0);
+    }
+
+    #[test]
+    fn fixed() {
+        let mut fs = FrameSkip::new(FrameSkipMode::Fixed(2));
+        assert_eq!(
+            run(&mut fs, &[1; 7]),
+            [true, false, false, true, false, false, true]
+        );
+        assert_eq!(fs.skipped(), 4);
+        assert_eq!(fs.current(), 2);
+    }
+
+    #[test]
+    fn auto_fast() {
+        let mut fs = FrameSkip::new(FrameSkipMode::Auto);
+        assert!(run(&mut fs, &[10; 20]).iter().all(|&p| p));
+        assert_eq!(fs.current(), 0);
+        assert_eq!(fs.skipped(), 0);
+    }
+
+    #[test]
+    fn auto_slow() {
+        let mut fs = FrameSkip::new(FrameSkipMode::Auto);
+        // 40ms covers 3 frames (16.7ms each), skip 2 after each
+        assert_eq!(
+            run(&mut fs, &[40; 7]),
+            [true, false, false, true, false, false, true]
+        );
+        assert_eq!(fs.current(), 2);
+        assert_eq!(fs.skipped(), 4);
+
+        // Very slow presentation is capped
+        run(&mut fs, &[1000; 20]);
+        assert_eq!(fs.current(), MAX_FRAMESKIP);
+    }
+
+    #[test]
+    fn auto_recover() {
+        let mut fs = FrameSkip::new(FrameSkipMode::Auto);
+        run(&mut fs, &[60]);
+        assert_eq!(fs.current(), 3);
+
+        // Lowered one frame per presented frame
+        let presented = run(&mut fs, &[10; 10]);
+        assert_eq!(
+            presented,
+            [false, false, false, true, false, false, true, false, true, true]
+        );
+        assert_eq!(fs.current(), 0);
+    }
+
+    #[test]
+    fn auto_min_speed() {
+        // At half speed, 30ms fits in the budget of a frame
+        let mut fs = FrameSkip::with_min_speed(FrameSkipMode::Auto, 0.5);
+        assert!(run(&mut fs, &[30; 10]).iter().all(|&p| p));
+        run(&mut fs, &[40]);
+        assert_eq!(fs.current(), 1);
+    }
+}
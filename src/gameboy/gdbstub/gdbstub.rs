@@ -0,0 +1,281 @@
+//! Minimal GDB remote serial protocol server
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+
+use anyhow::{Context, Result};
+
+use super::packet::{self, Event, PacketParser};
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::cpu::regs::Register;
+use crate::gameboy::debugger::{Breakpoint, Debugger};
+use crate::gameboy::emulator::Emulator;
+
+/// Register layout as exposed to GDB: 16-bit, little endian
+const REGISTERS: [Register; 6] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::SP,
+    Register::PC,
+];
+
+/// Stop reasons (signal numbers)
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Maximum packet size we accept, reported through qSupported
+const PACKET_SIZE: usize = 0x1000;
+
+/// Result of handling a command
+enum Reply {
+    Packet(Vec<u8>),
+    /// Client detached or killed the session
+    Close,
+}
+
+/// GDB stub serving a single client
+pub struct GdbStub {
+    stream: TcpStream,
+    parser: PacketParser,
+
+    /// Last packet sent, for retransmission
+    last_sent: Vec<u8>,
+}
+
+impl GdbStub {
+    /// Waits for a client to connect on localhost
+    pub fn listen(port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .with_context(|| format!("Cannot listen on port {}", port))?;
+        let (stream, _) = listener.accept()?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            parser: PacketParser::new(),
+            last_sent: vec![],
+        }
+    }
+
+    /// Serves the client until it detaches or disconnects
+    pub fn serve(&mut self, emu: &mut Emulator, dbg: &mut Debugger) -> Result<()> {
+        self.stream.set_nonblocking(false)?;
+        let mut buf = [0; PACKET_SIZE];
+        loop {
+            let len = match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for &b in &buf[..len] {
+                let Some(event) = self.parser.feed(b) else {
+                    continue;
+                };
+                match event {
+                    Event::Packet(data) => {
+                        self.stream.write_all(b"+")?;
+                        match self.handle(emu, dbg, &data)? {
+                            Reply::Packet(reply) => self.send(&reply)?,
+                            Reply::Close => return Ok(()),
+                        }
+                    }
+                    Event::BadChecksum => self.stream.write_all(b"-")?,
+                    Event::Nack => {
+                        let last = self.last_sent.clone();
+                        self.stream.write_all(&last)?;
+                    }
+                    // Interrupts only matter while running
+                    Event::Ack | Event::Interrupt => (),
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.last_sent = packet::encode(data);
+        self.stream.write_all(&self.last_sent)?;
+        Ok(())
+    }
+
+    /// Checks (without blocking) whether the client requested an interrupt
+    fn interrupted(&mut self) -> Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; PACKET_SIZE];
+        let result = match self.stream.read(&mut buf) {
+            Ok(len) => Ok(buf[..len]
+                .iter()
+                .any(|&b| self.parser.feed(b) == Some(Event::Interrupt))),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    /// Runs until a breakpoint is hit or the client interrupts,
+    /// returns the stop signal.
+    fn resume(&mut self, emu: &mut Emulator, dbg: &Debugger) -> Result<u8> {
+        loop {
+            match dbg.run(emu, Emulator::FRAME_CYCLES) {
+                Ok(true) => return Ok(SIGTRAP),
+                Ok(false) => (),
+                Err(_) => return Ok(SIGILL),
+            }
+            if self.interrupted()? {
+                return Ok(SIGINT);
+            }
+        }
+    }
+
+    fn handle(&mut self, emu: &mut Emulator, dbg: &mut Debugger, data: &[u8]) -> Result<Reply> {
+        let Some((&cmd, args)) = data.split_first() else {
+            return Ok(Reply::Packet(vec![]));
+        };
+        let stop = |sig: u8| Ok(Reply::Packet(format!("S{:02x}", sig).into_bytes()));
+
+        match cmd {
+            b'?' => stop(SIGTRAP),
+            b'q' if args.starts_with(b"Supported") => Ok(Reply::Packet(
+                format!("PacketSize={:x}", PACKET_SIZE).into_bytes(),
+            )),
+            b'q' if args == b"Attached" => Ok(Reply::Packet(b"1".to_vec())),
+            b'H' => Ok(Reply::Packet(b"OK".to_vec())),
+            b'g' => {
+                let regs: Vec<u8> = REGISTERS
+                    .iter()
+                    .flat_map(|&r| emu.cpu().regs.read(r).to_le_bytes())
+                    .collect();
+                Ok(Reply::Packet(packet::to_hex(&regs).into_bytes()))
+            }
+            b'G' => {
+                let regs = packet::from_hex(args).context("Invalid register data")?;
+                if regs.len() != REGISTERS.len() * 2 {
+                    return Ok(error(1));
+                }
+                for (&r, val) in REGISTERS.iter().zip(regs.chunks(2)) {
+                    emu.cpu_mut()
+                        .regs
+                        .write(r, u16::from_le_bytes([val[0], val[1]]))?;
+                }
+                Ok(ok())
+            }
+            b'p' => {
+                let Some(&r) = parse_hex(args).and_then(|n| REGISTERS.get(n)) else {
+                    return Ok(error(1));
+                };
+                let val = emu.cpu().regs.read(r).to_le_bytes();
+                Ok(Reply::Packet(packet::to_hex(&val).into_bytes()))
+            }
+            b'P' => {
+                let Some((n, val)) = split(args, b'=') else {
+                    return Ok(error(1));
+                };
+                let (Some(&r), Some(val)) = (
+                    parse_hex(n).and_then(|n| REGISTERS.get(n)),
+                    packet::from_hex(val).filter(|v| v.len() == 2),
+                ) else {
+                    return Ok(error(1));
+                };
+                emu.cpu_mut()
+                    .regs
+                    .write(r, u16::from_le_bytes([val[0], val[1]]))?;
+                Ok(ok())
+            }
+            b'm' => {
+                let Some((addr, len)) = parse_range(args) else {
+                    return Ok(error(1));
+                };
+                let mem: Vec<u8> = (0..len)
+                    .map(|i| emu.cpu().peek(addr.wrapping_add(i as u16)))
+                    .collect();
+                Ok(Reply::Packet(packet::to_hex(&mem).into_bytes()))
+            }
+            b'M' => {
+                let Some((range, mem)) = split(args, b':') else {
+                    return Ok(error(1));
+                };
+                let (Some((addr, len)), Some(mem)) = (parse_range(range), packet::from_hex(mem))
+                else {
+                    return Ok(error(1));
+                };
+                if mem.len() != len {
+                    return Ok(error(1));
+                }
+                dbg.write_memory(emu, addr, &mem);
+                Ok(ok())
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let Some(addr) = parse_hex(args) else {
+                        return Ok(error(1));
+                    };
+                    emu.cpu_mut().regs.pc = addr as u16;
+                }
+                if cmd == b's' {
+                    match emu.step() {
+                        Ok(_) => stop(SIGTRAP),
+                        Err(_) => stop(SIGILL),
+                    }
+                } else {
+                    stop(self.resume(emu, dbg)?)
+                }
+            }
+            b'Z' | b'z' => {
+                // Only software breakpoints (type 0) are supported
+                let Some(bp) = args.strip_prefix(b"0,") else {
+                    return Ok(Reply::Packet(vec![]));
+                };
+                let Some((addr, _kind)) = parse_range(bp) else {
+                    return Ok(error(1));
+                };
+                if cmd == b'Z' {
+                    dbg.set_breakpoint(Breakpoint::new(addr));
+                } else {
+                    dbg.clear_breakpoint(Breakpoint::new(addr));
+                }
+                Ok(ok())
+            }
+            b'D' => {
+                self.send(b"OK")?;
+                Ok(Reply::Close)
+            }
+            b'k' => Ok(Reply::Close),
+            // Unsupported
+            _ => Ok(Reply::Packet(vec![])),
+        }
+    }
+}
+
+fn ok() -> Reply {
+    Reply::Packet(b"OK".to_vec())
+}
+
+fn error(code: u8) -> Reply {
+    Reply::Packet(format!("E{:02x}", code).into_bytes())
+}
+
+fn split(data: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let pos = data.iter().position(|&b| b == sep)?;
+    Some((&data[..pos], &data[pos + 1..]))
+}
+
+fn parse_hex(data: &[u8]) -> Option<usize> {
+    usize::from_str_radix(str::from_utf8(data).ok()?, 16).ok()
+}
+
+/// Parses 'addr,length'
+fn parse_range(data: &[u8]) -> Option<(u16, usize)> {
+    let (addr, len) = split(data, b',')?;
+    let addr = parse_hex(addr)?;
+    if addr > u16::MAX as usize {
+        return None;
+    }
+    Some((addr as u16, parse_hex(len)?))
+}
@@ -0,0 +1,2 @@
+pub mod gdbstub;
+pub mod packet;
@@ -0,0 +1,215 @@
+//! Framing of GDB remote serial protocol packets:
+//! '$' data '#' checksum, with '}' escaping.
+
+use itertools::Itertools;
+
+/// Characters that have to be escaped in packet data
+const ESCAPED: [u8; 4] = [b'#', b'$', b'}', b'*'];
+const ESCAPE: u8 = b'}';
+const ESCAPE_XOR: u8 = 0x20;
+
+/// Interrupt request (Ctrl-C) sent outside of packets
+pub const INTERRUPT: u8 = 0x03;
+
+/// Modulo 256 sum of the (escaped) packet data
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))
+}
+
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if ESCAPED.contains(&b) {
+            out.push(ESCAPE);
+            out.push(b ^ ESCAPE_XOR);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+pub fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut it = data.iter();
+    while let Some(&b) = it.next() {
+        if b == ESCAPE {
+            if let Some(&e) = it.next() {
+                out.push(e ^ ESCAPE_XOR);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// Frames data into a packet
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let escaped = escape(data);
+    let mut out = Vec::with_capacity(escaped.len() + 4);
+    out.push(b'$');
+    out.extend_from_slice(&escaped);
+    out.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+    out
+}
+
+/// Encodes bytes as hexadecimal, as used in packets
+pub fn to_hex(data: &[u8]) -> String {
+    format!("{:02x}", data.iter().format(""))
+}
+
+/// Decodes hexadecimal data, returns None if invalid
+pub fn from_hex(s: &[u8]) -> Option<Vec<u8>> {
+    let chunks = s.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    chunks
+        .map(|c| u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok())
+        .collect()
+}
+
+/// Something received from the client
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A valid packet (unescaped data)
+    Packet(Vec<u8>),
+    /// A packet with a checksum mismatch
+    BadChecksum,
+    /// Acknowledgement of the last sent packet
+    Ack,
+    /// Last sent packet has to be retransmitted
+    Nack,
+    /// Interrupt request (Ctrl-C)
+    Interrupt,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    Idle,
+    Data,
+    Checksum(Option<u8>),
+}
+
+/// Parses a stream of bytes from the client into events
+#[derive(Default)]
+pub struct PacketParser {
+    state: State,
+    data: Vec<u8>,
+}
+
+impl PacketParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Processes a received byte, returns an event when complete
+    pub fn feed(&mut self, b: u8) -> Option<Event> {
+        match self.state {
+            State::Idle => match b {
+                b'$' => {
+                    self.data.clear();
+                    self.state = State::Data;
+                    None
+                }
+                b'+' => Some(Event::Ack),
+                b'-' => Some(Event::Nack),
+                INTERRUPT => Some(Event::Interrupt),
+                // Garbage between packets is ignored
+                _ => None,
+            },
+            State::Data => {
+                if b == b'#' {
+                    self.state = State::Checksum(None);
+                } else {
+                    self.data.push(b);
+                }
+                None
+            }
+            State::Checksum(None) => {
+                self.state = State::Checksum(Some(b));
+                None
+            }
+            State::Checksum(Some(hi)) => {
+                self.state = State::Idle;
+                let sum = from_hex(&[hi, b]);
+                if sum == Some(vec![checksum(&self.data)]) {
+                    Some(Event::Packet(unescape(&self.data)))
+                } else {
+                    Some(Event::BadChecksum)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Event> {
+        let mut p = PacketParser::new();
+        bytes.iter().filter_map(|&b| p.feed(b)).collect()
+    }
+
+    #[test]
+    fn encode_packets() {
+        assert_eq!(encode(b""), b"$#00");
+        assert_eq!(encode(b"OK"), b"$OK#9a");
+        assert_eq!(encode(b"S05"), b"$S05#b8");
+        // '#' = 0x23 is sent as '}' 0x03
+        assert_eq!(encode(b"a#b"), b"$a}\x03b#43");
+    }
+
+    #[test]
+    fn escaping() {
+        let data = b"}$#*x";
+        assert_eq!(escape(data), b"}]}\x04}\x03}\x0ax");
+        assert_eq!(unescape(&escape(data)), data);
+    }
+
+    #[test]
+    fn parse_packets() {
+        assert_eq!(
+            parse(b"+$qSupported:multiprocess+#c6"),
+            vec![
+                Event::Ack,
+                Event::Packet(b"qSupported:multiprocess+".to_vec())
+            ]
+        );
+        assert_eq!(
+            parse(b"$g#67$?#3f-"),
+            vec![
+                Event::Packet(b"g".to_vec()),
+                Event::Packet(b"?".to_vec()),
+                Event::Nack
+            ]
+        );
+        // Checksum is case insensitive
+        assert_eq!(parse(b"$OK#9A"), vec![Event::Packet(b"OK".to_vec())]);
+        assert_eq!(parse(b"\x03"), vec![Event::Interrupt]);
+        assert_eq!(parse(b"$a}\x03b#43"), vec![Event::Packet(b"a#b".to_vec())]);
+    }
+
+    #[test]
+    fn parse_bad_checksum() {
+        assert_eq!(
+            parse(b"$g#00$g#zz$g#67"),
+            vec![
+                Event::BadChecksum,
+                Event::BadChecksum,
+                Event::Packet(b"g".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x01, 0xAB, 0xFF]), "01abff");
+        assert_eq!(from_hex(b"01abFF"), Some(vec![0x01, 0xAB, 0xFF]));
+        assert_eq!(from_hex(b"1"), None);
+        assert_eq!(from_hex(b"zz"), None);
+    }
+}
@@ -0,0 +1,263 @@
+//! Memory dumps for the debugger. Regions with a known layout are
+//! annotated: the cartridge header by field, OAM by entry and the IO
+//! registers by name.
+
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+use itertools::Itertools;
+
+use crate::gameboy::ioregs::io_register_name;
+
+const HEADER: RangeInclusive<u16> = 0x0100..=0x014F;
+const OAM: RangeInclusive<u16> = 0xFE00..=0xFE9F;
+const IO: RangeInclusive<u16> = 0xFF00..=0xFF7F;
+const IE: u16 = 0xFFFF;
+
+const OAM_ENTRY_SIZE: usize = 4;
+
+/// Bytes per row
+const ROW_SIZE: usize = 16;
+
+/// Cartridge header fields: address, length and name
+const HEADER_FIELDS: [(u16, usize, &str); 14] = [
+    (0x0100, 4, "Entry point"),
+    (0x0104, 48, "Logo"),
+    (0x0134, 15, "Title"),
+    (0x0143, 1, "CGB flag"),
+    (0x0144, 2, "New licensee"),
+    (0x0146, 1, "SGB flag"),
+    (0x0147, 1, "Cartridge type"),
+    (0x0148, 1, "ROM size"),
+    (0x0149, 1, "RAM size"),
+    (0x014A, 1, "Destination"),
+    (0x014B, 1, "Old licensee"),
+    (0x014C, 1, "Version"),
+    (0x014D, 1, "Header checksum"),
+    (0x014E, 2, "Global checksum"),
+];
+
+/// OAM attribute flags, as shown in the dump
+const OAM_FLAGS: [(u8, &str); 4] = [(0x80, "PRI"), (0x40, "YF"), (0x20, "XF"), (0x10, "PAL")];
+
+/// Returns the annotated field containing an address, as its start
+/// address and length
+fn field_at(addr: u16) -> Option<(u16, usize)> {
+    if HEADER.contains(&addr) {
+        HEADER_FIELDS
+            .iter()
+            .find(|&&(start, len, _)| (start..start + len as u16).contains(&addr))
+            .map(|&(start, len, _)| (start, len))
+    } else if OAM.contains(&addr) {
+        Some((addr & !(OAM_ENTRY_SIZE as u16 - 1), OAM_ENTRY_SIZE))
+    } else if IO.contains(&addr) || addr == IE {
+        Some((addr, 1))
+    } else {
+        None
+    }
+}
+
+/// Annotation of a cartridge header field
+pub fn format_header_field(addr: u16, data: &[u8]) -> String {
+    let Some(&(_, _, name)) = HEADER_FIELDS.iter().find(|f| f.0 == addr) else {
+        return String::new();
+    };
+    if name == "Title" {
+        let title: String = data
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| {
+                if b == b' ' || b.is_ascii_graphic() {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        format!("{} \"{}\"", name, title)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Annotation of an OAM entry (Y, X, tile, flags)
+pub fn format_oam_entry(addr: u16, entry: &[u8]) -> String {
+    let mut out = format!(
+        "#{:02} Y={:02X} X={:02X} T={:02X} F={:02X}",
+        (addr - OAM.start()) as usize / OAM_ENTRY_SIZE,
+        entry[0],
+        entry[1],
+        entry[2],
+        entry[3]
+    );
+    for (bit, flag) in OAM_FLAGS {
+        if entry[3] & bit != 0 {
+            write!(out, " {}", flag).unwrap();
+        }
+    }
+    out
+}
+
+/// Writes a field as rows of bytes, with the annotation after the
+/// first row. 'width' is the amount of bytes the annotation is
+/// aligned to.
+fn write_field(out: &mut String, addr: u16, data: &[u8], annotation: &str, width: usize) {
+    for (i, row) in data.chunks(ROW_SIZE).enumerate() {
+        let start = addr.wrapping_add((i * ROW_SIZE) as u16);
+        let bytes = format!("{:02X}", row.iter().format(" "));
+        if i == 0 && !annotation.is_empty() {
+            let w = width * 3 - 1;
+            writeln!(out, "{:04X}: {:w$}  {}", start, bytes, annotation).unwrap();
+        } else {
+            writeln!(out, "{:04X}: {}", start, bytes).unwrap();
+        }
+    }
+}
+
+/// Formats a dump of memory read at 'addr'. Plain memory is shown in
+/// rows of 16 bytes. Cartridge header fields, OAM entries and IO
+/// registers are shown in a row each, with their annotation, if they
+/// are completely in the dump.
+pub fn format_memory(addr: u16, data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let start = addr.wrapping_add(offset as u16);
+        let rest = &data[offset..];
+        let len = match field_at(start) {
+            Some((field, len)) if field == start && len <= rest.len() => {
+                let data = &rest[..len];
+                let (annotation, width) = if HEADER.contains(&start) {
+                    (format_header_field(start, data), ROW_SIZE)
+                } else if OAM.contains(&start) {
+                    (format_oam_entry(start, data), OAM_ENTRY_SIZE)
+                } else {
+                    // Aligned with OAM entries
+                    let name = io_register_name(start).unwrap_or_default();
+                    (name.to_string(), OAM_ENTRY_SIZE)
+                };
+                write_field(&mut out, start, data, &annotation, width);
+                len
+            }
+            // Part of a field
+            Some((field, len)) => {
+                let len = (len - (start - field) as usize).min(rest.len());
+                write_field(&mut out, start, &rest[..len], "", 0);
+                len
+            }
+            // Plain memory, up to the next field
+            None => {
+                let len = (1..ROW_SIZE.min(rest.len()))
+                    .find(|&i| field_at(start.wrapping_add(i as u16)).is_some())
+                    .unwrap_or(ROW_SIZE.min(rest.len()));
+                write_field(&mut out, start, &rest[..len], "", 0);
+                len
+            }
+        };
+        offset += len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Memory image with a cartridge header, OAM and IO registers
+    fn image() -> Vec<u8> {
+        let mut mem = vec![0; 0x10000];
+        mem[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        mem[0x0134..0x0138].copy_from_slice(b"TEST");
+        mem[0x0143] = 0x80;
+        mem[0x0147] = 0x1B;
+        for (i, b) in mem[0xC000..0xC020].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        mem[0xFE00..0xFE08].copy_from_slice(&[0x10, 0x08, 0x01, 0x00, 0x20, 0x10, 0x02, 0xF3]);
+        mem[0xFEF8..0xFF00].fill(0xFF);
+        mem[0xFF00..0xFF06].copy_from_slice(&[0xCF, 0x00, 0x7E, 0xFF, 0xAB, 0x00]);
+        mem[0xFF40] = 0x91;
+        mem[0xFFFF] = 0x1F;
+        mem
+    }
+
+    fn dump(addr: u16, len: usize) -> String {
+        let mem = image();
+        format_memory(addr, &mem[addr as usize..addr as usize + len])
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            dump(0xC000, 20),
+            concat!(
+                "C000: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n",
+                "C010: 10 11 12 13\n",
+            )
+        );
+        assert_eq!(dump(0xC000, 0), "");
+    }
+
+    #[test]
+    fn io() {
+        assert_eq!(
+            dump(0xFEFC, 10),
+            concat!(
+                "FEFC: FF FF FF FF\n",
+                "FF00: CF           P1\n",
+                "FF01: 00           SB\n",
+                "FF02: 7E           SC\n",
+                "FF03: FF\n",
+                "FF04: AB           DIV\n",
+                "FF05: 00           TIMA\n",
+            )
+        );
+        assert_eq!(dump(0xFF40, 1), "FF40: 91           LCDC\n");
+        assert_eq!(dump(0xFFFF, 1), "FFFF: 1F           IE\n");
+    }
+
+    #[test]
+    fn oam() {
+        assert_eq!(
+            dump(0xFE02, 8),
+            concat!(
+                "FE02: 01 00\n",
+                "FE04: 20 10 02 F3  #01 Y=20 X=10 T=02 F=F3 PRI YF XF PAL\n",
+                "FE08: 00 00\n",
+            )
+        );
+        assert_eq!(
+            dump(0xFE00, 4),
+            "FE00: 10 08 01 00  #00 Y=10 X=08 T=01 F=00\n"
+        );
+    }
+
+    #[test]
+    fn header() {
+        let pad = |s: &str| format!("{:47}", s);
+        assert_eq!(
+            dump(0x0100, 4),
+            format!("0100: {}  Entry point\n", pad("00 C3 50 01"))
+        );
+        assert_eq!(
+            dump(0x0134, 20),
+            [
+                format!(
+                    "0134: {}  Title \"TEST\"\n",
+                    pad("54 45 53 54 00 00 00 00 00 00 00 00 00 00 00")
+                ),
+                format!("0143: {}  CGB flag\n", pad("80")),
+                format!("0144: {}  New licensee\n", pad("00 00")),
+                format!("0146: {}  SGB flag\n", pad("00")),
+                format!("0147: {}  Cartridge type\n", pad("1B")),
+            ]
+            .concat()
+        );
+        // The logo takes several rows, part of it is shown as is
+        assert_eq!(dump(0x0104, 48).lines().count(), 3);
+        assert_eq!(
+            dump(0x0130, 8),
+            concat!("0130: 00 00 00 00\n", "0134: 54 45 53 54\n")
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::Result;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use crate::tickable::{TickResult, Tickable, Ticks};
+
+/// RP: LED on
+const RP_LED: u8 = 1 << 0;
+/// RP: no light received (active low)
+const RP_NO_LIGHT: u8 = 1 << 1;
+/// RP: reading enabled (both bits have to be set)
+const RP_READ_ENABLE: u8 = 0xC0;
+/// RP: bits that read back as written
+const RP_WRITABLE: u8 = RP_READ_ENABLE | RP_LED;
+/// RP: unused bits, read as 1
+const RP_UNUSED: u8 = 0x3C;
+
+/// Carries the infrared signal between the LED of one system and the
+/// receiver of another
+pub trait IrTransport {
+    /// The LED was switched on (true) or off at the specified cycle
+    fn send(&mut self, level: bool, at_cycle: u64);
+
+    /// Returns true if light is received
+    fn recv(&self) -> bool;
+}
+
+/// Nothing in front of the IR port
+pub struct NullTransport;
+
+impl IrTransport for NullTransport {
+    fn send(&mut self, _level: bool, _at_cycle: u64) {}
+
+    fn recv(&self) -> bool {
+        false
+    }
+}
+
+/// One end of an in-memory infrared connection (see crossed_transports())
+pub struct CrossedTransport {
+    tx: Arc<AtomicBool>,
+    rx: Arc<AtomicBool>,
+}
+
+impl IrTransport for CrossedTransport {
+    fn send(&mut self, level: bool, _at_cycle: u64) {
+        self.tx.store(level, Ordering::Relaxed);
+    }
+
+    fn recv(&self) -> bool {
+        self.rx.load(Ordering::Relaxed)
+    }
+}
+
+/// Creates both ends of an infrared connection between two systems,
+/// the LED of one shines on the receiver of the other.
+pub fn crossed_transports() -> (CrossedTransport, CrossedTransport) {
+    let a = Arc::new(AtomicBool::new(false));
+    let b = Arc::new(AtomicBool::new(false));
+    (
+        CrossedTransport {
+            tx: a.clone(),
+            rx: b.clone(),
+        },
+        CrossedTransport { tx: b, rx: a },
+    )
+}
+
+/// CGB infrared communication port
+pub struct InfraRed {
+    /// RP register (writable bits)
+    rp: u8,
+    transport: Box<dyn IrTransport>,
+    /// Emulated cycles (at normal speed) since power on
+    cycles: u64,
+}
+
+impl InfraRed {
+    pub fn new(transport: Box<dyn IrTransport>) -> Self {
+        Self {
+            rp: 0,
+            transport,
+            cycles: 0,
+        }
+    }
+
+    pub fn set_transport(&mut self, transport: Box<dyn IrTransport>) {
+        self.transport = transport;
+    }
+
+    pub fn led(&self) -> bool {
+        self.rp & RP_LED != 0
+    }
+}
+
+impl BusMember for InfraRed {
+    fn read(&self, addr: u16) -> u8 {
+        assert_eq!(addr, 0xFF56);
+
+        let light = self.rp & RP_READ_ENABLE == RP_READ_ENABLE && self.transport.recv();
+        self.rp | RP_UNUSED | if light { 0 } else { RP_NO_LIGHT }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        assert_eq!(addr, 0xFF56);
+
+        let led = self.led();
+        self.rp = val & RP_WRITABLE;
+        if self.led() != led {
+            self.transport.send(self.led(), self.cycles);
+        }
+    }
+}
+
+impl Tickable for InfraRed {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
+        self.cycles += ticks.get_t_no_ds() as u64;
+        Ok(0)
+    }
+}
+
+impl Savestate for InfraRed {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u8(self.rp);
+        w.put_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rp = r.get_u8()? & RP_WRITABLE;
+        self.cycles = r.get_u64()?;
+        // Let the other side know the state of the LED
+        self.transport.send(self.led(), self.cycles);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Records the LED changes sent out
+    struct RecordingTransport {
+        sent: Rc<RefCell<Vec<(bool, u64)>>>,
+        light: bool,
+    }
+
+    impl IrTransport for RecordingTransport {
+        fn send(&mut self, level: bool, at_cycle: u64) {
+            self.sent.borrow_mut().push((level, at_cycle));
+        }
+
+        fn recv(&self) -> bool {
+            self.light
+        }
+    }
+
+    #[test]
+    fn register_bits() {
+        let mut ir = InfraRed::new(Box::new(NullTransport));
+        assert_eq!(ir.read(0xFF56), 0x3E);
+
+        ir.write(0xFF56, 0xFF);
+        assert!(ir.led());
+        // Read enabled, no light
+        assert_eq!(ir.read(0xFF56), 0xFF);
+        ir.write(0xFF56, 0x00);
+        assert!(!ir.led());
+        assert_eq!(ir.read(0xFF56), 0x3E);
+        // Bit 1 is read only
+        ir.write(0xFF56, 0x02);
+        assert_eq!(ir.read(0xFF56), 0x3E);
+    }
+
+    #[test]
+    fn receive() {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut ir = InfraRed::new(Box::new(RecordingTransport {
+            sent: sent.clone(),
+            light: true,
+        }));
+
+        // Reading disabled (both bits are needed)
+        assert_eq!(ir.read(0xFF56) & RP_NO_LIGHT, RP_NO_LIGHT);
+        ir.write(0xFF56, 0x80);
+        assert_eq!(ir.read(0xFF56) & RP_NO_LIGHT, RP_NO_LIGHT);
+        ir.write(0xFF56, 0x40);
+        assert_eq!(ir.read(0xFF56) & RP_NO_LIGHT, RP_NO_LIGHT);
+        ir.write(0xFF56, 0xC0);
+        assert_eq!(ir.read(0xFF56), 0xFC);
+        assert!(sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn send() {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut ir = InfraRed::new(Box::new(RecordingTransport {
+            sent: sent.clone(),
+            light: false,
+        }));
+
+        ir.tick(Ticks::from_t(100)).unwrap();
+        ir.write(0xFF56, 0x01);
+        // Only changes are sent
+        ir.write(0xFF56, 0xC1);
+        ir.tick(Ticks::from_t_ds(100)).unwrap();
+        ir.write(0xFF56, 0xC0);
+        assert_eq!(*sent.borrow(), [(true, 100), (false, 150)]);
+    }
+}
@@ -1,5 +1,8 @@
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use crate::input::input::{Button, Input};
 
+use anyhow::Result;
+
 const JOYPAD_UNUSED: u8 = (1 << 7) | (1 << 6);
 const JOYPAD_SELECT_MASK: u8 = 0x30;
 const JOYPAD_SELECT_ACTION: u8 = 1 << 5;
@@ -49,7 +52,23 @@ impl Joypad {
             }
     }
 
+    /// Notifies the input source of a new emulated frame
+    pub fn set_frame(&mut self, frame: u64) {
+        self.input.set_frame(frame);
+    }
+
     pub fn write(&mut self, val: u8) {
         self.select = val & JOYPAD_SELECT_MASK;
     }
 }
+
+impl Savestate for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u8(self.select);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.select = r.get_u8()?;
+        Ok(())
+    }
+}
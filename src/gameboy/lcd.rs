@@ -1,13 +1,22 @@
-use crate::display::display::Display;
+use crate::display::display::{Display, FrameBuffer};
+use crate::display::palette::{color_correct, DmgPalette, DMG_GREY};
 use crate::gameboy::bus::bus::BusMember;
-use crate::gameboy::lcd_oam::{OAMTable, ObjPriMode};
-use crate::tickable::{Tickable, Ticks};
-
-use anyhow::Result;
+use crate::gameboy::cpu::cpu;
+use crate::gameboy::lcd_oam::{OAMTable, ObjPriMode, OAM_SIZE};
+use crate::gameboy::model::Model;
+use crate::gameboy::raminit::RamInit;
+use crate::tickable::{TickResult, Tickable, Ticks};
+
+use crate::error::Result;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use num_derive::ToPrimitive;
 use num_traits::ToPrimitive;
 use strum::EnumCount;
 use strum_macros::EnumCount as EnumCountMacro;
+use thiserror::Error;
+
+/// Random sequence of VRAM for RamInit::Random
+const RAM_STREAM_VRAM: u64 = 2;
 
 pub const LCD_W: usize = 160;
 pub const LCD_H: usize = 144;
@@ -20,11 +29,11 @@ pub type Color = u16;
 pub type ColorIndex = u8;
 const COLORINDEX_DEFAULT: ColorIndex = 0;
 
-const VRAM_SIZE: usize = 0x2000;
-const VRAM_BANKS: usize = 2;
+pub const VRAM_SIZE: usize = 0x2000;
+pub const VRAM_BANKS: usize = 2;
 
 // Tile sizes
-const TILE_BSIZE: usize = 16;
+pub(crate) const TILE_BSIZE: usize = 16;
 const TILE_W: isize = 8;
 const TILE_H: isize = 8;
 
@@ -36,8 +45,8 @@ const BGW_W: isize = 32;
 const LCDC_ENABLE: u8 = 1 << 7;
 const LCDC_WINDOW_TILEMAP: u8 = 1 << 6;
 const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
-const LCDC_BGW_TILEDATA: u8 = 1 << 4;
-const LCDC_BG_TILEMAP: u8 = 1 << 3;
+pub(crate) const LCDC_BGW_TILEDATA: u8 = 1 << 4;
+pub(crate) const LCDC_BG_TILEMAP: u8 = 1 << 3;
 const LCDC_OBJ_SIZE: u8 = 1 << 2;
 const LCDC_OBJ_ENABLE: u8 = 1 << 1;
 const LCDC_BGW_ENABLE: u8 = 1 << 0;
@@ -60,7 +69,7 @@ const OAM_FLIP_Y: u8 = 1 << 6;
 const OAM_VRAM_BANK: u8 = 1 << 3;
 
 // Gameboy Color register properties
-const CRAM_ENTRIES: usize = 0x20;
+pub const CRAM_ENTRIES: usize = 0x20;
 const XCPS_ADDR_MASK: u8 = 0x3F;
 const XCPS_AUTO_INC: u8 = 1 << 7;
 const COLOR_MASK: Color = 0x7FFF;
@@ -68,35 +77,74 @@ const COLOR_DEFAULT: Color = 0x7FFF; // White
 const CGB_PALETTE_SIZE: usize = 4;
 
 // OAM/BG map attributes
-const TILEATTR_PALETTE_CGB_MASK: u8 = 0x07;
+pub(crate) const TILEATTR_PALETTE_CGB_MASK: u8 = 0x07;
 const TILEATTR_PALETTE_CGB_SHIFT: u8 = 0;
-const TILEATTR_PALETTE_DMG_MASK: u8 = 1 << 4;
+pub(crate) const TILEATTR_PALETTE_DMG_MASK: u8 = 1 << 4;
 const TILEATTR_PALETTE_DMG_SHIFT: u8 = 4;
-const TILEATTR_VRAM_BANK: u8 = 1 << 3;
+pub(crate) const TILEATTR_VRAM_BANK: u8 = 1 << 3;
+pub(crate) const TILEATTR_FLIP_X: u8 = 1 << 5;
+pub(crate) const TILEATTR_FLIP_Y: u8 = 1 << 6;
+pub(crate) const TILEATTR_PRIORITY: u8 = 1 << 7;
 
 /// Generic of the DMG and CGB palette types
 #[derive(Copy, Clone)]
 enum Palette {
     DMG(u8),
     CGB([Color; CGB_PALETTE_SIZE]),
+    /// DMG palette register mapping into a CGB palette (DMG-on-CGB)
+    Compat(u8, [Color; CGB_PALETTE_SIZE]),
 }
 
 impl Palette {
-    /// Converts a color index to a color from this palette
-    fn get_color(&self, cidx: u8) -> Color {
+    /// Converts a color index to a color from this palette,
+    /// DMG shades are looked up in 'shades'.
+    fn get_color(&self, cidx: u8, shades: &DmgPalette) -> Color {
         match self {
-            Palette::DMG(p) => match (p >> (cidx * 2)) & 3 {
-                3 => 0,
-                2 => 0b01000_01000_01000,
-                1 => 0b11000_11000_11000,
-                0 => COLOR_DEFAULT,
-                _ => unreachable!(),
-            },
+            Palette::DMG(p) => shades[((p >> (cidx * 2)) & 3) as usize],
             Palette::CGB(p) => p[cidx as usize],
+            Palette::Compat(p, colors) => colors[((p >> (cidx * 2)) & 3) as usize],
         }
     }
 }
 
+/// Layer a pixel originates from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelSource {
+    Background,
+    Window,
+    Object,
+}
+
+/// Outcome of the BG/object priority resolution for a pixel
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelPriority {
+    /// No (opaque) object pixel at this position
+    NoObject,
+    /// Object pixel drawn over the background
+    Object,
+    /// Object pixel hidden behind the background/window
+    BackgroundPriority,
+}
+
+/// Debug information of a rendered pixel (see LCDController::set_pixel_debug())
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PixelDebug {
+    /// Color index in the tile (0 - 3)
+    pub idx: ColorIndex,
+
+    /// Layer the color was taken from
+    pub source: PixelSource,
+
+    /// Palette number (DMG: 0 for BGP, 0/1 for OBP0/OBP1, CGB: 0 - 7)
+    pub palette: u8,
+
+    /// Result of the priority resolution
+    pub priority: PixelPriority,
+
+    /// Resulting color (before color correction)
+    pub color: Color,
+}
+
 /// Current state of a dot while rendering
 #[derive(Copy, Clone)]
 struct DotState {
@@ -108,6 +156,13 @@ struct DotState {
 
     /// Priority bit was set
     priority: bool,
+
+    /// Layer and palette number the color was taken from
+    source: PixelSource,
+    palette: u8,
+
+    /// Result of the object priority resolution
+    outcome: PixelPriority,
 }
 
 impl DotState {
@@ -116,6 +171,19 @@ impl DotState {
             color: COLOR_DEFAULT,
             idx: COLORINDEX_DEFAULT,
             priority: false,
+            source: PixelSource::Background,
+            palette: 0,
+            outcome: PixelPriority::NoObject,
+        }
+    }
+
+    fn to_debug(self) -> PixelDebug {
+        PixelDebug {
+            idx: self.idx,
+            source: self.source,
+            palette: self.palette,
+            priority: self.outcome,
+            color: self.color,
         }
     }
 }
@@ -141,21 +209,29 @@ impl<'a> Tile<'a> {
     }
 
     fn flip_x(&self) -> bool {
-        (self.attr & (1 << 5)) != 0
+        (self.attr & TILEATTR_FLIP_X) != 0
     }
 
     fn flip_y(&self) -> bool {
-        (self.attr & (1 << 6)) != 0
+        (self.attr & TILEATTR_FLIP_Y) != 0
     }
 
     fn has_priority(&self) -> bool {
-        (self.attr & (1 << 7)) != 0
+        (self.attr & TILEATTR_PRIORITY) != 0
     }
 
     fn is_object(&self) -> bool {
         self.ttype == TileType::Object
     }
 
+    fn source(&self) -> PixelSource {
+        match self.ttype {
+            TileType::Background => PixelSource::Background,
+            TileType::Window => PixelSource::Window,
+            TileType::Object => PixelSource::Object,
+        }
+    }
+
     fn is_bg(&self) -> bool {
         self.ttype == TileType::Background
     }
@@ -169,22 +245,68 @@ pub enum LCDStatMode {
     VBlank = 1,
 }
 
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LCDError {
+    /// Disabling the LCD outside VBlank can damage a real DMG's screen.
+    /// Only raised after set_strict_disable().
+    #[error("LCD disabled outside VBlank (mode {mode:?}, LY {ly})")]
+    UnsafeDisable { mode: LCDStatMode, ly: u8 },
+}
+
 #[derive(Debug, EnumCountMacro, ToPrimitive)]
 enum RegHist {
     BGP,
 }
 
+/// Called after a scanline is composed, with the line number and its colors
+pub type ScanlineHook = Box<dyn FnMut(u8, &[Color])>;
+
+/// How the PPU produces frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuMode {
+    /// Draws every scanline
+    Accurate,
+    /// Only keeps LY, mode and interrupt timing, scanlines are not
+    /// drawn unless someone looks at them: a display that shows frames,
+    /// a scanline hook or pixel debugging. For headless runs of test
+    /// ROMs that report through serial or memory.
+    Instant,
+}
+
 /// LCD controller state
 pub struct LCDController {
     /// Display output
     output: Box<dyn Display>,
 
+    /// Last completed frame
+    framebuffer: FrameBuffer,
+
+    /// Frame being drawn (row-major), copied to the framebuffer
+    /// and the output at VBlank
+    backbuffer: Vec<Color>,
+
+    /// Colors of the DMG shades
+    dmg_palette: DmgPalette,
+
+    /// CGB color correction enabled
+    color_correction: bool,
+
+    /// Per-pixel debug information of the last frame (if enabled)
+    pixel_debug: Option<Vec<PixelDebug>>,
+
+    /// Hook called for every drawn scanline (see set_scanline_hook())
+    scanline_hook: Option<ScanlineHook>,
+
     /// OAM memory
     oam: OAMTable,
 
     /// Gameboy Color mode
     cgb: bool,
 
+    /// DMG cartridge running on a CGB in compatibility mode. The DMG
+    /// palettes map into the compatibility palettes in CRAM.
+    dmg_on_cgb: bool,
+
     /// VRAM memory
     vram: [u8; VRAM_SIZE * VRAM_BANKS],
 
@@ -212,6 +334,11 @@ pub struct LCDController {
     /// Current window scanline
     wly: u8,
 
+    /// LY matched WY with the window enabled this frame. From then on,
+    /// the window is drawn on every line it is enabled on, regardless
+    /// of later changes to WY.
+    window_triggered: bool,
+
     /// LY compare register
     lyc: u8,
 
@@ -246,10 +373,13 @@ pub struct LCDController {
     /// Dot refresh position
     dots: u128,
 
-    /// STAT interrupt request
+    /// Pending interrupt requests, returned by the next tick()
+    intreq: u8,
+
+    /// STAT interrupt request (for get_clr_intreq_stat())
     intreq_stat: bool,
 
-    /// VBlank interrupt request
+    /// VBlank interrupt request (for get_clr_intreq_vblank())
     intreq_vblank: bool,
 
     /// STAT interrupt line
@@ -258,11 +388,35 @@ pub struct LCDController {
     /// Object priority mode
     objpri: ObjPriMode,
 
+    /// Whether OPRI accepts writes (only while the boot ROM runs)
+    opri_writable: bool,
+
     /// Skip drawing X frames
     skip_frames: usize,
 
+    /// Do not present the next completed frame on the output
+    skip_present: bool,
+
+    /// Amount of frames (VBlank periods) since power on
+    frames: u64,
+
     /// Register change history during mode 3
-    reg_history: [[u8; Self::TRANSFER_PERIOD as usize]; RegHist::COUNT],
+    reg_history: [[u8; Self::TRANSFER_PERIOD_MAX as usize]; RegHist::COUNT],
+
+    /// Length of mode 3 on the current scanline, in dots
+    transfer_period: u128,
+
+    /// Whether scanlines are drawn
+    ppu_mode: PpuMode,
+
+    /// Amount of times the LCD was disabled outside VBlank
+    unsafe_disables: u64,
+
+    /// Disabling the LCD outside VBlank is an error
+    strict_disable: bool,
+
+    /// Error returned by the next tick()
+    error: Option<LCDError>,
 }
 
 impl LCDController {
@@ -278,13 +432,17 @@ impl LCDController {
     /// Amount of dots in 'search' mode
     const SEARCH_PERIOD: u128 = 80;
 
-    /// Amount of dots in 'transfer' mode (actually 168 to 291)
-    const TRANSFER_PERIOD: u128 = 200;
+    /// Minimum amount of dots in 'transfer' mode, without penalties
+    const TRANSFER_PERIOD: u128 = 172;
+
+    /// Maximum amount of dots in 'transfer' mode (SCX % 8 = 7, 10 objects)
+    const TRANSFER_PERIOD_MAX: u128 = Self::TRANSFER_PERIOD + 7 + 10 * 11;
 
     /// Initialization of 'dots' after LCD is enabled
     const DOTS_INIT: u128 = 4;
 
-    pub fn new(display: Box<dyn Display>, cgb: bool) -> Self {
+    pub fn new(display: Box<dyn Display>, model: Model) -> Self {
+        let cgb = model.is_cgb();
         let objpri = if cgb {
             ObjPriMode::OAMPosition
         } else {
@@ -293,7 +451,14 @@ impl LCDController {
 
         let mut r = Self {
             output: display,
+            framebuffer: FrameBuffer::new(LCD_W, LCD_H),
+            backbuffer: vec![0; LCD_W * LCD_H],
+            dmg_palette: DMG_GREY,
+            color_correction: false,
+            pixel_debug: None,
+            scanline_hook: None,
             cgb,
+            dmg_on_cgb: false,
             oam: OAMTable::new(),
             vram: [0; VRAM_SIZE * VRAM_BANKS],
 
@@ -304,6 +469,7 @@ impl LCDController {
             wx: 0,
             wy: 0,
             wly: 0,
+            window_triggered: false,
             ly: 0,
             lyc: 0,
             bgp: 0,
@@ -318,14 +484,25 @@ impl LCDController {
 
             dots: Self::DOTS_INIT,
 
+            intreq: 0,
             intreq_stat: false,
             intreq_vblank: false,
             stat_int_line: false,
 
             objpri,
+            opri_writable: true,
             skip_frames: 1,
+            skip_present: false,
+            frames: 0,
+
+            reg_history: [[0; Self::TRANSFER_PERIOD_MAX as usize]; RegHist::COUNT],
+            transfer_period: Self::TRANSFER_PERIOD,
 
-            reg_history: [[0; Self::TRANSFER_PERIOD as usize]; RegHist::COUNT],
+            ppu_mode: PpuMode::Accurate,
+
+            unsafe_disables: 0,
+            strict_disable: false,
+            error: None,
         };
         r.reset();
 
@@ -344,7 +521,7 @@ impl LCDController {
             let hpos = self.dots % Self::DOTS_PER_LINE;
             if hpos < Self::SEARCH_PERIOD {
                 LCDStatMode::Search
-            } else if hpos < Self::SEARCH_PERIOD + Self::TRANSFER_PERIOD {
+            } else if hpos < Self::SEARCH_PERIOD + self.transfer_period {
                 LCDStatMode::Transfer
             } else {
                 LCDStatMode::HBlank
@@ -373,6 +550,36 @@ impl LCDController {
         self.reg_history[reg.to_usize().unwrap()][transfer_cycle]
     }
 
+    /// Calculates the length of mode 3 for a scanline. The pixel FIFO
+    /// discards SCX % 8 pixels at the start of the line, and every object
+    /// on the line stalls it for 6 to 11 dots, depending on how far the
+    /// background fetcher is into its tile.
+    fn mode3_length(&self, scanline: isize) -> u128 {
+        let mut period = Self::TRANSFER_PERIOD + (self.scx % 8) as u128;
+
+        if self.lcdc & LCDC_OBJ_ENABLE == LCDC_OBJ_ENABLE {
+            let sprite_h = if self.lcdc & LCDC_OBJ_SIZE == LCDC_OBJ_SIZE {
+                TILE_H * 2
+            } else {
+                TILE_H
+            };
+            period += self
+                .oam
+                .iter_scanline(scanline, sprite_h, self.objpri)
+                .map(|e| 6 + 5u128.saturating_sub((e.x as u128 + self.scx as u128) % 8))
+                .sum::<u128>();
+        }
+        period
+    }
+
+    /// Offset in VRAM of a CPU address. VRAM bank 1 only exists in CGB
+    /// mode, VBK is ignored otherwise (also if it was set before
+    /// switching to DMG compatibility mode).
+    fn vram_offset(&self, addr: usize) -> usize {
+        let bank = if self.cgb { self.vbk as usize } else { 0 };
+        addr - 0x8000 + VRAM_SIZE * bank
+    }
+
     /// Calculate LY based on current timed LCD scan
     fn calc_ly(&self) -> u8 {
         Self::calc_scanline(self.dots)
@@ -383,17 +590,27 @@ impl LCDController {
         (lines_scanned % Self::SCANLINES) as u8
     }
 
+    /// Returns true if the PPU locks the CPU out of OAM (modes 2 and 3)
+    pub fn oam_blocked(&self) -> bool {
+        self.lcdc & LCDC_ENABLE != 0
+            && matches!(
+                self.get_stat_mode(),
+                LCDStatMode::Search | LCDStatMode::Transfer
+            )
+    }
+
     pub fn in_vblank(&self) -> bool {
         self.dots >= (Self::VBLANK_START * Self::DOTS_PER_LINE)
     }
 
-    /// Tests all conditions for the window to be drawn and the counter
-    /// running
+    /// Tests all conditions for the window to be drawn on the current
+    /// line and the counter running. Games park the window at
+    /// WX=160-166 to hide it, which also stops the window line counter.
     fn is_window_active(&self) -> bool {
-        self.lcdc & LCDC_WINDOW_ENABLE == LCDC_WINDOW_ENABLE
+        self.window_triggered
+            && self.lcdc & LCDC_WINDOW_ENABLE == LCDC_WINDOW_ENABLE
             && (self.cgb || self.lcdc & LCDC_BGW_ENABLE == LCDC_BGW_ENABLE)
-            && (0u8..=166).contains(&self.wx)
-            && (0u8..=143).contains(&self.wy)
+            && (0u8..=159).contains(&self.wx)
     }
 
     fn get_bgw_tile(&self, tm_x: isize, tm_y: isize, ttype: TileType) -> Tile {
@@ -429,6 +646,18 @@ impl LCDController {
             0
         };
 
+        let tile_addr = self.get_bgw_tile_offset(tile_id) + tile_bank_offset;
+
+        Tile::new(
+            &self.vram[tile_addr..tile_addr + TILE_BSIZE],
+            tile_attr,
+            ttype,
+        )
+    }
+
+    /// Returns the offset of BG/window tile data in a VRAM bank for the
+    /// current addressing mode
+    pub(crate) fn get_bgw_tile_offset(&self, tile_id: usize) -> usize {
         // VRAM offset = 8000 - 9FFF
         // BG/Win tile data at 8800 - 97FF and 8000 - 8FFF
         // BG/Win tiles always 8 x 8 pixels
@@ -445,13 +674,7 @@ impl LCDController {
         };
 
         // Correct for our VRAM array
-        let tile_addr = (tile_addr - 0x8000) as usize + tile_bank_offset;
-
-        Tile::new(
-            &self.vram[tile_addr..tile_addr + TILE_BSIZE],
-            tile_attr,
-            ttype,
-        )
+        tile_addr - 0x8000
     }
 
     fn get_obj_tile(&self, tile_idx: usize, oam_flags: u8) -> Tile {
@@ -475,7 +698,7 @@ impl LCDController {
         )
     }
 
-    fn tile_decode(tile: &[u8], x: usize, y: usize) -> u8 {
+    pub(crate) fn tile_decode(tile: &[u8], x: usize, y: usize) -> u8 {
         // Least significant bit in the odd bytes,
         // most significant bit in the even bytes.
         let x = 7 - x;
@@ -518,31 +741,47 @@ impl LCDController {
         // Auto-increment has no effect on reads
     }
 
+    /// Returns the palette number of a tile, for debugging purposes
+    fn get_tile_palette_number(&self, tile: &Tile) -> u8 {
+        if self.cgb {
+            (tile.attr & TILEATTR_PALETTE_CGB_MASK) >> TILEATTR_PALETTE_CGB_SHIFT
+        } else if tile.is_object() {
+            (tile.attr & TILEATTR_PALETTE_DMG_MASK) >> TILEATTR_PALETTE_DMG_SHIFT
+        } else {
+            0
+        }
+    }
+
+    fn get_cram_palette(&self, ttype: &TileType, palidx: usize) -> [Color; CGB_PALETTE_SIZE] {
+        let cram_offset = (palidx * CGB_PALETTE_SIZE)..((palidx + 1) * CGB_PALETTE_SIZE);
+        match ttype {
+            TileType::Background | TileType::Window => {
+                self.cram_bg[cram_offset].try_into().unwrap()
+            }
+            TileType::Object => self.cram_obj[cram_offset].try_into().unwrap(),
+        }
+    }
+
     fn get_tile_palette(&self, tile: &Tile, x: usize) -> Palette {
+        let palidx = self.get_tile_palette_number(tile) as usize;
         if !self.cgb {
             let palette_val = match tile.ttype {
                 TileType::Background | TileType::Window => self.fetch_reg(RegHist::BGP, x),
-                TileType::Object => {
-                    self.obp[((tile.attr & TILEATTR_PALETTE_DMG_MASK) >> TILEATTR_PALETTE_DMG_SHIFT)
-                        as usize]
-                }
+                TileType::Object => self.obp[palidx],
             };
 
+            if self.dmg_on_cgb {
+                // The shade selected by BGP/OBPx is looked up in the
+                // compatibility palette: BG palette 0 for the background
+                // and window, object palette 0/1 for OBP0/OBP1. The CGB
+                // attribute bits are not used.
+                return Palette::Compat(palette_val, self.get_cram_palette(&tile.ttype, palidx));
+            }
             return Palette::DMG(palette_val);
         }
 
         // For CGB, consult CRAM.
-        let palidx =
-            ((tile.attr & TILEATTR_PALETTE_CGB_MASK) >> TILEATTR_PALETTE_CGB_SHIFT) as usize;
-        let cram_offset = (palidx * CGB_PALETTE_SIZE)..((palidx + 1) * CGB_PALETTE_SIZE);
-        let cram_val: [Color; CGB_PALETTE_SIZE] = match tile.ttype {
-            TileType::Background | TileType::Window => {
-                self.cram_bg[cram_offset].try_into().unwrap()
-            }
-            TileType::Object => self.cram_obj[cram_offset].try_into().unwrap(),
-        };
-
-        Palette::CGB(cram_val)
+        Palette::CGB(self.get_cram_palette(&tile.ttype, palidx))
     }
 
     fn draw_tile_at(
@@ -601,26 +840,32 @@ impl LCDController {
                     }
 
                     // BG priority
-                    if !self.cgb {
-                        if tile.has_priority() && line[disp_x as usize].idx != COLORINDEX_DEFAULT {
-                            continue;
-                        }
+                    let dot = &mut line[disp_x as usize];
+                    let hidden = if !self.cgb {
+                        tile.has_priority() && dot.idx != COLORINDEX_DEFAULT
                     } else {
-                        if line[disp_x as usize].idx != COLORINDEX_DEFAULT
+                        dot.idx != COLORINDEX_DEFAULT
                             && (self.lcdc & LCDC_CGB_BGW_MASTER_PRIORITY
                                 == LCDC_CGB_BGW_MASTER_PRIORITY)
-                            && (tile.has_priority() || line[disp_x as usize].priority)
-                        {
-                            continue;
+                            && (tile.has_priority() || dot.priority)
+                    };
+                    if hidden {
+                        if dot.outcome == PixelPriority::NoObject {
+                            dot.outcome = PixelPriority::BackgroundPriority;
                         }
+                        continue;
                     }
+                    dot.outcome = PixelPriority::Object;
                 } else {
                     // Track priority for object blending later
                     line[disp_x as usize].priority = tile.has_priority();
                 }
 
-                line[disp_x as usize].color = palette.get_color(color_idx);
-                line[disp_x as usize].idx = color_idx;
+                let dot = &mut line[disp_x as usize];
+                dot.color = palette.get_color(color_idx, &self.dmg_palette);
+                dot.idx = color_idx;
+                dot.source = tile.source();
+                dot.palette = self.get_tile_palette_number(tile);
             }
         }
     }
@@ -630,7 +875,20 @@ impl LCDController {
             return;
         }
 
-        let mut line = [DotState::new(); LCD_W];
+        // Per-line state starts out as BG color index 0. On DMG, clearing
+        // LCDC bit 0 blanks the BG and window to shade 0 (regardless of BGP).
+        // On CGB, the BG is always drawn and bit 0 only takes away its
+        // priority over objects.
+        let mut line = [DotState {
+            color: if self.cgb {
+                COLOR_DEFAULT
+            } else if self.dmg_on_cgb {
+                self.cram_bg[0]
+            } else {
+                self.dmg_palette[0]
+            },
+            ..DotState::new()
+        }; LCD_W];
 
         // Background
         if self.cgb || self.lcdc & LCDC_BGW_ENABLE == LCDC_BGW_ENABLE {
@@ -649,7 +907,7 @@ impl LCDController {
         }
 
         // The window
-        if self.is_window_active() && scanline >= self.wy as isize {
+        if self.is_window_active() {
             let t_y = self.wly as isize / TILE_H;
             for t_x in 0..BGW_W {
                 let tile = self.get_bgw_tile(t_x, t_y, TileType::Window);
@@ -705,14 +963,231 @@ impl LCDController {
             }
         }
 
-        for (x, c) in line.into_iter().enumerate() {
-            self.output.set_pixel(x, scanline as usize, c.color.into());
+        if let Some(ref mut debug) = self.pixel_debug {
+            let offset = scanline as usize * LCD_W;
+            for (d, c) in debug[offset..(offset + LCD_W)].iter_mut().zip(line) {
+                *d = c.to_debug();
+            }
+        }
+
+        let colors = line.map(|c| {
+            if self.color_correction && (self.cgb || self.dmg_on_cgb) {
+                color_correct(c.color)
+            } else {
+                c.color
+            }
+        });
+        if let Some(ref mut hook) = self.scanline_hook {
+            hook(scanline as u8, &colors);
         }
+        let offset = scanline as usize * LCD_W;
+        self.backbuffer[offset..(offset + LCD_W)].copy_from_slice(&colors);
 
         // Reset current state of tracked registers for next scanline
         self.reg_history[RegHist::BGP.to_usize().unwrap()].fill(self.bgp);
     }
 
+    /// Sets the colors used for the four DMG shades
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    /// Enables color correction in CGB mode (see display::palette::color_correct()).
+    /// Disabled by default, which the display tests rely on.
+    pub fn set_color_correction(&mut self, enable: bool) {
+        self.color_correction = enable;
+    }
+
+    /// Enables recording of per-pixel debug information while rendering
+    /// (see get_debug_scanline()). Disabled by default.
+    pub fn set_pixel_debug(&mut self, enable: bool) {
+        self.pixel_debug = if enable {
+            Some(vec![DotState::new().to_debug(); LCD_W * LCD_H])
+        } else {
+            None
+        };
+    }
+
+    /// Sets a hook that is called after every visible scanline is drawn,
+    /// with the line number and the colors of the line. The line is not
+    /// on the display until the frame completes.
+    pub fn set_scanline_hook(&mut self, hook: Option<ScanlineHook>) {
+        self.scanline_hook = hook;
+    }
+
+    /// Locks or unlocks writes to OPRI (0xFF6C). The boot ROM selects the
+    /// object priority mode, afterwards the register is read-only.
+    pub fn set_opri_writable(&mut self, writable: bool) {
+        self.opri_writable = writable;
+    }
+
+    /// Switches between Gameboy Color mode and DMG compatibility mode,
+    /// which the CGB boot ROM selects for DMG cartridges.
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// Enables DMG-on-CGB rendering, for DMG cartridges running on a CGB
+    /// in compatibility mode: the DMG palettes select colors from the
+    /// compatibility palettes the boot ROM loaded into CRAM, rather than
+    /// from the DMG shades.
+    pub fn set_dmg_on_cgb(&mut self, enable: bool) {
+        self.dmg_on_cgb = enable;
+    }
+
+    /// Amount of times the LCD was disabled outside VBlank, which games
+    /// never do because it can damage a real DMG's screen.
+    pub fn get_unsafe_disables(&self) -> u64 {
+        self.unsafe_disables
+    }
+
+    /// Initializes VRAM (see RamInit). This should happen before the
+    /// system runs.
+    pub fn init_ram(&mut self, init: RamInit) {
+        init.fill(&mut self.vram, RAM_STREAM_VRAM);
+    }
+
+    /// Makes disabling the LCD outside VBlank an error (returned by the
+    /// next tick()), rather than only counting it.
+    pub fn set_strict_disable(&mut self, strict: bool) {
+        self.strict_disable = strict;
+    }
+
+    /// Selects how frames are produced, PpuMode::Accurate by default.
+    /// In PpuMode::Instant, the framebuffer is not updated if nothing
+    /// needs the drawn frames.
+    pub fn set_ppu_mode(&mut self, mode: PpuMode) {
+        self.ppu_mode = mode;
+    }
+
+    /// Skips presenting the next completed frame on the output (see
+    /// frameskip::FrameSkip). The frame is still drawn in the framebuffer.
+    pub fn skip_next_present(&mut self) {
+        self.skip_present = true;
+    }
+
+    pub fn get_ppu_mode(&self) -> PpuMode {
+        self.ppu_mode
+    }
+
+    /// Whether scanlines need to be drawn
+    fn renders(&self) -> bool {
+        self.ppu_mode == PpuMode::Accurate
+            || self.output.shows_frames()
+            || self.scanline_hook.is_some()
+            || self.pixel_debug.is_some()
+    }
+
+    /// Returns the debug information of a scanline of the last frame.
+    /// Returns an empty slice if pixel debugging is not enabled.
+    pub fn get_debug_scanline(&self, y: usize) -> &[PixelDebug] {
+        match self.pixel_debug {
+            Some(ref debug) => &debug[(y * LCD_W)..((y + 1) * LCD_W)],
+            None => &[],
+        }
+    }
+
+    pub(crate) fn get_oam(&self) -> &OAMTable {
+        &self.oam
+    }
+
+    /// Writes data into a VRAM bank, starting at an offset (from
+    /// 0x8000). Unlike writes from the CPU, this ignores the PPU mode
+    /// and VBK (for tests and tools). Panics if the data does not fit
+    /// in the bank.
+    pub fn load_vram(&mut self, bank: usize, offset: usize, data: &[u8]) {
+        let bank = &mut self.vram[(bank * VRAM_SIZE)..((bank + 1) * VRAM_SIZE)];
+        bank[offset..(offset + data.len())].copy_from_slice(data);
+    }
+
+    /// Returns the contents of a VRAM bank (also bank 1 on DMG, which
+    /// is never used there)
+    pub fn dump_vram(&self, bank: usize) -> &[u8] {
+        &self.vram[(bank * VRAM_SIZE)..((bank + 1) * VRAM_SIZE)]
+    }
+
+    /// Replaces the contents of OAM, regardless of the PPU mode
+    pub fn load_oam(&mut self, data: &[u8; OAM_SIZE]) {
+        self.oam.load(data);
+    }
+
+    /// Returns the contents of OAM
+    pub fn dump_oam(&self) -> [u8; OAM_SIZE] {
+        self.oam.dump()
+    }
+
+    /// Replaces the CGB BG palette RAM (8 palettes of 4 colors)
+    pub fn load_cram_bg(&mut self, colors: &[Color; CRAM_ENTRIES]) {
+        self.cram_bg = colors.map(|c| c & COLOR_MASK);
+    }
+
+    /// Replaces the CGB object palette RAM (8 palettes of 4 colors)
+    pub fn load_cram_obj(&mut self, colors: &[Color; CRAM_ENTRIES]) {
+        self.cram_obj = colors.map(|c| c & COLOR_MASK);
+    }
+
+    pub fn dump_cram_bg(&self) -> &[Color; CRAM_ENTRIES] {
+        &self.cram_bg
+    }
+
+    pub fn dump_cram_obj(&self) -> &[Color; CRAM_ENTRIES] {
+        &self.cram_obj
+    }
+
+    pub(crate) fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+
+    /// DMG-on-CGB rendering enabled (see set_dmg_on_cgb())
+    pub fn is_dmg_on_cgb(&self) -> bool {
+        self.dmg_on_cgb
+    }
+
+    /// Returns the colors of a BG palette (for DMG, the palette
+    /// number is ignored and BGP is used).
+    pub(crate) fn get_bg_palette(&self, palidx: u8) -> [Color; CGB_PALETTE_SIZE] {
+        let palette = if self.cgb {
+            let offset = palidx as usize * CGB_PALETTE_SIZE;
+            Palette::CGB(
+                self.cram_bg[offset..(offset + CGB_PALETTE_SIZE)]
+                    .try_into()
+                    .unwrap(),
+            )
+        } else if self.dmg_on_cgb {
+            Palette::Compat(self.bgp, self.get_cram_palette(&TileType::Background, 0))
+        } else {
+            Palette::DMG(self.bgp)
+        };
+        [0, 1, 2, 3].map(|cidx| palette.get_color(cidx, &self.dmg_palette))
+    }
+
+    /// Returns the last completed frame (row-major, LCD_W x LCD_H)
+    pub fn get_framebuffer(&self) -> &[Color] {
+        self.framebuffer.pixels()
+    }
+
+    /// Returns the last completed frame, with conversions to other
+    /// pixel formats
+    pub fn get_frame(&self) -> &FrameBuffer {
+        &self.framebuffer
+    }
+
+    /// Returns the amount of frames (VBlank periods) since power on
+    pub fn get_frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Raises an interrupt request (cpu::INT_VBLANK or cpu::INT_LCDSTAT)
+    fn request_interrupt(&mut self, int: u8) {
+        self.intreq |= int;
+        if int & cpu::INT_VBLANK != 0 {
+            self.intreq_vblank = true;
+        }
+        if int & cpu::INT_LCDSTAT != 0 {
+            self.intreq_stat = true;
+        }
+    }
+
     pub fn get_clr_intreq_stat(&mut self) -> bool {
         let b = self.intreq_stat;
         self.intreq_stat = false;
@@ -745,10 +1220,27 @@ impl LCDController {
         return false;
     }
 
+    /// Updates the LY compare bit and checks the STAT interrupt
+    /// conditions.
+    fn update_stat(&mut self) {
+        if self.ly == self.lyc {
+            self.lcds |= LCDS_LYC;
+        } else {
+            self.lcds &= !LCDS_LYC;
+        }
+
+        if self.check_stat_int(self.lcds) {
+            self.request_interrupt(cpu::INT_LCDSTAT);
+        }
+    }
+
     /// Re-initializes the PPU (e.g. after being disabled).
     fn reset(&mut self) {
         self.dots = Self::DOTS_INIT;
         self.ly = 0;
+        self.wly = 0;
+        self.window_triggered = false;
+        self.transfer_period = Self::TRANSFER_PERIOD;
         self.lcds = self.lcds & !LCDS_STATMODE_MASK | LCDStatMode::Search.to_u8().unwrap();
 
         // After the PPU is re-enabled, the first frame is discarded.
@@ -757,13 +1249,24 @@ impl LCDController {
 }
 
 impl Tickable for LCDController {
-    fn tick(&mut self, ticks: Ticks) -> Result<()> {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
+        if let Some(e) = self.error.take() {
+            return Err(e.into());
+        }
+
         // LCD controller is not affected by double speed
-        let ticks = ticks.get_t_no_ds();
+        self.tick_dots(ticks.get_t_no_ds());
 
+        // Also includes interrupts raised by register writes
+        Ok(std::mem::take(&mut self.intreq))
+    }
+}
+
+impl LCDController {
+    fn tick_dots(&mut self, ticks: usize) {
         if self.lcdc & LCDC_ENABLE == 0 {
             // PPU disabled
-            return Ok(());
+            return;
         }
 
         let old_mode = self.get_stat_mode();
@@ -781,10 +1284,17 @@ impl Tickable for LCDController {
 
             // Check VBlank interrupt
             if old_mode != LCDStatMode::VBlank && new_mode == LCDStatMode::VBlank {
-                self.intreq_vblank = true;
+                self.request_interrupt(cpu::INT_VBLANK);
+                self.frames += 1;
+                if self.renders() {
+                    self.framebuffer
+                        .pixels_mut()
+                        .copy_from_slice(&self.backbuffer);
+                }
 
                 // Reset window line counter
                 self.wly = 0;
+                self.window_triggered = false;
             }
         }
 
@@ -793,10 +1303,22 @@ impl Tickable for LCDController {
             && new_mode == LCDStatMode::Transfer
             && !self.in_vblank()
         {
-            self.draw_scanline(self.ly as isize);
+            // The window Y condition latches for the rest of the frame
+            if self.ly == self.wy && self.lcdc & LCDC_WINDOW_ENABLE == LCDC_WINDOW_ENABLE {
+                self.window_triggered = true;
+            }
+
+            // Objects for the line were selected during mode 2, which
+            // determines when HBlank starts.
+            self.transfer_period = self.mode3_length(self.ly as isize);
+            if self.renders() {
+                self.draw_scanline(self.ly as isize);
+            } else {
+                self.reg_history[RegHist::BGP.to_usize().unwrap()].fill(self.bgp);
+            }
 
             // Window line counter
-            if self.is_window_active() && !self.in_vblank() && self.ly >= self.wy {
+            if self.is_window_active() {
                 self.wly += 1;
             }
         }
@@ -804,23 +1326,18 @@ impl Tickable for LCDController {
         // Update mode register
         self.lcds = (self.lcds & !LCDS_STATMODE_MASK) | self.get_stat_mode().to_u8().unwrap();
 
-        // Check LY compare bit
-        if self.ly == self.lyc {
-            self.lcds |= LCDS_LYC;
-        } else {
-            self.lcds &= !LCDS_LYC;
-        }
-
-        // Check STAT interrupt
-        if self.check_stat_int(self.lcds) {
-            self.intreq_stat = true;
-        }
+        self.update_stat();
 
         if self.in_vblank() {
             if self.redraw_pending {
                 self.redraw_pending = false;
                 if self.skip_frames == 0 {
-                    self.output.render();
+                    if self.skip_present {
+                        self.skip_present = false;
+                    } else {
+                        self.output.blit_frame(&self.framebuffer);
+                        self.output.render();
+                    }
                 } else {
                     self.skip_frames -= 1;
                 }
@@ -828,16 +1345,30 @@ impl Tickable for LCDController {
         } else {
             self.redraw_pending = true;
         }
-
-        Ok(())
     }
 }
 
 impl BusMember for LCDController {
     fn read(&self, addr: u16) -> u8 {
+        // The PPU locks out the CPU from VRAM and palette data during
+        // mode 3 and from OAM during modes 2 and 3.
+        let mode = if self.lcdc & LCDC_ENABLE != 0 {
+            Some(self.get_stat_mode())
+        } else {
+            None
+        };
+        match (addr, mode) {
+            (0x8000..=0x9FFF, Some(LCDStatMode::Transfer)) => 0xFF,
+            (0xFF69 | 0xFF6B, Some(LCDStatMode::Transfer)) if self.cgb => 0xFF,
+            (0xFE00..=0xFE9F, _) if self.oam_blocked() => 0xFF,
+            _ => self.peek(addr),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
         match addr {
             // Video RAM
-            0x8000..=0x9FFF => self.vram[addr as usize - 0x8000 + (VRAM_SIZE * self.vbk as usize)],
+            0x8000..=0x9FFF => self.vram[self.vram_offset(addr as usize)],
 
             // Object Attribute Table (OAM)
             0xFE00..=0xFE9F => self.oam.read(addr as usize - 0xFE00),
@@ -917,18 +1448,34 @@ impl BusMember for LCDController {
 
         match addr {
             // Video RAM
-            0x8000..=0x9FFF => self.vram[addr - 0x8000 + (VRAM_SIZE * self.vbk as usize)] = val,
+            0x8000..=0x9FFF => {
+                let offset = self.vram_offset(addr);
+                self.vram[offset] = val;
+            }
 
             // Object Attribute Table (OAM)
             0xFE00..=0xFE9F => self.oam.write(addr - 0xFE00, val),
 
             // LCDC - LCD control register
             0xFF40 => {
-                if self.lcdc & LCDC_ENABLE == 0 && val & LCDC_ENABLE != 0 {
+                let disabled = self.lcdc & LCDC_ENABLE != 0 && val & LCDC_ENABLE == 0;
+                let mode = self.get_stat_mode();
+                if disabled && mode != LCDStatMode::VBlank {
+                    self.unsafe_disables += 1;
+                    if self.strict_disable {
+                        self.error = Some(LCDError::UnsafeDisable { mode, ly: self.ly });
+                    }
+                }
+
+                let enabled = self.lcdc & LCDC_ENABLE == 0 && val & LCDC_ENABLE != 0;
+                if enabled {
                     // PPU re-enabled
                     self.reset();
                 }
                 self.lcdc = val;
+                if enabled {
+                    self.update_stat();
+                }
             }
 
             // LCDS - LCD status register
@@ -939,7 +1486,7 @@ impl BusMember for LCDController {
                 // source conditions is currently true, unless STAT blocking occurs.
                 if !self.cgb && self.check_stat_int(0xFF) {
                     self.stat_int_line = true;
-                    self.intreq_stat = true;
+                    self.request_interrupt(cpu::INT_LCDSTAT);
                 }
 
                 self.lcds = (self.lcds & !LCDS_MASK) | (val & LCDS_MASK);
@@ -952,7 +1499,13 @@ impl BusMember for LCDController {
             0xFF43 => self.scx = val,
 
             // LYC - LY compare
-            0xFF45 => self.lyc = val,
+            // The comparison is made immediately, not only when LY changes.
+            0xFF45 => {
+                self.lyc = val;
+                if self.lcdc & LCDC_ENABLE != 0 {
+                    self.update_stat();
+                }
+            }
 
             // BGP - Background and window palette
             0xFF47 => {
@@ -986,7 +1539,7 @@ impl BusMember for LCDController {
             0xFF6B if self.cgb => Self::write_xcpd(&mut self.cram_obj, &mut self.ocps, val),
 
             // OPRI - Object Priority Mode
-            0xFF6C if self.cgb => {
+            0xFF6C if self.cgb && self.opri_writable => {
                 self.objpri = if val & 0x01 == 0x01 {
                     ObjPriMode::Coordinate
                 } else {
@@ -999,12 +1552,111 @@ impl BusMember for LCDController {
     }
 }
 
+impl Savestate for LCDController {
+    fn save_state(&self, w: &mut StateWriter) {
+        for &c in self.framebuffer.pixels() {
+            w.put_u16(c);
+        }
+        self.oam.save_state(w);
+        w.put_slice(&self.vram);
+        w.put_slice(&[
+            self.lcdc,
+            self.lcds,
+            self.scy,
+            self.scx,
+            self.wx,
+            self.wy,
+            self.ly,
+            self.wly,
+            self.lyc,
+            self.bgp,
+            self.obp[0],
+            self.obp[1],
+            self.bcps,
+            self.ocps,
+            self.vbk,
+        ]);
+        for &c in self.dump_cram_bg().iter().chain(self.dump_cram_obj()) {
+            w.put_u16(c);
+        }
+        w.put_bool(self.redraw_pending);
+        w.put_u128(self.dots);
+        w.put_u8(self.intreq);
+        w.put_bool(self.intreq_stat);
+        w.put_bool(self.intreq_vblank);
+        w.put_bool(self.stat_int_line);
+        w.put_bool(matches!(self.objpri, ObjPriMode::OAMPosition));
+        w.put_usize(self.skip_frames);
+        w.put_u64(self.frames);
+        for h in &self.reg_history {
+            w.put_slice(h);
+        }
+        w.put_bool(self.window_triggered);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        for c in self.framebuffer.pixels_mut() {
+            *c = r.get_u16()?;
+        }
+        self.oam.load_state(r)?;
+        r.get_slice(&mut self.vram)?;
+        let mut regs = [0; 15];
+        r.get_slice(&mut regs)?;
+        [
+            self.lcdc,
+            self.lcds,
+            self.scy,
+            self.scx,
+            self.wx,
+            self.wy,
+            self.ly,
+            self.wly,
+            self.lyc,
+            self.bgp,
+            self.obp[0],
+            self.obp[1],
+            self.bcps,
+            self.ocps,
+            self.vbk,
+        ] = regs;
+        for c in self.cram_bg.iter_mut().chain(self.cram_obj.iter_mut()) {
+            *c = r.get_u16()?;
+        }
+        self.redraw_pending = r.get_bool()?;
+        self.dots = r.get_u128()?;
+        self.intreq = r.get_u8()?;
+        self.intreq_stat = r.get_bool()?;
+        self.intreq_vblank = r.get_bool()?;
+        self.stat_int_line = r.get_bool()?;
+        self.objpri = if r.get_bool()? {
+            ObjPriMode::OAMPosition
+        } else {
+            ObjPriMode::Coordinate
+        };
+        self.skip_frames = r.get_usize()?;
+        self.frames = r.get_u64()?;
+        for h in self.reg_history.iter_mut() {
+            r.get_slice(h)?;
+        }
+        self.window_triggered = r.get_bool()?;
+
+        // Push the restored frame to the output
+        self.backbuffer.copy_from_slice(self.framebuffer.pixels());
+        self.output.blit_frame(&self.framebuffer);
+        self.output.render();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use crate::display::display::NullDisplay;
 
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
     #[test]
     fn tile_decode() {
         let tile = [0x3C, 0x7E];
@@ -1015,6 +1667,16 @@ mod tests {
         }
     }
 
+    /// Runs a test on a DMG LCD controller in each PpuMode, which
+    /// should not make a difference to timing.
+    fn for_each_mode(test: impl Fn(LCDController)) {
+        for mode in [PpuMode::Accurate, PpuMode::Instant] {
+            let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+            c.set_ppu_mode(mode);
+            test(c);
+        }
+    }
+
     #[test]
     fn statmode() {
         fn next(l: &mut LCDController) {
@@ -1024,178 +1686,314 @@ mod tests {
             }
         }
 
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
-        assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
-
-        for _ in 0..LCDController::VBLANK_START {
+        for_each_mode(|mut c| {
             assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
-            next(&mut c);
-            assert_eq!(c.get_stat_mode(), LCDStatMode::Transfer);
-            next(&mut c);
-            assert_eq!(c.get_stat_mode(), LCDStatMode::HBlank);
-            next(&mut c);
-        }
-        assert_eq!(c.get_stat_mode(), LCDStatMode::VBlank);
+
+            for _ in 0..LCDController::VBLANK_START {
+                assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
+                next(&mut c);
+                assert_eq!(c.get_stat_mode(), LCDStatMode::Transfer);
+                next(&mut c);
+                assert_eq!(c.get_stat_mode(), LCDStatMode::HBlank);
+                next(&mut c);
+            }
+            assert_eq!(c.get_stat_mode(), LCDStatMode::VBlank);
+        });
     }
 
     #[test]
     fn vblank() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
-        for _ in
-            (LCDController::DOTS_INIT as usize)..(LCD_H * LCDController::DOTS_PER_LINE as usize)
-        {
-            assert!(!c.in_vblank());
-            c.tick(Ticks::from_t(1)).unwrap();
-        }
-        assert!(c.in_vblank());
+        for_each_mode(|mut c| {
+            for _ in
+                (LCDController::DOTS_INIT as usize)..(LCD_H * LCDController::DOTS_PER_LINE as usize)
+            {
+                assert!(!c.in_vblank());
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(c.in_vblank());
+        });
     }
 
     #[test]
     fn int_stat_lyc() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
-        c.write(0xFF45, 10);
-        c.write(0xFF41, LCDS_INT_LYC);
-        c.get_clr_intreq_stat(); // Clear STAT write glitch
+        for_each_mode(|mut c| {
+            c.write(0xFF45, 10);
+            c.write(0xFF41, LCDS_INT_LYC);
+            c.get_clr_intreq_stat(); // Clear STAT write glitch
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
-        assert!(c.read(0xFF41) & LCDS_LYC != LCDS_LYC);
+            c.tick(Ticks::from_t(1)).unwrap();
+            assert!(!c.get_clr_intreq_stat());
+            assert!(c.read(0xFF41) & LCDS_LYC != LCDS_LYC);
+
+            while c.ly != 10 {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(c.read(0xFF41) & LCDS_LYC == LCDS_LYC);
+            assert!(c.get_clr_intreq_stat());
+            assert!(!c.get_clr_intreq_stat());
 
-        while c.ly != 10 {
             c.tick(Ticks::from_t(1)).unwrap();
-        }
-        assert!(c.read(0xFF41) & LCDS_LYC == LCDS_LYC);
-        assert!(c.get_clr_intreq_stat());
-        assert!(!c.get_clr_intreq_stat());
+            assert!(c.read(0xFF41) & LCDS_LYC == LCDS_LYC);
+            assert!(!c.get_clr_intreq_stat());
+        });
+    }
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(c.read(0xFF41) & LCDS_LYC == LCDS_LYC);
-        assert!(!c.get_clr_intreq_stat());
+    #[test]
+    fn int_stat_lyc_write() {
+        for_each_mode(|mut c| {
+            c.write(0xFF41, LCDS_INT_LYC);
+            c.write(0xFF45, 0xFF);
+            c.get_clr_intreq_stat(); // Clear STAT write glitch
+
+            while c.ly != 10 {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(!c.get_clr_intreq_stat());
+
+            // Matching LYC, no tick needed
+            c.write(0xFF45, 10);
+            assert!(c.read(0xFF41) & LCDS_LYC == LCDS_LYC);
+            assert!(c.get_clr_intreq_stat());
+
+            // Not matching anymore
+            c.write(0xFF45, 11);
+            assert!(c.read(0xFF41) & LCDS_LYC != LCDS_LYC);
+            assert!(!c.get_clr_intreq_stat());
+        });
     }
 
     #[test]
     fn int_stat_vblank() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
-        c.write(0xFF41, LCDS_INT_STAT_VBLANK);
-        c.get_clr_intreq_stat(); // Clear STAT write glitch
-
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
+        for_each_mode(|mut c| {
+            c.write(0xFF41, LCDS_INT_STAT_VBLANK);
+            c.get_clr_intreq_stat(); // Clear STAT write glitch
 
-        while !c.in_vblank() {
             c.tick(Ticks::from_t(1)).unwrap();
-        }
-        assert!(c.get_clr_intreq_stat());
-        assert!(!c.get_clr_intreq_stat());
+            assert!(!c.get_clr_intreq_stat());
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
+            while !c.in_vblank() {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(c.get_clr_intreq_stat());
+            assert!(!c.get_clr_intreq_stat());
+
+            c.tick(Ticks::from_t(1)).unwrap();
+            assert!(!c.get_clr_intreq_stat());
+        });
     }
 
     #[test]
     fn int_stat_hblank() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
-        c.write(0xFF41, LCDS_INT_STAT_HBLANK);
-        c.get_clr_intreq_stat(); // Clear STAT write glitch
+        for_each_mode(|mut c| {
+            c.write(0xFF41, LCDS_INT_STAT_HBLANK);
+            c.get_clr_intreq_stat(); // Clear STAT write glitch
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
+            c.tick(Ticks::from_t(1)).unwrap();
+            assert!(!c.get_clr_intreq_stat());
 
+            while c.get_stat_mode() != LCDStatMode::HBlank {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(c.get_clr_intreq_stat());
+            assert!(!c.get_clr_intreq_stat());
+
+            c.tick(Ticks::from_t(1)).unwrap();
+            assert!(!c.get_clr_intreq_stat());
+        });
+    }
+
+    /// Runs to the next HBlank, returns the dot in the line it started at
+    fn hblank_start(c: &mut LCDController) -> u128 {
+        while c.get_stat_mode() == LCDStatMode::HBlank {
+            c.tick(Ticks::from_t(1)).unwrap();
+        }
         while c.get_stat_mode() != LCDStatMode::HBlank {
             c.tick(Ticks::from_t(1)).unwrap();
         }
-        assert!(c.get_clr_intreq_stat());
-        assert!(!c.get_clr_intreq_stat());
+        c.dots % LCDController::DOTS_PER_LINE
+    }
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
+    #[test]
+    fn mode_lengths() {
+        for_each_mode(|mut c| {
+            // Align to the start of a line
+            hblank_start(&mut c);
+            while c.get_stat_mode() != LCDStatMode::Search {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+
+            // Without penalties: 80 dots mode 2, 172 dots mode 3, the
+            // remaining 204 dots HBlank
+            for (mode, len) in [
+                (LCDStatMode::Search, 80),
+                (LCDStatMode::Transfer, 172),
+                (LCDStatMode::HBlank, 204),
+            ] {
+                let mut dots = 0;
+                while c.get_stat_mode() == mode {
+                    c.tick(Ticks::from_t(1)).unwrap();
+                    dots += 1;
+                }
+                assert_eq!(dots, len, "{:?}", mode);
+            }
+        });
     }
 
     #[test]
-    fn int_stat_oam() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
+    fn mode3_scx_penalty() {
+        for_each_mode(|mut c| {
+            let base = hblank_start(&mut c);
+            assert_eq!(
+                base,
+                LCDController::SEARCH_PERIOD + LCDController::TRANSFER_PERIOD
+            );
+
+            c.write(0xFF43, 7);
+            assert_eq!(hblank_start(&mut c), base + 7);
+            c.write(0xFF43, 8);
+            assert_eq!(hblank_start(&mut c), base);
+        });
+    }
 
-        while c.get_stat_mode() == LCDStatMode::Search {
+    #[test]
+    fn mode3_obj_penalty() {
+        for_each_mode(|mut c| {
+            c.write(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE);
+            let base = hblank_start(&mut c);
+
+            // Aligned with the BG fetcher: 11 dots
+            for (i, val) in [16, 8].into_iter().enumerate() {
+                c.write(0xFE00 + i as u16, val);
+            }
+            assert_eq!(hblank_start(&mut c), base + 11);
+
+            // Second object 5 pixels into a tile: 6 dots
+            for (i, val) in [16, 13].into_iter().enumerate() {
+                c.write(0xFE04 + i as u16, val);
+            }
+            assert_eq!(hblank_start(&mut c), base + 11 + 6);
+
+            // Objects disabled
+            c.write(0xFF40, LCDC_ENABLE);
+            assert_eq!(hblank_start(&mut c), base);
+        });
+    }
+
+    #[test]
+    fn hblank_int_scx() {
+        for_each_mode(|mut c| {
+            c.write(0xFF41, LCDS_INT_STAT_HBLANK);
+            c.write(0xFF43, 7);
+            c.get_clr_intreq_stat(); // Clear STAT write glitch
+
+            while c.dots % LCDController::DOTS_PER_LINE
+                < LCDController::SEARCH_PERIOD + LCDController::TRANSFER_PERIOD + 6
+            {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(!c.get_clr_intreq_stat());
             c.tick(Ticks::from_t(1)).unwrap();
-        }
+            assert_eq!(c.get_stat_mode(), LCDStatMode::HBlank);
+            assert!(c.get_clr_intreq_stat());
+        });
+    }
 
-        c.write(0xFF41, LCDS_INT_STAT_OAM);
-        c.get_clr_intreq_stat(); // Clear STAT write glitch
+    #[test]
+    fn int_stat_oam() {
+        for_each_mode(|mut c| {
+            while c.get_stat_mode() == LCDStatMode::Search {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
+            c.write(0xFF41, LCDS_INT_STAT_OAM);
+            c.get_clr_intreq_stat(); // Clear STAT write glitch
 
-        while c.get_stat_mode() != LCDStatMode::Search {
             c.tick(Ticks::from_t(1)).unwrap();
-        }
-        assert!(c.get_clr_intreq_stat());
-        assert!(!c.get_clr_intreq_stat());
+            assert!(!c.get_clr_intreq_stat());
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_stat());
+            while c.get_stat_mode() != LCDStatMode::Search {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(c.get_clr_intreq_stat());
+            assert!(!c.get_clr_intreq_stat());
+
+            c.tick(Ticks::from_t(1)).unwrap();
+            assert!(!c.get_clr_intreq_stat());
+        });
     }
 
     #[test]
     fn int_stat_quirk() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
+        for_each_mode(|mut c| {
+            assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
 
-        assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
+            c.write(0xFF45, 1); // LYC
+            assert!(!c.get_clr_intreq_stat());
+            c.write(0xFF41, 0);
+            // Triggered by LY = 0, OAM
+            assert!(c.get_clr_intreq_stat());
 
-        c.write(0xFF45, 1); // LYC
-        assert!(!c.get_clr_intreq_stat());
-        c.write(0xFF41, 0);
-        // Triggered by LY = 0, OAM
-        assert!(c.get_clr_intreq_stat());
+            while c.get_stat_mode() != LCDStatMode::Transfer {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            // NOT triggered by LY = 0, transfer
+            c.write(0xFF41, 0);
+            assert!(!c.get_clr_intreq_stat());
 
-        while c.get_stat_mode() != LCDStatMode::Transfer {
-            c.tick(Ticks::from_t(1)).unwrap();
-        }
-        // NOT triggered by LY = 0, transfer
-        c.write(0xFF41, 0);
-        assert!(!c.get_clr_intreq_stat());
+            while c.get_stat_mode() != LCDStatMode::HBlank {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            // Triggered by LY = 0, HBlank
+            c.write(0xFF41, 0);
+            assert!(c.get_clr_intreq_stat());
 
-        while c.get_stat_mode() != LCDStatMode::HBlank {
-            c.tick(Ticks::from_t(1)).unwrap();
-        }
-        // Triggered by LY = 0, HBlank
-        c.write(0xFF41, 0);
-        assert!(c.get_clr_intreq_stat());
+            while c.get_stat_mode() != LCDStatMode::Transfer {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            // Triggered by LY = 1, LY=LYC
+            c.write(0xFF41, 0);
+            assert!(c.get_clr_intreq_stat());
 
-        while c.get_stat_mode() != LCDStatMode::Transfer {
-            c.tick(Ticks::from_t(1)).unwrap();
-        }
-        // Triggered by LY = 1, LY=LYC
-        c.write(0xFF41, 0);
-        assert!(c.get_clr_intreq_stat());
+            while c.get_stat_mode() != LCDStatMode::VBlank {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            // Triggered by VBlank
+            c.write(0xFF41, 0);
+            assert!(c.get_clr_intreq_stat());
+        });
+    }
 
-        while c.get_stat_mode() != LCDStatMode::VBlank {
-            c.tick(Ticks::from_t(1)).unwrap();
+    #[test]
+    fn int_stat_quirk_models() {
+        for model in [Model::Dmg, Model::Mgb, Model::Sgb, Model::Cgb, Model::Agb] {
+            let mut c = LCDController::new(Box::new(NullDisplay::new()), model);
+            assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
+            c.write(0xFF41, 0);
+            // Only DMG-based models have the STAT write quirk
+            assert_eq!(c.get_clr_intreq_stat(), !model.is_cgb(), "{:?}", model);
         }
-        // Triggered by VBlank
-        c.write(0xFF41, 0);
-        assert!(c.get_clr_intreq_stat());
     }
 
     #[test]
     fn int_vblank() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
+        for_each_mode(|mut c| {
+            c.tick(Ticks::from_t(1)).unwrap();
+            assert!(!c.get_clr_intreq_vblank());
 
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_vblank());
+            while !c.in_vblank() {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+            assert!(c.get_clr_intreq_vblank());
+            assert!(!c.get_clr_intreq_vblank());
 
-        while !c.in_vblank() {
             c.tick(Ticks::from_t(1)).unwrap();
-        }
-        assert!(c.get_clr_intreq_vblank());
-        assert!(!c.get_clr_intreq_vblank());
-
-        c.tick(Ticks::from_t(1)).unwrap();
-        assert!(!c.get_clr_intreq_vblank());
+            assert!(!c.get_clr_intreq_vblank());
+        });
     }
 
     #[test]
     fn int_vblank_lcdc_disable() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), false);
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
 
         c.write(0xFF40, LCDC_ENABLE);
         c.tick(Ticks::from_t(1)).unwrap();
@@ -1217,6 +2015,46 @@ mod tests {
         assert!(!c.get_clr_intreq_vblank());
     }
 
+    #[test]
+    fn unsafe_disable() {
+        fn run_to_mode(c: &mut LCDController, mode: LCDStatMode) {
+            while c.get_stat_mode() != mode {
+                c.tick(Ticks::from_t(1)).unwrap();
+            }
+        }
+
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        run_to_mode(&mut c, LCDStatMode::Transfer);
+        c.write(0xFF40, 0);
+        assert_eq!(c.get_unsafe_disables(), 1);
+        // Not strict, so not an error
+        c.tick(Ticks::from_t(1)).unwrap();
+
+        // Already disabled
+        c.write(0xFF40, 0);
+        assert_eq!(c.get_unsafe_disables(), 1);
+
+        c.write(0xFF40, LCDC_ENABLE);
+        run_to_mode(&mut c, LCDStatMode::VBlank);
+        c.write(0xFF40, 0);
+        assert_eq!(c.get_unsafe_disables(), 1);
+
+        c.set_strict_disable(true);
+        c.write(0xFF40, LCDC_ENABLE);
+        run_to_mode(&mut c, LCDStatMode::Transfer);
+        c.write(0xFF40, 0);
+        assert_eq!(c.get_unsafe_disables(), 2);
+        let e = c.tick(Ticks::from_t(1)).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<LCDError>(),
+            Some(&LCDError::UnsafeDisable {
+                mode: LCDStatMode::Transfer,
+                ly: 0
+            })
+        );
+        c.tick(Ticks::from_t(1)).unwrap();
+    }
+
     fn test_cram(xcps_addr: u16, xcpd_addr: u16, lcd: &mut LCDController) {
         macro_rules! read_cram {
             ($entry:expr) => {
@@ -1263,19 +2101,19 @@ mod tests {
 
     #[test]
     fn cram_bg() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), true);
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
         test_cram(0xFF68, 0xFF69, &mut c);
     }
 
     #[test]
     fn cram_obj() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), true);
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
         test_cram(0xFF6A, 0xFF6B, &mut c);
     }
 
     #[test]
     fn vram_bank_switching() {
-        let mut c = LCDController::new(Box::new(NullDisplay::new()), true);
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
 
         c.write(0x8000, 0xAA);
         assert_eq!(c.vram[0], 0xAA);
@@ -1288,4 +2126,807 @@ mod tests {
         assert_eq!(c.read(0x8000), 0xBB);
         assert_eq!(c.vram[VRAM_SIZE], 0xBB);
     }
+
+    #[test]
+    fn dmg_ignores_cgb_registers() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        for addr in [0xFF4F, 0xFF68, 0xFF69, 0xFF6A, 0xFF6B] {
+            c.write(addr, 0x01);
+            assert_eq!(c.read(addr), 0xFF, "{:04X}", addr);
+        }
+        assert_eq!(c.vbk, 0);
+        assert_eq!(c.bcps, 0);
+        assert_eq!(c.ocps, 0);
+
+        // Writes only reach bank 0
+        c.write(0x8000, 0xAA);
+        assert_eq!(c.vram[0], 0xAA);
+        assert_eq!(c.vram[VRAM_SIZE], 0);
+
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        c.write(0xFF4F, 0x01);
+        assert_eq!(c.read(0xFF4F), 0x01);
+        c.write(0xFF68, 0x01);
+        assert_eq!(c.read(0xFF68), 0x01);
+    }
+
+    #[test]
+    fn vram_bank_dmg_compat() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        c.write(0xFF4F, 1);
+        c.write(0x8000, 0xBB);
+        assert_eq!(c.vram[VRAM_SIZE], 0xBB);
+
+        // The selected bank no longer applies
+        c.set_cgb_mode(false);
+        assert_eq!(c.read(0x8000), 0x00);
+        c.write(0x8000, 0xAA);
+        assert_eq!(c.vram[0], 0xAA);
+        assert_eq!(c.vram[VRAM_SIZE], 0xBB);
+    }
+
+    #[test]
+    fn dmg_on_cgb_palettes() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        // Compatibility palettes, as loaded by the boot ROM
+        c.load_cram_bg(&std::array::from_fn(|i| 0x0100 | i as Color));
+        c.load_cram_obj(&std::array::from_fn(|i| 0x0200 | i as Color));
+        c.set_cgb_mode(false);
+        c.set_dmg_on_cgb(true);
+
+        // Tile 1: color indices 0, 0, 1, 1, 2, 2, 3, 3
+        for row in 0..8 {
+            c.write(0x8010 + row * 2, 0x33);
+            c.write(0x8011 + row * 2, 0x0F);
+        }
+        // Objects with garbage in the CGB palette bits, using OBP0 and OBP1
+        c.write(0xFE00, 16);
+        c.write(0xFE01, 8);
+        c.write(0xFE02, 1);
+        c.write(0xFE03, 0x07);
+        c.write(0xFE04, 16);
+        c.write(0xFE05, 16);
+        c.write(0xFE06, 1);
+        c.write(0xFE07, 0x17);
+        c.write(0xFF47, 0x1B);
+        c.write(0xFF48, 0x1B);
+        c.write(0xFF49, 0xE4);
+        c.write(
+            0xFF40,
+            LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_BGW_ENABLE | LCDC_OBJ_ENABLE,
+        );
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert_eq!(
+            c.get_framebuffer()[0..24],
+            [
+                0x0103, 0x0103, 0x0202, 0x0202, 0x0201, 0x0201, 0x0200, 0x0200, //
+                0x0103, 0x0103, 0x0205, 0x0205, 0x0206, 0x0206, 0x0207, 0x0207, //
+                0x0103, 0x0103, 0x0103, 0x0103, 0x0103, 0x0103, 0x0103, 0x0103,
+            ]
+        );
+        assert_eq!(c.get_bg_palette(0), [0x0103, 0x0102, 0x0101, 0x0100]);
+
+        // DMG rules: LCDC bit 0 blanks the BG to color 0
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_OBJ_ENABLE);
+        run_frame(&mut c);
+        assert_eq!(c.get_framebuffer()[0..4], [0x0100, 0x0100, 0x0202, 0x0202]);
+        assert_eq!(c.get_framebuffer()[16], 0x0100);
+    }
+
+    #[test]
+    fn dmg_vram_bank1_unused() {
+        fn render(garbage: bool) -> Vec<Color> {
+            let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+            if garbage {
+                // Tile attributes and tile data in bank 1
+                let mut state = 0x12345678u32;
+                for b in c.vram[VRAM_SIZE..].iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *b = state as u8;
+                }
+            }
+            for i in 0..16 {
+                c.write(0x8010 + i, 0x0F);
+            }
+            for i in 0..(32 * 32) {
+                c.write(0x9800 + i, (i % 2) as u8);
+            }
+            // An object from the same tile
+            c.write(0xFE00, 32);
+            c.write(0xFE01, 16);
+            c.write(0xFE02, 1);
+            c.write(0xFE03, 0x1F);
+            c.write(0xFF47, 0xE4);
+            c.write(0xFF49, 0x1B);
+            c.write(
+                0xFF40,
+                LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_BGW_ENABLE | LCDC_OBJ_ENABLE,
+            );
+            run_frame(&mut c);
+            run_frame(&mut c);
+            c.get_framebuffer().to_vec()
+        }
+
+        assert_eq!(render(true), render(false));
+    }
+
+    #[test]
+    fn bulk_access() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        // Regardless of the PPU mode
+        c.write(0xFF40, LCDC_ENABLE);
+        while c.get_stat_mode() != LCDStatMode::Transfer {
+            c.tick(Ticks::from_t(1)).unwrap();
+        }
+
+        c.load_vram(1, 0x1FFE, &[0x12, 0x34]);
+        assert_eq!(c.dump_vram(1)[0x1FFE..], [0x12, 0x34]);
+        assert!(c.dump_vram(0).iter().all(|&b| b == 0));
+        assert_eq!(c.peek(0x9FFF), 0x00);
+        c.write(0xFF4F, 1);
+        assert_eq!(c.peek(0x9FFF), 0x34);
+
+        let oam: [u8; OAM_SIZE] = std::array::from_fn(|i| i as u8);
+        c.load_oam(&oam);
+        assert_eq!(c.dump_oam(), oam);
+        assert_eq!(c.peek(0xFE05), 0x05);
+
+        let cram: [Color; CRAM_ENTRIES] = std::array::from_fn(|i| 0x8000 | i as Color);
+        c.load_cram_obj(&cram);
+        assert_eq!(c.dump_cram_obj()[3], 0x0003);
+        c.write(0xFF6A, 3 * 2);
+        assert_eq!(c.peek(0xFF6B), 0x03);
+        assert!(c.dump_cram_bg().iter().all(|&c| c == 0x7F));
+    }
+
+    /// Encodes a tile, with the color index of each pixel given by
+    /// a function of (x, y)
+    fn make_tile(pixel: impl Fn(usize, usize) -> u8) -> [u8; TILE_BSIZE] {
+        let mut tile = [0; TILE_BSIZE];
+        for y in 0..8 {
+            for x in 0..8 {
+                let c = pixel(x, y);
+                tile[y * 2] |= (c & 1) << (7 - x);
+                tile[y * 2 + 1] |= (c >> 1) << (7 - x);
+            }
+        }
+        tile
+    }
+
+    /// DMG LCD showing a checkerboard of tile 0 (blank) and tile 1 (the
+    /// color index of each pixel is its row % 4), at SCX 3, SCY 5.
+    fn checkerboard() -> LCDController {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        c.load_vram(0, 0x0010, &make_tile(|_, y| (y % 4) as u8));
+        let map: Vec<u8> = (0..(32 * 32))
+            .map(|i| ((i % 32 + i / 32) % 2) as u8)
+            .collect();
+        c.load_vram(0, 0x1800, &map);
+        c.write(0xFF42, 5);
+        c.write(0xFF43, 3);
+        c.write(0xFF47, 0xE4);
+        c.write(0xFF48, 0xE4);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_BGW_ENABLE);
+        c
+    }
+
+    /// Color index of the checkerboard at a screen position
+    fn checkerboard_idx(x: usize, y: usize) -> usize {
+        let (bx, by) = (x + 3, y + 5);
+        if (bx / 8 + by / 8) % 2 == 1 {
+            by % 8 % 4
+        } else {
+            0
+        }
+    }
+
+    /// Renders two frames, returns a scanline of the last one
+    fn render_scanline(c: &mut LCDController, y: usize) -> Vec<Color> {
+        run_frame(c);
+        run_frame(c);
+        c.get_framebuffer()[(y * LCD_W)..((y + 1) * LCD_W)].to_vec()
+    }
+
+    #[test]
+    fn scanline_bg_scroll() {
+        let mut c = checkerboard();
+        let line = render_scanline(&mut c, 9);
+
+        // BG line 14: row 6 of the first row of tiles, starting 3
+        // pixels into the first tile
+        let start = [2, 2, 2, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2];
+        assert_eq!(line[..16], start.map(|i| DMG_GREY[i]));
+        for (x, &p) in line.iter().enumerate() {
+            assert_eq!(p, DMG_GREY[checkerboard_idx(x, 9)], "x {}", x);
+        }
+    }
+
+    #[test]
+    fn scanline_obj_flip() {
+        // Row 0: 3 1 0 0 0 0 0 0, row 7: 2 0 0 0 0 0 0 0
+        let obj = make_tile(|x, y| match (x, y) {
+            (0, 0) => 3,
+            (1, 0) => 1,
+            (0, 7) => 2,
+            _ => 0,
+        });
+        for (flags, pixels) in [
+            (0, [3, 1, 0, 0, 0, 0, 0, 0]),
+            (TILEATTR_FLIP_X, [0, 0, 0, 0, 0, 0, 1, 3]),
+            (TILEATTR_FLIP_Y, [2, 0, 0, 0, 0, 0, 0, 0]),
+            (TILEATTR_FLIP_X | TILEATTR_FLIP_Y, [0, 0, 0, 0, 0, 0, 0, 2]),
+        ] {
+            let mut c = checkerboard();
+            c.load_vram(0, 0x0020, &obj);
+            // Tile 2, top left at (16, 9)
+            let mut oam = [0; OAM_SIZE];
+            oam[..4].copy_from_slice(&[9 + 16, 16 + 8, 2, flags]);
+            c.load_oam(&oam);
+            c.write(0xFF40, c.read(0xFF40) | LCDC_OBJ_ENABLE);
+
+            let line = render_scanline(&mut c, 9);
+            for (x, &p) in line.iter().enumerate() {
+                // Color index 0 is transparent
+                let idx = match x.checked_sub(16) {
+                    Some(i) if i < 8 && pixels[i] != 0 => pixels[i],
+                    _ => checkerboard_idx(x, 9),
+                };
+                assert_eq!(p, DMG_GREY[idx], "flags {:02X} x {}", flags, x);
+            }
+        }
+    }
+
+    #[test]
+    fn scanline_window() {
+        let mut c = checkerboard();
+        // Tile 3: the color index of each pixel is (row + 1) % 4
+        c.load_vram(0, 0x0030, &make_tile(|_, y| ((y + 1) % 4) as u8));
+        c.load_vram(0, 0x1C00, &[3; 32 * 32]);
+        c.write(0xFF4A, 3);
+        c.write(0xFF4B, 80 + 7);
+        c.write(
+            0xFF40,
+            c.read(0xFF40) | LCDC_WINDOW_ENABLE | LCDC_WINDOW_TILEMAP,
+        );
+
+        // Window line 6, not scrolled
+        let line = render_scanline(&mut c, 9);
+        for (x, &p) in line.iter().enumerate() {
+            let idx = if x >= 80 { 3 } else { checkerboard_idx(x, 9) };
+            assert_eq!(p, DMG_GREY[idx], "x {}", x);
+        }
+    }
+
+    #[test]
+    fn peek_blocked() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        c.write(0xFF40, LCDC_ENABLE);
+        c.write(0x8000, 0xAA);
+        c.write(0xFE00, 0x12);
+        c.write(0xFF68, 0x02 | XCPS_AUTO_INC);
+        c.write(0xFF69, 0x34);
+
+        // OAM search, OAM is blocked
+        assert_eq!(c.get_stat_mode(), LCDStatMode::Search);
+        assert_eq!(c.read(0xFE00), 0xFF);
+        assert_eq!(c.peek(0xFE00), 0x12);
+        assert_eq!(c.read(0x8000), 0xAA);
+
+        // Transfer, everything is blocked
+        while c.get_stat_mode() != LCDStatMode::Transfer {
+            c.tick(Ticks::from_t(1)).unwrap();
+        }
+        assert_eq!(c.read(0x8000), 0xFF);
+        assert_eq!(c.peek(0x8000), 0xAA);
+        assert_eq!(c.read(0xFE00), 0xFF);
+        assert_eq!(c.peek(0xFE00), 0x12);
+        c.write(0xFF68, 0x02 | XCPS_AUTO_INC);
+        assert_eq!(c.read(0xFF69), 0xFF);
+        assert_eq!(c.peek(0xFF69), 0x34);
+        assert_eq!(c.peek(0xFF69), 0x34);
+        // Peeking does not advance the palette index
+        assert_eq!(c.peek(0xFF68), 0x02 | XCPS_AUTO_INC);
+
+        // HBlank, nothing is blocked
+        while c.get_stat_mode() != LCDStatMode::HBlank {
+            c.tick(Ticks::from_t(1)).unwrap();
+        }
+        for addr in [0x8000, 0xFE00, 0xFF69] {
+            assert_eq!(c.read(addr), c.peek(addr));
+        }
+    }
+
+    fn run_frame(c: &mut LCDController) {
+        let frame = c.get_frame_count();
+        while c.get_frame_count() == frame {
+            c.tick(Ticks::from_t(4)).unwrap();
+        }
+    }
+
+    #[test]
+    fn dmg_palette() {
+        use crate::display::palette::{DMG_GREEN, DMG_GREY};
+
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_ENABLE);
+        // Color index 0 (empty tiles) is the darkest shade
+        c.write(0xFF47, 0x03);
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == DMG_GREY[3]));
+
+        c.set_dmg_palette(DMG_GREEN);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == DMG_GREEN[3]));
+    }
+
+    #[test]
+    fn cgb_color_correction() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_ENABLE);
+        // BG palette 0, color 0: red
+        c.write(0xFF68, XCPS_AUTO_INC);
+        c.write(0xFF69, 0x1F);
+        c.write(0xFF69, 0x00);
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == 0x001F));
+
+        c.set_color_correction(true);
+        run_frame(&mut c);
+        assert!(c
+            .get_framebuffer()
+            .iter()
+            .all(|&p| p == color_correct(0x001F)));
+    }
+
+    #[test]
+    fn instant_mode() {
+        use crate::display::palette::DMG_GREY;
+
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        c.set_ppu_mode(PpuMode::Instant);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_ENABLE);
+        c.write(0xFF47, 0x02);
+        let before = c.get_framebuffer().to_vec();
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert_eq!(c.get_frame_count(), 2);
+        assert_eq!(c.get_framebuffer(), before);
+
+        // Someone is looking, so frames are drawn
+        c.set_scanline_hook(Some(Box::new(|_, _| ())));
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == DMG_GREY[2]));
+    }
+
+    #[test]
+    fn pixel_debug() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        c.set_pixel_debug(true);
+        c.write(
+            0xFF40,
+            LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_OBJ_ENABLE | LCDC_BGW_ENABLE,
+        );
+
+        // BG tile 0: left half color 1, right half color 0
+        for y in 0..8 {
+            c.write(0x8000 + y * 2, 0xF0);
+        }
+        // Object tile 1: color 3
+        for i in 0..16 {
+            c.write(0x8010 + i, 0xFF);
+        }
+        // Object at (4, 0) using OBP1, object at (4, 8) behind the BG
+        for (i, val) in [16, 12, 1, 0x10, 24, 12, 1, 0x80].into_iter().enumerate() {
+            c.write(0xFE00 + i as u16, val);
+        }
+        run_frame(&mut c);
+        run_frame(&mut c);
+
+        let bg = PixelDebug {
+            idx: 1,
+            source: PixelSource::Background,
+            palette: 0,
+            priority: PixelPriority::NoObject,
+            color: c.get_framebuffer()[0],
+        };
+        let line = c.get_debug_scanline(0);
+        assert_eq!(line.len(), LCD_W);
+        assert_eq!(line[0], bg);
+        assert_eq!(line[4].source, PixelSource::Object);
+        assert_eq!(line[4].idx, 3);
+        assert_eq!(line[4].palette, 1);
+        assert_eq!(line[4].priority, PixelPriority::Object);
+        // Object over BG color 1
+        assert_eq!(line[8].source, PixelSource::Object);
+        assert_eq!(line[16], bg);
+
+        let line = c.get_debug_scanline(8);
+        assert_eq!(line[4].source, PixelSource::Object);
+        assert_eq!(line[4].palette, 0);
+        // BG priority
+        assert_eq!(
+            line[8],
+            PixelDebug {
+                priority: PixelPriority::BackgroundPriority,
+                ..bg
+            }
+        );
+        assert_eq!(line[16], bg);
+
+        c.set_pixel_debug(false);
+        assert!(c.get_debug_scanline(0).is_empty());
+    }
+
+    /// Sets up BG tile 0 with color 1 and object tile 1 with color 3,
+    /// with an object at (0, 0).
+    fn setup_bg_obj(c: &mut LCDController, obj_flags: u8) {
+        for i in 0..16 {
+            c.write(0x8000 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+            c.write(0x8010 + i, 0xFF);
+        }
+        for (i, val) in [16, 8, 1, obj_flags].into_iter().enumerate() {
+            c.write(0xFE00 + i as u16, val);
+        }
+    }
+
+    #[test]
+    fn bgw_disable_dmg() {
+        use crate::display::palette::DMG_GREEN;
+
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        c.set_dmg_palette(DMG_GREEN);
+        // Object behind the BG
+        setup_bg_obj(&mut c, 0x80);
+        c.write(0xFF47, 0xFF);
+        c.write(0xFF48, 0xE4);
+        c.write(0xFF4A, 0);
+        c.write(0xFF4B, 7);
+        c.write(
+            0xFF40,
+            LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_OBJ_ENABLE | LCDC_BGW_ENABLE,
+        );
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == DMG_GREEN[3]));
+
+        // BG and window (also enabled) are blank, the object is drawn
+        // regardless of its priority.
+        c.write(
+            0xFF40,
+            LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_OBJ_ENABLE | LCDC_WINDOW_ENABLE,
+        );
+        run_frame(&mut c);
+        let fb = c.get_framebuffer();
+        for y in 0..LCD_H {
+            for x in 0..LCD_W {
+                let expected = if x < 8 && y < 8 { 3 } else { 0 };
+                assert_eq!(fb[y * LCD_W + x], DMG_GREEN[expected], "{},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn bgw_disable_cgb() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        // BG tile 0 has priority over objects
+        c.write(0xFF4F, 1);
+        c.write(0x9800, TILEATTR_PRIORITY);
+        c.write(0xFF4F, 0);
+        setup_bg_obj(&mut c, 0);
+        // BG palette 0, color 1: green
+        c.write(0xFF68, 2 | XCPS_AUTO_INC);
+        c.write(0xFF69, 0xE0);
+        c.write(0xFF69, 0x03);
+        // OBJ palette 0, color 3: blue
+        c.write(0xFF6A, 6 | XCPS_AUTO_INC);
+        c.write(0xFF6B, 0x00);
+        c.write(0xFF6B, 0x7C);
+        c.write(
+            0xFF40,
+            LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_OBJ_ENABLE | LCDC_BGW_ENABLE,
+        );
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == 0x03E0));
+
+        // BG is still drawn, but loses priority
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_OBJ_ENABLE);
+        run_frame(&mut c);
+        let fb = c.get_framebuffer();
+        assert_eq!(fb[0], 0x7C00);
+        assert_eq!(fb[8], 0x03E0);
+        assert_eq!(fb[LCD_W * 7], 0x7C00);
+        assert_eq!(fb[LCD_W * 8], 0x03E0);
+    }
+
+    #[test]
+    fn opri() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Cgb);
+        for i in 0..16 {
+            c.write(0x8010 + i, 0xFF);
+        }
+        // Object 0 at X 8, object 1 at X 4 overlapping it
+        for (i, val) in [16, 16, 1, 0, 16, 12, 1, 1].into_iter().enumerate() {
+            c.write(0xFE00 + i as u16, val);
+        }
+        // OBJ palette 0, color 3: blue, OBJ palette 1, color 3: red
+        c.write(0xFF6A, 6 | XCPS_AUTO_INC);
+        c.write(0xFF6B, 0x00);
+        c.write(0xFF6B, 0x7C);
+        c.write(0xFF6A, 14 | XCPS_AUTO_INC);
+        c.write(0xFF6B, 0x1F);
+        c.write(0xFF6B, 0x00);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE);
+
+        // Lowest OAM position wins
+        assert_eq!(c.read(0xFF6C), 0xFE);
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert_eq!(c.get_framebuffer()[8], 0x7C00);
+
+        // Lowest X coordinate wins
+        c.write(0xFF6C, 0x01);
+        assert_eq!(c.read(0xFF6C), 0xFF);
+        run_frame(&mut c);
+        assert_eq!(c.get_framebuffer()[8], 0x001F);
+
+        // Locked after boot
+        c.set_opri_writable(false);
+        c.write(0xFF6C, 0x00);
+        assert_eq!(c.read(0xFF6C), 0xFF);
+    }
+
+    #[test]
+    fn obj_disable_midframe() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        setup_bg_obj(&mut c, 0);
+        c.write(0xFF47, 0xE4);
+        // All object colors are the darkest shade
+        c.write(0xFF48, 0xFF);
+        let lcdc = LCDC_ENABLE | LCDC_BGW_TILEDATA | LCDC_BGW_ENABLE | LCDC_OBJ_SIZE;
+        c.write(0xFF40, lcdc | LCDC_OBJ_ENABLE);
+        run_frame(&mut c);
+
+        // Hide objects halfway through the lower half of the 8x16 object
+        while c.ly != 12 {
+            c.tick(Ticks::from_t(4)).unwrap();
+        }
+        c.write(0xFF40, lcdc);
+        run_frame(&mut c);
+
+        let fb = c.get_framebuffer();
+        for y in 0..16 {
+            let expected = if y < 12 { DMG_GREY[3] } else { DMG_GREY[1] };
+            assert_eq!(fb[y * LCD_W], expected, "line {}", y);
+            assert_eq!(fb[y * LCD_W + 8], DMG_GREY[1], "line {}", y);
+        }
+    }
+
+    #[test]
+    fn window_offscreen() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        // Tile 1 is color 3, tile 2 is color 1
+        for i in 0..16 {
+            c.write(0x8010 + i, 0xFF);
+            c.write(0x8020 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        }
+        // Window line 0-7 uses tile 1, the lines below tile 2
+        for i in 0..(32 * 32) {
+            c.write(0x9C00 + i, if i < 32 { 1 } else { 2 });
+        }
+        c.write(0xFF47, 0xE4);
+        c.write(0xFF4A, 0);
+        c.write(0xFF4B, 166);
+        c.write(
+            0xFF40,
+            LCDC_ENABLE
+                | LCDC_BGW_TILEDATA
+                | LCDC_BGW_ENABLE
+                | LCDC_WINDOW_ENABLE
+                | LCDC_WINDOW_TILEMAP,
+        );
+        run_frame(&mut c);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == c.dmg_palette[0]));
+
+        // Window line counter did not advance
+        while c.ly != 100 {
+            c.tick(Ticks::from_t(4)).unwrap();
+        }
+        assert_eq!(c.wly, 0);
+
+        // Showing the window continues at window line 0
+        c.write(0xFF4B, 80);
+        run_frame(&mut c);
+        let fb = c.get_framebuffer();
+        for y in 0..LCD_H {
+            for x in 0..LCD_W {
+                let expected = match (y, x) {
+                    (0..=99, _) | (_, 0..=72) => c.dmg_palette[0],
+                    (100..=107, _) => c.dmg_palette[3],
+                    _ => c.dmg_palette[1],
+                };
+                assert_eq!(fb[y * LCD_W + x], expected, "{},{}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn window_wy_midframe() {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        // Tile 1 is color 3, tile 2 is color 1
+        for i in 0..16 {
+            c.write(0x8010 + i, 0xFF);
+            c.write(0x8020 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        }
+        // Window line 0-7 uses tile 1, the lines below tile 2
+        for i in 0..(32 * 32) {
+            c.write(0x9C00 + i, if i < 32 { 1 } else { 2 });
+        }
+        c.write(0xFF47, 0xE4);
+        c.write(0xFF4A, 16);
+        c.write(0xFF4B, 7);
+        c.write(
+            0xFF40,
+            LCDC_ENABLE
+                | LCDC_BGW_TILEDATA
+                | LCDC_BGW_ENABLE
+                | LCDC_WINDOW_ENABLE
+                | LCDC_WINDOW_TILEMAP,
+        );
+        run_frame(&mut c);
+
+        // Moving the window down after it started does not stop it
+        while c.ly != 60 {
+            c.tick(Ticks::from_t(4)).unwrap();
+        }
+        c.write(0xFF4A, 120);
+        run_frame(&mut c);
+        let fb = c.get_framebuffer();
+        for y in 0..LCD_H {
+            let expected = match y {
+                0..=15 => c.dmg_palette[0],
+                16..=23 => c.dmg_palette[3],
+                _ => c.dmg_palette[1],
+            };
+            assert_eq!(fb[y * LCD_W], expected, "line {}", y);
+        }
+
+        // The next frame, it starts at line 120 (window line 0)
+        run_frame(&mut c);
+        let fb = c.get_framebuffer();
+        for y in 0..LCD_H {
+            let expected = match y {
+                0..=119 => c.dmg_palette[0],
+                120..=127 => c.dmg_palette[3],
+                _ => c.dmg_palette[1],
+            };
+            assert_eq!(fb[y * LCD_W], expected, "line {}", y);
+        }
+
+        // Moving the window above LY does not start it this frame
+        while c.ly != 60 {
+            c.tick(Ticks::from_t(4)).unwrap();
+        }
+        c.write(0xFF4A, 30);
+        run_frame(&mut c);
+        assert!(c.get_framebuffer().iter().all(|&p| p == c.dmg_palette[0]));
+    }
+
+    /// Display that checks it only receives complete frames, in order
+    struct FrameCheckDisplay {
+        next: usize,
+        frames: Rc<RefCell<Vec<Vec<Color>>>>,
+        pixels: Vec<Color>,
+    }
+
+    impl Display for FrameCheckDisplay {
+        fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+            assert_eq!(y * LCD_W + x, self.next, "Pixel out of order");
+            self.pixels[self.next] = color;
+            self.next += 1;
+        }
+
+        fn clear(&mut self) {}
+
+        fn render(&mut self) {
+            assert_eq!(self.next, LCD_W * LCD_H, "Incomplete frame");
+            self.next = 0;
+            self.frames.borrow_mut().push(self.pixels.clone());
+        }
+    }
+
+    #[test]
+    fn complete_frames() {
+        let frames = Rc::new(RefCell::new(vec![]));
+        let display = FrameCheckDisplay {
+            next: 0,
+            frames: Rc::clone(&frames),
+            pixels: vec![0; LCD_W * LCD_H],
+        };
+        let mut c = LCDController::new(Box::new(display), Model::Dmg);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_ENABLE);
+
+        // Change the palette halfway through each frame
+        for i in 0..4 {
+            c.write(0xFF47, i);
+            while c.ly != (LCD_H / 2) as u8 {
+                c.tick(Ticks::from_t(4)).unwrap();
+            }
+            c.write(0xFF47, i + 1);
+            run_frame(&mut c);
+            // The framebuffer does not change until the next VBlank
+            let frame = c.get_framebuffer().to_vec();
+            for _ in 0..(114 * 20) {
+                c.tick(Ticks::from_t(4)).unwrap();
+            }
+            assert_eq!(c.get_framebuffer(), frame);
+        }
+
+        // The first frame after enabling the LCD is not rendered
+        let frames = frames.borrow();
+        assert_eq!(frames.len(), 3);
+        for (i, frame) in frames.iter().enumerate() {
+            let i = i as u8 + 1;
+            let top = c.dmg_palette[i as usize & 3];
+            let bottom = c.dmg_palette[(i as usize + 1) & 3];
+            assert!(frame[..(LCD_W * LCD_H / 2)].iter().all(|&p| p == top));
+            assert!(frame[(LCD_W * LCD_H / 2)..].iter().all(|&p| p == bottom));
+        }
+    }
+
+    #[test]
+    fn skip_next_present() {
+        let frames = Rc::new(RefCell::new(vec![]));
+        let display = FrameCheckDisplay {
+            next: 0,
+            frames: Rc::clone(&frames),
+            pixels: vec![0; LCD_W * LCD_H],
+        };
+        let mut c = LCDController::new(Box::new(display), Model::Dmg);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_ENABLE);
+        // The first frame after enabling the LCD is not rendered
+        run_frame(&mut c);
+
+        run_frame(&mut c);
+        assert_eq!(frames.borrow().len(), 1);
+        c.skip_next_present();
+        run_frame(&mut c);
+        assert_eq!(frames.borrow().len(), 1);
+        run_frame(&mut c);
+        assert_eq!(frames.borrow().len(), 2);
+    }
+
+    #[test]
+    fn scanline_hook() {
+        let lines = Rc::new(RefCell::new(vec![]));
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+        c.write(0xFF40, LCDC_ENABLE | LCDC_BGW_ENABLE);
+        c.write(0xFF47, 0x03);
+        run_frame(&mut c);
+
+        let hook_lines = Rc::clone(&lines);
+        c.set_scanline_hook(Some(Box::new(move |ly, colors| {
+            assert_eq!(colors.len(), LCD_W);
+            hook_lines.borrow_mut().push((ly, colors[0]));
+        })));
+        run_frame(&mut c);
+        assert_eq!(
+            *lines.borrow(),
+            (0..LCD_H as u8)
+                .map(|ly| (ly, c.dmg_palette[3]))
+                .collect::<Vec<_>>()
+        );
+
+        lines.borrow_mut().clear();
+        c.set_scanline_hook(None);
+        run_frame(&mut c);
+        assert!(lines.borrow().is_empty());
+    }
 }
@@ -0,0 +1,309 @@
+use std::fmt::Write;
+
+use crate::display::display::Color;
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::lcd::{
+    ColorIndex, LCDController, LCDC_BG_TILEMAP, LCD_H, LCD_W, TILEATTR_FLIP_X, TILEATTR_FLIP_Y,
+    TILEATTR_PALETTE_CGB_MASK, TILEATTR_PALETTE_DMG_MASK, TILEATTR_PRIORITY, TILEATTR_VRAM_BANK,
+    TILE_BSIZE,
+};
+
+/// Amount of tiles in a VRAM bank
+pub const TILES_PER_BANK: usize = 384;
+
+/// Width of the tile sheet of a single VRAM bank (in tiles)
+pub const TILESHEET_COLS: usize = 16;
+
+/// Height of the tile sheet (in tiles)
+pub const TILESHEET_ROWS: usize = TILES_PER_BANK / TILESHEET_COLS;
+
+/// Width/height of a tile map (in pixels)
+pub const TILEMAP_SIZE: usize = 256;
+
+/// Tile map size (in tiles)
+const TILEMAP_TILES: usize = 32;
+
+/// A rendered image (row-major)
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// Decodes a tile from VRAM to color indices (row-major, 8x8).
+/// Tiles are numbered as in object tile indices (0 - 383).
+pub fn decode_tile(lcd: &LCDController, bank: usize, tile: usize) -> [ColorIndex; 64] {
+    let offset = tile * TILE_BSIZE;
+    let data = &lcd.dump_vram(bank)[offset..(offset + TILE_BSIZE)];
+    let mut out = [0; 64];
+    for (i, c) in out.iter_mut().enumerate() {
+        *c = LCDController::tile_decode(data, i % 8, i / 8);
+    }
+    out
+}
+
+/// Renders all tiles in VRAM into a grid of TILESHEET_COLS x TILESHEET_ROWS
+/// tiles using BG palette 0. In CGB mode, the second bank is placed
+/// to the right of the first bank.
+pub fn render_tiles(lcd: &LCDController) -> Image {
+    let banks = if lcd.is_cgb() { 2 } else { 1 };
+    let mut img = Image::new(TILESHEET_COLS * 8 * banks, TILESHEET_ROWS * 8);
+    let palette = lcd.get_bg_palette(0);
+
+    for bank in 0..banks {
+        for tile in 0..TILES_PER_BANK {
+            let tx = (bank * TILESHEET_COLS + tile % TILESHEET_COLS) * 8;
+            let ty = (tile / TILESHEET_COLS) * 8;
+            for (i, &c) in decode_tile(lcd, bank, tile).iter().enumerate() {
+                img.set_pixel(tx + i % 8, ty + i / 8, palette[c as usize]);
+            }
+        }
+    }
+    img
+}
+
+/// Renders the complete (TILEMAP_SIZE x TILEMAP_SIZE) background tile map
+/// currently selected in LCDC, including CGB attributes.
+pub fn render_bg_map(lcd: &LCDController) -> Image {
+    let mut img = Image::new(TILEMAP_SIZE, TILEMAP_SIZE);
+    let vram = [lcd.dump_vram(0), lcd.dump_vram(1)];
+    let map_offset = if lcd.read(0xFF40) & LCDC_BG_TILEMAP != 0 {
+        0x1C00
+    } else {
+        0x1800
+    };
+
+    for ty in 0..TILEMAP_TILES {
+        for tx in 0..TILEMAP_TILES {
+            let entry = map_offset + ty * TILEMAP_TILES + tx;
+            let tile_id = vram[0][entry] as usize;
+            let attr = if lcd.is_cgb() { vram[1][entry] } else { 0 };
+            let bank = usize::from(attr & TILEATTR_VRAM_BANK != 0);
+            let offset = lcd.get_bgw_tile_offset(tile_id);
+            let data = &vram[bank][offset..(offset + TILE_BSIZE)];
+            let palette = lcd.get_bg_palette(attr & TILEATTR_PALETTE_CGB_MASK);
+
+            for y in 0..8 {
+                for x in 0..8 {
+                    let sx = if attr & TILEATTR_FLIP_X != 0 {
+                        7 - x
+                    } else {
+                        x
+                    };
+                    let sy = if attr & TILEATTR_FLIP_Y != 0 {
+                        7 - y
+                    } else {
+                        y
+                    };
+                    let c = LCDController::tile_decode(data, sx, sy);
+                    img.set_pixel(tx * 8 + x, ty * 8 + y, palette[c as usize]);
+                }
+            }
+        }
+    }
+    img
+}
+
+/// Draws the outline of the visible screen area (at SCX/SCY) onto a
+/// rendered tile map, wrapping around the edges like the PPU does.
+pub fn draw_viewport(img: &mut Image, scx: u8, scy: u8, color: Color) {
+    let (scx, scy) = (scx as usize, scy as usize);
+    for x in 0..LCD_W {
+        let px = (scx + x) % TILEMAP_SIZE;
+        img.set_pixel(px, scy, color);
+        img.set_pixel(px, (scy + LCD_H - 1) % TILEMAP_SIZE, color);
+    }
+    for y in 0..LCD_H {
+        let py = (scy + y) % TILEMAP_SIZE;
+        img.set_pixel(scx, py, color);
+        img.set_pixel((scx + LCD_W - 1) % TILEMAP_SIZE, py, color);
+    }
+}
+
+/// Lists all OAM entries: position, tile, flags and palette
+pub fn dump_oam(lcd: &LCDController) -> String {
+    let mut out = String::new();
+    for (i, e) in lcd.get_oam().entries().iter().enumerate() {
+        let flag = |mask, c| if e.flags & mask != 0 { c } else { '-' };
+        let palette = if lcd.is_cgb() {
+            format!(
+                "{} (bank {})",
+                e.flags & TILEATTR_PALETTE_CGB_MASK,
+                (e.flags & TILEATTR_VRAM_BANK) >> 3
+            )
+        } else {
+            format!("OBP{}", (e.flags & TILEATTR_PALETTE_DMG_MASK) >> 4)
+        };
+        writeln!(
+            out,
+            "{:02}: X:{:3} Y:{:3} tile:{:02X} flags:{:02X} {}{}{} palette:{}",
+            i,
+            e.x,
+            e.y,
+            e.tile_idx,
+            e.flags,
+            flag(TILEATTR_PRIORITY, 'P'),
+            flag(TILEATTR_FLIP_Y, 'Y'),
+            flag(TILEATTR_FLIP_X, 'X'),
+            palette
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::display::NullDisplay;
+    use crate::gameboy::model::Model;
+
+    fn lcd(cgb: bool) -> LCDController {
+        let mut c = LCDController::new(Box::new(NullDisplay::new()), Model::from_cgb(cgb));
+        // Shades 0 - 3 = color indices 0 - 3
+        c.write(0xFF47, 0xE4);
+        c
+    }
+
+    /// A tile with a different color index in each quadrant
+    fn write_tile(c: &mut LCDController, addr: u16) {
+        for y in 0..8 {
+            let (lsb, msb) = if y < 4 { (0x0F, 0x00) } else { (0xF0, 0xFF) };
+            c.write(addr + y * 2, lsb);
+            c.write(addr + y * 2 + 1, msb);
+        }
+    }
+
+    const TILE: [ColorIndex; 64] = {
+        let mut t = [0; 64];
+        let mut i = 0;
+        while i < 64 {
+            t[i] = match (i % 8 < 4, i / 8 < 4) {
+                (true, true) => 0,
+                (false, true) => 1,
+                (true, false) => 3,
+                (false, false) => 2,
+            };
+            i += 1;
+        }
+        t
+    };
+
+    #[test]
+    fn decode() {
+        let mut c = lcd(false);
+        write_tile(&mut c, 0x8010);
+        assert_eq!(decode_tile(&c, 0, 1), TILE);
+        assert_eq!(decode_tile(&c, 0, 0), [0; 64]);
+    }
+
+    #[test]
+    fn decode_bank1() {
+        let mut c = lcd(true);
+        c.write(0xFF4F, 1);
+        write_tile(&mut c, 0x97F0);
+        assert_eq!(decode_tile(&c, 1, 383), TILE);
+        assert_eq!(decode_tile(&c, 0, 383), [0; 64]);
+    }
+
+    #[test]
+    fn tilesheet() {
+        let mut c = lcd(false);
+        // Tile 17: second row, second column
+        write_tile(&mut c, 0x8110);
+        let img = render_tiles(&c);
+        assert_eq!((img.width, img.height), (128, 192));
+        let palette = c.get_bg_palette(0);
+        for (i, &cidx) in TILE.iter().enumerate() {
+            assert_eq!(img.get_pixel(8 + i % 8, 8 + i / 8), palette[cidx as usize]);
+        }
+        assert_eq!(img.get_pixel(7, 7), palette[0]);
+
+        assert_eq!(render_tiles(&lcd(true)).width, 256);
+    }
+
+    #[test]
+    fn bg_map() {
+        let mut c = lcd(false);
+        // Signed addressing, tile 0 at 0x9000
+        write_tile(&mut c, 0x9000);
+        for i in 0..0x400 {
+            c.write(0x9800 + i, 0x80);
+        }
+        c.write(0x9800 + 33, 0x00);
+        c.write(0xFF40, 0x80);
+
+        let img = render_bg_map(&c);
+        assert_eq!((img.width, img.height), (256, 256));
+        let palette = c.get_bg_palette(0);
+        for (i, &cidx) in TILE.iter().enumerate() {
+            assert_eq!(img.get_pixel(8 + i % 8, 8 + i / 8), palette[cidx as usize]);
+        }
+        assert!(img.pixels[..(256 * 8)].iter().all(|&p| p == palette[0]));
+    }
+
+    #[test]
+    fn bg_map_cgb_attributes() {
+        let mut c = lcd(true);
+        c.write(0xFF4F, 1);
+        write_tile(&mut c, 0x8000);
+        // Map entry 0: tile 0 from bank 1, flipped horizontally
+        c.write(0x9800, TILEATTR_VRAM_BANK | TILEATTR_FLIP_X);
+        c.write(0xFF40, 0x90);
+
+        let img = render_bg_map(&c);
+        let palette = c.get_bg_palette(0);
+        for (i, &cidx) in TILE.iter().enumerate() {
+            assert_eq!(img.get_pixel(7 - i % 8, i / 8), palette[cidx as usize]);
+        }
+    }
+
+    #[test]
+    fn viewport() {
+        let mut img = Image::new(TILEMAP_SIZE, TILEMAP_SIZE);
+        draw_viewport(&mut img, 200, 250, 1);
+        // Corners, wrapped around
+        assert_eq!(img.get_pixel(200, 250), 1);
+        assert_eq!(img.get_pixel((200 + 159) % 256, 250), 1);
+        assert_eq!(img.get_pixel(200, (250 + 143) % 256), 1);
+        assert_eq!(img.get_pixel((200 + 159) % 256, (250 + 143) % 256), 1);
+        // Inside
+        assert_eq!(img.get_pixel(210, 10), 0);
+        assert_eq!(
+            img.pixels.iter().filter(|&&p| p == 1).count(),
+            2 * 160 + 2 * 142
+        );
+    }
+
+    #[test]
+    fn oam() {
+        let mut c = lcd(false);
+        for (i, val) in [16, 8, 0x12, 0xD0].into_iter().enumerate() {
+            c.write(0xFE04 + i as u16, val);
+        }
+        let dump = dump_oam(&c);
+        assert_eq!(dump.lines().count(), 40);
+        assert_eq!(
+            dump.lines().nth(1).unwrap(),
+            "01: X:  8 Y: 16 tile:12 flags:D0 PY- palette:OBP1"
+        );
+    }
+}
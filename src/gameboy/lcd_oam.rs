@@ -1,7 +1,10 @@
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use itertools::Itertools;
 
+use crate::error::Result;
+
 const OAM_ENTRY_SIZE: usize = 4;
-const OAM_SIZE: usize = 0xA0;
+pub const OAM_SIZE: usize = 0xA0;
 const OAM_ENTRIES: usize = OAM_SIZE / OAM_ENTRY_SIZE;
 
 /// One single table entry
@@ -42,6 +45,14 @@ impl OAMEntry {
             _ => unreachable!(),
         }
     }
+
+    /// Tests if the object covers a screen scanline. The OAM Y coordinate
+    /// is the top of the object plus 16, so Y=16 is the top line of the
+    /// screen and Y=0 hides an object of any height.
+    pub fn covers_scanline(&self, scanline: isize, sprite_h: isize) -> bool {
+        let top = self.y as isize - 16;
+        (top..(top + sprite_h)).contains(&scanline)
+    }
 }
 
 /// Sprite Attribute Table / Object Attribute Memory
@@ -66,6 +77,12 @@ impl OAMTable {
         }
     }
 
+    /// Selects the objects to draw on a screen scanline (0-143), in
+    /// drawing order (lowest priority first). sprite_h is the object
+    /// height (8 or 16); an 8x16 object is selected for all 16 lines it
+    /// covers. Like the OAM scan, at most 10 objects are selected, so
+    /// objects that are not on the scanline (e.g. below the screen)
+    /// never take a slot.
     pub fn iter_scanline(
         &self,
         y: isize,
@@ -74,13 +91,12 @@ impl OAMTable {
     ) -> Box<dyn Iterator<Item = &OAMEntry> + '_> {
         // TODO remove the heap allocation of the iterator
 
-        let y = y + 16;
         match mode {
             ObjPriMode::Coordinate => Box::new(
                 self.oam
                     .iter()
                     // Select objects in current scanline
-                    .filter(move |&&e| (e.y as isize) <= y && (e.y as isize + sprite_h) > y)
+                    .filter(move |e| e.covers_scanline(y, sprite_h))
                     // OAM scan only collects 10 objects per scanline
                     .take(10)
                     // Objects have priority from low X to high. To simplify this,
@@ -92,7 +108,7 @@ impl OAMTable {
                 self.oam
                     .iter()
                     // Select objects in current scanline
-                    .filter(move |&&e| (e.y as isize) <= y && (e.y as isize + sprite_h) > y)
+                    .filter(move |e| e.covers_scanline(y, sprite_h))
                     // OAM scan only collects 10 objects per scanline
                     .take(10)
                     // Draw in opposite order to get overlapping right
@@ -103,6 +119,11 @@ impl OAMTable {
         }
     }
 
+    /// Returns all entries, in OAM order
+    pub fn entries(&self) -> &[OAMEntry] {
+        &self.oam
+    }
+
     pub fn read(&self, addr: usize) -> u8 {
         self.oam[addr / OAM_ENTRY_SIZE].read(addr % OAM_ENTRY_SIZE)
     }
@@ -110,4 +131,83 @@ impl OAMTable {
     pub fn write(&mut self, addr: usize, val: u8) {
         self.oam[addr / OAM_ENTRY_SIZE].write(addr % OAM_ENTRY_SIZE, val)
     }
+
+    /// Replaces the table with raw OAM contents
+    pub fn load(&mut self, data: &[u8; OAM_SIZE]) {
+        for (addr, &val) in data.iter().enumerate() {
+            self.write(addr, val);
+        }
+    }
+
+    /// Returns the raw OAM contents
+    pub fn dump(&self) -> [u8; OAM_SIZE] {
+        std::array::from_fn(|addr| self.read(addr))
+    }
+}
+
+impl Savestate for OAMTable {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_slice(&self.dump());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let mut data = [0; OAM_SIZE];
+        r.get_slice(&mut data)?;
+        self.load(&data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gameboy::lcd::LCD_H;
+
+    /// Scanlines an object at OAM Y selects
+    fn scanlines(oam_y: u8, sprite_h: isize) -> Vec<isize> {
+        let mut oam = OAMTable::new();
+        oam.write(0, oam_y);
+        oam.write(1, 8);
+        (0..LCD_H as isize)
+            .filter(|&y| {
+                oam.iter_scanline(y, sprite_h, ObjPriMode::Coordinate)
+                    .any(|e| e.y == oam_y)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn iter_scanline_8() {
+        assert!(scanlines(0, 8).is_empty());
+        assert!(scanlines(1, 8).is_empty());
+        assert_eq!(scanlines(15, 8), (0..=6).collect::<Vec<_>>());
+        assert_eq!(scanlines(16, 8), (0..=7).collect::<Vec<_>>());
+        assert_eq!(scanlines(152, 8), (136..=143).collect::<Vec<_>>());
+        assert!(scanlines(160, 8).is_empty());
+    }
+
+    #[test]
+    fn iter_scanline_16() {
+        assert!(scanlines(0, 16).is_empty());
+        assert_eq!(scanlines(1, 16), [0]);
+        assert_eq!(scanlines(15, 16), (0..=14).collect::<Vec<_>>());
+        assert_eq!(scanlines(16, 16), (0..=15).collect::<Vec<_>>());
+        assert_eq!(scanlines(152, 16), (136..=143).collect::<Vec<_>>());
+        assert!(scanlines(160, 16).is_empty());
+    }
+
+    #[test]
+    fn iter_scanline_limit() {
+        let mut oam = OAMTable::new();
+        // 10 objects below the screen, then one on line 0
+        for i in 0..10 {
+            oam.write(i * OAM_ENTRY_SIZE, 160);
+        }
+        oam.write(10 * OAM_ENTRY_SIZE, 16);
+        for mode in [ObjPriMode::Coordinate, ObjPriMode::OAMPosition] {
+            assert_eq!(oam.iter_scanline(0, 8, mode).count(), 1);
+            assert_eq!(oam.iter_scanline(0, 8, mode).next().unwrap().y, 16);
+        }
+    }
 }
@@ -0,0 +1,493 @@
+//! Lockstep link cable connection between two emulator instances.
+//!
+//! Messages are framed as a 16-bit (little endian) length of the rest of
+//! the message, a type byte and the payload:
+//!
+//! - DATA: the transferred byte and the cycle count of the sender (u64)
+//! - SYNC: the cycle count of the sender (u64), sent every frame
+//! - PING: no payload, answered with a SYNC
+//!
+//! One side leads, the other side follows: the follower never runs past
+//! the last cycle count it received from the leader. A transfer started
+//! by the leader therefore completes on the follower at the same cycle
+//! count. The leader is throttled (see LockstepLink::wait_for_peer())
+//! when the follower falls too far behind.
+
+use crate::gameboy::emulator::Emulator;
+use crate::misc::{ReadableReceiver, WritableSender};
+use crate::time::{Clock, MonotonicClock};
+
+use core::time::Duration;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+pub const MSG_DATA: u8 = 0x01;
+pub const MSG_SYNC: u8 = 0x02;
+pub const MSG_PING: u8 = 0x03;
+
+/// Cycles between SYNC messages
+const SYNC_INTERVAL: u64 = Emulator::FRAME_CYCLES;
+
+/// Default amount of frames the leader may run ahead of the follower
+pub const DEFAULT_MAX_LEAD: u64 = 2;
+
+/// Default time to wait for the peer before giving up
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Interval of PINGs while waiting for the peer
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+
+const POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Data { byte: u8, cycles: u64 },
+    Sync { cycles: u64 },
+    Ping,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        match *self {
+            Message::Data { byte, cycles } => {
+                body.push(MSG_DATA);
+                body.push(byte);
+                body.extend_from_slice(&cycles.to_le_bytes());
+            }
+            Message::Sync { cycles } => {
+                body.push(MSG_SYNC);
+                body.extend_from_slice(&cycles.to_le_bytes());
+            }
+            Message::Ping => body.push(MSG_PING),
+        }
+        let mut out = (body.len() as u16).to_le_bytes().to_vec();
+        out.extend(body);
+        out
+    }
+
+    /// Decodes the body (type byte and payload) of a message. Returns
+    /// None for unknown types and invalid payloads.
+    pub fn decode(body: &[u8]) -> Option<Self> {
+        let (&msgtype, payload) = body.split_first()?;
+        match (msgtype, payload.len()) {
+            (MSG_DATA, 9) => Some(Message::Data {
+                byte: payload[0],
+                cycles: u64::from_le_bytes(payload[1..].try_into().unwrap()),
+            }),
+            (MSG_SYNC, 8) => Some(Message::Sync {
+                cycles: u64::from_le_bytes(payload.try_into().unwrap()),
+            }),
+            (MSG_PING, 0) => Some(Message::Ping),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a stream of bytes into messages
+#[derive(Default)]
+pub struct MessageParser {
+    buf: Vec<u8>,
+}
+
+impl MessageParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Processes a received byte, returns a message when complete.
+    /// Messages that cannot be decoded are skipped.
+    pub fn feed(&mut self, b: u8) -> Option<Message> {
+        self.buf.push(b);
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let len = u16::from_le_bytes([self.buf[0], self.buf[1]]) as usize;
+        if self.buf.len() < len + 2 {
+            return None;
+        }
+        let msg = Message::decode(&self.buf[2..]);
+        self.buf.clear();
+        msg
+    }
+}
+
+/// Which side of a lockstep link sets the pace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkRole {
+    Leader,
+    Follower,
+}
+
+/// One end of a lockstep link cable connection
+pub struct LockstepLink {
+    input: Box<dyn io::Read>,
+    output: Box<dyn io::Write>,
+    parser: MessageParser,
+    role: LinkRole,
+    connected: bool,
+    /// Emulated cycles (at normal speed) since the link was set up
+    cycles: u64,
+    /// Last known cycle count of the peer
+    peer_cycles: u64,
+    /// Received transfer (byte, cycle count of the peer)
+    pending: Option<(u8, u64)>,
+    /// Cycles the leader may run ahead of the follower
+    max_lead: u64,
+    timeout: Duration,
+}
+
+impl LockstepLink {
+    pub fn new(input: Box<dyn io::Read>, output: Box<dyn io::Write>, role: LinkRole) -> Self {
+        Self {
+            input,
+            output,
+            parser: MessageParser::new(),
+            role,
+            connected: true,
+            cycles: 0,
+            peer_cycles: 0,
+            pending: None,
+            max_lead: DEFAULT_MAX_LEAD * SYNC_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Creates a link through channels (see serial::crossed_channels())
+    pub fn new_crossed(tx: mpsc::Sender<u8>, rx: mpsc::Receiver<u8>, role: LinkRole) -> Self {
+        Self::new(
+            Box::new(ReadableReceiver::new(rx)),
+            Box::new(WritableSender::new(tx)),
+            role,
+        )
+    }
+
+    /// Sets the amount of frames the leader may run ahead
+    pub fn set_max_lead(&mut self, frames: u64) {
+        self.max_lead = frames * SYNC_INTERVAL;
+    }
+
+    /// Sets the time to wait for the peer before giving up
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn role(&self) -> LinkRole {
+        self.role
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn peer_cycles(&self) -> u64 {
+        self.peer_cycles
+    }
+
+    fn send(&mut self, msg: Message) {
+        if self.connected && self.output.write_all(&msg.encode()).is_err() {
+            self.connected = false;
+        }
+    }
+
+    fn handle(&mut self, msg: Message) {
+        match msg {
+            Message::Data { byte, cycles } => {
+                self.pending = Some((byte, cycles));
+                self.peer_cycles = self.peer_cycles.max(cycles);
+            }
+            Message::Sync { cycles } => self.peer_cycles = self.peer_cycles.max(cycles),
+            Message::Ping => self.send(Message::Sync {
+                cycles: self.cycles,
+            }),
+        }
+    }
+
+    /// Handles all received messages, without blocking
+    fn poll(&mut self) {
+        let mut buf = [0; 64];
+        while self.connected {
+            match self.input.read(&mut buf) {
+                Ok(0) => self.connected = false,
+                Ok(len) => {
+                    for &b in &buf[..len] {
+                        if let Some(msg) = self.parser.feed(b) {
+                            self.handle(msg);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => self.connected = false,
+            }
+        }
+    }
+
+    /// Polls the link while 'cond' holds, for at most the timeout.
+    /// Returns false if it timed out.
+    fn block_while(&mut self, cond: impl Fn(&Self) -> bool) -> bool {
+        let clock = MonotonicClock::new();
+        let mut last_ping = Duration::ZERO;
+        loop {
+            self.poll();
+            if !self.connected || !cond(self) {
+                return true;
+            }
+            let now = clock.now();
+            if now >= self.timeout {
+                return false;
+            }
+            if now - last_ping >= PING_INTERVAL {
+                self.send(Message::Ping);
+                last_ping = now;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Starts a transfer with the internal clock: sends a byte and
+    /// waits for the byte of the peer. Returns 0xFF if the peer does
+    /// not respond.
+    pub fn transfer(&mut self, out: u8) -> u8 {
+        self.send(Message::Data {
+            byte: out,
+            cycles: self.cycles,
+        });
+        self.block_while(|l| l.pending.is_none());
+        self.pending.take().map_or(0xFF, |(b, _)| b)
+    }
+
+    /// Advances the link by 'cycles'. If a transfer started by the peer
+    /// completes, 'out' is sent back and the received byte is returned.
+    /// The follower blocks here until the leader is ahead.
+    pub fn tick(&mut self, cycles: u64, out: u8) -> Option<u8> {
+        let last = self.cycles;
+        self.cycles += cycles;
+        if self.cycles / SYNC_INTERVAL != last / SYNC_INTERVAL {
+            self.send(Message::Sync {
+                cycles: self.cycles,
+            });
+        }
+        self.poll();
+
+        if self.role == LinkRole::Follower
+            && !self.block_while(|l| l.pending.is_none() && l.cycles >= l.peer_cycles)
+        {
+            // The leader is not responding (e.g. paused), run on for a
+            // frame before waiting again.
+            self.peer_cycles = self.cycles + SYNC_INTERVAL;
+        }
+
+        // Writes happen before the tick of their cycle, so a transfer
+        // started at cycle 'at' completes on the tick after it.
+        match self.pending {
+            Some((byte, at)) if at < self.cycles => {
+                self.pending = None;
+                self.send(Message::Data {
+                    byte: out,
+                    cycles: self.cycles,
+                });
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if this side is too far ahead of the peer
+    pub fn is_ahead(&self) -> bool {
+        self.connected && self.cycles > self.peer_cycles + self.max_lead
+    }
+
+    /// Back-pressure for the pacing of emulation: blocks while this
+    /// side is too far ahead of the peer, for at most the timeout.
+    /// Returns early if the peer starts a transfer.
+    pub fn wait_for_peer(&mut self) {
+        self.block_while(|l| l.is_ahead() && l.pending.is_none());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::serial;
+    use std::time::Instant;
+
+    fn pair() -> (LockstepLink, LockstepLink) {
+        let ((a_tx, a_rx), (b_tx, b_rx)) = serial::crossed_channels();
+        let mut leader = LockstepLink::new_crossed(a_tx, a_rx, LinkRole::Leader);
+        let mut follower = LockstepLink::new_crossed(b_tx, b_rx, LinkRole::Follower);
+        leader.set_timeout(Duration::from_millis(50));
+        follower.set_timeout(Duration::from_millis(50));
+        (leader, follower)
+    }
+
+    fn parse(bytes: &[u8]) -> Vec<Message> {
+        let mut p = MessageParser::new();
+        bytes.iter().filter_map(|&b| p.feed(b)).collect()
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(Message::Ping.encode(), [0x01, 0x00, MSG_PING]);
+        assert_eq!(
+            Message::Sync { cycles: 0x1234 }.encode(),
+            [0x09, 0x00, MSG_SYNC, 0x34, 0x12, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            Message::Data {
+                byte: 0xAB,
+                cycles: 1
+            }
+            .encode(),
+            [0x0A, 0x00, MSG_DATA, 0xAB, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn decode() {
+        let msgs = [
+            Message::Data {
+                byte: 0x42,
+                cycles: u64::MAX,
+            },
+            Message::Ping,
+            Message::Sync { cycles: 70224 },
+        ];
+        let bytes: Vec<u8> = msgs.iter().flat_map(|m| m.encode()).collect();
+        assert_eq!(parse(&bytes), msgs);
+
+        // Unknown types and bad payloads are skipped
+        assert_eq!(
+            parse(&[0x01, 0x00, 0x7F, 0x01, 0x00, MSG_PING]),
+            [Message::Ping]
+        );
+        assert_eq!(
+            parse(&[0x02, 0x00, MSG_SYNC, 0x00, 0x01, 0x00, MSG_PING]),
+            [Message::Ping]
+        );
+        assert_eq!(parse(&[0x00, 0x00, 0x01, 0x00, MSG_PING]), [Message::Ping]);
+    }
+
+    #[test]
+    fn loopback() {
+        let ((a_tx, a_rx), (b_tx, b_rx)) = serial::crossed_channels();
+        let mut a = WritableSender::new(a_tx);
+        let mut b = ReadableReceiver::new(b_rx);
+        drop((b_tx, a_rx));
+
+        let msgs = [
+            Message::Sync { cycles: 1 },
+            Message::Data {
+                byte: 0x55,
+                cycles: 2,
+            },
+            Message::Ping,
+        ];
+        for m in msgs {
+            io::Write::write_all(&mut a, &m.encode()).unwrap();
+        }
+        let mut received = vec![0; 64];
+        let len = io::Read::read(&mut b, &mut received).unwrap();
+        assert_eq!(parse(&received[..len]), msgs);
+    }
+
+    #[test]
+    fn sync_and_ping() {
+        let (mut leader, mut follower) = pair();
+        assert_eq!(leader.tick(SYNC_INTERVAL - 4, 0), None);
+        // Crossing a frame boundary sends a SYNC
+        assert_eq!(leader.tick(8, 0), None);
+        follower.poll();
+        assert_eq!(follower.peer_cycles(), SYNC_INTERVAL + 4);
+
+        // A PING is answered with the current cycle count
+        follower.cycles = 1234;
+        leader.send(Message::Ping);
+        follower.poll();
+        leader.poll();
+        assert_eq!(leader.peer_cycles(), 1234);
+    }
+
+    #[test]
+    fn follower_waits_for_leader() {
+        let (mut leader, mut follower) = pair();
+        leader.tick(1000, 0);
+        leader.send(Message::Sync { cycles: 1000 });
+
+        // Runs freely up to the leader
+        let start = Instant::now();
+        for _ in 0..249 {
+            follower.tick(4, 0);
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(follower.peer_cycles(), 1000);
+
+        // Times out, then runs on for a frame
+        let start = Instant::now();
+        follower.tick(4, 0);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(follower.peer_cycles(), 1000 + SYNC_INTERVAL);
+    }
+
+    #[test]
+    fn transfer_completes_at_leader_cycle() {
+        let (mut leader, mut follower) = pair();
+        leader.cycles = 100;
+        leader.send(Message::Data {
+            byte: 0x42,
+            cycles: 100,
+        });
+
+        // Completes when the follower passes the cycle of the leader
+        for _ in 0..25 {
+            assert_eq!(follower.tick(4, 0x99), None);
+        }
+        assert_eq!(follower.tick(4, 0x99), Some(0x42));
+        assert_eq!(follower.cycles(), 104);
+        leader.poll();
+        assert_eq!(leader.pending, Some((0x99, 104)));
+    }
+
+    #[test]
+    fn transfer_timeout() {
+        let (mut leader, follower) = pair();
+        assert!(leader.is_connected());
+        // Follower does not respond
+        assert_eq!(leader.transfer(0x42), 0xFF);
+
+        drop(follower);
+        assert_eq!(leader.transfer(0x42), 0xFF);
+        assert!(!leader.is_connected());
+    }
+
+    #[test]
+    fn back_pressure() {
+        let (mut leader, mut follower) = pair();
+        leader.set_max_lead(1);
+        leader.tick(SYNC_INTERVAL, 0);
+        assert!(!leader.is_ahead());
+        leader.tick(8, 0);
+        assert!(leader.is_ahead());
+
+        let start = Instant::now();
+        leader.wait_for_peer();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Follower catches up
+        follower.poll();
+        follower.tick(SYNC_INTERVAL, 0);
+        leader.wait_for_peer();
+        assert!(!leader.is_ahead());
+
+        // Disconnected peers are not waited for
+        leader.tick(SYNC_INTERVAL * 2, 0);
+        drop(follower);
+        leader.wait_for_peer();
+        assert!(!leader.is_ahead());
+    }
+}
@@ -1,9 +1,35 @@
 pub mod apu;
+pub mod bootrom;
 pub mod bus;
 pub mod cartridge;
+pub mod cheatfinder;
+pub mod clock;
 pub mod cpu;
+pub mod debugger;
+pub mod emulator;
+pub mod emuthread;
+pub mod frameskip;
+pub mod gdbstub;
+pub mod hexdump;
+pub mod infrared;
+pub mod ioregs;
 pub mod joypad;
 pub mod lcd;
+pub mod lcd_debug;
 pub mod lcd_oam;
+pub mod link;
+pub mod model;
+pub mod raminit;
+pub mod runaway;
+pub mod movie;
+
+#[cfg(feature = "remote")]
+pub mod remote;
+
+pub mod savestate;
 pub mod serial;
+pub mod sgb;
+pub mod stats;
+pub mod symbols;
+pub mod testing;
 pub mod timer;
@@ -0,0 +1,33 @@
+/// Emulated Gameboy hardware model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    /// Gameboy Pocket/Light (DMG, but A is 0xFF after boot)
+    Mgb,
+    /// Super Gameboy (DMG with SGB command support)
+    Sgb,
+    Cgb,
+    /// Gameboy Advance in Gameboy Color mode (CGB, but bit 0 of B is
+    /// set after boot)
+    Agb,
+}
+
+impl Model {
+    /// Model from the CGB mode flag used by the other components
+    pub fn from_cgb(cgb: bool) -> Self {
+        if cgb {
+            Self::Cgb
+        } else {
+            Self::Dmg
+        }
+    }
+
+    /// Runs in Gameboy Color mode
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Self::Cgb | Self::Agb)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        self == Self::Sgb
+    }
+}
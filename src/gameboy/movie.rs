@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use strum::IntoEnumIterator;
+
+use crate::input::input::{Button, Input};
+
+/// Magic on the first line of a movie file, followed by the format version
+const MOVIE_MAGIC: &str = "GBMOVIE";
+
+/// Version of the movie file format
+const MOVIE_FORMAT: u32 = 1;
+
+/// A single recorded input change
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MovieEvent {
+    /// Frame (VBlank count) on which the change takes effect
+    pub frame: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+/// An input recording (movie).
+///
+/// File format (text, one item per line):
+///   GBMOVIE <format version>
+///   version <emulator version>
+///   rom <SHA256 of ROM, hex>
+///   <frame> <button> <0|1>
+///   ...
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Movie {
+    pub version: String,
+    pub rom_checksum: [u8; 32],
+    pub events: Vec<MovieEvent>,
+}
+
+impl Movie {
+    /// Creates a new, empty movie for the specified ROM
+    pub fn new(rom: &[u8]) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            rom_checksum: Self::checksum(rom),
+            events: vec![],
+        }
+    }
+
+    fn checksum(rom: &[u8]) -> [u8; 32] {
+        Sha256::digest(rom).into()
+    }
+
+    /// Verifies the movie was recorded against the specified ROM
+    pub fn verify(&self, rom: &[u8]) -> Result<()> {
+        if self.rom_checksum != Self::checksum(rom) {
+            bail!("Movie was recorded with a different ROM");
+        }
+        Ok(())
+    }
+
+    /// Serializes the movie to the movie file format
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{} {}", MOVIE_MAGIC, MOVIE_FORMAT).unwrap();
+        writeln!(out, "version {}", self.version).unwrap();
+        write!(out, "rom ").unwrap();
+        for b in self.rom_checksum {
+            write!(out, "{:02x}", b).unwrap();
+        }
+        writeln!(out).unwrap();
+        for e in &self.events {
+            writeln!(out, "{} {} {}", e.frame, e.button, e.pressed as u8).unwrap();
+        }
+        out
+    }
+
+    /// Parses a movie from the movie file format
+    pub fn deserialize(s: &str) -> Result<Self> {
+        let mut lines = s.lines().enumerate();
+
+        let format = match lines.next().and_then(|(_, l)| l.trim().split_once(' ')) {
+            Some((magic, format)) if magic == MOVIE_MAGIC => format,
+            _ => bail!("Not a movie file"),
+        };
+        if format.parse() != Ok(MOVIE_FORMAT) {
+            bail!("Unsupported movie format version {}", format);
+        }
+
+        let mut version = None;
+        let mut rom_checksum = None;
+        let mut events = vec![];
+        for (i, line) in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                ["version", v] => version = Some(v.to_string()),
+                ["rom", hash] => {
+                    if hash.len() != 64 {
+                        bail!("Line {}: invalid ROM checksum", i + 1);
+                    }
+                    let mut cs = [0; 32];
+                    for (j, b) in cs.iter_mut().enumerate() {
+                        *b = u8::from_str_radix(&hash[j * 2..j * 2 + 2], 16)
+                            .with_context(|| format!("Line {}: invalid ROM checksum", i + 1))?;
+                    }
+                    rom_checksum = Some(cs);
+                }
+                [frame, button, pressed] => {
+                    let event = MovieEvent {
+                        frame: frame
+                            .parse()
+                            .with_context(|| format!("Line {}: invalid frame", i + 1))?,
+                        button: Button::from_str(button)
+                            .with_context(|| format!("Line {}: invalid button", i + 1))?,
+                        pressed: match pressed {
+                            "0" => false,
+                            "1" => true,
+                            _ => bail!("Line {}: invalid button state", i + 1),
+                        },
+                    };
+                    if events
+                        .last()
+                        .is_some_and(|l: &MovieEvent| l.frame > event.frame)
+                    {
+                        bail!("Line {}: events out of order", i + 1);
+                    }
+                    events.push(event);
+                }
+                _ => bail!("Line {}: syntax error", i + 1),
+            }
+        }
+
+        Ok(Self {
+            version: version.ok_or_else(|| anyhow!("Missing version"))?,
+            rom_checksum: rom_checksum.ok_or_else(|| anyhow!("Missing ROM checksum"))?,
+            events,
+        })
+    }
+}
+
+/// Shared handle to a movie being recorded
+pub type MovieHandle = Arc<Mutex<Movie>>;
+
+/// Input wrapper that records input changes of the wrapped input
+/// into a movie.
+/// Inputs are sampled once per frame so the emulated system sees
+/// exactly what is recorded.
+pub struct MovieRecorder {
+    inner: Box<dyn Input>,
+    movie: MovieHandle,
+    state: BTreeMap<Button, bool>,
+}
+
+impl MovieRecorder {
+    pub fn new(inner: Box<dyn Input>, movie: Movie) -> (Box<Self>, MovieHandle) {
+        let movie = Arc::new(Mutex::new(movie));
+        (Self::with_handle(inner, Arc::clone(&movie)), movie)
+    }
+
+    /// Records into an existing movie handle (e.g. when the recorder
+    /// is created on the emulation thread)
+    pub fn with_handle(inner: Box<dyn Input>, movie: MovieHandle) -> Box<Self> {
+        Box::new(Self {
+            inner,
+            movie,
+            state: BTreeMap::from_iter(Button::iter().map(|b| (b, false))),
+        })
+    }
+}
+
+impl Input for MovieRecorder {
+    fn is_pressed(&self, b: Button) -> bool {
+        self.state[&b]
+    }
+
+    fn set_frame(&mut self, frame: u64) {
+        self.inner.set_frame(frame);
+
+        let mut movie = self.movie.lock().unwrap();
+        for (&button, pressed) in self.state.iter_mut() {
+            let new = self.inner.is_pressed(button);
+            if new != *pressed {
+                *pressed = new;
+                movie.events.push(MovieEvent {
+                    frame,
+                    button,
+                    pressed: new,
+                });
+            }
+        }
+    }
+}
+
+/// Input that plays back a recorded movie, ignoring live input.
+pub struct MoviePlayer {
+    events: Vec<MovieEvent>,
+    next_event: usize,
+    state: BTreeMap<Button, bool>,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            events: movie.events,
+            next_event: 0,
+            state: BTreeMap::from_iter(Button::iter().map(|b| (b, false))),
+        }
+    }
+
+    /// Returns true once all events in the movie have been played
+    pub fn is_finished(&self) -> bool {
+        self.next_event >= self.events.len()
+    }
+}
+
+impl Input for MoviePlayer {
+    fn is_pressed(&self, b: Button) -> bool {
+        self.state[&b]
+    }
+
+    fn set_frame(&mut self, frame: u64) {
+        while let Some(e) = self.events.get(self.next_event) {
+            if e.frame > frame {
+                break;
+            }
+            self.state.insert(e.button, e.pressed);
+            self.next_event += 1;
+        }
+    }
+}
+
+/// Input that presses buttons based on the frame number, for
+/// scripted (automated) input.
+pub struct ScriptInput {
+    script: Box<dyn Fn(u64, Button) -> bool>,
+    frame: u64,
+}
+
+impl ScriptInput {
+    /// Creates a scripted input. 'script' returns whether a button is
+    /// pressed on a frame.
+    pub fn new(script: impl Fn(u64, Button) -> bool + 'static) -> Self {
+        Self {
+            script: Box::new(script),
+            frame: 0,
+        }
+    }
+}
+
+impl Input for ScriptInput {
+    fn is_pressed(&self, b: Button) -> bool {
+        (self.script)(self.frame, b)
+    }
+
+    fn set_frame(&mut self, frame: u64) {
+        self.frame = frame;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script() -> ScriptInput {
+        ScriptInput::new(|frame, b| match b {
+            Button::A => (2..5).contains(&frame),
+            Button::Start => frame >= 4,
+            _ => false,
+        })
+    }
+
+    fn movie() -> Movie {
+        Movie {
+            version: "1.0".to_string(),
+            rom_checksum: [0xAB; 32],
+            events: vec![
+                MovieEvent {
+                    frame: 1,
+                    button: Button::A,
+                    pressed: true,
+                },
+                MovieEvent {
+                    frame: 3,
+                    button: Button::DPadLeft,
+                    pressed: true,
+                },
+                MovieEvent {
+                    frame: 3,
+                    button: Button::A,
+                    pressed: false,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn serialize_roundtrip() {
+        let m = movie();
+        assert_eq!(Movie::deserialize(&m.serialize()).unwrap(), m);
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        assert!(Movie::deserialize("").is_err());
+        assert!(Movie::deserialize("GBMOVIE 1\nversion 1\n").is_err());
+        assert!(Movie::deserialize("GBMOVIE\nversion 1\n").is_err());
+        let v2 = movie().serialize().replacen("GBMOVIE 1", "GBMOVIE 2", 1);
+        assert!(Movie::deserialize(&v2).is_err());
+        let s = movie().serialize();
+        assert!(Movie::deserialize(&(s.clone() + "2 Foo 1\n")).is_err());
+        assert!(Movie::deserialize(&(s.clone() + "2 A 2\n")).is_err());
+        assert!(Movie::deserialize(&(s + "2 A 1\n")).is_err());
+    }
+
+    #[test]
+    fn verify() {
+        let m = Movie::new(&[1, 2, 3]);
+        assert!(m.verify(&[1, 2, 3]).is_ok());
+        assert!(m.verify(&[1, 2, 4]).is_err());
+    }
+
+    #[test]
+    fn record() {
+        let (mut rec, movie) = MovieRecorder::new(Box::new(script()), Movie::new(&[]));
+        for f in 1..10 {
+            rec.set_frame(f);
+        }
+        assert_eq!(
+            movie.lock().unwrap().events,
+            vec![
+                MovieEvent {
+                    frame: 2,
+                    button: Button::A,
+                    pressed: true
+                },
+                MovieEvent {
+                    frame: 4,
+                    button: Button::Start,
+                    pressed: true
+                },
+                MovieEvent {
+                    frame: 5,
+                    button: Button::A,
+                    pressed: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn playback() {
+        let mut p = MoviePlayer::new(movie());
+        assert!(!p.is_pressed(Button::A));
+        p.set_frame(1);
+        assert!(p.is_pressed(Button::A));
+        p.set_frame(2);
+        assert!(p.is_pressed(Button::A));
+        assert!(!p.is_pressed(Button::DPadLeft));
+        assert!(!p.is_finished());
+        p.set_frame(3);
+        assert!(!p.is_pressed(Button::A));
+        assert!(p.is_pressed(Button::DPadLeft));
+        assert!(p.is_finished());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::time::{HostClock, SystemClock};
+
+use std::fmt;
+
+/// Size of the blocks of RamInit::Pattern
+const PATTERN_BLOCK: usize = 0x10;
+
+/// Contents of WRAM, VRAM and HRAM at power on. Real hardware starts
+/// with semi-random contents, which hides (or exposes) games reading
+/// uninitialized memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    /// All zeros (the default, keeps test results stable)
+    #[default]
+    Zeros,
+    /// Alternating blocks of 0x00 and 0xFF, like many DMGs
+    Pattern,
+    /// Pseudo-random contents, reproducible with the same seed
+    Random(u64),
+}
+
+impl RamInit {
+    /// Parses 'zero', 'pattern' or 'random[:seed]'. Without a seed, the
+    /// seed is taken from the current time.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "zero" => Ok(Self::Zeros),
+            None if s == "pattern" => Ok(Self::Pattern),
+            None if s == "random" => Ok(Self::Random(SystemClock.now())),
+            Some(("random", seed)) => seed
+                .parse()
+                .map(Self::Random)
+                .map_err(|_| format!("Invalid seed '{}'", seed)),
+            _ => Err(format!(
+                "Unknown RAM initialization '{}' (zero, pattern, random[:seed])",
+                s
+            )),
+        }
+    }
+
+    /// Fills a memory. 'stream' selects the random sequence, so memories
+    /// initialized from the same seed have different contents.
+    pub fn fill(self, mem: &mut [u8], stream: u64) {
+        match self {
+            Self::Zeros => mem.fill(0),
+            Self::Pattern => {
+                for (i, block) in mem.chunks_mut(PATTERN_BLOCK).enumerate() {
+                    block.fill(if i % 2 == 0 { 0x00 } else { 0xFF });
+                }
+            }
+            Self::Random(seed) => {
+                let mut rng = SplitMix64(seed ^ stream.wrapping_mul(0xA076_1D64_78BD_642F));
+                for chunk in mem.chunks_mut(8) {
+                    let val = rng.next_u64().to_le_bytes();
+                    chunk.copy_from_slice(&val[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for RamInit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Zeros => write!(f, "zero"),
+            Self::Pattern => write!(f, "pattern"),
+            Self::Random(seed) => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+/// SplitMix64 pseudo-random number generator
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(RamInit::parse("zero"), Ok(RamInit::Zeros));
+        assert_eq!(RamInit::parse("pattern"), Ok(RamInit::Pattern));
+        assert_eq!(RamInit::parse("random:1234"), Ok(RamInit::Random(1234)));
+        assert!(matches!(RamInit::parse("random"), Ok(RamInit::Random(_))));
+        assert!(RamInit::parse("random:x").is_err());
+        assert!(RamInit::parse("ones").is_err());
+        assert!(RamInit::parse("pattern:1").is_err());
+
+        for init in [RamInit::Zeros, RamInit::Pattern, RamInit::Random(42)] {
+            assert_eq!(RamInit::parse(&init.to_string()), Ok(init));
+        }
+    }
+
+    #[test]
+    fn fill_zeros() {
+        let mut mem = [0xAA; 100];
+        RamInit::Zeros.fill(&mut mem, 0);
+        assert_eq!(mem, [0; 100]);
+    }
+
+    #[test]
+    fn fill_pattern() {
+        let mut mem = [0xAA; 0x30];
+        RamInit::Pattern.fill(&mut mem, 0);
+        assert_eq!(mem[..0x10], [0x00; 0x10]);
+        assert_eq!(mem[0x10..0x20], [0xFF; 0x10]);
+        assert_eq!(mem[0x20..], [0x00; 0x10]);
+    }
+
+    #[test]
+    fn fill_random() {
+        let fill = |seed, stream| {
+            let mut mem = [0; 1001];
+            RamInit::Random(seed).fill(&mut mem, stream);
+            mem
+        };
+        // Reproducible
+        assert_eq!(fill(1, 0), fill(1, 0));
+        // Different per seed and stream
+        assert_ne!(fill(1, 0), fill(2, 0));
+        assert_ne!(fill(1, 0), fill(1, 1));
+        // Not all the same value
+        let mem = fill(0, 0);
+        assert!(mem.iter().any(|&b| b != mem[0]));
+    }
+}
@@ -0,0 +1,570 @@
+//! JSON-RPC 2.0 control server for remote automation.
+//!
+//! Clients connect over TCP and send one request per line, each answered
+//! by a single line response. Requests are queued and only executed when
+//! the thread that owns the emulator calls handle_pending() or
+//! handle_next(), e.g. at frame boundaries.
+//!
+//! Methods:
+//!  - get_registers: {"a", "f", "b", ..., "sp", "pc"}
+//!  - read_memory {addr, len}: array of bytes (through peek)
+//!  - write_memory {addr, data}: writes an array of bytes
+//!  - press/release {button}: button name, e.g. "A" or "DPadUp"
+//!  - run_frames {n}: runs n frames, returns the frame count
+//!  - get_framebuffer: {width, height, data}, data is base64 encoded
+//!    RGB555 (little endian, row-major)
+//!  - save_state: base64 encoded savestate
+//!  - load_state {state}: restores a base64 encoded savestate
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::lcd::{LCD_H, LCD_W};
+use crate::input::input::{Button, ButtonState};
+
+/// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Method failed while executing
+const EXECUTION_ERROR: i64 = -32000;
+
+/// Maximum amount of frames a single run_frames request may run
+const MAX_FRAMES: u64 = 60 * 60 * 10;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+/// System the requests operate on
+struct Target<'a> {
+    emu: &'a mut Emulator,
+    buttons: &'a ButtonState,
+}
+
+type Method = fn(&mut Target, &Value) -> Result<Value, RpcError>;
+
+const METHODS: &[(&str, Method)] = &[
+    ("get_registers", get_registers),
+    ("read_memory", read_memory),
+    ("write_memory", write_memory),
+    ("press", press),
+    ("release", release),
+    ("run_frames", run_frames),
+    ("get_framebuffer", get_framebuffer),
+    ("save_state", save_state),
+    ("load_state", load_state),
+];
+
+/// Handles a single request, returns the response. Notifications
+/// (requests without an id) get no response.
+pub fn handle(request: &str, emu: &mut Emulator, buttons: &ButtonState) -> Option<String> {
+    let request: Value = match serde_json::from_str(request) {
+        Ok(r) => r,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            ))
+        }
+    };
+    let id = request.get("id").cloned();
+    let result = dispatch(&mut Target { emu, buttons }, &request);
+
+    // Errors in notifications are not reported either
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string(),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn dispatch(target: &mut Target, request: &Value) -> Result<Value, RpcError> {
+    if request.get("jsonrpc") != Some(&json!("2.0")) {
+        return Err(RpcError::new(INVALID_REQUEST, "Not a JSON-RPC 2.0 request"));
+    }
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Err(RpcError::new(INVALID_REQUEST, "Missing method"));
+    };
+    let Some(&(_, f)) = METHODS.iter().find(|(name, _)| *name == method) else {
+        return Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {}", method),
+        ));
+    };
+    f(target, request.get("params").unwrap_or(&Value::Null))
+}
+
+fn error_response(id: Value, e: RpcError) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": e.code, "message": e.message},
+    })
+    .to_string()
+}
+
+/// Returns a numeric parameter
+fn param_u64(params: &Value, name: &str, max: u64) -> Result<u64, RpcError> {
+    match params.get(name).and_then(Value::as_u64) {
+        Some(v) if v <= max => Ok(v),
+        Some(_) => Err(RpcError::params(format!("{} out of range", name))),
+        None => Err(RpcError::params(format!("Missing or invalid {}", name))),
+    }
+}
+
+fn param_str<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::params(format!("Missing or invalid {}", name)))
+}
+
+fn get_registers(target: &mut Target, _params: &Value) -> Result<Value, RpcError> {
+    let regs = &target.emu.cpu().regs;
+    Ok(json!({
+        "a": regs.a,
+        "f": regs.f,
+        "b": regs.b,
+        "c": regs.c,
+        "d": regs.d,
+        "e": regs.e,
+        "h": regs.h,
+        "l": regs.l,
+        "sp": regs.sp,
+        "pc": regs.pc,
+    }))
+}
+
+fn read_memory(target: &mut Target, params: &Value) -> Result<Value, RpcError> {
+    let addr = param_u64(params, "addr", u16::MAX as u64)? as u16;
+    let len = param_u64(params, "len", 0x10000)?;
+    let mem: Vec<u8> = (0..len)
+        .map(|i| target.emu.cpu().peek(addr.wrapping_add(i as u16)))
+        .collect();
+    Ok(json!(mem))
+}
+
+fn write_memory(target: &mut Target, params: &Value) -> Result<Value, RpcError> {
+    let addr = param_u64(params, "addr", u16::MAX as u64)? as u16;
+    let data: Vec<u8> = params
+        .get("data")
+        .and_then(Value::as_array)
+        .and_then(|a| {
+            a.iter()
+                .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+                .collect()
+        })
+        .ok_or_else(|| RpcError::params("Missing or invalid data"))?;
+    for (i, &b) in data.iter().enumerate() {
+        target.emu.cpu_mut().write(addr.wrapping_add(i as u16), b);
+    }
+    Ok(Value::Null)
+}
+
+fn set_button(target: &mut Target, params: &Value, pressed: bool) -> Result<Value, RpcError> {
+    let name = param_str(params, "button")?;
+    let button =
+        Button::from_str(name).map_err(|_| RpcError::params(format!("Unknown button {}", name)))?;
+    target.buttons.set(button, pressed);
+    Ok(Value::Null)
+}
+
+fn press(target: &mut Target, params: &Value) -> Result<Value, RpcError> {
+    set_button(target, params, true)
+}
+
+fn release(target: &mut Target, params: &Value) -> Result<Value, RpcError> {
+    set_button(target, params, false)
+}
+
+fn run_frames(target: &mut Target, params: &Value) -> Result<Value, RpcError> {
+    for _ in 0..param_u64(params, "n", MAX_FRAMES)? {
+        target
+            .emu
+            .run_frame()
+            .map_err(|e| RpcError::new(EXECUTION_ERROR, format!("{:#}", e)))?;
+    }
+    Ok(json!(target.emu.get_frame_count()))
+}
+
+fn get_framebuffer(target: &mut Target, _params: &Value) -> Result<Value, RpcError> {
+    let data: Vec<u8> = target
+        .emu
+        .get_framebuffer()
+        .iter()
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+    Ok(json!({
+        "width": LCD_W,
+        "height": LCD_H,
+        "data": base64_encode(&data),
+    }))
+}
+
+fn save_state(target: &mut Target, _params: &Value) -> Result<Value, RpcError> {
+    Ok(json!(base64_encode(&target.emu.save_state())))
+}
+
+fn load_state(target: &mut Target, params: &Value) -> Result<Value, RpcError> {
+    let state = base64_decode(param_str(params, "state")?)
+        .ok_or_else(|| RpcError::params("Invalid base64 in state"))?;
+    target
+        .emu
+        .load_state(&state)
+        .map_err(|e| RpcError::new(EXECUTION_ERROR, format!("{:#}", e)))?;
+    Ok(Value::Null)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if s.len() % 4 != 0 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for chunk in s.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let v = BASE64.iter().position(|&b| b == c)?;
+            n = n << 6 | v as u32;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+/// A request from a client, with the channel to send the response to
+struct Call {
+    request: String,
+    response: mpsc::Sender<String>,
+}
+
+/// Control server accepting clients on a background thread
+pub struct RemoteServer {
+    calls: mpsc::Receiver<Call>,
+    port: u16,
+}
+
+impl RemoteServer {
+    /// Starts listening, port 0 selects a free port (see port())
+    pub fn listen(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("Cannot start control server")?;
+        let port = listener.local_addr()?.port();
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("remote".to_string())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let calls = tx.clone();
+                    // A client that goes away only ends its own thread
+                    let _ = thread::Builder::new()
+                        .name("remote client".to_string())
+                        .spawn(move || Self::serve_client(stream, calls));
+                }
+            })?;
+        Ok(Self { calls: rx, port })
+    }
+
+    /// Port the server listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn serve_client(stream: TcpStream, calls: mpsc::Sender<Call>) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (tx, rx) = mpsc::channel();
+            let call = Call {
+                request: line,
+                response: tx,
+            };
+            if calls.send(call).is_err() {
+                // Server stopped
+                return Ok(());
+            }
+            // Notifications drop the sender without a response
+            if let Ok(response) = rx.recv() {
+                writer.write_all(response.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(call: Call, emu: &mut Emulator, buttons: &ButtonState) {
+        if let Some(response) = handle(&call.request, emu, buttons) {
+            // Client may have disconnected
+            let _ = call.response.send(response);
+        }
+    }
+
+    /// Executes all queued requests, without blocking
+    pub fn handle_pending(&self, emu: &mut Emulator, buttons: &ButtonState) {
+        while let Ok(call) = self.calls.try_recv() {
+            Self::execute(call, emu, buttons);
+        }
+    }
+
+    /// Waits for a request and executes it
+    pub fn handle_next(&self, emu: &mut Emulator, buttons: &ButtonState) -> Result<()> {
+        let call = self.calls.recv().context("Control server stopped")?;
+        Self::execute(call, emu, buttons);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::display::NullDisplay;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::model::Model;
+    use crate::gameboy::serial::Serial;
+    use crate::input::input::SharedInput;
+
+    fn emulator() -> (Emulator, ButtonState) {
+        emulator_model(Model::Dmg)
+    }
+
+    fn emulator_model(model: Model) -> (Emulator, ButtonState) {
+        // 0x0100: LD A,42h, JR -2
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x104].copy_from_slice(&[0x3E, 0x42, 0x18, 0xFE]);
+        let (input, buttons) = SharedInput::new();
+        let emu = Emulator::new(
+            cartridge::load(&rom).unwrap(),
+            None,
+            Box::new(NullDisplay::new()),
+            input,
+            model,
+            Serial::new_null(),
+        );
+        (emu, buttons)
+    }
+
+    /// Handles a request, returns the parsed response
+    fn call(emu: &mut Emulator, buttons: &ButtonState, request: Value) -> Value {
+        let response = handle(&request.to_string(), emu, buttons).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    fn request(method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params})
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn registers() {
+        let (mut emu, buttons) = emulator();
+        let r = call(&mut emu, &buttons, request("get_registers", Value::Null));
+        assert_eq!(r["id"], 1);
+        assert_eq!(r["result"]["pc"], 0x0100);
+        assert_eq!(r["result"]["sp"], 0xFFFE);
+    }
+
+    #[test]
+    fn memory() {
+        let (mut emu, buttons) = emulator();
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("write_memory", json!({"addr": 0xC000, "data": [1, 2, 3]})),
+        );
+        assert_eq!(r["result"], Value::Null);
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("read_memory", json!({"addr": 0xBFFF, "len": 5})),
+        );
+        // No cartridge RAM at 0xBFFF
+        assert_eq!(r["result"], json!([0xFF, 1, 2, 3, 0]));
+
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("write_memory", json!({"addr": 0xC000, "data": [256]})),
+        );
+        assert_eq!(error_code(&r), INVALID_PARAMS);
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("read_memory", json!({"addr": 0x10000, "len": 1})),
+        );
+        assert_eq!(error_code(&r), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn memory_io_cgb() {
+        let (mut emu, buttons) = emulator_model(Model::Cgb);
+        // Includes KEY1, which is handled by the CPU
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("read_memory", json!({"addr": 0xFF00, "len": 0x100})),
+        );
+        assert_eq!(r["result"].as_array().unwrap().len(), 0x100);
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("write_memory", json!({"addr": 0xFF4D, "data": [1]})),
+        );
+        assert_eq!(r["result"], Value::Null);
+        assert_eq!(emu.cpu().peek(0xFF4D) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn buttons() {
+        let (mut emu, buttons) = emulator();
+        call(
+            &mut emu,
+            &buttons,
+            request("press", json!({"button": "Start"})),
+        );
+        assert!(buttons.is_pressed(Button::Start));
+        call(
+            &mut emu,
+            &buttons,
+            request("release", json!({"button": "Start"})),
+        );
+        assert!(!buttons.is_pressed(Button::Start));
+
+        let r = call(&mut emu, &buttons, request("press", json!({"button": "X"})));
+        assert_eq!(error_code(&r), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn frames_and_states() {
+        let (mut emu, buttons) = emulator();
+        let state = call(&mut emu, &buttons, request("save_state", Value::Null));
+        let r = call(&mut emu, &buttons, request("run_frames", json!({"n": 2})));
+        assert_eq!(r["result"], 2);
+        let r = call(&mut emu, &buttons, request("get_registers", Value::Null));
+        assert_eq!(r["result"]["a"], 0x42);
+
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("load_state", json!({"state": state["result"]})),
+        );
+        assert_eq!(r["result"], Value::Null);
+        assert_eq!(emu.get_frame_count(), 0);
+        assert_eq!(emu.cpu().regs.pc, 0x0100);
+
+        let r = call(
+            &mut emu,
+            &buttons,
+            request("load_state", json!({"state": "AAAA"})),
+        );
+        assert_eq!(error_code(&r), EXECUTION_ERROR);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let (mut emu, buttons) = emulator();
+        let r = call(&mut emu, &buttons, request("get_framebuffer", Value::Null));
+        assert_eq!(r["result"]["width"], LCD_W);
+        assert_eq!(r["result"]["height"], LCD_H);
+        let data = base64_decode(r["result"]["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data.len(), LCD_W * LCD_H * 2);
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let (mut emu, buttons) = emulator();
+        let r: Value = serde_json::from_str(&handle("{", &mut emu, &buttons).unwrap()).unwrap();
+        assert_eq!(error_code(&r), PARSE_ERROR);
+        assert_eq!(r["id"], Value::Null);
+
+        let r = call(
+            &mut emu,
+            &buttons,
+            json!({"id": 2, "method": "get_registers"}),
+        );
+        assert_eq!(error_code(&r), INVALID_REQUEST);
+        assert_eq!(r["id"], 2);
+
+        let r = call(&mut emu, &buttons, request("reboot", Value::Null));
+        assert_eq!(error_code(&r), METHOD_NOT_FOUND);
+
+        let r = call(&mut emu, &buttons, request("run_frames", json!({"n": -1})));
+        assert_eq!(error_code(&r), INVALID_PARAMS);
+
+        // Notifications are executed, but not answered
+        let notification = json!({"jsonrpc": "2.0", "method": "press", "params": {"button": "A"}});
+        assert!(handle(&notification.to_string(), &mut emu, &buttons).is_none());
+        assert!(buttons.is_pressed(Button::A));
+    }
+
+    #[test]
+    fn base64() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (&b"f"[..], "Zg=="),
+            (&b"fo"[..], "Zm8="),
+            (&b"foo"[..], "Zm9v"),
+            (&b"foob"[..], "Zm9vYg=="),
+            (&[0xFF, 0xEF, 0x00][..], "/+8A"),
+        ] {
+            assert_eq!(base64_encode(data), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), data);
+        }
+        assert!(base64_decode("Zg=").is_none());
+        assert!(base64_decode("Z===").is_none());
+        assert!(base64_decode("Zg!=").is_none());
+    }
+}
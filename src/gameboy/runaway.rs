@@ -0,0 +1,68 @@
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::cpu::cpu::CPU;
+
+use std::fmt;
+
+/// Consecutive self-calls after which the CPU is considered crashed.
+/// Every call pushes 2 bytes, so this is 2KB of stack.
+pub const RUNAWAY_STEPS: usize = 1024;
+
+/// A detected runaway loop: the CPU keeps calling the address it is
+/// executing, pushing on the stack without ever returning. This
+/// typically is RST 38H at 0x0038 after a jump into 0xFF-filled memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Runaway {
+    /// Address of the looping instruction
+    pub pc: u16,
+    /// Opcode at that address
+    pub opcode: u8,
+    /// Stack pointer at the time of detection
+    pub sp: u16,
+}
+
+impl fmt::Display for Runaway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Likely crashed: executing 0x{:02X} at 0x{:04X} repeatedly, SP={:04X}",
+            self.opcode, self.pc, self.sp
+        )
+    }
+}
+
+/// Detects runaway loops, see Runaway. Legitimate tight loops (HALT,
+/// polling LY, JR to itself) do not touch the stack, so they never
+/// trip the detector.
+#[derive(Debug, Clone, Default)]
+pub struct RunawayDetector {
+    /// Consecutive self-calls seen
+    steps: usize,
+
+    /// Stack pointer after the last observed instruction
+    sp: u16,
+}
+
+impl RunawayDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Observes an executed instruction, with the PC before executing
+    /// it and the CPU state after. Returns the runaway loop once, when
+    /// it has run for RUNAWAY_STEPS instructions.
+    pub fn observe(&mut self, pc: u16, cpu: &CPU) -> Option<Runaway> {
+        let sp = cpu.regs.sp;
+        if cpu.regs.pc == pc && sp == self.sp.wrapping_sub(2) {
+            self.steps += 1;
+        } else {
+            self.steps = 0;
+        }
+        self.sp = sp;
+
+        (self.steps == RUNAWAY_STEPS).then(|| Runaway {
+            pc,
+            opcode: cpu.peek(pc),
+            sp,
+        })
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{bail, Result};
+
+/// Magic at the start of a savestate
+const SAVESTATE_MAGIC: &[u8; 4] = b"GBSS";
+
+/// Version of the savestate format. Bump when the layout changes.
+const SAVESTATE_VERSION: u32 = 11;
+
+/// Serializes component state into a savestate
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut w = Self { buf: vec![] };
+        w.put_slice(SAVESTATE_MAGIC);
+        w.put_u32(SAVESTATE_VERSION);
+        w
+    }
+
+    pub fn put_bool(&mut self, val: bool) {
+        self.put_u8(val as u8);
+    }
+
+    pub fn put_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn put_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_u128(&mut self, val: u128) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn put_usize(&mut self, val: usize) {
+        self.put_u64(val as u64);
+    }
+
+    /// Writes a slice of bytes (fixed length, not length-prefixed)
+    pub fn put_slice(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    /// Writes a length-prefixed block of bytes
+    pub fn put_block(&mut self, val: &[u8]) {
+        self.put_u32(val.len() as u32);
+        self.put_slice(val);
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Deserializes component state from a savestate
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        let mut r = Self { buf, pos: 0 };
+        if r.take(SAVESTATE_MAGIC.len())? != SAVESTATE_MAGIC {
+            bail!("Not a savestate");
+        }
+        let version = r.get_u32()?;
+        if version != SAVESTATE_VERSION {
+            bail!("Unsupported savestate version {}", version);
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            bail!("Savestate truncated");
+        }
+        let s = &self.buf[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(s)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => bail!("Invalid boolean value {}", v),
+        }
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn get_u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.take_array()?))
+    }
+
+    pub fn get_usize(&mut self) -> Result<usize> {
+        Ok(self.get_u64()?.try_into()?)
+    }
+
+    /// Reads a slice of bytes (fixed length, see StateWriter::put_slice)
+    pub fn get_slice(&mut self, out: &mut [u8]) -> Result<()> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    /// Reads a length-prefixed block of bytes
+    pub fn get_block(&mut self) -> Result<&'a [u8]> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Verifies all data was consumed
+    pub fn finish(self) -> Result<()> {
+        if self.pos != self.buf.len() {
+            bail!("Trailing data in savestate");
+        }
+        Ok(())
+    }
+}
+
+/// A component of which the state can be saved and restored
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut w = StateWriter::new();
+        w.put_bool(true);
+        w.put_u8(0x12);
+        w.put_u16(0x3456);
+        w.put_u32(0x789ABCDE);
+        w.put_u64(u64::MAX - 1);
+        w.put_u128(u128::MAX - 2);
+        w.put_usize(1234);
+        w.put_slice(&[1, 2, 3]);
+        w.put_block(&[4, 5]);
+        let state = w.into_vec();
+
+        let mut r = StateReader::new(&state).unwrap();
+        assert!(r.get_bool().unwrap());
+        assert_eq!(r.get_u8().unwrap(), 0x12);
+        assert_eq!(r.get_u16().unwrap(), 0x3456);
+        assert_eq!(r.get_u32().unwrap(), 0x789ABCDE);
+        assert_eq!(r.get_u64().unwrap(), u64::MAX - 1);
+        assert_eq!(r.get_u128().unwrap(), u128::MAX - 2);
+        assert_eq!(r.get_usize().unwrap(), 1234);
+        let mut s = [0; 3];
+        r.get_slice(&mut s).unwrap();
+        assert_eq!(s, [1, 2, 3]);
+        assert_eq!(r.get_block().unwrap(), &[4, 5]);
+        r.finish().unwrap();
+    }
+
+    #[test]
+    fn header() {
+        assert!(StateReader::new(&[]).is_err());
+        assert!(StateReader::new(b"GBSS\xFF\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"XXXX\x01\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x01\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x03\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x04\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x05\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x06\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x07\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x08\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x09\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x0A\x00\x00\x00").is_err());
+        assert!(StateReader::new(b"GBSS\x0B\x00\x00\x00").is_ok());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut w = StateWriter::new();
+        w.put_u16(0x1234);
+        let state = w.into_vec();
+
+        let mut r = StateReader::new(&state[..state.len() - 1]).unwrap();
+        assert!(r.get_u16().is_err());
+
+        let mut r = StateReader::new(&state).unwrap();
+        assert!(r.get_u32().is_err());
+    }
+
+    #[test]
+    fn trailing() {
+        let mut w = StateWriter::new();
+        w.put_u16(0x1234);
+        let state = w.into_vec();
+
+        let mut r = StateReader::new(&state).unwrap();
+        r.get_u8().unwrap();
+        assert!(r.finish().is_err());
+    }
+
+    #[test]
+    fn invalid_bool() {
+        let mut w = StateWriter::new();
+        w.put_u8(2);
+        let state = w.into_vec();
+        assert!(StateReader::new(&state).unwrap().get_bool().is_err());
+    }
+}
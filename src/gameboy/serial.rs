@@ -1,9 +1,89 @@
 use anyhow::Result;
 use std::io;
 use std::io::Write;
+use std::sync::{mpsc, Arc, Mutex};
 
 use crate::gameboy::bus::bus::BusMember;
-use crate::tickable::{Tickable, Ticks};
+use crate::gameboy::cpu::cpu;
+use crate::gameboy::link::LockstepLink;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+use crate::misc::{ReadableReceiver, WritableSender};
+use crate::tickable::{TickResult, Tickable, Ticks};
+
+/// One end of an in-memory link cable (see Serial::new_crossed)
+pub type LinkChannels = (mpsc::Sender<u8>, mpsc::Receiver<u8>);
+
+/// Creates both ends of an in-memory link cable
+pub fn crossed_channels() -> (LinkChannels, LinkChannels) {
+    let (a_tx, b_rx) = mpsc::channel();
+    let (b_tx, a_rx) = mpsc::channel();
+    ((a_tx, a_rx), (b_tx, b_rx))
+}
+
+/// Collects the bytes sent out of a serial port (see
+/// Serial::new_buffered()). Clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct SerialBuffer {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SerialBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes all bytes collected so far, clearing the buffer
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.buf.lock().unwrap())
+    }
+
+    /// Takes all bytes collected so far as text, clearing the buffer.
+    /// Invalid UTF-8 is replaced.
+    pub fn take_string(&self) -> String {
+        String::from_utf8_lossy(&self.take()).into_owned()
+    }
+
+    /// Takes the complete lines collected so far, without line endings.
+    /// An unterminated last line stays in the buffer.
+    pub fn lines(&self) -> Vec<String> {
+        let mut buf = self.buf.lock().unwrap();
+        let Some(end) = buf.iter().rposition(|&b| b == b'\n')
+            else { return vec![] };
+        let rest = buf.split_off(end + 1);
+        let complete = std::mem::replace(&mut *buf, rest);
+        complete[..end]
+            .split(|&b| b == b'\n')
+            .map(|l| String::from_utf8_lossy(l.strip_suffix(b"\r").unwrap_or(l)).into_owned())
+            .collect()
+    }
+}
+
+impl io::Write for SerialBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Calls a function for every byte written (see Serial::on_byte())
+struct ByteCallback(Box<dyn FnMut(u8)>);
+
+impl io::Write for ByteCallback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            (self.0)(b);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 /// Serial (link cable) controller
 pub struct Serial {
@@ -19,7 +99,10 @@ pub struct Serial {
     /// Serial output stream
     serial_out: Option<Box<dyn io::Write>>,
 
-    /// Interrupt request
+    /// Lockstep connection to another instance (instead of the streams)
+    link: Option<LockstepLink>,
+
+    /// Transfer completed, interrupt request pending
     intreq: bool,
 }
 
@@ -36,20 +119,55 @@ impl Serial {
         Self::_new(Some(serial_in), Some(serial_out))
     }
 
+    /// Creates a serial port without anything connected that collects
+    /// the bytes sent out into a buffer (e.g. test ROM output)
+    pub fn new_buffered() -> (Self, SerialBuffer) {
+        let buffer = SerialBuffer::new();
+        (Self::new_out(Box::new(buffer.clone())), buffer)
+    }
+
+    /// Creates a serial port without anything connected that calls
+    /// the specified function for every byte sent out
+    pub fn on_byte(callback: Box<dyn FnMut(u8)>) -> Self {
+        Self::new_out(Box::new(ByteCallback(callback)))
+    }
+
+    /// Creates a serial port connected to another emulator instance
+    /// through channels (see crossed_channels()). Both instances need
+    /// to run on separate threads.
+    pub fn new_crossed(tx: mpsc::Sender<u8>, rx: mpsc::Receiver<u8>) -> Self {
+        Self::new(
+            Box::new(ReadableReceiver::new(rx)),
+            Box::new(WritableSender::new(tx)),
+        )
+    }
+
+    /// Creates a serial port connected to another emulator instance
+    /// through a lockstep link
+    pub fn new_lockstep(link: LockstepLink) -> Self {
+        Self {
+            link: Some(link),
+            ..Self::new_null()
+        }
+    }
+
     fn _new(serial_in: Option<Box<dyn io::Read>>, serial_out: Option<Box<dyn io::Write>>) -> Self {
         Self {
             serial_in,
             serial_out,
+            link: None,
             serialbuffer: 0,
             sc: 0,
             intreq: false,
         }
     }
 
-    pub fn get_clr_intreq(&mut self) -> bool {
-        let val = self.intreq;
-        self.intreq = false;
-        val
+    /// Blocks while a lockstep link partner is too far behind
+    /// (see LockstepLink::wait_for_peer())
+    pub fn wait_for_link(&mut self) {
+        if let Some(link) = &mut self.link {
+            link.wait_for_peer();
+        }
     }
 }
 
@@ -74,23 +192,40 @@ impl BusMember for Serial {
             // I/O - Serial transfer control
             0xFF02 => {
                 if val & 0x81 == 0x81 {
+                    if let Some(link) = &mut self.link {
+                        self.serialbuffer = link.transfer(self.serialbuffer);
+                        self.intreq = true;
+                        self.sc = val & !0x80;
+                        return;
+                    }
                     if let Some(ref mut so) = &mut self.serial_out {
-                        so.write(&[self.serialbuffer]).unwrap();
+                        // Other side may have disconnected
+                        let _ = so.write_all(&[self.serialbuffer]);
                     }
                     if let Some(ref mut si) = &mut self.serial_in {
                         let mut buf = [0; 1];
                         loop {
                             match si.read_exact(&mut buf) {
                                 Ok(()) => break,
-                                _ => (),
+                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                                Err(_) => {
+                                    // Disconnected, nothing connected to the port
+                                    buf[0] = 0xFF;
+                                    break;
+                                }
                             }
                         }
                         self.serialbuffer = buf[0];
                     } else {
                         self.serialbuffer = 0xFF;
                     }
+                    self.intreq = true;
+                    self.sc = val & !0x80;
+                } else {
+                    // External clock: the transfer completes when the
+                    // other side sends a byte.
+                    self.sc = val;
                 }
-                self.sc = val & !0x80;
             }
 
             _ => unreachable!(),
@@ -99,22 +234,107 @@ impl BusMember for Serial {
 }
 
 impl Tickable for Serial {
-    fn tick(&mut self, _ticks: Ticks) -> Result<()> {
-        if let Some(ref mut si) = &mut self.serial_in {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
+        if let Some(link) = &mut self.link {
+            if let Some(b) = link.tick(ticks.get_t_no_ds() as u64, self.serialbuffer) {
+                self.serialbuffer = b;
+                self.sc &= !0x80;
+                self.intreq = true;
+            }
+        } else if let Some(ref mut si) = &mut self.serial_in {
             let mut buf = [0; 1];
             match si.read_exact(&mut buf) {
                 Ok(()) => {
                     if let Some(ref mut so) = &mut self.serial_out {
-                        so.write(&[self.serialbuffer]).unwrap();
+                        let _ = so.write_all(&[self.serialbuffer]);
                     }
                     self.serialbuffer = buf[0];
-                    self.sc = 0x01;
+                    self.sc &= !0x80;
                     self.intreq = true;
                 }
                 _ => (),
             }
         }
 
+        if std::mem::take(&mut self.intreq) {
+            Ok(cpu::INT_SERIAL)
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+impl Savestate for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_u8(self.serialbuffer);
+        w.put_u8(self.sc);
+        w.put_bool(self.intreq);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.serialbuffer = r.get_u8()?;
+        self.sc = r.get_u8()?;
+        self.intreq = r.get_bool()?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(s: &mut Serial, b: u8) {
+        s.write(0xFF01, b);
+        s.write(0xFF02, 0x81);
+    }
+
+    #[test]
+    fn buffer_interleaved() {
+        let (mut a, buf) = Serial::new_buffered();
+        let mut b = Serial::new_out(Box::new(buf.clone()));
+        send(&mut a, b'a');
+        send(&mut b, b'b');
+        send(&mut a, b'c');
+        assert_eq!(buf.take(), b"abc");
+    }
+
+    #[test]
+    fn buffer_take_clears() {
+        let (mut s, buf) = Serial::new_buffered();
+        send(&mut s, b'x');
+        assert_eq!(buf.take_string(), "x");
+        assert_eq!(buf.take_string(), "");
+        assert!(buf.take().is_empty());
+    }
+
+    #[test]
+    fn buffer_lossy() {
+        let (mut s, buf) = Serial::new_buffered();
+        for b in [b'o', 0xFF, b'k'] {
+            send(&mut s, b);
+        }
+        assert_eq!(buf.take_string(), "o\u{FFFD}k");
+    }
+
+    #[test]
+    fn buffer_lines() {
+        let (mut s, buf) = Serial::new_buffered();
+        for &b in b"one\r\ntwo\nthr" {
+            send(&mut s, b);
+        }
+        assert_eq!(buf.lines(), ["one", "two"]);
+        assert!(buf.lines().is_empty());
+        send(&mut s, b'\n');
+        assert_eq!(buf.lines(), ["thr"]);
+    }
+
+    #[test]
+    fn on_byte() {
+        let out = Arc::new(Mutex::new(vec![]));
+        let out_cb = out.clone();
+        let mut s = Serial::on_byte(Box::new(move |b| out_cb.lock().unwrap().push(b)));
+        send(&mut s, 0x12);
+        send(&mut s, 0x34);
+        assert_eq!(*out.lock().unwrap(), [0x12, 0x34]);
+    }
+}
@@ -0,0 +1,630 @@
+use crate::display::display::Color;
+use crate::display::palette::DmgPalette;
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
+
+use anyhow::{bail, Result};
+
+/// Size of a command packet, in bytes
+pub const PACKET_SIZE: usize = 16;
+/// Maximum amount of packets of a command
+const MAX_PACKETS: usize = 7;
+/// Size of the data of a VRAM transfer (e.g. PAL_TRN)
+pub const VRAM_TRANSFER_SIZE: usize = 4096;
+/// Amount of system palettes (loaded by PAL_TRN)
+const SYSTEM_PALETTES: usize = 512;
+
+/// P1 select bits used for the pulses
+const P1_SELECT_MASK: u8 = 0x30;
+/// Reset pulse (P14 and P15 low)
+const P1_RESET: u8 = 0x00;
+/// '0' bit (P14 low)
+const P1_BIT0: u8 = 0x20;
+/// '1' bit (P15 low)
+const P1_BIT1: u8 = 0x10;
+/// Idle level between pulses (P14 and P15 high)
+const P1_IDLE: u8 = 0x30;
+
+const CMD_PAL01: u8 = 0x00;
+const CMD_PAL23: u8 = 0x01;
+const CMD_PAL03: u8 = 0x02;
+const CMD_PAL12: u8 = 0x03;
+const CMD_PAL_SET: u8 = 0x0A;
+const CMD_PAL_TRN: u8 = 0x0B;
+const CMD_MLT_REQ: u8 = 0x11;
+
+/// Decodes command packets from the pulses written to P1
+pub struct PacketReceiver {
+    /// Receiving a packet (a reset pulse was seen)
+    receiving: bool,
+    /// Previous P1 select bits
+    prev: u8,
+    buf: [u8; PACKET_SIZE],
+    /// Amount of bits received of the current packet
+    bits: usize,
+}
+
+impl PacketReceiver {
+    pub fn new() -> Self {
+        Self {
+            receiving: false,
+            prev: P1_IDLE,
+            buf: [0; PACKET_SIZE],
+            bits: 0,
+        }
+    }
+
+    /// Processes a write to P1, returns a packet once it is complete
+    pub fn write(&mut self, val: u8) -> Option<[u8; PACKET_SIZE]> {
+        let sel = val & P1_SELECT_MASK;
+        let prev = std::mem::replace(&mut self.prev, sel);
+
+        if sel == P1_RESET {
+            self.receiving = true;
+            self.bits = 0;
+            self.buf = [0; PACKET_SIZE];
+            return None;
+        }
+        // Bits are only sampled on the edge from the idle level
+        if !self.receiving || prev != P1_IDLE || sel == P1_IDLE {
+            return None;
+        }
+
+        let bit = sel == P1_BIT1;
+        if self.bits == PACKET_SIZE * 8 {
+            // Stop bit, a '1' aborts the packet
+            self.receiving = false;
+            return (!bit).then_some(self.buf);
+        }
+        if bit {
+            self.buf[self.bits / 8] |= 1 << (self.bits % 8);
+        }
+        self.bits += 1;
+        None
+    }
+}
+
+impl Default for PacketReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for PacketReceiver {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_bool(self.receiving);
+        w.put_u8(self.prev);
+        w.put_slice(&self.buf);
+        w.put_usize(self.bits);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.receiving = r.get_bool()?;
+        self.prev = r.get_u8()? & P1_SELECT_MASK;
+        r.get_slice(&mut self.buf)?;
+        self.bits = r.get_usize()?;
+        if self.bits > PACKET_SIZE * 8 {
+            bail!("Invalid SGB packet bit count {}", self.bits);
+        }
+        Ok(())
+    }
+}
+
+/// A decoded SGB command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// PAL01/PAL23/PAL03/PAL12: sets colors 1 - 3 of two palettes and
+    /// the shared color 0
+    Palettes {
+        pals: [usize; 2],
+        color0: Color,
+        colors: [[Color; 3]; 2],
+    },
+    /// PAL_SET: selects system palettes for palettes 0 - 3
+    PalSet { ids: [u16; 4], flags: u8 },
+    /// PAL_TRN: loads the system palettes through a VRAM transfer
+    PalTrn,
+    /// MLT_REQ: selects the amount of joypads (1, 2 or 4)
+    MltReq { players: u8 },
+    /// Any other (unsupported) command
+    Other { cmd: u8, data: Vec<u8> },
+}
+
+impl Command {
+    /// Decodes a command from its packets
+    pub fn decode(data: &[u8]) -> Self {
+        let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let cmd = data[0] >> 3;
+
+        match cmd {
+            CMD_PAL01 | CMD_PAL23 | CMD_PAL03 | CMD_PAL12 => Self::Palettes {
+                pals: match cmd {
+                    CMD_PAL01 => [0, 1],
+                    CMD_PAL23 => [2, 3],
+                    CMD_PAL03 => [0, 3],
+                    _ => [1, 2],
+                },
+                color0: word(1),
+                colors: [[word(3), word(5), word(7)], [word(9), word(11), word(13)]],
+            },
+            CMD_PAL_SET => Self::PalSet {
+                ids: [word(1), word(3), word(5), word(7)].map(|id| id & 0x1FF),
+                flags: data[9],
+            },
+            CMD_PAL_TRN => Self::PalTrn,
+            CMD_MLT_REQ => Self::MltReq {
+                players: match data[1] & 3 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                },
+            },
+            _ => Self::Other {
+                cmd,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+/// Super Gameboy: receives commands through P1 and applies the
+/// supported ones (palettes and multiplayer). Border graphics and
+/// attribute files are not supported.
+pub struct Sgb {
+    receiver: PacketReceiver,
+    /// Packets of the command being received
+    command: Vec<u8>,
+    /// Palettes 0 - 3 (color 0 is shared)
+    palettes: [DmgPalette; 4],
+    /// A palette command was received since power on
+    palettes_set: bool,
+    /// Palette 0 changed since the last take_palette()
+    palette_changed: bool,
+    system_palettes: Vec<DmgPalette>,
+    /// A PAL_TRN is waiting for the next frame
+    pal_trn: bool,
+    /// Amount of joypads (1, 2 or 4)
+    players: u8,
+    /// Currently selected joypad
+    player: u8,
+    /// Previous P1 select bits
+    prev_p1: u8,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            receiver: PacketReceiver::new(),
+            command: vec![],
+            palettes: [[0; 4]; 4],
+            palettes_set: false,
+            palette_changed: false,
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+            pal_trn: false,
+            players: 1,
+            player: 0,
+            prev_p1: P1_IDLE,
+        }
+    }
+
+    /// Processes a write to P1
+    pub fn write_p1(&mut self, val: u8) {
+        let sel = val & P1_SELECT_MASK;
+        let prev = std::mem::replace(&mut self.prev_p1, sel);
+        // The next joypad is selected when P15 goes high
+        if self.players > 1 && prev & P1_BIT0 == 0 && sel & P1_BIT0 != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+
+        let Some(packet) = self.receiver.write(val) else {
+            return;
+        };
+        if self.command.is_empty() && packet[0] & 7 == 0 {
+            // Zero-length commands are not valid
+            return;
+        }
+        self.command.extend_from_slice(&packet);
+        let packets = ((self.command[0] & 7) as usize).min(MAX_PACKETS);
+        if self.command.len() == packets * PACKET_SIZE {
+            let command = Command::decode(&self.command);
+            self.command.clear();
+            self.execute(command);
+        }
+    }
+
+    /// Modifies a P1 value read from the joypad for multiplayer
+    pub fn read_p1(&self, val: u8) -> u8 {
+        if self.players == 1 {
+            val
+        } else if val & P1_SELECT_MASK == P1_IDLE {
+            // Joypad ID
+            (val & 0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            // Only the first joypad is connected
+            val | 0x0F
+        } else {
+            val
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Palettes {
+                pals,
+                color0,
+                colors,
+            } => {
+                for (pal, colors) in pals.into_iter().zip(colors) {
+                    self.palettes[pal][1..].copy_from_slice(&colors);
+                }
+                self.set_color0(color0);
+                self.palettes_set = true;
+                self.palette_changed = true;
+            }
+            Command::PalSet { ids, .. } => {
+                for (pal, id) in ids.into_iter().enumerate() {
+                    self.palettes[pal] = self.system_palettes[id as usize];
+                }
+                self.set_color0(self.palettes[0][0]);
+                self.palettes_set = true;
+                self.palette_changed = true;
+            }
+            Command::PalTrn => self.pal_trn = true,
+            Command::MltReq { players } => {
+                self.players = players;
+                self.player = 0;
+            }
+            Command::Other { .. } => (),
+        }
+    }
+
+    fn set_color0(&mut self, color: Color) {
+        for p in &mut self.palettes {
+            p[0] = color;
+        }
+    }
+
+    /// Returns palette 0 if it changed since the last call
+    pub fn take_palette(&mut self) -> Option<DmgPalette> {
+        std::mem::take(&mut self.palette_changed).then_some(self.palettes[0])
+    }
+
+    /// Returns palette 0 if it was ever set
+    pub fn get_palette(&self) -> Option<DmgPalette> {
+        self.palettes_set.then_some(self.palettes[0])
+    }
+
+    /// A VRAM transfer is waiting for the next frame
+    pub fn wants_vram_transfer(&self) -> bool {
+        self.pal_trn
+    }
+
+    /// Completes a pending VRAM transfer with the tile data in VRAM
+    pub fn vram_transfer(&mut self, data: &[u8]) {
+        assert_eq!(data.len(), VRAM_TRANSFER_SIZE);
+
+        if std::mem::take(&mut self.pal_trn) {
+            for (pal, d) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                for (c, b) in pal.iter_mut().zip(d.chunks(2)) {
+                    *c = u16::from_le_bytes([b[0], b[1]]);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Sgb {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.receiver.save_state(w);
+        w.put_block(&self.command);
+        for c in self.palettes.iter().chain(&self.system_palettes).flatten() {
+            w.put_u16(*c);
+        }
+        w.put_bool(self.palettes_set);
+        w.put_bool(self.pal_trn);
+        w.put_u8(self.players);
+        w.put_u8(self.player);
+        w.put_u8(self.prev_p1);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.receiver.load_state(r)?;
+        self.command = r.get_block()?.to_vec();
+        if !self.command.len().is_multiple_of(PACKET_SIZE)
+            || self.command.len() >= MAX_PACKETS * PACKET_SIZE
+        {
+            bail!("Invalid SGB command length {}", self.command.len());
+        }
+        for c in self
+            .palettes
+            .iter_mut()
+            .chain(&mut self.system_palettes)
+            .flatten()
+        {
+            *c = r.get_u16()?;
+        }
+        self.palettes_set = r.get_bool()?;
+        self.palette_changed = self.palettes_set;
+        self.pal_trn = r.get_bool()?;
+        self.players = r.get_u8()?;
+        self.player = r.get_u8()?;
+        if ![1, 2, 4].contains(&self.players) || self.player >= self.players {
+            bail!("Invalid SGB joypad {}/{}", self.player, self.players);
+        }
+        self.prev_p1 = r.get_u8()? & P1_SELECT_MASK;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PAL01, palettes 0 and 1 set to the same greens
+    const PACKET_PAL01: [u8; PACKET_SIZE] = [
+        0x01, 0x7F, 0x7F, 0x1F, 0x3E, 0x08, 0x19, 0x00, 0x00, 0x1F, 0x3E, 0x08, 0x19, 0x00, 0x00,
+        0x00,
+    ];
+    /// MLT_REQ, two players
+    const PACKET_MLT_REQ2: [u8; PACKET_SIZE] = [
+        0x89, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+    /// MLT_REQ, one player
+    const PACKET_MLT_REQ1: [u8; PACKET_SIZE] = [
+        0x89, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+    /// PAL_SET, system palettes 1, 2, 3 and 4
+    const PACKET_PAL_SET: [u8; PACKET_SIZE] = [
+        0x51, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    /// Returns the P1 writes a game does to send a packet
+    fn pulses(packet: &[u8; PACKET_SIZE]) -> Vec<u8> {
+        let mut writes = vec![P1_RESET, P1_IDLE];
+        for byte in packet {
+            for bit in 0..8 {
+                writes.push(if byte & (1 << bit) != 0 {
+                    P1_BIT1
+                } else {
+                    P1_BIT0
+                });
+                writes.push(P1_IDLE);
+            }
+        }
+        // Stop bit
+        writes.extend([P1_BIT0, P1_IDLE]);
+        writes
+    }
+
+    fn send(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+        for w in pulses(packet) {
+            sgb.write_p1(w);
+        }
+    }
+
+    fn receive(rx: &mut PacketReceiver, writes: &[u8]) -> Vec<[u8; PACKET_SIZE]> {
+        writes.iter().filter_map(|&w| rx.write(w)).collect()
+    }
+
+    #[test]
+    fn receiver() {
+        let mut rx = PacketReceiver::new();
+        assert_eq!(receive(&mut rx, &pulses(&PACKET_PAL01)), [PACKET_PAL01]);
+        assert_eq!(
+            receive(&mut rx, &pulses(&PACKET_MLT_REQ2)),
+            [PACKET_MLT_REQ2]
+        );
+
+        // Unused bits of P1 are ignored
+        let writes: Vec<u8> = pulses(&PACKET_PAL_SET).iter().map(|w| w | 0xC1).collect();
+        assert_eq!(receive(&mut rx, &writes), [PACKET_PAL_SET]);
+    }
+
+    #[test]
+    fn receiver_joypad_reads() {
+        let mut rx = PacketReceiver::new();
+        // Reading the joypad is not a packet
+        assert!(receive(&mut rx, &[0x20, 0x30, 0x10, 0x30, 0x20, 0x10, 0x30]).is_empty());
+
+        // Packets need a reset pulse first
+        let writes = pulses(&PACKET_PAL01);
+        assert!(receive(&mut rx, &writes[1..]).is_empty());
+        assert_eq!(receive(&mut rx, &writes), [PACKET_PAL01]);
+    }
+
+    #[test]
+    fn receiver_repeated_levels() {
+        let mut rx = PacketReceiver::new();
+        // A bit only counts once until P1 returns to idle
+        let writes: Vec<u8> = pulses(&PACKET_PAL01)
+            .into_iter()
+            .flat_map(|w| [w, w, w])
+            .collect();
+        assert_eq!(receive(&mut rx, &writes), [PACKET_PAL01]);
+    }
+
+    #[test]
+    fn receiver_abort() {
+        let mut rx = PacketReceiver::new();
+
+        // A '1' stop bit discards the packet
+        let mut writes = pulses(&PACKET_PAL01);
+        let len = writes.len();
+        writes[len - 2] = P1_BIT1;
+        assert!(receive(&mut rx, &writes).is_empty());
+
+        // A reset pulse restarts the packet
+        let mut writes = pulses(&PACKET_MLT_REQ2)[..100].to_vec();
+        writes.extend(pulses(&PACKET_PAL01));
+        assert_eq!(receive(&mut rx, &writes), [PACKET_PAL01]);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            Command::decode(&PACKET_PAL01),
+            Command::Palettes {
+                pals: [0, 1],
+                color0: 0x7F7F,
+                colors: [[0x3E1F, 0x1908, 0x0000], [0x3E1F, 0x1908, 0x0000]],
+            }
+        );
+        assert_eq!(
+            Command::decode(&PACKET_MLT_REQ2),
+            Command::MltReq { players: 2 }
+        );
+        assert_eq!(
+            Command::decode(&PACKET_MLT_REQ1),
+            Command::MltReq { players: 1 }
+        );
+        assert_eq!(
+            Command::decode(&PACKET_PAL_SET),
+            Command::PalSet {
+                ids: [1, 2, 3, 4],
+                flags: 0
+            }
+        );
+
+        let mut p = PACKET_PAL01;
+        p[0] = (CMD_PAL12 << 3) | 1;
+        assert!(matches!(
+            Command::decode(&p),
+            Command::Palettes { pals: [1, 2], .. }
+        ));
+
+        // MASK_EN
+        let mut p = [0; PACKET_SIZE];
+        p[0] = (0x17 << 3) | 1;
+        p[1] = 1;
+        assert_eq!(
+            Command::decode(&p),
+            Command::Other {
+                cmd: 0x17,
+                data: p.to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn multi_packet_command() {
+        let mut sgb = Sgb::new();
+
+        // ATTR_BLK, 2 packets, is ignored but must not interfere
+        let mut p1 = [0; PACKET_SIZE];
+        p1[0] = (0x04 << 3) | 2;
+        let p2 = PACKET_MLT_REQ2;
+        send(&mut sgb, &p1);
+        send(&mut sgb, &p2);
+        assert_eq!(sgb.players, 1);
+        send(&mut sgb, &PACKET_MLT_REQ2);
+        assert_eq!(sgb.players, 2);
+    }
+
+    #[test]
+    fn palettes() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.get_palette(), None);
+        assert_eq!(sgb.take_palette(), None);
+
+        send(&mut sgb, &PACKET_PAL01);
+        let expected = [0x7F7F, 0x3E1F, 0x1908, 0x0000];
+        assert_eq!(sgb.take_palette(), Some(expected));
+        assert_eq!(sgb.take_palette(), None);
+        assert_eq!(sgb.get_palette(), Some(expected));
+        assert_eq!(sgb.palettes[1], expected);
+
+        // PAL23 changes the shared color 0
+        let mut p = PACKET_PAL01;
+        p[0] = (CMD_PAL23 << 3) | 1;
+        p[1] = 0x00;
+        send(&mut sgb, &p);
+        assert_eq!(sgb.take_palette(), Some([0x7F00, 0x3E1F, 0x1908, 0x0000]));
+        assert_eq!(sgb.palettes[3][0], 0x7F00);
+    }
+
+    #[test]
+    fn pal_trn_set() {
+        let mut sgb = Sgb::new();
+        let mut p = [0; PACKET_SIZE];
+        p[0] = (CMD_PAL_TRN << 3) | 1;
+        send(&mut sgb, &p);
+        assert!(sgb.wants_vram_transfer());
+
+        let data: Vec<u8> = (0..VRAM_TRANSFER_SIZE / 2)
+            .flat_map(|i| (i as u16).to_le_bytes())
+            .collect();
+        sgb.vram_transfer(&data);
+        assert!(!sgb.wants_vram_transfer());
+        assert_eq!(sgb.system_palettes[1], [4, 5, 6, 7]);
+
+        send(&mut sgb, &PACKET_PAL_SET);
+        assert_eq!(sgb.take_palette(), Some([4, 5, 6, 7]));
+        // Color 0 of palette 0 is shared
+        assert_eq!(sgb.palettes[3], [4, 17, 18, 19]);
+    }
+
+    #[test]
+    fn multiplayer() {
+        let mut sgb = Sgb::new();
+        // No joypad IDs with one player
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+
+        send(&mut sgb, &PACKET_MLT_REQ2);
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+        // P15 low, then high selects the next joypad
+        sgb.write_p1(0x10);
+        assert_eq!(sgb.read_p1(0xDE), 0xDE);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFE);
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFE);
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+
+        let mut p = PACKET_MLT_REQ2;
+        p[1] = 3;
+        send(&mut sgb, &p);
+        let mut ids = vec![];
+        for _ in 0..5 {
+            sgb.write_p1(0x10);
+            sgb.write_p1(0x30);
+            ids.push(sgb.read_p1(0xFF) & 0x0F);
+        }
+        assert_eq!(ids, [0x0E, 0x0D, 0x0C, 0x0F, 0x0E]);
+
+        send(&mut sgb, &PACKET_MLT_REQ1);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.read_p1(0xFF), 0xFF);
+    }
+
+    #[test]
+    fn savestate() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &PACKET_PAL01);
+        send(&mut sgb, &PACKET_MLT_REQ2);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        let mut w = StateWriter::new();
+        sgb.save_state(&mut w);
+
+        let state = w.into_vec();
+        let mut sgb2 = Sgb::new();
+        let mut r = StateReader::new(&state).unwrap();
+        sgb2.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(sgb2.take_palette(), sgb.get_palette());
+        assert_eq!(sgb2.read_p1(0xFF), 0xFE);
+    }
+}
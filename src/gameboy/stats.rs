@@ -0,0 +1,250 @@
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+use crate::time::{Clock, MonotonicClock};
+
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Default length of the sliding window
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Emulation performance statistics
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Emulated cycles per second, over the window
+    pub cycles_per_sec: f64,
+    /// Frames rendered per second, over the window
+    pub fps: f64,
+    /// Emulation speed over the last frame, relative to real hardware
+    /// (1.0 is real time)
+    pub speed: f64,
+    /// Standard deviation of the time between frames, over the window
+    pub jitter: Duration,
+    /// Emulated time at the last frame
+    pub emulated: Duration,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Speed: {:3.0}% | {:4.1} FPS | {:.2} MHz | Jitter: {:.1} ms | {}:{:02}",
+            self.speed * 100.0,
+            self.fps,
+            self.cycles_per_sec / 1_000_000.0,
+            self.jitter.as_secs_f64() * 1000.0,
+            self.emulated.as_secs() / 60,
+            self.emulated.as_secs() % 60
+        )
+    }
+}
+
+struct Sample {
+    time: Duration,
+    cycles: u64,
+}
+
+/// Collects statistics over a sliding window of rendered frames
+pub struct StatsCollector<C: Clock = MonotonicClock> {
+    clock: C,
+    window: Duration,
+    samples: VecDeque<Sample>,
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        Self::with_clock(MonotonicClock::new(), DEFAULT_WINDOW)
+    }
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> StatsCollector<C> {
+    pub fn with_clock(clock: C, window: Duration) -> Self {
+        Self {
+            clock,
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Records a rendered frame, with the emulated time (in cycles at
+    /// normal speed) at that frame
+    pub fn frame(&mut self, cycles: u64) {
+        let time = self.clock.now();
+        self.samples.push_back(Sample { time, cycles });
+        while self
+            .samples
+            .front()
+            .is_some_and(|s| s.time + self.window < time)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else {
+            return Stats::default();
+        };
+        let emulated = Duration::from_nanos(
+            (u128::from(last.cycles) * 1_000_000_000 / CPU_CLOCK_HZ as u128) as u64,
+        );
+        let span = (last.time - first.time).as_secs_f64();
+        if span == 0.0 {
+            return Stats {
+                emulated,
+                ..Default::default()
+            };
+        }
+
+        let prev = &self.samples[self.samples.len() - 2];
+        let last_span = (last.time - prev.time).as_secs_f64();
+        let speed = if last_span > 0.0 {
+            (last.cycles - prev.cycles) as f64 / CPU_CLOCK_HZ as f64 / last_span
+        } else {
+            0.0
+        };
+
+        let intervals = self.samples.len() - 1;
+        let mean = span / intervals as f64;
+        let variance = self
+            .samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .map(|(a, b)| ((b.time - a.time).as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / intervals as f64;
+
+        Stats {
+            cycles_per_sec: (last.cycles - first.cycles) as f64 / span,
+            fps: intervals as f64 / span,
+            speed,
+            jitter: Duration::from_secs_f64(variance.sqrt()),
+            emulated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Clock that only moves when told to
+    #[derive(Clone, Default)]
+    struct FakeClock(Rc<Cell<Duration>>);
+
+    impl FakeClock {
+        fn advance(&self, ms: u64) {
+            self.0.set(self.0.get() + Duration::from_millis(ms));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    const FRAME: u64 = 70224;
+
+    fn collector() -> (FakeClock, StatsCollector<FakeClock>) {
+        let clock = FakeClock::default();
+        let c = StatsCollector::with_clock(clock.clone(), Duration::from_secs(1));
+        (clock, c)
+    }
+
+    #[test]
+    fn empty() {
+        let (clock, mut c) = collector();
+        assert_eq!(c.stats(), Stats::default());
+        c.frame(0);
+        assert_eq!(c.stats(), Stats::default());
+        // No time passed
+        c.frame(FRAME);
+        let s = c.stats();
+        assert_eq!(
+            Stats {
+                emulated: Duration::ZERO,
+                ..s
+            },
+            Stats::default()
+        );
+        clock.advance(10);
+        c.frame(FRAME * 2);
+        assert_ne!(c.stats(), Stats::default());
+    }
+
+    #[test]
+    fn emulated() {
+        let (clock, mut c) = collector();
+        c.frame(CPU_CLOCK_HZ as u64 * 90);
+        assert_eq!(c.stats().emulated, Duration::from_secs(90));
+        clock.advance(10);
+        c.frame(CPU_CLOCK_HZ as u64 * 90 + FRAME);
+        let s = c.stats();
+        // 16.742706298828125 ms
+        assert_eq!(s.emulated, Duration::from_nanos(90_016_742_706));
+        assert!(s.to_string().ends_with(" | 1:30"), "{}", s);
+    }
+
+    #[test]
+    fn steady() {
+        let (clock, mut c) = collector();
+        // 50 frames per second, twice as fast as real time would be
+        // (59.7 frames per second)
+        for i in 0..100 {
+            c.frame(i * FRAME * 2);
+            clock.advance(20);
+        }
+        let s = c.stats();
+        assert!((s.fps - 50.0).abs() < 0.001, "{:?}", s);
+        assert!((s.cycles_per_sec - (FRAME * 2 * 50) as f64).abs() < 1.0);
+        assert!((s.speed - (FRAME * 2 * 50) as f64 / CPU_CLOCK_HZ as f64).abs() < 0.001);
+        assert!(s.jitter < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn window() {
+        let (clock, mut c) = collector();
+        // Slow first second, which drops out of the window
+        for i in 0..10 {
+            c.frame(i * FRAME);
+            clock.advance(100);
+        }
+        for i in 10..200 {
+            c.frame(i * FRAME);
+            clock.advance(10);
+        }
+        let s = c.stats();
+        assert!((s.fps - 100.0).abs() < 0.001, "{:?}", s);
+        assert!(s.jitter < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn jitter_and_speed() {
+        let (clock, mut c) = collector();
+        // Alternating 10 ms and 30 ms frames
+        for i in 0..=10 {
+            if i > 0 {
+                clock.advance(if i % 2 == 0 { 10 } else { 30 });
+            }
+            c.frame(i * FRAME);
+        }
+        let s = c.stats();
+        assert!((s.jitter.as_secs_f64() - 0.010).abs() < 0.0001, "{:?}", s);
+        assert!((s.fps - 50.0).abs() < 0.001);
+
+        // Instantaneous speed only looks at the last frame
+        clock.advance(30);
+        c.frame(10 * FRAME + FRAME / 2);
+        let expected = (FRAME / 2) as f64 / CPU_CLOCK_HZ as f64 / 0.030;
+        assert!((c.stats().speed - expected).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/// A label in a symbol file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub bank: usize,
+    pub addr: u16,
+    pub name: String,
+}
+
+/// Symbol table, as loaded from an RGBDS symbol (.sym) file
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+
+    /// Name to index in symbols
+    names: HashMap<String, usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses an RGBDS symbol file. Each line contains a symbol as
+    /// 'bank:addr name' (both in hexadecimal), comments start with ';'.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut table = Self::new();
+
+        for (lnum, line) in s.lines().enumerate() {
+            let line = match line.find(';') {
+                Some(i) => &line[..i],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse = || -> Result<Symbol> {
+                let (loc, name) = line.split_once(char::is_whitespace).context("No name")?;
+                let (bank, addr) = loc.split_once(':').context("No bank")?;
+                Ok(Symbol {
+                    bank: usize::from_str_radix(bank, 16).context("Invalid bank")?,
+                    addr: u16::from_str_radix(addr, 16).context("Invalid address")?,
+                    name: name.trim().to_string(),
+                })
+            };
+            let sym = parse().with_context(|| format!("Line {}: '{}'", lnum + 1, line))?;
+            if table.names.contains_key(&sym.name) {
+                bail!("Line {}: duplicate label '{}'", lnum + 1, sym.name);
+            }
+            table.names.insert(sym.name.clone(), table.symbols.len());
+            table.symbols.push(sym);
+        }
+
+        Ok(table)
+    }
+
+    pub fn load(filename: &Path) -> Result<Self> {
+        let s = fs::read_to_string(filename)
+            .with_context(|| format!("Cannot read {}", filename.display()))?;
+        Self::parse(&s)
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Finds a symbol by name
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.names.get(name).map(|&i| &self.symbols[i])
+    }
+
+    /// Finds the (first) label at an address. For the switchable ROM
+    /// bank (0x4000 - 0x7FFF), only labels in 'rom_bank' (the currently
+    /// mapped bank) match.
+    pub fn lookup(&self, addr: u16, rom_bank: usize) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|s| s.addr == addr && (!(0x4000..0x8000).contains(&addr) || s.bank == rom_bank))
+            .map(|s| s.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMFILE: &str = "\
+; File generated by rgblink
+00:0150 Start
+00:0150 EntryPoint ; two labels on the same address
+01:4000 BankedA
+02:4000 BankedB
+00:C0fF wCounter
+
+00:0200 Start.loop
+";
+
+    #[test]
+    fn parse() {
+        let t = SymbolTable::parse(SYMFILE).unwrap();
+        assert_eq!(t.len(), 6);
+        assert_eq!(
+            t.get("wCounter"),
+            Some(&Symbol {
+                bank: 0,
+                addr: 0xC0FF,
+                name: "wCounter".to_string()
+            })
+        );
+        assert_eq!(t.get("Start.loop").unwrap().addr, 0x0200);
+        assert_eq!(t.get("BankedB").unwrap().bank, 2);
+        assert!(t.get("Nope").is_none());
+    }
+
+    #[test]
+    fn parse_empty() {
+        let t = SymbolTable::parse("; only a comment\n\n").unwrap();
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(SymbolTable::parse("00:0150").is_err());
+        assert!(SymbolTable::parse("0150 Start").is_err());
+        assert!(SymbolTable::parse("00:XYZ Start").is_err());
+        assert!(SymbolTable::parse("00:10000 Start").is_err());
+
+        let Err(err) = SymbolTable::parse("00:0150 Start\n01:4000 Start\n") else {
+            panic!("Duplicate label accepted");
+        };
+        assert_eq!(err.to_string(), "Line 2: duplicate label 'Start'");
+    }
+
+    #[test]
+    fn lookup() {
+        let t = SymbolTable::parse(SYMFILE).unwrap();
+        // First label wins
+        assert_eq!(t.lookup(0x0150, 1), Some("Start"));
+        assert_eq!(t.lookup(0x0150, 5), Some("Start"));
+        assert_eq!(t.lookup(0xC0FF, 3), Some("wCounter"));
+        // Banked
+        assert_eq!(t.lookup(0x4000, 1), Some("BankedA"));
+        assert_eq!(t.lookup(0x4000, 2), Some("BankedB"));
+        assert_eq!(t.lookup(0x4000, 3), None);
+        assert_eq!(t.lookup(0x0151, 1), None);
+    }
+}
@@ -0,0 +1,665 @@
+//! Runs test ROMs headlessly and checks their results. Shared by the
+//! unit tests and the romtest binary.
+
+use crate::audio::audio::{hash_audio, AUDIO_HASH_SIZE};
+use crate::audio::wav::WavSink;
+use crate::display::test::{TestDisplay, TestDisplayState, TDS};
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::lcd::{PpuMode, LCD_H, LCD_W};
+use crate::gameboy::model::Model;
+use crate::gameboy::serial::Serial;
+use crate::input::input::NullInput;
+use crate::misc::panic_message;
+use crate::time::{Clock, MonotonicClock};
+
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+pub mod lockstep;
+pub mod vectors;
+
+pub use lockstep::{compare_trace, lockstep_compare, Chunk, CompareOptions, Divergence};
+
+/// Wall-clock safety net for ROM tests. Tests are limited by their
+/// cycle budget, this only catches an emulator that hangs.
+pub const TIME_LIMIT: Duration = Duration::from_secs(600);
+
+/// Cycle budget of the specified amount of emulated seconds
+pub const fn secs(s: u64) -> u64 {
+    s * CPU_CLOCK_HZ as u64
+}
+
+/// Signature at 0xA001 of tests reporting their result in cartridge RAM
+const MEMORY_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+/// Result status (at 0xA000) while a test is still running
+const MEMORY_RUNNING: u8 = 0x80;
+
+/// Amount of identical frames after which the display is considered
+/// to show the final result
+pub const STABLE_FRAMES: u16 = 100;
+
+/// Cycle budget of manifest entries that do not specify one
+pub const DEFAULT_BUDGET: u64 = secs(60);
+
+/// Result of running a test ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// The ROM reported a failure (or showed the wrong screen)
+    Fail(String),
+    /// The cycle budget ran out before the ROM reported a result
+    Timeout(String),
+    /// The emulator stopped with an error (or panicked)
+    Error(String),
+}
+
+impl Outcome {
+    pub fn is_pass(&self) -> bool {
+        *self == Outcome::Pass
+    }
+
+    /// Panics with the details if the test did not pass
+    pub fn unwrap(self) {
+        if !self.is_pass() {
+            panic!("{}", self);
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "Passed"),
+            Outcome::Fail(msg) => write!(f, "Test failed: {}", msg),
+            Outcome::Timeout(msg) => write!(f, "Cycle budget exhausted: {}", msg),
+            Outcome::Error(msg) => write!(f, "Emulator error: {}", msg),
+        }
+    }
+}
+
+/// How a test ROM reports its result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expectation {
+    /// Serial output ends with the pass text (or the fail text)
+    Serial { pass: Vec<u8>, fail: Vec<u8> },
+    /// The display settles on the image with this SHA-256 hash
+    DisplayHash([u8; 32]),
+    /// Status and text in cartridge RAM (see run_memory_result())
+    MemoryResult,
+}
+
+/// Classifies serial output, None while the test is still running
+pub fn classify_serial(output: &[u8], pass_text: &[u8], fail_text: &[u8]) -> Option<Outcome> {
+    if output.ends_with(pass_text) {
+        Some(Outcome::Pass)
+    } else if output.ends_with(fail_text) {
+        Some(Outcome::Fail(String::from_utf8_lossy(output).into_owned()))
+    } else {
+        None
+    }
+}
+
+/// Classifies the result in cartridge RAM, None while the test is
+/// still running (or has not written the signature yet)
+pub fn classify_memory(
+    signature: [u8; 3],
+    status: u8,
+    text: impl FnOnce() -> String,
+) -> Option<Outcome> {
+    if signature != MEMORY_SIGNATURE {
+        return None;
+    }
+    match status {
+        MEMORY_RUNNING => None,
+        0 => Some(Outcome::Pass),
+        status => Some(Outcome::Fail(format!("({}) {:?}", status, text()))),
+    }
+}
+
+/// Classifies the final display state against the expected hash
+pub fn classify_display(status: &TestDisplayState, pass_hash: &[u8]) -> Outcome {
+    if status.hash == pass_hash {
+        return Outcome::Pass;
+    }
+    Outcome::Fail(format!(
+        "Expected hash {:02x} but saw {:02x} (for {} frames)",
+        pass_hash.iter().format(""),
+        status.hash.iter().format(""),
+        status.stable_frames
+    ))
+}
+
+/// Headless emulator for tests that nobody looks at
+fn headless(rom: &[u8], cgb: bool) -> Result<Emulator> {
+    let mut emu = Emulator::new_headless(cartridge::load(rom)?, Model::from_cgb(cgb));
+    emu.get_lcd_mut().set_ppu_mode(PpuMode::Instant);
+    Ok(emu)
+}
+
+/// Runs a ROM that reports its result on the serial port
+pub fn run_serial(
+    rom: &[u8],
+    pass_text: &[u8],
+    fail_text: &[u8],
+    max_cycles: u64,
+    cgb: bool,
+) -> Outcome {
+    let mut emu = match headless(rom, cgb) {
+        Ok(emu) => emu,
+        Err(e) => return Outcome::Error(format!("{:#}", e)),
+    };
+    let serial = emu.serial_output().unwrap().clone();
+    let clock = MonotonicClock::new();
+    let mut output: Vec<u8> = vec![];
+    loop {
+        if clock.now() > TIME_LIMIT || emu.cpu().get_cycles() >= max_cycles {
+            return Outcome::Timeout(format!("output: {:?}", String::from_utf8_lossy(&output)));
+        }
+
+        if let Err(e) = emu.run_frame() {
+            return Outcome::Error(format!("{:#}", e));
+        }
+        for c in serial.take() {
+            output.push(c);
+            if let Some(outcome) = classify_serial(&output, pass_text, fail_text) {
+                return outcome;
+            }
+        }
+    }
+}
+
+/// Runs a ROM that reports its result in cartridge RAM: a status byte
+/// at 0xA000, the signature and a zero-terminated text from 0xA004.
+/// Status 0 is a pass.
+pub fn run_memory_result(rom: &[u8], max_cycles: u64, cgb: bool) -> Outcome {
+    let mut emu = match headless(rom, cgb) {
+        Ok(emu) => emu,
+        Err(e) => return Outcome::Error(format!("{:#}", e)),
+    };
+
+    let text = |emu: &Emulator| -> String {
+        let bus = &emu.cpu().bus;
+        let text: Vec<u8> = (0xA004..0xC000)
+            .map(|addr| bus.peek(addr))
+            .take_while(|&c| c != 0)
+            .collect();
+        String::from_utf8_lossy(&text).into_owned()
+    };
+
+    let clock = MonotonicClock::new();
+    loop {
+        if clock.now() > TIME_LIMIT || emu.cpu().get_cycles() >= max_cycles {
+            return Outcome::Timeout(format!("output: {:?}", text(&emu)));
+        }
+
+        if let Err(e) = emu.run_frame() {
+            return Outcome::Error(format!("{:#}", e));
+        }
+
+        let bus = &emu.cpu().bus;
+        let signature = [bus.peek(0xA001), bus.peek(0xA002), bus.peek(0xA003)];
+        if let Some(outcome) = classify_memory(signature, bus.peek(0xA000), || text(&emu)) {
+            return outcome;
+        }
+    }
+}
+
+/// Emulator with a hashing test display
+pub fn display_emulator(rom: &[u8], cgb: bool) -> Result<(Emulator, TDS)> {
+    let (display, dispstatus) = TestDisplay::new(LCD_W, LCD_H);
+    let emu = Emulator::new(
+        cartridge::load(rom)?,
+        None,
+        display,
+        Box::new(NullInput::new()),
+        Model::from_cgb(cgb),
+        Serial::new_null(),
+    );
+    Ok((emu, dispstatus))
+}
+
+/// Runs until the display has been stable for STABLE_FRAMES, returns
+/// None if the cycle budget ran out first
+fn wait_stable(
+    emu: &mut Emulator,
+    dispstatus: &TDS,
+    max_cycles: u64,
+) -> Result<Option<TestDisplayState>> {
+    let clock = MonotonicClock::new();
+    loop {
+        if clock.now() > TIME_LIMIT || emu.cpu().get_cycles() >= max_cycles {
+            return Ok(None);
+        }
+        emu.step()?;
+
+        let status = dispstatus.get();
+        if status.stable_frames >= STABLE_FRAMES {
+            return Ok(Some(status));
+        }
+    }
+}
+
+/// Runs a ROM until the display has been stable for STABLE_FRAMES
+pub fn run_display(rom: &[u8], max_cycles: u64, cgb: bool) -> Result<TestDisplayState> {
+    let (mut emu, dispstatus) = display_emulator(rom, cgb)?;
+    match wait_stable(&mut emu, &dispstatus, max_cycles)? {
+        Some(status) => Ok(status),
+        None => bail!("Timeout, display state: {:?}", dispstatus.get()),
+    }
+}
+
+/// Runs a ROM until the display is stable and checks its hash
+pub fn check_display(rom: &[u8], pass_hash: &[u8], max_cycles: u64, cgb: bool) -> Outcome {
+    let (mut emu, dispstatus) = match display_emulator(rom, cgb) {
+        Ok(r) => r,
+        Err(e) => return Outcome::Error(format!("{:#}", e)),
+    };
+    match wait_stable(&mut emu, &dispstatus, max_cycles) {
+        Ok(Some(status)) => classify_display(&status, pass_hash),
+        Ok(None) => Outcome::Timeout(format!("display state: {:?}", dispstatus.get())),
+        Err(e) => Outcome::Error(format!("{:#}", e)),
+    }
+}
+
+/// Renders the audio of an emulator for a cycle budget and returns its
+/// hash (see hash_audio())
+pub fn render_audio_hash(emu: &mut Emulator, max_cycles: u64) -> Result<[u8; AUDIO_HASH_SIZE]> {
+    let mut sink = WavSink::new();
+    emu.render_audio(max_cycles, &mut sink)?;
+    Ok(hash_audio(sink.samples()))
+}
+
+/// Runs a ROM headless for a cycle budget and returns the hash of the
+/// rendered audio
+pub fn audio_hash(rom: &[u8], max_cycles: u64, cgb: bool) -> Result<[u8; AUDIO_HASH_SIZE]> {
+    let mut emu = Emulator::new_headless(cartridge::load(rom)?, Model::from_cgb(cgb));
+    render_audio_hash(&mut emu, max_cycles)
+}
+
+/// Runs a test ROM, a panic in the emulator is reported as an error
+pub fn run(rom: &[u8], expect: &Expectation, max_cycles: u64, cgb: bool) -> Outcome {
+    panic::catch_unwind(AssertUnwindSafe(|| match expect {
+        Expectation::Serial { pass, fail } => run_serial(rom, pass, fail, max_cycles, cgb),
+        Expectation::DisplayHash(hash) => check_display(rom, hash, max_cycles, cgb),
+        Expectation::MemoryResult => run_memory_result(rom, max_cycles, cgb),
+    }))
+    .unwrap_or_else(|p| Outcome::Error(format!("Emulator panicked: {}", panic_message(&*p))))
+}
+
+/// A test ROM listed in a manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestSpec {
+    /// Path of the ROM, relative to the manifest
+    pub path: PathBuf,
+    pub expect: Expectation,
+    pub max_cycles: u64,
+    pub cgb: bool,
+}
+
+/// Value in a manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ManifestValue {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+}
+
+/// Parses a test manifest, a (subset of) TOML file with a table per ROM:
+///
+/// ```toml
+/// [[rom]]
+/// path = "cpu_instrs/individual/01-special.gb"
+/// expect = "serial-pass-text"   # or "display-hash", "memory-result"
+/// value = "Passed"              # pass text or SHA-256 hash of the screen
+/// fail = "Failed"               # fail text (serial-pass-text only)
+/// seconds = 30                  # budget in emulated seconds (or 'cycles')
+/// cgb = false
+/// ```
+pub fn parse_manifest(text: &str) -> Result<Vec<TestSpec>> {
+    let mut tables: Vec<(usize, HashMap<String, ManifestValue>)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let lineno = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[rom]]" {
+            tables.push((lineno, HashMap::new()));
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {}: expected 'key = value' or [[rom]]", lineno);
+        };
+        let Some((_, table)) = tables.last_mut() else {
+            bail!("Line {}: value outside of a [[rom]] table", lineno);
+        };
+        let key = key.trim();
+        let value = parse_manifest_value(value.trim())
+            .with_context(|| format!("Line {}: invalid value for '{}'", lineno, key))?;
+        if table.insert(key.to_string(), value).is_some() {
+            bail!("Line {}: duplicate key '{}'", lineno, key);
+        }
+    }
+
+    tables
+        .into_iter()
+        .map(|(lineno, table)| {
+            manifest_entry(table).with_context(|| format!("ROM at line {}", lineno))
+        })
+        .collect()
+}
+
+fn parse_manifest_value(value: &str) -> Result<ManifestValue> {
+    if let Some(s) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    let rest = chars.as_str().trim();
+                    if !rest.is_empty() && !rest.starts_with('#') {
+                        bail!("Trailing characters after string");
+                    }
+                    return Ok(ManifestValue::Str(out));
+                }
+                '\\' => out.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    c => bail!("Unsupported escape {:?}", c),
+                }),
+                c => out.push(c),
+            }
+        }
+        bail!("Unterminated string");
+    }
+
+    // Strip a trailing comment
+    let value = value.split('#').next().unwrap().trim();
+    match value {
+        "true" => Ok(ManifestValue::Bool(true)),
+        "false" => Ok(ManifestValue::Bool(false)),
+        _ => {
+            let digits = value.replace('_', "");
+            let n = match digits.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => digits.parse(),
+            };
+            let n = n.map_err(|_| anyhow!("Expected a string, integer or boolean"))?;
+            Ok(ManifestValue::Int(n))
+        }
+    }
+}
+
+fn manifest_entry(mut table: HashMap<String, ManifestValue>) -> Result<TestSpec> {
+    let mut take_str = |key: &str| match table.remove(key) {
+        Some(ManifestValue::Str(s)) => Ok(Some(s)),
+        Some(_) => Err(anyhow!("'{}' must be a string", key)),
+        None => Ok(None),
+    };
+    let path = take_str("path")?.context("Missing 'path'")?;
+    let kind = take_str("expect")?.context("Missing 'expect'")?;
+    let value = take_str("value")?;
+    let fail = take_str("fail")?;
+
+    let expect = match kind.as_str() {
+        "serial-pass-text" => {
+            let pass = value.context("Missing 'value' (the pass text)")?;
+            if pass.is_empty() {
+                bail!("Empty pass text");
+            }
+            Expectation::Serial {
+                pass: pass.into_bytes(),
+                fail: fail.as_deref().unwrap_or("Failed").as_bytes().to_vec(),
+            }
+        }
+        "display-hash" => {
+            let hash = value.context("Missing 'value' (the display hash)")?;
+            Expectation::DisplayHash(parse_hash(&hash)?)
+        }
+        "memory-result" => Expectation::MemoryResult,
+        _ => bail!("Unknown outcome type '{}'", kind),
+    };
+    if fail.is_some() && !matches!(expect, Expectation::Serial { .. }) {
+        bail!("'fail' is only used by serial-pass-text");
+    }
+
+    let max_cycles = match (table.remove("cycles"), table.remove("seconds")) {
+        (Some(ManifestValue::Int(n)), None) => n,
+        (None, Some(ManifestValue::Int(n))) => secs(n),
+        (None, None) => DEFAULT_BUDGET,
+        (Some(_), Some(_)) => bail!("Specify either 'cycles' or 'seconds'"),
+        _ => bail!("Budget must be an integer"),
+    };
+    let cgb = match table.remove("cgb") {
+        Some(ManifestValue::Bool(b)) => b,
+        Some(_) => bail!("'cgb' must be a boolean"),
+        None => false,
+    };
+    if let Some(key) = table.keys().next() {
+        bail!("Unknown key '{}'", key);
+    }
+
+    Ok(TestSpec {
+        path: PathBuf::from(path),
+        expect,
+        max_cycles,
+        cgb,
+    })
+}
+
+/// Parses a SHA-256 hash in hexadecimal
+fn parse_hash(hex: &str) -> Result<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("Hash must be 64 hexadecimal digits");
+    }
+    let mut hash = [0; 32];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[(i * 2)..(i * 2 + 2)], 16)
+            .with_context(|| format!("Invalid hash {}", hex))?;
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let specs = parse_manifest(
+            r#"
+            # Test ROMs
+            [[rom]]
+            path = "cpu_instrs/01-special.gb"
+            expect = "serial-pass-text"
+            value = "Passed"
+            seconds = 30 # comment
+
+            [[rom]]
+            path = "mem_timing-2/mem_timing.gb"
+            expect = "display-hash"
+            value = "180edbacf7255addb9537cc7c95b1f5352ee7061b973ecab4e8054b0502eba4e"
+            cycles = 0x1_0000
+            cgb = true
+
+            [[rom]]
+            path = "oam_bug/1-lcd_sync.gb"
+            expect = "memory-result"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            specs,
+            [
+                TestSpec {
+                    path: PathBuf::from("cpu_instrs/01-special.gb"),
+                    expect: Expectation::Serial {
+                        pass: b"Passed".to_vec(),
+                        fail: b"Failed".to_vec(),
+                    },
+                    max_cycles: secs(30),
+                    cgb: false,
+                },
+                TestSpec {
+                    path: PathBuf::from("mem_timing-2/mem_timing.gb"),
+                    expect: Expectation::DisplayHash([
+                        0x18, 0x0e, 0xdb, 0xac, 0xf7, 0x25, 0x5a, 0xdd, 0xb9, 0x53, 0x7c, 0xc7,
+                        0xc9, 0x5b, 0x1f, 0x53, 0x52, 0xee, 0x70, 0x61, 0xb9, 0x73, 0xec, 0xab,
+                        0x4e, 0x80, 0x54, 0xb0, 0x50, 0x2e, 0xba, 0x4e,
+                    ]),
+                    max_cycles: 0x10000,
+                    cgb: true,
+                },
+                TestSpec {
+                    path: PathBuf::from("oam_bug/1-lcd_sync.gb"),
+                    expect: Expectation::MemoryResult,
+                    max_cycles: DEFAULT_BUDGET,
+                    cgb: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_strings() {
+        let specs = parse_manifest(
+            r#"
+            [[rom]]
+            path = "a # b.gb"
+            expect = "serial-pass-text"
+            value = "Ok\n"
+            fail = "Bad \"x\""
+            "#,
+        )
+        .unwrap();
+        assert_eq!(specs[0].path, PathBuf::from("a # b.gb"));
+        assert_eq!(
+            specs[0].expect,
+            Expectation::Serial {
+                pass: b"Ok\n".to_vec(),
+                fail: b"Bad \"x\"".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn manifest_errors() {
+        for manifest in [
+            // Outside of a table
+            "path = \"a.gb\"",
+            // Missing path
+            "[[rom]]\nexpect = \"memory-result\"",
+            // Unknown outcome type
+            "[[rom]]\npath = \"a.gb\"\nexpect = \"registers\"",
+            // Missing pass text
+            "[[rom]]\npath = \"a.gb\"\nexpect = \"serial-pass-text\"",
+            // Invalid hash
+            "[[rom]]\npath = \"a.gb\"\nexpect = \"display-hash\"\nvalue = \"abc\"",
+            // Unknown key
+            "[[rom]]\npath = \"a.gb\"\nexpect = \"memory-result\"\nmodel = \"cgb\"",
+            // Duplicate key
+            "[[rom]]\npath = \"a.gb\"\npath = \"b.gb\"\nexpect = \"memory-result\"",
+            // Both budgets
+            "[[rom]]\npath = \"a.gb\"\nexpect = \"memory-result\"\ncycles = 1\nseconds = 1",
+            // Wrong type
+            "[[rom]]\npath = \"a.gb\"\nexpect = \"memory-result\"\ncgb = 1",
+            // Unterminated string
+            "[[rom]]\npath = \"a.gb",
+        ] {
+            assert!(parse_manifest(manifest).is_err(), "{}", manifest);
+        }
+    }
+
+    #[test]
+    fn classify() {
+        assert_eq!(classify_serial(b"Test", b"Passed", b"Failed"), None);
+        assert_eq!(
+            classify_serial(b"Test\nPassed", b"Passed", b"Failed"),
+            Some(Outcome::Pass)
+        );
+        assert!(matches!(
+            classify_serial(b"Test\nFailed", b"Passed", b"Failed"),
+            Some(Outcome::Fail(_))
+        ));
+
+        let text = || "Text".to_string();
+        assert_eq!(classify_memory([0; 3], 0, text), None);
+        assert_eq!(
+            classify_memory(MEMORY_SIGNATURE, MEMORY_RUNNING, text),
+            None
+        );
+        assert_eq!(
+            classify_memory(MEMORY_SIGNATURE, 0, text),
+            Some(Outcome::Pass)
+        );
+        assert_eq!(
+            classify_memory(MEMORY_SIGNATURE, 3, text),
+            Some(Outcome::Fail("(3) \"Text\"".to_string()))
+        );
+
+        let status = TestDisplayState {
+            stable_frames: STABLE_FRAMES,
+            hash: [0xAB; 32],
+        };
+        assert_eq!(classify_display(&status, &[0xAB; 32]), Outcome::Pass);
+        assert!(!classify_display(&status, &[0; 32]).is_pass());
+    }
+
+    #[test]
+    fn run_timeout() {
+        // JR -2
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let expectations = [
+            Expectation::Serial {
+                pass: b"Passed".to_vec(),
+                fail: b"Failed".to_vec(),
+            },
+            Expectation::MemoryResult,
+            Expectation::DisplayHash([0; 32]),
+        ];
+        for expect in expectations {
+            let outcome = run(&rom, &expect, secs(1), false);
+            assert!(matches!(outcome, Outcome::Timeout(_)), "{:?}", outcome);
+        }
+    }
+
+    #[test]
+    fn run_error() {
+        let expectations = [
+            Expectation::Serial {
+                pass: b"Passed".to_vec(),
+                fail: b"Failed".to_vec(),
+            },
+            Expectation::MemoryResult,
+            Expectation::DisplayHash([0; 32]),
+        ];
+
+        // Invalid opcode
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100] = 0xD3;
+        for expect in &expectations {
+            let outcome = run(&rom, expect, secs(1), false);
+            assert!(matches!(outcome, Outcome::Error(_)), "{:?}", outcome);
+        }
+
+        // Unknown cartridge type
+        rom[0x147] = 0xEE;
+        for expect in &expectations {
+            let outcome = run(&rom, expect, secs(1), false);
+            assert!(matches!(outcome, Outcome::Error(_)), "{:?}", outcome);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! A/B testing of emulator changes: two emulators run in lockstep and
+//! their state is compared after each chunk of execution, or an
+//! emulator is compared against a Gameboy Doctor trace recorded by
+//! another build (see CPU::set_doctor_log()).
+
+use crate::display::display::hash_frame;
+use crate::gameboy::bus::bus::BusMember;
+use crate::gameboy::emulator::Emulator;
+
+use anyhow::{bail, Result};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+const ADDR_IF: u16 = 0xFF0F;
+const ADDR_IE: u16 = 0xFFFF;
+
+/// Cycles compare_trace() runs without a new trace line before giving
+/// up (about a second)
+const TRACE_STALL_CYCLES: u64 = Emulator::FRAME_CYCLES * 60;
+
+/// Amount of execution between comparisons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunk {
+    /// CPU steps (instructions, or M-cycles while halted)
+    Steps(usize),
+    /// Frames, see Emulator::run_frame()
+    Frames(u64),
+}
+
+/// What lockstep_compare() runs and compares. Registers and IF/IE are
+/// always compared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareOptions {
+    pub chunk: Chunk,
+    /// Amount of chunks to run
+    pub chunks: usize,
+    /// Memory to compare after each chunk
+    pub memory: Option<RangeInclusive<u16>>,
+    /// Compare the frame hashes (see hash_frame()) after each chunk
+    pub frame_hash: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self {
+            chunk: Chunk::Steps(1),
+            chunks: 1_000_000,
+            memory: None,
+            frame_hash: false,
+        }
+    }
+}
+
+/// State of one of the emulators at a divergence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmulatorState {
+    /// CPU steps executed during the comparison
+    pub steps: usize,
+    /// Address and disassembly of the last executed instruction
+    pub last_instruction: Option<(u16, String)>,
+    /// See CPU::dump_state_verbose(), includes the cycle count
+    pub state: String,
+}
+
+impl EmulatorState {
+    fn capture(emu: &Emulator, steps: usize) -> Self {
+        let cpu = emu.cpu();
+        let last_instruction = cpu.recent_pcs().iter().last().map(|pc| {
+            let instr = match cpu.peek_instr_at(pc) {
+                Ok(instr) => instr.to_string(),
+                Err(e) => format!("<{}>", e),
+            };
+            (pc, instr)
+        });
+        Self {
+            steps,
+            last_instruction,
+            state: cpu.dump_state_verbose(),
+        }
+    }
+}
+
+impl fmt::Display for EmulatorState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, " Steps: {}", self.steps)?;
+        if let Some((pc, instr)) = &self.last_instruction {
+            write!(f, ", last instruction: {:04X}: {}", pc, instr)?;
+        }
+        write!(f, "\n{}", self.state)
+    }
+}
+
+/// The first difference found by lockstep_compare()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Chunk after which the difference was found, from 0
+    pub chunk: usize,
+    /// What differs
+    pub what: String,
+    pub a: EmulatorState,
+    pub b: EmulatorState,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged after chunk {}: {}", self.chunk, self.what)?;
+        writeln!(f, "A:{}", self.a)?;
+        write!(f, "B:{}", self.b)
+    }
+}
+
+/// Runs a chunk, returns the amount of CPU steps
+fn run_chunk(emu: &mut Emulator, chunk: Chunk) -> Result<usize> {
+    match chunk {
+        Chunk::Steps(n) => {
+            for _ in 0..n {
+                emu.step()?;
+            }
+            Ok(n)
+        }
+        Chunk::Frames(n) => {
+            let start = emu.cpu().get_cycles();
+            let mut steps = 0;
+            for _ in 0..n {
+                let frame = emu.get_frame_count();
+                while emu.get_frame_count() == frame
+                    && emu.cpu().get_cycles() - start < Emulator::FRAME_CYCLES * 2 * n
+                {
+                    emu.step()?;
+                    steps += 1;
+                }
+            }
+            Ok(steps)
+        }
+    }
+}
+
+/// Returns what differs between the emulators, if anything
+fn difference(a: &Emulator, b: &Emulator, opts: &CompareOptions) -> Option<String> {
+    let (ca, cb) = (a.cpu(), b.cpu());
+    if ca.regs != cb.regs || ca.ime != cb.ime {
+        return Some("registers".to_string());
+    }
+    for (addr, name) in [(ADDR_IF, "IF"), (ADDR_IE, "IE")] {
+        let (va, vb) = (ca.peek(addr), cb.peek(addr));
+        if va != vb {
+            return Some(format!("{}: {:02X} != {:02X}", name, va, vb));
+        }
+    }
+    if let Some(range) = &opts.memory {
+        for addr in range.clone() {
+            let (va, vb) = (ca.peek(addr), cb.peek(addr));
+            if va != vb {
+                return Some(format!("memory at {:04X}: {:02X} != {:02X}", addr, va, vb));
+            }
+        }
+    }
+    if opts.frame_hash && hash_frame(a.get_framebuffer()) != hash_frame(b.get_framebuffer()) {
+        return Some("frame hash".to_string());
+    }
+    None
+}
+
+/// Runs two emulators (normally with the same ROM and configuration)
+/// in lockstep, comparing them after each chunk. Returns the first
+/// divergence, if any.
+pub fn lockstep_compare(
+    a: &mut Emulator,
+    b: &mut Emulator,
+    opts: &CompareOptions,
+) -> Result<Option<Divergence>> {
+    let (mut steps_a, mut steps_b) = (0, 0);
+    for chunk in 0..opts.chunks {
+        steps_a += run_chunk(a, opts.chunk)?;
+        steps_b += run_chunk(b, opts.chunk)?;
+        if let Some(what) = difference(a, b, opts) {
+            return Ok(Some(Divergence {
+                chunk,
+                what,
+                a: EmulatorState::capture(a, steps_a),
+                b: EmulatorState::capture(b, steps_b),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// The first difference found by compare_trace()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceDivergence {
+    /// Line of the trace, from 1
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    /// State of the emulator, after executing the instruction of the
+    /// line
+    pub state: EmulatorState,
+}
+
+impl fmt::Display for TraceDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged at line {} of the trace", self.line)?;
+        writeln!(f, " Expected: {}", self.expected)?;
+        writeln!(f, " Actual:   {}", self.actual)?;
+        write!(f, "{}", self.state)
+    }
+}
+
+/// Collects the lines of a doctor log
+#[derive(Clone, Default)]
+struct TraceBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs an emulator against a Gameboy Doctor trace, as written by
+/// CPU::set_doctor_log(), for as many instructions as the trace has
+/// lines. Returns the first line that differs, if any. The emulator
+/// has to be set up like the one that recorded the trace.
+pub fn compare_trace(emu: &mut Emulator, trace: &str) -> Result<Option<TraceDivergence>> {
+    let buffer = TraceBuffer::default();
+    emu.cpu_mut().set_doctor_log(Some(Box::new(buffer.clone())));
+    let result = run_trace(emu, &buffer, trace);
+    emu.cpu_mut().set_doctor_log(None);
+    result
+}
+
+fn run_trace(
+    emu: &mut Emulator,
+    buffer: &TraceBuffer,
+    trace: &str,
+) -> Result<Option<TraceDivergence>> {
+    let mut steps = 0;
+    for (i, expected) in trace.lines().enumerate() {
+        let start = emu.cpu().get_cycles();
+        // A step logs at most one line, none while halted
+        while buffer.0.borrow().is_empty() {
+            if emu.cpu().get_cycles() - start > TRACE_STALL_CYCLES {
+                bail!("Line {}: no instructions executed", i + 1);
+            }
+            emu.step()?;
+            steps += 1;
+        }
+        let line = buffer.0.take();
+        let actual = String::from_utf8_lossy(&line);
+        if actual.trim_end() != expected.trim_end() {
+            return Ok(Some(TraceDivergence {
+                line: i + 1,
+                expected: expected.trim_end().to_string(),
+                actual: actual.trim_end().to_string(),
+                state: EmulatorState::capture(emu, steps),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cartridge::cartridge;
+    use crate::gameboy::model::Model;
+
+    /// Copies a counter at 0xC000 and reads 0xC100, in a loop of 5
+    /// instructions
+    fn emulator() -> Emulator {
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x10D].copy_from_slice(&[
+            0xFA, 0x00, 0xC0, // LD A,(C000h)
+            0x3C, // INC A
+            0xEA, 0x00, 0xC0, // LD (C000h),A
+            0xFA, 0x00, 0xC1, // LD A,(C100h)
+            0xC3, 0x00, 0x01, // JP 0100h
+        ]);
+        Emulator::new_headless(cartridge::load(&rom).unwrap(), Model::Dmg)
+    }
+
+    fn steps(chunks: usize) -> CompareOptions {
+        CompareOptions {
+            chunks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn identical() {
+        let (mut a, mut b) = (emulator(), emulator());
+        assert_eq!(
+            lockstep_compare(&mut a, &mut b, &steps(1000)).unwrap(),
+            None
+        );
+
+        let opts = CompareOptions {
+            chunk: Chunk::Frames(1),
+            chunks: 3,
+            memory: Some(0xC000..=0xDFFF),
+            frame_hash: true,
+        };
+        assert_eq!(lockstep_compare(&mut a, &mut b, &opts).unwrap(), None);
+        assert_eq!(a.get_frame_count(), b.get_frame_count());
+    }
+
+    #[test]
+    fn diverge_registers() {
+        let (mut a, mut b) = (emulator(), emulator());
+        assert_eq!(lockstep_compare(&mut a, &mut b, &steps(10)).unwrap(), None);
+        assert_eq!(a.cpu().regs.pc, 0x0100);
+
+        // Read by the 4th instruction
+        b.bus_mut().write(0xC100, 0x55);
+        let d = lockstep_compare(&mut a, &mut b, &steps(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(d.chunk, 3);
+        assert_eq!(d.what, "registers");
+        assert_eq!((d.a.steps, d.b.steps), (4, 4));
+        let (pc, instr) = d.b.last_instruction.clone().unwrap();
+        assert_eq!(pc, 0x0107);
+        assert!(instr.ends_with("LD A,($C100)"), "{}", instr);
+        assert!(d.a.state.starts_with("A:00 "), "{}", d.a.state);
+        assert!(d.b.state.starts_with("A:55 "), "{}", d.b.state);
+        assert!(d
+            .to_string()
+            .starts_with("Diverged after chunk 3: registers\n"));
+    }
+
+    #[test]
+    fn diverge_memory() {
+        let (mut a, mut b) = (emulator(), emulator());
+        b.bus_mut().write(0xC100, 0x55);
+        let opts = CompareOptions {
+            memory: Some(0xC000..=0xC1FF),
+            ..steps(10)
+        };
+        let d = lockstep_compare(&mut a, &mut b, &opts).unwrap().unwrap();
+        assert_eq!(d.chunk, 0);
+        assert_eq!(d.what, "memory at C100: 00 != 55");
+    }
+
+    #[test]
+    fn trace() {
+        // Recorded like CPU::set_doctor_log() does
+        let mut e = emulator();
+        let buffer = TraceBuffer::default();
+        e.cpu_mut().set_doctor_log(Some(Box::new(buffer.clone())));
+        for _ in 0..20 {
+            e.step().unwrap();
+        }
+        e.cpu_mut().set_doctor_log(None);
+        let trace = String::from_utf8(buffer.0.take()).unwrap();
+        assert_eq!(trace.lines().count(), 20);
+
+        assert_eq!(compare_trace(&mut emulator(), &trace).unwrap(), None);
+
+        // The 4th instruction reads the patched memory
+        let mut e = emulator();
+        e.bus_mut().write(0xC100, 0x55);
+        let d = compare_trace(&mut e, &trace).unwrap().unwrap();
+        assert_eq!(d.line, 5);
+        assert!(d.expected.starts_with("A:00 "));
+        assert!(d.actual.starts_with("A:55 "));
+        assert_eq!(d.state.steps, 5);
+    }
+}
@@ -0,0 +1,425 @@
+//! CPU test vectors: small scripts that set up memory and registers,
+//! run the CPU against a Testbus and check the resulting state.
+//!
+//! ```text
+//! # Comment
+//! mem 8000: CD 34 12          # Bytes from an address
+//! reg A=3C F=Z,N SP=FFFE PC=8000 IME=1
+//! run 5                       # Amount of instructions
+//! run-until pc=1234
+//! expect A=00 mem:FFFC=03 cycles=40
+//! ```
+//!
+//! Directives run in order, so expectations can be checked between
+//! runs. Values are hexadecimal, except for IME (0 or 1), 'cycles'
+//! (the total amount of CPU cycles, decimal) and F, which is either a
+//! list of flags (Z,N,H,C or - for none) or hexadecimal. Memory and
+//! registers start out zero, IME disabled.
+
+use crate::gameboy::bus::testbus::Testbus;
+use crate::gameboy::cpu::cpu::CPU;
+use crate::gameboy::cpu::regs::{Flag, Register, RegisterWidth};
+use crate::gameboy::model::Model;
+
+use anyhow::{bail, Context, Result};
+use num_traits::ToPrimitive;
+
+use std::fmt;
+
+/// Maximum amount of instructions of a run-until directive
+pub const RUN_UNTIL_LIMIT: usize = 1_000_000;
+
+/// Part of the system state a directive sets or checks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Target {
+    Reg(Register),
+    Ime,
+    Mem(u16),
+    /// Total amount of CPU cycles
+    Cycles,
+}
+
+impl Target {
+    fn parse(s: &str) -> Result<Self> {
+        if let Some(addr) = s.strip_prefix("mem:") {
+            return Ok(Self::Mem(parse_hex16(addr)?));
+        }
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "A" => Self::Reg(Register::A),
+            "F" => Self::Reg(Register::F),
+            "B" => Self::Reg(Register::B),
+            "C" => Self::Reg(Register::C),
+            "D" => Self::Reg(Register::D),
+            "E" => Self::Reg(Register::E),
+            "H" => Self::Reg(Register::H),
+            "L" => Self::Reg(Register::L),
+            "AF" => Self::Reg(Register::AF),
+            "BC" => Self::Reg(Register::BC),
+            "DE" => Self::Reg(Register::DE),
+            "HL" => Self::Reg(Register::HL),
+            "SP" => Self::Reg(Register::SP),
+            "PC" => Self::Reg(Register::PC),
+            "IME" => Self::Ime,
+            "CYCLES" => Self::Cycles,
+            _ => bail!("Unknown register '{}'", s),
+        })
+    }
+
+    fn parse_value(&self, s: &str) -> Result<u64> {
+        let val = match self {
+            Self::Reg(Register::F) => parse_flags(s)?,
+            Self::Reg(r) if r.width() == RegisterWidth::EightBit => parse_hex8(s)?.into(),
+            Self::Reg(_) => parse_hex16(s)?.into(),
+            Self::Mem(_) => parse_hex8(s)?.into(),
+            Self::Ime => match s {
+                "0" => 0,
+                "1" => 1,
+                _ => bail!("Invalid IME '{}' (expected 0 or 1)", s),
+            },
+            Self::Cycles => s
+                .parse()
+                .with_context(|| format!("Invalid cycles '{}'", s))?,
+        };
+        Ok(val)
+    }
+
+    fn read(&self, cpu: &CPU) -> u64 {
+        match *self {
+            Self::Reg(r) => cpu.regs.read(r).into(),
+            Self::Ime => cpu.ime.into(),
+            Self::Mem(addr) => cpu.bus.peek(addr).into(),
+            Self::Cycles => cpu.get_cycles(),
+        }
+    }
+
+    fn format_value(&self, val: u64) -> String {
+        match self {
+            Self::Reg(r) if r.width() == RegisterWidth::SixteenBit => format!("{:04X}", val),
+            Self::Reg(_) | Self::Mem(_) => format!("{:02X}", val),
+            Self::Ime | Self::Cycles => val.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reg(r) => write!(f, "{:?}", r),
+            Self::Ime => write!(f, "IME"),
+            Self::Mem(addr) => write!(f, "mem:{:04X}", addr),
+            Self::Cycles => write!(f, "cycles"),
+        }
+    }
+}
+
+/// A single line of a test vector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    /// Writes bytes starting at an address
+    Mem(u16, Vec<u8>),
+    /// Sets registers (or IME)
+    Reg(Vec<(Target, u64)>),
+    /// Executes an amount of instructions
+    Run(usize),
+    /// Executes instructions until PC reaches an address
+    RunUntil(u16),
+    /// Checks the state
+    Expect(Vec<(Target, u64)>),
+}
+
+fn parse_hex8(s: &str) -> Result<u8> {
+    u8::from_str_radix(s, 16).with_context(|| format!("Invalid byte '{}'", s))
+}
+
+fn parse_hex16(s: &str) -> Result<u16> {
+    u16::from_str_radix(s, 16).with_context(|| format!("Invalid address '{}'", s))
+}
+
+/// Parses the value of F: a list of flags or hexadecimal
+fn parse_flags(s: &str) -> Result<u64> {
+    if s.len() == 2 && s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(parse_hex8(s)? as u64 & 0xF0);
+    }
+    if s == "-" {
+        return Ok(0);
+    }
+    let mut f = 0;
+    for flag in s.split(',') {
+        let flag = match flag.to_ascii_uppercase().as_str() {
+            "Z" => Flag::Z,
+            "N" => Flag::N,
+            "H" => Flag::H,
+            "C" => Flag::C,
+            _ => bail!("Invalid flag '{}'", flag),
+        };
+        f |= 1 << flag.to_usize().unwrap();
+    }
+    Ok(f)
+}
+
+/// Parses a list of NAME=VALUE assignments
+fn parse_assignments(args: &[&str]) -> Result<Vec<(Target, u64)>> {
+    if args.is_empty() {
+        bail!("Nothing to set or check");
+    }
+    args.iter()
+        .map(|arg| {
+            let Some((name, val)) = arg.split_once('=') else {
+                bail!("Expected NAME=VALUE, got '{}'", arg);
+            };
+            let target = Target::parse(name)?;
+            Ok((target, target.parse_value(val)?))
+        })
+        .collect()
+}
+
+impl Directive {
+    fn parse(line: &str) -> Result<Self> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (&cmd, args) = args.split_first().context("Empty directive")?;
+        Ok(match cmd {
+            "mem" => {
+                let (addr, bytes) = line["mem".len()..]
+                    .split_once(':')
+                    .context("Expected 'mem ADDR: BYTES'")?;
+                let bytes = bytes
+                    .split_whitespace()
+                    .map(parse_hex8)
+                    .collect::<Result<Vec<_>>>()?;
+                if bytes.is_empty() {
+                    bail!("No bytes to write");
+                }
+                Self::Mem(parse_hex16(addr.trim())?, bytes)
+            }
+            "reg" => {
+                let assignments = parse_assignments(args)?;
+                if let Some((t, _)) = assignments
+                    .iter()
+                    .find(|(t, _)| matches!(t, Target::Mem(_) | Target::Cycles))
+                {
+                    bail!("Cannot set {} with reg", t);
+                }
+                Self::Reg(assignments)
+            }
+            "run" => match args {
+                [n] => Self::Run(
+                    n.parse()
+                        .with_context(|| format!("Invalid count '{}'", n))?,
+                ),
+                _ => bail!("Expected 'run COUNT'"),
+            },
+            "run-until" => match args {
+                [cond] => match cond.split_once('=') {
+                    Some((pc, addr)) if pc.eq_ignore_ascii_case("pc") => {
+                        Self::RunUntil(parse_hex16(addr)?)
+                    }
+                    _ => bail!("Expected 'run-until pc=ADDR'"),
+                },
+                _ => bail!("Expected 'run-until pc=ADDR'"),
+            },
+            "expect" => Self::Expect(parse_assignments(args)?),
+            _ => bail!("Unknown directive '{}'", cmd),
+        })
+    }
+}
+
+/// A parsed test vector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    /// Directives with their line numbers
+    pub directives: Vec<(usize, Directive)>,
+}
+
+impl Vector {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut directives = vec![];
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let directive = Directive::parse(line).with_context(|| format!("Line {}", n + 1))?;
+            directives.push((n + 1, directive));
+        }
+        if !directives
+            .iter()
+            .any(|(_, d)| matches!(d, Directive::Expect(_)))
+        {
+            bail!("No expectations");
+        }
+        Ok(Self { directives })
+    }
+
+    /// Runs the vector on a DMG CPU with a Testbus, returns an error
+    /// describing the first expectation that is not met.
+    pub fn run(&self) -> Result<()> {
+        let mut cpu = CPU::new(Box::new(Testbus::new()), Model::Dmg);
+
+        for (line, directive) in &self.directives {
+            let context = || format!("Line {}", line);
+            match directive {
+                Directive::Mem(addr, bytes) => {
+                    for (i, &b) in bytes.iter().enumerate() {
+                        cpu.bus.write(addr.wrapping_add(i as u16), b);
+                    }
+                }
+                Directive::Reg(assignments) => {
+                    for &(target, val) in assignments {
+                        match target {
+                            Target::Reg(r) => cpu.regs.write(r, val as u16)?,
+                            Target::Ime => cpu.ime = val != 0,
+                            _ => unreachable!(),
+                        }
+                    }
+                }
+                Directive::Run(n) => {
+                    for _ in 0..*n {
+                        cpu.step().with_context(context)?;
+                    }
+                }
+                Directive::RunUntil(addr) => {
+                    let mut steps = 0;
+                    while cpu.regs.pc != *addr {
+                        if steps == RUN_UNTIL_LIMIT {
+                            bail!("{}: PC {:04X} not reached", context(), addr);
+                        }
+                        cpu.step().with_context(context)?;
+                        steps += 1;
+                    }
+                }
+                Directive::Expect(expectations) => {
+                    for &(target, val) in expectations {
+                        let actual = target.read(&cpu);
+                        if actual != val {
+                            bail!(
+                                "{}: expected {}={}, got {}",
+                                context(),
+                                target,
+                                target.format_value(val),
+                                target.format_value(actual)
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directive(s: &str) -> Directive {
+        Directive::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse_directives() {
+        assert_eq!(
+            directive("mem 8000: CD 34 12"),
+            Directive::Mem(0x8000, vec![0xCD, 0x34, 0x12])
+        );
+        assert_eq!(
+            directive("reg A=3C F=Z,N sp=FFFE IME=1"),
+            Directive::Reg(vec![
+                (Target::Reg(Register::A), 0x3C),
+                (Target::Reg(Register::F), 0xC0),
+                (Target::Reg(Register::SP), 0xFFFE),
+                (Target::Ime, 1),
+            ])
+        );
+        assert_eq!(directive("run 5"), Directive::Run(5));
+        assert_eq!(directive("run-until pc=1234"), Directive::RunUntil(0x1234));
+        assert_eq!(
+            directive("expect A=00 F=- mem:FFFC=03 cycles=40"),
+            Directive::Expect(vec![
+                (Target::Reg(Register::A), 0),
+                (Target::Reg(Register::F), 0),
+                (Target::Mem(0xFFFC), 0x03),
+                (Target::Cycles, 40),
+            ])
+        );
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(directive("reg F=C"), directive("reg F=10"));
+        assert_eq!(directive("reg F=z,h"), directive("reg F=A0"));
+        assert_eq!(directive("reg F=FF"), directive("reg F=Z,N,H,C"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for line in [
+            "foo",
+            "mem 8000 CD",
+            "mem 8000:",
+            "mem 10000: 00",
+            "mem 8000: 100",
+            "reg",
+            "reg A",
+            "reg A=100",
+            "reg X=00",
+            "reg mem:8000=00",
+            "reg F=Q",
+            "reg IME=2",
+            "run",
+            "run x",
+            "run 1 2",
+            "run-until 1234",
+            "run-until a=1234",
+            "expect cycles=x",
+        ] {
+            assert!(Directive::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_vector() {
+        let v = Vector::parse("# Comment\n\nmem 0: 00  # NOP\nrun 1\nexpect PC=0001\n").unwrap();
+        assert_eq!(
+            v.directives,
+            [
+                (3, Directive::Mem(0, vec![0])),
+                (4, Directive::Run(1)),
+                (5, Directive::Expect(vec![(Target::Reg(Register::PC), 1)])),
+            ]
+        );
+
+        let e = Vector::parse("run 1\nexpect A=00\nfoo\n").unwrap_err();
+        assert!(format!("{:#}", e).starts_with("Line 3: "), "{:#}", e);
+        assert!(Vector::parse("run 1\n").is_err());
+    }
+
+    #[test]
+    fn run() {
+        // LD A,42h; INC A; JR -3
+        let v = Vector::parse(
+            "mem 0: 3E 42 3C 18 FD\n\
+             run 2\n\
+             expect A=43 PC=0003 cycles=12\n\
+             run-until pc=0002\n\
+             expect A=43 F=- cycles=24\n",
+        )
+        .unwrap();
+        v.run().unwrap();
+    }
+
+    #[test]
+    fn run_failures() {
+        let e = Vector::parse("reg A=01\nexpect A=02\n")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Line 2: expected A=02, got 01");
+
+        // JR -2
+        let e = Vector::parse("mem 0: 18 FE\nrun-until pc=1234\nexpect PC=1234\n")
+            .unwrap()
+            .run()
+            .unwrap_err();
+        assert_eq!(e.to_string(), "Line 2: PC 1234 not reached");
+    }
+}
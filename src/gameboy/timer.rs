@@ -1,7 +1,8 @@
-use super::cpu::cpu::CPU_CLOCK_HZ;
+use super::cpu::cpu::{self, CPU_CLOCK_HZ};
 use crate::gameboy::bus::bus::BusMember;
-use crate::tickable::{Tickable, Ticks, ONE_MCYCLE};
+use crate::tickable::{TickResult, Tickable, Ticks, ONE_MCYCLE};
 
+use crate::gameboy::savestate::{Savestate, StateReader, StateWriter};
 use anyhow::Result;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
@@ -10,6 +11,11 @@ const TAC_ENABLE: u8 = 1 << 2;
 const TAC_DIV_MASK: u8 = 0x03;
 const TAC_MASK: u8 = 0x07;
 
+/// Divider bit that clocks the APU frame sequencer (falling edge),
+/// normal speed and double speed.
+const DIV_APU_BIT: usize = 1 << 12;
+const DIV_APU_BIT_DS: usize = 1 << 13;
+
 #[derive(FromPrimitive)]
 enum TimerInput {
     CPUDiv1024 = 0,
@@ -43,13 +49,18 @@ pub struct Timer {
     tima: u8,
     tma: u8,
     tac: u8,
-    intreq: bool,
 
     /// Timer has overflowed this cycle (set for 1 M-cycle)
     overflow: bool,
 
     /// Timer has reloaded this cycle (set for 1 M-cycle)
     reloaded: bool,
+
+    /// CPU is in double speed mode
+    double_speed: bool,
+
+    /// Pending APU frame sequencer clocks
+    apu_events: u8,
 }
 
 impl Timer {
@@ -59,9 +70,10 @@ impl Timer {
             tima: 0,
             tma: 0,
             tac: 0,
-            intreq: false,
             overflow: false,
             reloaded: false,
+            double_speed: false,
+            apu_events: 0,
         }
     }
 
@@ -69,9 +81,16 @@ impl Timer {
         Self::from_div(0)
     }
 
-    pub fn get_clr_intreq(&mut self) -> bool {
-        let val = self.intreq;
-        self.intreq = false;
+    /// Gets the internal 16-bit divider (DIV is the upper 8 bits)
+    pub fn get_divider(&self) -> u16 {
+        self.cycles as u16
+    }
+
+    /// Gets and clears the amount of APU frame sequencer clocks that
+    /// occured since the last call.
+    pub fn get_clr_apu_events(&mut self) -> u8 {
+        let val = self.apu_events;
+        self.apu_events = 0;
         val
     }
 
@@ -94,6 +113,17 @@ impl Timer {
             .get_mask();
         self.update_timer(self.cycles & mask, cycles & mask);
 
+        // The APU frame sequencer is clocked by the same divider, so
+        // writes to DIV may also clock it.
+        let apu_bit = if self.double_speed {
+            DIV_APU_BIT_DS
+        } else {
+            DIV_APU_BIT
+        };
+        if self.cycles & apu_bit != 0 && cycles & apu_bit == 0 {
+            self.apu_events = self.apu_events.saturating_add(1);
+        }
+
         self.cycles = cycles;
     }
 
@@ -166,7 +196,11 @@ impl BusMember for Timer {
 }
 
 impl Tickable for Timer {
-    fn tick(&mut self, ticks: Ticks) -> Result<()> {
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult> {
+        let mut intreq = 0;
+
+        self.double_speed = ticks.is_double_speed();
+
         // Timer can run on double speed
         for _ in 0..ticks.get_t_ds() {
             if self.cycles % ONE_MCYCLE == 0 {
@@ -174,7 +208,7 @@ impl Tickable for Timer {
                 // one M-cycle after overflow.
                 if self.overflow {
                     self.tima = self.tma;
-                    self.intreq = true;
+                    intreq |= cpu::INT_TIMER;
                     self.overflow = false;
                     self.reloaded = true;
                 } else if self.reloaded {
@@ -185,6 +219,31 @@ impl Tickable for Timer {
             self.update_cycles(self.cycles.wrapping_add(1));
         }
 
+        Ok(intreq)
+    }
+}
+
+impl Savestate for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.put_usize(self.cycles);
+        w.put_u8(self.tima);
+        w.put_u8(self.tma);
+        w.put_u8(self.tac);
+        w.put_bool(self.overflow);
+        w.put_bool(self.reloaded);
+        w.put_bool(self.double_speed);
+        w.put_u8(self.apu_events);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.cycles = r.get_usize()?;
+        self.tima = r.get_u8()?;
+        self.tma = r.get_u8()?;
+        self.tac = r.get_u8()?;
+        self.overflow = r.get_bool()?;
+        self.reloaded = r.get_bool()?;
+        self.double_speed = r.get_bool()?;
+        self.apu_events = r.get_u8()?;
         Ok(())
     }
 }
@@ -215,17 +274,50 @@ mod tests {
         assert_eq!(t.read(0xFF04), 0);
     }
 
+    #[test]
+    fn apu_events() {
+        let mut t = Timer::new();
+        t.tick(Ticks::from_t(DIV_APU_BIT)).unwrap();
+        assert_eq!(t.get_clr_apu_events(), 0);
+        t.tick(Ticks::from_t(DIV_APU_BIT)).unwrap();
+        assert_eq!(t.get_clr_apu_events(), 1);
+        assert_eq!(t.get_clr_apu_events(), 0);
+        t.tick(Ticks::from_t(DIV_APU_BIT * 4)).unwrap();
+        assert_eq!(t.get_clr_apu_events(), 2);
+    }
+
+    #[test]
+    fn apu_events_double_speed() {
+        let mut t = Timer::new();
+        t.tick(Ticks::from_t_ds(DIV_APU_BIT * 2)).unwrap();
+        assert_eq!(t.get_clr_apu_events(), 0);
+        t.tick(Ticks::from_t_ds(DIV_APU_BIT * 2)).unwrap();
+        assert_eq!(t.get_clr_apu_events(), 1);
+        assert_eq!(t.get_divider(), (DIV_APU_BIT_DS * 2) as u16);
+    }
+
+    #[test]
+    fn apu_events_div_write() {
+        let mut t = Timer::new();
+        t.tick(Ticks::from_t(DIV_APU_BIT - 1)).unwrap();
+        t.write(0xFF04, 0);
+        assert_eq!(t.get_clr_apu_events(), 0);
+
+        t.tick(Ticks::from_t(DIV_APU_BIT)).unwrap();
+        assert_eq!(t.get_divider(), DIV_APU_BIT as u16);
+        t.write(0xFF04, 0);
+        assert_eq!(t.get_clr_apu_events(), 1);
+        assert_eq!(t.get_divider(), 0);
+    }
+
     #[test]
     fn interrupt() {
         let mut t = Timer::new();
         t.write(0xFF07, 0x07);
-        assert!(!t.get_clr_intreq());
-        t.tick(Ticks::from_t(256 * 256)).unwrap();
-        assert!(!t.get_clr_intreq());
+        assert_eq!(t.tick(Ticks::from_t(256 * 256)).unwrap(), 0);
         // Extra tick for timer reload quirk
-        t.tick(Ticks::from_t(ONE_MCYCLE)).unwrap();
-        assert!(t.get_clr_intreq());
-        assert!(!t.get_clr_intreq());
+        assert_eq!(t.tick(Ticks::from_t(ONE_MCYCLE)).unwrap(), cpu::INT_TIMER);
+        assert_eq!(t.tick(Ticks::from_t(ONE_MCYCLE)).unwrap(), 0);
     }
 
     #[test]
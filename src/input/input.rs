@@ -1,6 +1,9 @@
-use strum_macros::EnumIter;
+use std::cell::Cell;
+use std::rc::Rc;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, EnumIter, Ord, PartialOrd)]
+use strum_macros::{Display, EnumIter, EnumString};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, EnumIter, Ord, PartialOrd, Display, EnumString)]
 pub enum Button {
     DPadUp,
     DPadDown,
@@ -14,6 +17,9 @@ pub enum Button {
 
 pub trait Input {
     fn is_pressed(&self, b: Button) -> bool;
+
+    /// Called when the emulated LCD starts a new frame (VBlank).
+    fn set_frame(&mut self, _frame: u64) {}
 }
 
 pub struct NullInput {}
@@ -29,3 +35,75 @@ impl Input for NullInput {
         false
     }
 }
+
+/// Shared handle to the button state of a SharedInput
+#[derive(Clone)]
+pub struct ButtonState {
+    state: Rc<Cell<u8>>,
+}
+
+impl ButtonState {
+    pub fn set(&self, b: Button, pressed: bool) {
+        let mask = 1 << b as u8;
+        if pressed {
+            self.state.set(self.state.get() | mask);
+        } else {
+            self.state.set(self.state.get() & !mask);
+        }
+    }
+
+    pub fn is_pressed(&self, b: Button) -> bool {
+        self.state.get() & (1 << b as u8) != 0
+    }
+}
+
+/// Input that is controlled externally (e.g. by an embedding
+/// frontend) through a ButtonState handle.
+pub struct SharedInput {
+    state: ButtonState,
+}
+
+impl SharedInput {
+    pub fn new() -> (Box<Self>, ButtonState) {
+        let state = ButtonState {
+            state: Rc::new(Cell::new(0)),
+        };
+        (
+            Box::new(Self {
+                state: state.clone(),
+            }),
+            state,
+        )
+    }
+}
+
+impl Input for SharedInput {
+    fn is_pressed(&self, b: Button) -> bool {
+        self.state.is_pressed(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn shared_input() {
+        let (input, state) = SharedInput::new();
+        for b in Button::iter() {
+            assert!(!input.is_pressed(b));
+        }
+
+        state.set(Button::A, true);
+        state.set(Button::DPadLeft, true);
+        for b in Button::iter() {
+            assert_eq!(input.is_pressed(b), b == Button::A || b == Button::DPadLeft);
+        }
+
+        state.set(Button::A, false);
+        assert!(!input.is_pressed(Button::A));
+        assert!(input.is_pressed(Button::DPadLeft));
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use strum::IntoEnumIterator;
+use terminal::KeyCode;
+
+use super::input::Button;
+
+/// Names of the buttons as used in keymap files and --bind
+const BUTTON_NAMES: [(&str, Button); 8] = [
+    ("up", Button::DPadUp),
+    ("down", Button::DPadDown),
+    ("left", Button::DPadLeft),
+    ("right", Button::DPadRight),
+    ("a", Button::A),
+    ("b", Button::B),
+    ("start", Button::Start),
+    ("select", Button::Select),
+];
+
+/// Names of non-character keys
+const KEY_NAMES: [(&str, KeyCode); 12] = [
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("space", KeyCode::Char(' ')),
+    ("enter", KeyCode::Enter),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+];
+
+/// Maps terminal keys to Gameboy buttons.
+///
+/// Keymap file format (one binding per line, '#' starts a comment):
+///   <button> = <key>[, <key>...]
+/// Buttons: up, down, left, right, a, b, start, select.
+/// Keys: a single character or one of the key names (up, space,
+/// enter, f1, ...).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMap {
+    map: HashMap<KeyCode, Button>,
+}
+
+impl KeyMap {
+    /// Creates a keymap without any bindings
+    pub fn empty() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Returns the button bound to a key
+    pub fn get(&self, key: KeyCode) -> Option<Button> {
+        self.map.get(&key).copied()
+    }
+
+    /// Returns the keys bound to a button
+    pub fn keys(&self, button: Button) -> Vec<KeyCode> {
+        self.map
+            .iter()
+            .filter(|(_, &b)| b == button)
+            .map(|(&k, _)| k)
+            .collect()
+    }
+
+    /// Binds keys to a button, replacing the existing bindings of the
+    /// button and of the keys.
+    pub fn bind(&mut self, button: Button, keys: &[KeyCode]) {
+        self.map.retain(|_, b| *b != button);
+        for &k in keys {
+            self.map.insert(k, button);
+        }
+    }
+
+    /// Binds an additional key to a button
+    pub fn add(&mut self, button: Button, key: KeyCode) {
+        self.map.insert(key, button);
+    }
+
+    /// Applies all bindings of another keymap onto this keymap
+    pub fn apply(&mut self, other: &KeyMap) {
+        for button in Button::iter() {
+            let keys = other.keys(button);
+            if !keys.is_empty() {
+                self.bind(button, &keys);
+            }
+        }
+    }
+
+    /// Returns the name of a button as used in keymaps
+    pub fn button_name(button: Button) -> &'static str {
+        BUTTON_NAMES.iter().find(|(_, b)| *b == button).unwrap().0
+    }
+
+    pub fn parse_button(s: &str) -> Result<Button> {
+        let s = s.trim().to_lowercase();
+        match BUTTON_NAMES.iter().find(|(name, _)| *name == s) {
+            Some(&(_, b)) => Ok(b),
+            None => bail!(
+                "Unknown button '{}', valid buttons are: {}",
+                s,
+                BUTTON_NAMES.iter().map(|(name, _)| name).join(", ")
+            ),
+        }
+    }
+
+    pub fn parse_key(s: &str) -> Result<KeyCode> {
+        let s = s.trim();
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(KeyCode::Char(c));
+        }
+
+        let lower = s.to_lowercase();
+        if let Some(&(_, k)) = KEY_NAMES.iter().find(|(name, _)| *name == lower) {
+            return Ok(k);
+        }
+        if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+            if (1..=12).contains(&n) {
+                return Ok(KeyCode::F(n));
+            }
+        }
+        bail!(
+            "Unknown key '{}', use a single character, f1-f12 or one of: {}",
+            s,
+            KEY_NAMES.iter().map(|(name, _)| name).join(", ")
+        )
+    }
+
+    /// Parses a single '<button>=<key>' binding (e.g. from --bind)
+    pub fn parse_binding(s: &str) -> Result<(Button, KeyCode)> {
+        let Some((button, key)) = s.split_once('=') else {
+            bail!("Invalid binding '{}', expected <button>=<key>", s);
+        };
+        Ok((Self::parse_button(button)?, Self::parse_key(key)?))
+    }
+
+    /// Parses a keymap file. Buttons and keys may only be bound once.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut keymap = Self::empty();
+        let mut buttons = vec![];
+
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.split_once('#') {
+                // Allow '#' as a key
+                Some((l, _)) if !l.trim_end().ends_with('=') => l,
+                _ => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((button, keys)) = line.split_once('=') else {
+                bail!("Line {}: expected <button> = <key>", i + 1);
+            };
+            let button = Self::parse_button(button).with_context(|| format!("Line {}", i + 1))?;
+            if buttons.contains(&button) {
+                bail!(
+                    "Line {}: button '{}' bound more than once",
+                    i + 1,
+                    Self::button_name(button)
+                );
+            }
+            buttons.push(button);
+
+            let keys = if keys.trim() == "," {
+                vec![KeyCode::Char(',')]
+            } else {
+                keys.split(',')
+                    .map(Self::parse_key)
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("Line {}", i + 1))?
+            };
+            for &k in &keys {
+                if let Some(other) = keymap.get(k) {
+                    bail!(
+                        "Line {}: key {:?} is already bound to '{}'",
+                        i + 1,
+                        k,
+                        Self::button_name(other)
+                    );
+                }
+                keymap.add(button, k);
+            }
+        }
+
+        Ok(keymap)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read keymap {}", path.display()))?;
+        Self::parse(&s).with_context(|| format!("Invalid keymap {}", path.display()))
+    }
+
+    /// Default location of the keymap file
+    /// ($XDG_CONFIG_HOME/gbrust/keymap or ~/.config/gbrust/keymap)
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+        Some(config.join("gbrust").join("keymap"))
+    }
+
+    /// Default keymap of the second player (--second-instance),
+    /// chosen not to overlap with the default keymap.
+    pub fn player2() -> Self {
+        let mut keymap = Self::empty();
+        keymap.bind(Button::DPadUp, &[KeyCode::Char('w')]);
+        keymap.bind(Button::DPadDown, &[KeyCode::Char('s')]);
+        keymap.bind(Button::DPadLeft, &[KeyCode::Char('a')]);
+        keymap.bind(Button::DPadRight, &[KeyCode::Char('d')]);
+        keymap.bind(Button::A, &[KeyCode::Char('g')]);
+        keymap.bind(Button::B, &[KeyCode::Char('f')]);
+        keymap.bind(Button::Select, &[KeyCode::Char('t')]);
+        keymap.bind(Button::Start, &[KeyCode::Char('r')]);
+        keymap
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut keymap = Self::empty();
+        keymap.bind(Button::DPadUp, &[KeyCode::Up]);
+        keymap.bind(Button::DPadDown, &[KeyCode::Down]);
+        keymap.bind(Button::DPadLeft, &[KeyCode::Left]);
+        keymap.bind(Button::DPadRight, &[KeyCode::Right]);
+        keymap.bind(Button::A, &[KeyCode::Char('l')]);
+        keymap.bind(Button::B, &[KeyCode::Char('m')]);
+        keymap.bind(Button::Select, &[KeyCode::Char('.')]);
+        keymap.bind(Button::Start, &[KeyCode::Char(' ')]);
+        keymap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::joypad::Joypad;
+    use crate::input::terminal::TerminalInput;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn parse() {
+        let k = KeyMap::parse(
+            "# WASD layout\n\
+             up = w\n\
+             down = s\n\
+             left = a, Left\n\
+             RIGHT = d # comment\n\
+             \n\
+             a = k\n\
+             b = j\n\
+             start = Enter\n\
+             select = F2\n",
+        )
+        .unwrap();
+        assert_eq!(k.get(KeyCode::Char('w')), Some(Button::DPadUp));
+        assert_eq!(k.get(KeyCode::Char('a')), Some(Button::DPadLeft));
+        assert_eq!(k.get(KeyCode::Left), Some(Button::DPadLeft));
+        assert_eq!(k.get(KeyCode::Char('d')), Some(Button::DPadRight));
+        assert_eq!(k.get(KeyCode::Enter), Some(Button::Start));
+        assert_eq!(k.get(KeyCode::F(2)), Some(Button::Select));
+        assert_eq!(k.get(KeyCode::Char('l')), None);
+        assert_eq!(k.keys(Button::A), vec![KeyCode::Char('k')]);
+    }
+
+    #[test]
+    fn parse_special_chars() {
+        let k = KeyMap::parse("a = #\nb = =\nstart = ,\nselect = space").unwrap();
+        assert_eq!(k.get(KeyCode::Char('#')), Some(Button::A));
+        assert_eq!(k.get(KeyCode::Char('=')), Some(Button::B));
+        assert_eq!(k.get(KeyCode::Char(',')), Some(Button::Start));
+        assert_eq!(k.get(KeyCode::Char(' ')), Some(Button::Select));
+    }
+
+    #[test]
+    fn parse_duplicate() {
+        let e = KeyMap::parse("a = z\nb = z").unwrap_err();
+        assert!(format!("{:#}", e).contains("already bound"));
+
+        let e = KeyMap::parse("a = z\na = x").unwrap_err();
+        assert!(format!("{:#}", e).contains("more than once"));
+    }
+
+    #[test]
+    fn parse_unknown_button() {
+        let e = KeyMap::parse("a = z\nturbo = x").unwrap_err();
+        let msg = format!("{:#}", e);
+        assert!(msg.contains("Line 2"));
+        assert!(msg.contains("'turbo'"));
+        assert!(msg.contains("up, down, left, right, a, b, start, select"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(KeyMap::parse("a z").is_err());
+        assert!(KeyMap::parse("a = foo").is_err());
+        assert!(KeyMap::parse("a = f13").is_err());
+        assert!(KeyMap::parse("a = ").is_err());
+    }
+
+    #[test]
+    fn binding() {
+        assert_eq!(
+            KeyMap::parse_binding("a=z").unwrap(),
+            (Button::A, KeyCode::Char('z'))
+        );
+        assert_eq!(
+            KeyMap::parse_binding("Start=enter").unwrap(),
+            (Button::Start, KeyCode::Enter)
+        );
+        assert!(KeyMap::parse_binding("a").is_err());
+        assert!(KeyMap::parse_binding("x=z").is_err());
+    }
+
+    #[test]
+    fn bind_override() {
+        let mut k = KeyMap::default();
+        assert_eq!(k.get(KeyCode::Char('l')), Some(Button::A));
+
+        k.bind(Button::A, &[KeyCode::Char('m')]);
+        assert_eq!(k.get(KeyCode::Char('l')), None);
+        assert_eq!(k.get(KeyCode::Char('m')), Some(Button::A));
+        assert!(k.keys(Button::B).is_empty());
+
+        let mut k = KeyMap::default();
+        k.apply(&KeyMap::parse("start = enter").unwrap());
+        assert_eq!(k.get(KeyCode::Enter), Some(Button::Start));
+        assert_eq!(k.get(KeyCode::Char(' ')), None);
+        assert_eq!(k.get(KeyCode::Char('l')), Some(Button::A));
+    }
+
+    #[test]
+    fn player2_no_overlap() {
+        let p1 = KeyMap::default();
+        let p2 = KeyMap::player2();
+        for b in Button::iter() {
+            assert_eq!(p2.keys(b).len(), 1);
+            for k in p2.keys(b) {
+                assert_eq!(p1.get(k), None);
+            }
+        }
+    }
+
+    #[test]
+    fn remapped_key_reaches_joypad() {
+        let mut keymap = KeyMap::default();
+        keymap.bind(Button::A, &[KeyCode::Char('z')]);
+
+        let (tx, rx) = mpsc::channel();
+        let mut joypad = Joypad::new(Box::new(TerminalInput::new(rx, keymap)));
+        // Select action buttons
+        joypad.write(0x10);
+        assert_eq!(joypad.read() & 0x0F, 0x0F);
+
+        // Old binding no longer works
+        tx.send(KeyCode::Char('l').into()).unwrap();
+        assert_eq!(joypad.read() & 0x0F, 0x0F);
+
+        tx.send(KeyCode::Char('z').into()).unwrap();
+        assert_eq!(joypad.read() & 0x0F, 0x0E);
+    }
+}
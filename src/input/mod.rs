@@ -1,2 +1,8 @@
 pub mod input;
+#[cfg(feature = "terminal")]
+pub mod keymap;
+#[cfg(feature = "terminal")]
+pub mod multiplexer;
+#[cfg(feature = "terminal")]
 pub mod terminal;
+pub mod turbo;
@@ -0,0 +1,107 @@
+use std::sync::mpsc;
+
+use terminal::KeyEvent;
+
+use super::keymap::KeyMap;
+
+/// Distributes key events from a single terminal over multiple
+/// players (e.g. two emulator instances sharing a keyboard).
+pub struct InputMultiplexer {
+    players: Vec<(KeyMap, mpsc::Sender<KeyEvent>)>,
+}
+
+impl InputMultiplexer {
+    pub fn new() -> Self {
+        Self { players: vec![] }
+    }
+
+    /// Adds a player and returns the receiver for its key events
+    /// (to be passed to a TerminalInput).
+    pub fn add_player(&mut self, keymap: KeyMap) -> mpsc::Receiver<KeyEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.players.push((keymap, tx));
+        rx
+    }
+
+    pub fn players(&self) -> usize {
+        self.players.len()
+    }
+
+    /// Returns the player a key is bound to
+    pub fn player_for(&self, event: &KeyEvent) -> Option<usize> {
+        self.players
+            .iter()
+            .position(|(keymap, _)| keymap.get(event.code).is_some())
+    }
+
+    /// Sends a key event to the first player that has the key bound.
+    /// Returns false if no player uses the key.
+    pub fn route(&self, event: KeyEvent) -> bool {
+        match self.player_for(&event) {
+            Some(p) => {
+                self.send(p, event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends a key event to a specific player
+    pub fn send(&self, player: usize, event: KeyEvent) {
+        // The instance may have stopped; nothing to do then.
+        let _ = self.players[player].1.send(event);
+    }
+}
+
+impl Default for InputMultiplexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::input::Button;
+
+    use terminal::KeyCode;
+
+    #[test]
+    fn route() {
+        let mut mux = InputMultiplexer::new();
+        let p1 = mux.add_player(KeyMap::default());
+        let p2 = mux.add_player(KeyMap::player2());
+        assert_eq!(mux.players(), 2);
+
+        assert!(mux.route(KeyCode::Char('l').into()));
+        assert!(mux.route(KeyCode::Char('w').into()));
+        assert!(!mux.route(KeyCode::Char('q').into()));
+
+        assert_eq!(p1.try_recv().unwrap().code, KeyCode::Char('l'));
+        assert!(p1.try_recv().is_err());
+        assert_eq!(p2.try_recv().unwrap().code, KeyCode::Char('w'));
+        assert!(p2.try_recv().is_err());
+    }
+
+    #[test]
+    fn first_player_wins() {
+        let mut keymap = KeyMap::player2();
+        keymap.bind(Button::A, &[KeyCode::Char('l')]);
+
+        let mut mux = InputMultiplexer::new();
+        let p1 = mux.add_player(KeyMap::default());
+        let p2 = mux.add_player(keymap);
+
+        assert_eq!(mux.player_for(&KeyCode::Char('l').into()), Some(0));
+        assert!(mux.route(KeyCode::Char('l').into()));
+        assert!(p1.try_recv().is_ok());
+        assert!(p2.try_recv().is_err());
+    }
+
+    #[test]
+    fn stopped_player() {
+        let mut mux = InputMultiplexer::new();
+        drop(mux.add_player(KeyMap::default()));
+        assert!(mux.route(KeyCode::Char('l').into()));
+    }
+}
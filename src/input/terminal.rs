@@ -1,60 +1,144 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::mpsc;
-use std::time::{Duration, Instant};
 
 use super::input::{Button, Input};
+use super::keymap::KeyMap;
 
-use strum::IntoEnumIterator;
-use terminal::{KeyCode, KeyEvent};
+use terminal::KeyEvent;
 
-pub struct TerminalInput {
-    receiver: mpsc::Receiver<KeyEvent>,
-    press_time: RefCell<BTreeMap<Button, Instant>>,
+/// Time a button remains pressed after a key event, in emulated
+/// frames. Terminals only deliver key presses (repeated while a key is
+/// held), never releases, so a release is assumed when no repeat
+/// arrives in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HoldTimeout {
+    /// After the first key event. Has to cover the delay before the
+    /// terminal starts repeating (typically 250-600ms).
+    pub initial: u64,
+    /// After a repeat. Has to cover the repeat interval (typically
+    /// 30-50ms).
+    pub repeat: u64,
 }
 
-impl TerminalInput {
-    /// Time an input remains asserted after a key press
-    const KEYDOWN_TIME: u128 = 200;
+impl HoldTimeout {
+    /// Parses 'INITIAL[,REPEAT]' (in frames). Without REPEAT, both
+    /// timeouts are the same.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let parse_frames = |v: &str| match v.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Invalid amount of frames '{}'", v)),
+        };
+        match s.split_once(',') {
+            None => {
+                let frames = parse_frames(s)?;
+                Ok(Self {
+                    initial: frames,
+                    repeat: frames,
+                })
+            }
+            Some((initial, repeat)) => Ok(Self {
+                initial: parse_frames(initial)?,
+                repeat: parse_frames(repeat)?,
+            }),
+        }
+    }
+}
 
-    pub fn new(receiver: mpsc::Receiver<KeyEvent>) -> Self {
+impl Default for HoldTimeout {
+    /// 500ms to cover the initial autorepeat delay of most terminals,
+    /// then 100ms for repeats.
+    fn default() -> Self {
         Self {
-            receiver,
-            press_time: RefCell::new(BTreeMap::from_iter(Button::iter().map(|e| {
-                (
-                    e,
-                    Instant::now()
-                        .checked_sub(Duration::from_millis(Self::KEYDOWN_TIME as u64))
-                        .unwrap(),
-                )
-            }))),
+            initial: 30,
+            repeat: 6,
+        }
+    }
+}
+
+impl fmt::Display for HoldTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{}", self.initial, self.repeat)
+    }
+}
+
+/// Button state for key events without releases. A key event presses
+/// the button until a deadline, repeats while the button is pressed
+/// extend the deadline. Time only advances with emulated frames, so
+/// the same events on the same frames give the same button states.
+pub struct KeyHold {
+    timeout: HoldTimeout,
+    frame: u64,
+    /// Frame at which each button is released, None if not pressed
+    deadlines: [Option<u64>; 8],
+}
+
+impl KeyHold {
+    pub fn new(timeout: HoldTimeout) -> Self {
+        Self {
+            timeout,
+            frame: 0,
+            deadlines: Default::default(),
         }
     }
 
-    fn kick_btn(&self, b: Button) {
-        self.press_time.borrow_mut().insert(b, Instant::now());
+    /// Processes a key event (press or repeat) for a button
+    pub fn press(&mut self, b: Button) {
+        let deadline = &mut self.deadlines[b as usize];
+        *deadline = Some(match *deadline {
+            Some(d) => d.max(self.frame + self.timeout.repeat),
+            None => self.frame + self.timeout.initial,
+        });
     }
 
-    fn map_key(k: KeyCode) -> Option<Button> {
-        match k {
-            KeyCode::Up => Some(Button::DPadUp),
-            KeyCode::Down => Some(Button::DPadDown),
-            KeyCode::Left => Some(Button::DPadLeft),
-            KeyCode::Right => Some(Button::DPadRight),
+    /// Advances to a frame, releasing the buttons of which the deadline
+    /// passed.
+    pub fn set_frame(&mut self, frame: u64) {
+        if frame < self.frame {
+            // The frame count went back (e.g. after loading a state)
+            self.deadlines = Default::default();
+        }
+        self.frame = frame;
+        for deadline in self.deadlines.iter_mut() {
+            if deadline.is_some_and(|d| d <= frame) {
+                *deadline = None;
+            }
+        }
+    }
 
-            KeyCode::Char('l') => Some(Button::A),
-            KeyCode::Char('m') => Some(Button::B),
-            KeyCode::Char('.') => Some(Button::Select),
-            KeyCode::Char(' ') => Some(Button::Start),
+    pub fn is_pressed(&self, b: Button) -> bool {
+        self.deadlines[b as usize].is_some()
+    }
+}
+
+pub struct TerminalInput {
+    receiver: mpsc::Receiver<KeyEvent>,
+    keymap: KeyMap,
+    hold: RefCell<KeyHold>,
+}
+
+impl TerminalInput {
+    pub fn new(receiver: mpsc::Receiver<KeyEvent>, keymap: KeyMap) -> Self {
+        Self::with_timeout(receiver, keymap, HoldTimeout::default())
+    }
 
-            _ => None,
+    pub fn with_timeout(
+        receiver: mpsc::Receiver<KeyEvent>,
+        keymap: KeyMap,
+        timeout: HoldTimeout,
+    ) -> Self {
+        Self {
+            receiver,
+            keymap,
+            hold: RefCell::new(KeyHold::new(timeout)),
         }
     }
 
     fn process_input(&self) {
-        if let Ok(keyevent) = self.receiver.try_recv() {
-            if let Some(btn) = Self::map_key(keyevent.code) {
-                self.kick_btn(btn);
+        let mut hold = self.hold.borrow_mut();
+        while let Ok(keyevent) = self.receiver.try_recv() {
+            if let Some(btn) = self.keymap.get(keyevent.code) {
+                hold.press(btn);
             }
         }
     }
@@ -63,12 +147,129 @@ impl TerminalInput {
 impl Input for TerminalInput {
     fn is_pressed(&self, b: Button) -> bool {
         self.process_input();
-        self.press_time
-            .borrow()
-            .get(&b)
-            .unwrap()
-            .elapsed()
-            .as_millis()
-            < Self::KEYDOWN_TIME
+        self.hold.borrow().is_pressed(b)
+    }
+
+    fn set_frame(&mut self, frame: u64) {
+        self.process_input();
+        self.hold.get_mut().set_frame(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use strum::IntoEnumIterator;
+
+    const TIMEOUT: HoldTimeout = HoldTimeout {
+        initial: 10,
+        repeat: 3,
+    };
+
+    #[test]
+    fn press() {
+        let mut hold = KeyHold::new(TIMEOUT);
+        for b in Button::iter() {
+            assert!(!hold.is_pressed(b));
+        }
+        hold.set_frame(5);
+        hold.press(Button::A);
+        for b in Button::iter() {
+            assert_eq!(hold.is_pressed(b), b == Button::A);
+        }
+    }
+
+    #[test]
+    fn release_by_timeout() {
+        let mut hold = KeyHold::new(TIMEOUT);
+        hold.set_frame(5);
+        hold.press(Button::A);
+        hold.set_frame(14);
+        assert!(hold.is_pressed(Button::A));
+        hold.set_frame(15);
+        assert!(!hold.is_pressed(Button::A));
+    }
+
+    #[test]
+    fn repeat() {
+        let mut hold = KeyHold::new(TIMEOUT);
+        hold.press(Button::DPadRight);
+
+        // A repeat early on does not shorten the initial timeout
+        hold.set_frame(1);
+        hold.press(Button::DPadRight);
+        hold.set_frame(9);
+        assert!(hold.is_pressed(Button::DPadRight));
+        hold.press(Button::DPadRight);
+
+        // Repeats keep the button pressed past the initial timeout
+        for frame in 10..30 {
+            hold.set_frame(frame);
+            assert!(hold.is_pressed(Button::DPadRight), "frame {}", frame);
+            if frame % 2 == 0 {
+                hold.press(Button::DPadRight);
+            }
+        }
+
+        // Released after the repeat timeout
+        hold.set_frame(30);
+        assert!(hold.is_pressed(Button::DPadRight));
+        hold.set_frame(31);
+        assert!(!hold.is_pressed(Button::DPadRight));
+    }
+
+    #[test]
+    fn two_buttons() {
+        let mut hold = KeyHold::new(TIMEOUT);
+        hold.press(Button::DPadRight);
+        hold.set_frame(4);
+        hold.press(Button::A);
+        hold.set_frame(8);
+        hold.press(Button::DPadRight);
+        assert!(hold.is_pressed(Button::DPadRight));
+        assert!(hold.is_pressed(Button::A));
+
+        // Deadlines are per button
+        hold.set_frame(11);
+        assert!(!hold.is_pressed(Button::DPadRight));
+        assert!(hold.is_pressed(Button::A));
+        hold.set_frame(14);
+        assert!(!hold.is_pressed(Button::A));
+    }
+
+    #[test]
+    fn frame_count_reset() {
+        let mut hold = KeyHold::new(TIMEOUT);
+        hold.set_frame(100);
+        hold.press(Button::B);
+        hold.set_frame(0);
+        assert!(!hold.is_pressed(Button::B));
+        hold.press(Button::B);
+        hold.set_frame(9);
+        assert!(hold.is_pressed(Button::B));
+    }
+
+    #[test]
+    fn parse_timeout() {
+        assert_eq!(
+            HoldTimeout::parse("20"),
+            Ok(HoldTimeout {
+                initial: 20,
+                repeat: 20
+            })
+        );
+        assert_eq!(
+            HoldTimeout::parse("30,5"),
+            Ok(HoldTimeout {
+                initial: 30,
+                repeat: 5
+            })
+        );
+        assert!(HoldTimeout::parse("0").is_err());
+        assert!(HoldTimeout::parse("10,").is_err());
+        assert!(HoldTimeout::parse("x").is_err());
+        let default = HoldTimeout::default();
+        assert_eq!(HoldTimeout::parse(&default.to_string()), Ok(default));
     }
 }
@@ -0,0 +1,188 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use strum::IntoEnumIterator;
+
+use super::input::{Button, Input};
+use crate::gameboy::cpu::cpu::CPU_CLOCK_HZ;
+use crate::gameboy::emulator::Emulator;
+
+/// Emulated frames per second
+const FRAME_RATE: f64 = CPU_CLOCK_HZ as f64 / Emulator::FRAME_CYCLES as f64;
+
+/// Default autofire rate, in presses per second
+pub const DEFAULT_TURBO_RATE: f64 = 10.0;
+
+/// Shared handle to the set of buttons in turbo mode, which can be
+/// changed from another thread while the emulator is running.
+#[derive(Clone, Default)]
+pub struct TurboButtons {
+    mask: Arc<AtomicU8>,
+}
+
+impl TurboButtons {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, b: Button, enabled: bool) {
+        let mask = 1 << b as u8;
+        if enabled {
+            self.mask.fetch_or(mask, Ordering::Relaxed);
+        } else {
+            self.mask.fetch_and(!mask, Ordering::Relaxed);
+        }
+    }
+
+    /// Toggles turbo mode for a button, returns the new state
+    pub fn toggle(&self, b: Button) -> bool {
+        let mask = 1 << b as u8;
+        self.mask.fetch_xor(mask, Ordering::Relaxed) & mask == 0
+    }
+
+    pub fn is_enabled(&self, b: Button) -> bool {
+        self.mask.load(Ordering::Relaxed) & (1 << b as u8) != 0
+    }
+}
+
+/// Input wrapper that makes held buttons in turbo mode repeatedly
+/// press and release (autofire).
+/// The press pattern only depends on the emulated frame count, so it
+/// is deterministic and can be recorded by a MovieRecorder wrapping
+/// this input.
+pub struct TurboInput {
+    inner: Box<dyn Input>,
+    buttons: TurboButtons,
+    /// Length of one press + release cycle, in frames
+    period: u64,
+    frame: u64,
+    /// Frame at which each button was first seen held
+    held_since: [Cell<Option<u64>>; 8],
+}
+
+impl TurboInput {
+    /// Wraps an input, autofiring 'rate' times per second
+    pub fn new(inner: Box<dyn Input>, buttons: TurboButtons, rate: f64) -> Self {
+        Self {
+            inner,
+            buttons,
+            period: Self::rate_to_period(rate),
+            frame: 0,
+            held_since: Default::default(),
+        }
+    }
+
+    /// Converts a rate to a period in frames. The shortest period is
+    /// two frames (pressed one frame, released the next).
+    fn rate_to_period(rate: f64) -> u64 {
+        ((FRAME_RATE / rate).round() as u64).max(2)
+    }
+}
+
+impl Input for TurboInput {
+    fn is_pressed(&self, b: Button) -> bool {
+        let held = self.inner.is_pressed(b);
+        if !held || !self.buttons.is_enabled(b) {
+            return held;
+        }
+
+        // Pressed for the first half of every period since the button
+        // was first held down
+        let since = &self.held_since[b as usize];
+        let start = since.get().unwrap_or(self.frame);
+        since.set(Some(start));
+        (self.frame - start) % self.period < self.period / 2
+    }
+
+    fn set_frame(&mut self, frame: u64) {
+        self.inner.set_frame(frame);
+        self.frame = frame;
+        for b in Button::iter() {
+            let since = &self.held_since[b as usize];
+            if !self.inner.is_pressed(b) {
+                since.set(None);
+            } else if since.get().is_none_or(|s| s > frame) {
+                // Also restarts if the frame count went back (e.g.
+                // after loading a state)
+                since.set(Some(frame));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::input::input::SharedInput;
+
+    /// Runs 'frames' frames, returns the state of a button in each frame
+    fn pattern(input: &mut TurboInput, b: Button, start: u64, frames: u64) -> String {
+        (start..(start + frames))
+            .map(|f| {
+                input.set_frame(f);
+                if input.is_pressed(b) {
+                    'X'
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn duty_cycle() {
+        let (inner, state) = SharedInput::new();
+        let buttons = TurboButtons::new();
+        buttons.set(Button::A, true);
+        // 59.7 / 15 = 4 frames per press
+        let mut input = TurboInput::new(inner, buttons, 15.0);
+        assert_eq!(input.period, 4);
+
+        assert_eq!(pattern(&mut input, Button::A, 0, 4), "....");
+        state.set(Button::A, true);
+        state.set(Button::B, true);
+        assert_eq!(pattern(&mut input, Button::A, 5, 12), "XX..XX..XX..");
+        // Not in turbo mode
+        assert_eq!(pattern(&mut input, Button::B, 17, 4), "XXXX");
+
+        // Restarts when released
+        state.set(Button::A, false);
+        assert_eq!(pattern(&mut input, Button::A, 21, 1), ".");
+        state.set(Button::A, true);
+        assert_eq!(pattern(&mut input, Button::A, 22, 4), "XX..");
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(TurboInput::rate_to_period(DEFAULT_TURBO_RATE), 6);
+        assert_eq!(TurboInput::rate_to_period(30.0), 2);
+        assert_eq!(TurboInput::rate_to_period(60.0), 2);
+        assert_eq!(TurboInput::rate_to_period(1.0), 60);
+
+        let (inner, state) = SharedInput::new();
+        let buttons = TurboButtons::new();
+        buttons.set(Button::B, true);
+        let mut input = TurboInput::new(inner, buttons, 30.0);
+        state.set(Button::B, true);
+        assert_eq!(pattern(&mut input, Button::B, 100, 6), "X.X.X.");
+    }
+
+    #[test]
+    fn toggle() {
+        let (inner, state) = SharedInput::new();
+        let buttons = TurboButtons::new();
+        let mut input = TurboInput::new(inner, buttons.clone(), 30.0);
+        state.set(Button::A, true);
+        assert_eq!(pattern(&mut input, Button::A, 0, 4), "XXXX");
+
+        assert!(buttons.toggle(Button::A));
+        assert!(buttons.is_enabled(Button::A));
+        assert!(!buttons.is_enabled(Button::B));
+        assert_eq!(pattern(&mut input, Button::A, 4, 4), "X.X.");
+
+        assert!(!buttons.toggle(Button::A));
+        assert_eq!(pattern(&mut input, Button::A, 8, 4), "XXXX");
+    }
+}
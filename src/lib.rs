@@ -1,8 +1,19 @@
+pub mod audio;
 pub mod display;
+pub mod error;
+
+#[cfg(feature = "capi")]
+pub mod ffi;
+
 pub mod gameboy;
 pub mod input;
+
+#[cfg(feature = "libretro")]
+pub mod libretro;
+
 pub mod misc;
 pub mod tickable;
+pub mod time;
 
 #[cfg(test)]
 pub mod test;
@@ -0,0 +1,782 @@
+//! libretro core implementation. Enabled by the 'libretro' feature,
+//! the 'gbrust_libretro' crate builds this into a loadable core.
+//!
+//! The libretro API is a singleton API; the core state lives in a
+//! thread local as the emulator is not Send. Frontends call the core
+//! from a single thread.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use num_traits::FromPrimitive;
+
+use crate::audio::audio;
+use crate::display::display::NullDisplay;
+use crate::gameboy::cartridge::cartridge::{self, CartridgeType, CARTTYPE_OFFSET};
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::lcd::{LCD_H, LCD_W};
+use crate::gameboy::model::Model;
+use crate::gameboy::serial::Serial;
+use crate::input::input::{Button, ButtonState, SharedInput};
+
+pub const RETRO_API_VERSION: c_uint = 1;
+
+pub const RETRO_DEVICE_JOYPAD: c_uint = 1;
+
+pub const RETRO_DEVICE_ID_JOYPAD_B: c_uint = 0;
+pub const RETRO_DEVICE_ID_JOYPAD_SELECT: c_uint = 2;
+pub const RETRO_DEVICE_ID_JOYPAD_START: c_uint = 3;
+pub const RETRO_DEVICE_ID_JOYPAD_UP: c_uint = 4;
+pub const RETRO_DEVICE_ID_JOYPAD_DOWN: c_uint = 5;
+pub const RETRO_DEVICE_ID_JOYPAD_LEFT: c_uint = 6;
+pub const RETRO_DEVICE_ID_JOYPAD_RIGHT: c_uint = 7;
+pub const RETRO_DEVICE_ID_JOYPAD_A: c_uint = 8;
+
+pub const RETRO_ENVIRONMENT_SHUTDOWN: c_uint = 7;
+pub const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+
+pub const RETRO_PIXEL_FORMAT_RGB565: c_uint = 2;
+
+pub const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+
+pub const RETRO_REGION_NTSC: c_uint = 0;
+
+/// Audio sample rate reported to the frontend
+const SAMPLE_RATE: f64 = audio::SAMPLE_RATE as f64;
+
+/// Gameboy frame rate (4.194304 MHz / 70224 cycles per frame)
+const FPS: f64 = 4194304.0 / Emulator::FRAME_CYCLES as f64;
+
+/// Mapping of libretro joypad buttons to Gameboy buttons
+const BUTTON_MAP: [(c_uint, Button); 8] = [
+    (RETRO_DEVICE_ID_JOYPAD_UP, Button::DPadUp),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, Button::DPadDown),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, Button::DPadLeft),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, Button::DPadRight),
+    (RETRO_DEVICE_ID_JOYPAD_A, Button::A),
+    (RETRO_DEVICE_ID_JOYPAD_B, Button::B),
+    (RETRO_DEVICE_ID_JOYPAD_START, Button::Start),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, Button::Select),
+];
+
+pub type RetroEnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type RetroVideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type RetroAudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPollFn = unsafe extern "C" fn();
+pub type RetroInputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+/// Callbacks registered by the frontend
+#[derive(Copy, Clone, Default)]
+struct Callbacks {
+    environment: Option<RetroEnvironmentFn>,
+    video_refresh: Option<RetroVideoRefreshFn>,
+    audio_sample: Option<RetroAudioSampleFn>,
+    audio_sample_batch: Option<RetroAudioSampleBatchFn>,
+    input_poll: Option<RetroInputPollFn>,
+    input_state: Option<RetroInputStateFn>,
+}
+
+/// State of a loaded game
+struct Core {
+    rom: Vec<u8>,
+    emu: Emulator,
+    buttons: ButtonState,
+    /// Cartridge RAM exposed to the frontend as RETRO_MEMORY_SAVE_RAM.
+    /// The frontend reads and writes this buffer directly, so it is
+    /// synchronized with the cartridge around every frame.
+    sram: Vec<u8>,
+    /// Samples (silence) for one frame, interleaved stereo
+    audio: Vec<i16>,
+}
+
+impl Core {
+    fn new(rom: &[u8], emu: Emulator, buttons: ButtonState) -> Self {
+        let sram = emu.get_save();
+        Self {
+            rom: rom.to_vec(),
+            emu,
+            buttons,
+            sram,
+            audio: vec![0; (SAMPLE_RATE / FPS) as usize * 2],
+        }
+    }
+
+    /// Loads SRAM into the cartridge if the frontend modified it
+    fn sync_sram_in(&mut self) {
+        if !self.sram.is_empty() && self.sram != self.emu.get_save() {
+            if let Err(e) = self.emu.load_save(&self.sram) {
+                // Continue with empty RAM rather than taking the
+                // frontend down with us. An empty save always fits.
+                eprintln!("gbrust: failed to load SRAM: {:#}", e);
+                self.sram.fill(0);
+                let _ = self.emu.load_save(&[]);
+            }
+        }
+    }
+
+    fn sync_sram_out(&mut self) {
+        let save = self.emu.get_save();
+        self.sram.copy_from_slice(&save);
+    }
+
+    fn run_frame(&mut self, cb: &Callbacks) -> anyhow::Result<()> {
+        if let Some(poll) = cb.input_poll {
+            // SAFETY: callback provided by the frontend
+            unsafe { poll() };
+        }
+        if let Some(state) = cb.input_state {
+            for (id, button) in BUTTON_MAP {
+                // SAFETY: callback provided by the frontend
+                let pressed = unsafe { state(0, RETRO_DEVICE_JOYPAD, 0, id) } != 0;
+                self.buttons.set(button, pressed);
+            }
+        }
+
+        self.sync_sram_in();
+        self.emu.run_frame()?;
+        self.sync_sram_out();
+
+        if let Some(video) = cb.video_refresh {
+            let frame = self.emu.get_lcd().get_frame().rgb565();
+            // SAFETY: callback provided by the frontend
+            unsafe {
+                video(
+                    frame.as_ptr() as *const c_void,
+                    LCD_W as c_uint,
+                    LCD_H as c_uint,
+                    LCD_W * 2,
+                )
+            };
+        }
+
+        // The APU does not produce samples yet, but frontends use
+        // audio to synchronize.
+        if let Some(audio) = cb.audio_sample_batch {
+            // SAFETY: callback provided by the frontend
+            unsafe { audio(self.audio.as_ptr(), self.audio.len() / 2) };
+        }
+
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CALLBACKS: Cell<Callbacks> = Cell::new(Callbacks::default());
+    static CORE: RefCell<Option<Core>> = const { RefCell::new(None) };
+}
+
+fn callbacks() -> Callbacks {
+    CALLBACKS.with(|c| c.get())
+}
+
+fn set_callbacks(f: impl FnOnce(&mut Callbacks)) {
+    CALLBACKS.with(|c| {
+        let mut cb = c.get();
+        f(&mut cb);
+        c.set(cb);
+    });
+}
+
+/// Runs a closure on the loaded game (if any), catching panics.
+/// Returns None if no game is loaded or the core panicked; after a
+/// panic the game is unloaded.
+fn with_core<T>(f: impl FnOnce(&mut Core) -> T) -> Option<T> {
+    CORE.with(|c| {
+        let mut core = c.borrow_mut();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| core.as_mut().map(f)));
+        match result {
+            Ok(r) => r,
+            Err(_) => {
+                *core = None;
+                None
+            }
+        }
+    })
+}
+
+/// Asks the frontend to shut down after a fatal error
+fn shutdown() {
+    CORE.with(|c| *c.borrow_mut() = None);
+    if let Some(env) = callbacks().environment {
+        // SAFETY: callback provided by the frontend
+        unsafe { env(RETRO_ENVIRONMENT_SHUTDOWN, ptr::null_mut()) };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: RetroEnvironmentFn) {
+    set_callbacks(|c| c.environment = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: RetroVideoRefreshFn) {
+    set_callbacks(|c| c.video_refresh = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(cb: RetroAudioSampleFn) {
+    set_callbacks(|c| c.audio_sample = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: RetroAudioSampleBatchFn) {
+    set_callbacks(|c| c.audio_sample_batch = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: RetroInputPollFn) {
+    set_callbacks(|c| c.input_poll = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: RetroInputStateFn) {
+    set_callbacks(|c| c.input_state = Some(cb));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    CORE.with(|c| *c.borrow_mut() = None);
+}
+
+/// # Safety
+/// info must point to a writable retro_system_info.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    if info.is_null() {
+        return;
+    }
+    // SAFETY: checked for NULL, caller guarantees validity
+    unsafe {
+        *info = RetroSystemInfo {
+            library_name: c"gbrust".as_ptr(),
+            library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            valid_extensions: c"gb|gbc".as_ptr(),
+            need_fullpath: false,
+            block_extract: false,
+        };
+    }
+}
+
+/// # Safety
+/// info must point to a writable retro_system_av_info.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    if info.is_null() {
+        return;
+    }
+    // SAFETY: checked for NULL, caller guarantees validity
+    unsafe {
+        *info = RetroSystemAvInfo {
+            geometry: RetroGameGeometry {
+                base_width: LCD_W as c_uint,
+                base_height: LCD_H as c_uint,
+                max_width: LCD_W as c_uint,
+                max_height: LCD_H as c_uint,
+                aspect_ratio: LCD_W as f32 / LCD_H as f32,
+            },
+            timing: RetroSystemTiming {
+                fps: FPS,
+                sample_rate: SAMPLE_RATE,
+            },
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    // Reload the cartridge, keeping cartridge RAM.
+    with_core(|core| {
+        let save = core.emu.get_save();
+        if let Some(new) = create_core(&core.rom) {
+            *core = new;
+            core.sram.copy_from_slice(&save);
+            core.sync_sram_in();
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let cb = callbacks();
+    if !matches!(with_core(|core| core.run_frame(&cb)), Some(Ok(_))) {
+        shutdown();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_core(|core| core.emu.save_state().len()).unwrap_or(0)
+}
+
+/// # Safety
+/// data must point to size writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    with_core(|core| {
+        core.sync_sram_in();
+        let state = core.emu.save_state();
+        if size < state.len() {
+            return false;
+        }
+        // SAFETY: caller guarantees data points to size bytes
+        unsafe { ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len()) };
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// # Safety
+/// data must point to size readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    with_core(|core| {
+        // SAFETY: caller guarantees data points to size bytes
+        let state = unsafe { slice::from_raw_parts(data as *const u8, size) };
+        if core.emu.load_state(state).is_err() {
+            return false;
+        }
+        core.sync_sram_out();
+        true
+    })
+    .unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+fn create_core(rom: &[u8]) -> Option<Core> {
+    if rom.len() < 32 * 1024 || CartridgeType::from_u8(rom[CARTTYPE_OFFSET]).is_none() {
+        return None;
+    }
+
+    panic::catch_unwind(|| {
+        let cart = cartridge::load(rom).ok()?;
+        let cgb = cart.is_cgb();
+        let (input, buttons) = SharedInput::new();
+        let emu = Emulator::new(
+            cart,
+            None,
+            Box::new(NullDisplay::new()),
+            input,
+            Model::from_cgb(cgb),
+            Serial::new_null(),
+        );
+        Some(Core::new(rom, emu, buttons))
+    })
+    .ok()
+    .flatten()
+}
+
+/// # Safety
+/// game must be NULL or point to a valid retro_game_info with data
+/// pointing to size readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() {
+        return false;
+    }
+    // SAFETY: checked for NULL, caller guarantees validity
+    let game = unsafe { &*game };
+    if game.data.is_null() {
+        return false;
+    }
+    // SAFETY: caller guarantees data points to size bytes
+    let rom = unsafe { slice::from_raw_parts(game.data as *const u8, game.size) };
+
+    if let Some(env) = callbacks().environment {
+        let mut format = RETRO_PIXEL_FORMAT_RGB565;
+        // SAFETY: callback provided by the frontend
+        if !unsafe {
+            env(
+                RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+                &mut format as *mut _ as *mut c_void,
+            )
+        } {
+            return false;
+        }
+    }
+
+    let Some(core) = create_core(rom) else {
+        return false;
+    };
+    CORE.with(|c| *c.borrow_mut() = Some(core));
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const RetroGameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    CORE.with(|c| *c.borrow_mut() = None);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return ptr::null_mut();
+    }
+    with_core(|core| {
+        if core.sram.is_empty() {
+            ptr::null_mut()
+        } else {
+            core.sram.as_mut_ptr() as *mut c_void
+        }
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    if id != RETRO_MEMORY_SAVE_RAM {
+        return 0;
+    }
+    with_core(|core| core.sram.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::without_panic_output;
+
+    #[derive(Default)]
+    struct Frontend {
+        frames: usize,
+        frame_sizes: Vec<(c_uint, c_uint, usize)>,
+        last_frame: Vec<u16>,
+        audio_frames: usize,
+        pixel_format: Option<c_uint>,
+        shutdown: bool,
+        start_pressed: bool,
+    }
+
+    thread_local! {
+        static FRONTEND: RefCell<Frontend> = RefCell::new(Frontend::default());
+    }
+
+    unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+        FRONTEND.with(|f| {
+            let mut f = f.borrow_mut();
+            match cmd {
+                RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                    f.pixel_format = Some(unsafe { *(data as *const c_uint) });
+                    true
+                }
+                RETRO_ENVIRONMENT_SHUTDOWN => {
+                    f.shutdown = true;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    unsafe extern "C" fn video_refresh(
+        data: *const c_void,
+        width: c_uint,
+        height: c_uint,
+        pitch: usize,
+    ) {
+        FRONTEND.with(|f| {
+            let mut f = f.borrow_mut();
+            f.frames += 1;
+            f.frame_sizes.push((width, height, pitch));
+            f.last_frame = unsafe {
+                slice::from_raw_parts(data as *const u16, pitch / 2 * height as usize).to_vec()
+            };
+        })
+    }
+
+    unsafe extern "C" fn audio_sample(_left: i16, _right: i16) {}
+
+    unsafe extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+        FRONTEND.with(|f| f.borrow_mut().audio_frames += frames);
+        frames
+    }
+
+    unsafe extern "C" fn input_poll() {}
+
+    unsafe extern "C" fn input_state(
+        port: c_uint,
+        device: c_uint,
+        _index: c_uint,
+        id: c_uint,
+    ) -> i16 {
+        assert_eq!(port, 0);
+        assert_eq!(device, RETRO_DEVICE_JOYPAD);
+        FRONTEND.with(|f| (f.borrow().start_pressed && id == RETRO_DEVICE_ID_JOYPAD_START) as i16)
+    }
+
+    fn init() {
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample(audio_sample);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+    }
+
+    fn load_game(rom: &[u8]) -> bool {
+        let game = RetroGameInfo {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+        unsafe { retro_load_game(&game) }
+    }
+
+    /// MBC1+RAM+BATTERY ROM that selects the buttons and copies the
+    /// joypad register to cartridge RAM (0xA000) in a loop.
+    fn sram_rom() -> Vec<u8> {
+        let mut rom = vec![0; 64 * 1024];
+        rom[0x100..0x111].copy_from_slice(&[
+            0x3E, 0x0A, // LD A,0Ah
+            0xEA, 0x00, 0x00, // LD (0000h),A - enable RAM
+            0x3E, 0x10, // LD A,10h
+            0xE0, 0x00, // LDH (00h),A - select buttons
+            0xF0, 0x00, // LDH A,(00h)
+            0xEA, 0x00, 0xA0, // LD (A000h),A
+            0xC3, 0x09, 0x01, // JP 0109h
+        ]);
+        rom[CARTTYPE_OFFSET] = CartridgeType::Mbc1RamBat as u8;
+        rom[0x148] = 1;
+        rom[0x149] = 2;
+        rom
+    }
+
+    #[test]
+    fn info() {
+        assert_eq!(retro_api_version(), RETRO_API_VERSION);
+
+        let mut info = RetroSystemInfo {
+            library_name: ptr::null(),
+            library_version: ptr::null(),
+            valid_extensions: ptr::null(),
+            need_fullpath: true,
+            block_extract: true,
+        };
+        unsafe { retro_get_system_info(&mut info) };
+        assert!(!info.library_name.is_null());
+        assert!(!info.need_fullpath);
+
+        let mut av = RetroSystemAvInfo {
+            geometry: RetroGameGeometry {
+                base_width: 0,
+                base_height: 0,
+                max_width: 0,
+                max_height: 0,
+                aspect_ratio: 0.0,
+            },
+            timing: RetroSystemTiming {
+                fps: 0.0,
+                sample_rate: 0.0,
+            },
+        };
+        unsafe { retro_get_system_av_info(&mut av) };
+        assert_eq!(
+            (av.geometry.base_width, av.geometry.base_height),
+            (160, 144)
+        );
+        assert!((av.timing.fps - 59.7275).abs() < 0.001);
+    }
+
+    #[test]
+    fn run() {
+        init();
+        assert!(load_game(include_bytes!("../tests/dmg-acid2/dmg-acid2.gb")));
+        FRONTEND.with(|f| assert_eq!(f.borrow().pixel_format, Some(RETRO_PIXEL_FORMAT_RGB565)));
+
+        for _ in 0..300 {
+            retro_run();
+        }
+        FRONTEND.with(|f| {
+            let f = f.borrow();
+            assert!(!f.shutdown);
+            assert_eq!(f.frames, 300);
+            assert!(f.frame_sizes.iter().all(|&s| s == (160, 144, 320)));
+            assert_eq!(f.audio_frames, 300 * 738);
+            // Test image is drawn
+            assert!(f.last_frame.iter().any(|&c| c != f.last_frame[0]));
+        });
+
+        // Savestate roundtrip
+        let size = retro_serialize_size();
+        assert!(size > 0);
+        let mut state = vec![0u8; size];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, size) });
+        assert!(!unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, size - 1) });
+        retro_run();
+        let frame = FRONTEND.with(|f| f.borrow().last_frame.clone());
+        for _ in 0..10 {
+            retro_run();
+        }
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, size) });
+        assert!(!unsafe { retro_unserialize(state.as_ptr() as *const c_void, 10) });
+        retro_run();
+        FRONTEND.with(|f| assert_eq!(f.borrow().last_frame, frame));
+        assert_eq!(retro_serialize_size(), size);
+
+        retro_reset();
+        retro_run();
+        assert!(!FRONTEND.with(|f| f.borrow().shutdown));
+
+        // No cartridge RAM
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0);
+        assert!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM).is_null());
+
+        retro_unload_game();
+        retro_deinit();
+    }
+
+    #[test]
+    fn sram() {
+        init();
+        assert!(load_game(&sram_rom()));
+
+        let size = retro_get_memory_size(RETRO_MEMORY_SAVE_RAM);
+        assert!(size > 0);
+        let data = retro_get_memory_data(RETRO_MEMORY_SAVE_RAM) as *mut u8;
+        assert!(!data.is_null());
+        let sram = unsafe { slice::from_raw_parts_mut(data, size) };
+
+        // Frontend loads a save before the first frame
+        sram[1] = 0x55;
+        retro_run();
+        assert_eq!(sram[0] & 0x0F, 0x0F);
+        assert_eq!(sram[1], 0x55);
+        assert_eq!(with_core(|c| c.emu.get_save()[1]), Some(0x55));
+
+        // Start (bit 3) pressed is reflected in cartridge RAM
+        FRONTEND.with(|f| f.borrow_mut().start_pressed = true);
+        retro_run();
+        assert_eq!(sram[0] & 0x0F, 0x07);
+
+        // Reset keeps cartridge RAM
+        retro_reset();
+        assert_eq!(with_core(|c| c.emu.get_save()[1]), Some(0x55));
+
+        retro_unload_game();
+        retro_deinit();
+    }
+
+    #[test]
+    fn invalid() {
+        init();
+        assert!(!unsafe { retro_load_game(ptr::null()) });
+        assert!(!load_game(&[0; 1024]));
+        let mut rom = vec![0u8; 32 * 1024];
+        rom[CARTTYPE_OFFSET] = 0xEE;
+        assert!(!load_game(&rom));
+
+        // Running without a game asks the frontend to shut down
+        retro_run();
+        FRONTEND.with(|f| {
+            let f = f.borrow();
+            assert!(f.shutdown);
+            assert_eq!(f.frames, 0);
+        });
+        assert_eq!(retro_serialize_size(), 0);
+        assert!(retro_get_memory_data(RETRO_MEMORY_SAVE_RAM).is_null());
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        init();
+        let mut rom = vec![0u8; 32 * 1024];
+        rom[0x100] = 0xD3;
+        assert!(load_game(&rom));
+
+        retro_run();
+        FRONTEND.with(|f| assert!(f.borrow().shutdown));
+        assert_eq!(retro_serialize_size(), 0);
+    }
+
+    #[test]
+    fn panic_safe() {
+        init();
+        let rom = vec![0u8; 32 * 1024];
+        assert!(load_game(&rom));
+
+        let result = without_panic_output(|| with_core(|_| panic!("Test panic")));
+
+        assert!(result.is_none());
+        assert_eq!(retro_serialize_size(), 0);
+    }
+}
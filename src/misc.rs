@@ -1,3 +1,4 @@
+use std::any::Any;
 use std::io;
 use std::sync::mpsc;
 
@@ -27,3 +28,50 @@ impl io::Write for WritableSender {
         Ok(())
     }
 }
+
+/// Wraps a mpsc::Receiver<u8> to make it
+/// implement the std::io::Read trait.
+/// Reads do not block; if no data is available,
+/// io::ErrorKind::WouldBlock is returned.
+pub struct ReadableReceiver {
+    receiver: mpsc::Receiver<u8>,
+}
+
+impl ReadableReceiver {
+    pub fn new(receiver: mpsc::Receiver<u8>) -> Self {
+        Self { receiver }
+    }
+}
+
+impl io::Read for ReadableReceiver {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        while len < buf.len() {
+            match self.receiver.try_recv() {
+                Ok(b) => {
+                    buf[len] = b;
+                    len += 1;
+                }
+                Err(mpsc::TryRecvError::Empty) if len == 0 => {
+                    return Err(io::Error::from(io::ErrorKind::WouldBlock))
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                // End of stream
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Returns the message of a panic payload (as returned by
+/// std::panic::catch_unwind())
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
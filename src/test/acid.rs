@@ -1,23 +1,15 @@
-use super::test_display;
-
-use hex_literal::hex;
+use super::secs;
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::model::Model;
 
 #[test]
-fn dmg_acid2() {
-    test_display(
-        include_bytes!("../../tests/dmg-acid2/dmg-acid2.gb"),
-        &hex!("d6b6323524d570d90f34793530f51a026cdfeaf1103b674d0c88be87f44ab92e"),
-        20000,
-        false,
-    );
-}
+fn deterministic_run() {
+    let rom = include_bytes!("../../tests/dmg-acid2/dmg-acid2.gb");
+    let mut a = Emulator::new_headless(cartridge::load(rom).unwrap(), Model::Dmg);
+    let mut b = Emulator::new_headless(cartridge::load(rom).unwrap(), Model::Dmg);
 
-#[test]
-fn cgb_acid2() {
-    test_display(
-        include_bytes!("../../tests/cgb-acid2/cgb-acid2.gbc"),
-        &hex!("c587a0e67f4a9e7ceccfc3b1c1991510a6476bd6b4a8b2f109f83e94f97116cb"),
-        20000,
-        true,
-    );
+    let report = a.run_for_cycles(secs(2)).unwrap();
+    assert_eq!(report, b.run_for_cycles(secs(2)).unwrap());
+    assert!(report.frames > 100);
 }
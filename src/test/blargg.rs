@@ -1,4 +1,4 @@
-use super::{test_display, test_serial};
+use super::{secs, test_display, test_memory_result, test_serial};
 
 use hex_literal::hex;
 
@@ -8,7 +8,7 @@ fn cpu_instrs_01() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/01-special.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -18,7 +18,7 @@ fn cpu_instrs_02() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/02-interrupts.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -28,7 +28,7 @@ fn cpu_instrs_03() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/03-op sp,hl.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -38,7 +38,7 @@ fn cpu_instrs_04() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/04-op r,imm.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -48,7 +48,7 @@ fn cpu_instrs_05() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/05-op rp.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -58,7 +58,7 @@ fn cpu_instrs_06() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/06-ld r,r.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -68,7 +68,7 @@ fn cpu_instrs_07() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/07-jr,jp,call,ret,rst.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -78,7 +78,7 @@ fn cpu_instrs_08() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/08-misc instrs.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -88,7 +88,7 @@ fn cpu_instrs_09() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/09-op r,r.gb"),
         b"Passed",
         b"Failed",
-        60000,
+        secs(60),
     );
 }
 
@@ -98,7 +98,7 @@ fn cpu_instrs_10() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/10-bit ops.gb"),
         b"Passed",
         b"Failed",
-        90000,
+        secs(90),
     );
 }
 
@@ -108,7 +108,7 @@ fn cpu_instrs_11() {
         include_bytes!("../../tests/blargg/cpu_instrs/individual/11-op a,(hl).gb"),
         b"Passed",
         b"Failed",
-        180000,
+        secs(180),
     );
 }
 
@@ -118,7 +118,7 @@ fn instr_timing() {
         include_bytes!("../../tests/blargg/instr_timing/instr_timing.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -128,7 +128,7 @@ fn mem_timing() {
         include_bytes!("../../tests/blargg/mem_timing/mem_timing.gb"),
         b"Passed",
         b"Failed",
-        30000,
+        secs(30),
     );
 }
 
@@ -137,37 +137,88 @@ fn mem_timing_2() {
     test_display(
         include_bytes!("../../tests/blargg/mem_timing-2/mem_timing.gb"),
         &hex!("180edbacf7255addb9537cc7c95b1f5352ee7061b973ecab4e8054b0502eba4e"),
-        60000,
+        secs(60),
         false,
     );
 }
 
+#[test]
+// Fails: HALT bug is not emulated
+#[ignore]
+fn halt_bug() {
+    test_memory_result(include_bytes!("../../tests/blargg/halt_bug.gb"), secs(60));
+}
+
 #[test]
 fn oam_bug_lcd_sync() {
-    test_display(
+    test_memory_result(
         include_bytes!("../../tests/blargg/oam_bug/rom_singles/1-lcd_sync.gb"),
-        &hex!("35081c557a9cb2717998045663132658cdba0fd454765a2145b14546f83587aa"),
-        60000,
-        false,
+        secs(60),
+    );
+}
+
+#[test]
+// Fails: OAM corruption is not emulated
+#[ignore]
+fn oam_bug_causes() {
+    test_memory_result(
+        include_bytes!("../../tests/blargg/oam_bug/rom_singles/2-causes.gb"),
+        secs(60),
     );
 }
 
 #[test]
 fn oam_bug_non_causes() {
-    test_display(
+    test_memory_result(
         include_bytes!("../../tests/blargg/oam_bug/rom_singles/3-non_causes.gb"),
-        &hex!("f417f087dc9aefd1a853719415c01f68142ab9d9e30b66c73e1ff429e5152a92"),
-        60000,
-        false,
+        secs(60),
+    );
+}
+
+#[test]
+// Fails: OAM corruption is not emulated
+#[ignore]
+fn oam_bug_scanline_timing() {
+    test_memory_result(
+        include_bytes!("../../tests/blargg/oam_bug/rom_singles/4-scanline_timing.gb"),
+        secs(60),
+    );
+}
+
+#[test]
+// Fails: OAM corruption is not emulated
+#[ignore]
+fn oam_bug_timing_bug() {
+    test_memory_result(
+        include_bytes!("../../tests/blargg/oam_bug/rom_singles/5-timing_bug.gb"),
+        secs(60),
     );
 }
 
 #[test]
 fn oam_bug_timing_no_bug() {
-    test_display(
+    test_memory_result(
         include_bytes!("../../tests/blargg/oam_bug/rom_singles/6-timing_no_bug.gb"),
-        &hex!("b4cc0155826c546939b7df321b8653d7fdc5235e38f907172ecfa3f1a7947c4f"),
-        60000,
-        false,
+        secs(60),
+    );
+}
+
+#[test]
+// Fails: OAM corruption is not emulated
+#[ignore]
+fn oam_bug_timing_effect() {
+    test_memory_result(
+        include_bytes!("../../tests/blargg/oam_bug/rom_singles/7-timing_effect.gb"),
+        secs(60),
+    );
+}
+
+#[test]
+// Fails: OAM corruption is not emulated
+#[ignore]
+fn oam_bug_instr_effect() {
+    test_memory_result(
+        include_bytes!("../../tests/blargg/oam_bug/rom_singles/8-instr_effect.gb"),
+        secs(60),
     );
 }
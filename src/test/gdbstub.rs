@@ -0,0 +1,94 @@
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::debugger::Debugger;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::gdbstub::gdbstub::GdbStub;
+use crate::gameboy::gdbstub::packet::{self, Event, PacketParser};
+use crate::gameboy::model::Model;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// GDB client side of the connection
+struct Client {
+    stream: TcpStream,
+    parser: PacketParser,
+}
+
+impl Client {
+    /// Sends a command and returns the reply, checking the acknowledgement
+    fn command(&mut self, cmd: &str) -> String {
+        self.stream
+            .write_all(&packet::encode(cmd.as_bytes()))
+            .unwrap();
+        let mut acked = false;
+        let mut buf = [0; 1];
+        loop {
+            self.stream.read_exact(&mut buf).unwrap();
+            match self.parser.feed(buf[0]) {
+                Some(Event::Ack) => acked = true,
+                Some(Event::Packet(reply)) => {
+                    assert!(acked, "Reply to '{}' before acknowledgement", cmd);
+                    self.stream.write_all(b"+").unwrap();
+                    return String::from_utf8(reply).unwrap();
+                }
+                Some(e) => panic!("Unexpected {:?} after '{}'", e, cmd),
+                None => (),
+            }
+        }
+    }
+}
+
+#[test]
+fn gdbstub_single_step() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        // 0x0100: NOP, LD A,42h, JR -2
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x105].copy_from_slice(&[0x00, 0x3E, 0x42, 0x18, 0xFE]);
+        let mut emu = Emulator::new_headless(cartridge::load(&rom).unwrap(), Model::Dmg);
+        let mut dbg = Debugger::new();
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(stream).serve(&mut emu, &mut dbg).unwrap();
+        emu.cpu().regs.pc
+    });
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut client = Client {
+        stream,
+        parser: PacketParser::new(),
+    };
+
+    assert!(client
+        .command("qSupported:swbreak+")
+        .starts_with("PacketSize="));
+    assert_eq!(client.command("?"), "S05");
+    // PC is register 5, little endian
+    assert_eq!(client.command("p5"), "0001");
+
+    assert_eq!(client.command("s"), "S05");
+    assert_eq!(client.command("p5"), "0101");
+    assert_eq!(client.command("s"), "S05");
+    let regs = client.command("g");
+    assert_eq!(regs.len(), 6 * 4);
+    // A (high byte of AF) and PC
+    assert_eq!(&regs[2..4], "42");
+    assert_eq!(&regs[20..24], "0301");
+    assert_eq!(client.command("m0101,2"), "3e42");
+
+    // Breakpoint on the JR, continue runs into it again
+    assert_eq!(client.command("Z0,0103,1"), "OK");
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p5"), "0301");
+    assert_eq!(client.command("z0,0103,1"), "OK");
+
+    assert_eq!(client.command("D"), "OK");
+    assert_eq!(server.join().unwrap(), 0x0103);
+}
@@ -0,0 +1,211 @@
+//! Golden frame hash tests.
+//!
+//! Every `tests/golden/*.golden` file describes a ROM whose display should
+//! end up showing a known frame:
+//!
+//! ```text
+//! rom = dmg-acid2/dmg-acid2.gb  # Relative to tests/
+//! cgb = false                   # Optional, default false
+//! seconds = 20                  # Optional cycle budget, default 20
+//! hash = <SHA256 of the frame>
+//! ```
+//!
+//! To add a ROM, drop it in tests/, create a .golden file without a hash
+//! and run `GB_BLESS=1 cargo test golden`. Blessing rewrites the hashes
+//! of all golden files from the current output instead of failing, which
+//! is also how hashes are updated after an intentional rendering change.
+
+use super::{run_display, secs};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Environment variable that enables bless mode
+const BLESS_VAR: &str = "GB_BLESS";
+
+const DEFAULT_SECONDS: u64 = 20;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Golden {
+    rom: String,
+    cgb: bool,
+    seconds: u64,
+    hash: Option<[u8; 32]>,
+}
+
+impl Golden {
+    fn parse(s: &str) -> Result<Self> {
+        let mut rom = None;
+        let mut cgb = false;
+        let mut seconds = DEFAULT_SECONDS;
+        let mut hash = None;
+
+        for line in s.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, val)) = line.split_once('=') else {
+                bail!("Invalid line: {}", line);
+            };
+            let val = val.trim();
+            match key.trim() {
+                "rom" => rom = Some(val.to_string()),
+                "cgb" => cgb = val.parse().context("Invalid value for cgb")?,
+                "seconds" => seconds = val.parse().context("Invalid value for seconds")?,
+                "hash" => hash = Some(parse_hash(val)?),
+                k => bail!("Unknown key: {}", k),
+            }
+        }
+
+        Ok(Self {
+            rom: rom.context("No ROM specified")?,
+            cgb,
+            seconds,
+            hash,
+        })
+    }
+}
+
+fn parse_hash(s: &str) -> Result<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        bail!("Invalid hash: {}", s);
+    }
+    let mut hash = [0; 32];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[(i * 2)..(i * 2 + 2)], 16)
+            .with_context(|| format!("Invalid hash: {}", s))?;
+    }
+    Ok(hash)
+}
+
+fn format_hash(hash: &[u8]) -> String {
+    format!("{:02x}", hash.iter().format(""))
+}
+
+/// Replaces (or adds) the hash in the contents of a golden file,
+/// leaving everything else as is.
+fn bless(s: &str, hash: &[u8]) -> String {
+    let line = format!("hash = {}", format_hash(hash));
+    let mut out = vec![];
+    let mut replaced = false;
+    for l in s.lines() {
+        if l.trim_start().starts_with("hash") {
+            out.push(line.clone());
+            replaced = true;
+        } else {
+            out.push(l.to_string());
+        }
+    }
+    if !replaced {
+        out.push(line);
+    }
+    out.join("\n") + "\n"
+}
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+/// Runs the ROM of a golden file, returns a description of the failure
+/// if there is one.
+fn check(path: &Path, blessing: bool) -> Result<Option<String>> {
+    let contents = fs::read_to_string(path)?;
+    let golden = Golden::parse(&contents)?;
+    let rom = fs::read(tests_dir().join(&golden.rom))
+        .with_context(|| format!("Cannot read ROM {}", golden.rom))?;
+    let status = run_display(&rom, secs(golden.seconds), golden.cgb)?;
+
+    if golden.hash == Some(status.hash) {
+        return Ok(None);
+    }
+    if blessing {
+        fs::write(path, bless(&contents, &status.hash))?;
+        println!("Blessed {}", path.display());
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "-hash = {}\n+hash = {}\n(stable for {} frames)",
+        golden.hash.map(|h| format_hash(&h)).unwrap_or_default(),
+        format_hash(&status.hash),
+        status.stable_frames
+    )))
+}
+
+#[test]
+fn golden() {
+    let blessing = env::var_os(BLESS_VAR).is_some_and(|v| v == "1");
+    let files = fs::read_dir(tests_dir().join("golden"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "golden"))
+        .sorted()
+        .collect_vec();
+    assert!(!files.is_empty());
+
+    // Each ROM runs on its own thread
+    let results = thread::scope(|s| {
+        files
+            .iter()
+            .map(|path| s.spawn(|| check(path, blessing)))
+            .collect_vec()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect_vec()
+    });
+
+    let mut failures = vec![];
+    for (path, result) in files.iter().zip(results) {
+        let name = path.file_name().unwrap().to_string_lossy();
+        match result {
+            Ok(None) => (),
+            Ok(Some(diff)) => failures.push(format!("{}:\n{}", name, diff)),
+            Err(e) => failures.push(format!("{}: {:#}", name, e)),
+        }
+    }
+    if !failures.is_empty() {
+        panic!(
+            "{} golden test(s) failed (run with {}=1 to update):\n{}",
+            failures.len(),
+            BLESS_VAR,
+            failures.join("\n")
+        );
+    }
+}
+
+#[test]
+fn golden_parse() {
+    let g = Golden::parse(
+        "# Comment\nrom = a/b.gb  # ROM\ncgb = true\nseconds = 5\n\
+         hash = 00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\n",
+    )
+    .unwrap();
+    assert_eq!(g.rom, "a/b.gb");
+    assert!(g.cgb);
+    assert_eq!(g.seconds, 5);
+    assert_eq!(g.hash.unwrap()[..4], [0x00, 0x11, 0x22, 0x33]);
+
+    let g = Golden::parse("rom = x.gb").unwrap();
+    assert!(!g.cgb);
+    assert_eq!(g.seconds, DEFAULT_SECONDS);
+    assert_eq!(g.hash, None);
+
+    assert!(Golden::parse("cgb = true").is_err());
+    assert!(Golden::parse("rom = x.gb\nfoo = 1").is_err());
+    assert!(Golden::parse("rom = x.gb\nhash = 1234").is_err());
+    assert!(Golden::parse("rom x.gb").is_err());
+}
+
+#[test]
+fn golden_bless() {
+    let hash = [0xAB; 32];
+    let expected = format!("rom = x.gb\nhash = {}\n", "ab".repeat(32));
+    assert_eq!(bless("rom = x.gb\n", &hash), expected);
+    assert_eq!(bless("rom = x.gb\nhash = 1234\n", &hash), expected);
+    assert_eq!(Golden::parse(&expected).unwrap().hash, Some(hash));
+}
@@ -0,0 +1,212 @@
+use crate::display::display::NullDisplay;
+use crate::gameboy::bus::gbbus::Gameboybus;
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::cpu::cpu::CPU;
+use crate::gameboy::cpu::regs::Register;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::lcd::LCDController;
+use crate::gameboy::link::{LinkRole, LockstepLink};
+use crate::gameboy::model::Model;
+use crate::gameboy::serial::{self, LinkChannels, Serial};
+use crate::input::input::NullInput;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Builds a ROM that sends a byte over the link cable and stores the
+/// received byte at 0xC000.
+fn link_rom(send: u8, internal_clock: bool) -> Vec<u8> {
+    let mut rom = vec![0; 32 * 1024];
+    rom[0x100..0x114].copy_from_slice(&[
+        0x3E,
+        send, // LD A,send
+        0xE0,
+        0x01, // LDH (01h),A
+        0x3E,
+        0x80 | internal_clock as u8, // LD A,80h/81h
+        0xE0,
+        0x02, // LDH (02h),A - start transfer
+        0xF0,
+        0x02, // LDH A,(02h)
+        0xCB,
+        0x7F, // BIT 7,A
+        0x20,
+        0xFA, // JR NZ,-6 - wait for transfer
+        0xF0,
+        0x01, // LDH A,(01h)
+        0xEA,
+        0x00,
+        0xC0, // LD (C000h),A
+        0x18, // JR...
+    ]);
+    rom[0x114] = 0xFE; // ...-2
+    rom
+}
+
+/// DMG system running a ROM, with the serial port connected to 'serial'
+fn link_cpu(rom: &[u8], serial: Serial) -> CPU {
+    let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+    let bus = Box::new(Gameboybus::new_with_serial(
+        cartridge::load(rom).unwrap(),
+        None,
+        lcd,
+        Box::new(NullInput::new()),
+        Model::Dmg,
+        serial,
+    ));
+    CPU::new(bus, Model::Dmg)
+}
+
+/// Runs a ROM on a CPU with a crossed serial port, signals 'ready'
+/// after the transfer was set up and returns the received byte.
+fn run(rom: Vec<u8>, link: LinkChannels, ready: mpsc::Sender<()>) -> u8 {
+    let mut cpu = link_cpu(&rom, Serial::new_crossed(link.0, link.1));
+
+    // Write SB, write SC
+    for _ in 0..4 {
+        cpu.step().unwrap();
+    }
+    ready.send(()).unwrap();
+
+    let start = Instant::now();
+    while cpu.regs.pc != 0x113 {
+        cpu.step().unwrap();
+        // The partner runs on another thread, which may be slow to
+        // start; use a wall-clock limit.
+        assert!(start.elapsed() < Duration::from_secs(30), "Timeout");
+    }
+    cpu.bus.read(0xC000)
+}
+
+#[test]
+fn crossed_serial() {
+    let (master_link, slave_link) = serial::crossed_channels();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    // Slave (external clock) has to be ready before the master starts
+    // the transfer.
+    let slave = {
+        let ready = ready_tx.clone();
+        thread::spawn(move || run(link_rom(0x99, false), slave_link, ready))
+    };
+    ready_rx.recv().unwrap();
+    let master = thread::spawn(move || run(link_rom(0x42, true), master_link, ready_tx));
+
+    assert_eq!(master.join().unwrap(), 0x99);
+    assert_eq!(slave.join().unwrap(), 0x42);
+}
+
+#[test]
+fn crossed_serial_disconnected() {
+    let (master_link, slave_link) = serial::crossed_channels();
+    drop(slave_link);
+
+    let (ready_tx, _ready_rx) = mpsc::channel();
+    // Nothing connected reads as 0xFF
+    assert_eq!(run(link_rom(0x42, true), master_link, ready_tx), 0xFF);
+}
+
+/// Amount of transfers in the lockstep test
+const TRANSFERS: u16 = 1000;
+
+/// Builds a ROM that repeatedly transfers a counter (starting at
+/// 'first') over the link cable, storing the received bytes from 0xC000
+/// on. Every transfer is followed by a delay of 'delay' loops.
+fn repeat_link_rom(first: u8, internal_clock: bool, delay: u8) -> Vec<u8> {
+    let mut rom = vec![0; 32 * 1024];
+    rom[0x100..0x11D].copy_from_slice(&[
+        0x21,
+        0x00,
+        0xC0, // LD HL,C000h
+        0x06,
+        first, // LD B,first
+        0x78,  // LD A,B - loop
+        0xE0,
+        0x01, // LDH (01h),A
+        0x3E,
+        0x80 | internal_clock as u8, // LD A,80h/81h
+        0xE0,
+        0x02, // LDH (02h),A - start transfer
+        0xF0,
+        0x02, // LDH A,(02h) - wait
+        0xCB,
+        0x7F, // BIT 7,A
+        0x20,
+        0xFA, // JR NZ,wait
+        0xF0,
+        0x01, // LDH A,(01h)
+        0x22, // LD (HL+),A
+        0x04, // INC B
+        0x0E,
+        delay, // LD C,delay
+        0x0D,  // DEC C - delay
+        0x20,
+        0xFD, // JR NZ,delay
+        0x18,
+        0xE8, // JR loop
+    ]);
+    rom
+}
+
+/// Runs TRANSFERS transfers, returns the cycle count at which each
+/// received byte was stored and the received bytes.
+fn run_transfers(rom: Vec<u8>, link: LinkChannels, role: LinkRole) -> (Vec<u64>, Vec<u8>) {
+    let link = LockstepLink::new_crossed(link.0, link.1, role);
+    let mut cpu = link_cpu(&rom, Serial::new_lockstep(link));
+    let end = 0xC000 + TRANSFERS;
+    let mut times = vec![];
+    let mut hl = 0;
+
+    let start = Instant::now();
+    while hl != end {
+        cpu.step().unwrap();
+        let new_hl = cpu.regs.read16(Register::HL).unwrap();
+        if new_hl != hl && new_hl > 0xC000 {
+            times.push(cpu.get_cycles());
+        }
+        hl = new_hl;
+        assert!(start.elapsed() < Duration::from_secs(60), "Timeout");
+    }
+    let received = (0xC000..end).map(|a| cpu.bus.read(a)).collect();
+    (times, received)
+}
+
+#[test]
+fn lockstep_no_drift() {
+    let (leader, follower) = serial::crossed_channels();
+
+    // The master spends ~3000 cycles between transfers, so the transfers
+    // span a few dozen frames.
+    let master = thread::spawn(move || {
+        run_transfers(repeat_link_rom(0, true, 250), leader, LinkRole::Leader)
+    });
+    let slave = thread::spawn(move || {
+        run_transfers(
+            repeat_link_rom(0x80, false, 1),
+            follower,
+            LinkRole::Follower,
+        )
+    });
+    let (master_times, master_rx) = master.join().unwrap();
+    let (slave_times, slave_rx) = slave.join().unwrap();
+
+    assert!(master_rx
+        .iter()
+        .enumerate()
+        .all(|(i, &b)| b == 0x80u8.wrapping_add(i as u8)));
+    assert!(slave_rx.iter().enumerate().all(|(i, &b)| b == i as u8));
+
+    // Both sides complete every transfer at (nearly) the same time,
+    // the difference is the polling loop of the slave.
+    assert_eq!(master_times.len(), TRANSFERS as usize);
+    assert_eq!(slave_times.len(), TRANSFERS as usize);
+    let drift = master_times
+        .iter()
+        .zip(&slave_times)
+        .map(|(&m, &s)| m.abs_diff(s))
+        .max()
+        .unwrap();
+    assert!(drift < 64, "Drift of {} cycles", drift);
+    assert!(*master_times.last().unwrap() > Emulator::FRAME_CYCLES * 30);
+}
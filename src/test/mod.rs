@@ -1,90 +1,68 @@
 mod acid;
+mod audio;
 mod blargg;
+mod gdbstub;
+mod golden;
+mod link;
 mod mooneye;
+mod movie;
+#[cfg(feature = "remote")]
+mod remote;
+mod screenshot;
 mod sm83;
+mod vectors;
 
-use crate::display::display::NullDisplay;
-use crate::display::test::TestDisplay;
-use crate::gameboy::bus::gbbus::Gameboybus;
-use crate::gameboy::cartridge::cartridge;
-use crate::gameboy::cpu::cpu::CPU;
-use crate::gameboy::lcd::{LCDController, LCD_H, LCD_W};
-use crate::gameboy::serial::Serial;
-use crate::input::input::NullInput;
-use crate::misc::WritableSender;
+use crate::gameboy::testing;
+use crate::time::HostClock;
 
-use itertools::Itertools;
+use std::panic;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-use std::sync::mpsc;
-use std::time::Instant;
+pub use crate::gameboy::testing::{run_display, secs};
 
-fn test_serial(rom: &[u8], pass_text: &[u8], fail_text: &[u8], time_limit: u128) {
-    let cart = cartridge::load(rom);
-    let display = Box::new(NullDisplay::new());
-    let input = Box::new(NullInput::new());
-    let lcd = LCDController::new(display, false);
+fn test_serial(rom: &[u8], pass_text: &[u8], fail_text: &[u8], max_cycles: u64) {
+    testing::run_serial(rom, pass_text, fail_text, max_cycles, false).unwrap();
+}
 
-    let (tx, rx) = mpsc::channel::<u8>();
-    let bus = Box::new(Gameboybus::new_with_serial(
-        cart,
-        None,
-        lcd,
-        input,
-        false,
-        Serial::new_out(Box::new(WritableSender::new(tx))),
-    ));
+/// Runs a ROM that reports its result in cartridge RAM
+/// (see testing::run_memory_result())
+fn test_memory_result(rom: &[u8], max_cycles: u64) {
+    testing::run_memory_result(rom, max_cycles, false).unwrap();
+}
 
-    let mut cpu = CPU::new(bus, false);
+/// Runs a ROM until the display is stable and checks its hash
+fn test_display(rom: &[u8], pass_hash: &[u8], max_cycles: u64, cgb: bool) {
+    testing::check_display(rom, pass_hash, max_cycles, cgb).unwrap();
+}
 
-    let start = Instant::now();
-    let mut output: Vec<u8> = vec![];
-    loop {
-        if start.elapsed().as_millis() > time_limit {
-            panic!("Timeout");
-        }
-        cpu.step().unwrap();
+/// Serializes changes to the panic hook, which is global to the process
+static PANIC_HOOK: Mutex<()> = Mutex::new(());
 
-        if let Ok(c) = rx.try_recv() {
-            output.push(c);
-            if output.ends_with(&pass_text) {
-                return;
-            }
-            if output.ends_with(&fail_text) {
-                panic!("Test failed");
-            }
-        }
-    }
+/// Runs 'f' with panic messages suppressed, for tests that panic on
+/// purpose. Tests run in parallel, so swapping the hook is serialized
+/// to make sure the original hook is restored.
+pub fn without_panic_output<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = PANIC_HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+    let result = f();
+    panic::set_hook(hook);
+    result
 }
 
-fn test_display(rom: &[u8], pass_hash: &[u8], time_limit: u128, cgb: bool) {
-    let cart = cartridge::load(rom);
-    let (display, dispstatus) = TestDisplay::new(LCD_W, LCD_H);
-    let input = Box::new(NullInput::new());
-    let lcd = LCDController::new(display, cgb);
+/// Host clock that is set by the test
+#[derive(Clone, Default)]
+pub struct MockClock(Arc<AtomicU64>);
 
-    let bus = Box::new(Gameboybus::new(cart, None, lcd, input, cgb));
-    let mut cpu = CPU::new(bus, cgb);
-
-    let start = Instant::now();
-    loop {
-        if start.elapsed().as_millis() > time_limit {
-            dbg!(dispstatus.get());
-            panic!("Timeout");
-        }
-        cpu.step().unwrap();
+impl MockClock {
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::Relaxed);
+    }
+}
 
-        let newstatus = dispstatus.get();
-        if newstatus.stable_frames >= 100 {
-            if newstatus.hash != pass_hash {
-                panic!(
-                    "Expected hash {:02x} but saw {:02x} (for {} frames)",
-                    pass_hash.iter().format(""),
-                    newstatus.hash.iter().format(""),
-                    newstatus.stable_frames
-                );
-            } else {
-                return;
-            }
-        }
+impl HostClock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
     }
 }
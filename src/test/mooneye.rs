@@ -1,134 +1,321 @@
-use super::test_serial;
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::model::Model;
 
+use super::secs;
+
+use std::fs;
+use std::path::Path;
+
+/// LD B,B, used as a breakpoint to signal the end of a test
+const MAGIC_BREAKPOINT: u8 = 0x40;
+
+/// B, C, D, E, H, L after a successful test
+const PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+/// B, C, D, E, H, L after a failed test
+const FAIL: [u8; 6] = [0x42; 6];
+
+/// Generates a test per ROM in a directory of the test suite. Every ROM
+/// in the directory has to be listed, currently failing ones are marked
+/// #[ignore] so they still show up in the test output.
 macro_rules! mooneye {
-    ( $( $x:expr ),* $(,)? ) => {
-        {
-            $(
-				println!("Testing {}...", $x);
-				test_mooneye(include_bytes!($x));
-			)*
+    ($dir:literal; $( $(#[$attr:meta])* $name:ident => $file:literal ),* $(,)?) => {
+        $(
+            #[test]
+            $(#[$attr])*
+            fn $name() {
+                crate::test::mooneye::test_mooneye(include_bytes!(concat!(
+                    "../../tests/mooneye/",
+                    $dir,
+                    "/",
+                    $file
+                )));
+            }
+        )*
+
+        #[test]
+        fn all_roms_listed() {
+            crate::test::mooneye::check_listing($dir, &[$($file),*]);
         }
     };
 }
 
+/// Runs a test ROM until it hits the magic breakpoint, then checks the
+/// result in the registers.
 fn test_mooneye(rom: &[u8]) {
-    test_serial(
-        rom,
-        &[3, 5, 8, 13, 21, 34],
-        &[0x42, 0x42, 0x42, 0x42, 0x42, 0x42],
-        120000,
-    )
-}
+    let mut emu = Emulator::new_headless(cartridge::load(rom).unwrap(), Model::Dmg);
 
-#[test]
-fn boot() {
-    mooneye!(
-        "../../tests/mooneye/acceptance/boot_regs-dmgABC.gb",
-        //"../../tests/mooneye/acceptance/boot_div-dmgABCmgb.gb",
-        //"../../tests/mooneye/acceptance/boot_hwio-dmgABCmgb.gb",
-    );
+    while emu.cpu().get_cycles() < secs(30) {
+        let cpu = emu.cpu();
+        if cpu.bus.peek(cpu.regs.pc) == MAGIC_BREAKPOINT {
+            let r = &cpu.regs;
+            let result = [r.b, r.c, r.d, r.e, r.h, r.l];
+            if result == PASS {
+                return;
+            }
+            if result == FAIL {
+                panic!("Test failed");
+            }
+            panic!("Unexpected result: {:02X?}", result);
+        }
+        emu.step().unwrap();
+    }
+    panic!("Cycle budget exhausted");
 }
 
-#[test]
-fn instr_daa() {
-    mooneye!("../../tests/mooneye/acceptance/instr/daa.gb",);
+/// Checks that every ROM in a directory of the test suite has a test
+fn check_listing(dir: &str, listed: &[&str]) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/mooneye")
+        .join(dir);
+    let mut roms: Vec<String> = fs::read_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|f| f.ends_with(".gb"))
+        .collect();
+    roms.sort();
+    let mut listed = listed.to_vec();
+    listed.sort();
+    assert_eq!(roms, listed);
 }
 
-#[test]
-fn mbc1_ram() {
-    mooneye!(
-        "../../tests/mooneye/emulator-only/mbc1/bits_bank1.gb",
-        "../../tests/mooneye/emulator-only/mbc1/bits_bank2.gb",
-        "../../tests/mooneye/emulator-only/mbc1/bits_mode.gb",
-        "../../tests/mooneye/emulator-only/mbc1/bits_ramg.gb",
-        "../../tests/mooneye/emulator-only/mbc1/ram_256kb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/ram_64kb.gb",
+mod acceptance {
+    mooneye!("acceptance";
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        add_sp_e_timing => "add_sp_e_timing.gb",
+        // Model not emulated
+        #[ignore]
+        boot_div_s => "boot_div-S.gb",
+        // Model not emulated
+        #[ignore]
+        boot_div_dmg0 => "boot_div-dmg0.gb",
+        // Fails: DIV after boot is off
+        #[ignore]
+        boot_div_dmgabcmgb => "boot_div-dmgABCmgb.gb",
+        // Model not emulated
+        #[ignore]
+        boot_div2_s => "boot_div2-S.gb",
+        // Model not emulated
+        #[ignore]
+        boot_hwio_s => "boot_hwio-S.gb",
+        // Model not emulated
+        #[ignore]
+        boot_hwio_dmg0 => "boot_hwio-dmg0.gb",
+        // Fails: IO register values after boot
+        #[ignore]
+        boot_hwio_dmgabcmgb => "boot_hwio-dmgABCmgb.gb",
+        // Model not emulated
+        #[ignore]
+        boot_regs_dmg0 => "boot_regs-dmg0.gb",
+        boot_regs_dmgabc => "boot_regs-dmgABC.gb",
+        // Model not emulated
+        #[ignore]
+        boot_regs_mgb => "boot_regs-mgb.gb",
+        // Model not emulated
+        #[ignore]
+        boot_regs_sgb => "boot_regs-sgb.gb",
+        // Model not emulated
+        #[ignore]
+        boot_regs_sgb2 => "boot_regs-sgb2.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        call_cc_timing => "call_cc_timing.gb",
+        call_cc_timing2 => "call_cc_timing2.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        call_timing => "call_timing.gb",
+        call_timing2 => "call_timing2.gb",
+        di_timing_gs => "di_timing-GS.gb",
+        div_timing => "div_timing.gb",
+        ei_sequence => "ei_sequence.gb",
+        ei_timing => "ei_timing.gb",
+        halt_ime0_ei => "halt_ime0_ei.gb",
+        // Fails: HALT timing
+        #[ignore]
+        halt_ime0_nointr_timing => "halt_ime0_nointr_timing.gb",
+        halt_ime1_timing => "halt_ime1_timing.gb",
+        halt_ime1_timing2_gs => "halt_ime1_timing2-GS.gb",
+        if_ie_registers => "if_ie_registers.gb",
+        intr_timing => "intr_timing.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        jp_cc_timing => "jp_cc_timing.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        jp_timing => "jp_timing.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        ld_hl_sp_e_timing => "ld_hl_sp_e_timing.gb",
+        oam_dma_restart => "oam_dma_restart.gb",
+        oam_dma_start => "oam_dma_start.gb",
+        oam_dma_timing => "oam_dma_timing.gb",
+        pop_timing => "pop_timing.gb",
+        push_timing => "push_timing.gb",
+        rapid_di_ei => "rapid_di_ei.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        ret_cc_timing => "ret_cc_timing.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        ret_timing => "ret_timing.gb",
+        reti_intr_timing => "reti_intr_timing.gb",
+        // Hangs: memory accesses within an instruction are not timed
+        #[ignore]
+        reti_timing => "reti_timing.gb",
+        rst_timing => "rst_timing.gb",
     );
-}
 
-#[test]
-fn mbc1_rom() {
-    mooneye!(
-        //"../../tests/mooneye/emulator-only/mbc1/multicart_rom_8Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/rom_16Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/rom_1Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/rom_2Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/rom_4Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/rom_512kb.gb",
-        "../../tests/mooneye/emulator-only/mbc1/rom_8Mb.gb"
-    );
-}
+    mod bits {
+        mooneye!("acceptance/bits";
+            mem_oam => "mem_oam.gb",
+            reg_f => "reg_f.gb",
+            // Fails: unused IO register bits
+            #[ignore]
+            unused_hwio_gs => "unused_hwio-GS.gb",
+        );
+    }
 
-#[test]
-fn mbc5() {
-    mooneye!(
-        "../../tests/mooneye/emulator-only/mbc5/rom_16Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_1Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_2Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_32Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_4Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_512kb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_64Mb.gb",
-        "../../tests/mooneye/emulator-only/mbc5/rom_8Mb.gb"
-    );
-}
+    mod instr {
+        mooneye!("acceptance/instr";
+            daa => "daa.gb",
+        );
+    }
 
-#[test]
-fn ei_sequence() {
-    mooneye!("../../tests/mooneye/acceptance/ei_sequence.gb",);
-}
+    mod interrupts {
+        mooneye!("acceptance/interrupts";
+            // Fails: IE written by the interrupt dispatch push
+            #[ignore]
+            ie_push => "ie_push.gb",
+        );
+    }
 
-#[test]
-fn ei_timing() {
-    mooneye!("../../tests/mooneye/acceptance/ei_timing.gb",);
-}
+    mod oam_dma {
+        mooneye!("acceptance/oam_dma";
+            basic => "basic.gb",
+            // Fails: OAM DMA register read back
+            #[ignore]
+            reg_read => "reg_read.gb",
+            // Fails: OAM DMA from 0xE000 and up
+            #[ignore]
+            sources_gs => "sources-GS.gb",
+        );
+    }
 
-#[test]
-fn rapid_ei_di() {
-    mooneye!("../../tests/mooneye/acceptance/rapid_di_ei.gb",);
-}
+    mod ppu {
+        mooneye!("acceptance/ppu";
+            // Fails: PPU mode timing
+            #[ignore]
+            hblank_ly_scx_timing_gs => "hblank_ly_scx_timing-GS.gb",
+            intr_1_2_timing_gs => "intr_1_2_timing-GS.gb",
+            intr_2_0_timing => "intr_2_0_timing.gb",
+            intr_2_mode0_timing => "intr_2_mode0_timing.gb",
+            // Fails: PPU mode timing
+            #[ignore]
+            intr_2_mode0_timing_sprites => "intr_2_mode0_timing_sprites.gb",
+            intr_2_mode3_timing => "intr_2_mode3_timing.gb",
+            intr_2_oam_ok_timing => "intr_2_oam_ok_timing.gb",
+            // Fails: PPU mode timing
+            #[ignore]
+            lcdon_timing_gs => "lcdon_timing-GS.gb",
+            // Fails: PPU mode timing
+            #[ignore]
+            lcdon_write_timing_gs => "lcdon_write_timing-GS.gb",
+            stat_irq_blocking => "stat_irq_blocking.gb",
+            // Fails: PPU mode timing
+            #[ignore]
+            stat_lyc_onoff => "stat_lyc_onoff.gb",
+            // Fails: PPU mode timing
+            #[ignore]
+            vblank_stat_intr_gs => "vblank_stat_intr-GS.gb",
+        );
+    }
 
-#[test]
-fn oam_dma_timing() {
-    mooneye!(
-        "../../tests/mooneye/acceptance/oam_dma_timing.gb",
-        "../../tests/mooneye/acceptance/oam_dma_start.gb",
-        "../../tests/mooneye/acceptance/oam_dma_restart.gb",
-    );
-}
+    mod serial {
+        mooneye!("acceptance/serial";
+            // Fails: serial clock alignment after boot
+            #[ignore]
+            boot_sclk_align_dmgabcmgb => "boot_sclk_align-dmgABCmgb.gb",
+        );
+    }
 
-#[test]
-fn timer() {
-    mooneye!(
-        "../../tests/mooneye/acceptance/timer/div_write.gb",
-        "../../tests/mooneye/acceptance/timer/rapid_toggle.gb",
-        "../../tests/mooneye/acceptance/timer/tim00.gb",
-        "../../tests/mooneye/acceptance/timer/tim00_div_trigger.gb",
-        "../../tests/mooneye/acceptance/timer/tim01.gb",
-        "../../tests/mooneye/acceptance/timer/tim01_div_trigger.gb",
-        "../../tests/mooneye/acceptance/timer/tim10.gb",
-        "../../tests/mooneye/acceptance/timer/tim10_div_trigger.gb",
-        "../../tests/mooneye/acceptance/timer/tim11.gb",
-        "../../tests/mooneye/acceptance/timer/tim11_div_trigger.gb",
-        "../../tests/mooneye/acceptance/timer/tima_reload.gb",
-        "../../tests/mooneye/acceptance/timer/tima_write_reloading.gb",
-        "../../tests/mooneye/acceptance/timer/tma_write_reloading.gb",
-    );
+    mod timer {
+        mooneye!("acceptance/timer";
+            div_write => "div_write.gb",
+            rapid_toggle => "rapid_toggle.gb",
+            tim00 => "tim00.gb",
+            tim00_div_trigger => "tim00_div_trigger.gb",
+            tim01 => "tim01.gb",
+            tim01_div_trigger => "tim01_div_trigger.gb",
+            tim10 => "tim10.gb",
+            tim10_div_trigger => "tim10_div_trigger.gb",
+            tim11 => "tim11.gb",
+            tim11_div_trigger => "tim11_div_trigger.gb",
+            tima_reload => "tima_reload.gb",
+            tima_write_reloading => "tima_write_reloading.gb",
+            tma_write_reloading => "tma_write_reloading.gb",
+        );
+    }
 }
 
-#[test]
-fn ppu_stat_irq_blocking() {
-    mooneye!("../../tests/mooneye/acceptance/ppu/stat_irq_blocking.gb",);
-}
+mod emulator_only {
+    mod mbc1 {
+        mooneye!("emulator-only/mbc1";
+            bits_bank1 => "bits_bank1.gb",
+            bits_bank2 => "bits_bank2.gb",
+            bits_mode => "bits_mode.gb",
+            bits_ramg => "bits_ramg.gb",
+            // Multicart wiring not emulated
+            #[ignore]
+            multicart_rom_8mb => "multicart_rom_8Mb.gb",
+            ram_256kb => "ram_256kb.gb",
+            ram_64kb => "ram_64kb.gb",
+            rom_16mb => "rom_16Mb.gb",
+            rom_1mb => "rom_1Mb.gb",
+            rom_2mb => "rom_2Mb.gb",
+            rom_4mb => "rom_4Mb.gb",
+            rom_512kb => "rom_512kb.gb",
+            rom_8mb => "rom_8Mb.gb",
+        );
+    }
 
-#[test]
-fn ppu_intr() {
-    mooneye!(
-        "../../tests/mooneye/acceptance/ppu/intr_1_2_timing-GS.gb",
-        //"../../tests/mooneye/acceptance/ppu/intr_2_0_timing.gb",
-        //"../../tests/mooneye/acceptance/ppu/intr_2_mode0_timing.gb",
-        //"../../tests/mooneye/acceptance/ppu/intr_2_mode0_timing_sprites.gb",
-        "../../tests/mooneye/acceptance/ppu/intr_2_mode3_timing.gb",
-        //"../../tests/mooneye/acceptance/ppu/intr_2_oam_ok_timing.gb",
-    );
+    mod mbc2 {
+        mooneye!("emulator-only/mbc2";
+            // MBC2 not implemented
+            #[ignore]
+            bits_ramg => "bits_ramg.gb",
+            // MBC2 not implemented
+            #[ignore]
+            bits_romb => "bits_romb.gb",
+            // MBC2 not implemented
+            #[ignore]
+            bits_unused => "bits_unused.gb",
+            // MBC2 not implemented
+            #[ignore]
+            ram => "ram.gb",
+            // MBC2 not implemented
+            #[ignore]
+            rom_1mb => "rom_1Mb.gb",
+            // MBC2 not implemented
+            #[ignore]
+            rom_2mb => "rom_2Mb.gb",
+            // MBC2 not implemented
+            #[ignore]
+            rom_512kb => "rom_512kb.gb",
+        );
+    }
+
+    mod mbc5 {
+        mooneye!("emulator-only/mbc5";
+            rom_16mb => "rom_16Mb.gb",
+            rom_1mb => "rom_1Mb.gb",
+            rom_2mb => "rom_2Mb.gb",
+            rom_32mb => "rom_32Mb.gb",
+            rom_4mb => "rom_4Mb.gb",
+            rom_512kb => "rom_512kb.gb",
+            rom_64mb => "rom_64Mb.gb",
+            rom_8mb => "rom_8Mb.gb",
+        );
+    }
 }
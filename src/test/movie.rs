@@ -0,0 +1,91 @@
+use crate::display::test::{TestDisplay, TestDisplayState};
+use crate::gameboy::bus::gbbus::Gameboybus;
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::cpu::cpu::CPU;
+use crate::gameboy::lcd::{LCDController, LCD_H, LCD_W};
+use crate::gameboy::model::Model;
+use crate::gameboy::movie::{Movie, MoviePlayer, MovieRecorder, ScriptInput};
+use crate::input::input::{Button, Input};
+
+/// Frames to run the test ROM for
+const FRAMES: u64 = 60;
+
+/// Builds a ROM that reads the joypad (P1) at the start of every
+/// VBlank and logs it into WRAM from 0xC000 onwards, one byte per frame
+/// (directions in the upper nibble, buttons in the lower nibble).
+fn joypad_log_rom() -> Vec<u8> {
+    let mut rom = vec![0; 32 * 1024];
+    // JP 0x0150
+    rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+    rom[0x150..0x176].copy_from_slice(&[
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0xF0, 0x44, // loop: LDH A, (LY)
+        0xFE, 0x90, // CP 144
+        0x20, 0xFA, // JR NZ, loop
+        0x3E, 0x20, // LD A, 0x20 (select directions)
+        0xE0, 0x00, // LDH (P1), A
+        0xF0, 0x00, // LDH A, (P1)
+        0xE6, 0x0F, // AND 0x0F
+        0xCB, 0x37, // SWAP A
+        0x47, // LD B, A
+        0x3E, 0x10, // LD A, 0x10 (select buttons)
+        0xE0, 0x00, // LDH (P1), A
+        0xF0, 0x00, // LDH A, (P1)
+        0xE6, 0x0F, // AND 0x0F
+        0xB0, // OR B
+        0x22, // LD (HL+), A
+        0xF0, 0x44, // wait: LDH A, (LY)
+        0xFE, 0x90, // CP 144
+        0x28, 0xFA, // JR Z, wait
+        0x18, 0xDD, // JR loop
+    ]);
+    rom
+}
+
+/// Runs a ROM for the specified amount of cycles using the
+/// specified input, returns the final display state and the
+/// joypad log.
+fn run(rom: &[u8], input: Box<dyn Input>, cycles: u64) -> (TestDisplayState, Vec<u8>) {
+    let cart = cartridge::load(rom).unwrap();
+    let (display, dispstatus) = TestDisplay::new(LCD_W, LCD_H);
+    let lcd = LCDController::new(display, Model::Dmg);
+    let bus = Box::new(Gameboybus::new(cart, None, lcd, input, Model::Dmg));
+    let mut cpu = CPU::new(bus, Model::Dmg);
+
+    while cpu.get_cycles() < cycles {
+        cpu.step().unwrap();
+    }
+
+    let log = (0..FRAMES as u16)
+        .map(|i| cpu.bus.read(0xC000 + i))
+        .collect();
+    (dispstatus.get(), log)
+}
+
+#[test]
+fn record_playback() {
+    let rom = joypad_log_rom();
+    let cycles = 70224 * FRAMES;
+    let script = ScriptInput::new(|frame, b| match b {
+        Button::Start => (10..20).contains(&frame),
+        Button::DPadDown => (15..40).contains(&frame),
+        Button::A => frame % 7 == 0,
+        _ => false,
+    });
+
+    let (recorder, movie) = MovieRecorder::new(Box::new(script), Movie::new(&rom));
+    let (recorded, recorded_log) = run(&rom, recorder, cycles);
+
+    // The ROM must have seen the scripted input
+    assert!(recorded_log.contains(&0xF7)); // Start
+    assert!(recorded_log.contains(&0x77)); // Down + Start
+    assert!(recorded_log.contains(&0x7E)); // Down + A
+
+    let movie = Movie::deserialize(&movie.lock().unwrap().serialize()).unwrap();
+    movie.verify(&rom).unwrap();
+    assert!(movie.events.iter().any(|e| e.button == Button::DPadDown));
+
+    let (played, played_log) = run(&rom, Box::new(MoviePlayer::new(movie)), cycles);
+    assert_eq!(recorded_log, played_log);
+    assert_eq!(recorded.hash, played.hash);
+}
@@ -0,0 +1,70 @@
+use crate::display::display::NullDisplay;
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::emulator::Emulator;
+use crate::gameboy::model::Model;
+use crate::gameboy::remote::RemoteServer;
+use crate::gameboy::serial::Serial;
+use crate::input::input::SharedInput;
+
+use serde_json::{json, Value};
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn remote_registers() {
+    let (port_tx, port_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+
+    // The emulator is not Send, so it lives on the server thread
+    let server = thread::spawn(move || {
+        // 0x0100: LD A,42h, JR -2
+        let mut rom = vec![0; 32 * 1024];
+        rom[0x100..0x104].copy_from_slice(&[0x3E, 0x42, 0x18, 0xFE]);
+        let (input, buttons) = SharedInput::new();
+        let mut emu = Emulator::new(
+            cartridge::load(&rom).unwrap(),
+            None,
+            Box::new(NullDisplay::new()),
+            input,
+            Model::Dmg,
+            Serial::new_null(),
+        );
+
+        let server = RemoteServer::listen("127.0.0.1:0").unwrap();
+        port_tx.send(server.port()).unwrap();
+        while done_rx.try_recv().is_err() {
+            server.handle_pending(&mut emu, &buttons);
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+
+    let port = port_rx.recv().unwrap();
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut call = |id: u64, method: &str, params: Value| -> Value {
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        writeln!(writer, "{}", request).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["id"], id);
+        response["result"].clone()
+    };
+
+    assert_eq!(call(1, "get_registers", Value::Null)["pc"], 0x0100);
+    assert_eq!(call(2, "run_frames", json!({"n": 1})), 1);
+    let regs = call(3, "get_registers", Value::Null);
+    assert_eq!(regs["a"], 0x42);
+    assert_eq!(regs["pc"], 0x0102);
+
+    done_tx.send(()).unwrap();
+    server.join().unwrap();
+}
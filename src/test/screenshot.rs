@@ -0,0 +1,48 @@
+use crate::display::bmp;
+use crate::display::display::{color_to_rgb888, NullDisplay};
+use crate::gameboy::bus::gbbus::Gameboybus;
+use crate::gameboy::cartridge::cartridge;
+use crate::gameboy::cpu::cpu::CPU;
+use crate::gameboy::lcd::{LCDController, LCD_H, LCD_W};
+use crate::gameboy::model::Model;
+use crate::input::input::NullInput;
+
+use std::env;
+use std::fs;
+
+#[test]
+fn dump_frame() {
+    let cart = cartridge::load(include_bytes!("../../tests/dmg-acid2/dmg-acid2.gb")).unwrap();
+    let lcd = LCDController::new(Box::new(NullDisplay::new()), Model::Dmg);
+    let bus = Box::new(Gameboybus::new(
+        cart,
+        None,
+        lcd,
+        Box::new(NullInput::new()),
+        Model::Dmg,
+    ));
+    let mut cpu = CPU::new(bus, Model::Dmg);
+
+    let lcd = loop {
+        cpu.step().unwrap();
+        let lcd = Gameboybus::from_bus(&*cpu.bus).unwrap().get_lcd();
+        if lcd.get_frame_count() >= 60 {
+            break lcd;
+        }
+    };
+
+    let fb = lcd.get_framebuffer();
+    let filename = env::temp_dir().join(format!("gbrust-dump-frame-{}.bmp", std::process::id()));
+    bmp::save(&filename, LCD_W, LCD_H, fb).unwrap();
+    let data = fs::read(&filename).unwrap();
+    fs::remove_file(&filename).unwrap();
+
+    let (w, h, pixels) = bmp::decode(&data).unwrap();
+    assert_eq!((w, h), (LCD_W, LCD_H));
+    for (x, y) in [(0, 0), (80, 72), (159, 0), (0, 143), (159, 143), (40, 100)] {
+        assert_eq!(pixels[y * LCD_W + x], color_to_rgb888(fb[y * LCD_W + x]));
+    }
+
+    // The acid test image is not a blank screen
+    assert!(fb.iter().any(|&c| c != fb[0]));
+}
@@ -7,6 +7,7 @@ use crate::gameboy::bus::bus::BusMember;
 use crate::gameboy::bus::testbus::{Access, Testbus};
 use crate::gameboy::cpu::cpu::CPU;
 use crate::gameboy::cpu::regs::RegisterFile;
+use crate::gameboy::model::Model;
 
 macro_rules! cpu_test {
     ($testfn:ident, $instr:expr, $cycle_count_correct:expr) => {
@@ -58,13 +59,14 @@ fn run_testcase(testcase: &Value, cycle_count_correct: bool) {
     let ram_initial = parse_ram(&testcase["initial"]["ram"]);
     let ram_final = parse_ram(&testcase["final"]["ram"]);
 
-    let mut bus = Testbus::new();
-    for (addr, val) in ram_initial {
-        bus.write(addr, val);
-    }
+    let segments: Vec<(u16, &[u8])> = ram_initial
+        .iter()
+        .map(|(addr, val)| (*addr, std::slice::from_ref(val)))
+        .collect();
+    let mut bus = Testbus::from_segments(&segments);
     bus.reset_trace();
 
-    let mut cpu = CPU::new(Box::new(bus), false);
+    let mut cpu = CPU::new(Box::new(bus), Model::Dmg);
     cpu.regs = regs_initial;
     cpu.step().unwrap();
 
@@ -96,7 +98,7 @@ fn run_testcase(testcase: &Value, cycle_count_correct: bool) {
         // The amount of cycles is not correct in some
         // test data files. We ignore the cycle count for those.
 
-        assert!(cpu.get_cycles() / 4 >= testcase["cycles"].as_array().unwrap().len());
+        assert!(cpu.get_cycles() / 4 >= testcase["cycles"].as_array().unwrap().len() as u64);
     }
 
     // Compare bus trace to the cycles in the test data
@@ -0,0 +1,48 @@
+//! CPU test vectors (see gameboy::testing::vectors).
+//!
+//! Every `tests/vectors/*.vec` file is run against a CPU on a Testbus.
+//! To add a regression case, extract the instructions and the state
+//! they start in, and write down the state they should end up in.
+
+use crate::gameboy::testing::vectors::Vector;
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn vectors_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors")
+}
+
+fn check(path: &Path) -> Result<()> {
+    let contents = fs::read_to_string(path)?;
+    Vector::parse(&contents).context("Parse error")?.run()
+}
+
+#[test]
+fn vectors() {
+    let files = fs::read_dir(vectors_dir())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "vec"))
+        .sorted()
+        .collect_vec();
+    assert!(!files.is_empty());
+
+    let failures = files
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            check(path).err().map(|e| format!("{}: {:#}", name, e))
+        })
+        .collect_vec();
+    if !failures.is_empty() {
+        panic!(
+            "{} vector(s) failed:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use crate::error::Result;
 
 pub const ONE_MCYCLE: usize = 4;
 
@@ -68,6 +68,10 @@ impl Ticks {
     }
 }
 
+/// Result of a tick: bitmask of interrupt requests raised by the
+/// peripheral (cpu::INT_*), to be OR'ed into IF.
+pub type TickResult = u8;
+
 pub trait Tickable {
-    fn tick(&mut self, ticks: Ticks) -> Result<()>;
+    fn tick(&mut self, ticks: Ticks) -> Result<TickResult>;
 }
@@ -0,0 +1,58 @@
+//! Time of the host.
+//!
+//! Library code that needs host time (statistics, the real time clock,
+//! link timeouts) reads it through the traits here rather than through
+//! std::time, so the emulation core does not depend on an OS clock.
+//! The implementations below are the only users of std::time in the
+//! library; other targets can provide their own.
+
+use core::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of monotonic host time
+pub trait Clock {
+    /// Time since a fixed point in the past
+    fn now(&self) -> Duration;
+}
+
+/// Source of wall clock time on the host
+pub trait HostClock: Send {
+    /// Current time, in seconds since the UNIX epoch
+    fn now(&self) -> u64;
+}
+
+/// Monotonic host clock, counting from its creation
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// The host system clock
+pub struct SystemClock;
+
+impl HostClock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+}